@@ -21,7 +21,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     let selected_symbol = select_symbol(&pe, symbol_arg)?;
     print_dll_info(&pe, &selected_symbol);
-    print_export_opcodes(&pe, 256);
+    print_export_disassembly(&pe, 64);
 
     let image_base = pe.file().optional_header.image_base;
     let mut executor = SymbolExecutor::new(&mut vm, &pe).load(&selected_symbol);
@@ -172,50 +172,47 @@ fn print_resource_node(node: &pe_vm::ResourceNode, depth: usize, shown: &mut usi
     }
 }
 
-fn print_export_opcodes(pe: &Pe, max_bytes: usize) {
+fn print_export_disassembly(pe: &Pe, max_instructions: usize) {
     if pe.symbols().is_empty() {
-        println!("opcodes: <no exports>");
+        println!("disassembly: <no exports>");
         return;
     }
-    println!("opcodes (by export):");
+    println!("disassembly (by export):");
+    let image_base = pe.file().optional_header.image_base;
     for symbol in pe.symbols() {
         let name = symbol.name.as_deref().unwrap_or("<ordinal>");
         if symbol.forwarder.is_some() || symbol.rva == 0 {
             println!("  {name}: <forwarder or empty>");
             continue;
         }
-        let Some((start_rva, bytes)) = symbol_bytes(pe, symbol) else {
+        let listing = symbol_end_rva(pe, symbol.rva)
+            .and_then(|end| Some((end, pe.disassemble_rva(symbol.rva, max_instructions)?)));
+        let Some((end_rva, mut listing)) = listing else {
             println!("  {name}: <unmapped>");
             continue;
         };
+        let end = image_base.wrapping_add(end_rva);
+        listing.retain(|ins| ins.address < end);
+        // Drop the INT3 padding between functions.
+        while listing.last().is_some_and(|ins| ins.mnemonic == "int3") {
+            listing.pop();
+        }
         println!("  {name}:");
-        let limit = max_bytes.min(bytes.len());
-        for (index, chunk) in bytes[..limit].chunks(16).enumerate() {
-            let addr = start_rva + (index as u32) * 16;
-            let line = chunk
+        for ins in &listing {
+            let bytes = ins
+                .bytes
                 .iter()
                 .map(|byte| format!("{byte:02X}"))
                 .collect::<Vec<_>>()
                 .join(" ");
-            println!("    0x{addr:08X}  {line}");
+            println!("    0x{:08X}  {bytes:<20}  {ins}", ins.address);
         }
-        if bytes.len() > limit {
-            println!("    ... {} bytes omitted", bytes.len() - limit);
+        if listing.len() == max_instructions {
+            println!("    ...");
         }
     }
 }
 
-fn symbol_bytes<'a>(pe: &'a Pe, symbol: &pe_vm::ExportSymbol) -> Option<(u32, &'a [u8])> {
-    let start = symbol.rva;
-    let end = symbol_end_rva(pe, start)?;
-    let start_off = pe.file().rva_to_offset(start)? as usize;
-    let end_off = pe.file().rva_to_offset(end)? as usize;
-    if start_off >= pe.image().len() || end_off > pe.image().len() || end_off <= start_off {
-        return None;
-    }
-    Some((start, &pe.image()[start_off..end_off]))
-}
-
 fn symbol_end_rva(pe: &Pe, start_rva: u32) -> Option<u32> {
     let file = pe.file();
     let section_end = file
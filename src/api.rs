@@ -2,6 +2,7 @@
 
 use std::path::Path;
 
+use crate::architecture::intel::x86::disasm::Instruction;
 use crate::pe::{ExportSymbol, PeFile, ResourceDirectory};
 use crate::vm::{ExecuteOptions, Value, Vm, VmError};
 
@@ -14,8 +15,7 @@ pub struct Pe {
 impl Pe {
     pub fn load(vm: &mut Vm, path: impl AsRef<Path>) -> Result<Self, VmError> {
         let guest_path = path.as_ref().to_string_lossy();
-        let host_path = vm.map_path(&guest_path);
-        let image = std::fs::read(&host_path)?;
+        let image = vm.host_read_all(&guest_path)?;
         let file = PeFile::parse(&image)?;
         vm.load_image(&file, &image)?;
         vm.set_image_path(guest_path.to_string());
@@ -39,6 +39,16 @@ impl Pe {
         &self.image
     }
 
+    /// Disassemble up to `count` instructions at `rva` without executing them.
+    pub fn disassemble_rva(&self, rva: u32, count: usize) -> Option<Vec<Instruction>> {
+        self.file.disassemble_rva(&self.image, rva, count)
+    }
+
+    /// Disassemble up to `count` instructions at the named export.
+    pub fn disassemble_export(&self, name: &str, count: usize) -> Option<Vec<Instruction>> {
+        self.file.disassemble_export(&self.image, name, count)
+    }
+
     pub fn default_path_mapping() -> crate::vm::PathMapping {
         let mut paths = crate::settings::default_path_mapping();
         if let Some(settings) = crate::settings::load_default_settings() {
@@ -0,0 +1,165 @@
+//! Decoded-instruction cache.
+//!
+//! Decoding walks the prefixes, reads the opcode, looks up its handler, validates
+//! LOCK and, for most opcodes, decodes the ModRM, SIB and displacement bytes. None
+//! of that depends on anything but the instruction bytes, so the result is kept per
+//! EIP. Guest memory flags the pages an entry was read from and reports them once
+//! they are written, unmapped or reprotected; their entries are dropped before the
+//! next instruction runs, which covers self-modifying code and `VirtualProtect`.
+
+use std::collections::HashMap;
+
+use crate::vm::{Vm, VmError, PAGE_SIZE};
+
+use super::ins::{decode_modrm, parse_prefixes, ExecFn, InstructionSet, ModRm, Prefixes};
+
+/// One instruction, decoded up to the point its handler takes over.
+#[derive(Clone, Copy)]
+pub(crate) struct Decoded {
+    /// Address of the opcode byte, past the prefixes.
+    pub(crate) cursor: u32,
+    pub(crate) prefixes: Prefixes,
+    pub(crate) handler: ExecFn,
+    // The last segment override; FS and GS bases are per thread and re-read on reuse.
+    segment: Option<u8>,
+    // ModRM operand pre-decoded at its address, for opcodes that take one.
+    modrm: Option<(u32, ModRm)>,
+    // Last byte the entry was decoded from.
+    end: u32,
+}
+
+pub(crate) struct DecodeCache {
+    enabled: bool,
+    entries: HashMap<u32, Decoded>,
+    // Page number to the entries decoded from it.
+    pages: HashMap<u32, Vec<u32>>,
+    // ModRM of the instruction being executed, handed back by `decode_modrm`.
+    current: Option<(u32, ModRm)>,
+}
+
+impl DecodeCache {
+    pub(crate) fn new(enabled: bool) -> Self {
+        Self {
+            enabled,
+            entries: HashMap::new(),
+            pages: HashMap::new(),
+            current: None,
+        }
+    }
+
+    /// The pre-decoded ModRM of the current instruction, if it sits at `addr`.
+    pub(crate) fn modrm_at(&self, addr: u32, address_size_16: bool) -> Option<ModRm> {
+        match self.current {
+            Some((at, modrm)) if at == addr && modrm.address_size_16 == address_size_16 => {
+                Some(modrm)
+            }
+            _ => None,
+        }
+    }
+
+    fn insert(&mut self, eip: u32, decoded: Decoded) {
+        let first = eip / PAGE_SIZE;
+        let last = decoded.end / PAGE_SIZE;
+        self.pages.entry(first).or_default().push(eip);
+        if last != first {
+            self.pages.entry(last).or_default().push(eip);
+        }
+        self.entries.insert(eip, decoded);
+    }
+
+    fn invalidate(&mut self, pages: &[u32]) {
+        self.current = None;
+        for page in pages {
+            for eip in self.pages.remove(page).unwrap_or_default() {
+                self.entries.remove(&eip);
+            }
+        }
+    }
+}
+
+/// Decode the instruction at EIP, or reuse its cached decoding.
+pub(crate) fn fetch(vm: &mut Vm, set: &InstructionSet) -> Result<Decoded, VmError> {
+    let modified = vm.take_modified_code();
+    if !modified.is_empty() {
+        vm.decode_cache_mut().invalidate(&modified);
+    }
+    let eip = vm.eip();
+    let cached = vm.decode_cache().entries.get(&eip).copied();
+    let decoded = match cached {
+        Some(mut decoded) => {
+            match decoded.segment {
+                Some(0x64) => decoded.prefixes.segment_base = vm.fs_base(),
+                Some(0x65) => decoded.prefixes.segment_base = vm.gs_base(),
+                _ => {}
+            }
+            decoded
+        }
+        None => {
+            vm.decode_cache_mut().current = None;
+            vm.check_execute(eip)?;
+            let decoded = decode(vm, set, eip)?;
+            if vm.decode_cache().enabled {
+                vm.watch_code(eip, decoded.end.wrapping_sub(eip).wrapping_add(1));
+                vm.decode_cache_mut().insert(eip, decoded);
+            }
+            decoded
+        }
+    };
+    vm.decode_cache_mut().current = decoded.modrm;
+    Ok(decoded)
+}
+
+fn decode(vm: &Vm, set: &InstructionSet, eip: u32) -> Result<Decoded, VmError> {
+    let (cursor, prefixes) = parse_prefixes(vm, eip)?;
+    let opcode = vm.read_u8(cursor)?;
+    let handler = set.resolve(opcode, vm, cursor, prefixes)?;
+    let mut segment = None;
+    let mut addr = eip;
+    while addr != cursor {
+        let byte = vm.read_u8(addr)?;
+        if matches!(byte, 0x26 | 0x2E | 0x36 | 0x3E | 0x64 | 0x65) {
+            segment = Some(byte);
+        }
+        addr = addr.wrapping_add(1);
+    }
+    let modrm_addr = match opcode {
+        0x0F => match vm.read_u8(cursor.wrapping_add(1))? {
+            // Jcc rel32, RDTSC, CPUID and BSWAP have no ModRM byte.
+            0x31 | 0x80..=0x8F | 0xA2 | 0xC8..=0xCF => None,
+            _ => Some(cursor.wrapping_add(2)),
+        },
+        _ if has_modrm(opcode) => Some(cursor.wrapping_add(1)),
+        _ => None,
+    };
+    // A guessed ModRM that fails to decode is left to the handler to report.
+    let modrm = modrm_addr.and_then(|addr| {
+        decode_modrm(vm, addr, prefixes)
+            .ok()
+            .map(|modrm| (addr, modrm))
+    });
+    let end = match modrm {
+        Some((addr, modrm)) => addr.wrapping_add(modrm.len as u32 - 1),
+        None => cursor.wrapping_add(u32::from(opcode == 0x0F)),
+    };
+    Ok(Decoded {
+        cursor,
+        prefixes,
+        handler,
+        segment,
+        modrm,
+        end,
+    })
+}
+
+// One-byte opcodes followed by a ModRM byte.
+fn has_modrm(opcode: u8) -> bool {
+    match opcode {
+        0x00..=0x3F => opcode & 0x07 < 4,
+        0x62 | 0x63 | 0x69 | 0x6B => true,
+        0x80..=0x8F => true,
+        0xC0 | 0xC1 | 0xC4..=0xC7 => true,
+        0xD0..=0xD3 | 0xD8..=0xDF => true,
+        0xF6 | 0xF7 | 0xFE | 0xFF => true,
+        _ => false,
+    }
+}
@@ -0,0 +1,752 @@
+//! Prefix, operand and one- and two-byte opcode decoding.
+
+use super::super::ins::{parse_modrm, ModRm};
+use super::MAX_INSTRUCTION_LENGTH;
+use super::{sse, x87, DisasmError, Flow, Instruction, MemoryOperand, Operand};
+
+pub(super) const REG8: [&str; 8] = ["al", "cl", "dl", "bl", "ah", "ch", "dh", "bh"];
+pub(super) const REG16: [&str; 8] = ["ax", "cx", "dx", "bx", "sp", "bp", "si", "di"];
+pub(super) const REG32: [&str; 8] = ["eax", "ecx", "edx", "ebx", "esp", "ebp", "esi", "edi"];
+pub(super) const XMM: [&str; 8] = [
+    "xmm0", "xmm1", "xmm2", "xmm3", "xmm4", "xmm5", "xmm6", "xmm7",
+];
+pub(super) const MM: [&str; 8] = ["mm0", "mm1", "mm2", "mm3", "mm4", "mm5", "mm6", "mm7"];
+const SEG: [&str; 8] = ["es", "cs", "ss", "ds", "fs", "gs", "", ""];
+const CR: [&str; 8] = ["cr0", "cr1", "cr2", "cr3", "cr4", "cr5", "cr6", "cr7"];
+const DR: [&str; 8] = ["dr0", "dr1", "dr2", "dr3", "dr4", "dr5", "dr6", "dr7"];
+
+const ALU: [&str; 8] = ["add", "or", "adc", "sbb", "and", "sub", "xor", "cmp"];
+const SHIFT: [&str; 8] = ["rol", "ror", "rcl", "rcr", "shl", "shr", "sal", "sar"];
+const JCC: [&str; 16] = [
+    "jo", "jno", "jb", "jae", "je", "jne", "jbe", "ja", "js", "jns", "jp", "jnp", "jl", "jge",
+    "jle", "jg",
+];
+const SETCC: [&str; 16] = [
+    "seto", "setno", "setb", "setae", "sete", "setne", "setbe", "seta", "sets", "setns", "setp",
+    "setnp", "setl", "setge", "setle", "setg",
+];
+const CMOVCC: [&str; 16] = [
+    "cmovo", "cmovno", "cmovb", "cmovae", "cmove", "cmovne", "cmovbe", "cmova", "cmovs", "cmovns",
+    "cmovp", "cmovnp", "cmovl", "cmovge", "cmovle", "cmovg",
+];
+
+/// Operand encodings, named after the Intel opcode-map abbreviations.
+#[derive(Clone, Copy)]
+pub(super) enum Op {
+    /// ModRM r/m: byte, word, dword, or operand-sized.
+    Eb,
+    Ew,
+    Ed,
+    Ev,
+    /// ModRM reg: byte, word, dword, or operand-sized.
+    Gb,
+    Gw,
+    Gd,
+    Gv,
+    /// ModRM r/m restricted to memory, of the given size (0: unsized).
+    M(u16),
+    /// General register for r/m, or a word in memory (PINSRW).
+    Rdw,
+    Ib,
+    Iw,
+    Iz,
+    /// Byte immediate sign-extended to the operand size.
+    Ibs,
+    Jb,
+    Jz,
+    /// Absolute moffs: byte, or operand-sized.
+    Ob,
+    Ov,
+    Ap,
+    Reg(&'static str),
+    /// AL/AX/EAX-style accumulator of the operand size.
+    Acc,
+    /// Register in the low three opcode bits.
+    Zb,
+    Zv,
+    Zd,
+    Sw,
+    Cd,
+    Dd,
+    /// ModRM r/m as a 32-bit register.
+    Rd,
+    One,
+    /// XMM register in ModRM reg, XMM register or memory in r/m, XMM register in r/m.
+    V,
+    W(u16),
+    U,
+    /// MMX register in ModRM reg, MMX register or memory in r/m, MMX register in r/m.
+    P,
+    Q(u16),
+    N,
+}
+
+pub(super) struct Decoder<'a> {
+    bytes: &'a [u8],
+    address: u32,
+    pos: usize,
+    pub(super) operand_16: bool,
+    address_16: bool,
+    segment: Option<&'static str>,
+    lock: bool,
+    /// Last of F2/F3, until an SSE opcode claims it as a mandatory prefix.
+    pub(super) rep: Option<u8>,
+    modrm: Option<ModRm>,
+    /// Low opcode byte, for register-in-opcode forms.
+    opcode: u8,
+}
+
+pub(super) fn decode(bytes: &[u8], address: u32) -> Result<Instruction, DisasmError> {
+    let mut d = Decoder {
+        bytes,
+        address,
+        pos: 0,
+        operand_16: false,
+        address_16: false,
+        segment: None,
+        lock: false,
+        rep: None,
+        modrm: None,
+        opcode: 0,
+    };
+    let opcode = d.prefixes()?;
+    d.opcode = opcode;
+    let (mnemonic, operands) = match opcode {
+        0x0F => {
+            let ext = d.u8()?;
+            d.opcode = ext;
+            two_byte(&mut d, ext)?
+        }
+        0xD8..=0xDF => x87::decode(&mut d, opcode)?,
+        _ => one_byte(&mut d, opcode)?,
+    };
+    let prefix = if d.lock {
+        Some("lock")
+    } else {
+        match d.rep {
+            Some(0xF3) if matches!(opcode, 0xA6 | 0xA7 | 0xAE | 0xAF) => Some("repe"),
+            Some(0xF3) => Some("rep"),
+            Some(_) => Some("repne"),
+            None => None,
+        }
+    };
+    Ok(Instruction {
+        address,
+        bytes: bytes[..d.pos].to_vec(),
+        prefix,
+        mnemonic,
+        operands,
+        flow: flow_of(mnemonic),
+    })
+}
+
+fn flow_of(mnemonic: &str) -> Flow {
+    match mnemonic {
+        "jmp" => Flow::Jump,
+        "call" => Flow::Call,
+        "ret" | "retf" | "iret" | "iretd" => Flow::Return,
+        "int" | "int1" | "int3" | "into" | "ud1" | "ud2" | "hlt" | "sysenter" | "sysexit" => {
+            Flow::Interrupt
+        }
+        _ if mnemonic.starts_with('j') || mnemonic.starts_with("loop") => Flow::ConditionalJump,
+        _ => Flow::Sequential,
+    }
+}
+
+impl Decoder<'_> {
+    pub(super) fn u8(&mut self) -> Result<u8, DisasmError> {
+        if self.pos >= MAX_INSTRUCTION_LENGTH {
+            return Err(DisasmError::Invalid);
+        }
+        let byte = *self.bytes.get(self.pos).ok_or(DisasmError::Truncated)?;
+        self.pos += 1;
+        Ok(byte)
+    }
+
+    fn u16(&mut self) -> Result<u16, DisasmError> {
+        Ok(u16::from_le_bytes([self.u8()?, self.u8()?]))
+    }
+
+    fn u32(&mut self) -> Result<u32, DisasmError> {
+        Ok(u32::from_le_bytes([
+            self.u8()?,
+            self.u8()?,
+            self.u8()?,
+            self.u8()?,
+        ]))
+    }
+
+    // Consume the prefixes and return the opcode byte after them.
+    fn prefixes(&mut self) -> Result<u8, DisasmError> {
+        loop {
+            let byte = self.u8()?;
+            match byte {
+                0xF0 => self.lock = true,
+                0xF2 | 0xF3 => self.rep = Some(byte),
+                0x26 => self.segment = Some("es"),
+                0x2E => self.segment = Some("cs"),
+                0x36 => self.segment = Some("ss"),
+                0x3E => self.segment = Some("ds"),
+                0x64 => self.segment = Some("fs"),
+                0x65 => self.segment = Some("gs"),
+                0x66 => self.operand_16 = true,
+                0x67 => self.address_16 = true,
+                _ => return Ok(byte),
+            }
+        }
+    }
+
+    /// The ModRM byte, SIB and displacement, decoded on first use.
+    pub(super) fn modrm(&mut self) -> Result<ModRm, DisasmError> {
+        if let Some(modrm) = self.modrm {
+            return Ok(modrm);
+        }
+        if self.pos >= MAX_INSTRUCTION_LENGTH {
+            return Err(DisasmError::Invalid);
+        }
+        let bytes = self.bytes;
+        let modrm = parse_modrm(
+            |at| {
+                bytes
+                    .get(at as usize)
+                    .copied()
+                    .ok_or(DisasmError::Truncated)
+            },
+            self.pos as u32,
+            self.address_16,
+        )?;
+        self.pos += modrm.len;
+        if self.pos > MAX_INSTRUCTION_LENGTH {
+            return Err(DisasmError::Invalid);
+        }
+        self.modrm = Some(modrm);
+        Ok(modrm)
+    }
+
+    pub(super) fn memory(&self, modrm: &ModRm, size: u16) -> Operand {
+        let (base, index, scale) = if modrm.address_size_16 {
+            let (base, index) = match modrm.rm {
+                0 => (Some("bx"), Some("si")),
+                1 => (Some("bx"), Some("di")),
+                2 => (Some("bp"), Some("si")),
+                3 => (Some("bp"), Some("di")),
+                4 => (Some("si"), None),
+                5 => (Some("di"), None),
+                6 if modrm.mod_bits == 0 => (None, None),
+                6 => (Some("bp"), None),
+                _ => (Some("bx"), None),
+            };
+            (base, index, 1)
+        } else if let Some(sib) = modrm.sib {
+            let base = if modrm.mod_bits == 0 && sib.base == 5 {
+                None
+            } else {
+                Some(REG32[sib.base as usize])
+            };
+            let index = (sib.index != 4).then(|| REG32[sib.index as usize]);
+            (base, index, 1 << sib.scale)
+        } else if modrm.mod_bits == 0 && modrm.rm == 5 {
+            (None, None, 1)
+        } else {
+            (Some(REG32[modrm.rm as usize]), None, 1)
+        };
+        Operand::Memory(MemoryOperand {
+            size,
+            segment: self.segment,
+            base,
+            index,
+            scale,
+            displacement: modrm.disp,
+        })
+    }
+
+    fn operand_size(&self) -> u16 {
+        if self.operand_16 {
+            2
+        } else {
+            4
+        }
+    }
+
+    fn gpr(&self, size: u16, index: u8) -> Operand {
+        let names = match size {
+            1 => &REG8,
+            2 => &REG16,
+            _ => &REG32,
+        };
+        Operand::Register(names[index as usize])
+    }
+
+    // r/m as a register from `regs`, or memory of `size`.
+    fn rm(&mut self, regs: &[&'static str; 8], size: u16) -> Result<Operand, DisasmError> {
+        let modrm = self.modrm()?;
+        if modrm.mod_bits == 3 {
+            Ok(Operand::Register(regs[modrm.rm as usize]))
+        } else {
+            Ok(self.memory(&modrm, size))
+        }
+    }
+
+    fn rm_gpr(&mut self, size: u16) -> Result<Operand, DisasmError> {
+        let modrm = self.modrm()?;
+        if modrm.mod_bits == 3 {
+            Ok(self.gpr(size, modrm.rm))
+        } else {
+            Ok(self.memory(&modrm, size))
+        }
+    }
+
+    fn rm_memory(&mut self, size: u16) -> Result<Operand, DisasmError> {
+        let modrm = self.modrm()?;
+        if modrm.mod_bits == 3 {
+            return Err(DisasmError::Invalid);
+        }
+        Ok(self.memory(&modrm, size))
+    }
+
+    fn rm_register(&mut self, regs: &[&'static str; 8]) -> Result<Operand, DisasmError> {
+        let modrm = self.modrm()?;
+        if modrm.mod_bits != 3 {
+            return Err(DisasmError::Invalid);
+        }
+        Ok(Operand::Register(regs[modrm.rm as usize]))
+    }
+
+    fn reg(&mut self, regs: &[&'static str; 8]) -> Result<Operand, DisasmError> {
+        let modrm = self.modrm()?;
+        Ok(Operand::Register(regs[modrm.reg as usize]))
+    }
+
+    fn immediate(&mut self, size: u8) -> Result<Operand, DisasmError> {
+        let value = match size {
+            1 => self.u8()? as u32,
+            2 => self.u16()? as u32,
+            _ => self.u32()?,
+        };
+        Ok(Operand::Immediate { value, size })
+    }
+
+    // Relative branch; 16-bit operand size truncates the target like the executor.
+    fn relative(&mut self, size: u8) -> Result<Operand, DisasmError> {
+        let rel = match size {
+            1 => self.u8()? as i8 as i32,
+            2 => self.u16()? as i16 as i32,
+            _ => self.u32()? as i32,
+        };
+        let next = self.address.wrapping_add(self.pos as u32);
+        let target = next.wrapping_add(rel as u32);
+        Ok(Operand::Target(if self.operand_16 {
+            target & 0xFFFF
+        } else {
+            target
+        }))
+    }
+
+    fn moffs(&mut self, size: u16) -> Result<Operand, DisasmError> {
+        let displacement = if self.address_16 {
+            self.u16()? as i32
+        } else {
+            self.u32()? as i32
+        };
+        Ok(Operand::Memory(MemoryOperand {
+            size,
+            segment: self.segment,
+            base: None,
+            index: None,
+            scale: 1,
+            displacement,
+        }))
+    }
+
+    fn operand(&mut self, op: Op) -> Result<Operand, DisasmError> {
+        let v = self.operand_size();
+        match op {
+            Op::Eb => self.rm_gpr(1),
+            Op::Ew => self.rm_gpr(2),
+            Op::Ed => self.rm_gpr(4),
+            Op::Ev => self.rm_gpr(v),
+            Op::Gb => self.reg(&REG8),
+            Op::Gw => self.reg(&REG16),
+            Op::Gd => self.reg(&REG32),
+            Op::Gv => {
+                let modrm = self.modrm()?;
+                Ok(self.gpr(v, modrm.reg))
+            }
+            Op::M(size) => self.rm_memory(size),
+            Op::Rdw => self.rm(&REG32, 2),
+            Op::Ib => self.immediate(1),
+            Op::Iw => self.immediate(2),
+            Op::Iz => self.immediate(v as u8),
+            Op::Ibs => {
+                let value = self.u8()? as i8 as i32 as u32;
+                Ok(Operand::Immediate {
+                    value,
+                    size: v as u8,
+                })
+            }
+            Op::Jb => self.relative(1),
+            Op::Jz => self.relative(v as u8),
+            Op::Ob => self.moffs(1),
+            Op::Ov => self.moffs(v),
+            Op::Ap => {
+                let offset = if self.operand_16 {
+                    self.u16()? as u32
+                } else {
+                    self.u32()?
+                };
+                let selector = self.u16()?;
+                Ok(Operand::FarPointer { selector, offset })
+            }
+            Op::Reg(name) => Ok(Operand::Register(name)),
+            Op::Acc => Ok(self.gpr(v, 0)),
+            Op::Zb => Ok(self.gpr(1, self.opcode & 7)),
+            Op::Zv => Ok(self.gpr(v, self.opcode & 7)),
+            Op::Zd => Ok(self.gpr(4, self.opcode & 7)),
+            Op::Sw => {
+                let modrm = self.modrm()?;
+                match SEG[modrm.reg as usize] {
+                    "" => Err(DisasmError::Invalid),
+                    name => Ok(Operand::Register(name)),
+                }
+            }
+            Op::Cd => self.reg(&CR),
+            Op::Dd => self.reg(&DR),
+            Op::Rd => self.rm_register(&REG32),
+            Op::One => Ok(Operand::Immediate { value: 1, size: 1 }),
+            Op::V => self.reg(&XMM),
+            Op::W(size) => self.rm(&XMM, size),
+            Op::U => self.rm_register(&XMM),
+            Op::P => self.reg(&MM),
+            Op::Q(size) => self.rm(&MM, size),
+            Op::N => self.rm_register(&MM),
+        }
+    }
+
+    pub(super) fn operands(&mut self, ops: &[Op]) -> Result<Vec<Operand>, DisasmError> {
+        ops.iter().map(|op| self.operand(*op)).collect()
+    }
+
+    // The word or dword spelling of an operand-size dependent mnemonic.
+    fn sized(&self, word: &'static str, dword: &'static str) -> &'static str {
+        if self.operand_16 {
+            word
+        } else {
+            dword
+        }
+    }
+}
+
+pub(super) type Decoded = (&'static str, Vec<Operand>);
+
+fn one_byte(d: &mut Decoder, opcode: u8) -> Result<Decoded, DisasmError> {
+    use Op::*;
+    let (mnemonic, ops): (&'static str, &[Op]) = match opcode {
+        0x00..=0x3F if opcode & 7 < 6 => {
+            let ops: &[Op] = match opcode & 7 {
+                0 => &[Eb, Gb],
+                1 => &[Ev, Gv],
+                2 => &[Gb, Eb],
+                3 => &[Gv, Ev],
+                4 => &[Reg("al"), Ib],
+                _ => &[Acc, Iz],
+            };
+            (ALU[(opcode >> 3) as usize], ops)
+        }
+        0x06 => ("push", &[Reg("es")]),
+        0x07 => ("pop", &[Reg("es")]),
+        0x0E => ("push", &[Reg("cs")]),
+        0x16 => ("push", &[Reg("ss")]),
+        0x17 => ("pop", &[Reg("ss")]),
+        0x1E => ("push", &[Reg("ds")]),
+        0x1F => ("pop", &[Reg("ds")]),
+        0x27 => ("daa", &[]),
+        0x2F => ("das", &[]),
+        0x37 => ("aaa", &[]),
+        0x3F => ("aas", &[]),
+        0x40..=0x47 => ("inc", &[Zv]),
+        0x48..=0x4F => ("dec", &[Zv]),
+        0x50..=0x57 => ("push", &[Zv]),
+        0x58..=0x5F => ("pop", &[Zv]),
+        0x60 => (d.sized("pusha", "pushad"), &[]),
+        0x61 => (d.sized("popa", "popad"), &[]),
+        0x62 => ("bound", &[Gv, M(0)]),
+        0x63 => ("arpl", &[Ew, Gw]),
+        0x68 => ("push", &[Iz]),
+        0x69 => ("imul", &[Gv, Ev, Iz]),
+        0x6A => ("push", &[Ibs]),
+        0x6B => ("imul", &[Gv, Ev, Ibs]),
+        0x6C => ("insb", &[]),
+        0x6D => (d.sized("insw", "insd"), &[]),
+        0x6E => ("outsb", &[]),
+        0x6F => (d.sized("outsw", "outsd"), &[]),
+        0x70..=0x7F => (JCC[(opcode & 0xF) as usize], &[Jb]),
+        0x80 | 0x82 => (ALU[d.modrm()?.reg as usize], &[Eb, Ib]),
+        0x81 => (ALU[d.modrm()?.reg as usize], &[Ev, Iz]),
+        0x83 => (ALU[d.modrm()?.reg as usize], &[Ev, Ibs]),
+        0x84 => ("test", &[Eb, Gb]),
+        0x85 => ("test", &[Ev, Gv]),
+        0x86 => ("xchg", &[Eb, Gb]),
+        0x87 => ("xchg", &[Ev, Gv]),
+        0x88 => ("mov", &[Eb, Gb]),
+        0x89 => ("mov", &[Ev, Gv]),
+        0x8A => ("mov", &[Gb, Eb]),
+        0x8B => ("mov", &[Gv, Ev]),
+        0x8C => ("mov", &[Ew, Sw]),
+        0x8D => ("lea", &[Gv, M(0)]),
+        0x8E => ("mov", &[Sw, Ew]),
+        0x8F if d.modrm()?.reg == 0 => ("pop", &[Ev]),
+        0x90 if d.rep == Some(0xF3) => {
+            d.rep = None;
+            ("pause", &[])
+        }
+        0x90 => ("nop", &[]),
+        0x91..=0x97 => ("xchg", &[Zv, Acc]),
+        0x98 => (d.sized("cbw", "cwde"), &[]),
+        0x99 => (d.sized("cwd", "cdq"), &[]),
+        0x9A => ("call", &[Ap]),
+        0x9B => ("fwait", &[]),
+        0x9C => (d.sized("pushf", "pushfd"), &[]),
+        0x9D => (d.sized("popf", "popfd"), &[]),
+        0x9E => ("sahf", &[]),
+        0x9F => ("lahf", &[]),
+        0xA0 => ("mov", &[Reg("al"), Ob]),
+        0xA1 => ("mov", &[Acc, Ov]),
+        0xA2 => ("mov", &[Ob, Reg("al")]),
+        0xA3 => ("mov", &[Ov, Acc]),
+        0xA4 => ("movsb", &[]),
+        0xA5 => (d.sized("movsw", "movsd"), &[]),
+        0xA6 => ("cmpsb", &[]),
+        0xA7 => (d.sized("cmpsw", "cmpsd"), &[]),
+        0xA8 => ("test", &[Reg("al"), Ib]),
+        0xA9 => ("test", &[Acc, Iz]),
+        0xAA => ("stosb", &[]),
+        0xAB => (d.sized("stosw", "stosd"), &[]),
+        0xAC => ("lodsb", &[]),
+        0xAD => (d.sized("lodsw", "lodsd"), &[]),
+        0xAE => ("scasb", &[]),
+        0xAF => (d.sized("scasw", "scasd"), &[]),
+        0xB0..=0xB7 => ("mov", &[Zb, Ib]),
+        0xB8..=0xBF => ("mov", &[Zv, Iz]),
+        0xC0 => (SHIFT[d.modrm()?.reg as usize], &[Eb, Ib]),
+        0xC1 => (SHIFT[d.modrm()?.reg as usize], &[Ev, Ib]),
+        0xC2 => ("ret", &[Iw]),
+        0xC3 => ("ret", &[]),
+        0xC4 => ("les", &[Gv, M(6)]),
+        0xC5 => ("lds", &[Gv, M(6)]),
+        0xC6 if d.modrm()?.reg == 0 => ("mov", &[Eb, Ib]),
+        0xC7 if d.modrm()?.reg == 0 => ("mov", &[Ev, Iz]),
+        0xC8 => ("enter", &[Iw, Ib]),
+        0xC9 => ("leave", &[]),
+        0xCA => ("retf", &[Iw]),
+        0xCB => ("retf", &[]),
+        0xCC => ("int3", &[]),
+        0xCD => ("int", &[Ib]),
+        0xCE => ("into", &[]),
+        0xCF => (d.sized("iret", "iretd"), &[]),
+        0xD0 => (SHIFT[d.modrm()?.reg as usize], &[Eb, One]),
+        0xD1 => (SHIFT[d.modrm()?.reg as usize], &[Ev, One]),
+        0xD2 => (SHIFT[d.modrm()?.reg as usize], &[Eb, Reg("cl")]),
+        0xD3 => (SHIFT[d.modrm()?.reg as usize], &[Ev, Reg("cl")]),
+        0xD4 => ("aam", &[Ib]),
+        0xD5 => ("aad", &[Ib]),
+        0xD6 => ("salc", &[]),
+        0xD7 => ("xlatb", &[]),
+        0xE0 => ("loopne", &[Jb]),
+        0xE1 => ("loope", &[Jb]),
+        0xE2 => ("loop", &[Jb]),
+        0xE3 if d.address_16 => ("jcxz", &[Jb]),
+        0xE3 => ("jecxz", &[Jb]),
+        0xE4 => ("in", &[Reg("al"), Ib]),
+        0xE5 => ("in", &[Acc, Ib]),
+        0xE6 => ("out", &[Ib, Reg("al")]),
+        0xE7 => ("out", &[Ib, Acc]),
+        0xE8 => ("call", &[Jz]),
+        0xE9 => ("jmp", &[Jz]),
+        0xEA => ("jmp", &[Ap]),
+        0xEB => ("jmp", &[Jb]),
+        0xEC => ("in", &[Reg("al"), Reg("dx")]),
+        0xED => ("in", &[Acc, Reg("dx")]),
+        0xEE => ("out", &[Reg("dx"), Reg("al")]),
+        0xEF => ("out", &[Reg("dx"), Acc]),
+        0xF1 => ("int1", &[]),
+        0xF4 => ("hlt", &[]),
+        0xF5 => ("cmc", &[]),
+        0xF6 | 0xF7 => {
+            let reg = d.modrm()?.reg;
+            let byte = opcode == 0xF6;
+            let mnemonic =
+                ["test", "test", "not", "neg", "mul", "imul", "div", "idiv"][reg as usize];
+            let ops: &[Op] = match (reg, byte) {
+                (0 | 1, true) => &[Eb, Ib],
+                (0 | 1, false) => &[Ev, Iz],
+                (_, true) => &[Eb],
+                (_, false) => &[Ev],
+            };
+            (mnemonic, ops)
+        }
+        0xF8 => ("clc", &[]),
+        0xF9 => ("stc", &[]),
+        0xFA => ("cli", &[]),
+        0xFB => ("sti", &[]),
+        0xFC => ("cld", &[]),
+        0xFD => ("std", &[]),
+        0xFE => match d.modrm()?.reg {
+            0 => ("inc", &[Eb]),
+            1 => ("dec", &[Eb]),
+            _ => return Err(DisasmError::Invalid),
+        },
+        0xFF => match d.modrm()?.reg {
+            0 => ("inc", &[Ev]),
+            1 => ("dec", &[Ev]),
+            2 => ("call", &[Ev]),
+            3 => ("call", &[M(6)]),
+            4 => ("jmp", &[Ev]),
+            5 => ("jmp", &[M(6)]),
+            6 => ("push", &[Ev]),
+            _ => return Err(DisasmError::Invalid),
+        },
+        _ => return Err(DisasmError::Invalid),
+    };
+    Ok((mnemonic, d.operands(ops)?))
+}
+
+fn two_byte(d: &mut Decoder, ext: u8) -> Result<Decoded, DisasmError> {
+    use Op::*;
+    let (mnemonic, ops): (&'static str, &[Op]) = match ext {
+        0x00 => match d.modrm()?.reg {
+            0 => ("sldt", &[Ew]),
+            1 => ("str", &[Ew]),
+            2 => ("lldt", &[Ew]),
+            3 => ("ltr", &[Ew]),
+            4 => ("verr", &[Ew]),
+            5 => ("verw", &[Ew]),
+            _ => return Err(DisasmError::Invalid),
+        },
+        0x01 => {
+            let modrm = d.modrm()?;
+            match (modrm.mod_bits == 3, modrm.reg, modrm.rm) {
+                (true, 1, 0) => ("monitor", &[]),
+                (true, 1, 1) => ("mwait", &[]),
+                (true, 2, 0) => ("xgetbv", &[]),
+                (true, 2, 1) => ("xsetbv", &[]),
+                (true, 7, 1) => ("rdtscp", &[]),
+                (_, 4, _) => ("smsw", &[Ew]),
+                (_, 6, _) => ("lmsw", &[Ew]),
+                (true, _, _) => return Err(DisasmError::Invalid),
+                (false, 0, _) => ("sgdt", &[M(6)]),
+                (false, 1, _) => ("sidt", &[M(6)]),
+                (false, 2, _) => ("lgdt", &[M(6)]),
+                (false, 3, _) => ("lidt", &[M(6)]),
+                (false, 7, _) => ("invlpg", &[M(0)]),
+                _ => return Err(DisasmError::Invalid),
+            }
+        }
+        0x06 => ("clts", &[]),
+        0x08 => ("invd", &[]),
+        0x09 => ("wbinvd", &[]),
+        0x0B => ("ud2", &[]),
+        0x0D if d.modrm()?.reg == 1 => ("prefetchw", &[M(0)]),
+        0x0D => ("prefetch", &[M(0)]),
+        0x18 => {
+            let modrm = d.modrm()?;
+            match modrm.reg {
+                0 if modrm.mod_bits != 3 => ("prefetchnta", &[M(0)]),
+                1 if modrm.mod_bits != 3 => ("prefetcht0", &[M(0)]),
+                2 if modrm.mod_bits != 3 => ("prefetcht1", &[M(0)]),
+                3 if modrm.mod_bits != 3 => ("prefetcht2", &[M(0)]),
+                _ => ("nop", &[Ev]),
+            }
+        }
+        0x19..=0x1F => ("nop", &[Ev]),
+        0x20 => ("mov", &[Rd, Cd]),
+        0x21 => ("mov", &[Rd, Dd]),
+        0x22 => ("mov", &[Cd, Rd]),
+        0x23 => ("mov", &[Dd, Rd]),
+        0x30 => ("wrmsr", &[]),
+        0x31 => ("rdtsc", &[]),
+        0x32 => ("rdmsr", &[]),
+        0x33 => ("rdpmc", &[]),
+        0x34 => ("sysenter", &[]),
+        0x35 => ("sysexit", &[]),
+        0x40..=0x4F => (CMOVCC[(ext & 0xF) as usize], &[Gv, Ev]),
+        0x80..=0x8F => (JCC[(ext & 0xF) as usize], &[Jz]),
+        0x90..=0x9F => (SETCC[(ext & 0xF) as usize], &[Eb]),
+        0xA0 => ("push", &[Reg("fs")]),
+        0xA1 => ("pop", &[Reg("fs")]),
+        0xA2 => ("cpuid", &[]),
+        0xA3 => ("bt", &[Ev, Gv]),
+        0xA4 => ("shld", &[Ev, Gv, Ib]),
+        0xA5 => ("shld", &[Ev, Gv, Reg("cl")]),
+        0xA8 => ("push", &[Reg("gs")]),
+        0xA9 => ("pop", &[Reg("gs")]),
+        0xAA => ("rsm", &[]),
+        0xAB => ("bts", &[Ev, Gv]),
+        0xAC => ("shrd", &[Ev, Gv, Ib]),
+        0xAD => ("shrd", &[Ev, Gv, Reg("cl")]),
+        0xAE => {
+            let modrm = d.modrm()?;
+            match (modrm.mod_bits == 3, modrm.reg) {
+                (true, 5) => ("lfence", &[]),
+                (true, 6) => ("mfence", &[]),
+                (true, 7) => ("sfence", &[]),
+                (true, _) => return Err(DisasmError::Invalid),
+                (false, 0) => ("fxsave", &[M(0)]),
+                (false, 1) => ("fxrstor", &[M(0)]),
+                (false, 2) => ("ldmxcsr", &[M(4)]),
+                (false, 3) => ("stmxcsr", &[M(4)]),
+                (false, 4) => ("xsave", &[M(0)]),
+                (false, 5) => ("xrstor", &[M(0)]),
+                (false, 6) => ("xsaveopt", &[M(0)]),
+                _ => ("clflush", &[M(1)]),
+            }
+        }
+        0xAF => ("imul", &[Gv, Ev]),
+        0xB0 => ("cmpxchg", &[Eb, Gb]),
+        0xB1 => ("cmpxchg", &[Ev, Gv]),
+        0xB2 => ("lss", &[Gv, M(6)]),
+        0xB3 => ("btr", &[Ev, Gv]),
+        0xB4 => ("lfs", &[Gv, M(6)]),
+        0xB5 => ("lgs", &[Gv, M(6)]),
+        0xB6 => ("movzx", &[Gv, Eb]),
+        0xB7 => ("movzx", &[Gv, Ew]),
+        0xB8 if d.rep == Some(0xF3) => {
+            d.rep = None;
+            ("popcnt", &[Gv, Ev])
+        }
+        0xB9 => ("ud1", &[Gv, Ev]),
+        0xBA => match d.modrm()?.reg {
+            4 => ("bt", &[Ev, Ib]),
+            5 => ("bts", &[Ev, Ib]),
+            6 => ("btr", &[Ev, Ib]),
+            7 => ("btc", &[Ev, Ib]),
+            _ => return Err(DisasmError::Invalid),
+        },
+        0xBB => ("btc", &[Ev, Gv]),
+        0xBC | 0xBD if d.rep == Some(0xF3) => {
+            d.rep = None;
+            (if ext == 0xBC { "tzcnt" } else { "lzcnt" }, &[Gv, Ev])
+        }
+        0xBC => ("bsf", &[Gv, Ev]),
+        0xBD => ("bsr", &[Gv, Ev]),
+        0xBE => ("movsx", &[Gv, Eb]),
+        0xBF => ("movsx", &[Gv, Ew]),
+        0xC0 => ("xadd", &[Eb, Gb]),
+        0xC1 => ("xadd", &[Ev, Gv]),
+        0xC3 => ("movnti", &[M(4), Gd]),
+        0xC7 => {
+            let modrm = d.modrm()?;
+            match (modrm.mod_bits == 3, modrm.reg) {
+                (false, 1) => ("cmpxchg8b", &[M(8)]),
+                (true, 6) => ("rdrand", &[Ev]),
+                (true, 7) => ("rdseed", &[Ev]),
+                _ => return Err(DisasmError::Invalid),
+            }
+        }
+        0xC8..=0xCF => ("bswap", &[Zd]),
+        0x10..=0x17 | 0x28..=0x2F | 0x50..=0x7F | 0xC2 | 0xC4..=0xC6 | 0xD0..=0xFF => {
+            return sse::decode(d, ext);
+        }
+        _ => return Err(DisasmError::Invalid),
+    };
+    Ok((mnemonic, d.operands(ops)?))
+}
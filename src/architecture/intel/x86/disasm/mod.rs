@@ -0,0 +1,366 @@
+//! Standalone x86 disassembler.
+//!
+//! Decodes 32-bit protected-mode code into structured instructions that render as
+//! Intel syntax. It shares the ModRM parser with the executor but nothing else, so
+//! it also covers opcodes the executor does not implement: the general-purpose
+//! one- and two-byte maps, x87, and MMX/SSE/SSE2.
+//!
+//! ```
+//! use pe_vm::disasm;
+//!
+//! let ins = disasm::decode(&[0xF0, 0xFF, 0x03], 0x1000).unwrap();
+//! assert_eq!(ins.to_string(), "lock inc dword ptr [ebx]");
+//! assert_eq!(ins.length(), 3);
+//! ```
+
+mod decoder;
+mod sse;
+mod x87;
+
+use std::fmt;
+
+/// Longest encoding the processor accepts.
+pub const MAX_INSTRUCTION_LENGTH: usize = 15;
+
+/// How an instruction affects control flow.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Flow {
+    Sequential,
+    Jump,
+    /// Jcc, LOOP and JECXZ: either falls through or branches.
+    ConditionalJump,
+    Call,
+    Return,
+    /// INT, UD2, HLT and other instructions that trap or stop.
+    Interrupt,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Operand {
+    Register(&'static str),
+    /// Immediate of `size` bytes; sign-extended immediates are stored extended.
+    Immediate {
+        value: u32,
+        size: u8,
+    },
+    Memory(MemoryOperand),
+    /// Resolved destination of a relative branch.
+    Target(u32),
+    /// `ptr16:32` operand of a far CALL or JMP.
+    FarPointer {
+        selector: u16,
+        offset: u32,
+    },
+}
+
+/// `size ptr segment:[base + index*scale + displacement]`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MemoryOperand {
+    /// Access size in bytes; 0 for operands without one (LEA, FXSAVE, ...).
+    pub size: u16,
+    pub segment: Option<&'static str>,
+    pub base: Option<&'static str>,
+    pub index: Option<&'static str>,
+    pub scale: u8,
+    pub displacement: i32,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Instruction {
+    pub address: u32,
+    pub bytes: Vec<u8>,
+    /// LOCK or REP-family prefix, when it is not part of the opcode.
+    pub prefix: Option<&'static str>,
+    pub mnemonic: &'static str,
+    pub operands: Vec<Operand>,
+    pub flow: Flow,
+}
+
+impl Instruction {
+    /// Encoded length in bytes, prefixes included.
+    pub fn length(&self) -> usize {
+        self.bytes.len()
+    }
+
+    pub fn next_address(&self) -> u32 {
+        self.address.wrapping_add(self.bytes.len() as u32)
+    }
+
+    /// Destination of a relative JMP, Jcc, LOOP or CALL.
+    pub fn branch_target(&self) -> Option<u32> {
+        self.operands.iter().find_map(|operand| match operand {
+            Operand::Target(target) => Some(*target),
+            _ => None,
+        })
+    }
+
+    // `db 0xXX` for a byte that does not start a valid instruction.
+    fn data_byte(address: u32, byte: u8) -> Self {
+        Self {
+            address,
+            bytes: vec![byte],
+            prefix: None,
+            mnemonic: "db",
+            operands: vec![Operand::Immediate {
+                value: byte as u32,
+                size: 1,
+            }],
+            flow: Flow::Sequential,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DisasmError {
+    /// The bytes end in the middle of an instruction.
+    Truncated,
+    /// Undefined opcode, or an encoding longer than 15 bytes.
+    Invalid,
+}
+
+impl fmt::Display for DisasmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DisasmError::Truncated => write!(f, "truncated instruction"),
+            DisasmError::Invalid => write!(f, "invalid instruction"),
+        }
+    }
+}
+
+impl std::error::Error for DisasmError {}
+
+/// Decode the instruction at the start of `bytes`, which sit at `address`.
+pub fn decode(bytes: &[u8], address: u32) -> Result<Instruction, DisasmError> {
+    decoder::decode(bytes, address)
+}
+
+/// Decode all of `bytes`; bytes that do not form an instruction become `db`.
+pub fn disassemble(bytes: &[u8], address: u32) -> Vec<Instruction> {
+    let mut out = Vec::new();
+    let mut offset = 0usize;
+    while offset < bytes.len() {
+        let at = address.wrapping_add(offset as u32);
+        let ins = decode(&bytes[offset..], at)
+            .unwrap_or_else(|_| Instruction::data_byte(at, bytes[offset]));
+        offset += ins.length();
+        out.push(ins);
+    }
+    out
+}
+
+/// Decode up to `count` instructions, fetching bytes through `read_u8` until it
+/// fails; an undecodable byte becomes `db` and decoding carries on after it.
+pub(crate) fn disassemble_with(
+    read_u8: impl Fn(u32) -> Option<u8>,
+    address: u32,
+    count: usize,
+) -> Vec<Instruction> {
+    let mut out = Vec::new();
+    let mut at = address;
+    while out.len() < count {
+        let mut bytes = Vec::with_capacity(MAX_INSTRUCTION_LENGTH);
+        while bytes.len() < MAX_INSTRUCTION_LENGTH {
+            match read_u8(at.wrapping_add(bytes.len() as u32)) {
+                Some(byte) => bytes.push(byte),
+                None => break,
+            }
+        }
+        if bytes.is_empty() {
+            break;
+        }
+        let ins = decode(&bytes, at).unwrap_or_else(|_| Instruction::data_byte(at, bytes[0]));
+        at = ins.next_address();
+        out.push(ins);
+    }
+    out
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(prefix) = self.prefix {
+            write!(f, "{prefix} ")?;
+        }
+        f.write_str(self.mnemonic)?;
+        for (index, operand) in self.operands.iter().enumerate() {
+            let separator = if index == 0 { " " } else { ", " };
+            write!(f, "{separator}{operand}")?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Operand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Operand::Register(name) => f.write_str(name),
+            Operand::Immediate { value, size } => {
+                let value = match size {
+                    1 => value & 0xFF,
+                    2 => value & 0xFFFF,
+                    _ => *value,
+                };
+                write!(f, "0x{value:X}")
+            }
+            Operand::Memory(memory) => write!(f, "{memory}"),
+            Operand::Target(target) => write!(f, "0x{target:08X}"),
+            Operand::FarPointer { selector, offset } => {
+                write!(f, "0x{selector:04X}:0x{offset:08X}")
+            }
+        }
+    }
+}
+
+impl fmt::Display for MemoryOperand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let size = match self.size {
+            1 => Some("byte"),
+            2 => Some("word"),
+            4 => Some("dword"),
+            6 => Some("fword"),
+            8 => Some("qword"),
+            10 => Some("tbyte"),
+            16 => Some("xmmword"),
+            _ => None,
+        };
+        if let Some(size) = size {
+            write!(f, "{size} ptr ")?;
+        }
+        if let Some(segment) = self.segment {
+            write!(f, "{segment}:")?;
+        }
+        f.write_str("[")?;
+        if self.base.is_none() && self.index.is_none() {
+            return write!(f, "0x{:08X}]", self.displacement as u32);
+        }
+        if let Some(base) = self.base {
+            f.write_str(base)?;
+        }
+        if let Some(index) = self.index {
+            if self.base.is_some() {
+                f.write_str("+")?;
+            }
+            f.write_str(index)?;
+            if self.scale > 1 {
+                write!(f, "*{}", self.scale)?;
+            }
+        }
+        if self.displacement > 0 {
+            write!(f, "+0x{:X}", self.displacement)?;
+        } else if self.displacement < 0 {
+            write!(f, "-0x{:X}", self.displacement.unsigned_abs())?;
+        }
+        f.write_str("]")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(bytes: &[u8]) -> String {
+        let ins = decode(bytes, 0x1000).expect("decode");
+        assert_eq!(ins.length(), bytes.len(), "length of {ins}");
+        ins.to_string()
+    }
+
+    #[test]
+    fn decodes_general_purpose_forms() {
+        assert_eq!(text(&[0x55]), "push ebp");
+        assert_eq!(text(&[0x8B, 0xEC]), "mov ebp, esp");
+        assert_eq!(
+            text(&[0x8B, 0x4C, 0x24, 0x04]),
+            "mov ecx, dword ptr [esp+0x4]"
+        );
+        assert_eq!(
+            text(&[0x8D, 0x84, 0x8E, 0x00, 0x01, 0x00, 0x00]),
+            "lea eax, [esi+ecx*4+0x100]"
+        );
+        assert_eq!(text(&[0x83, 0xC4, 0xF0]), "add esp, 0xFFFFFFF0");
+        assert_eq!(
+            text(&[0x80, 0x7D, 0xFC, 0x00]),
+            "cmp byte ptr [ebp-0x4], 0x0"
+        );
+        assert_eq!(
+            text(&[0xC7, 0x05, 0x00, 0x30, 0x40, 0x00, 0x01, 0x00, 0x00, 0x00]),
+            "mov dword ptr [0x00403000], 0x1"
+        );
+        assert_eq!(text(&[0xD1, 0xE2]), "shl edx, 0x1");
+        assert_eq!(text(&[0x0F, 0xB6, 0xC1]), "movzx eax, cl");
+        assert_eq!(text(&[0x0F, 0xA4, 0xC6, 0x04]), "shld esi, eax, 0x4");
+        assert_eq!(
+            text(&[0x69, 0xC0, 0x10, 0x00, 0x00, 0x00]),
+            "imul eax, eax, 0x10"
+        );
+        assert_eq!(text(&[0xC3]), "ret");
+    }
+
+    #[test]
+    fn applies_prefixes() {
+        assert_eq!(text(&[0xF0, 0xFF, 0x03]), "lock inc dword ptr [ebx]");
+        assert_eq!(text(&[0x66, 0x05, 0xFF, 0xFF]), "add ax, 0xFFFF");
+        assert_eq!(text(&[0x66, 0x6A, 0x7F]), "push 0x7F");
+        assert_eq!(text(&[0x67, 0x8B, 0x00]), "mov eax, dword ptr [bx+si]");
+        assert_eq!(text(&[0x67, 0x8D, 0x4F, 0xF0]), "lea ecx, [bx-0x10]");
+        assert_eq!(
+            text(&[0x64, 0xA1, 0x18, 0x00, 0x00, 0x00]),
+            "mov eax, dword ptr fs:[0x00000018]"
+        );
+        assert_eq!(text(&[0xF3, 0xA5]), "rep movsd");
+        assert_eq!(text(&[0xF2, 0xAE]), "repne scasb");
+        assert_eq!(text(&[0x66, 0x98]), "cbw");
+    }
+
+    #[test]
+    fn resolves_branch_targets() {
+        let ins = decode(&[0x75, 0xF5], 0x1010).expect("jnz");
+        assert_eq!(ins.to_string(), "jne 0x00001007");
+        assert_eq!(ins.flow, Flow::ConditionalJump);
+        assert_eq!(ins.branch_target(), Some(0x1007));
+
+        let ins = decode(&[0xE8, 0x00, 0x01, 0x00, 0x00], 0x1000).expect("call");
+        assert_eq!(ins.branch_target(), Some(0x1105));
+        assert_eq!(ins.flow, Flow::Call);
+
+        let ins = decode(&[0xFF, 0x15, 0x00, 0x20, 0x40, 0x00], 0x1000).expect("call");
+        assert_eq!(ins.to_string(), "call dword ptr [0x00402000]");
+        assert_eq!(ins.branch_target(), None);
+        assert_eq!(ins.next_address(), 0x1006);
+
+        let ins = decode(&[0xC2, 0x08, 0x00], 0x1000).expect("ret");
+        assert_eq!(ins.flow, Flow::Return);
+    }
+
+    #[test]
+    fn decodes_x87_and_sse() {
+        assert_eq!(text(&[0xD9, 0xE8]), "fld1");
+        assert_eq!(text(&[0xDE, 0xC1]), "faddp st(1), st");
+        assert_eq!(text(&[0xDD, 0x45, 0x08]), "fld qword ptr [ebp+0x8]");
+        assert_eq!(text(&[0xDB, 0x2E]), "fld tbyte ptr [esi]");
+        assert_eq!(text(&[0xDF, 0xE0]), "fnstsw ax");
+        assert_eq!(text(&[0xF2, 0x0F, 0x58, 0xC1]), "addsd xmm0, xmm1");
+        assert_eq!(
+            text(&[0xF3, 0x0F, 0x10, 0x06]),
+            "movss xmm0, dword ptr [esi]"
+        );
+        assert_eq!(text(&[0x0F, 0x28, 0xC8]), "movaps xmm1, xmm0");
+        assert_eq!(text(&[0x66, 0x0F, 0xEF, 0xC0]), "pxor xmm0, xmm0");
+        assert_eq!(text(&[0x0F, 0xEF, 0xC0]), "pxor mm0, mm0");
+        assert_eq!(text(&[0x66, 0x0F, 0x73, 0xD8, 0x08]), "psrldq xmm0, 0x8");
+        assert_eq!(
+            text(&[0x0F, 0xAE, 0x5D, 0xFC]),
+            "stmxcsr dword ptr [ebp-0x4]"
+        );
+    }
+
+    #[test]
+    fn reports_truncated_and_invalid_bytes() {
+        assert_eq!(decode(&[0xE8, 0x00], 0), Err(DisasmError::Truncated));
+        assert_eq!(decode(&[0x0F, 0x0F], 0), Err(DisasmError::Invalid));
+        assert_eq!(decode(&[0x66; 16], 0), Err(DisasmError::Invalid));
+
+        let listing = disassemble(&[0x90, 0x0F, 0x0F, 0xC3], 0x2000);
+        let text: Vec<String> = listing.iter().map(ToString::to_string).collect();
+        assert_eq!(text, ["nop", "db 0xF", "db 0xF", "ret"]);
+        assert_eq!(listing[3].address, 0x2003);
+    }
+}
@@ -0,0 +1,248 @@
+//! MMX, SSE and SSE2 opcodes of the 0F map, keyed by their mandatory prefix.
+
+use super::decoder::{Decoded, Decoder, Op};
+use super::DisasmError;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Mandatory {
+    Plain,
+    P66,
+    F3,
+    F2,
+}
+
+// Integer instructions with an MMX form and, behind 66, an XMM form.
+fn integer_name(ext: u8) -> Option<&'static str> {
+    Some(match ext {
+        0x60 => "punpcklbw",
+        0x61 => "punpcklwd",
+        0x62 => "punpckldq",
+        0x63 => "packsswb",
+        0x64 => "pcmpgtb",
+        0x65 => "pcmpgtw",
+        0x66 => "pcmpgtd",
+        0x67 => "packuswb",
+        0x68 => "punpckhbw",
+        0x69 => "punpckhwd",
+        0x6A => "punpckhdq",
+        0x6B => "packssdw",
+        0x74 => "pcmpeqb",
+        0x75 => "pcmpeqw",
+        0x76 => "pcmpeqd",
+        0xD1 => "psrlw",
+        0xD2 => "psrld",
+        0xD3 => "psrlq",
+        0xD4 => "paddq",
+        0xD5 => "pmullw",
+        0xD8 => "psubusb",
+        0xD9 => "psubusw",
+        0xDA => "pminub",
+        0xDB => "pand",
+        0xDC => "paddusb",
+        0xDD => "paddusw",
+        0xDE => "pmaxub",
+        0xDF => "pandn",
+        0xE0 => "pavgb",
+        0xE1 => "psraw",
+        0xE2 => "psrad",
+        0xE3 => "pavgw",
+        0xE4 => "pmulhuw",
+        0xE5 => "pmulhw",
+        0xE8 => "psubsb",
+        0xE9 => "psubsw",
+        0xEA => "pminsw",
+        0xEB => "por",
+        0xEC => "paddsb",
+        0xED => "paddsw",
+        0xEE => "pmaxsw",
+        0xEF => "pxor",
+        0xF1 => "psllw",
+        0xF2 => "pslld",
+        0xF3 => "psllq",
+        0xF4 => "pmuludq",
+        0xF5 => "pmaddwd",
+        0xF6 => "psadbw",
+        0xF8 => "psubb",
+        0xF9 => "psubw",
+        0xFA => "psubd",
+        0xFB => "psubq",
+        0xFC => "paddb",
+        0xFD => "paddw",
+        0xFE => "paddd",
+        _ => return None,
+    })
+}
+
+// Floating-point instructions as [ps, pd, ss, sd]; "" where a form does not exist.
+fn float_names(ext: u8) -> Option<[&'static str; 4]> {
+    Some(match ext {
+        0x14 => ["unpcklps", "unpcklpd", "", ""],
+        0x15 => ["unpckhps", "unpckhpd", "", ""],
+        0x51 => ["sqrtps", "sqrtpd", "sqrtss", "sqrtsd"],
+        0x52 => ["rsqrtps", "", "rsqrtss", ""],
+        0x53 => ["rcpps", "", "rcpss", ""],
+        0x54 => ["andps", "andpd", "", ""],
+        0x55 => ["andnps", "andnpd", "", ""],
+        0x56 => ["orps", "orpd", "", ""],
+        0x57 => ["xorps", "xorpd", "", ""],
+        0x58 => ["addps", "addpd", "addss", "addsd"],
+        0x59 => ["mulps", "mulpd", "mulss", "mulsd"],
+        0x5C => ["subps", "subpd", "subss", "subsd"],
+        0x5D => ["minps", "minpd", "minss", "minsd"],
+        0x5E => ["divps", "divpd", "divss", "divsd"],
+        0x5F => ["maxps", "maxpd", "maxss", "maxsd"],
+        0xC2 => ["cmpps", "cmppd", "cmpss", "cmpsd"],
+        0xC6 => ["shufps", "shufpd", "", ""],
+        _ => return None,
+    })
+}
+
+pub(super) fn decode(d: &mut Decoder, ext: u8) -> Result<Decoded, DisasmError> {
+    use Mandatory::*;
+    use Op::*;
+    // F2/F3 take precedence over 66; whichever applies is part of the opcode.
+    let prefix = match d.rep.take() {
+        Some(0xF3) => F3,
+        Some(_) => F2,
+        None if d.operand_16 => P66,
+        None => Plain,
+    };
+
+    if let Some(names) = float_names(ext) {
+        let (mnemonic, source) = match prefix {
+            Plain => (names[0], W(16)),
+            P66 => (names[1], W(16)),
+            F3 => (names[2], W(4)),
+            F2 => (names[3], W(8)),
+        };
+        if mnemonic.is_empty() {
+            return Err(DisasmError::Invalid);
+        }
+        let ops: &[Op] = match ext {
+            0xC2 | 0xC6 => &[V, source, Ib],
+            _ => &[V, source],
+        };
+        return Ok((mnemonic, d.operands(ops)?));
+    }
+    if let Some(name) = integer_name(ext) {
+        let ops: &[Op] = match prefix {
+            Plain => &[P, Q(8)],
+            P66 => &[V, W(16)],
+            _ => return Err(DisasmError::Invalid),
+        };
+        return Ok((name, d.operands(ops)?));
+    }
+
+    // MOVLPS/MOVHPS turn into MOVHLPS/MOVLHPS with a register source.
+    let register_form = matches!(ext, 0x12 | 0x16) && d.modrm()?.mod_bits == 3;
+    let (mnemonic, ops): (&'static str, &[Op]) = match (ext, prefix) {
+        (0x10, Plain) => ("movups", &[V, W(16)]),
+        (0x10, P66) => ("movupd", &[V, W(16)]),
+        (0x10, F3) => ("movss", &[V, W(4)]),
+        (0x10, F2) => ("movsd", &[V, W(8)]),
+        (0x11, Plain) => ("movups", &[W(16), V]),
+        (0x11, P66) => ("movupd", &[W(16), V]),
+        (0x11, F3) => ("movss", &[W(4), V]),
+        (0x11, F2) => ("movsd", &[W(8), V]),
+        (0x12, Plain) if register_form => ("movhlps", &[V, U]),
+        (0x12, Plain) => ("movlps", &[V, M(8)]),
+        (0x12, P66) => ("movlpd", &[V, M(8)]),
+        (0x12, F3) => ("movsldup", &[V, W(16)]),
+        (0x12, F2) => ("movddup", &[V, W(8)]),
+        (0x13, Plain) => ("movlps", &[M(8), V]),
+        (0x13, P66) => ("movlpd", &[M(8), V]),
+        (0x16, Plain) if register_form => ("movlhps", &[V, U]),
+        (0x16, Plain) => ("movhps", &[V, M(8)]),
+        (0x16, P66) => ("movhpd", &[V, M(8)]),
+        (0x16, F3) => ("movshdup", &[V, W(16)]),
+        (0x17, Plain) => ("movhps", &[M(8), V]),
+        (0x17, P66) => ("movhpd", &[M(8), V]),
+        (0x28, Plain) => ("movaps", &[V, W(16)]),
+        (0x28, P66) => ("movapd", &[V, W(16)]),
+        (0x29, Plain) => ("movaps", &[W(16), V]),
+        (0x29, P66) => ("movapd", &[W(16), V]),
+        (0x2A, Plain) => ("cvtpi2ps", &[V, Q(8)]),
+        (0x2A, P66) => ("cvtpi2pd", &[V, Q(8)]),
+        (0x2A, F3) => ("cvtsi2ss", &[V, Ed]),
+        (0x2A, F2) => ("cvtsi2sd", &[V, Ed]),
+        (0x2B, Plain) => ("movntps", &[M(16), V]),
+        (0x2B, P66) => ("movntpd", &[M(16), V]),
+        (0x2C, Plain) => ("cvttps2pi", &[P, W(8)]),
+        (0x2C, P66) => ("cvttpd2pi", &[P, W(16)]),
+        (0x2C, F3) => ("cvttss2si", &[Gd, W(4)]),
+        (0x2C, F2) => ("cvttsd2si", &[Gd, W(8)]),
+        (0x2D, Plain) => ("cvtps2pi", &[P, W(8)]),
+        (0x2D, P66) => ("cvtpd2pi", &[P, W(16)]),
+        (0x2D, F3) => ("cvtss2si", &[Gd, W(4)]),
+        (0x2D, F2) => ("cvtsd2si", &[Gd, W(8)]),
+        (0x2E, Plain) => ("ucomiss", &[V, W(4)]),
+        (0x2E, P66) => ("ucomisd", &[V, W(8)]),
+        (0x2F, Plain) => ("comiss", &[V, W(4)]),
+        (0x2F, P66) => ("comisd", &[V, W(8)]),
+        (0x50, Plain) => ("movmskps", &[Gd, U]),
+        (0x50, P66) => ("movmskpd", &[Gd, U]),
+        (0x5A, Plain) => ("cvtps2pd", &[V, W(8)]),
+        (0x5A, P66) => ("cvtpd2ps", &[V, W(16)]),
+        (0x5A, F3) => ("cvtss2sd", &[V, W(4)]),
+        (0x5A, F2) => ("cvtsd2ss", &[V, W(8)]),
+        (0x5B, Plain) => ("cvtdq2ps", &[V, W(16)]),
+        (0x5B, P66) => ("cvtps2dq", &[V, W(16)]),
+        (0x5B, F3) => ("cvttps2dq", &[V, W(16)]),
+        (0x6C, P66) => ("punpcklqdq", &[V, W(16)]),
+        (0x6D, P66) => ("punpckhqdq", &[V, W(16)]),
+        (0x6E, Plain) => ("movd", &[P, Ed]),
+        (0x6E, P66) => ("movd", &[V, Ed]),
+        (0x6F, Plain) => ("movq", &[P, Q(8)]),
+        (0x6F, P66) => ("movdqa", &[V, W(16)]),
+        (0x6F, F3) => ("movdqu", &[V, W(16)]),
+        (0x70, Plain) => ("pshufw", &[P, Q(8), Ib]),
+        (0x70, P66) => ("pshufd", &[V, W(16), Ib]),
+        (0x70, F3) => ("pshufhw", &[V, W(16), Ib]),
+        (0x70, F2) => ("pshuflw", &[V, W(16), Ib]),
+        (0x71..=0x73, Plain | P66) => {
+            let modrm = d.modrm()?;
+            let mnemonic = match (ext, modrm.reg, prefix) {
+                (0x71, 2, _) => "psrlw",
+                (0x71, 4, _) => "psraw",
+                (0x71, 6, _) => "psllw",
+                (0x72, 2, _) => "psrld",
+                (0x72, 4, _) => "psrad",
+                (0x72, 6, _) => "pslld",
+                (0x73, 2, _) => "psrlq",
+                (0x73, 3, P66) => "psrldq",
+                (0x73, 6, _) => "psllq",
+                (0x73, 7, P66) => "pslldq",
+                _ => return Err(DisasmError::Invalid),
+            };
+            let ops: &[Op] = if prefix == P66 { &[U, Ib] } else { &[N, Ib] };
+            (mnemonic, ops)
+        }
+        (0x77, Plain) => ("emms", &[]),
+        (0x7E, Plain) => ("movd", &[Ed, P]),
+        (0x7E, P66) => ("movd", &[Ed, V]),
+        (0x7E, F3) => ("movq", &[V, W(8)]),
+        (0x7F, Plain) => ("movq", &[Q(8), P]),
+        (0x7F, P66) => ("movdqa", &[W(16), V]),
+        (0x7F, F3) => ("movdqu", &[W(16), V]),
+        (0xC4, Plain) => ("pinsrw", &[P, Rdw, Ib]),
+        (0xC4, P66) => ("pinsrw", &[V, Rdw, Ib]),
+        (0xC5, Plain) => ("pextrw", &[Gd, N, Ib]),
+        (0xC5, P66) => ("pextrw", &[Gd, U, Ib]),
+        (0xD0, P66) => ("addsubpd", &[V, W(16)]),
+        (0xD0, F2) => ("addsubps", &[V, W(16)]),
+        (0xD6, P66) => ("movq", &[W(8), V]),
+        (0xD6, F3) => ("movq2dq", &[V, N]),
+        (0xD6, F2) => ("movdq2q", &[P, U]),
+        (0xD7, Plain) => ("pmovmskb", &[Gd, N]),
+        (0xD7, P66) => ("pmovmskb", &[Gd, U]),
+        (0xE6, P66) => ("cvttpd2dq", &[V, W(16)]),
+        (0xE6, F3) => ("cvtdq2pd", &[V, W(8)]),
+        (0xE6, F2) => ("cvtpd2dq", &[V, W(16)]),
+        (0xE7, Plain) => ("movntq", &[M(8), P]),
+        (0xE7, P66) => ("movntdq", &[M(16), V]),
+        (0xF7, Plain) => ("maskmovq", &[P, N]),
+        (0xF7, P66) => ("maskmovdqu", &[V, U]),
+        _ => return Err(DisasmError::Invalid),
+    };
+    Ok((mnemonic, d.operands(ops)?))
+}
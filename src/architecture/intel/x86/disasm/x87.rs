@@ -0,0 +1,129 @@
+//! x87 escape opcodes D8-DF.
+
+use super::decoder::{Decoded, Decoder};
+use super::{DisasmError, Operand};
+
+const ST: [&str; 8] = [
+    "st(0)", "st(1)", "st(2)", "st(3)", "st(4)", "st(5)", "st(6)", "st(7)",
+];
+const ARITH: [&str; 8] = [
+    "fadd", "fmul", "fcom", "fcomp", "fsub", "fsubr", "fdiv", "fdivr",
+];
+const INT_ARITH: [&str; 8] = [
+    "fiadd", "fimul", "ficom", "ficomp", "fisub", "fisubr", "fidiv", "fidivr",
+];
+
+pub(super) fn decode(d: &mut Decoder, opcode: u8) -> Result<Decoded, DisasmError> {
+    let modrm = d.modrm()?;
+    let reg = modrm.reg as usize;
+    if modrm.mod_bits != 3 {
+        let (mnemonic, size) = match (opcode, reg) {
+            (0xD8, _) => (ARITH[reg], 4),
+            (0xDC, _) => (ARITH[reg], 8),
+            (0xDA, _) => (INT_ARITH[reg], 4),
+            (0xDE, _) => (INT_ARITH[reg], 2),
+            (0xD9, 0) => ("fld", 4),
+            (0xD9, 2) => ("fst", 4),
+            (0xD9, 3) => ("fstp", 4),
+            (0xD9, 4) => ("fldenv", 0),
+            (0xD9, 5) => ("fldcw", 2),
+            (0xD9, 6) => ("fnstenv", 0),
+            (0xD9, 7) => ("fnstcw", 2),
+            (0xDB, 0) => ("fild", 4),
+            (0xDB, 1) => ("fisttp", 4),
+            (0xDB, 2) => ("fist", 4),
+            (0xDB, 3) => ("fistp", 4),
+            (0xDB, 5) => ("fld", 10),
+            (0xDB, 7) => ("fstp", 10),
+            (0xDD, 0) => ("fld", 8),
+            (0xDD, 1) => ("fisttp", 8),
+            (0xDD, 2) => ("fst", 8),
+            (0xDD, 3) => ("fstp", 8),
+            (0xDD, 4) => ("frstor", 0),
+            (0xDD, 6) => ("fnsave", 0),
+            (0xDD, 7) => ("fnstsw", 2),
+            (0xDF, 0) => ("fild", 2),
+            (0xDF, 1) => ("fisttp", 2),
+            (0xDF, 2) => ("fist", 2),
+            (0xDF, 3) => ("fistp", 2),
+            (0xDF, 4) => ("fbld", 10),
+            (0xDF, 5) => ("fild", 8),
+            (0xDF, 6) => ("fbstp", 10),
+            (0xDF, 7) => ("fistp", 8),
+            _ => return Err(DisasmError::Invalid),
+        };
+        return Ok((mnemonic, vec![d.memory(&modrm, size)]));
+    }
+
+    let rm = modrm.rm as usize;
+    let st = Operand::Register("st");
+    let sti = Operand::Register(ST[rm]);
+    let decoded = match (opcode, reg) {
+        (0xD8, 2 | 3) => (ARITH[reg], vec![sti]),
+        (0xD8, _) => (ARITH[reg], vec![st, sti]),
+        (0xD9, 0) => ("fld", vec![sti]),
+        (0xD9, 1) => ("fxch", vec![sti]),
+        (0xD9, 2) if rm == 0 => ("fnop", vec![]),
+        (0xD9, 4) => {
+            let mnemonic = match rm {
+                0 => "fchs",
+                1 => "fabs",
+                4 => "ftst",
+                5 => "fxam",
+                _ => return Err(DisasmError::Invalid),
+            };
+            (mnemonic, vec![])
+        }
+        (0xD9, 5) if rm != 7 => (
+            [
+                "fld1", "fldl2t", "fldl2e", "fldpi", "fldlg2", "fldln2", "fldz",
+            ][rm],
+            vec![],
+        ),
+        (0xD9, 6) => (
+            [
+                "f2xm1", "fyl2x", "fptan", "fpatan", "fxtract", "fprem1", "fdecstp", "fincstp",
+            ][rm],
+            vec![],
+        ),
+        (0xD9, 7) => (
+            [
+                "fprem", "fyl2xp1", "fsqrt", "fsincos", "frndint", "fscale", "fsin", "fcos",
+            ][rm],
+            vec![],
+        ),
+        (0xDA, 0..=3) => (
+            ["fcmovb", "fcmove", "fcmovbe", "fcmovu"][reg],
+            vec![st, sti],
+        ),
+        (0xDA, 5) if rm == 1 => ("fucompp", vec![]),
+        (0xDB, 0..=3) => (
+            ["fcmovnb", "fcmovne", "fcmovnbe", "fcmovnu"][reg],
+            vec![st, sti],
+        ),
+        (0xDB, 4) if rm == 2 => ("fnclex", vec![]),
+        (0xDB, 4) if rm == 3 => ("fninit", vec![]),
+        (0xDB, 5) => ("fucomi", vec![st, sti]),
+        (0xDB, 6) => ("fcomi", vec![st, sti]),
+        // DC reverses the operands and swaps the SUB/SUBR and DIV/DIVR encodings.
+        (0xDC, 0 | 1) => (ARITH[reg], vec![sti, st]),
+        (0xDC, 4..=7) => (ARITH[reg ^ 1], vec![sti, st]),
+        (0xDD, 0) => ("ffree", vec![sti]),
+        (0xDD, 2) => ("fst", vec![sti]),
+        (0xDD, 3) => ("fstp", vec![sti]),
+        (0xDD, 4) => ("fucom", vec![sti]),
+        (0xDD, 5) => ("fucomp", vec![sti]),
+        (0xDE, 3) if rm == 1 => ("fcompp", vec![]),
+        (0xDE, 0 | 1 | 4..=7) => (
+            [
+                "faddp", "fmulp", "", "", "fsubrp", "fsubp", "fdivrp", "fdivp",
+            ][reg],
+            vec![sti, st],
+        ),
+        (0xDF, 4) if rm == 0 => ("fnstsw", vec![Operand::Register("ax")]),
+        (0xDF, 5) => ("fucomip", vec![st, sti]),
+        (0xDF, 6) => ("fcomip", vec![st, sti]),
+        _ => return Err(DisasmError::Invalid),
+    };
+    Ok(decoded)
+}
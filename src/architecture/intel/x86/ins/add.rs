@@ -1,14 +1,14 @@
 //! x86 add/adc instruction handlers.
 
-use crate::vm::{Vm, VmError, REG_AL, REG_EAX};
+use crate::vm::{FlagOp, Vm, VmError, REG_AL, REG_EAX};
 
 use super::core::{
-    decode_modrm, read_rm32, read_rm8, update_flags_add32, update_flags_add8, write_rm32,
-    write_rm8, ModRm, Prefixes,
+    decode_modrm, operand_width, read_immv, read_rm8, read_rmv, regv, set_regv, update_flags_add,
+    update_flags_add8, update_flags_inc, update_flags_inc8, write_rm8, write_rmv, ModRm, Prefixes,
 };
 
 pub(crate) fn add_rm8_r8(vm: &mut Vm, cursor: u32, prefixes: Prefixes) -> Result<(), VmError> {
-    let modrm = decode_modrm(vm, cursor + 1)?;
+    let modrm = decode_modrm(vm, cursor + 1, prefixes)?;
     let rhs = vm.reg8(modrm.reg);
     let lhs = read_rm8(vm, &modrm, prefixes.segment_base)?;
     let result = lhs.wrapping_add(rhs);
@@ -19,18 +19,18 @@ pub(crate) fn add_rm8_r8(vm: &mut Vm, cursor: u32, prefixes: Prefixes) -> Result
 }
 
 pub(crate) fn add_rm32_r32(vm: &mut Vm, cursor: u32, prefixes: Prefixes) -> Result<(), VmError> {
-    let modrm = decode_modrm(vm, cursor + 1)?;
-    let rhs = vm.reg32(modrm.reg);
-    let lhs = read_rm32(vm, &modrm, prefixes.segment_base)?;
+    let modrm = decode_modrm(vm, cursor + 1, prefixes)?;
+    let rhs = regv(vm, modrm.reg, prefixes);
+    let lhs = read_rmv(vm, &modrm, prefixes)?;
     let result = lhs.wrapping_add(rhs);
-    write_rm32(vm, &modrm, prefixes.segment_base, result)?;
-    update_flags_add32(vm, lhs, rhs, result);
+    write_rmv(vm, &modrm, prefixes, result)?;
+    update_flags_add(vm, operand_width(prefixes), lhs, rhs, result);
     vm.set_eip(cursor + 1 + modrm.len as u32);
     Ok(())
 }
 
 pub(crate) fn add_r8_rm8(vm: &mut Vm, cursor: u32, prefixes: Prefixes) -> Result<(), VmError> {
-    let modrm = decode_modrm(vm, cursor + 1)?;
+    let modrm = decode_modrm(vm, cursor + 1, prefixes)?;
     let rhs = read_rm8(vm, &modrm, prefixes.segment_base)?;
     let lhs = vm.reg8(modrm.reg);
     let result = lhs.wrapping_add(rhs);
@@ -41,12 +41,12 @@ pub(crate) fn add_r8_rm8(vm: &mut Vm, cursor: u32, prefixes: Prefixes) -> Result
 }
 
 pub(crate) fn add_r32_rm32(vm: &mut Vm, cursor: u32, prefixes: Prefixes) -> Result<(), VmError> {
-    let modrm = decode_modrm(vm, cursor + 1)?;
-    let rhs = read_rm32(vm, &modrm, prefixes.segment_base)?;
-    let lhs = vm.reg32(modrm.reg);
+    let modrm = decode_modrm(vm, cursor + 1, prefixes)?;
+    let rhs = read_rmv(vm, &modrm, prefixes)?;
+    let lhs = regv(vm, modrm.reg, prefixes);
     let result = lhs.wrapping_add(rhs);
-    vm.set_reg32(modrm.reg, result);
-    update_flags_add32(vm, lhs, rhs, result);
+    set_regv(vm, modrm.reg, prefixes, result);
+    update_flags_add(vm, operand_width(prefixes), lhs, rhs, result);
     vm.set_eip(cursor + 1 + modrm.len as u32);
     Ok(())
 }
@@ -61,18 +61,18 @@ pub(crate) fn add_al_imm8(vm: &mut Vm, cursor: u32, _prefixes: Prefixes) -> Resu
     Ok(())
 }
 
-pub(crate) fn add_eax_imm32(vm: &mut Vm, cursor: u32, _prefixes: Prefixes) -> Result<(), VmError> {
-    let imm = vm.read_u32(cursor + 1)?;
-    let lhs = vm.reg32(REG_EAX);
+pub(crate) fn add_eax_imm32(vm: &mut Vm, cursor: u32, prefixes: Prefixes) -> Result<(), VmError> {
+    let (imm, len) = read_immv(vm, cursor + 1, prefixes)?;
+    let lhs = regv(vm, REG_EAX, prefixes);
     let result = lhs.wrapping_add(imm);
-    vm.set_reg32(REG_EAX, result);
-    update_flags_add32(vm, lhs, imm, result);
-    vm.set_eip(cursor + 5);
+    set_regv(vm, REG_EAX, prefixes, result);
+    update_flags_add(vm, operand_width(prefixes), lhs, imm, result);
+    vm.set_eip(cursor + 1 + len);
     Ok(())
 }
 
 pub(crate) fn adc_rm8_r8(vm: &mut Vm, cursor: u32, prefixes: Prefixes) -> Result<(), VmError> {
-    let modrm = decode_modrm(vm, cursor + 1)?;
+    let modrm = decode_modrm(vm, cursor + 1, prefixes)?;
     let rhs = vm.reg8(modrm.reg);
     let lhs = read_rm8(vm, &modrm, prefixes.segment_base)?;
     let result = adc8(vm, lhs, rhs);
@@ -82,17 +82,17 @@ pub(crate) fn adc_rm8_r8(vm: &mut Vm, cursor: u32, prefixes: Prefixes) -> Result
 }
 
 pub(crate) fn adc_rm32_r32(vm: &mut Vm, cursor: u32, prefixes: Prefixes) -> Result<(), VmError> {
-    let modrm = decode_modrm(vm, cursor + 1)?;
-    let rhs = vm.reg32(modrm.reg);
-    let lhs = read_rm32(vm, &modrm, prefixes.segment_base)?;
-    let result = adc32(vm, lhs, rhs);
-    write_rm32(vm, &modrm, prefixes.segment_base, result)?;
+    let modrm = decode_modrm(vm, cursor + 1, prefixes)?;
+    let rhs = regv(vm, modrm.reg, prefixes);
+    let lhs = read_rmv(vm, &modrm, prefixes)?;
+    let result = adc(vm, operand_width(prefixes), lhs, rhs);
+    write_rmv(vm, &modrm, prefixes, result)?;
     vm.set_eip(cursor + 1 + modrm.len as u32);
     Ok(())
 }
 
 pub(crate) fn adc_r8_rm8(vm: &mut Vm, cursor: u32, prefixes: Prefixes) -> Result<(), VmError> {
-    let modrm = decode_modrm(vm, cursor + 1)?;
+    let modrm = decode_modrm(vm, cursor + 1, prefixes)?;
     let rhs = read_rm8(vm, &modrm, prefixes.segment_base)?;
     let lhs = vm.reg8(modrm.reg);
     let result = adc8(vm, lhs, rhs);
@@ -102,11 +102,11 @@ pub(crate) fn adc_r8_rm8(vm: &mut Vm, cursor: u32, prefixes: Prefixes) -> Result
 }
 
 pub(crate) fn adc_r32_rm32(vm: &mut Vm, cursor: u32, prefixes: Prefixes) -> Result<(), VmError> {
-    let modrm = decode_modrm(vm, cursor + 1)?;
-    let rhs = read_rm32(vm, &modrm, prefixes.segment_base)?;
-    let lhs = vm.reg32(modrm.reg);
-    let result = adc32(vm, lhs, rhs);
-    vm.set_reg32(modrm.reg, result);
+    let modrm = decode_modrm(vm, cursor + 1, prefixes)?;
+    let rhs = read_rmv(vm, &modrm, prefixes)?;
+    let lhs = regv(vm, modrm.reg, prefixes);
+    let result = adc(vm, operand_width(prefixes), lhs, rhs);
+    set_regv(vm, modrm.reg, prefixes, result);
     vm.set_eip(cursor + 1 + modrm.len as u32);
     Ok(())
 }
@@ -120,31 +120,31 @@ pub(crate) fn adc_al_imm8(vm: &mut Vm, cursor: u32, _prefixes: Prefixes) -> Resu
     Ok(())
 }
 
-pub(crate) fn adc_eax_imm32(vm: &mut Vm, cursor: u32, _prefixes: Prefixes) -> Result<(), VmError> {
-    let imm = vm.read_u32(cursor + 1)?;
-    let lhs = vm.reg32(REG_EAX);
-    let result = adc32(vm, lhs, imm);
-    vm.set_reg32(REG_EAX, result);
-    vm.set_eip(cursor + 5);
+pub(crate) fn adc_eax_imm32(vm: &mut Vm, cursor: u32, prefixes: Prefixes) -> Result<(), VmError> {
+    let (imm, len) = read_immv(vm, cursor + 1, prefixes)?;
+    let lhs = regv(vm, REG_EAX, prefixes);
+    let result = adc(vm, operand_width(prefixes), lhs, imm);
+    set_regv(vm, REG_EAX, prefixes, result);
+    vm.set_eip(cursor + 1 + len);
     Ok(())
 }
 
-pub(crate) fn inc_reg(vm: &mut Vm, cursor: u32, _prefixes: Prefixes) -> Result<(), VmError> {
+pub(crate) fn inc_reg(vm: &mut Vm, cursor: u32, prefixes: Prefixes) -> Result<(), VmError> {
     let opcode = vm.read_u8(cursor)?;
     let reg = opcode - 0x40;
-    let value = vm.reg32(reg);
+    let value = regv(vm, reg, prefixes);
     let result = value.wrapping_add(1);
-    vm.set_reg32(reg, result);
-    update_flags_add32(vm, value, 1, result);
+    set_regv(vm, reg, prefixes, result);
+    update_flags_inc(vm, operand_width(prefixes), value, result);
     vm.set_eip(cursor + 1);
     Ok(())
 }
 
 pub(crate) fn inc_rm32(vm: &mut Vm, modrm: &ModRm, prefixes: Prefixes) -> Result<(), VmError> {
-    let value = read_rm32(vm, modrm, prefixes.segment_base)?;
+    let value = read_rmv(vm, modrm, prefixes)?;
     let result = value.wrapping_add(1);
-    write_rm32(vm, modrm, prefixes.segment_base, result)?;
-    update_flags_add32(vm, value, 1, result);
+    write_rmv(vm, modrm, prefixes, result)?;
+    update_flags_inc(vm, operand_width(prefixes), value, result);
     Ok(())
 }
 
@@ -152,7 +152,7 @@ pub(crate) fn inc_rm8(vm: &mut Vm, modrm: &ModRm, prefixes: Prefixes) -> Result<
     let value = read_rm8(vm, modrm, prefixes.segment_base)?;
     let result = value.wrapping_add(1);
     write_rm8(vm, modrm, prefixes.segment_base, result)?;
-    update_flags_add8(vm, value, 1, result);
+    update_flags_inc8(vm, value, result);
     Ok(())
 }
 
@@ -162,10 +162,10 @@ pub(crate) fn add_rm32_imm(
     prefixes: Prefixes,
     imm: u32,
 ) -> Result<(), VmError> {
-    let dst = read_rm32(vm, modrm, prefixes.segment_base)?;
+    let dst = read_rmv(vm, modrm, prefixes)?;
     let result = dst.wrapping_add(imm);
-    write_rm32(vm, modrm, prefixes.segment_base, result)?;
-    update_flags_add32(vm, dst, imm, result);
+    write_rmv(vm, modrm, prefixes, result)?;
+    update_flags_add(vm, operand_width(prefixes), dst, imm, result);
     Ok(())
 }
 
@@ -188,9 +188,9 @@ pub(crate) fn adc_rm32_imm(
     prefixes: Prefixes,
     imm: u32,
 ) -> Result<(), VmError> {
-    let dst = read_rm32(vm, modrm, prefixes.segment_base)?;
-    let result = adc32(vm, dst, imm);
-    write_rm32(vm, modrm, prefixes.segment_base, result)?;
+    let dst = read_rmv(vm, modrm, prefixes)?;
+    let result = adc(vm, operand_width(prefixes), dst, imm);
+    write_rmv(vm, modrm, prefixes, result)?;
     Ok(())
 }
 
@@ -207,29 +207,15 @@ pub(crate) fn adc_rm8_imm(
 }
 
 fn adc8(vm: &mut Vm, a: u8, b: u8) -> u8 {
-    let carry = if vm.cf() { 1u16 } else { 0 };
-    let sum = a as u16 + b as u16 + carry;
-    let result = sum as u8;
-    let cf = sum > 0xFF;
-    let b_with_carry = b.wrapping_add(carry as u8);
-    let sign = 0x80;
-    let of = ((a ^ result) & (b_with_carry ^ result) & sign) != 0;
-    let zf = result == 0;
-    let sf = (result & sign) != 0;
-    vm.set_flags(zf, sf, of, cf);
+    let carry = vm.cf();
+    let result = a.wrapping_add(b).wrapping_add(carry as u8);
+    vm.record_flags_with_carry(FlagOp::Adc, 8, a as u32, b as u32, result as u32, carry);
     result
 }
 
-fn adc32(vm: &mut Vm, a: u32, b: u32) -> u32 {
-    let carry = if vm.cf() { 1u64 } else { 0 };
-    let sum = a as u64 + b as u64 + carry;
-    let result = sum as u32;
-    let cf = sum > 0xFFFF_FFFF;
-    let b_with_carry = b.wrapping_add(carry as u32);
-    let sign = 0x8000_0000;
-    let of = ((a ^ result) & (b_with_carry ^ result) & sign) != 0;
-    let zf = result == 0;
-    let sf = (result & sign) != 0;
-    vm.set_flags(zf, sf, of, cf);
+fn adc(vm: &mut Vm, width: u8, a: u32, b: u32) -> u32 {
+    let carry = vm.cf();
+    let result = a.wrapping_add(b).wrapping_add(carry as u32);
+    vm.record_flags_with_carry(FlagOp::Adc, width, a, b, result, carry);
     result
 }
@@ -1,38 +1,39 @@
 //! x86 atomic instruction handlers.
 
-use crate::vm::{Vm, VmError, REG_EAX};
+use crate::vm::{Vm, VmError, REG_AL, REG_EAX, REG_EBX, REG_ECX, REG_EDX};
 
 use super::core::{
-    decode_modrm, read_rm32, update_flags_add32, update_flags_sub32, write_rm32, Prefixes,
+    calc_ea, decode_modrm, operand_width, read_rm8, read_rmv, regv, set_regv, update_flags_add,
+    update_flags_add8, update_flags_sub, update_flags_sub8, write_rm8, write_rmv, Prefixes,
 };
 
 pub(crate) fn xchg_rm32_r32(vm: &mut Vm, cursor: u32, prefixes: Prefixes) -> Result<(), VmError> {
-    let modrm = decode_modrm(vm, cursor + 1)?;
-    let reg_val = vm.reg32(modrm.reg);
-    if modrm.mod_bits == 3 {
-        let rm_val = vm.reg32(modrm.rm);
-        vm.set_reg32(modrm.rm, reg_val);
-        vm.set_reg32(modrm.reg, rm_val);
-    } else {
-        let mem_val = read_rm32(vm, &modrm, prefixes.segment_base)?;
-        write_rm32(vm, &modrm, prefixes.segment_base, reg_val)?;
-        vm.set_reg32(modrm.reg, mem_val);
-    }
+    let modrm = decode_modrm(vm, cursor + 1, prefixes)?;
+    let reg_val = regv(vm, modrm.reg, prefixes);
+    let rm_val = read_rmv(vm, &modrm, prefixes)?;
+    write_rmv(vm, &modrm, prefixes, reg_val)?;
+    set_regv(vm, modrm.reg, prefixes, rm_val);
+    vm.set_eip(cursor + 1 + modrm.len as u32);
+    Ok(())
+}
+
+pub(crate) fn xchg_rm8_r8(vm: &mut Vm, cursor: u32, prefixes: Prefixes) -> Result<(), VmError> {
+    let modrm = decode_modrm(vm, cursor + 1, prefixes)?;
+    let reg_val = vm.reg8(modrm.reg);
+    let rm_val = read_rm8(vm, &modrm, prefixes.segment_base)?;
+    write_rm8(vm, &modrm, prefixes.segment_base, reg_val)?;
+    vm.set_reg8(modrm.reg, rm_val);
     vm.set_eip(cursor + 1 + modrm.len as u32);
     Ok(())
 }
 
-pub(crate) fn xchg_eax_reg(vm: &mut Vm, cursor: u32, _prefixes: Prefixes) -> Result<(), VmError> {
+pub(crate) fn xchg_eax_reg(vm: &mut Vm, cursor: u32, prefixes: Prefixes) -> Result<(), VmError> {
     let opcode = vm.read_u8(cursor)?;
     let reg = opcode - 0x90;
-    if reg == 0 {
-        vm.set_eip(cursor + 1);
-        return Ok(());
-    }
-    let eax = vm.reg32(REG_EAX);
-    let other = vm.reg32(reg);
-    vm.set_reg32(REG_EAX, other);
-    vm.set_reg32(reg, eax);
+    let eax = regv(vm, REG_EAX, prefixes);
+    let other = regv(vm, reg, prefixes);
+    set_regv(vm, REG_EAX, prefixes, other);
+    set_regv(vm, reg, prefixes, eax);
     vm.set_eip(cursor + 1);
     Ok(())
 }
@@ -42,37 +43,84 @@ pub(crate) fn cmpxchg_rm32_r32(
     cursor: u32,
     prefixes: Prefixes,
 ) -> Result<(), VmError> {
-    let modrm = decode_modrm(vm, cursor + 2)?;
-    let value = read_rm32(vm, &modrm, prefixes.segment_base)?;
-    let eax = vm.reg32(REG_EAX);
-    let result = value.wrapping_sub(eax);
-    update_flags_sub32(vm, value, eax, result);
+    let modrm = decode_modrm(vm, cursor + 2, prefixes)?;
+    let value = read_rmv(vm, &modrm, prefixes)?;
+    let eax = regv(vm, REG_EAX, prefixes);
+    update_flags_sub(
+        vm,
+        operand_width(prefixes),
+        eax,
+        value,
+        eax.wrapping_sub(value),
+    );
     if value == eax {
-        let src = vm.reg32(modrm.reg);
-        write_rm32(vm, &modrm, prefixes.segment_base, src)?;
+        let src = regv(vm, modrm.reg, prefixes);
+        write_rmv(vm, &modrm, prefixes, src)?;
     } else {
-        vm.set_reg32(REG_EAX, value);
+        set_regv(vm, REG_EAX, prefixes, value);
     }
     vm.set_eip(cursor + 2 + modrm.len as u32);
     Ok(())
 }
 
 pub(crate) fn xadd_rm32_r32(vm: &mut Vm, cursor: u32, prefixes: Prefixes) -> Result<(), VmError> {
-    let modrm = decode_modrm(vm, cursor + 2)?;
-    let src = vm.reg32(modrm.reg);
-    let dst = if modrm.mod_bits == 3 {
-        vm.reg32(modrm.rm)
+    let modrm = decode_modrm(vm, cursor + 2, prefixes)?;
+    let src = regv(vm, modrm.reg, prefixes);
+    let dst = read_rmv(vm, &modrm, prefixes)?;
+    let result = dst.wrapping_add(src);
+    update_flags_add(vm, operand_width(prefixes), dst, src, result);
+    set_regv(vm, modrm.reg, prefixes, dst);
+    write_rmv(vm, &modrm, prefixes, result)?;
+    vm.set_eip(cursor + 2 + modrm.len as u32);
+    Ok(())
+}
+
+pub(crate) fn cmpxchg_rm8_r8(vm: &mut Vm, cursor: u32, prefixes: Prefixes) -> Result<(), VmError> {
+    let modrm = decode_modrm(vm, cursor + 2, prefixes)?;
+    let value = read_rm8(vm, &modrm, prefixes.segment_base)?;
+    let al = vm.reg8(REG_AL);
+    update_flags_sub8(vm, al, value, al.wrapping_sub(value));
+    if value == al {
+        let src = vm.reg8(modrm.reg);
+        write_rm8(vm, &modrm, prefixes.segment_base, src)?;
     } else {
-        read_rm32(vm, &modrm, prefixes.segment_base)?
-    };
+        vm.set_reg8(REG_AL, value);
+    }
+    vm.set_eip(cursor + 2 + modrm.len as u32);
+    Ok(())
+}
+
+pub(crate) fn xadd_rm8_r8(vm: &mut Vm, cursor: u32, prefixes: Prefixes) -> Result<(), VmError> {
+    let modrm = decode_modrm(vm, cursor + 2, prefixes)?;
+    let src = vm.reg8(modrm.reg);
+    let dst = read_rm8(vm, &modrm, prefixes.segment_base)?;
     let result = dst.wrapping_add(src);
-    update_flags_add32(vm, dst, src, result);
-    if modrm.mod_bits == 3 {
-        vm.set_reg32(modrm.rm, result);
+    update_flags_add8(vm, dst, src, result);
+    // Write the register first so `xadd al, al`-style forms end with the sum.
+    vm.set_reg8(modrm.reg, dst);
+    write_rm8(vm, &modrm, prefixes.segment_base, result)?;
+    vm.set_eip(cursor + 2 + modrm.len as u32);
+    Ok(())
+}
+
+/// CMPXCHG8B (0F C7 /1): compare EDX:EAX with m64 and store ECX:EBX on a match.
+pub(crate) fn cmpxchg8b(vm: &mut Vm, cursor: u32, prefixes: Prefixes) -> Result<(), VmError> {
+    let modrm = decode_modrm(vm, cursor + 2, prefixes)?;
+    if modrm.reg != 1 || modrm.mod_bits == 3 {
+        return Err(VmError::UnsupportedInstruction(0xC7));
+    }
+    let addr = calc_ea(vm, &modrm, prefixes.segment_base)?;
+    let value = vm.read_u64(addr)?;
+    let expected = ((vm.reg32(REG_EDX) as u64) << 32) | vm.reg32(REG_EAX) as u64;
+    let equal = value == expected;
+    if equal {
+        let replacement = ((vm.reg32(REG_ECX) as u64) << 32) | vm.reg32(REG_EBX) as u64;
+        vm.write_u64(addr, replacement)?;
     } else {
-        write_rm32(vm, &modrm, prefixes.segment_base, result)?;
+        vm.set_reg32(REG_EAX, value as u32);
+        vm.set_reg32(REG_EDX, (value >> 32) as u32);
     }
-    vm.set_reg32(modrm.reg, dst);
+    vm.set_zf(equal);
     vm.set_eip(cursor + 2 + modrm.len as u32);
     Ok(())
 }
@@ -2,27 +2,114 @@
 
 use crate::vm::{Vm, VmError};
 
-use super::core::{decode_modrm, read_rm32, write_rm32, Prefixes};
+use super::core::{
+    calc_ea, decode_modrm, operand_width, read_rmv, regv, set_regv, sign_extend, write_rmv,
+    Prefixes,
+};
+
+// BT/BTS/BTR/BTC share the /4../7 numbering of group BA; `None` leaves the operand
+// unchanged.
+fn modify(op: u8, value: u32, mask: u32) -> Option<u32> {
+    match op {
+        5 => Some(value | mask),
+        6 => Some(value & !mask),
+        7 => Some(value ^ mask),
+        _ => None,
+    }
+}
 
 pub(crate) fn group_ba(vm: &mut Vm, cursor: u32, prefixes: Prefixes) -> Result<(), VmError> {
-    let modrm = decode_modrm(vm, cursor + 2)?;
+    let modrm = decode_modrm(vm, cursor + 2, prefixes)?;
     let imm = vm.read_u8(cursor + 2 + modrm.len as u32)?;
-    let bit = (imm & 0x1f) as u32;
-    let mask = 1u32 << bit;
-    let value = read_rm32(vm, &modrm, prefixes.segment_base)?;
-    let bit_set = (value & mask) != 0;
-    let mut new_value = value;
-    match modrm.reg {
-        4 => {}
-        5 => new_value = value | mask,
-        6 => new_value = value & !mask,
-        7 => new_value = value ^ mask,
-        _ => return Err(VmError::UnsupportedInstruction(0xBA)),
+    if modrm.reg < 4 {
+        return Err(VmError::UnsupportedInstruction(0xBA));
     }
-    if modrm.reg != 4 {
-        write_rm32(vm, &modrm, prefixes.segment_base, new_value)?;
+    let bit = imm as u32 % operand_width(prefixes) as u32;
+    let mask = 1u32 << bit;
+    let value = read_rmv(vm, &modrm, prefixes)?;
+    if let Some(new_value) = modify(modrm.reg, value, mask) {
+        write_rmv(vm, &modrm, prefixes, new_value)?;
     }
-    vm.set_flags(vm.zf(), vm.sf(), vm.of(), bit_set);
+    vm.set_cf(value & mask != 0);
     vm.set_eip(cursor + 2 + modrm.len as u32 + 1);
     Ok(())
 }
+
+/// BT/BTS/BTR/BTC r/m16/32, r16/32 (0F A3/AB/B3/BB). With a memory operand the
+/// bit offset is signed and may address any word or dword around the effective
+/// address.
+pub(crate) fn bit_rm32_r32(
+    vm: &mut Vm,
+    cursor: u32,
+    ext: u8,
+    prefixes: Prefixes,
+) -> Result<(), VmError> {
+    let modrm = decode_modrm(vm, cursor + 2, prefixes)?;
+    let op = match ext {
+        0xA3 => 4,
+        0xAB => 5,
+        0xB3 => 6,
+        _ => 7,
+    };
+    let width = operand_width(prefixes);
+    let bits = width as u32;
+    let offset = sign_extend(regv(vm, modrm.reg, prefixes), width) as i32;
+    let mask = 1u32 << (offset as u32 % bits);
+    if modrm.mod_bits == 3 {
+        let value = read_rmv(vm, &modrm, prefixes)?;
+        if let Some(new_value) = modify(op, value, mask) {
+            write_rmv(vm, &modrm, prefixes, new_value)?;
+        }
+        vm.set_cf(value & mask != 0);
+    } else {
+        let base = calc_ea(vm, &modrm, prefixes.segment_base)?;
+        let unit = bits / 8;
+        let addr = base.wrapping_add((offset.div_euclid(bits as i32) * unit as i32) as u32);
+        let value = if width == 16 {
+            vm.read_u16(addr)? as u32
+        } else {
+            vm.read_u32(addr)?
+        };
+        if let Some(new_value) = modify(op, value, mask) {
+            if width == 16 {
+                vm.write_u16(addr, new_value as u16)?;
+            } else {
+                vm.write_u32(addr, new_value)?;
+            }
+        }
+        vm.set_cf(value & mask != 0);
+    }
+    vm.set_eip(cursor + 2 + modrm.len as u32);
+    Ok(())
+}
+
+/// BSF/BSR (0F BC/BD). A zero source sets ZF and leaves the destination alone.
+/// TZCNT/LZCNT (F3-prefixed) run as BSF/BSR, as on CPUs without BMI1/LZCNT.
+pub(crate) fn bit_scan(
+    vm: &mut Vm,
+    cursor: u32,
+    ext: u8,
+    prefixes: Prefixes,
+) -> Result<(), VmError> {
+    let modrm = decode_modrm(vm, cursor + 2, prefixes)?;
+    let value = read_rmv(vm, &modrm, prefixes)?;
+    if value != 0 {
+        let index = if ext == 0xBC {
+            value.trailing_zeros()
+        } else {
+            31 - value.leading_zeros()
+        };
+        set_regv(vm, modrm.reg, prefixes, index);
+    }
+    vm.set_zf(value == 0);
+    vm.set_eip(cursor + 2 + modrm.len as u32);
+    Ok(())
+}
+
+pub(crate) fn bswap(vm: &mut Vm, cursor: u32, ext: u8, _prefixes: Prefixes) -> Result<(), VmError> {
+    let reg = ext - 0xC8;
+    let value = vm.reg32(reg);
+    vm.set_reg32(reg, value.swap_bytes());
+    vm.set_eip(cursor + 2);
+    Ok(())
+}
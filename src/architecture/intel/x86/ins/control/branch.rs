@@ -1,9 +1,11 @@
-use crate::vm::{Vm, VmError, REG_AL, REG_EDI, REG_EDX};
+use crate::vm::{FlagOp, Vm, VmError, REG_AL, REG_ECX, REG_EDI, REG_EDX};
 
 use crate::architecture::intel::x86::ins::core::{
-    decode_modrm, read_rm16, read_rm32, write_rm8, Prefixes,
+    decode_modrm, read_rmv, set_regv, set_string_reg, string_reg, write_rm8, Prefixes,
 };
 
+use super::jump::branch_target;
+
 pub(crate) fn jcc_rel8(vm: &mut Vm, cursor: u32, _prefixes: Prefixes) -> Result<(), VmError> {
     let opcode = vm.read_u8(cursor)?;
     let cond = condition(vm, opcode).ok_or(VmError::UnsupportedInstruction(opcode))?;
@@ -28,7 +30,7 @@ pub(crate) fn jcc_rel8(vm: &mut Vm, cursor: u32, _prefixes: Prefixes) -> Result<
                         vm.reg8(REG_AL)
                     );
                 }
-                vm.set_flags(true, false, false, false);
+                vm.record_flags(FlagOp::Sub, 32, 1, 1, 0);
                 vm.set_eip((next as i32).wrapping_add(rel) as u32);
                 return Ok(());
             }
@@ -43,7 +45,7 @@ pub(crate) fn jcc_rel8(vm: &mut Vm, cursor: u32, _prefixes: Prefixes) -> Result<
                 vm.memset(dst, value, count as usize)?;
                 vm.set_reg32(REG_EDI, dst.wrapping_add(count));
                 vm.set_reg32(REG_EDX, 0);
-                vm.set_flags(true, false, false, false);
+                vm.record_flags(FlagOp::Sub, 32, 1, 1, 0);
                 vm.set_eip((next as i32).wrapping_add(rel) as u32);
                 return Ok(());
             }
@@ -61,12 +63,37 @@ pub(crate) fn jcc_rel32_ext(
     vm: &mut Vm,
     cursor: u32,
     opcode: u8,
-    _prefixes: Prefixes,
+    prefixes: Prefixes,
 ) -> Result<(), VmError> {
     let cond = condition(vm, opcode).ok_or(VmError::UnsupportedInstruction(opcode))?;
-    let rel = vm.read_u32(cursor + 2)? as i32;
-    let next = cursor + 6;
+    let (target, next) = branch_target(vm, cursor + 2, prefixes)?;
     if cond {
+        vm.set_eip(target);
+    } else {
+        vm.set_eip(next);
+    }
+    Ok(())
+}
+
+// LOOPNZ, LOOPZ and LOOP (E0-E2) decrement ECX without touching flags; JECXZ (E3)
+// only tests it. The address-size prefix selects CX instead.
+pub(crate) fn loop_rel8(vm: &mut Vm, cursor: u32, prefixes: Prefixes) -> Result<(), VmError> {
+    let opcode = vm.read_u8(cursor)?;
+    let rel = vm.read_u8(cursor + 1)? as i8 as i32;
+    let next = cursor + 2;
+    let taken = if opcode == 0xE3 {
+        string_reg(vm, REG_ECX, prefixes) == 0
+    } else {
+        let ecx = string_reg(vm, REG_ECX, prefixes).wrapping_sub(1);
+        set_string_reg(vm, REG_ECX, prefixes, ecx);
+        ecx != 0
+            && match opcode {
+                0xE0 => !vm.zf(),
+                0xE1 => vm.zf(),
+                _ => true,
+            }
+    };
+    if taken {
         vm.set_eip((next as i32).wrapping_add(rel) as u32);
     } else {
         vm.set_eip(next);
@@ -75,7 +102,7 @@ pub(crate) fn jcc_rel32_ext(
 }
 
 pub(crate) fn setcc(vm: &mut Vm, cursor: u32, ext: u8, prefixes: Prefixes) -> Result<(), VmError> {
-    let modrm = decode_modrm(vm, cursor + 2)?;
+    let modrm = decode_modrm(vm, cursor + 2, prefixes)?;
     let cond = condition(vm, ext.wrapping_sub(0x20)).ok_or(VmError::UnsupportedInstruction(ext))?;
     let value = if cond { 1 } else { 0 };
     write_rm8(vm, &modrm, prefixes.segment_base, value)?;
@@ -93,15 +120,10 @@ pub(crate) fn cmovcc(vm: &mut Vm, cursor: u32, ext: u8, prefixes: Prefixes) -> R
             vm.reg32(crate::vm::REG_ESI)
         );
     }
-    let modrm = decode_modrm(vm, cursor + 2)?;
+    let modrm = decode_modrm(vm, cursor + 2, prefixes)?;
     if cond {
-        if prefixes.operand_size_16 {
-            let value = read_rm16(vm, &modrm, prefixes.segment_base)?;
-            vm.set_reg16(modrm.reg, value);
-        } else {
-            let value = read_rm32(vm, &modrm, prefixes.segment_base)?;
-            vm.set_reg32(modrm.reg, value);
-        }
+        let value = read_rmv(vm, &modrm, prefixes)?;
+        set_regv(vm, modrm.reg, prefixes, value);
     }
     vm.set_eip(cursor + 2 + modrm.len as u32);
     Ok(())
@@ -119,8 +141,8 @@ fn condition(vm: &Vm, opcode: u8) -> Option<bool> {
         0x77 | 0x87 => Some(!vm.cf() && !vm.zf()),
         0x78 | 0x88 => Some(vm.sf()),
         0x79 | 0x89 => Some(!vm.sf()),
-        0x7A | 0x8A => Some(false),
-        0x7B | 0x8B => Some(true),
+        0x7A | 0x8A => Some(vm.pf()),
+        0x7B | 0x8B => Some(!vm.pf()),
         0x7C | 0x8C => Some(vm.sf() != vm.of()),
         0x7D | 0x8D => Some(vm.sf() == vm.of()),
         0x7E | 0x8E => Some(vm.zf() || (vm.sf() != vm.of())),
@@ -2,10 +2,10 @@ use crate::vm::{Vm, VmError, REG_EAX, REG_ECX, REG_EDX, REG_ESP};
 
 use crate::architecture::intel::x86::ins::core::{calc_ea, ModRm, Prefixes};
 
-pub(crate) fn call_rel32(vm: &mut Vm, cursor: u32, _prefixes: Prefixes) -> Result<(), VmError> {
-    let rel = vm.read_u32(cursor + 1)? as i32;
-    let next = cursor + 5;
-    let target = (next as i32).wrapping_add(rel) as u32;
+use super::jump::branch_target;
+
+pub(crate) fn call_rel32(vm: &mut Vm, cursor: u32, prefixes: Prefixes) -> Result<(), VmError> {
+    let (target, next) = branch_target(vm, cursor + 1, prefixes)?;
     if target == 0 && std::env::var("PE_VM_ABORT_ON_NULL_CALL").is_ok() {
         if std::env::var("PE_VM_TRACE").is_ok() {
             eprintln!("[pe_vm] null call_rel32 target at eip=0x{cursor:08X} next=0x{next:08X}");
@@ -13,7 +13,11 @@ pub(crate) fn call_rel32(vm: &mut Vm, cursor: u32, _prefixes: Prefixes) -> Resul
         return Err(VmError::InvalidConfig("null call"));
     }
     if !vm.try_call_import(target, next)? {
-        vm.push(next)?;
+        if prefixes.operand_size_16 {
+            vm.push16(next as u16)?;
+        } else {
+            vm.push(next)?;
+        }
         vm.set_eip(target);
     }
     Ok(())
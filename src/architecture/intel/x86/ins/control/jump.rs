@@ -2,13 +2,27 @@ use crate::vm::{Vm, VmError};
 
 use crate::architecture::intel::x86::ins::core::{calc_ea, ModRm, Prefixes};
 
-pub(crate) fn jmp_rel32(vm: &mut Vm, cursor: u32, _prefixes: Prefixes) -> Result<(), VmError> {
-    let rel = vm.read_u32(cursor + 1)? as i32;
-    let next = cursor + 5;
-    vm.set_eip((next as i32).wrapping_add(rel) as u32);
+pub(crate) fn jmp_rel32(vm: &mut Vm, cursor: u32, prefixes: Prefixes) -> Result<(), VmError> {
+    let (target, _) = branch_target(vm, cursor + 1, prefixes)?;
+    vm.set_eip(target);
     Ok(())
 }
 
+/// Resolve a rel32 branch displacement at `addr`, or a rel16 one under the
+/// operand-size prefix, which also truncates the target to 16 bits. Returns the
+/// target and the address of the next instruction.
+pub(crate) fn branch_target(vm: &Vm, addr: u32, prefixes: Prefixes) -> Result<(u32, u32), VmError> {
+    if prefixes.operand_size_16 {
+        let rel = vm.read_u16(addr)? as i16 as i32;
+        let next = addr + 2;
+        Ok(((next as i32).wrapping_add(rel) as u32 & 0xFFFF, next))
+    } else {
+        let rel = vm.read_u32(addr)? as i32;
+        let next = addr + 4;
+        Ok(((next as i32).wrapping_add(rel) as u32, next))
+    }
+}
+
 pub(crate) fn jmp_rel8(vm: &mut Vm, cursor: u32, _prefixes: Prefixes) -> Result<(), VmError> {
     let rel = vm.read_u8(cursor + 1)? as i8 as i32;
     let next = cursor + 2;
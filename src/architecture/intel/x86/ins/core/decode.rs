@@ -2,8 +2,13 @@ use crate::vm::{Vm, VmError};
 
 #[derive(Default, Clone, Copy)]
 pub(crate) struct Prefixes {
+    /// Base of the FS or GS override; the other segments are flat.
     pub(crate) segment_base: u32,
+    /// 0x66: 16-bit operands.
     pub(crate) operand_size_16: bool,
+    /// 0x67: 16-bit addressing, and CX/SI/DI for string and loop instructions.
+    pub(crate) address_size_16: bool,
+    pub(crate) lock: bool,
     pub(crate) rep: bool,
     pub(crate) repne: bool,
 }
@@ -14,36 +19,65 @@ pub(crate) fn parse_prefixes(vm: &Vm, cursor: u32) -> Result<(u32, Prefixes), Vm
     loop {
         let byte = vm.read_u8(cursor)?;
         match byte {
-            0xF0 | 0xF2 | 0xF3 => {
-                if byte == 0xF2 {
-                    prefixes.repne = true;
-                    prefixes.rep = false;
-                } else if byte == 0xF3 {
-                    prefixes.rep = true;
-                    prefixes.repne = false;
-                }
-                cursor = cursor.wrapping_add(1);
+            0xF0 => prefixes.lock = true,
+            // The last of F2/F3 wins.
+            0xF2 => {
+                prefixes.repne = true;
+                prefixes.rep = false;
             }
-            0x64 => {
-                prefixes.segment_base = vm.fs_base();
-                cursor = cursor.wrapping_add(1);
-            }
-            0x65 => {
-                prefixes.segment_base = vm.gs_base();
-                cursor = cursor.wrapping_add(1);
-            }
-            0x66 => {
-                prefixes.operand_size_16 = true;
-                cursor = cursor.wrapping_add(1);
+            0xF3 => {
+                prefixes.rep = true;
+                prefixes.repne = false;
             }
+            // ES, CS, SS and DS overrides (CS and DS double as branch hints).
+            0x26 | 0x2E | 0x36 | 0x3E => prefixes.segment_base = 0,
+            0x64 => prefixes.segment_base = vm.fs_base(),
+            0x65 => prefixes.segment_base = vm.gs_base(),
+            0x66 => prefixes.operand_size_16 = true,
+            0x67 => prefixes.address_size_16 = true,
             _ => break,
         }
+        cursor = cursor.wrapping_add(1);
     }
     Ok((cursor, prefixes))
 }
 
-#[derive(Debug, Clone)]
+/// Whether a LOCK prefix is valid on the instruction at `cursor`: only the
+/// read-modify-write forms with a memory destination accept it.
+pub(crate) fn lock_allowed(vm: &Vm, cursor: u32) -> Result<bool, VmError> {
+    let opcode = vm.read_u8(cursor)?;
+    let (modrm_addr, groups) = match opcode {
+        // ADD, OR, ADC, SBB, AND, SUB, XOR r/m, r
+        0x00..=0x31 if opcode & 0x07 < 2 => (cursor + 1, 0xFF),
+        // Group 1 except CMP
+        0x80..=0x83 => (cursor + 1, 0x7F),
+        0x86 | 0x87 => (cursor + 1, 0xFF),
+        // NOT, NEG
+        0xF6 | 0xF7 => (cursor + 1, 0x0C),
+        // INC, DEC
+        0xFE | 0xFF => (cursor + 1, 0x03),
+        0x0F => {
+            let groups = match vm.read_u8(cursor + 1)? {
+                // BTS, BTR, BTC, CMPXCHG, XADD
+                0xAB | 0xB3 | 0xBB | 0xB0 | 0xB1 | 0xC0 | 0xC1 => 0xFF,
+                // Group 8 BTS/BTR/BTC
+                0xBA => 0xE0,
+                // CMPXCHG8B
+                0xC7 => 0x02,
+                _ => return Ok(false),
+            };
+            (cursor + 2, groups)
+        }
+        _ => return Ok(false),
+    };
+    let modrm = vm.read_u8(modrm_addr)?;
+    Ok(modrm >> 6 != 3 && groups & (1 << ((modrm >> 3) & 7)) != 0)
+}
+
+#[derive(Debug, Clone, Copy)]
 pub(crate) struct ModRm {
+    /// Decoded with 16-bit addressing (BX/BP + SI/DI forms, 16-bit displacements).
+    pub(crate) address_size_16: bool,
     pub(crate) mod_bits: u8,
     pub(crate) reg: u8,
     pub(crate) rm: u8,
@@ -52,24 +86,68 @@ pub(crate) struct ModRm {
     pub(crate) len: usize,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Copy)]
 pub(crate) struct Sib {
     pub(crate) scale: u8,
     pub(crate) index: u8,
     pub(crate) base: u8,
 }
 
-pub(crate) fn decode_modrm(vm: &Vm, addr: u32) -> Result<ModRm, VmError> {
-    let modrm = vm.read_u8(addr)?;
+pub(crate) fn decode_modrm(vm: &Vm, addr: u32, prefixes: Prefixes) -> Result<ModRm, VmError> {
+    if let Some(modrm) = vm.decode_cache().modrm_at(addr, prefixes.address_size_16) {
+        return Ok(modrm);
+    }
+    parse_modrm(|at| vm.read_u8(at), addr, prefixes.address_size_16)
+}
+
+/// Decode the ModRM byte at `addr` and any SIB and displacement after it, reading
+/// bytes through `read_u8`; shared with the disassembler.
+pub(crate) fn parse_modrm<E>(
+    read_u8: impl Fn(u32) -> Result<u8, E>,
+    addr: u32,
+    address_size_16: bool,
+) -> Result<ModRm, E> {
+    let read_disp = |at: u32, len: u32| -> Result<i32, E> {
+        let mut value = 0u32;
+        for index in 0..len {
+            value |= (read_u8(at.wrapping_add(index))? as u32) << (index * 8);
+        }
+        Ok(match len {
+            1 => value as i8 as i32,
+            2 => value as i16 as i32,
+            _ => value as i32,
+        })
+    };
+    let modrm = read_u8(addr)?;
     let mod_bits = (modrm >> 6) & 0x3;
     let reg = (modrm >> 3) & 0x7;
     let rm = modrm & 0x7;
-    let mut len = 1usize;
+    if address_size_16 {
+        let disp_len = match mod_bits {
+            0 if rm == 6 => 2,
+            1 => 1,
+            2 => 2,
+            _ => 0,
+        };
+        let disp = if disp_len == 0 {
+            0
+        } else {
+            read_disp(addr.wrapping_add(1), disp_len)?
+        };
+        return Ok(ModRm {
+            address_size_16: true,
+            mod_bits,
+            reg,
+            rm,
+            disp,
+            sib: None,
+            len: 1 + disp_len as usize,
+        });
+    }
+    let mut len = 1u32;
     let mut sib = None;
-    let mut disp = 0i32;
-
     if mod_bits != 3 && rm == 4 {
-        let sib_byte = vm.read_u8(addr + len as u32)?;
+        let sib_byte = read_u8(addr.wrapping_add(len))?;
         len += 1;
         sib = Some(Sib {
             scale: (sib_byte >> 6) & 0x3,
@@ -77,44 +155,53 @@ pub(crate) fn decode_modrm(vm: &Vm, addr: u32) -> Result<ModRm, VmError> {
             base: sib_byte & 0x7,
         });
     }
-
-    match mod_bits {
-        0 => {
-            if rm == 5 {
-                disp = vm.read_u32(addr + len as u32)? as i32;
-                len += 4;
-            } else if let Some(sib_val) = &sib {
-                if sib_val.base == 5 {
-                    disp = vm.read_u32(addr + len as u32)? as i32;
-                    len += 4;
-                }
-            }
-        }
-        1 => {
-            disp = vm.read_u8(addr + len as u32)? as i8 as i32;
-            len += 1;
-        }
-        2 => {
-            disp = vm.read_u32(addr + len as u32)? as i32;
-            len += 4;
-        }
-        _ => {}
-    }
-
+    let disp_len = match mod_bits {
+        0 if rm == 5 || sib.is_some_and(|sib| sib.base == 5) => 4,
+        1 => 1,
+        2 => 4,
+        _ => 0,
+    };
+    let disp = if disp_len == 0 {
+        0
+    } else {
+        read_disp(addr.wrapping_add(len), disp_len)?
+    };
     Ok(ModRm {
+        address_size_16: false,
         mod_bits,
         reg,
         rm,
         disp,
         sib,
-        len,
+        len: (len + disp_len) as usize,
     })
 }
 
+// BX+SI, BX+DI, BP+SI, BP+DI, SI, DI, BP (or disp16 alone with mod 0) and BX,
+// wrapping at 64 KiB.
+fn calc_ea16(vm: &Vm, modrm: &ModRm) -> u32 {
+    let (bx, bp, si, di) = (vm.reg16(3), vm.reg16(5), vm.reg16(6), vm.reg16(7));
+    let base = match modrm.rm {
+        0 => bx.wrapping_add(si),
+        1 => bx.wrapping_add(di),
+        2 => bp.wrapping_add(si),
+        3 => bp.wrapping_add(di),
+        4 => si,
+        5 => di,
+        6 if modrm.mod_bits == 0 => 0,
+        6 => bp,
+        _ => bx,
+    };
+    base.wrapping_add(modrm.disp as u16) as u32
+}
+
 pub(crate) fn calc_ea(vm: &Vm, modrm: &ModRm, segment_base: u32) -> Result<u32, VmError> {
     if modrm.mod_bits == 3 {
         return Err(VmError::UnsupportedInstruction(0));
     }
+    if modrm.address_size_16 {
+        return Ok(segment_base.wrapping_add(calc_ea16(vm, modrm)));
+    }
     let mut base = 0u32;
     if let Some(sib) = &modrm.sib {
         if sib.index != 4 {
@@ -1,98 +1,60 @@
-use crate::vm::Vm;
+use crate::vm::{FlagOp, Vm};
 
 pub(crate) fn update_flags_logic32(vm: &mut Vm, result: u32) {
-    let zf = result == 0;
-    let sf = (result & 0x8000_0000) != 0;
-    vm.set_flags(zf, sf, false, false);
+    vm.record_flags(FlagOp::Logic, 32, 0, 0, result);
 }
 
 pub(crate) fn update_flags_logic8(vm: &mut Vm, result: u8) {
-    let zf = result == 0;
-    let sf = (result & 0x80) != 0;
-    vm.set_flags(zf, sf, false, false);
+    vm.record_flags(FlagOp::Logic, 8, 0, 0, result as u32);
 }
 
 pub(crate) fn update_flags_logic16(vm: &mut Vm, result: u16) {
     // Operand-size override uses 16-bit flags.
-    let zf = result == 0;
-    let sf = (result & 0x8000) != 0;
-    vm.set_flags(zf, sf, false, false);
+    vm.record_flags(FlagOp::Logic, 16, 0, 0, result as u32);
 }
 
-pub(crate) fn update_flags_add32(vm: &mut Vm, a: u32, b: u32, result: u32) {
-    let sign = 0x8000_0000;
-    let zf = result == 0;
-    let sf = (result & sign) != 0;
-    let of = ((a ^ result) & (b ^ result) & sign) != 0;
-    let cf = (a as u64 + b as u64) > 0xFFFF_FFFF;
-    vm.set_flags(zf, sf, of, cf);
+pub(crate) fn update_flags_add8(vm: &mut Vm, a: u8, b: u8, result: u8) {
+    vm.record_flags(FlagOp::Add, 8, a as u32, b as u32, result as u32);
 }
 
 pub(crate) fn update_flags_sub32(vm: &mut Vm, a: u32, b: u32, result: u32) {
-    let sign = 0x8000_0000;
-    let zf = result == 0;
-    let sf = (result & sign) != 0;
-    let of = ((a ^ b) & (a ^ result) & sign) != 0;
-    let cf = a < b;
-    vm.set_flags(zf, sf, of, cf);
+    vm.record_flags(FlagOp::Sub, 32, a, b, result);
 }
 
-pub(crate) fn update_flags_sub32_with_cf(vm: &mut Vm, a: u32, b: u32, result: u32, cf: bool) {
-    let sign = 0x8000_0000;
-    let zf = result == 0;
-    let sf = (result & sign) != 0;
-    let of = ((a ^ b) & (a ^ result) & sign) != 0;
-    vm.set_flags(zf, sf, of, cf);
+pub(crate) fn update_flags_sub16(vm: &mut Vm, a: u16, b: u16, result: u16) {
+    vm.record_flags(FlagOp::Sub, 16, a as u32, b as u32, result as u32);
 }
 
-pub(crate) fn update_flags_sub16(vm: &mut Vm, a: u16, b: u16, result: u16) {
-    let sign = 0x8000;
-    let zf = result == 0;
-    let sf = (result & sign) != 0;
-    let of = ((a ^ b) & (a ^ result) & sign) != 0;
-    let cf = a < b;
-    vm.set_flags(zf, sf, of, cf);
+pub(crate) fn update_flags_sub8(vm: &mut Vm, a: u8, b: u8, result: u8) {
+    vm.record_flags(FlagOp::Sub, 8, a as u32, b as u32, result as u32);
 }
 
-pub(crate) fn update_flags_add8(vm: &mut Vm, a: u8, b: u8, result: u8) {
-    let sign = 0x80;
-    let zf = result == 0;
-    let sf = (result & sign) != 0;
-    let of = ((a ^ result) & (b ^ result) & sign) != 0;
-    let cf = (a as u16 + b as u16) > 0xFF;
-    vm.set_flags(zf, sf, of, cf);
+// INC and DEC set every status flag but CF.
+pub(crate) fn update_flags_inc8(vm: &mut Vm, value: u8, result: u8) {
+    vm.record_flags(FlagOp::Inc, 8, value as u32, 1, result as u32);
 }
 
-pub(crate) fn update_flags_sub8(vm: &mut Vm, a: u8, b: u8, result: u8) {
-    let sign = 0x80;
-    let zf = result == 0;
-    let sf = (result & sign) != 0;
-    let of = ((a ^ b) & (a ^ result) & sign) != 0;
-    let cf = a < b;
-    vm.set_flags(zf, sf, of, cf);
+pub(crate) fn update_flags_dec8(vm: &mut Vm, value: u8, result: u8) {
+    vm.record_flags(FlagOp::Dec, 8, value as u32, 1, result as u32);
+}
+
+// Width-generic forms for handlers that follow the operand size (16 or 32).
+pub(crate) fn update_flags_logic(vm: &mut Vm, width: u8, result: u32) {
+    vm.record_flags(FlagOp::Logic, width, 0, 0, result);
+}
+
+pub(crate) fn update_flags_add(vm: &mut Vm, width: u8, a: u32, b: u32, result: u32) {
+    vm.record_flags(FlagOp::Add, width, a, b, result);
+}
+
+pub(crate) fn update_flags_sub(vm: &mut Vm, width: u8, a: u32, b: u32, result: u32) {
+    vm.record_flags(FlagOp::Sub, width, a, b, result);
 }
 
-pub(crate) fn update_flags_sub8_with_cf(vm: &mut Vm, a: u8, b: u8, result: u8, cf: bool) {
-    let sign = 0x80;
-    let zf = result == 0;
-    let sf = (result & sign) != 0;
-    let of = ((a ^ b) & (a ^ result) & sign) != 0;
-    vm.set_flags(zf, sf, of, cf);
+pub(crate) fn update_flags_inc(vm: &mut Vm, width: u8, value: u32, result: u32) {
+    vm.record_flags(FlagOp::Inc, width, value, 1, result);
 }
 
-pub(crate) fn pack_eflags(vm: &Vm) -> u32 {
-    let mut value = 1u32 << 1;
-    if vm.cf() {
-        value |= 1;
-    }
-    if vm.zf() {
-        value |= 1 << 6;
-    }
-    if vm.sf() {
-        value |= 1 << 7;
-    }
-    if vm.of() {
-        value |= 1 << 11;
-    }
-    value
+pub(crate) fn update_flags_dec(vm: &mut Vm, width: u8, value: u32, result: u32) {
+    vm.record_flags(FlagOp::Dec, width, value, 1, result);
 }
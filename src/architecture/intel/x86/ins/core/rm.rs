@@ -1,6 +1,6 @@
 use crate::vm::{Vm, VmError};
 
-use super::decode::{calc_ea, ModRm};
+use super::decode::{calc_ea, ModRm, Prefixes};
 
 pub(crate) fn segment_value(reg: u8) -> u16 {
     match reg & 0x7 {
@@ -85,3 +85,89 @@ pub(crate) fn write_rm8(
         vm.write_u8(addr, value)
     }
 }
+
+/// The operand size in bits: 16 with the 0x66 prefix, otherwise 32.
+pub(crate) fn operand_width(prefixes: Prefixes) -> u8 {
+    if prefixes.operand_size_16 {
+        16
+    } else {
+        32
+    }
+}
+
+pub(crate) fn width_mask(width: u8) -> u32 {
+    u32::MAX >> (32 - width as u32)
+}
+
+/// Sign-extend the low `width` bits of `value`.
+pub(crate) fn sign_extend(value: u32, width: u8) -> u32 {
+    let shift = 32 - width as u32;
+    (((value << shift) as i32) >> shift) as u32
+}
+
+/// Read an r/m operand of the current operand size, zero-extended.
+pub(crate) fn read_rmv(vm: &Vm, modrm: &ModRm, prefixes: Prefixes) -> Result<u32, VmError> {
+    if prefixes.operand_size_16 {
+        Ok(read_rm16(vm, modrm, prefixes.segment_base)? as u32)
+    } else {
+        read_rm32(vm, modrm, prefixes.segment_base)
+    }
+}
+
+/// Write an r/m operand of the current operand size; 16-bit writes keep the
+/// upper half of a register destination.
+pub(crate) fn write_rmv(
+    vm: &mut Vm,
+    modrm: &ModRm,
+    prefixes: Prefixes,
+    value: u32,
+) -> Result<(), VmError> {
+    if prefixes.operand_size_16 {
+        write_rm16(vm, modrm, prefixes.segment_base, value as u16)
+    } else {
+        write_rm32(vm, modrm, prefixes.segment_base, value)
+    }
+}
+
+pub(crate) fn regv(vm: &Vm, reg: u8, prefixes: Prefixes) -> u32 {
+    if prefixes.operand_size_16 {
+        vm.reg16(reg) as u32
+    } else {
+        vm.reg32(reg)
+    }
+}
+
+pub(crate) fn set_regv(vm: &mut Vm, reg: u8, prefixes: Prefixes, value: u32) {
+    if prefixes.operand_size_16 {
+        vm.set_reg16(reg, value as u16);
+    } else {
+        vm.set_reg32(reg, value);
+    }
+}
+
+/// Read an imm16 or imm32 operand; returns the value and its length in bytes.
+pub(crate) fn read_immv(vm: &Vm, addr: u32, prefixes: Prefixes) -> Result<(u32, u32), VmError> {
+    if prefixes.operand_size_16 {
+        Ok((vm.read_u16(addr)? as u32, 2))
+    } else {
+        Ok((vm.read_u32(addr)?, 4))
+    }
+}
+
+/// Read a string or loop counter register: CX, SI or DI under the address-size
+/// prefix, ECX, ESI or EDI otherwise.
+pub(crate) fn string_reg(vm: &Vm, reg: u8, prefixes: Prefixes) -> u32 {
+    if prefixes.address_size_16 {
+        vm.reg16(reg) as u32
+    } else {
+        vm.reg32(reg)
+    }
+}
+
+pub(crate) fn set_string_reg(vm: &mut Vm, reg: u8, prefixes: Prefixes, value: u32) {
+    if prefixes.address_size_16 {
+        vm.set_reg16(reg, value as u16);
+    } else {
+        vm.set_reg32(reg, value);
+    }
+}
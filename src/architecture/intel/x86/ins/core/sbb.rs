@@ -1,17 +1,16 @@
-use crate::vm::Vm;
+use crate::vm::{FlagOp, Vm};
 
-pub(crate) fn sbb32(vm: &mut Vm, a: u32, b: u32) -> (u32, bool) {
-    let carry = if vm.cf() { 1u64 } else { 0 };
-    let b_total = b as u64 + carry;
-    let result = a.wrapping_sub(b_total as u32);
-    let cf = (a as u64) < b_total;
-    (result, cf)
+/// SBB at the operand width (16 or 32); the caller truncates the result.
+pub(crate) fn sbb(vm: &mut Vm, width: u8, a: u32, b: u32) -> u32 {
+    let borrow = vm.cf();
+    let result = a.wrapping_sub(b).wrapping_sub(borrow as u32);
+    vm.record_flags_with_carry(FlagOp::Sbb, width, a, b, result, borrow);
+    result
 }
 
-pub(crate) fn sbb8(vm: &mut Vm, a: u8, b: u8) -> (u8, bool) {
-    let carry = if vm.cf() { 1u16 } else { 0 };
-    let b_total = b as u16 + carry;
-    let result = a.wrapping_sub(b_total as u8);
-    let cf = (a as u16) < b_total;
-    (result, cf)
+pub(crate) fn sbb8(vm: &mut Vm, a: u8, b: u8) -> u8 {
+    let borrow = vm.cf();
+    let result = a.wrapping_sub(b).wrapping_sub(borrow as u8);
+    vm.record_flags_with_carry(FlagOp::Sbb, 8, a as u32, b as u32, result as u32, borrow);
+    result
 }
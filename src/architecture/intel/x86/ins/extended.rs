@@ -3,38 +3,48 @@
 use crate::vm::{Vm, VmError};
 
 use super::core::Prefixes;
-use super::{atomic, bit, control, imul, mov, sse, system};
+use super::{atomic, bit, control, imul, mov, shift, sse, system};
 
 pub(crate) fn exec(vm: &mut Vm, cursor: u32, prefixes: Prefixes) -> Result<(), VmError> {
     let ext = vm.read_u8(cursor + 1)?;
     match ext {
-        0x82..=0x8F => control::jcc_rel32_ext(vm, cursor, ext, prefixes),
+        0x80..=0x8F => control::jcc_rel32_ext(vm, cursor, ext, prefixes),
         0x90..=0x9F => control::setcc(vm, cursor, ext, prefixes),
+        0x0B => system::ud2(vm, cursor, prefixes),
+        0x1F => system::nop_rm32(vm, cursor, prefixes),
+        0x31 => system::rdtsc(vm, cursor, prefixes),
         0xA2 => system::cpuid(vm, cursor, prefixes),
+        0xA3 | 0xAB | 0xB3 | 0xBB => bit::bit_rm32_r32(vm, cursor, ext, prefixes),
+        0xA4 | 0xA5 | 0xAC | 0xAD => shift::double_shift(vm, cursor, ext, prefixes),
         0x40..=0x4F => control::cmovcc(vm, cursor, ext, prefixes),
-        0x57 => sse::exec_rm32(vm, cursor, prefixes),
-        0x6F => sse::exec_rm32(vm, cursor, prefixes),
-        0x7F => sse::exec_rm32(vm, cursor, prefixes),
         0xAF => imul::imul_r32_rm32(vm, cursor, prefixes),
         0xBA => bit::group_ba(vm, cursor, prefixes),
+        0xB0 => atomic::cmpxchg_rm8_r8(vm, cursor, prefixes),
         0xB1 => atomic::cmpxchg_rm32_r32(vm, cursor, prefixes),
         0xB6 => mov::movzx_rm8(vm, cursor, prefixes),
         0xB7 => mov::movzx_rm16(vm, cursor, prefixes),
         0xBE => mov::movsx_rm8(vm, cursor, prefixes),
         0xBF => mov::movsx_rm16(vm, cursor, prefixes),
+        0xBC | 0xBD => bit::bit_scan(vm, cursor, ext, prefixes),
         0x01 => system::xgetbv(vm, cursor, prefixes),
+        0xC0 => atomic::xadd_rm8_r8(vm, cursor, prefixes),
         0xC1 => atomic::xadd_rm32_r32(vm, cursor, prefixes),
-        0xD6 => sse::exec_rm32(vm, cursor, prefixes),
+        0xC7 => atomic::cmpxchg8b(vm, cursor, prefixes),
+        0xC8..=0xCF => bit::bswap(vm, cursor, ext, prefixes),
+        ext if sse::is_sse_opcode(ext) => sse::exec(vm, cursor, prefixes),
         _ => Err(VmError::UnsupportedInstruction(ext)),
     }
 }
 
 pub(crate) fn supported_opcodes() -> Vec<u8> {
     let mut ops: Vec<u8> = (0x40..=0x4F).collect();
-    ops.extend(0x82..=0x8F);
+    ops.extend(0x80..=0x8F);
     ops.extend([
-        0x01, 0x57, 0x6F, 0x7F, 0xA2, 0xAF, 0xBA, 0xB1, 0xB6, 0xB7, 0xC1, 0xD6,
+        0x01, 0x0B, 0x1F, 0x31, 0xA2, 0xA3, 0xA4, 0xA5, 0xAB, 0xAC, 0xAD, 0xAF, 0xB0, 0xB1, 0xB3,
+        0xB6, 0xB7, 0xBA, 0xBB, 0xBC, 0xBD, 0xC0, 0xC1, 0xC7,
     ]);
+    ops.extend(0xC8..=0xCF);
+    ops.extend((0x00..=0xFF).filter(|&ext| sse::is_sse_opcode(ext)));
     ops.extend(0x90..=0x9F);
     ops.extend([0xBE, 0xBF]);
     ops.sort_unstable();
@@ -0,0 +1,148 @@
+//! x86 flag manipulation and decimal adjust instruction handlers.
+
+use crate::vm::{Vm, VmError, REG_AH, REG_AL, REG_EAX};
+
+use super::core::Prefixes;
+
+pub(crate) fn cmc(vm: &mut Vm, cursor: u32, _prefixes: Prefixes) -> Result<(), VmError> {
+    let cf = vm.cf();
+    vm.set_cf(!cf);
+    vm.set_eip(cursor + 1);
+    Ok(())
+}
+
+pub(crate) fn clc(vm: &mut Vm, cursor: u32, _prefixes: Prefixes) -> Result<(), VmError> {
+    vm.set_cf(false);
+    vm.set_eip(cursor + 1);
+    Ok(())
+}
+
+pub(crate) fn stc(vm: &mut Vm, cursor: u32, _prefixes: Prefixes) -> Result<(), VmError> {
+    vm.set_cf(true);
+    vm.set_eip(cursor + 1);
+    Ok(())
+}
+
+pub(crate) fn cld(vm: &mut Vm, cursor: u32, _prefixes: Prefixes) -> Result<(), VmError> {
+    vm.set_df(false);
+    vm.set_eip(cursor + 1);
+    Ok(())
+}
+
+pub(crate) fn std(vm: &mut Vm, cursor: u32, _prefixes: Prefixes) -> Result<(), VmError> {
+    vm.set_df(true);
+    vm.set_eip(cursor + 1);
+    Ok(())
+}
+
+pub(crate) fn sahf(vm: &mut Vm, cursor: u32, _prefixes: Prefixes) -> Result<(), VmError> {
+    let value = vm.reg8(REG_AH);
+    vm.set_low_flags(value);
+    vm.set_eip(cursor + 1);
+    Ok(())
+}
+
+pub(crate) fn lahf(vm: &mut Vm, cursor: u32, _prefixes: Prefixes) -> Result<(), VmError> {
+    let value = vm.low_flags();
+    vm.set_reg8(REG_AH, value);
+    vm.set_eip(cursor + 1);
+    Ok(())
+}
+
+// Decimal adjusts set ZF/SF/PF from AL; OF is undefined and reported clear.
+fn set_adjust_flags(vm: &mut Vm, al: u8, cf: bool, af: bool) {
+    vm.set_result_flags(8, al as u32, cf, false);
+    vm.set_af(af);
+}
+
+pub(crate) fn daa(vm: &mut Vm, cursor: u32, _prefixes: Prefixes) -> Result<(), VmError> {
+    let old_al = vm.reg8(REG_AL);
+    let mut al = old_al;
+    let af = (al & 0x0F) > 9 || vm.af();
+    if af {
+        al = al.wrapping_add(6);
+    }
+    let cf = old_al > 0x99 || vm.cf();
+    if cf {
+        al = al.wrapping_add(0x60);
+    }
+    vm.set_reg8(REG_AL, al);
+    set_adjust_flags(vm, al, cf, af);
+    vm.set_eip(cursor + 1);
+    Ok(())
+}
+
+pub(crate) fn das(vm: &mut Vm, cursor: u32, _prefixes: Prefixes) -> Result<(), VmError> {
+    let old_al = vm.reg8(REG_AL);
+    let old_cf = vm.cf();
+    let mut al = old_al;
+    let mut cf = false;
+    let af = (al & 0x0F) > 9 || vm.af();
+    if af {
+        let (difference, borrow) = al.overflowing_sub(6);
+        al = difference;
+        cf = old_cf || borrow;
+    }
+    if old_al > 0x99 || old_cf {
+        al = al.wrapping_sub(0x60);
+        cf = true;
+    }
+    vm.set_reg8(REG_AL, al);
+    set_adjust_flags(vm, al, cf, af);
+    vm.set_eip(cursor + 1);
+    Ok(())
+}
+
+pub(crate) fn aaa(vm: &mut Vm, cursor: u32, _prefixes: Prefixes) -> Result<(), VmError> {
+    let adjust = (vm.reg8(REG_AL) & 0x0F) > 9 || vm.af();
+    if adjust {
+        let ax = vm.reg16(REG_EAX).wrapping_add(0x106);
+        vm.set_reg16(REG_EAX, ax);
+    }
+    let al = vm.reg8(REG_AL) & 0x0F;
+    vm.set_reg8(REG_AL, al);
+    set_adjust_flags(vm, al, adjust, adjust);
+    vm.set_eip(cursor + 1);
+    Ok(())
+}
+
+pub(crate) fn aas(vm: &mut Vm, cursor: u32, _prefixes: Prefixes) -> Result<(), VmError> {
+    let adjust = (vm.reg8(REG_AL) & 0x0F) > 9 || vm.af();
+    if adjust {
+        let al = vm.reg8(REG_AL).wrapping_sub(6);
+        let ah = vm.reg8(REG_AH).wrapping_sub(1);
+        vm.set_reg8(REG_AL, al);
+        vm.set_reg8(REG_AH, ah);
+    }
+    let al = vm.reg8(REG_AL) & 0x0F;
+    vm.set_reg8(REG_AL, al);
+    set_adjust_flags(vm, al, adjust, adjust);
+    vm.set_eip(cursor + 1);
+    Ok(())
+}
+
+pub(crate) fn aam(vm: &mut Vm, cursor: u32, _prefixes: Prefixes) -> Result<(), VmError> {
+    let base = vm.read_u8(cursor + 1)?;
+    if base == 0 {
+        return Err(VmError::DivideError);
+    }
+    let value = vm.reg8(REG_AL);
+    let al = value % base;
+    vm.set_reg8(REG_AH, value / base);
+    vm.set_reg8(REG_AL, al);
+    set_adjust_flags(vm, al, false, false);
+    vm.set_eip(cursor + 2);
+    Ok(())
+}
+
+pub(crate) fn aad(vm: &mut Vm, cursor: u32, _prefixes: Prefixes) -> Result<(), VmError> {
+    let base = vm.read_u8(cursor + 1)?;
+    let al = vm
+        .reg8(REG_AL)
+        .wrapping_add(vm.reg8(REG_AH).wrapping_mul(base));
+    vm.set_reg8(REG_AL, al);
+    vm.set_reg8(REG_AH, 0);
+    set_adjust_flags(vm, al, false, false);
+    vm.set_eip(cursor + 2);
+    Ok(())
+}
@@ -0,0 +1,46 @@
+//! FADD/FSUB/FSUBR/FMUL/FDIV/FDIVR and their integer and popping forms
+
+use crate::vm::{RoundMode, Vm, VmError, F80};
+
+use super::{read_operand, set_result, stack_underflow, ArithOp, Operand};
+
+impl ArithOp {
+    /// `dst op src`, with the reversed forms swapping the operands.
+    fn apply(self, dst: F80, src: F80, mode: RoundMode) -> (F80, u16) {
+        match self {
+            ArithOp::Add => dst.add(src, mode),
+            ArithOp::Mul => dst.mul(src, mode),
+            ArithOp::Sub => dst.sub(src, mode),
+            ArithOp::SubR => src.sub(dst, mode),
+            ArithOp::Div => dst.div(src, mode),
+            ArithOp::DivR => src.div(dst, mode),
+        }
+    }
+}
+
+/// ST(0) = ST(0) op src
+pub(super) fn arith(vm: &mut Vm, op: ArithOp, src: Operand) -> Result<(), VmError> {
+    let operand = read_operand(vm, src)?;
+    let (Some(dst), Some((src, load_flags))) = (vm.fpu_get(0), operand) else {
+        stack_underflow(vm, Some(0));
+        return Ok(());
+    };
+    let (value, flags) = op.apply(dst, src, vm.fpu_round_mode());
+    set_result(vm, 0, value, flags | load_flags);
+    Ok(())
+}
+
+/// ST(i) = ST(i) op ST(0), popping afterwards for the -P forms.
+pub(super) fn arith_to(vm: &mut Vm, op: ArithOp, dst: u8, pop: bool) {
+    let index = dst as usize;
+    let completed = match (vm.fpu_get(index), vm.fpu_get(0)) {
+        (Some(dst), Some(src)) => {
+            let (value, flags) = op.apply(dst, src, vm.fpu_round_mode());
+            set_result(vm, index, value, flags)
+        }
+        _ => stack_underflow(vm, Some(index)),
+    };
+    if pop && completed {
+        vm.fpu_pop();
+    }
+}
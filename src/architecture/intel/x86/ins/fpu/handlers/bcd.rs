@@ -0,0 +1,36 @@
+//! FBLD/FBSTP - Load and store packed BCD
+
+use crate::vm::{Vm, VmError, F80};
+
+use super::read_ten;
+
+/// The packed BCD indefinite stored for masked invalid operations.
+const BCD_INDEFINITE: [u8; 10] = [0, 0, 0, 0, 0, 0, 0, 0xC0, 0xFF, 0xFF];
+
+/// FBLD m80bcd (DF /4)
+pub(super) fn fbld(vm: &mut Vm, addr: u32) -> Result<(), VmError> {
+    let bytes = read_ten(vm, addr)?;
+    vm.fpu_push(F80::from_bcd(bytes));
+    Ok(())
+}
+
+/// FBSTP m80bcd (DF /6)
+pub(super) fn fbstp(vm: &mut Vm, addr: u32) -> Result<(), VmError> {
+    let (bytes, flags) = match vm.fpu_get(0) {
+        Some(value) => value.to_bcd(vm.fpu_rounding()),
+        None => {
+            vm.fpu_stack_fault(false);
+            (None, 0)
+        }
+    };
+    if vm.fpu_blocks_result(flags) || (bytes.is_none() && !vm.fpu_invalid_masked()) {
+        vm.fpu_raise(flags);
+        return Ok(());
+    }
+    vm.write_bytes(addr, &bytes.unwrap_or(BCD_INDEFINITE))?;
+    if flags != 0 {
+        vm.fpu_raise(flags);
+    }
+    vm.fpu_pop();
+    Ok(())
+}
@@ -0,0 +1,119 @@
+//! FCOM/FUCOM/FICOM, FCOMI/FUCOMI, FTST and FXAM
+
+use std::cmp::Ordering;
+
+use crate::vm::{Class, Vm, VmError, F80, FSW_C0, FSW_C1, FSW_C2, FSW_C3};
+
+use super::{read_operand, stack_underflow, Operand};
+
+const UNORDERED: u16 = FSW_C3 | FSW_C2 | FSW_C0;
+
+// EFLAGS bits FCOMI reports the result in.
+const ZF: u8 = 1 << 6;
+const PF: u8 = 1 << 2;
+const CF: u8 = 1 << 0;
+
+/// The ordering of `a` against `b` as condition codes, plus the exceptions raised.
+fn condition(a: F80, b: F80, unordered: bool) -> (u16, u16) {
+    let flags = a.compare_flags(b, unordered);
+    let bits = match a.compare(b) {
+        Some(Ordering::Greater) => 0,
+        Some(Ordering::Less) => FSW_C0,
+        Some(Ordering::Equal) => FSW_C3,
+        None => UNORDERED,
+    };
+    (bits, flags)
+}
+
+fn pop_times(vm: &mut Vm, pops: u8) {
+    for _ in 0..pops {
+        vm.fpu_pop();
+    }
+}
+
+/// Compare ST(0) with `src` into C0, C2 and C3.
+pub(super) fn compare(vm: &mut Vm, src: Operand, pops: u8, unordered: bool) -> Result<(), VmError> {
+    let operand = read_operand(vm, src)?;
+    let (Some(a), Some((b, load_flags))) = (vm.fpu_get(0), operand) else {
+        if stack_underflow(vm, None) {
+            vm.fpu_set_condition(UNORDERED);
+            pop_times(vm, pops);
+        }
+        return Ok(());
+    };
+    let (bits, flags) = condition(a, b, unordered);
+    vm.fpu_raise(flags | load_flags);
+    if vm.fpu_blocks_result(flags | load_flags) {
+        return Ok(());
+    }
+    vm.fpu_set_condition(bits);
+    pop_times(vm, pops);
+    Ok(())
+}
+
+/// Compare ST(0) with ST(i) into ZF, PF and CF.
+pub(super) fn compare_eflags(vm: &mut Vm, src: u8, pop: bool, unordered: bool) {
+    let (Some(a), Some(b)) = (vm.fpu_get(0), vm.fpu_get(src as usize)) else {
+        if stack_underflow(vm, None) {
+            vm.set_low_flags(ZF | PF | CF);
+            vm.set_of(false);
+            if pop {
+                vm.fpu_pop();
+            }
+        }
+        return;
+    };
+    let (bits, flags) = condition(a, b, unordered);
+    vm.fpu_raise(flags);
+    if vm.fpu_blocks_result(flags) {
+        return;
+    }
+    let mut eflags = 0;
+    if bits & FSW_C3 != 0 {
+        eflags |= ZF;
+    }
+    if bits & FSW_C2 != 0 {
+        eflags |= PF;
+    }
+    if bits & FSW_C0 != 0 {
+        eflags |= CF;
+    }
+    vm.set_low_flags(eflags);
+    vm.set_of(false);
+    if pop {
+        vm.fpu_pop();
+    }
+}
+
+/// FTST: compare ST(0) with +0.0.
+pub(super) fn test(vm: &mut Vm) {
+    let Some(value) = vm.fpu_get(0) else {
+        if stack_underflow(vm, None) {
+            vm.fpu_set_condition(UNORDERED);
+        }
+        return;
+    };
+    let (bits, flags) = condition(value, F80::ZERO, false);
+    vm.fpu_raise(flags);
+    if !vm.fpu_blocks_result(flags) {
+        vm.fpu_set_condition(bits);
+    }
+}
+
+/// FXAM: classify ST(0), with its sign in C1.
+pub(super) fn examine(vm: &mut Vm) {
+    let Some(value) = vm.fpu_get(0) else {
+        vm.fpu_set_condition(FSW_C3 | FSW_C0);
+        return;
+    };
+    let class = match value.class() {
+        Class::Unsupported => 0,
+        Class::Nan => FSW_C0,
+        Class::Normal => FSW_C2,
+        Class::Infinity => FSW_C2 | FSW_C0,
+        Class::Zero => FSW_C3,
+        Class::Denormal => FSW_C3 | FSW_C2,
+    };
+    let sign = if value.is_negative() { FSW_C1 } else { 0 };
+    vm.fpu_set_condition(class | sign);
+}
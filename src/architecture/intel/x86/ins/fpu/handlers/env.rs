@@ -0,0 +1,49 @@
+//! FNSTSW, FLDENV/FNSTENV, FRSTOR/FNSAVE and FWAIT
+
+use crate::architecture::intel::x86::ins::core::Prefixes;
+use crate::vm::{Vm, VmError, FPU_ENV_SIZE, FPU_SAVE_SIZE, REG_EAX};
+
+/// FNSTSW m2byte (DD /7), FNSTSW AX (DF E0)
+pub(super) fn fstsw(vm: &mut Vm, dst: Option<u32>) -> Result<(), VmError> {
+    let status = vm.fpu_status();
+    match dst {
+        Some(addr) => vm.write_u16(addr, status)?,
+        None => vm.set_reg16(REG_EAX, status),
+    }
+    Ok(())
+}
+
+/// FLDENV m28byte (D9 /4)
+pub(super) fn fldenv(vm: &mut Vm, addr: u32) -> Result<(), VmError> {
+    let image = vm.read_bytes(addr, FPU_ENV_SIZE)?;
+    vm.fpu_load_env(&image);
+    Ok(())
+}
+
+/// FNSTENV m28byte (D9 /6); all exceptions are masked afterwards.
+pub(super) fn fstenv(vm: &mut Vm, addr: u32) -> Result<(), VmError> {
+    vm.write_bytes(addr, &vm.fpu_env_image())?;
+    vm.fpu_set_control(vm.fpu_control() | 0x3F);
+    Ok(())
+}
+
+/// FRSTOR m108byte (DD /4)
+pub(super) fn frstor(vm: &mut Vm, addr: u32) -> Result<(), VmError> {
+    let image = vm.read_bytes(addr, FPU_SAVE_SIZE)?;
+    vm.fpu_load_image(&image);
+    Ok(())
+}
+
+/// FNSAVE m108byte (DD /6); the FPU is reinitialised afterwards.
+pub(super) fn fsave(vm: &mut Vm, addr: u32) -> Result<(), VmError> {
+    vm.write_bytes(addr, &vm.fpu_save_image())?;
+    vm.fpu_init();
+    Ok(())
+}
+
+/// FWAIT (9B): raise any pending unmasked FPU exception.
+pub(crate) fn fwait(vm: &mut Vm, cursor: u32, _prefixes: Prefixes) -> Result<(), VmError> {
+    vm.fpu_check_pending(cursor)?;
+    vm.set_eip(cursor + 1);
+    Ok(())
+}
@@ -1,13 +1,29 @@
-//! FLD - Load floating point value
+//! FLD/FILD - Load floating point or integer value, and the constant loads
 
-use crate::architecture::intel::x86::ins::core::ModRm;
-use crate::vm::{Vm, VmError};
+use crate::vm::{Vm, VmError, F80};
 
-use super::{mem_address, read_f64};
+use super::{read_operand, Operand};
 
-/// FLD m64real (DD /0)
-pub(super) fn fld_m64(vm: &mut Vm, modrm: &ModRm, segment: u32) -> Result<(), VmError> {
-    let addr = mem_address(vm, modrm, segment)?;
-    let value = read_f64(vm, addr)?;
-    vm.fpu_push(value)
+/// FLD m32real/m64real/m80real/ST(i), FILD m16int/m32int/m64int
+pub(super) fn fld(vm: &mut Vm, src: Operand) -> Result<(), VmError> {
+    let Some((value, flags)) = read_operand(vm, src)? else {
+        vm.fpu_stack_fault(false);
+        if vm.fpu_invalid_masked() {
+            vm.fpu_push(F80::INDEFINITE);
+        }
+        return Ok(());
+    };
+    if flags != 0 {
+        vm.fpu_raise(flags);
+        if vm.fpu_blocks_result(flags) {
+            return Ok(());
+        }
+    }
+    vm.fpu_push(value);
+    Ok(())
+}
+
+/// FLD1, FLDL2T, FLDL2E, FLDPI, FLDLG2, FLDLN2, FLDZ
+pub(super) fn fld_constant(vm: &mut Vm, value: F80) {
+    vm.fpu_push(value);
 }
@@ -0,0 +1,23 @@
+//! FST/FSTP/FIST/FISTP/FISTTP - Store ST(0)
+
+use crate::vm::{Rounding, Vm, VmError, F80};
+
+use super::{write_operand, Operand};
+
+/// Store ST(0) to `dst` rounding with `rounding`, then pop if asked to.
+pub(super) fn fst(vm: &mut Vm, dst: Operand, pop: bool, rounding: Rounding) -> Result<(), VmError> {
+    let value = match vm.fpu_get(0) {
+        Some(value) => value,
+        None => {
+            vm.fpu_stack_fault(false);
+            if !vm.fpu_invalid_masked() {
+                return Ok(());
+            }
+            F80::INDEFINITE
+        }
+    };
+    if write_operand(vm, dst, value, rounding)? && pop {
+        vm.fpu_pop();
+    }
+    Ok(())
+}
@@ -0,0 +1,186 @@
+//! FCHS/FABS/FSQRT/FRNDINT/FSCALE/FXTRACT/FPREM(1) and the transcendental functions
+
+use crate::vm::{
+    Class, Vm, EXC_INVALID, EXC_PRECISION, EXC_ZERO_DIVIDE, F80, FSW_C0, FSW_C1, FSW_C2, FSW_C3,
+};
+
+use super::{set_result, stack_underflow, MathOp};
+
+/// FSIN, FCOS, FSINCOS and FPTAN leave operands of this magnitude unreduced.
+const TRIG_LIMIT: f64 = 9_223_372_036_854_775_808.0;
+
+pub(super) fn exec(vm: &mut Vm, op: MathOp) {
+    match op {
+        MathOp::Chs | MathOp::Abs => sign(vm, op == MathOp::Chs),
+        MathOp::Sqrt => unary(vm, |vm, x| x.sqrt(vm.fpu_round_mode())),
+        MathOp::Rndint => unary(vm, |vm, x| x.round_to_integral(vm.fpu_rounding())),
+        MathOp::F2xm1 => unary(vm, |_, x| {
+            transcendental(x, |x| (x * std::f64::consts::LN_2).exp_m1())
+        }),
+        MathOp::Scale => binary(vm, false, |vm, x, y| x.scale(y, vm.fpu_round_mode())),
+        MathOp::Xtract => xtract(vm),
+        MathOp::Prem | MathOp::Prem1 => prem(vm, op == MathOp::Prem1),
+        MathOp::Sin | MathOp::Cos | MathOp::Sincos | MathOp::Ptan => trig(vm, op),
+        MathOp::Patan => binary(vm, true, |_, x, y| match x.nan_operands(y) {
+            Some(nan) => nan,
+            None => inexact(F80::from_f64(y.as_f64().atan2(x.as_f64())), !y.is_zero()),
+        }),
+        MathOp::Yl2x => binary(vm, true, |_, x, y| yl2x(x, y)),
+        MathOp::Yl2xp1 => binary(vm, true, |_, x, y| {
+            if let Some(nan) = x.nan_operands(y) {
+                return nan;
+            }
+            let value = y.as_f64() * x.as_f64().ln_1p() / std::f64::consts::LN_2;
+            inexact(F80::from_f64(value), !x.is_zero())
+        }),
+    }
+}
+
+/// A result computed in `f64`, which is almost never exact.
+fn inexact(value: F80, rounded: bool) -> (F80, u16) {
+    (value, if rounded { EXC_PRECISION } else { 0 })
+}
+
+fn transcendental(x: F80, f: impl Fn(f64) -> f64) -> (F80, u16) {
+    if let Some(nan) = x.nan_operands(x) {
+        return nan;
+    }
+    let value = f(x.as_f64());
+    if value.is_nan() {
+        return (F80::INDEFINITE, EXC_INVALID);
+    }
+    inexact(F80::from_f64(value), !x.is_zero())
+}
+
+/// FCHS/FABS only touch the sign; C1 is cleared.
+fn sign(vm: &mut Vm, negate: bool) {
+    let Some(value) = vm.fpu_get(0) else {
+        stack_underflow(vm, Some(0));
+        return;
+    };
+    vm.fpu_set(0, if negate { value.negate() } else { value.abs() });
+    vm.fpu_raise(0);
+}
+
+/// ST(0) = f(ST(0))
+fn unary(vm: &mut Vm, f: impl Fn(&Vm, F80) -> (F80, u16)) {
+    let Some(value) = vm.fpu_get(0) else {
+        stack_underflow(vm, Some(0));
+        return;
+    };
+    let (value, flags) = f(vm, value);
+    set_result(vm, 0, value, flags);
+}
+
+/// f(ST(0), ST(1)), stored in ST(0), or in ST(1) followed by a pop.
+fn binary(vm: &mut Vm, into_st1: bool, f: impl Fn(&Vm, F80, F80) -> (F80, u16)) {
+    let dst = if into_st1 { 1 } else { 0 };
+    let (Some(x), Some(y)) = (vm.fpu_get(0), vm.fpu_get(1)) else {
+        if stack_underflow(vm, Some(dst)) && into_st1 {
+            vm.fpu_pop();
+        }
+        return;
+    };
+    let (value, flags) = f(vm, x, y);
+    if set_result(vm, dst, value, flags) && into_st1 {
+        vm.fpu_pop();
+    }
+}
+
+/// ST(1) * log2(ST(0))
+fn yl2x(x: F80, y: F80) -> (F80, u16) {
+    if let Some(nan) = x.nan_operands(y) {
+        return nan;
+    }
+    if x.is_negative() && !x.is_zero() {
+        return (F80::INDEFINITE, EXC_INVALID);
+    }
+    if x.is_zero() {
+        if y.is_zero() {
+            return (F80::INDEFINITE, EXC_INVALID);
+        }
+        let infinity = F80::from_f64(f64::NEG_INFINITY);
+        let value = if y.is_negative() {
+            infinity.negate()
+        } else {
+            infinity
+        };
+        return (value, EXC_ZERO_DIVIDE);
+    }
+    let value = y.as_f64() * x.as_f64().log2();
+    if value.is_nan() {
+        return (F80::INDEFINITE, EXC_INVALID);
+    }
+    inexact(F80::from_f64(value), true)
+}
+
+/// FXTRACT: ST(0) becomes the exponent and the significand is pushed.
+fn xtract(vm: &mut Vm) {
+    let Some(value) = vm.fpu_get(0) else {
+        if stack_underflow(vm, Some(0)) {
+            vm.fpu_push(F80::INDEFINITE);
+        }
+        return;
+    };
+    let (exponent, significand, flags) = value.extract();
+    if set_result(vm, 0, exponent, flags) {
+        vm.fpu_push(significand);
+    }
+}
+
+/// FPREM/FPREM1: C2 set means the reduction is incomplete; otherwise C0, C3 and
+/// C1 hold the low three quotient bits.
+fn prem(vm: &mut Vm, nearest: bool) {
+    let (Some(x), Some(y)) = (vm.fpu_get(0), vm.fpu_get(1)) else {
+        stack_underflow(vm, Some(0));
+        return;
+    };
+    let (value, quotient, complete, flags) = x.remainder(y, nearest);
+    if !set_result(vm, 0, value, flags) {
+        return;
+    }
+    let bits = if complete {
+        let bit = |mask: u8, flag: u16| if quotient & mask != 0 { flag } else { 0 };
+        bit(4, FSW_C0) | bit(2, FSW_C3) | bit(1, FSW_C1)
+    } else {
+        FSW_C2
+    };
+    vm.fpu_set_condition(bits);
+}
+
+/// FSIN, FCOS, FSINCOS and FPTAN. Operands outside ±2^63 set C2 and are left alone.
+fn trig(vm: &mut Vm, op: MathOp) {
+    let Some(value) = vm.fpu_get(0) else {
+        if stack_underflow(vm, Some(0)) && matches!(op, MathOp::Sincos | MathOp::Ptan) {
+            vm.fpu_push(F80::INDEFINITE);
+        }
+        return;
+    };
+    let x = value.as_f64();
+    if value.class() != Class::Infinity && !value.is_nan() && x.abs() >= TRIG_LIMIT {
+        vm.fpu_set_condition(FSW_C2);
+        return;
+    }
+    let primary = match op {
+        MathOp::Cos => transcendental(value, f64::cos),
+        MathOp::Ptan => transcendental(value, f64::tan),
+        _ => transcendental(value, f64::sin),
+    };
+    let (result, flags) = primary;
+    if !set_result(vm, 0, result, flags) {
+        return;
+    }
+    match op {
+        MathOp::Sincos => {
+            let (cos, cos_flags) = transcendental(value, f64::cos);
+            vm.fpu_push(cos);
+            vm.fpu_raise(flags | cos_flags);
+        }
+        // FPTAN pushes 1.0 so that ST(0)/ST(1) is the tangent.
+        MathOp::Ptan if !result.is_nan() => vm.fpu_push(F80::ONE),
+        MathOp::Ptan => vm.fpu_push(result),
+        _ => {}
+    }
+    let status = vm.fpu_status();
+    vm.fpu_set_condition(status & (FSW_C0 | FSW_C1 | FSW_C3));
+}
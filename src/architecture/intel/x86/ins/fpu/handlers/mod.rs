@@ -1,111 +1,555 @@
 //! FPU instruction handlers
 
-mod fadd;
-mod fild;
+mod arith;
+mod bcd;
+mod compare;
+mod env;
 mod fld;
 mod fldcw;
-mod fmul;
+mod fst;
 mod fstcw;
-mod fstp;
+mod math;
+mod stack;
 
-use crate::vm::{Vm, VmError};
+use crate::vm::{
+    Rounding, Vm, VmError, EXC_DENORMAL, EXC_INVALID, EXC_OVERFLOW, EXC_UNDERFLOW, EXC_ZERO_DIVIDE,
+    F80,
+};
 
 use crate::architecture::intel::x86::ins::core::{calc_ea, decode_modrm, ModRm, Prefixes};
 
-/// FPU instructions
+pub(crate) use env::fwait;
+
+/// An x87 operand: a stack register or a memory location of the given format.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum Operand {
+    St(u8),
+    Real32(u32),
+    Real64(u32),
+    Real80(u32),
+    Int16(u32),
+    Int32(u32),
+    Int64(u32),
+}
+
+/// The six two-operand arithmetic instructions, selected by the ModRM reg field.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum ArithOp {
+    Add,
+    Mul,
+    Sub,
+    SubR,
+    Div,
+    DivR,
+}
+
+impl ArithOp {
+    fn from_reg(reg: u8) -> Option<Self> {
+        match reg {
+            0 => Some(Self::Add),
+            1 => Some(Self::Mul),
+            4 => Some(Self::Sub),
+            5 => Some(Self::SubR),
+            6 => Some(Self::Div),
+            7 => Some(Self::DivR),
+            _ => None,
+        }
+    }
+
+    /// DC and DE register forms encode the reversed subtraction and division.
+    fn reversed(self) -> Self {
+        match self {
+            Self::Sub => Self::SubR,
+            Self::SubR => Self::Sub,
+            Self::Div => Self::DivR,
+            Self::DivR => Self::Div,
+            other => other,
+        }
+    }
+}
+
+/// One-operand and ST(0)/ST(1) instructions from the D9 register space.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum MathOp {
+    Chs,
+    Abs,
+    Sqrt,
+    Rndint,
+    Scale,
+    Xtract,
+    Prem,
+    Prem1,
+    Sin,
+    Cos,
+    Sincos,
+    Ptan,
+    Patan,
+    F2xm1,
+    Yl2x,
+    Yl2xp1,
+}
+
+/// FPU instructions
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub(super) enum FpuInstruction {
-    /// FADD m64real (DC /0)
-    Fadd,
-    /// FADDP ST(i), ST(0) (DE C0+i)
-    Faddp,
-    /// FMUL m64real (DC /1)
-    Fmul,
-    /// FMULP ST(i), ST(0) (DE C8+i)
-    Fmulp,
-    /// FLD m64real (DD /0)
-    Fld,
-    /// FSTP m64real (DD /3)
-    Fstp,
-    /// FILD m32int (DB /0)
-    Fild,
+    /// FADD/FMUL/FSUB(R)/FDIV(R) ST(0), src and FIADD etc. (D8, DA, DC, DE memory; D8 register)
+    Arith {
+        op: ArithOp,
+        src: Operand,
+    },
+    /// FADD(P)/FMUL(P)/FSUB(R)(P)/FDIV(R)(P) ST(i), ST(0) (DC, DE register)
+    ArithTo {
+        op: ArithOp,
+        dst: u8,
+        pop: bool,
+    },
+    /// FCOM(P)(P)/FUCOM(P)(P)/FICOM(P)
+    Compare {
+        src: Operand,
+        pops: u8,
+        unordered: bool,
+    },
+    /// FCOMI(P)/FUCOMI(P) (DB, DF F0/E8+i)
+    CompareEflags {
+        src: u8,
+        pop: bool,
+        unordered: bool,
+    },
+    /// FTST (D9 E4)
+    Test,
+    /// FXAM (D9 E5)
+    Examine,
+    /// FLD/FILD
+    Load(Operand),
+    /// FLD1, FLDZ, FLDPI and the other constants (D9 E8..EE)
+    LoadConstant(F80),
+    /// FBLD m80bcd (DF /4)
+    LoadBcd(u32),
+    /// FST(P)/FIST(P)
+    Store {
+        dst: Operand,
+        pop: bool,
+    },
+    /// FISTTP (DB /1, DD /1, DF /1)
+    StoreTruncated(Operand),
+    /// FBSTP m80bcd (DF /6)
+    StoreBcd(u32),
+    /// FXCH ST(i) (D9 C8+i)
+    Exchange(u8),
+    /// FCMOVcc ST(0), ST(i) (DA, DB C0..DF)
+    CondMove {
+        cond: u8,
+        src: u8,
+    },
+    /// FFREE ST(i) (DD C0+i), FFREEP (DF C0+i)
+    Free {
+        reg: u8,
+        pop: bool,
+    },
+    /// FINCSTP/FDECSTP (D9 F7/F6)
+    Rotate {
+        up: bool,
+    },
+    Math(MathOp),
     /// FLDCW m2byte (D9 /5)
-    Fldcw,
-    /// FSTCW m2byte (D9 /7)
-    Fstcw,
+    Fldcw(u32),
+    /// FNSTCW m2byte (D9 /7)
+    Fstcw(u32),
+    /// FNSTSW m2byte (DD /7) or AX (DF E0)
+    Fstsw(Option<u32>),
+    /// FLDENV (D9 /4)
+    LoadEnv(u32),
+    /// FNSTENV (D9 /6)
+    StoreEnv(u32),
+    /// FRSTOR (DD /4)
+    Restore(u32),
+    /// FNSAVE (DD /6)
+    Save(u32),
+    /// FNCLEX (DB E2)
+    Clex,
+    /// FNINIT (DB E3)
+    Init,
+    /// FNOP, and FENI/FDISI/FSETPM which are no-ops on a 387 and later
+    Nop,
 }
 
 impl FpuInstruction {
-    /// Decode FPU instruction from opcode and modrm
-    fn decode(opcode: u8, modrm: &ModRm) -> Option<Self> {
-        match opcode {
-            0xD9 => match modrm.reg {
-                5 => Some(Self::Fldcw),
-                7 => Some(Self::Fstcw),
-                _ => None,
+    /// Decode FPU instruction from opcode and modrm; `addr` is the memory operand.
+    fn decode(opcode: u8, modrm: &ModRm, addr: u32) -> Option<Self> {
+        if modrm.mod_bits != 3 {
+            return Self::decode_memory(opcode, modrm.reg, addr);
+        }
+        let (reg, i) = (modrm.reg, modrm.rm);
+        let st = Operand::St(i);
+        let instruction = match (opcode, reg) {
+            (0xD8, 2) | (0xDC, 2) => Self::compare(st, 0, false),
+            (0xD8, 3) | (0xDC, 3) | (0xDE, 2) => Self::compare(st, 1, false),
+            (0xD8, _) => Self::Arith {
+                op: ArithOp::from_reg(reg)?,
+                src: st,
+            },
+            (0xD9, 0) => Self::Load(st),
+            (0xD9, 1) | (0xDD, 1) | (0xDF, 1) => Self::Exchange(i),
+            (0xD9, 2) if i == 0 => Self::Nop,
+            (0xD9, 3) | (0xDD, 3) | (0xDF, 2) | (0xDF, 3) => Self::Store { dst: st, pop: true },
+            (0xD9, 4) => match i {
+                0 => Self::Math(MathOp::Chs),
+                1 => Self::Math(MathOp::Abs),
+                4 => Self::Test,
+                5 => Self::Examine,
+                _ => return None,
             },
-            0xDB => match modrm.reg {
-                0 => Some(Self::Fild),
-                _ => None,
+            (0xD9, 5) => Self::LoadConstant(match i {
+                0 => F80::ONE,
+                1 => F80::LOG2_10,
+                2 => F80::LOG2_E,
+                3 => F80::PI,
+                4 => F80::LOG10_2,
+                5 => F80::LN_2,
+                6 => F80::ZERO,
+                _ => return None,
+            }),
+            (0xD9, 6) => match i {
+                0 => Self::Math(MathOp::F2xm1),
+                1 => Self::Math(MathOp::Yl2x),
+                2 => Self::Math(MathOp::Ptan),
+                3 => Self::Math(MathOp::Patan),
+                4 => Self::Math(MathOp::Xtract),
+                5 => Self::Math(MathOp::Prem1),
+                6 => Self::Rotate { up: false },
+                _ => Self::Rotate { up: true },
             },
-            0xDC => match modrm.reg {
-                0 => Some(Self::Fadd),
-                1 => Some(Self::Fmul),
-                _ => None,
+            (0xD9, 7) => Self::Math(match i {
+                0 => MathOp::Prem,
+                1 => MathOp::Yl2xp1,
+                2 => MathOp::Sqrt,
+                3 => MathOp::Sincos,
+                4 => MathOp::Rndint,
+                5 => MathOp::Scale,
+                6 => MathOp::Sin,
+                _ => MathOp::Cos,
+            }),
+            (0xDA, 0..=3) => Self::CondMove { cond: reg, src: i },
+            (0xDA, 5) if i == 1 => Self::compare(Operand::St(1), 2, true),
+            (0xDB, 0..=3) => Self::CondMove {
+                cond: reg + 4,
+                src: i,
             },
-            0xDD => match modrm.reg {
-                0 => Some(Self::Fld),
-                3 => Some(Self::Fstp),
-                _ => None,
+            (0xDB, 4) => match i {
+                0 | 1 | 4 => Self::Nop,
+                2 => Self::Clex,
+                3 => Self::Init,
+                _ => return None,
             },
-            0xDE if modrm.mod_bits == 3 => match modrm.reg {
-                0 => Some(Self::Faddp),
-                1 => Some(Self::Fmulp),
-                _ => None,
+            (0xDB, 5) | (0xDB, 6) => Self::CompareEflags {
+                src: i,
+                pop: false,
+                unordered: reg == 5,
             },
+            (0xDC, _) => Self::ArithTo {
+                op: ArithOp::from_reg(reg)?.reversed(),
+                dst: i,
+                pop: false,
+            },
+            (0xDD, 0) => Self::Free { reg: i, pop: false },
+            (0xDD, 2) => Self::Store {
+                dst: st,
+                pop: false,
+            },
+            (0xDD, 4) => Self::compare(st, 0, true),
+            (0xDD, 5) => Self::compare(st, 1, true),
+            (0xDE, 3) if i == 1 => Self::compare(st, 2, false),
+            (0xDE, _) => Self::ArithTo {
+                op: ArithOp::from_reg(reg)?.reversed(),
+                dst: i,
+                pop: true,
+            },
+            (0xDF, 0) => Self::Free { reg: i, pop: true },
+            (0xDF, 4) if i == 0 => Self::Fstsw(None),
+            (0xDF, 5) | (0xDF, 6) => Self::CompareEflags {
+                src: i,
+                pop: true,
+                unordered: reg == 5,
+            },
+            _ => return None,
+        };
+        Some(instruction)
+    }
+
+    fn decode_memory(opcode: u8, reg: u8, addr: u32) -> Option<Self> {
+        // The integer and real formats of the arithmetic group.
+        let arith_operand = match opcode {
+            0xD8 => Some(Operand::Real32(addr)),
+            0xDA => Some(Operand::Int32(addr)),
+            0xDC => Some(Operand::Real64(addr)),
+            0xDE => Some(Operand::Int16(addr)),
             _ => None,
+        };
+        if let Some(src) = arith_operand {
+            return Some(match reg {
+                2 => Self::compare(src, 0, false),
+                3 => Self::compare(src, 1, false),
+                _ => Self::Arith {
+                    op: ArithOp::from_reg(reg)?,
+                    src,
+                },
+            });
         }
+        let instruction = match (opcode, reg) {
+            (0xD9, 0) => Self::Load(Operand::Real32(addr)),
+            (0xD9, 2) => Self::store(Operand::Real32(addr), false),
+            (0xD9, 3) => Self::store(Operand::Real32(addr), true),
+            (0xD9, 4) => Self::LoadEnv(addr),
+            (0xD9, 5) => Self::Fldcw(addr),
+            (0xD9, 6) => Self::StoreEnv(addr),
+            (0xD9, 7) => Self::Fstcw(addr),
+            (0xDB, 0) => Self::Load(Operand::Int32(addr)),
+            (0xDB, 1) => Self::StoreTruncated(Operand::Int32(addr)),
+            (0xDB, 2) => Self::store(Operand::Int32(addr), false),
+            (0xDB, 3) => Self::store(Operand::Int32(addr), true),
+            (0xDB, 5) => Self::Load(Operand::Real80(addr)),
+            (0xDB, 7) => Self::store(Operand::Real80(addr), true),
+            (0xDD, 0) => Self::Load(Operand::Real64(addr)),
+            (0xDD, 1) => Self::StoreTruncated(Operand::Int64(addr)),
+            (0xDD, 2) => Self::store(Operand::Real64(addr), false),
+            (0xDD, 3) => Self::store(Operand::Real64(addr), true),
+            (0xDD, 4) => Self::Restore(addr),
+            (0xDD, 6) => Self::Save(addr),
+            (0xDD, 7) => Self::Fstsw(Some(addr)),
+            (0xDF, 0) => Self::Load(Operand::Int16(addr)),
+            (0xDF, 1) => Self::StoreTruncated(Operand::Int16(addr)),
+            (0xDF, 2) => Self::store(Operand::Int16(addr), false),
+            (0xDF, 3) => Self::store(Operand::Int16(addr), true),
+            (0xDF, 4) => Self::LoadBcd(addr),
+            (0xDF, 5) => Self::Load(Operand::Int64(addr)),
+            (0xDF, 6) => Self::StoreBcd(addr),
+            (0xDF, 7) => Self::store(Operand::Int64(addr), true),
+            _ => return None,
+        };
+        Some(instruction)
     }
+
+    fn compare(src: Operand, pops: u8, unordered: bool) -> Self {
+        Self::Compare {
+            src,
+            pops,
+            unordered,
+        }
+    }
+
+    fn store(dst: Operand, pop: bool) -> Self {
+        Self::Store { dst, pop }
+    }
+
+    /// The FN- forms skip the check for pending exceptions.
+    fn waits(&self) -> bool {
+        !matches!(
+            self,
+            Self::Fstcw(_)
+                | Self::Fstsw(_)
+                | Self::StoreEnv(_)
+                | Self::Save(_)
+                | Self::Clex
+                | Self::Init
+        )
+    }
+
+    /// Control instructions leave the last-instruction pointers alone.
+    fn is_control(&self) -> bool {
+        matches!(
+            self,
+            Self::Fldcw(_)
+                | Self::Fstcw(_)
+                | Self::Fstsw(_)
+                | Self::LoadEnv(_)
+                | Self::StoreEnv(_)
+                | Self::Restore(_)
+                | Self::Save(_)
+                | Self::Clex
+                | Self::Init
+        )
+    }
+}
+
+fn read_ten(vm: &Vm, addr: u32) -> Result<[u8; 10], VmError> {
+    let bytes = vm.read_bytes(addr, 10)?;
+    Ok(bytes.try_into().expect("10 bytes"))
+}
+
+/// Read a source operand with the exceptions loading it raised; `None` when it is
+/// an empty register.
+pub(super) fn read_operand(vm: &Vm, operand: Operand) -> Result<Option<(F80, u16)>, VmError> {
+    let value = match operand {
+        Operand::St(index) => return Ok(vm.fpu_get(index as usize).map(|value| (value, 0))),
+        Operand::Real32(addr) => {
+            let bits = vm.read_u32(addr)?;
+            let denormal = bits & 0x7F80_0000 == 0 && bits & 0x007F_FFFF != 0;
+            let (value, flags) = F80::from_f32(f32::from_bits(bits)).quiet_signaling();
+            (value, flags | if denormal { EXC_DENORMAL } else { 0 })
+        }
+        Operand::Real64(addr) => {
+            let bits = vm.read_u64(addr)?;
+            let denormal = bits & 0x7FF0_0000_0000_0000 == 0 && bits & 0x000F_FFFF_FFFF_FFFF != 0;
+            let (value, flags) = F80::from_f64(f64::from_bits(bits)).quiet_signaling();
+            (value, flags | if denormal { EXC_DENORMAL } else { 0 })
+        }
+        Operand::Real80(addr) => (F80::from_bytes(read_ten(vm, addr)?), 0),
+        Operand::Int16(addr) => (F80::from_i64(vm.read_u16(addr)? as i16 as i64), 0),
+        Operand::Int32(addr) => (F80::from_i64(vm.read_u32(addr)? as i32 as i64), 0),
+        Operand::Int64(addr) => (F80::from_i64(vm.read_u64(addr)? as i64), 0),
+    };
+    Ok(Some(value))
 }
 
-pub(super) fn read_f64(vm: &Vm, addr: u32) -> Result<f64, VmError> {
-    let bits = vm.read_u64(addr)?;
-    Ok(f64::from_bits(bits))
+/// Write ST(0) (or the indefinite value) to `dst`, converting to its format.
+/// Returns `false` when an unmasked exception suppressed the store.
+pub(super) fn write_operand(
+    vm: &mut Vm,
+    dst: Operand,
+    value: F80,
+    rounding: Rounding,
+) -> Result<bool, VmError> {
+    let flags = match dst {
+        Operand::St(index) => {
+            vm.fpu_set(index as usize, value);
+            return Ok(true);
+        }
+        Operand::Real80(addr) => {
+            vm.write_bytes(addr, &value.to_bytes())?;
+            return Ok(true);
+        }
+        Operand::Real32(addr) => {
+            let (result, flags) = value.to_f32(rounding);
+            if store_blocked(vm, flags) {
+                vm.fpu_raise(flags);
+                return Ok(false);
+            }
+            vm.write_u32(addr, result.to_bits())?;
+            flags
+        }
+        Operand::Real64(addr) => {
+            let (result, flags) = value.to_f64(rounding);
+            if store_blocked(vm, flags) {
+                vm.fpu_raise(flags);
+                return Ok(false);
+            }
+            vm.write_u64(addr, result.to_bits())?;
+            flags
+        }
+        Operand::Int16(addr) | Operand::Int32(addr) | Operand::Int64(addr) => {
+            let bits = match dst {
+                Operand::Int16(_) => 16,
+                Operand::Int32(_) => 32,
+                _ => 64,
+            };
+            let (result, flags) = value.to_int(bits, rounding);
+            if store_blocked(vm, flags) {
+                vm.fpu_raise(flags);
+                return Ok(false);
+            }
+            // Out of range: the integer indefinite, the most negative value.
+            let result = result.unwrap_or(i64::MIN >> (64 - bits));
+            match bits {
+                16 => vm.write_u16(addr, result as u16)?,
+                32 => vm.write_u32(addr, result as u32)?,
+                _ => vm.write_u64(addr, result as u64)?,
+            }
+            flags
+        }
+    };
+    if flags != 0 {
+        vm.fpu_raise(flags);
+    }
+    Ok(true)
 }
 
-pub(super) fn write_f64(vm: &mut Vm, addr: u32, value: f64) -> Result<(), VmError> {
-    vm.write_u64(addr, value.to_bits())
+/// Memory destinations are left unchanged on any unmasked exception but precision.
+fn store_blocked(vm: &Vm, flags: u16) -> bool {
+    let numeric = EXC_INVALID | EXC_DENORMAL | EXC_ZERO_DIVIDE | EXC_OVERFLOW | EXC_UNDERFLOW;
+    flags & numeric & !vm.fpu_control() != 0
 }
 
-pub(super) fn read_i32(vm: &Vm, addr: u32) -> Result<i32, VmError> {
-    Ok(vm.read_u32(addr)? as i32)
+/// Store an arithmetic result in ST(`index`) unless an unmasked exception blocks it.
+pub(super) fn set_result(vm: &mut Vm, index: usize, value: F80, flags: u16) -> bool {
+    let stored = !vm.fpu_blocks_result(flags);
+    if stored {
+        vm.fpu_set(index, value);
+    }
+    vm.fpu_raise(flags);
+    stored
 }
 
-pub(super) fn mem_address(vm: &Vm, modrm: &ModRm, segment: u32) -> Result<u32, VmError> {
-    if modrm.mod_bits == 3 {
-        return Err(VmError::UnsupportedInstruction(0));
+/// An empty source register. Masked, `dst` receives the indefinite value and the
+/// instruction completes; the return value says whether it did.
+pub(super) fn stack_underflow(vm: &mut Vm, dst: Option<usize>) -> bool {
+    vm.fpu_stack_fault(false);
+    if !vm.fpu_invalid_masked() {
+        return false;
+    }
+    if let Some(index) = dst {
+        vm.fpu_set(index, F80::INDEFINITE);
     }
-    calc_ea(vm, modrm, segment)
+    true
 }
 
 pub(crate) fn exec(vm: &mut Vm, cursor: u32, prefixes: Prefixes) -> Result<(), VmError> {
     let opcode = vm.read_u8(cursor)?;
-    let modrm = decode_modrm(vm, cursor + 1)?;
+    let modrm_byte = vm.read_u8(cursor + 1)?;
+    let modrm = decode_modrm(vm, cursor + 1, prefixes)?;
     let next = cursor + 1 + modrm.len as u32;
+    let addr = if modrm.mod_bits == 3 {
+        0
+    } else {
+        calc_ea(vm, &modrm, prefixes.segment_base)?
+    };
 
-    let instruction = FpuInstruction::decode(opcode, &modrm)
+    let instruction = FpuInstruction::decode(opcode, &modrm, addr)
         .ok_or(VmError::UnsupportedInstruction(opcode))?;
 
+    if instruction.waits() {
+        vm.fpu_check_pending(cursor)?;
+    }
+    if !instruction.is_control() {
+        let fpu_opcode = (((opcode & 7) as u16) << 8) | modrm_byte as u16;
+        vm.fpu_note_instruction(cursor, fpu_opcode, addr);
+    }
+
     match instruction {
-        FpuInstruction::Fadd => fadd::fadd_m64(vm, &modrm, prefixes.segment_base)?,
-        FpuInstruction::Faddp => fadd::faddp(vm, &modrm)?,
-        FpuInstruction::Fmul => fmul::fmul_m64(vm, &modrm, prefixes.segment_base)?,
-        FpuInstruction::Fmulp => fmul::fmulp(vm, &modrm)?,
-        FpuInstruction::Fld => fld::fld_m64(vm, &modrm, prefixes.segment_base)?,
-        FpuInstruction::Fstp => fstp::fstp_m64(vm, &modrm, prefixes.segment_base)?,
-        FpuInstruction::Fild => fild::fild_m32(vm, &modrm, prefixes.segment_base)?,
-        FpuInstruction::Fldcw => fldcw::fldcw(vm, &modrm, prefixes.segment_base)?,
-        FpuInstruction::Fstcw => fstcw::fstcw(vm, &modrm, prefixes.segment_base)?,
+        FpuInstruction::Arith { op, src } => arith::arith(vm, op, src)?,
+        FpuInstruction::ArithTo { op, dst, pop } => arith::arith_to(vm, op, dst, pop),
+        FpuInstruction::Compare {
+            src,
+            pops,
+            unordered,
+        } => compare::compare(vm, src, pops, unordered)?,
+        FpuInstruction::CompareEflags {
+            src,
+            pop,
+            unordered,
+        } => compare::compare_eflags(vm, src, pop, unordered),
+        FpuInstruction::Test => compare::test(vm),
+        FpuInstruction::Examine => compare::examine(vm),
+        FpuInstruction::Load(src) => fld::fld(vm, src)?,
+        FpuInstruction::LoadConstant(value) => fld::fld_constant(vm, value),
+        FpuInstruction::LoadBcd(addr) => bcd::fbld(vm, addr)?,
+        FpuInstruction::Store { dst, pop } => fst::fst(vm, dst, pop, vm.fpu_rounding())?,
+        FpuInstruction::StoreTruncated(dst) => fst::fst(vm, dst, true, Rounding::Zero)?,
+        FpuInstruction::StoreBcd(addr) => bcd::fbstp(vm, addr)?,
+        FpuInstruction::Exchange(index) => stack::fxch(vm, index),
+        FpuInstruction::CondMove { cond, src } => stack::fcmov(vm, cond, src),
+        FpuInstruction::Free { reg, pop } => stack::ffree(vm, reg, pop),
+        FpuInstruction::Rotate { up } => stack::rotate(vm, up),
+        FpuInstruction::Math(op) => math::exec(vm, op),
+        FpuInstruction::Fldcw(addr) => fldcw::fldcw(vm, addr)?,
+        FpuInstruction::Fstcw(addr) => fstcw::fstcw(vm, addr)?,
+        FpuInstruction::Fstsw(dst) => env::fstsw(vm, dst)?,
+        FpuInstruction::LoadEnv(addr) => env::fldenv(vm, addr)?,
+        FpuInstruction::StoreEnv(addr) => env::fstenv(vm, addr)?,
+        FpuInstruction::Restore(addr) => env::frstor(vm, addr)?,
+        FpuInstruction::Save(addr) => env::fsave(vm, addr)?,
+        FpuInstruction::Clex => vm.fpu_clear_exceptions(),
+        FpuInstruction::Init => vm.fpu_init(),
+        FpuInstruction::Nop => {}
     }
 
     vm.set_eip(next);
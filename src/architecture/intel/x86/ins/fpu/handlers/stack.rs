@@ -0,0 +1,65 @@
+//! FXCH, FCMOVcc, FFREE(P), FINCSTP and FDECSTP
+
+use crate::vm::{Vm, F80, FSW_C1};
+
+use super::stack_underflow;
+
+/// FXCH ST(i) (D9 C8+i)
+pub(super) fn fxch(vm: &mut Vm, index: u8) {
+    let index = index as usize;
+    match (vm.fpu_get(0), vm.fpu_get(index)) {
+        (Some(top), Some(other)) => {
+            vm.fpu_set(0, other);
+            vm.fpu_set(index, top);
+            clear_c1(vm);
+        }
+        (top, other) => {
+            vm.fpu_stack_fault(false);
+            if vm.fpu_invalid_masked() {
+                let indefinite = F80::INDEFINITE;
+                vm.fpu_set(0, other.unwrap_or(indefinite));
+                vm.fpu_set(index, top.unwrap_or(indefinite));
+            }
+        }
+    }
+}
+
+/// FCMOVB/E/BE/U and their negations (`cond` 4..7).
+pub(super) fn fcmov(vm: &mut Vm, cond: u8, src: u8) {
+    let taken = match cond & 3 {
+        0 => vm.cf(),
+        1 => vm.zf(),
+        2 => vm.cf() || vm.zf(),
+        _ => vm.pf(),
+    } != (cond >= 4);
+    match vm.fpu_get(src as usize) {
+        Some(value) => {
+            if taken {
+                vm.fpu_set(0, value);
+            }
+            clear_c1(vm);
+        }
+        None => {
+            stack_underflow(vm, Some(0));
+        }
+    }
+}
+
+/// FFREE ST(i), and FFREEP which also pops.
+pub(super) fn ffree(vm: &mut Vm, reg: u8, pop: bool) {
+    vm.fpu_free(reg as usize);
+    if pop {
+        vm.fpu_pop();
+    }
+}
+
+/// FINCSTP/FDECSTP: move TOP without touching the tags.
+pub(super) fn rotate(vm: &mut Vm, up: bool) {
+    vm.fpu_rotate(up);
+    clear_c1(vm);
+}
+
+fn clear_c1(vm: &mut Vm) {
+    let status = vm.fpu_status();
+    vm.fpu_set_status(status & !FSW_C1);
+}
@@ -1,5 +1,5 @@
-//! Minimal x87 opcode support
+//! x87 FPU opcode support
 
 mod handlers;
 
-pub(crate) use handlers::exec;
+pub(crate) use handlers::{exec, fwait};
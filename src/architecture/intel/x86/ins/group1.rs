@@ -3,12 +3,12 @@
 use crate::vm::{Vm, VmError};
 
 use super::add;
-use super::core::{decode_modrm, Prefixes};
+use super::core::{decode_modrm, read_immv, Prefixes};
 use super::logic;
 use super::sub;
 
 pub(crate) fn exec_group1_8(vm: &mut Vm, cursor: u32, prefixes: Prefixes) -> Result<(), VmError> {
-    let modrm = decode_modrm(vm, cursor + 1)?;
+    let modrm = decode_modrm(vm, cursor + 1, prefixes)?;
     let imm = vm.read_u8(cursor + 1 + modrm.len as u32)?;
     match modrm.reg {
         0 => add::add_rm8_imm(vm, &modrm, prefixes, imm)?,
@@ -26,13 +26,14 @@ pub(crate) fn exec_group1_8(vm: &mut Vm, cursor: u32, prefixes: Prefixes) -> Res
 }
 
 pub(crate) fn exec_group1_32(vm: &mut Vm, cursor: u32, prefixes: Prefixes) -> Result<(), VmError> {
-    let modrm = decode_modrm(vm, cursor + 1)?;
+    let modrm = decode_modrm(vm, cursor + 1, prefixes)?;
     let opcode = vm.read_u8(cursor)?;
-    let imm_size = if opcode == 0x83 { 1 } else { 4 };
-    let imm = if imm_size == 1 {
-        vm.read_u8(cursor + 1 + modrm.len as u32)? as i8 as i32 as u32
+    let imm_addr = cursor + 1 + modrm.len as u32;
+    // 83 sign-extends imm8; 81 takes an imm16 under the operand-size prefix.
+    let (imm, imm_size) = if opcode == 0x83 {
+        (vm.read_u8(imm_addr)? as i8 as i32 as u32, 1)
     } else {
-        vm.read_u32(cursor + 1 + modrm.len as u32)?
+        read_immv(vm, imm_addr, prefixes)?
     };
     match modrm.reg {
         0 => add::add_rm32_imm(vm, &modrm, prefixes, imm)?,
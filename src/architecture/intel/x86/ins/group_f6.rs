@@ -1,14 +1,15 @@
 //! x86 group F6 instruction handlers.
 
-use crate::vm::{Vm, VmError};
+use crate::vm::{Vm, VmError, REG_AL, REG_EAX};
 
 use super::core::{decode_modrm, read_rm8, update_flags_logic8, Prefixes};
 use super::{logic, sub};
 
 pub(crate) fn exec(vm: &mut Vm, cursor: u32, prefixes: Prefixes) -> Result<(), VmError> {
-    let modrm = decode_modrm(vm, cursor + 1)?;
+    let modrm = decode_modrm(vm, cursor + 1, prefixes)?;
     match modrm.reg {
-        0 => {
+        // /1 is an undocumented alias of TEST.
+        0 | 1 => {
             let imm = vm.read_u8(cursor + 1 + modrm.len as u32)?;
             let lhs = read_rm8(vm, &modrm, prefixes.segment_base)?;
             let result = lhs & imm;
@@ -23,7 +24,57 @@ pub(crate) fn exec(vm: &mut Vm, cursor: u32, prefixes: Prefixes) -> Result<(), V
             sub::neg_rm8(vm, &modrm, prefixes)?;
             vm.set_eip(cursor + 1 + modrm.len as u32);
         }
+        4..=7 => {
+            let value = read_rm8(vm, &modrm, prefixes.segment_base)?;
+            mul_div8(vm, modrm.reg, value)?;
+            vm.set_eip(cursor + 1 + modrm.len as u32);
+        }
         _ => return Err(VmError::UnsupportedInstruction(0xF6)),
     }
     Ok(())
 }
+
+// MUL, IMUL, DIV and IDIV with AL/AX as the implicit operand.
+fn mul_div8(vm: &mut Vm, reg: u8, value: u8) -> Result<(), VmError> {
+    match reg {
+        4 => {
+            let result = vm.reg8(REG_AL) as u16 * value as u16;
+            vm.set_reg16(REG_EAX, result);
+            let overflow = result > 0xFF;
+            vm.set_result_flags(8, result as u32, overflow, overflow);
+        }
+        5 => {
+            let result = vm.reg8(REG_AL) as i8 as i16 * value as i8 as i16;
+            vm.set_reg16(REG_EAX, result as u16);
+            let overflow = result != result as i8 as i16;
+            vm.set_result_flags(8, result as u32, overflow, overflow);
+        }
+        6 => {
+            if value == 0 {
+                return Err(VmError::DivideError);
+            }
+            let dividend = vm.reg16(REG_EAX);
+            let quotient = dividend / value as u16;
+            if quotient > 0xFF {
+                return Err(VmError::DivideError);
+            }
+            let remainder = dividend % value as u16;
+            vm.set_reg16(REG_EAX, (remainder << 8) | quotient);
+        }
+        _ => {
+            if value == 0 {
+                return Err(VmError::DivideError);
+            }
+            let dividend = vm.reg16(REG_EAX) as i16 as i32;
+            let divisor = value as i8 as i32;
+            let quotient = dividend / divisor;
+            if quotient < i8::MIN as i32 || quotient > i8::MAX as i32 {
+                return Err(VmError::DivideError);
+            }
+            let remainder = dividend % divisor;
+            let ax = ((remainder as u8 as u16) << 8) | quotient as u8 as u16;
+            vm.set_reg16(REG_EAX, ax);
+        }
+    }
+    Ok(())
+}
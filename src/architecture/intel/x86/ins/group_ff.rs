@@ -9,7 +9,7 @@ use super::stack;
 use super::sub;
 
 pub(crate) fn exec(vm: &mut Vm, cursor: u32, prefixes: Prefixes) -> Result<(), VmError> {
-    let modrm = decode_modrm(vm, cursor + 1)?;
+    let modrm = decode_modrm(vm, cursor + 1, prefixes)?;
     match modrm.reg {
         0 => {
             add::inc_rm32(vm, &modrm, prefixes)?;
@@ -2,61 +2,47 @@
 
 use crate::vm::{Vm, VmError};
 
-use super::core::{decode_modrm, read_rm32, Prefixes};
+use super::core::{
+    decode_modrm, operand_width, read_immv, read_rmv, regv, set_regv, sign_extend, width_mask,
+    Prefixes,
+};
+
+// Truncate `lhs * rhs` to the operand size into `reg`; CF and OF report
+// whether the product lost significant bits.
+fn imul_into(vm: &mut Vm, reg: u8, prefixes: Prefixes, lhs: u32, rhs: u32) {
+    let width = operand_width(prefixes);
+    let lhs = sign_extend(lhs, width) as i32 as i64;
+    let rhs = sign_extend(rhs, width) as i32 as i64;
+    let result = lhs.wrapping_mul(rhs);
+    let low = result as u32 & width_mask(width);
+    set_regv(vm, reg, prefixes, low);
+    let overflow = result != sign_extend(low, width) as i32 as i64;
+    vm.set_result_flags(width, low, overflow, overflow);
+}
 
 pub(crate) fn imul_rm32_imm8(vm: &mut Vm, cursor: u32, prefixes: Prefixes) -> Result<(), VmError> {
-    let modrm = decode_modrm(vm, cursor + 1)?;
-    let imm = vm.read_u8(cursor + 1 + modrm.len as u32)? as i8 as i32;
-    let value = read_rm32(vm, &modrm, prefixes.segment_base)? as i32 as i64;
-    let result = value.wrapping_mul(imm as i64);
-    let low = result as u32;
-    let high = (result >> 32) as u32;
-    vm.set_reg32(modrm.reg, low);
-    let sign_ext = if (low & 0x8000_0000) != 0 {
-        0xFFFF_FFFF
-    } else {
-        0
-    };
-    let overflow = high != sign_ext;
-    vm.set_flags(vm.zf(), vm.sf(), overflow, overflow);
+    let modrm = decode_modrm(vm, cursor + 1, prefixes)?;
+    let imm = vm.read_u8(cursor + 1 + modrm.len as u32)? as i8 as i32 as u32;
+    let value = read_rmv(vm, &modrm, prefixes)?;
+    imul_into(vm, modrm.reg, prefixes, value, imm);
     vm.set_eip(cursor + 1 + modrm.len as u32 + 1);
     Ok(())
 }
 
 pub(crate) fn imul_rm32_imm32(vm: &mut Vm, cursor: u32, prefixes: Prefixes) -> Result<(), VmError> {
-    let modrm = decode_modrm(vm, cursor + 1)?;
-    let imm = vm.read_u32(cursor + 1 + modrm.len as u32)? as i32 as i64;
-    let value = read_rm32(vm, &modrm, prefixes.segment_base)? as i32 as i64;
-    let result = value.wrapping_mul(imm);
-    let low = result as u32;
-    let high = (result >> 32) as u32;
-    vm.set_reg32(modrm.reg, low);
-    let sign_ext = if (low & 0x8000_0000) != 0 {
-        0xFFFF_FFFF
-    } else {
-        0
-    };
-    let overflow = high != sign_ext;
-    vm.set_flags(vm.zf(), vm.sf(), overflow, overflow);
-    vm.set_eip(cursor + 1 + modrm.len as u32 + 4);
+    let modrm = decode_modrm(vm, cursor + 1, prefixes)?;
+    let (imm, imm_size) = read_immv(vm, cursor + 1 + modrm.len as u32, prefixes)?;
+    let value = read_rmv(vm, &modrm, prefixes)?;
+    imul_into(vm, modrm.reg, prefixes, value, imm);
+    vm.set_eip(cursor + 1 + modrm.len as u32 + imm_size);
     Ok(())
 }
 
 pub(crate) fn imul_r32_rm32(vm: &mut Vm, cursor: u32, prefixes: Prefixes) -> Result<(), VmError> {
-    let modrm = decode_modrm(vm, cursor + 2)?;
-    let value = read_rm32(vm, &modrm, prefixes.segment_base)? as i32 as i64;
-    let lhs = vm.reg32(modrm.reg) as i32 as i64;
-    let result = lhs.wrapping_mul(value);
-    let low = result as u32;
-    let high = (result >> 32) as u32;
-    vm.set_reg32(modrm.reg, low);
-    let sign_ext = if (low & 0x8000_0000) != 0 {
-        0xFFFF_FFFF
-    } else {
-        0
-    };
-    let overflow = high != sign_ext;
-    vm.set_flags(vm.zf(), vm.sf(), overflow, overflow);
+    let modrm = decode_modrm(vm, cursor + 2, prefixes)?;
+    let value = read_rmv(vm, &modrm, prefixes)?;
+    let lhs = regv(vm, modrm.reg, prefixes);
+    imul_into(vm, modrm.reg, prefixes, lhs, value);
     vm.set_eip(cursor + 2 + modrm.len as u32);
     Ok(())
 }
@@ -6,7 +6,7 @@ use crate::architecture::intel::x86::ins::core::{
 };
 
 pub(crate) fn and_rm32_r32(vm: &mut Vm, cursor: u32, prefixes: Prefixes) -> Result<(), VmError> {
-    let modrm = decode_modrm(vm, cursor + 1)?;
+    let modrm = decode_modrm(vm, cursor + 1, prefixes)?;
     // Honor operand-size override for 16-bit AND.
     if prefixes.operand_size_16 {
         let rhs = vm.reg16(modrm.reg);
@@ -26,7 +26,7 @@ pub(crate) fn and_rm32_r32(vm: &mut Vm, cursor: u32, prefixes: Prefixes) -> Resu
 }
 
 pub(crate) fn and_rm8_r8(vm: &mut Vm, cursor: u32, prefixes: Prefixes) -> Result<(), VmError> {
-    let modrm = decode_modrm(vm, cursor + 1)?;
+    let modrm = decode_modrm(vm, cursor + 1, prefixes)?;
     let rhs = vm.reg8(modrm.reg);
     let lhs = read_rm8(vm, &modrm, prefixes.segment_base)?;
     let result = lhs & rhs;
@@ -37,7 +37,7 @@ pub(crate) fn and_rm8_r8(vm: &mut Vm, cursor: u32, prefixes: Prefixes) -> Result
 }
 
 pub(crate) fn and_r32_rm32(vm: &mut Vm, cursor: u32, prefixes: Prefixes) -> Result<(), VmError> {
-    let modrm = decode_modrm(vm, cursor + 1)?;
+    let modrm = decode_modrm(vm, cursor + 1, prefixes)?;
     // Honor operand-size override for 16-bit AND.
     if prefixes.operand_size_16 {
         let rhs = read_rm16(vm, &modrm, prefixes.segment_base)?;
@@ -57,7 +57,7 @@ pub(crate) fn and_r32_rm32(vm: &mut Vm, cursor: u32, prefixes: Prefixes) -> Resu
 }
 
 pub(crate) fn and_r8_rm8(vm: &mut Vm, cursor: u32, prefixes: Prefixes) -> Result<(), VmError> {
-    let modrm = decode_modrm(vm, cursor + 1)?;
+    let modrm = decode_modrm(vm, cursor + 1, prefixes)?;
     let rhs = read_rm8(vm, &modrm, prefixes.segment_base)?;
     let lhs = vm.reg8(modrm.reg);
     let result = lhs & rhs;
@@ -6,7 +6,7 @@ use crate::architecture::intel::x86::ins::core::{
 };
 
 pub(crate) fn xor_rm8_r8(vm: &mut Vm, cursor: u32, prefixes: Prefixes) -> Result<(), VmError> {
-    let modrm = decode_modrm(vm, cursor + 1)?;
+    let modrm = decode_modrm(vm, cursor + 1, prefixes)?;
     let src = vm.reg8(modrm.reg);
     let dst = read_rm8(vm, &modrm, prefixes.segment_base)?;
     let result = dst ^ src;
@@ -17,7 +17,7 @@ pub(crate) fn xor_rm8_r8(vm: &mut Vm, cursor: u32, prefixes: Prefixes) -> Result
 }
 
 pub(crate) fn xor_rm32_r32(vm: &mut Vm, cursor: u32, prefixes: Prefixes) -> Result<(), VmError> {
-    let modrm = decode_modrm(vm, cursor + 1)?;
+    let modrm = decode_modrm(vm, cursor + 1, prefixes)?;
     // Honor operand-size override for 16-bit XOR.
     if prefixes.operand_size_16 {
         let src = vm.reg16(modrm.reg);
@@ -37,7 +37,7 @@ pub(crate) fn xor_rm32_r32(vm: &mut Vm, cursor: u32, prefixes: Prefixes) -> Resu
 }
 
 pub(crate) fn xor_r8_rm8(vm: &mut Vm, cursor: u32, prefixes: Prefixes) -> Result<(), VmError> {
-    let modrm = decode_modrm(vm, cursor + 1)?;
+    let modrm = decode_modrm(vm, cursor + 1, prefixes)?;
     let src = read_rm8(vm, &modrm, prefixes.segment_base)?;
     let dst = vm.reg8(modrm.reg);
     let result = dst ^ src;
@@ -48,7 +48,7 @@ pub(crate) fn xor_r8_rm8(vm: &mut Vm, cursor: u32, prefixes: Prefixes) -> Result
 }
 
 pub(crate) fn xor_r32_rm32(vm: &mut Vm, cursor: u32, prefixes: Prefixes) -> Result<(), VmError> {
-    let modrm = decode_modrm(vm, cursor + 1)?;
+    let modrm = decode_modrm(vm, cursor + 1, prefixes)?;
     // Honor operand-size override for 16-bit XOR.
     if prefixes.operand_size_16 {
         let src = read_rm16(vm, &modrm, prefixes.segment_base)?;
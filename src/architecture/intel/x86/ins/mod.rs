@@ -1,4 +1,19 @@
 //! x86 instruction registration table.
+//!
+//! The coverage target is the user-mode i686 integer instruction set that MSVC and
+//! MinGW toolchains from 2010 to 2022 emit, together with x87, SSE and SSE2.
+//! Privileged and port I/O instructions raise `STATUS_PRIVILEGED_INSTRUCTION`, as
+//! they would in a user-mode process. Far calls, jumps and returns, segment
+//! register loads, `bound`, `arpl`, `into`, MMX and anything newer than SSE2 are
+//! deliberately left out.
+//!
+//! Every legacy prefix is decoded. The 0x66 and 0x67 size overrides select 16-bit
+//! operands and 16-bit addressing throughout; ES, CS, SS and DS overrides are
+//! accepted but flat, while FS and GS add their segment base. LOCK is only valid
+//! on read-modify-write instructions with a memory destination and raises
+//! `STATUS_ILLEGAL_INSTRUCTION` anywhere else. Relative branches under 0x66 take a
+//! rel16 and truncate EIP to 16 bits, as the hardware does; indirect branches and
+//! returns always use 32-bit targets.
 
 mod add;
 mod atomic;
@@ -6,6 +21,7 @@ mod bit;
 mod control;
 mod core;
 mod extended;
+mod flags;
 mod fpu;
 mod group1;
 mod group_f6;
@@ -21,9 +37,10 @@ mod stack;
 mod sub;
 mod system;
 
-use crate::vm::{Vm, VmError};
+use crate::vm::{Vm, VmError, STATUS_ILLEGAL_INSTRUCTION};
 
-pub(crate) use core::{parse_prefixes, Prefixes};
+use core::lock_allowed;
+pub(crate) use core::{decode_modrm, parse_modrm, parse_prefixes, ModRm, Prefixes};
 
 pub(crate) type ExecFn = fn(&mut Vm, u32, Prefixes) -> Result<(), VmError>;
 
@@ -48,18 +65,21 @@ impl InstructionSet {
         }
     }
 
-    pub(crate) fn execute(
+    /// Handler for the instruction at `cursor`, after checking that any LOCK prefix is valid.
+    pub(crate) fn resolve(
         &self,
         opcode: u8,
-        vm: &mut Vm,
+        vm: &Vm,
         cursor: u32,
         prefixes: Prefixes,
-    ) -> Result<(), VmError> {
-        if let Some(handler) = self.handlers[opcode as usize] {
-            handler(vm, cursor, prefixes)
-        } else {
-            Err(VmError::UnsupportedInstruction(opcode))
+    ) -> Result<ExecFn, VmError> {
+        if prefixes.lock && !lock_allowed(vm, cursor)? {
+            return Err(VmError::Exception {
+                code: STATUS_ILLEGAL_INSTRUCTION,
+                address: vm.eip(),
+            });
         }
+        self.handlers[opcode as usize].ok_or(VmError::UnsupportedInstruction(opcode))
     }
 
     pub(crate) fn supported_opcodes(&self) -> Vec<u8> {
@@ -134,21 +154,30 @@ pub(crate) fn build_instruction_set() -> InstructionSet {
     register(&mut ins, 0x3A, sub::cmp_r8_rm8);
     register(&mut ins, 0x3B, sub::cmp_r32_rm32);
     register(&mut ins, 0x3C, sub::cmp_al_imm8);
+    register(&mut ins, 0x27, flags::daa);
+    register(&mut ins, 0x2F, flags::das);
+    register(&mut ins, 0x37, flags::aaa);
     register(&mut ins, 0x3D, sub::cmp_eax_imm32);
+    register(&mut ins, 0x3F, flags::aas);
     register_range(&mut ins, 0x40, 0x47, add::inc_reg);
     register_range(&mut ins, 0x48, 0x4F, sub::dec_reg);
     register_range(&mut ins, 0x50, 0x57, stack::push_reg);
     register_range(&mut ins, 0x58, 0x5F, stack::pop_reg);
+    register(&mut ins, 0x60, stack::pushad);
+    register(&mut ins, 0x61, stack::popad);
     register(&mut ins, 0x6A, stack::push_imm8);
     register(&mut ins, 0x68, stack::push_imm32);
     register(&mut ins, 0x69, imul::imul_rm32_imm32);
     register(&mut ins, 0x6B, imul::imul_rm32_imm8);
+    register_range(&mut ins, 0x6C, 0x6F, system::privileged);
     register_range(&mut ins, 0x70, 0x7F, control::jcc_rel8);
     register(&mut ins, 0x80, group1::exec_group1_8);
     register(&mut ins, 0x81, group1::exec_group1_32);
+    register(&mut ins, 0x82, group1::exec_group1_8);
     register(&mut ins, 0x83, group1::exec_group1_32);
     register(&mut ins, 0x84, logic::test_rm8_r8);
     register(&mut ins, 0x85, logic::test_rm32_r32);
+    register(&mut ins, 0x86, atomic::xchg_rm8_r8);
     register(&mut ins, 0x87, atomic::xchg_rm32_r32);
     register(&mut ins, 0x88, mov::mov_rm8_r8);
     register(&mut ins, 0x89, mov::mov_rm32_r32);
@@ -159,14 +188,25 @@ pub(crate) fn build_instruction_set() -> InstructionSet {
     register(&mut ins, 0x8F, stack::pop_rm32);
     register(&mut ins, 0x90, system::nop);
     register_range(&mut ins, 0x91, 0x97, atomic::xchg_eax_reg);
+    register(&mut ins, 0x9B, fpu::fwait);
     register(&mut ins, 0x9C, stack::pushfd);
+    register(&mut ins, 0x9D, stack::popfd);
+    register(&mut ins, 0x9E, flags::sahf);
+    register(&mut ins, 0x9F, flags::lahf);
+    register(&mut ins, 0x98, system::cwde);
     register(&mut ins, 0x99, system::cdq);
+    register(&mut ins, 0xA0, mov::mov_moffs_to_al);
     register(&mut ins, 0xA1, mov::mov_moffs_to_eax);
+    register(&mut ins, 0xA2, mov::mov_al_to_moffs);
     register(&mut ins, 0xA3, mov::mov_eax_to_moffs);
     register(&mut ins, 0xA4, mov::movsb);
     register(&mut ins, 0xA5, mov::movsd);
+    register(&mut ins, 0xA6, mov::cmpsb);
+    register(&mut ins, 0xA7, mov::cmpsd);
     register(&mut ins, 0xAA, mov::stosb);
     register(&mut ins, 0xAB, mov::stosd);
+    register(&mut ins, 0xAC, mov::lodsb);
+    register(&mut ins, 0xAD, mov::lodsd);
     register(&mut ins, 0xAE, mov::scasb);
     register(&mut ins, 0xAF, mov::scasd);
     register(&mut ins, 0xA8, logic::test_al_imm8);
@@ -179,20 +219,35 @@ pub(crate) fn build_instruction_set() -> InstructionSet {
     register(&mut ins, 0xC3, control::ret_near);
     register(&mut ins, 0xC6, mov::mov_rm8_imm8);
     register(&mut ins, 0xC7, mov::mov_rm32_imm32);
+    register(&mut ins, 0xC8, stack::enter);
     register(&mut ins, 0xC9, stack::leave);
     register(&mut ins, 0xCC, system::int3);
     register(&mut ins, 0xCD, system::int);
+    register(&mut ins, 0xD4, flags::aam);
+    register(&mut ins, 0xD5, flags::aad);
     register(&mut ins, 0xD6, system::salc);
     register_range(&mut ins, 0xD8, 0xDF, fpu::exec);
     register(&mut ins, 0xD0, shift::shift_rm8_1);
     register(&mut ins, 0xD1, shift::shift_rm32_1);
     register(&mut ins, 0xD2, shift::shift_rm8_cl);
     register(&mut ins, 0xD3, shift::shift_rm32_cl);
+    register(&mut ins, 0xD7, mov::xlat);
+    register_range(&mut ins, 0xE0, 0xE3, control::loop_rel8);
+    register_range(&mut ins, 0xE4, 0xE7, system::privileged);
     register(&mut ins, 0xE8, control::call_rel32);
     register(&mut ins, 0xE9, control::jmp_rel32);
     register(&mut ins, 0xEB, control::jmp_rel8);
+    register_range(&mut ins, 0xEC, 0xEF, system::privileged);
+    register(&mut ins, 0xF4, system::privileged);
+    register(&mut ins, 0xF5, flags::cmc);
     register(&mut ins, 0xF6, group_f6::exec);
     register(&mut ins, 0xF7, group_f7::exec);
+    register(&mut ins, 0xF8, flags::clc);
+    register(&mut ins, 0xF9, flags::stc);
+    register(&mut ins, 0xFA, system::privileged);
+    register(&mut ins, 0xFB, system::privileged);
+    register(&mut ins, 0xFC, flags::cld);
+    register(&mut ins, 0xFD, flags::std);
     register(&mut ins, 0xFE, group_fe::exec);
     register(&mut ins, 0xFF, group_ff::exec);
 
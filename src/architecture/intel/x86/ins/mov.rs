@@ -1,14 +1,15 @@
 //! x86 mov and lea instruction handlers.
 
-use crate::vm::{Vm, VmError, REG_AL, REG_EAX, REG_ECX, REG_EDI, REG_ESI};
+use crate::vm::{Vm, VmError, REG_AL, REG_EAX, REG_EBX, REG_ECX, REG_EDI, REG_ESI};
 
 use super::core::{
-    calc_ea, decode_modrm, read_rm16, read_rm32, read_rm8, segment_value, update_flags_sub16,
-    update_flags_sub32, update_flags_sub8, write_rm16, write_rm32, write_rm8, Prefixes,
+    calc_ea, decode_modrm, read_rm16, read_rm32, read_rm8, segment_value, set_regv, set_string_reg,
+    string_reg, update_flags_sub16, update_flags_sub32, update_flags_sub8, write_rm16, write_rm32,
+    write_rm8, Prefixes,
 };
 
 pub(crate) fn mov_rm8_r8(vm: &mut Vm, cursor: u32, prefixes: Prefixes) -> Result<(), VmError> {
-    let modrm = decode_modrm(vm, cursor + 1)?;
+    let modrm = decode_modrm(vm, cursor + 1, prefixes)?;
     let value = vm.reg8(modrm.reg);
     write_rm8(vm, &modrm, prefixes.segment_base, value)?;
     vm.set_eip(cursor + 1 + modrm.len as u32);
@@ -16,7 +17,7 @@ pub(crate) fn mov_rm8_r8(vm: &mut Vm, cursor: u32, prefixes: Prefixes) -> Result
 }
 
 pub(crate) fn mov_rm32_r32(vm: &mut Vm, cursor: u32, prefixes: Prefixes) -> Result<(), VmError> {
-    let modrm = decode_modrm(vm, cursor + 1)?;
+    let modrm = decode_modrm(vm, cursor + 1, prefixes)?;
     if prefixes.operand_size_16 {
         let value = vm.reg16(modrm.reg);
         write_rm16(vm, &modrm, prefixes.segment_base, value)?;
@@ -29,7 +30,7 @@ pub(crate) fn mov_rm32_r32(vm: &mut Vm, cursor: u32, prefixes: Prefixes) -> Resu
 }
 
 pub(crate) fn mov_r8_rm8(vm: &mut Vm, cursor: u32, prefixes: Prefixes) -> Result<(), VmError> {
-    let modrm = decode_modrm(vm, cursor + 1)?;
+    let modrm = decode_modrm(vm, cursor + 1, prefixes)?;
     let value = read_rm8(vm, &modrm, prefixes.segment_base)?;
     vm.set_reg8(modrm.reg, value);
     vm.set_eip(cursor + 1 + modrm.len as u32);
@@ -37,7 +38,7 @@ pub(crate) fn mov_r8_rm8(vm: &mut Vm, cursor: u32, prefixes: Prefixes) -> Result
 }
 
 pub(crate) fn mov_r32_rm32(vm: &mut Vm, cursor: u32, prefixes: Prefixes) -> Result<(), VmError> {
-    let modrm = decode_modrm(vm, cursor + 1)?;
+    let modrm = decode_modrm(vm, cursor + 1, prefixes)?;
     if prefixes.operand_size_16 {
         let value = read_rm16(vm, &modrm, prefixes.segment_base)?;
         vm.set_reg16(modrm.reg, value);
@@ -53,7 +54,7 @@ pub(crate) fn mov_seg_to_rm16(vm: &mut Vm, cursor: u32, prefixes: Prefixes) -> R
     if !prefixes.operand_size_16 {
         return Err(VmError::UnsupportedInstruction(0x8C));
     }
-    let modrm = decode_modrm(vm, cursor + 1)?;
+    let modrm = decode_modrm(vm, cursor + 1, prefixes)?;
     let value = segment_value(modrm.reg);
     write_rm16(vm, &modrm, prefixes.segment_base, value)?;
     vm.set_eip(cursor + 1 + modrm.len as u32);
@@ -61,9 +62,10 @@ pub(crate) fn mov_seg_to_rm16(vm: &mut Vm, cursor: u32, prefixes: Prefixes) -> R
 }
 
 pub(crate) fn lea(vm: &mut Vm, cursor: u32, prefixes: Prefixes) -> Result<(), VmError> {
-    let modrm = decode_modrm(vm, cursor + 1)?;
-    let addr = calc_ea(vm, &modrm, prefixes.segment_base)?;
-    vm.set_reg32(modrm.reg, addr);
+    let modrm = decode_modrm(vm, cursor + 1, prefixes)?;
+    // LEA only computes the offset; segment overrides do not apply.
+    let addr = calc_ea(vm, &modrm, 0)?;
+    set_regv(vm, modrm.reg, prefixes, addr);
     vm.set_eip(cursor + 1 + modrm.len as u32);
     Ok(())
 }
@@ -93,7 +95,7 @@ pub(crate) fn mov_r32_imm32(vm: &mut Vm, cursor: u32, prefixes: Prefixes) -> Res
 }
 
 pub(crate) fn mov_rm8_imm8(vm: &mut Vm, cursor: u32, prefixes: Prefixes) -> Result<(), VmError> {
-    let modrm = decode_modrm(vm, cursor + 1)?;
+    let modrm = decode_modrm(vm, cursor + 1, prefixes)?;
     if modrm.reg != 0 {
         return Err(VmError::UnsupportedInstruction(0xC6));
     }
@@ -104,7 +106,7 @@ pub(crate) fn mov_rm8_imm8(vm: &mut Vm, cursor: u32, prefixes: Prefixes) -> Resu
 }
 
 pub(crate) fn mov_rm32_imm32(vm: &mut Vm, cursor: u32, prefixes: Prefixes) -> Result<(), VmError> {
-    let modrm = decode_modrm(vm, cursor + 1)?;
+    let modrm = decode_modrm(vm, cursor + 1, prefixes)?;
     if modrm.reg != 0 {
         return Err(VmError::UnsupportedInstruction(0xC7));
     }
@@ -120,12 +122,22 @@ pub(crate) fn mov_rm32_imm32(vm: &mut Vm, cursor: u32, prefixes: Prefixes) -> Re
     Ok(())
 }
 
+// The moffs operand is a 16-bit offset under the address-size prefix.
+fn moffs(vm: &Vm, cursor: u32, prefixes: Prefixes) -> Result<(u32, u32), VmError> {
+    let (offset, len) = if prefixes.address_size_16 {
+        (vm.read_u16(cursor + 1)? as u32, 2)
+    } else {
+        (vm.read_u32(cursor + 1)?, 4)
+    };
+    Ok((offset.wrapping_add(prefixes.segment_base), len))
+}
+
 pub(crate) fn mov_moffs_to_eax(
     vm: &mut Vm,
     cursor: u32,
     prefixes: Prefixes,
 ) -> Result<(), VmError> {
-    let addr = vm.read_u32(cursor + 1)?.wrapping_add(prefixes.segment_base);
+    let (addr, len) = moffs(vm, cursor, prefixes)?;
     if prefixes.operand_size_16 {
         let value = vm.read_u16(addr)?;
         vm.set_reg16(0, value);
@@ -133,7 +145,7 @@ pub(crate) fn mov_moffs_to_eax(
         let value = vm.read_u32(addr)?;
         vm.set_reg32(REG_EAX, value);
     }
-    vm.set_eip(cursor + 5);
+    vm.set_eip(cursor + 1 + len);
     Ok(())
 }
 
@@ -142,7 +154,7 @@ pub(crate) fn mov_eax_to_moffs(
     cursor: u32,
     prefixes: Prefixes,
 ) -> Result<(), VmError> {
-    let addr = vm.read_u32(cursor + 1)?.wrapping_add(prefixes.segment_base);
+    let (addr, len) = moffs(vm, cursor, prefixes)?;
     if prefixes.operand_size_16 {
         let value = vm.reg16(0);
         vm.write_u16(addr, value)?;
@@ -150,90 +162,226 @@ pub(crate) fn mov_eax_to_moffs(
         let value = vm.reg32(REG_EAX);
         vm.write_u32(addr, value)?;
     }
-    vm.set_eip(cursor + 5);
+    vm.set_eip(cursor + 1 + len);
     Ok(())
 }
 
-pub(crate) fn movsd(vm: &mut Vm, cursor: u32, prefixes: Prefixes) -> Result<(), VmError> {
-    let size = if prefixes.operand_size_16 { 2 } else { 4 };
-    let count = if prefixes.rep { vm.reg32(REG_ECX) } else { 1 };
-    let mut src = vm.reg32(REG_ESI);
-    let mut dst = vm.reg32(REG_EDI);
-    for _ in 0..count {
-        if size == 2 {
-            let value = vm.read_u16(src)?;
-            vm.write_u16(dst, value)?;
-            src = src.wrapping_add(2);
-            dst = dst.wrapping_add(2);
-        } else {
-            let value = vm.read_u32(src)?;
-            vm.write_u32(dst, value)?;
-            src = src.wrapping_add(4);
-            dst = dst.wrapping_add(4);
+pub(crate) fn mov_moffs_to_al(vm: &mut Vm, cursor: u32, prefixes: Prefixes) -> Result<(), VmError> {
+    let (addr, len) = moffs(vm, cursor, prefixes)?;
+    let value = vm.read_u8(addr)?;
+    vm.set_reg8(REG_AL, value);
+    vm.set_eip(cursor + 1 + len);
+    Ok(())
+}
+
+pub(crate) fn mov_al_to_moffs(vm: &mut Vm, cursor: u32, prefixes: Prefixes) -> Result<(), VmError> {
+    let (addr, len) = moffs(vm, cursor, prefixes)?;
+    vm.write_u8(addr, vm.reg8(REG_AL))?;
+    vm.set_eip(cursor + 1 + len);
+    Ok(())
+}
+
+// XLAT: AL = [EBX + AL] ([BX + AL] under the address-size prefix).
+pub(crate) fn xlat(vm: &mut Vm, cursor: u32, prefixes: Prefixes) -> Result<(), VmError> {
+    let offset = string_reg(vm, REG_EBX, prefixes).wrapping_add(vm.reg8(REG_AL) as u32);
+    let addr = advance(offset, 0, prefixes).wrapping_add(prefixes.segment_base);
+    let value = vm.read_u8(addr)?;
+    vm.set_reg8(REG_AL, value);
+    vm.set_eip(cursor + 1);
+    Ok(())
+}
+
+// String instructions walk ESI/EDI down instead of up while DF is set. Only the
+// ESI operand takes a segment override; EDI is always relative to ES.
+fn string_step(vm: &Vm, size: u32) -> u32 {
+    if vm.df() {
+        size.wrapping_neg()
+    } else {
+        size
+    }
+}
+
+// Under the address-size prefix SI and DI wrap at 64 KiB.
+fn advance(addr: u32, step: u32, prefixes: Prefixes) -> u32 {
+    let next = addr.wrapping_add(step);
+    if prefixes.address_size_16 {
+        next & 0xFFFF
+    } else {
+        next
+    }
+}
+
+fn read_sized(vm: &Vm, addr: u32, size: u32) -> Result<u32, VmError> {
+    match size {
+        1 => Ok(vm.read_u8(addr)? as u32),
+        2 => Ok(vm.read_u16(addr)? as u32),
+        _ => vm.read_u32(addr),
+    }
+}
+
+fn write_sized(vm: &mut Vm, addr: u32, size: u32, value: u32) -> Result<(), VmError> {
+    match size {
+        1 => vm.write_u8(addr, value as u8),
+        2 => vm.write_u16(addr, value as u16),
+        _ => vm.write_u32(addr, value),
+    }
+}
+
+// CMPS/SCAS compare `lhs - rhs` at the given operand size.
+fn compare_sized(vm: &mut Vm, lhs: u32, rhs: u32, size: u32) {
+    match size {
+        1 => {
+            let (lhs, rhs) = (lhs as u8, rhs as u8);
+            update_flags_sub8(vm, lhs, rhs, lhs.wrapping_sub(rhs));
+        }
+        2 => {
+            let (lhs, rhs) = (lhs as u16, rhs as u16);
+            update_flags_sub16(vm, lhs, rhs, lhs.wrapping_sub(rhs));
         }
+        _ => update_flags_sub32(vm, lhs, rhs, lhs.wrapping_sub(rhs)),
     }
-    vm.set_reg32(REG_ESI, src);
-    vm.set_reg32(REG_EDI, dst);
-    if prefixes.rep {
-        vm.set_reg32(REG_ECX, 0);
+}
+
+fn string_size(prefixes: Prefixes) -> u32 {
+    if prefixes.operand_size_16 {
+        2
+    } else {
+        4
     }
-    vm.set_eip(cursor + 1);
-    Ok(())
+}
+
+pub(crate) fn movsd(vm: &mut Vm, cursor: u32, prefixes: Prefixes) -> Result<(), VmError> {
+    movs_common(vm, cursor, prefixes, string_size(prefixes))
 }
 
 pub(crate) fn movsb(vm: &mut Vm, cursor: u32, prefixes: Prefixes) -> Result<(), VmError> {
-    let count = if prefixes.rep { vm.reg32(REG_ECX) } else { 1 };
-    let mut src = vm.reg32(REG_ESI);
-    let mut dst = vm.reg32(REG_EDI);
+    movs_common(vm, cursor, prefixes, 1)
+}
+
+fn movs_common(vm: &mut Vm, cursor: u32, prefixes: Prefixes, size: u32) -> Result<(), VmError> {
+    let count = if prefixes.rep {
+        string_reg(vm, REG_ECX, prefixes)
+    } else {
+        1
+    };
+    let step = string_step(vm, size);
+    let mut src = string_reg(vm, REG_ESI, prefixes);
+    let mut dst = string_reg(vm, REG_EDI, prefixes);
     for _ in 0..count {
-        let value = vm.read_u8(src)?;
-        vm.write_u8(dst, value)?;
-        src = src.wrapping_add(1);
-        dst = dst.wrapping_add(1);
+        let value = read_sized(vm, src.wrapping_add(prefixes.segment_base), size)?;
+        write_sized(vm, dst, size, value)?;
+        src = advance(src, step, prefixes);
+        dst = advance(dst, step, prefixes);
     }
-    vm.set_reg32(REG_ESI, src);
-    vm.set_reg32(REG_EDI, dst);
+    set_string_reg(vm, REG_ESI, prefixes, src);
+    set_string_reg(vm, REG_EDI, prefixes, dst);
     if prefixes.rep {
-        vm.set_reg32(REG_ECX, 0);
+        set_string_reg(vm, REG_ECX, prefixes, 0);
     }
     vm.set_eip(cursor + 1);
     Ok(())
 }
 
 pub(crate) fn stosb(vm: &mut Vm, cursor: u32, prefixes: Prefixes) -> Result<(), VmError> {
-    let count = if prefixes.rep { vm.reg32(REG_ECX) } else { 1 };
-    let mut dst = vm.reg32(REG_EDI);
-    let value = vm.reg8(REG_AL);
+    stos_common(vm, cursor, prefixes, 1)
+}
+
+pub(crate) fn stosd(vm: &mut Vm, cursor: u32, prefixes: Prefixes) -> Result<(), VmError> {
+    stos_common(vm, cursor, prefixes, string_size(prefixes))
+}
+
+fn stos_common(vm: &mut Vm, cursor: u32, prefixes: Prefixes, size: u32) -> Result<(), VmError> {
+    let count = if prefixes.rep {
+        string_reg(vm, REG_ECX, prefixes)
+    } else {
+        1
+    };
+    let step = string_step(vm, size);
+    let mut dst = string_reg(vm, REG_EDI, prefixes);
+    let value = vm.reg32(REG_EAX);
     for _ in 0..count {
-        vm.write_u8(dst, value)?;
-        dst = dst.wrapping_add(1);
+        write_sized(vm, dst, size, value)?;
+        dst = advance(dst, step, prefixes);
     }
-    vm.set_reg32(REG_EDI, dst);
+    set_string_reg(vm, REG_EDI, prefixes, dst);
     if prefixes.rep {
-        vm.set_reg32(REG_ECX, 0);
+        set_string_reg(vm, REG_ECX, prefixes, 0);
     }
     vm.set_eip(cursor + 1);
     Ok(())
 }
 
-pub(crate) fn stosd(vm: &mut Vm, cursor: u32, prefixes: Prefixes) -> Result<(), VmError> {
-    let size = if prefixes.operand_size_16 { 2 } else { 4 };
-    let count = if prefixes.rep { vm.reg32(REG_ECX) } else { 1 };
-    let mut dst = vm.reg32(REG_EDI);
-    let value32 = vm.reg32(REG_EAX);
+pub(crate) fn lodsb(vm: &mut Vm, cursor: u32, prefixes: Prefixes) -> Result<(), VmError> {
+    lods_common(vm, cursor, prefixes, 1)
+}
+
+pub(crate) fn lodsd(vm: &mut Vm, cursor: u32, prefixes: Prefixes) -> Result<(), VmError> {
+    lods_common(vm, cursor, prefixes, string_size(prefixes))
+}
+
+fn lods_common(vm: &mut Vm, cursor: u32, prefixes: Prefixes, size: u32) -> Result<(), VmError> {
+    let count = if prefixes.rep {
+        string_reg(vm, REG_ECX, prefixes)
+    } else {
+        1
+    };
+    let step = string_step(vm, size);
+    let mut src = string_reg(vm, REG_ESI, prefixes);
     for _ in 0..count {
-        if size == 2 {
-            vm.write_u16(dst, value32 as u16)?;
-            dst = dst.wrapping_add(2);
-        } else {
-            vm.write_u32(dst, value32)?;
-            dst = dst.wrapping_add(4);
+        let value = read_sized(vm, src.wrapping_add(prefixes.segment_base), size)?;
+        match size {
+            1 => vm.set_reg8(REG_AL, value as u8),
+            2 => vm.set_reg16(REG_EAX, value as u16),
+            _ => vm.set_reg32(REG_EAX, value),
         }
+        src = advance(src, step, prefixes);
     }
-    vm.set_reg32(REG_EDI, dst);
+    set_string_reg(vm, REG_ESI, prefixes, src);
     if prefixes.rep {
-        vm.set_reg32(REG_ECX, 0);
+        set_string_reg(vm, REG_ECX, prefixes, 0);
+    }
+    vm.set_eip(cursor + 1);
+    Ok(())
+}
+
+pub(crate) fn cmpsb(vm: &mut Vm, cursor: u32, prefixes: Prefixes) -> Result<(), VmError> {
+    cmps_common(vm, cursor, prefixes, 1)
+}
+
+pub(crate) fn cmpsd(vm: &mut Vm, cursor: u32, prefixes: Prefixes) -> Result<(), VmError> {
+    cmps_common(vm, cursor, prefixes, string_size(prefixes))
+}
+
+fn cmps_common(vm: &mut Vm, cursor: u32, prefixes: Prefixes, size: u32) -> Result<(), VmError> {
+    let repeats = prefixes.rep || prefixes.repne;
+    let mut remaining = if repeats {
+        string_reg(vm, REG_ECX, prefixes)
+    } else {
+        1
+    };
+    let step = string_step(vm, size);
+    let mut esi = string_reg(vm, REG_ESI, prefixes);
+    let mut edi = string_reg(vm, REG_EDI, prefixes);
+    while remaining > 0 {
+        let src = read_sized(vm, esi.wrapping_add(prefixes.segment_base), size)?;
+        let dst = read_sized(vm, edi, size)?;
+        compare_sized(vm, src, dst, size);
+        esi = advance(esi, step, prefixes);
+        edi = advance(edi, step, prefixes);
+        remaining = remaining.wrapping_sub(1);
+        if !repeats {
+            break;
+        }
+        let condition = if prefixes.rep { vm.zf() } else { !vm.zf() };
+        if !condition {
+            break;
+        }
     }
+    if repeats {
+        set_string_reg(vm, REG_ECX, prefixes, remaining);
+    }
+    set_string_reg(vm, REG_ESI, prefixes, esi);
+    set_string_reg(vm, REG_EDI, prefixes, edi);
     vm.set_eip(cursor + 1);
     Ok(())
 }
@@ -243,82 +391,68 @@ pub(crate) fn scasb(vm: &mut Vm, cursor: u32, prefixes: Prefixes) -> Result<(),
 }
 
 pub(crate) fn scasd(vm: &mut Vm, cursor: u32, prefixes: Prefixes) -> Result<(), VmError> {
-    let size = if prefixes.operand_size_16 { 2 } else { 4 };
-    scas_common(vm, cursor, prefixes, size)
+    scas_common(vm, cursor, prefixes, string_size(prefixes))
 }
 
 fn scas_common(vm: &mut Vm, cursor: u32, prefixes: Prefixes, size: u32) -> Result<(), VmError> {
     let repeats = prefixes.rep || prefixes.repne;
-    let mut remaining = if repeats { vm.reg32(REG_ECX) } else { 1 };
-    let mut edi = vm.reg32(REG_EDI);
+    let mut remaining = if repeats {
+        string_reg(vm, REG_ECX, prefixes)
+    } else {
+        1
+    };
+    let step = string_step(vm, size);
+    let mut edi = string_reg(vm, REG_EDI, prefixes);
+    let eax = vm.reg32(REG_EAX);
     while remaining > 0 {
-        match size {
-            1 => {
-                let dst = vm.read_u8(edi)?;
-                let src = vm.reg8(REG_AL);
-                let result = dst.wrapping_sub(src);
-                update_flags_sub8(vm, dst, src, result);
-            }
-            2 => {
-                let dst = vm.read_u16(edi)?;
-                let src = vm.reg16(REG_EAX);
-                let result = dst.wrapping_sub(src);
-                update_flags_sub16(vm, dst, src, result);
-            }
-            4 => {
-                let dst = vm.read_u32(edi)?;
-                let src = vm.reg32(REG_EAX);
-                let result = dst.wrapping_sub(src);
-                update_flags_sub32(vm, dst, src, result);
-            }
-            _ => unreachable!(),
-        }
-        edi = edi.wrapping_add(size);
+        let dst = read_sized(vm, edi, size)?;
+        compare_sized(vm, eax, dst, size);
+        edi = advance(edi, step, prefixes);
         remaining = remaining.wrapping_sub(1);
         if !repeats {
             break;
         }
         let condition = if prefixes.rep { vm.zf() } else { !vm.zf() };
-        if !condition || remaining == 0 {
+        if !condition {
             break;
         }
     }
     if repeats {
-        vm.set_reg32(REG_ECX, remaining);
+        set_string_reg(vm, REG_ECX, prefixes, remaining);
     }
-    vm.set_reg32(REG_EDI, edi);
+    set_string_reg(vm, REG_EDI, prefixes, edi);
     vm.set_eip(cursor + 1);
     Ok(())
 }
 
 pub(crate) fn movzx_rm8(vm: &mut Vm, cursor: u32, prefixes: Prefixes) -> Result<(), VmError> {
-    let modrm = decode_modrm(vm, cursor + 2)?;
+    let modrm = decode_modrm(vm, cursor + 2, prefixes)?;
     let value = read_rm8(vm, &modrm, prefixes.segment_base)? as u32;
-    vm.set_reg32(modrm.reg, value);
+    set_regv(vm, modrm.reg, prefixes, value);
     vm.set_eip(cursor + 2 + modrm.len as u32);
     Ok(())
 }
 
 pub(crate) fn movzx_rm16(vm: &mut Vm, cursor: u32, prefixes: Prefixes) -> Result<(), VmError> {
-    let modrm = decode_modrm(vm, cursor + 2)?;
+    let modrm = decode_modrm(vm, cursor + 2, prefixes)?;
     let value = read_rm16(vm, &modrm, prefixes.segment_base)? as u32;
-    vm.set_reg32(modrm.reg, value);
+    set_regv(vm, modrm.reg, prefixes, value);
     vm.set_eip(cursor + 2 + modrm.len as u32);
     Ok(())
 }
 
 pub(crate) fn movsx_rm8(vm: &mut Vm, cursor: u32, prefixes: Prefixes) -> Result<(), VmError> {
-    let modrm = decode_modrm(vm, cursor + 2)?;
+    let modrm = decode_modrm(vm, cursor + 2, prefixes)?;
     let value = read_rm8(vm, &modrm, prefixes.segment_base)? as i8 as i32 as u32;
-    vm.set_reg32(modrm.reg, value);
+    set_regv(vm, modrm.reg, prefixes, value);
     vm.set_eip(cursor + 2 + modrm.len as u32);
     Ok(())
 }
 
 pub(crate) fn movsx_rm16(vm: &mut Vm, cursor: u32, prefixes: Prefixes) -> Result<(), VmError> {
-    let modrm = decode_modrm(vm, cursor + 2)?;
+    let modrm = decode_modrm(vm, cursor + 2, prefixes)?;
     let value = read_rm16(vm, &modrm, prefixes.segment_base)? as i16 as i32 as u32;
-    vm.set_reg32(modrm.reg, value);
+    set_regv(vm, modrm.reg, prefixes, value);
     vm.set_eip(cursor + 2 + modrm.len as u32);
     Ok(())
 }
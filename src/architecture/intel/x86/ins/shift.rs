@@ -2,8 +2,12 @@
 
 use crate::vm::{Vm, VmError, REG_CL};
 
-use super::core::{decode_modrm, read_rm32, read_rm8, write_rm32, write_rm8, ModRm, Prefixes};
+use super::core::{
+    decode_modrm, operand_width, read_rm8, read_rmv, regv, sign_extend, width_mask, write_rm8,
+    write_rmv, ModRm, Prefixes,
+};
 
+// The 16- and 32-bit forms; `count` is already masked to five bits.
 fn exec_shift_rm32(
     vm: &mut Vm,
     modrm: &ModRm,
@@ -11,104 +15,92 @@ fn exec_shift_rm32(
     count: u32,
     opcode: u8,
 ) -> Result<(), VmError> {
-    match modrm.reg {
-        0 => {
-            let value = read_rm32(vm, modrm, prefixes.segment_base)?;
-            let result = value.rotate_left(count);
-            let cf = (result & 1) != 0;
-            let of = if count == 1 {
-                ((result >> 31) & 1) != (cf as u32)
-            } else {
-                vm.of()
-            };
-            vm.set_flags(vm.zf(), vm.sf(), of, cf);
-            write_rm32(vm, modrm, prefixes.segment_base, result)?;
+    let width = operand_width(prefixes);
+    let bits = width as u32;
+    let mask = width_mask(width);
+    let sign = 1u32 << (bits - 1);
+    if modrm.reg == 0 || modrm.reg == 1 {
+        let count = count % bits;
+        if count == 0 {
+            return Ok(());
         }
-        1 => {
-            let value = read_rm32(vm, modrm, prefixes.segment_base)?;
-            let result = value.rotate_right(count);
-            let cf = (result & 0x8000_0000) != 0;
-            let of = if count == 1 {
-                ((result >> 31) ^ (result >> 30)) & 1 != 0
-            } else {
-                vm.of()
-            };
-            write_rm32(vm, modrm, prefixes.segment_base, result)?;
-            vm.set_flags(vm.zf(), vm.sf(), of, cf);
-        }
-        2 => {
-            let value = read_rm32(vm, modrm, prefixes.segment_base)?;
+        let value = read_rmv(vm, modrm, prefixes)?;
+        let (result, cf) = if modrm.reg == 0 {
+            let result = ((value << count) | (value >> (bits - count))) & mask;
+            (result, (result & 1) != 0)
+        } else {
+            let result = ((value >> count) | (value << (bits - count))) & mask;
+            (result, (result & sign) != 0)
+        };
+        let of = if count != 1 {
+            vm.of()
+        } else if modrm.reg == 0 {
+            ((result & sign) != 0) != cf
+        } else {
+            ((result ^ (result << 1)) & sign) != 0
+        };
+        write_rmv(vm, modrm, prefixes, result)?;
+        vm.set_cf(cf);
+        vm.set_of(of);
+        return Ok(());
+    }
+    let value = read_rmv(vm, modrm, prefixes)?;
+    match modrm.reg {
+        2 | 3 => {
+            let count = count % (bits + 1);
+            if count == 0 {
+                return Ok(());
+            }
             let cf_in = if vm.cf() { 1u64 } else { 0 };
-            let full = (cf_in << 32) | value as u64;
-            let rotated = ((full << count) | (full >> (33 - count))) & ((1u64 << 33) - 1);
-            let result = rotated as u32;
-            let cf = ((rotated >> 32) & 1) != 0;
-            let of = if count == 1 {
-                ((result >> 31) & 1) != (cf as u32)
+            let full = (cf_in << bits) | value as u64;
+            let span = (1u64 << (bits + 1)) - 1;
+            let rotated = if modrm.reg == 2 {
+                ((full << count) | (full >> (bits + 1 - count))) & span
             } else {
-                vm.of()
+                ((full >> count) | (full << (bits + 1 - count))) & span
             };
-            write_rm32(vm, modrm, prefixes.segment_base, result)?;
-            vm.set_flags(vm.zf(), vm.sf(), of, cf);
-        }
-        3 => {
-            let value = read_rm32(vm, modrm, prefixes.segment_base)?;
-            let cf_in = if vm.cf() { 1u64 } else { 0 };
-            let full = (cf_in << 32) | value as u64;
-            let rotated = ((full >> count) | (full << (33 - count))) & ((1u64 << 33) - 1);
-            let result = rotated as u32;
-            let cf = ((rotated >> 32) & 1) != 0;
-            let of = if count == 1 {
-                ((result >> 31) ^ (result >> 30)) & 1 != 0
-            } else {
+            let result = rotated as u32 & mask;
+            let cf = ((rotated >> bits) & 1) != 0;
+            let of = if count != 1 {
                 vm.of()
+            } else if modrm.reg == 2 {
+                ((result & sign) != 0) != cf
+            } else {
+                ((result ^ (result << 1)) & sign) != 0
             };
-            write_rm32(vm, modrm, prefixes.segment_base, result)?;
-            vm.set_flags(vm.zf(), vm.sf(), of, cf);
+            write_rmv(vm, modrm, prefixes, result)?;
+            vm.set_cf(cf);
+            vm.set_of(of);
         }
-        4 => {
-            let value = read_rm32(vm, modrm, prefixes.segment_base)?;
-            let result = value << count;
-            let cf = ((value >> (32 - count)) & 1) != 0;
+        4 | 6 => {
+            let wide = (value as u64) << count;
+            let result = wide as u32 & mask;
+            let cf = ((wide >> bits) & 1) != 0;
             let of = if count == 1 {
-                ((result ^ value) & 0x8000_0000) != 0
+                ((result ^ value) & sign) != 0
             } else {
                 vm.of()
             };
-            vm.set_flags(result == 0, (result & 0x8000_0000) != 0, of, cf);
-            write_rm32(vm, modrm, prefixes.segment_base, result)?;
+            vm.set_result_flags(width, result, cf, of);
+            write_rmv(vm, modrm, prefixes, result)?;
         }
         5 => {
-            let value = read_rm32(vm, modrm, prefixes.segment_base)?;
             let result = value >> count;
             let cf = ((value >> (count - 1)) & 1) != 0;
             let of = if count == 1 {
-                (value & 0x8000_0000) != 0
+                (value & sign) != 0
             } else {
                 false
             };
-            vm.set_flags(result == 0, (result & 0x8000_0000) != 0, of, cf);
-            write_rm32(vm, modrm, prefixes.segment_base, result)?;
-        }
-        6 => {
-            let value = read_rm32(vm, modrm, prefixes.segment_base)?;
-            let result = value << count;
-            let cf = ((value >> (32 - count)) & 1) != 0;
-            let of = if count == 1 {
-                ((result ^ value) & 0x8000_0000) != 0
-            } else {
-                vm.of()
-            };
-            vm.set_flags(result == 0, (result & 0x8000_0000) != 0, of, cf);
-            write_rm32(vm, modrm, prefixes.segment_base, result)?;
+            vm.set_result_flags(width, result, cf, of);
+            write_rmv(vm, modrm, prefixes, result)?;
         }
         7 => {
-            let value = read_rm32(vm, modrm, prefixes.segment_base)?;
-            let signed = value as i32;
-            let result = (signed >> count) as u32;
-            let cf = ((value >> (count - 1)) & 1) != 0;
-            vm.set_flags(result == 0, (result & 0x8000_0000) != 0, false, cf);
-            write_rm32(vm, modrm, prefixes.segment_base, result)?;
+            let signed = sign_extend(value, width) as i32;
+            let result = (signed >> count) as u32 & mask;
+            let cf = ((signed >> (count - 1)) & 1) != 0;
+            vm.set_result_flags(width, result, cf, false);
+            write_rmv(vm, modrm, prefixes, result)?;
         }
         _ => return Err(VmError::UnsupportedInstruction(opcode)),
     }
@@ -136,7 +128,8 @@ fn exec_shift_rm8(
             } else {
                 vm.of()
             };
-            vm.set_flags(vm.zf(), vm.sf(), of, cf);
+            vm.set_cf(cf);
+            vm.set_of(of);
             write_rm8(vm, modrm, prefixes.segment_base, result)?;
         }
         1 => {
@@ -153,7 +146,8 @@ fn exec_shift_rm8(
                 vm.of()
             };
             write_rm8(vm, modrm, prefixes.segment_base, result)?;
-            vm.set_flags(vm.zf(), vm.sf(), of, cf);
+            vm.set_cf(cf);
+            vm.set_of(of);
         }
         2 => {
             let count = count % 9;
@@ -172,7 +166,8 @@ fn exec_shift_rm8(
                 vm.of()
             };
             write_rm8(vm, modrm, prefixes.segment_base, result)?;
-            vm.set_flags(vm.zf(), vm.sf(), of, cf);
+            vm.set_cf(cf);
+            vm.set_of(of);
         }
         3 => {
             let count = count % 9;
@@ -191,7 +186,8 @@ fn exec_shift_rm8(
                 vm.of()
             };
             write_rm8(vm, modrm, prefixes.segment_base, result)?;
-            vm.set_flags(vm.zf(), vm.sf(), of, cf);
+            vm.set_cf(cf);
+            vm.set_of(of);
         }
         4 => {
             let value = read_rm8(vm, modrm, prefixes.segment_base)?;
@@ -205,7 +201,7 @@ fn exec_shift_rm8(
             } else {
                 vm.of()
             };
-            vm.set_flags(result == 0, (result & 0x80) != 0, of, cf);
+            vm.set_result_flags(8, result as u32, cf, of);
             write_rm8(vm, modrm, prefixes.segment_base, result)?;
         }
         5 => {
@@ -220,7 +216,7 @@ fn exec_shift_rm8(
             } else {
                 false
             };
-            vm.set_flags(result == 0, (result & 0x80) != 0, of, cf);
+            vm.set_result_flags(8, result as u32, cf, of);
             write_rm8(vm, modrm, prefixes.segment_base, result)?;
         }
         6 => {
@@ -235,7 +231,7 @@ fn exec_shift_rm8(
             } else {
                 vm.of()
             };
-            vm.set_flags(result == 0, (result & 0x80) != 0, of, cf);
+            vm.set_result_flags(8, result as u32, cf, of);
             write_rm8(vm, modrm, prefixes.segment_base, result)?;
         }
         7 => {
@@ -248,7 +244,7 @@ fn exec_shift_rm8(
                     ((value >> (count - 1)) & 1) != 0,
                 )
             };
-            vm.set_flags(result == 0, (result & 0x80) != 0, false, cf);
+            vm.set_result_flags(8, result as u32, cf, false);
             write_rm8(vm, modrm, prefixes.segment_base, result)?;
         }
         _ => return Err(VmError::UnsupportedInstruction(opcode)),
@@ -257,7 +253,7 @@ fn exec_shift_rm8(
 }
 
 pub(crate) fn shift_rm8_imm8(vm: &mut Vm, cursor: u32, prefixes: Prefixes) -> Result<(), VmError> {
-    let modrm = decode_modrm(vm, cursor + 1)?;
+    let modrm = decode_modrm(vm, cursor + 1, prefixes)?;
     let imm = vm.read_u8(cursor + 1 + modrm.len as u32)?;
     let count = (imm & 0x1f) as u32;
     if count == 0 {
@@ -270,7 +266,7 @@ pub(crate) fn shift_rm8_imm8(vm: &mut Vm, cursor: u32, prefixes: Prefixes) -> Re
 }
 
 pub(crate) fn shift_rm32_imm8(vm: &mut Vm, cursor: u32, prefixes: Prefixes) -> Result<(), VmError> {
-    let modrm = decode_modrm(vm, cursor + 1)?;
+    let modrm = decode_modrm(vm, cursor + 1, prefixes)?;
     let imm = vm.read_u8(cursor + 1 + modrm.len as u32)?;
     let count = (imm & 0x1f) as u32;
     if count == 0 {
@@ -283,21 +279,21 @@ pub(crate) fn shift_rm32_imm8(vm: &mut Vm, cursor: u32, prefixes: Prefixes) -> R
 }
 
 pub(crate) fn shift_rm8_1(vm: &mut Vm, cursor: u32, prefixes: Prefixes) -> Result<(), VmError> {
-    let modrm = decode_modrm(vm, cursor + 1)?;
+    let modrm = decode_modrm(vm, cursor + 1, prefixes)?;
     exec_shift_rm8(vm, &modrm, prefixes, 1, 0xD0)?;
     vm.set_eip(cursor + 1 + modrm.len as u32);
     Ok(())
 }
 
 pub(crate) fn shift_rm32_1(vm: &mut Vm, cursor: u32, prefixes: Prefixes) -> Result<(), VmError> {
-    let modrm = decode_modrm(vm, cursor + 1)?;
+    let modrm = decode_modrm(vm, cursor + 1, prefixes)?;
     exec_shift_rm32(vm, &modrm, prefixes, 1, 0xD1)?;
     vm.set_eip(cursor + 1 + modrm.len as u32);
     Ok(())
 }
 
 pub(crate) fn shift_rm8_cl(vm: &mut Vm, cursor: u32, prefixes: Prefixes) -> Result<(), VmError> {
-    let modrm = decode_modrm(vm, cursor + 1)?;
+    let modrm = decode_modrm(vm, cursor + 1, prefixes)?;
     let count = (vm.reg8(REG_CL) & 0x1f) as u32;
     if count == 0 {
         vm.set_eip(cursor + 1 + modrm.len as u32);
@@ -309,7 +305,7 @@ pub(crate) fn shift_rm8_cl(vm: &mut Vm, cursor: u32, prefixes: Prefixes) -> Resu
 }
 
 pub(crate) fn shift_rm32_cl(vm: &mut Vm, cursor: u32, prefixes: Prefixes) -> Result<(), VmError> {
-    let modrm = decode_modrm(vm, cursor + 1)?;
+    let modrm = decode_modrm(vm, cursor + 1, prefixes)?;
     let count = (vm.reg8(REG_CL) & 0x1f) as u32;
     if count == 0 {
         vm.set_eip(cursor + 1 + modrm.len as u32);
@@ -319,3 +315,44 @@ pub(crate) fn shift_rm32_cl(vm: &mut Vm, cursor: u32, prefixes: Prefixes) -> Res
     vm.set_eip(cursor + 1 + modrm.len as u32);
     Ok(())
 }
+
+/// SHLD/SHRD r/m16/32, r16/32 by imm8 (0F A4/AC) or CL (0F A5/AD).
+pub(crate) fn double_shift(
+    vm: &mut Vm,
+    cursor: u32,
+    ext: u8,
+    prefixes: Prefixes,
+) -> Result<(), VmError> {
+    let modrm = decode_modrm(vm, cursor + 2, prefixes)?;
+    let mut next = cursor + 2 + modrm.len as u32;
+    let count = if ext == 0xA4 || ext == 0xAC {
+        next += 1;
+        vm.read_u8(next - 1)?
+    } else {
+        vm.reg8(REG_CL)
+    } as u32
+        & 0x1f;
+    if count != 0 {
+        let width = operand_width(prefixes);
+        let bits = width as u32;
+        let dst = read_rmv(vm, &modrm, prefixes)?;
+        let src = regv(vm, modrm.reg, prefixes);
+        let (result, cf) = if ext < 0xA8 {
+            let wide = (((dst as u128) << bits) | src as u128) << count;
+            ((wide >> bits) as u32, (wide >> (bits * 2)) & 1 != 0)
+        } else {
+            let wide = ((src as u64) << bits) | dst as u64;
+            ((wide >> count) as u32, (wide >> (count - 1)) & 1 != 0)
+        };
+        let result = result & width_mask(width);
+        let of = if count == 1 {
+            ((result ^ dst) >> (bits - 1)) & 1 != 0
+        } else {
+            vm.of()
+        };
+        vm.set_result_flags(width, result, cf, of);
+        write_rmv(vm, &modrm, prefixes, result)?;
+    }
+    vm.set_eip(next);
+    Ok(())
+}
@@ -0,0 +1,193 @@
+//! Conversions between integers, single and double precision.
+
+use crate::vm::{Mxcsr, Rounding, Vm, VmError};
+
+use super::float::{round, Precision, IE, PE};
+use super::{lane, set_lane, Mandatory, Operands, Rm};
+
+/// The integer indefinite stored for NaNs and out-of-range values.
+const INTEGER_INDEFINITE: u32 = 0x8000_0000;
+
+/// A float lane to a signed 32-bit integer, rounding as `rounding` says.
+fn to_int(bits: u64, precision: Precision, rounding: Rounding, mxcsr: Mxcsr) -> (u32, u32) {
+    if precision.is_nan(bits) {
+        return (INTEGER_INDEFINITE, IE);
+    }
+    let mut flags = 0;
+    let value = precision.read(bits, mxcsr, &mut flags);
+    let integral = match rounding {
+        Rounding::Nearest => value.round_ties_even(),
+        Rounding::Down => value.floor(),
+        Rounding::Up => value.ceil(),
+        Rounding::Zero => value.trunc(),
+    };
+    if !(i32::MIN as f64..=i32::MAX as f64).contains(&integral) {
+        return (INTEGER_INDEFINITE, flags | IE);
+    }
+    if integral != value {
+        flags |= PE;
+    }
+    (integral as i32 as u32, flags)
+}
+
+/// A signed 32-bit integer to `precision`; only single precision can round.
+fn from_int(value: u32, precision: Precision, mxcsr: Mxcsr) -> (u64, u32) {
+    round(value as i32 as f64, 0.0, precision, mxcsr)
+}
+
+/// CVTSS2SD/CVTPS2PD: exact, apart from quieting signaling NaNs.
+fn widen(bits: u64, mxcsr: Mxcsr) -> (u64, u32) {
+    let single = Precision::Single;
+    if single.is_nan(bits) {
+        let flags = if single.is_signaling(bits) { IE } else { 0 };
+        let sign = (bits & single.sign_bit()) << 32;
+        let payload = (bits & 0x003F_FFFF) << 29;
+        return (sign | 0x7FF8_0000_0000_0000 | payload, flags);
+    }
+    let mut flags = 0;
+    let value = single.read(bits, mxcsr, &mut flags);
+    (value.to_bits(), flags)
+}
+
+/// CVTSD2SS/CVTPD2PS.
+fn narrow(bits: u64, mxcsr: Mxcsr) -> (u64, u32) {
+    let double = Precision::Double;
+    if double.is_nan(bits) {
+        let flags = if double.is_signaling(bits) { IE } else { 0 };
+        let sign = (bits >> 32) & 0x8000_0000;
+        let payload = (bits >> 29) & 0x003F_FFFF;
+        return (sign | 0x7FC0_0000 | payload, flags);
+    }
+    let mut flags = 0;
+    let value = double.read(bits, mxcsr, &mut flags);
+    if value.is_infinite() {
+        return (Precision::Single.bits(value), flags);
+    }
+    let (result, round_flags) = round(value, 0.0, Precision::Single, mxcsr);
+    (result, flags | round_flags)
+}
+
+/// The general-purpose source of CVTSI2SS/CVTSI2SD.
+fn read_r32(vm: &Vm, ops: &Operands) -> Result<u32, VmError> {
+    match ops.rm {
+        Rm::Reg(index) => Ok(vm.reg32(index)),
+        Rm::Mem(addr) => vm.read_u32(addr),
+    }
+}
+
+pub(super) fn exec(vm: &mut Vm, ext: u8, ops: &Operands) -> Result<bool, VmError> {
+    let mxcsr = vm.mxcsr();
+    let scalar = match ops.prefix {
+        Mandatory::Rep => Some(Precision::Single),
+        Mandatory::Repne => Some(Precision::Double),
+        _ => None,
+    };
+    match (ext, scalar) {
+        // CVTSI2SS/CVTSI2SD
+        (0x2A, Some(precision)) => {
+            let (value, flags) = from_int(read_r32(vm, ops)?, precision, mxcsr);
+            vm.simd_raise(flags, ops.address)?;
+            let mut dst = vm.xmm(ops.reg);
+            set_lane(&mut dst, precision.width(), 0, value);
+            vm.set_xmm(ops.reg, dst);
+        }
+        // CVTTSS2SI/CVTTSD2SI and CVTSS2SI/CVTSD2SI
+        (0x2C | 0x2D, Some(precision)) => {
+            let width = precision.width();
+            let bits = lane(&ops.src_low(vm, width)?, width, 0);
+            let rounding = if ext == 0x2C {
+                Rounding::Zero
+            } else {
+                mxcsr.rounding()
+            };
+            let (value, flags) = to_int(bits, precision, rounding, mxcsr);
+            vm.simd_raise(flags, ops.address)?;
+            vm.set_reg32(ops.reg, value);
+        }
+        (0x5A, _) => {
+            let convert = |bits| match ops.prefix {
+                Mandatory::None | Mandatory::Rep => widen(bits, mxcsr),
+                _ => narrow(bits, mxcsr),
+            };
+            let (src, in_width, out_width, count) = match ops.prefix {
+                Mandatory::None => (ops.src_low(vm, 8)?, 4, 8, 2),
+                Mandatory::OpSize => (ops.src(vm)?, 8, 4, 2),
+                Mandatory::Rep => (ops.src_low(vm, 4)?, 4, 8, 1),
+                Mandatory::Repne => (ops.src_low(vm, 8)?, 8, 4, 1),
+            };
+            // Packed forms replace the whole register; scalar forms only the low lane.
+            let mut out = if scalar.is_some() {
+                vm.xmm(ops.reg)
+            } else {
+                [0u8; 16]
+            };
+            let mut flags = 0;
+            for index in 0..count {
+                let (value, lane_flags) = convert(lane(&src, in_width, index));
+                set_lane(&mut out, out_width, index, value);
+                flags |= lane_flags;
+            }
+            vm.simd_raise(flags, ops.address)?;
+            vm.set_xmm(ops.reg, out);
+        }
+        // CVTDQ2PS, CVTPS2DQ, CVTTPS2DQ
+        (0x5B, _) => {
+            let src = ops.src(vm)?;
+            let rounding = if ops.prefix == Mandatory::Rep {
+                Rounding::Zero
+            } else {
+                mxcsr.rounding()
+            };
+            let mut out = [0u8; 16];
+            let mut flags = 0;
+            for index in 0..4 {
+                let bits = lane(&src, 4, index);
+                let (value, lane_flags) = match ops.prefix {
+                    Mandatory::None => from_int(bits as u32, Precision::Single, mxcsr),
+                    Mandatory::OpSize | Mandatory::Rep => {
+                        let (value, lane_flags) = to_int(bits, Precision::Single, rounding, mxcsr);
+                        (value as u64, lane_flags)
+                    }
+                    Mandatory::Repne => return Ok(false),
+                };
+                set_lane(&mut out, 4, index, value);
+                flags |= lane_flags;
+            }
+            vm.simd_raise(flags, ops.address)?;
+            vm.set_xmm(ops.reg, out);
+        }
+        // CVTTPD2DQ (66), CVTDQ2PD (F3), CVTPD2DQ (F2)
+        (0xE6, _) => {
+            let mut out = [0u8; 16];
+            let mut flags = 0;
+            match ops.prefix {
+                Mandatory::Rep => {
+                    let src = ops.src_low(vm, 8)?;
+                    for index in 0..2 {
+                        let value = lane(&src, 4, index) as u32 as i32 as f64;
+                        set_lane(&mut out, 8, index, value.to_bits());
+                    }
+                }
+                Mandatory::OpSize | Mandatory::Repne => {
+                    let src = ops.src(vm)?;
+                    let rounding = if ops.prefix == Mandatory::OpSize {
+                        Rounding::Zero
+                    } else {
+                        mxcsr.rounding()
+                    };
+                    for index in 0..2 {
+                        let bits = lane(&src, 8, index);
+                        let (value, lane_flags) = to_int(bits, Precision::Double, rounding, mxcsr);
+                        set_lane(&mut out, 4, index, value as u64);
+                        flags |= lane_flags;
+                    }
+                }
+                Mandatory::None => return Ok(false),
+            }
+            vm.simd_raise(flags, ops.address)?;
+            vm.set_xmm(ops.reg, out);
+        }
+        _ => return Ok(false),
+    }
+    Ok(true)
+}
@@ -0,0 +1,451 @@
+//! Floating-point arithmetic, comparison and logic on packed and scalar values.
+//!
+//! Lanes are computed with the host's round-to-nearest `f64` arithmetic. The exact
+//! rounding error of each operation (from an FMA or a two-sum) tells which way the
+//! result was rounded, which is enough to derive the MXCSR exception flags and the
+//! directed rounding modes.
+
+use crate::vm::{Mxcsr, Rounding, Vm, VmError};
+
+use super::{lane, set_lane, Mandatory, Operands};
+
+pub(super) const IE: u32 = 0x01;
+pub(super) const DE: u32 = 0x02;
+pub(super) const ZE: u32 = 0x04;
+pub(super) const OE: u32 = 0x08;
+pub(super) const UE: u32 = 0x10;
+pub(super) const PE: u32 = 0x20;
+
+// EFLAGS bits COMISS/UCOMISS report the result in.
+const ZF: u8 = 1 << 6;
+const PF: u8 = 1 << 2;
+const CF: u8 = 1 << 0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum Precision {
+    Single,
+    Double,
+}
+
+impl Precision {
+    pub(super) fn width(self) -> usize {
+        match self {
+            Precision::Single => 4,
+            Precision::Double => 8,
+        }
+    }
+
+    fn exponent_mask(self) -> u64 {
+        match self {
+            Precision::Single => 0x7F80_0000,
+            Precision::Double => 0x7FF0_0000_0000_0000,
+        }
+    }
+
+    fn fraction_mask(self) -> u64 {
+        match self {
+            Precision::Single => 0x007F_FFFF,
+            Precision::Double => 0x000F_FFFF_FFFF_FFFF,
+        }
+    }
+
+    fn quiet_bit(self) -> u64 {
+        match self {
+            Precision::Single => 1 << 22,
+            Precision::Double => 1 << 51,
+        }
+    }
+
+    pub(super) fn sign_bit(self) -> u64 {
+        match self {
+            Precision::Single => 1 << 31,
+            Precision::Double => 1 << 63,
+        }
+    }
+
+    pub(super) fn is_nan(self, bits: u64) -> bool {
+        bits & self.exponent_mask() == self.exponent_mask() && bits & self.fraction_mask() != 0
+    }
+
+    pub(super) fn is_signaling(self, bits: u64) -> bool {
+        self.is_nan(bits) && bits & self.quiet_bit() == 0
+    }
+
+    pub(super) fn quiet(self, bits: u64) -> u64 {
+        bits | self.quiet_bit()
+    }
+
+    /// The default NaN produced by invalid operations.
+    pub(super) fn indefinite(self) -> u64 {
+        self.sign_bit() | self.exponent_mask() | self.quiet_bit()
+    }
+
+    fn is_denormal(self, bits: u64) -> bool {
+        bits & self.exponent_mask() == 0 && bits & self.fraction_mask() != 0
+    }
+
+    fn max(self) -> f64 {
+        match self {
+            Precision::Single => f32::MAX as f64,
+            Precision::Double => f64::MAX,
+        }
+    }
+
+    fn min_normal(self) -> f64 {
+        match self {
+            Precision::Single => f32::MIN_POSITIVE as f64,
+            Precision::Double => f64::MIN_POSITIVE,
+        }
+    }
+
+    fn next_up(self, value: f64) -> f64 {
+        match self {
+            Precision::Single => (value as f32).next_up() as f64,
+            Precision::Double => value.next_up(),
+        }
+    }
+
+    fn next_down(self, value: f64) -> f64 {
+        match self {
+            Precision::Single => (value as f32).next_down() as f64,
+            Precision::Double => value.next_down(),
+        }
+    }
+
+    /// The bit pattern of a value already representable in this precision.
+    pub(super) fn bits(self, value: f64) -> u64 {
+        match self {
+            Precision::Single => (value as f32).to_bits() as u64,
+            Precision::Double => value.to_bits(),
+        }
+    }
+
+    /// A non-NaN operand as `f64`. Denormals raise DE, or read as zero under DAZ.
+    pub(super) fn read(self, bits: u64, mxcsr: Mxcsr, flags: &mut u32) -> f64 {
+        let bits = if self.is_denormal(bits) {
+            if mxcsr.denormals_are_zero() {
+                bits & self.sign_bit()
+            } else {
+                *flags |= DE;
+                bits
+            }
+        } else {
+            bits
+        };
+        match self {
+            Precision::Single => f32::from_bits(bits as u32) as f64,
+            Precision::Double => f64::from_bits(bits),
+        }
+    }
+}
+
+/// Round `value`, whose rounding error against the exact result is `err`, to
+/// `precision` under the MXCSR rounding mode. Only the sign of `err` matters.
+pub(super) fn round(value: f64, err: f64, precision: Precision, mxcsr: Mxcsr) -> (u64, u32) {
+    let (mut value, mut err) = (value, err);
+    if precision == Precision::Single {
+        let single = value as f32 as f64;
+        if single != value {
+            err = value - single;
+        }
+        value = single;
+    }
+    let rounding = mxcsr.rounding();
+    if value.is_infinite() {
+        return (overflow(value, precision, rounding), OE | PE);
+    }
+    if err == 0.0 || err.is_nan() {
+        return (precision.bits(value), 0);
+    }
+    let toward_zero = (value > 0.0 && err < 0.0) || (value < 0.0 && err > 0.0);
+    value = match rounding {
+        Rounding::Nearest => value,
+        Rounding::Down if err < 0.0 => precision.next_down(value),
+        Rounding::Up if err > 0.0 => precision.next_up(value),
+        Rounding::Zero if toward_zero && value > 0.0 => precision.next_down(value),
+        Rounding::Zero if toward_zero => precision.next_up(value),
+        _ => value,
+    };
+    if value.is_infinite() {
+        return (precision.bits(value), OE | PE);
+    }
+    let mut flags = PE;
+    if value.abs() < precision.min_normal() {
+        flags |= UE;
+        if mxcsr.flush_to_zero() && mxcsr.masked(UE) {
+            value = 0.0f64.copysign(value);
+        }
+    }
+    (precision.bits(value), flags)
+}
+
+// An overflow under round-to-nearest; the directed modes stop at the largest
+// finite value when rounding towards zero.
+fn overflow(value: f64, precision: Precision, rounding: Rounding) -> u64 {
+    let negative = value < 0.0;
+    let infinite = matches!(
+        (rounding, negative),
+        (Rounding::Nearest, _) | (Rounding::Up, false) | (Rounding::Down, true)
+    );
+    if infinite {
+        precision.bits(value)
+    } else {
+        precision.bits(precision.max().copysign(value))
+    }
+}
+
+/// The sign of `numerator / denominator` as ±1, or zero.
+fn sign_of_ratio(numerator: f64, denominator: f64) -> f64 {
+    if numerator == 0.0 {
+        0.0
+    } else if (numerator > 0.0) == (denominator > 0.0) {
+        1.0
+    } else {
+        -1.0
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Arith {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Min,
+    Max,
+    Sqrt,
+}
+
+/// One lane: `a op b` with `a` the destination, or `op b` for square root.
+fn arith(op: Arith, a: u64, b: u64, precision: Precision, mxcsr: Mxcsr) -> (u64, u32) {
+    if matches!(op, Arith::Min | Arith::Max) {
+        return min_max(op, a, b, precision, mxcsr);
+    }
+    let a = if op == Arith::Sqrt { b } else { a };
+    if precision.is_nan(a) || precision.is_nan(b) {
+        let invalid = precision.is_signaling(a) || precision.is_signaling(b);
+        let value = if precision.is_nan(a) { a } else { b };
+        return (precision.quiet(value), if invalid { IE } else { 0 });
+    }
+    let mut flags = 0;
+    let x = precision.read(a, mxcsr, &mut flags);
+    let y = precision.read(b, mxcsr, &mut flags);
+    let (value, err) = match op {
+        Arith::Add | Arith::Sub => {
+            let y = if op == Arith::Sub { -y } else { y };
+            let sum = x + y;
+            let virtual_y = sum - x;
+            (sum, (x - (sum - virtual_y)) + (y - virtual_y))
+        }
+        Arith::Mul => {
+            let product = x * y;
+            (product, x.mul_add(y, -product))
+        }
+        Arith::Div => {
+            if y == 0.0 && x.is_finite() && x != 0.0 {
+                let infinity = f64::INFINITY.copysign(x) * 1.0f64.copysign(y);
+                return (precision.bits(infinity), flags | ZE);
+            }
+            let quotient = x / y;
+            (quotient, sign_of_ratio((-quotient).mul_add(y, x), y))
+        }
+        _ => {
+            if x < 0.0 {
+                return (precision.indefinite(), flags | IE);
+            }
+            // x - root² has the sign of the rounding error.
+            let root = x.sqrt();
+            (root, (-root).mul_add(root, x))
+        }
+    };
+    if value.is_nan() {
+        return (precision.indefinite(), flags | IE);
+    }
+    if x.is_infinite() || y.is_infinite() {
+        return (precision.bits(value), flags);
+    }
+    let (bits, round_flags) = round(value, err, precision, mxcsr);
+    (bits, flags | round_flags)
+}
+
+// MINPS/MAXPS return the source when either operand is a NaN or both are zero.
+fn min_max(op: Arith, a: u64, b: u64, precision: Precision, mxcsr: Mxcsr) -> (u64, u32) {
+    if precision.is_nan(a) || precision.is_nan(b) {
+        return (b, IE);
+    }
+    let mut flags = 0;
+    let x = precision.read(a, mxcsr, &mut flags);
+    let y = precision.read(b, mxcsr, &mut flags);
+    let take_a = if op == Arith::Min { x < y } else { x > y };
+    (if take_a { a } else { b }, flags)
+}
+
+/// CMPPS predicates 0-7; `true` for an all-ones lane.
+fn compare(predicate: u8, a: u64, b: u64, precision: Precision, mxcsr: Mxcsr) -> (bool, u32) {
+    let unordered = precision.is_nan(a) || precision.is_nan(b);
+    let signaling = precision.is_signaling(a) || precision.is_signaling(b);
+    // The ordered relations (LT, LE and their negations) also signal on quiet NaNs.
+    let relational = matches!(predicate & 7, 1 | 2 | 5 | 6);
+    if unordered {
+        let flags = if signaling || relational { IE } else { 0 };
+        return (matches!(predicate & 7, 3..=6), flags);
+    }
+    let mut flags = 0;
+    let x = precision.read(a, mxcsr, &mut flags);
+    let y = precision.read(b, mxcsr, &mut flags);
+    let result = match predicate & 7 {
+        0 => x == y,
+        1 => x < y,
+        2 => x <= y,
+        3 => false,
+        4 => x != y,
+        5 => x >= y,
+        6 => x > y,
+        _ => true,
+    };
+    (result, flags)
+}
+
+fn form(prefix: Mandatory) -> (Precision, bool) {
+    match prefix {
+        Mandatory::None => (Precision::Single, false),
+        Mandatory::OpSize => (Precision::Double, false),
+        Mandatory::Rep => (Precision::Single, true),
+        Mandatory::Repne => (Precision::Double, true),
+    }
+}
+
+/// Apply `f` to each lane of the destination and source; scalar forms only touch
+/// the low lane. Nothing is written when an unmasked exception is raised.
+fn lanes(
+    vm: &mut Vm,
+    ops: &Operands,
+    precision: Precision,
+    scalar: bool,
+    f: impl Fn(u64, u64) -> (u64, u32),
+) -> Result<bool, VmError> {
+    let width = precision.width();
+    let dst = vm.xmm(ops.reg);
+    let src = if scalar {
+        ops.src_low(vm, width)?
+    } else {
+        ops.src(vm)?
+    };
+    let count = if scalar { 1 } else { 16 / width };
+    let mut out = dst;
+    let mut flags = 0;
+    for index in 0..count {
+        let (value, lane_flags) = f(lane(&dst, width, index), lane(&src, width, index));
+        set_lane(&mut out, width, index, value);
+        flags |= lane_flags;
+    }
+    vm.simd_raise(flags, ops.address)?;
+    vm.set_xmm(ops.reg, out);
+    Ok(true)
+}
+
+pub(super) fn exec(vm: &mut Vm, ext: u8, ops: &Operands) -> Result<bool, VmError> {
+    let (precision, scalar) = form(ops.prefix);
+    let mxcsr = vm.mxcsr();
+    let op = match ext {
+        0x51 => Arith::Sqrt,
+        0x58 => Arith::Add,
+        0x59 => Arith::Mul,
+        0x5C => Arith::Sub,
+        0x5D => Arith::Min,
+        0x5E => Arith::Div,
+        0x5F => Arith::Max,
+        0x52 | 0x53 if precision == Precision::Single => {
+            return lanes(vm, ops, precision, scalar, |_, b| {
+                (reciprocal(b, ext == 0x52), 0)
+            });
+        }
+        0x54..=0x57 if !scalar => return logic(vm, ext, ops),
+        0x2E | 0x2F if !scalar => return comis(vm, ext == 0x2F, precision, ops),
+        0xC2 => {
+            let predicate = ops.imm;
+            let ones = if precision == Precision::Single {
+                0xFFFF_FFFF
+            } else {
+                u64::MAX
+            };
+            return lanes(vm, ops, precision, scalar, |a, b| {
+                let (result, flags) = compare(predicate, a, b, precision, mxcsr);
+                (if result { ones } else { 0 }, flags)
+            });
+        }
+        _ => return Ok(false),
+    };
+    lanes(vm, ops, precision, scalar, |a, b| {
+        arith(op, a, b, precision, mxcsr)
+    })
+}
+
+/// RCPPS/RSQRTPS. These raise no exceptions and flush denormal inputs to zero;
+/// the exact result stands in for the hardware's 12-bit approximation.
+fn reciprocal(bits: u64, square_root: bool) -> u64 {
+    let precision = Precision::Single;
+    if precision.is_nan(bits) {
+        return precision.quiet(bits);
+    }
+    let value = if precision.is_denormal(bits) {
+        0.0f32.copysign(f32::from_bits(bits as u32))
+    } else {
+        f32::from_bits(bits as u32)
+    };
+    let result = if !square_root {
+        1.0 / value
+    } else if value < 0.0 {
+        return precision.indefinite();
+    } else {
+        1.0 / value.sqrt()
+    };
+    result.to_bits() as u64
+}
+
+/// ANDPS/ANDNPS/ORPS/XORPS and their PD forms.
+fn logic(vm: &mut Vm, ext: u8, ops: &Operands) -> Result<bool, VmError> {
+    let dst = u128::from_le_bytes(vm.xmm(ops.reg));
+    let src = u128::from_le_bytes(ops.src(vm)?);
+    let value = match ext {
+        0x54 => dst & src,
+        0x55 => !dst & src,
+        0x56 => dst | src,
+        _ => dst ^ src,
+    };
+    vm.set_xmm(ops.reg, value.to_le_bytes());
+    Ok(true)
+}
+
+/// COMISS/UCOMISS/COMISD/UCOMISD: ZF, PF and CF from the low lanes.
+fn comis(
+    vm: &mut Vm,
+    signal_qnan: bool,
+    precision: Precision,
+    ops: &Operands,
+) -> Result<bool, VmError> {
+    let width = precision.width();
+    let a = lane(&vm.xmm(ops.reg), width, 0);
+    let b = lane(&ops.src_low(vm, width)?, width, 0);
+    let mxcsr = vm.mxcsr();
+    let (eflags, flags) = if precision.is_nan(a) || precision.is_nan(b) {
+        let invalid = signal_qnan || precision.is_signaling(a) || precision.is_signaling(b);
+        (ZF | PF | CF, if invalid { IE } else { 0 })
+    } else {
+        let mut flags = 0;
+        let x = precision.read(a, mxcsr, &mut flags);
+        let y = precision.read(b, mxcsr, &mut flags);
+        let eflags = if x < y {
+            CF
+        } else if x == y {
+            ZF
+        } else {
+            0
+        };
+        (eflags, flags)
+    };
+    vm.simd_raise(flags, ops.address)?;
+    vm.set_low_flags(eflags);
+    vm.set_of(false);
+    Ok(true)
+}
@@ -0,0 +1,320 @@
+//! SSE2 packed integer arithmetic, logic, comparisons, packs and shifts.
+//!
+//! Every form here needs the 66 prefix; without it the opcodes address MMX
+//! registers.
+
+use crate::vm::{Vm, VmError};
+
+use super::mov::interleave;
+use super::{lane, map_lanes, set_lane, Mandatory, Operands, Rm, Xmm};
+
+/// Sign-extend the low `width` bytes of `value`.
+fn signed(value: u64, width: usize) -> i64 {
+    let shift = 64 - width * 8;
+    ((value << shift) as i64) >> shift
+}
+
+fn lane_mask(width: usize) -> u64 {
+    u64::MAX >> (64 - width * 8)
+}
+
+fn saturate_signed(value: i64, width: usize) -> u64 {
+    let max = (lane_mask(width) >> 1) as i64;
+    value.clamp(-max - 1, max) as u64 & lane_mask(width)
+}
+
+fn saturate_unsigned(value: i64, width: usize) -> u64 {
+    value.clamp(0, lane_mask(width) as i64) as u64
+}
+
+/// PACKSSWB/PACKSSDW/PACKUSWB: narrow `a` then `b` into one register.
+fn pack(a: &Xmm, b: &Xmm, width: usize, unsigned: bool) -> Xmm {
+    let count = 16 / width;
+    let mut out = [0u8; 16];
+    for (half, src) in [a, b].into_iter().enumerate() {
+        for index in 0..count {
+            let value = signed(lane(src, width, index), width);
+            let narrow = if unsigned {
+                saturate_unsigned(value, width / 2)
+            } else {
+                saturate_signed(value, width / 2)
+            };
+            set_lane(&mut out, width / 2, half * count + index, narrow);
+        }
+    }
+    out
+}
+
+enum Shift {
+    Left,
+    Right,
+    Arithmetic,
+}
+
+fn shift_lanes(value: &Xmm, width: usize, kind: Shift, count: u64) -> Xmm {
+    let bits = (width * 8) as u64;
+    let mut out = [0u8; 16];
+    for index in 0..16 / width {
+        let lane_value = lane(value, width, index);
+        let shifted = match kind {
+            Shift::Left if count < bits => lane_value << count,
+            Shift::Right if count < bits => lane_value >> count,
+            Shift::Left | Shift::Right => 0,
+            Shift::Arithmetic => (signed(lane_value, width) >> count.min(bits - 1)) as u64,
+        };
+        set_lane(&mut out, width, index, shifted & lane_mask(width));
+    }
+    out
+}
+
+/// PSRLDQ/PSLLDQ: byte shifts of the whole register.
+fn shift_bytes(value: &Xmm, count: u8, left: bool) -> Xmm {
+    let count = count.min(16) as usize;
+    let mut out = [0u8; 16];
+    if left {
+        out[count..].copy_from_slice(&value[..16 - count]);
+    } else {
+        out[..16 - count].copy_from_slice(&value[count..]);
+    }
+    out
+}
+
+/// The immediate shift groups 71, 72 and 73.
+fn shift_immediate(vm: &mut Vm, ext: u8, ops: &Operands) -> Result<bool, VmError> {
+    let index = ops.rm_reg()?;
+    let value = vm.xmm(index);
+    let count = ops.imm as u64;
+    let result = match (ext, ops.reg) {
+        (0x71, 2) => shift_lanes(&value, 2, Shift::Right, count),
+        (0x71, 4) => shift_lanes(&value, 2, Shift::Arithmetic, count),
+        (0x71, 6) => shift_lanes(&value, 2, Shift::Left, count),
+        (0x72, 2) => shift_lanes(&value, 4, Shift::Right, count),
+        (0x72, 4) => shift_lanes(&value, 4, Shift::Arithmetic, count),
+        (0x72, 6) => shift_lanes(&value, 4, Shift::Left, count),
+        (0x73, 2) => shift_lanes(&value, 8, Shift::Right, count),
+        (0x73, 3) => shift_bytes(&value, ops.imm, false),
+        (0x73, 6) => shift_lanes(&value, 8, Shift::Left, count),
+        (0x73, 7) => shift_bytes(&value, ops.imm, true),
+        _ => return Ok(false),
+    };
+    vm.set_xmm(index, result);
+    Ok(true)
+}
+
+/// A lane operation; the third argument is the lane width in bytes.
+type LaneOp = fn(u64, u64, usize) -> u64;
+
+/// Lane-wise operations with the lane width and the operation to apply.
+fn binary(ext: u8) -> Option<(usize, LaneOp)> {
+    fn add(a: u64, b: u64, _: usize) -> u64 {
+        a.wrapping_add(b)
+    }
+    fn sub(a: u64, b: u64, _: usize) -> u64 {
+        a.wrapping_sub(b)
+    }
+    fn adds(a: u64, b: u64, width: usize) -> u64 {
+        saturate_signed(signed(a, width) + signed(b, width), width)
+    }
+    fn subs(a: u64, b: u64, width: usize) -> u64 {
+        saturate_signed(signed(a, width) - signed(b, width), width)
+    }
+    fn addus(a: u64, b: u64, width: usize) -> u64 {
+        saturate_unsigned((a + b) as i64, width)
+    }
+    fn subus(a: u64, b: u64, width: usize) -> u64 {
+        saturate_unsigned(a as i64 - b as i64, width)
+    }
+    fn cmpeq(a: u64, b: u64, _: usize) -> u64 {
+        if a == b {
+            u64::MAX
+        } else {
+            0
+        }
+    }
+    fn cmpgt(a: u64, b: u64, width: usize) -> u64 {
+        if signed(a, width) > signed(b, width) {
+            u64::MAX
+        } else {
+            0
+        }
+    }
+    fn min_unsigned(a: u64, b: u64, _: usize) -> u64 {
+        a.min(b)
+    }
+    fn max_unsigned(a: u64, b: u64, _: usize) -> u64 {
+        a.max(b)
+    }
+    fn min_signed(a: u64, b: u64, width: usize) -> u64 {
+        if signed(a, width) < signed(b, width) {
+            a
+        } else {
+            b
+        }
+    }
+    fn max_signed(a: u64, b: u64, width: usize) -> u64 {
+        if signed(a, width) > signed(b, width) {
+            a
+        } else {
+            b
+        }
+    }
+    fn average(a: u64, b: u64, _: usize) -> u64 {
+        (a + b + 1) >> 1
+    }
+    fn mullo(a: u64, b: u64, _: usize) -> u64 {
+        a.wrapping_mul(b)
+    }
+    fn mulhi_unsigned(a: u64, b: u64, width: usize) -> u64 {
+        (a * b) >> (width * 8)
+    }
+    fn mulhi_signed(a: u64, b: u64, width: usize) -> u64 {
+        ((signed(a, width) * signed(b, width)) >> (width * 8)) as u64
+    }
+    fn and(a: u64, b: u64, _: usize) -> u64 {
+        a & b
+    }
+    fn andn(a: u64, b: u64, _: usize) -> u64 {
+        !a & b
+    }
+    fn or(a: u64, b: u64, _: usize) -> u64 {
+        a | b
+    }
+    fn xor(a: u64, b: u64, _: usize) -> u64 {
+        a ^ b
+    }
+
+    let op: (usize, LaneOp) = match ext {
+        0x64 => (1, cmpgt),
+        0x65 => (2, cmpgt),
+        0x66 => (4, cmpgt),
+        0x74 => (1, cmpeq),
+        0x75 => (2, cmpeq),
+        0x76 => (4, cmpeq),
+        0xD4 => (8, add),
+        0xD5 => (2, mullo),
+        0xD8 => (1, subus),
+        0xD9 => (2, subus),
+        0xDA => (1, min_unsigned),
+        0xDB => (8, and),
+        0xDC => (1, addus),
+        0xDD => (2, addus),
+        0xDE => (1, max_unsigned),
+        0xDF => (8, andn),
+        0xE0 => (1, average),
+        0xE3 => (2, average),
+        0xE4 => (2, mulhi_unsigned),
+        0xE5 => (2, mulhi_signed),
+        0xE8 => (1, subs),
+        0xE9 => (2, subs),
+        0xEA => (2, min_signed),
+        0xEB => (8, or),
+        0xEC => (1, adds),
+        0xED => (2, adds),
+        0xEE => (2, max_signed),
+        0xEF => (8, xor),
+        0xF8 => (1, sub),
+        0xF9 => (2, sub),
+        0xFA => (4, sub),
+        0xFB => (8, sub),
+        0xFC => (1, add),
+        0xFD => (2, add),
+        0xFE => (4, add),
+        _ => return None,
+    };
+    Some(op)
+}
+
+pub(super) fn exec(vm: &mut Vm, ext: u8, ops: &Operands) -> Result<bool, VmError> {
+    if ops.prefix != Mandatory::OpSize {
+        return Ok(false);
+    }
+    if matches!(ext, 0x71..=0x73) {
+        return shift_immediate(vm, ext, ops);
+    }
+    if let Some((width, op)) = binary(ext) {
+        let dst = vm.xmm(ops.reg);
+        let src = ops.src(vm)?;
+        let mask = lane_mask(width);
+        let value = map_lanes(&dst, &src, width, |a, b| op(a, b, width) & mask);
+        vm.set_xmm(ops.reg, value);
+        return Ok(true);
+    }
+
+    let dst = vm.xmm(ops.reg);
+    let value = match ext {
+        // PUNPCKLBW/WD/DQ, PUNPCKHBW/WD/DQ, PUNPCKLQDQ/HQDQ
+        0x60..=0x62 => interleave(&dst, &ops.src(vm)?, 1 << (ext - 0x60), false),
+        0x68..=0x6A => interleave(&dst, &ops.src(vm)?, 1 << (ext - 0x68), true),
+        0x6C | 0x6D => interleave(&dst, &ops.src(vm)?, 8, ext == 0x6D),
+        // PACKSSWB, PACKUSWB, PACKSSDW
+        0x63 => pack(&dst, &ops.src(vm)?, 2, false),
+        0x67 => pack(&dst, &ops.src(vm)?, 2, true),
+        0x6B => pack(&dst, &ops.src(vm)?, 4, false),
+        // PSRLW/D/Q, PSRAW/D, PSLLW/D/Q by the low quadword of the source
+        0xD1..=0xD3 | 0xE1 | 0xE2 | 0xF1..=0xF3 => {
+            let count = lane(&ops.src(vm)?, 8, 0);
+            let (width, kind) = match ext {
+                0xD1 => (2, Shift::Right),
+                0xD2 => (4, Shift::Right),
+                0xD3 => (8, Shift::Right),
+                0xE1 => (2, Shift::Arithmetic),
+                0xE2 => (4, Shift::Arithmetic),
+                0xF1 => (2, Shift::Left),
+                0xF2 => (4, Shift::Left),
+                _ => (8, Shift::Left),
+            };
+            shift_lanes(&dst, width, kind, count)
+        }
+        // PMULUDQ: the even doublets, widened.
+        0xF4 => {
+            let src = ops.src(vm)?;
+            let mut out = [0u8; 16];
+            for index in 0..2 {
+                let product = lane(&dst, 4, index * 2) * lane(&src, 4, index * 2);
+                set_lane(&mut out, 8, index, product);
+            }
+            out
+        }
+        // PMADDWD
+        0xF5 => {
+            let src = ops.src(vm)?;
+            let mut out = [0u8; 16];
+            for index in 0..4 {
+                let pair =
+                    |word: usize| signed(lane(&dst, 2, word), 2) * signed(lane(&src, 2, word), 2);
+                let sum = pair(index * 2).wrapping_add(pair(index * 2 + 1)) as i32;
+                set_lane(&mut out, 4, index, sum as u32 as u64);
+            }
+            out
+        }
+        // PSADBW
+        0xF6 => {
+            let src = ops.src(vm)?;
+            let mut out = [0u8; 16];
+            for half in 0..2 {
+                let sum: u64 = (half * 8..half * 8 + 8)
+                    .map(|byte| dst[byte].abs_diff(src[byte]) as u64)
+                    .sum();
+                set_lane(&mut out, 8, half, sum);
+            }
+            out
+        }
+        // MASKMOVDQU: store the bytes selected by the mask's sign bits to [EDI].
+        0xF7 => {
+            let Rm::Reg(index) = ops.rm else {
+                return Ok(false);
+            };
+            let mask = vm.xmm(index);
+            let base = vm.reg32(7);
+            for (offset, byte) in dst.iter().enumerate() {
+                if mask[offset] & 0x80 != 0 {
+                    vm.write_u8(base.wrapping_add(offset as u32), *byte)?;
+                }
+            }
+            return Ok(true);
+        }
+        _ => return Ok(false),
+    };
+    vm.set_xmm(ops.reg, value);
+    Ok(true)
+}
@@ -0,0 +1,210 @@
+//! x86 SSE and SSE2 instruction handlers.
+//!
+//! Most 0F opcodes in this space select between packed single, packed double,
+//! scalar single and scalar double (or MMX and XMM integer) forms with a
+//! mandatory 66, F3 or F2 prefix. MMX register forms are not supported.
+
+mod convert;
+mod float;
+mod integer;
+mod mov;
+
+use crate::vm::{Vm, VmError};
+
+use super::core::{calc_ea, decode_modrm, Prefixes};
+
+pub(super) type Xmm = [u8; 16];
+
+/// The prefix that selects an SSE instruction form.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum Mandatory {
+    None,
+    /// 66: packed double or XMM integer.
+    OpSize,
+    /// F3: scalar single.
+    Rep,
+    /// F2: scalar double.
+    Repne,
+}
+
+impl Mandatory {
+    // F2 and F3 take precedence over 66 when several are present.
+    fn from_prefixes(prefixes: &Prefixes) -> Self {
+        if prefixes.repne {
+            Self::Repne
+        } else if prefixes.rep {
+            Self::Rep
+        } else if prefixes.operand_size_16 {
+            Self::OpSize
+        } else {
+            Self::None
+        }
+    }
+}
+
+/// The ModRM r/m operand: a register or a memory address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum Rm {
+    Reg(u8),
+    Mem(u32),
+}
+
+/// The decoded operands of one instruction.
+#[derive(Debug, Clone, Copy)]
+pub(super) struct Operands {
+    /// Start of the instruction, prefixes included; SIMD exceptions report it.
+    pub(super) address: u32,
+    pub(super) prefix: Mandatory,
+    pub(super) reg: u8,
+    pub(super) rm: Rm,
+    pub(super) imm: u8,
+}
+
+impl Operands {
+    /// The full 128-bit source operand.
+    pub(super) fn src(&self, vm: &Vm) -> Result<Xmm, VmError> {
+        self.src_low(vm, 16)
+    }
+
+    /// A source whose memory form is only `size` bytes wide; the rest reads as zero.
+    pub(super) fn src_low(&self, vm: &Vm, size: usize) -> Result<Xmm, VmError> {
+        match self.rm {
+            Rm::Reg(index) => Ok(vm.xmm(index)),
+            Rm::Mem(addr) => {
+                let mut value = [0u8; 16];
+                value[..size].copy_from_slice(&vm.read_bytes(addr, size)?);
+                Ok(value)
+            }
+        }
+    }
+
+    /// Write the low `size` bytes to memory, or the whole register.
+    pub(super) fn store(&self, vm: &mut Vm, value: &Xmm, size: usize) -> Result<(), VmError> {
+        match self.rm {
+            Rm::Reg(index) => {
+                vm.set_xmm(index, *value);
+                Ok(())
+            }
+            Rm::Mem(addr) => vm.write_bytes(addr, &value[..size]),
+        }
+    }
+
+    /// Forms that only exist with a memory operand.
+    pub(super) fn mem(&self) -> Result<u32, VmError> {
+        match self.rm {
+            Rm::Mem(addr) => Ok(addr),
+            Rm::Reg(_) => Err(VmError::UnsupportedInstruction(0)),
+        }
+    }
+
+    /// Forms that only exist with a register operand.
+    pub(super) fn rm_reg(&self) -> Result<u8, VmError> {
+        match self.rm {
+            Rm::Reg(index) => Ok(index),
+            Rm::Mem(_) => Err(VmError::UnsupportedInstruction(0)),
+        }
+    }
+}
+
+/// Lane `index` of `width` bytes, zero-extended.
+pub(super) fn lane(value: &Xmm, width: usize, index: usize) -> u64 {
+    let mut bytes = [0u8; 8];
+    bytes[..width].copy_from_slice(&value[index * width..(index + 1) * width]);
+    u64::from_le_bytes(bytes)
+}
+
+pub(super) fn set_lane(value: &mut Xmm, width: usize, index: usize, lane: u64) {
+    value[index * width..(index + 1) * width].copy_from_slice(&lane.to_le_bytes()[..width]);
+}
+
+/// Combine `a` and `b` lane by lane.
+pub(super) fn map_lanes(a: &Xmm, b: &Xmm, width: usize, f: impl Fn(u64, u64) -> u64) -> Xmm {
+    let mut out = [0u8; 16];
+    for index in 0..16 / width {
+        set_lane(
+            &mut out,
+            width,
+            index,
+            f(lane(a, width, index), lane(b, width, index)),
+        );
+    }
+    out
+}
+
+fn has_imm8(ext: u8) -> bool {
+    matches!(ext, 0x70..=0x73 | 0xC2 | 0xC4..=0xC6)
+}
+
+/// Opcodes after 0F handled here.
+pub(crate) fn is_sse_opcode(ext: u8) -> bool {
+    matches!(
+        ext,
+        0x10..=0x18 | 0x28..=0x2F | 0x50..=0x7F | 0xAE | 0xC2..=0xC6 | 0xD1..=0xFE
+    )
+}
+
+pub(crate) fn exec(vm: &mut Vm, cursor: u32, prefixes: Prefixes) -> Result<(), VmError> {
+    let ext = vm.read_u8(cursor + 1)?;
+    let modrm = decode_modrm(vm, cursor + 2, prefixes)?;
+    let mut next = cursor + 2 + modrm.len as u32;
+    let imm = if has_imm8(ext) {
+        next += 1;
+        vm.read_u8(next - 1)?
+    } else {
+        0
+    };
+    let rm = if modrm.mod_bits == 3 {
+        Rm::Reg(modrm.rm)
+    } else {
+        Rm::Mem(calc_ea(vm, &modrm, prefixes.segment_base)?)
+    };
+    let ops = Operands {
+        address: vm.eip(),
+        prefix: Mandatory::from_prefixes(&prefixes),
+        reg: modrm.reg,
+        rm,
+        imm,
+    };
+
+    let handled = match ext {
+        0x18 => prefetch(&ops)?,
+        0xAE => group_ae(vm, &ops)?,
+        0x2A | 0x2C | 0x2D | 0x5A | 0x5B | 0xE6 => convert::exec(vm, ext, &ops)?,
+        0x2E | 0x2F | 0x51..=0x5F | 0xC2 => float::exec(vm, ext, &ops)?,
+        0x10..=0x17 | 0x28 | 0x29 | 0x2B | 0x50 | 0x6E | 0x6F | 0x70 | 0x7E | 0x7F => {
+            mov::exec(vm, ext, &ops)?
+        }
+        0xC3..=0xC6 | 0xD6 | 0xD7 | 0xE7 => mov::exec(vm, ext, &ops)?,
+        _ => integer::exec(vm, ext, &ops)?,
+    };
+    if !handled {
+        return Err(VmError::UnsupportedInstruction(ext));
+    }
+    vm.set_eip(next);
+    Ok(())
+}
+
+/// PREFETCHNTA/T0/T1/T2 (0F 18 /0-3): hints only.
+fn prefetch(ops: &Operands) -> Result<bool, VmError> {
+    Ok(ops.prefix == Mandatory::None && ops.reg < 4 && ops.mem().is_ok())
+}
+
+/// LDMXCSR/STMXCSR and the fences (0F AE).
+fn group_ae(vm: &mut Vm, ops: &Operands) -> Result<bool, VmError> {
+    if ops.prefix != Mandatory::None {
+        return Ok(false);
+    }
+    match (ops.rm, ops.reg) {
+        (Rm::Mem(addr), 2) => {
+            let value = vm.read_u32(addr)?;
+            vm.load_mxcsr(value, ops.address)?;
+        }
+        (Rm::Mem(addr), 3) => vm.write_u32(addr, vm.mxcsr().bits())?,
+        // CLFLUSH
+        (Rm::Mem(_), 7) => {}
+        // LFENCE, MFENCE, SFENCE
+        (Rm::Reg(_), 5..=7) => {}
+        _ => return Ok(false),
+    }
+    Ok(true)
+}
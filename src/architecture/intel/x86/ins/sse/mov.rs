@@ -0,0 +1,231 @@
+//! Data movement, shuffles and mask extraction.
+
+use crate::vm::{Vm, VmError};
+
+use super::{lane, set_lane, Mandatory, Operands, Rm, Xmm};
+
+/// Replace the low `size` bytes of `dst` with those of `src`.
+fn merge_low(dst: Xmm, src: &Xmm, size: usize) -> Xmm {
+    let mut out = dst;
+    out[..size].copy_from_slice(&src[..size]);
+    out
+}
+
+fn zero_extend(src: &Xmm, size: usize) -> Xmm {
+    merge_low([0u8; 16], src, size)
+}
+
+/// MOVSS/MOVSD loads: a register source merges, a memory source zero-extends.
+fn move_scalar_load(vm: &mut Vm, ops: &Operands, size: usize) -> Result<(), VmError> {
+    let value = match ops.rm {
+        Rm::Reg(index) => merge_low(vm.xmm(ops.reg), &vm.xmm(index), size),
+        Rm::Mem(_) => ops.src_low(vm, size)?,
+    };
+    vm.set_xmm(ops.reg, value);
+    Ok(())
+}
+
+fn move_scalar_store(vm: &mut Vm, ops: &Operands, size: usize) -> Result<(), VmError> {
+    let value = vm.xmm(ops.reg);
+    match ops.rm {
+        Rm::Reg(index) => {
+            let merged = merge_low(vm.xmm(index), &value, size);
+            vm.set_xmm(index, merged);
+            Ok(())
+        }
+        Rm::Mem(addr) => vm.write_bytes(addr, &value[..size]),
+    }
+}
+
+/// UNPCKLPS/UNPCKHPS/UNPCKLPD/UNPCKHPD and the PUNPCK family: interleave the
+/// low (or high) halves of `a` and `b`.
+pub(super) fn interleave(a: &Xmm, b: &Xmm, width: usize, high: bool) -> Xmm {
+    let half = 8 / width;
+    let base = if high { half } else { 0 };
+    let mut out = [0u8; 16];
+    for index in 0..half {
+        set_lane(&mut out, width, index * 2, lane(a, width, base + index));
+        set_lane(&mut out, width, index * 2 + 1, lane(b, width, base + index));
+    }
+    out
+}
+
+/// The sign bit of each lane, lowest lane in bit 0.
+fn sign_mask(value: &Xmm, width: usize) -> u32 {
+    (0..16 / width).fold(0, |mask, index| {
+        let sign = value[index * width + width - 1] >> 7;
+        mask | ((sign as u32) << index)
+    })
+}
+
+pub(super) fn exec(vm: &mut Vm, ext: u8, ops: &Operands) -> Result<bool, VmError> {
+    let prefix = ops.prefix;
+    let packed_float = matches!(prefix, Mandatory::None | Mandatory::OpSize);
+    let integer = matches!(prefix, Mandatory::OpSize | Mandatory::Rep);
+    match ext {
+        // MOVUPS/MOVUPD/MOVSS/MOVSD
+        0x10 => match prefix {
+            Mandatory::Rep => move_scalar_load(vm, ops, 4)?,
+            Mandatory::Repne => move_scalar_load(vm, ops, 8)?,
+            _ => vm.set_xmm(ops.reg, ops.src(vm)?),
+        },
+        0x11 => match prefix {
+            Mandatory::Rep => move_scalar_store(vm, ops, 4)?,
+            Mandatory::Repne => move_scalar_store(vm, ops, 8)?,
+            _ => ops.store(vm, &vm.xmm(ops.reg), 16)?,
+        },
+        // MOVLPS/MOVLPD, MOVHLPS
+        0x12 if packed_float => {
+            let src = match ops.rm {
+                Rm::Reg(index) if prefix == Mandatory::None => {
+                    let value = vm.xmm(index);
+                    let mut high = [0u8; 16];
+                    high[..8].copy_from_slice(&value[8..]);
+                    high
+                }
+                Rm::Reg(_) => return Ok(false),
+                Rm::Mem(_) => ops.src_low(vm, 8)?,
+            };
+            vm.set_xmm(ops.reg, merge_low(vm.xmm(ops.reg), &src, 8));
+        }
+        0x13 if packed_float => {
+            let addr = ops.mem()?;
+            vm.write_bytes(addr, &vm.xmm(ops.reg)[..8])?;
+        }
+        // UNPCKLPS/UNPCKLPD, UNPCKHPS/UNPCKHPD
+        0x14 | 0x15 if packed_float => {
+            let width = if prefix == Mandatory::None { 4 } else { 8 };
+            let value = interleave(&vm.xmm(ops.reg), &ops.src(vm)?, width, ext == 0x15);
+            vm.set_xmm(ops.reg, value);
+        }
+        // MOVHPS/MOVHPD, MOVLHPS
+        0x16 if packed_float => {
+            if prefix == Mandatory::OpSize && ops.mem().is_err() {
+                return Ok(false);
+            }
+            let src = ops.src_low(vm, 8)?;
+            let mut value = vm.xmm(ops.reg);
+            value[8..].copy_from_slice(&src[..8]);
+            vm.set_xmm(ops.reg, value);
+        }
+        0x17 if packed_float => {
+            let addr = ops.mem()?;
+            vm.write_bytes(addr, &vm.xmm(ops.reg)[8..])?;
+        }
+        // MOVAPS/MOVAPD
+        0x28 if packed_float => vm.set_xmm(ops.reg, ops.src(vm)?),
+        0x29 if packed_float => ops.store(vm, &vm.xmm(ops.reg), 16)?,
+        // MOVNTPS/MOVNTPD
+        0x2B if packed_float => {
+            let addr = ops.mem()?;
+            vm.write_bytes(addr, &vm.xmm(ops.reg))?;
+        }
+        // MOVMSKPS/MOVMSKPD
+        0x50 if packed_float => {
+            let width = if prefix == Mandatory::None { 4 } else { 8 };
+            let mask = sign_mask(&vm.xmm(ops.rm_reg()?), width);
+            vm.set_reg32(ops.reg, mask);
+        }
+        // MOVD xmm, r/m32
+        0x6E if prefix == Mandatory::OpSize => {
+            let value = match ops.rm {
+                Rm::Reg(index) => vm.reg32(index),
+                Rm::Mem(addr) => vm.read_u32(addr)?,
+            };
+            let mut out = [0u8; 16];
+            out[..4].copy_from_slice(&value.to_le_bytes());
+            vm.set_xmm(ops.reg, out);
+        }
+        // MOVDQA/MOVDQU
+        0x6F if integer => vm.set_xmm(ops.reg, ops.src(vm)?),
+        0x7F if integer => ops.store(vm, &vm.xmm(ops.reg), 16)?,
+        // PSHUFD/PSHUFHW/PSHUFLW
+        0x70 if prefix != Mandatory::None => {
+            let src = ops.src(vm)?;
+            let mut out = src;
+            let (width, first, count) = match prefix {
+                Mandatory::OpSize => (4, 0, 4),
+                Mandatory::Rep => (2, 4, 4),
+                _ => (2, 0, 4),
+            };
+            for index in 0..count {
+                let select = ((ops.imm >> (index * 2)) & 3) as usize;
+                set_lane(
+                    &mut out,
+                    width,
+                    first + index,
+                    lane(&src, width, first + select),
+                );
+            }
+            vm.set_xmm(ops.reg, out);
+        }
+        // MOVD r/m32, xmm
+        0x7E if prefix == Mandatory::OpSize => {
+            let value = lane(&vm.xmm(ops.reg), 4, 0) as u32;
+            match ops.rm {
+                Rm::Reg(index) => vm.set_reg32(index, value),
+                Rm::Mem(addr) => vm.write_u32(addr, value)?,
+            }
+        }
+        // MOVQ xmm, xmm/m64
+        0x7E if prefix == Mandatory::Rep => {
+            let value = zero_extend(&ops.src_low(vm, 8)?, 8);
+            vm.set_xmm(ops.reg, value);
+        }
+        // MOVNTI m32, r32
+        0xC3 if prefix == Mandatory::None => {
+            let addr = ops.mem()?;
+            vm.write_u32(addr, vm.reg32(ops.reg))?;
+        }
+        // PINSRW
+        0xC4 if prefix == Mandatory::OpSize => {
+            let word = match ops.rm {
+                Rm::Reg(index) => vm.reg32(index) as u16,
+                Rm::Mem(addr) => vm.read_u16(addr)?,
+            };
+            let mut value = vm.xmm(ops.reg);
+            set_lane(&mut value, 2, (ops.imm & 7) as usize, word as u64);
+            vm.set_xmm(ops.reg, value);
+        }
+        // PEXTRW
+        0xC5 if prefix == Mandatory::OpSize => {
+            let word = lane(&vm.xmm(ops.rm_reg()?), 2, (ops.imm & 7) as usize);
+            vm.set_reg32(ops.reg, word as u32);
+        }
+        // SHUFPS/SHUFPD: low lanes from the destination, high lanes from the source.
+        0xC6 if packed_float => {
+            let dst = vm.xmm(ops.reg);
+            let src = ops.src(vm)?;
+            let (width, bits) = if prefix == Mandatory::None {
+                (4, 2)
+            } else {
+                (8, 1)
+            };
+            let count = 16 / width;
+            let mut out = [0u8; 16];
+            for index in 0..count {
+                let select = ((ops.imm >> (index * bits)) & ((1 << bits) - 1)) as usize;
+                let from = if index < count / 2 { &dst } else { &src };
+                set_lane(&mut out, width, index, lane(from, width, select));
+            }
+            vm.set_xmm(ops.reg, out);
+        }
+        // MOVQ xmm/m64, xmm
+        0xD6 if prefix == Mandatory::OpSize => {
+            let value = zero_extend(&vm.xmm(ops.reg), 8);
+            ops.store(vm, &value, 8)?;
+        }
+        // PMOVMSKB
+        0xD7 if prefix == Mandatory::OpSize => {
+            let mask = sign_mask(&vm.xmm(ops.rm_reg()?), 1);
+            vm.set_reg32(ops.reg, mask);
+        }
+        // MOVNTDQ
+        0xE7 if prefix == Mandatory::OpSize => {
+            let addr = ops.mem()?;
+            vm.write_bytes(addr, &vm.xmm(ops.reg))?;
+        }
+        _ => return Ok(false),
+    }
+    Ok(true)
+}
@@ -2,69 +2,145 @@
 
 use crate::vm::{Vm, VmError, REG_EBP, REG_ESP};
 
-use super::core::{decode_modrm, pack_eflags, read_rm32, write_rm32, ModRm, Prefixes};
+use super::core::{decode_modrm, read_immv, read_rmv, regv, set_regv, write_rmv, ModRm, Prefixes};
 
-pub(crate) fn push_reg(vm: &mut Vm, cursor: u32, _prefixes: Prefixes) -> Result<(), VmError> {
+// PUSH and POP move a word under the operand-size prefix, a dword otherwise.
+fn push_operand(vm: &mut Vm, prefixes: Prefixes, value: u32) -> Result<(), VmError> {
+    if prefixes.operand_size_16 {
+        vm.push16(value as u16)
+    } else {
+        vm.push(value)
+    }
+}
+
+fn pop_operand(vm: &mut Vm, prefixes: Prefixes) -> Result<u32, VmError> {
+    if prefixes.operand_size_16 {
+        Ok(vm.pop16()? as u32)
+    } else {
+        vm.pop()
+    }
+}
+
+pub(crate) fn push_reg(vm: &mut Vm, cursor: u32, prefixes: Prefixes) -> Result<(), VmError> {
     let opcode = vm.read_u8(cursor)?;
     let reg = opcode - 0x50;
-    let value = vm.reg32(reg);
-    vm.push(value)?;
+    let value = regv(vm, reg, prefixes);
+    push_operand(vm, prefixes, value)?;
     vm.set_eip(cursor + 1);
     Ok(())
 }
 
-pub(crate) fn pop_reg(vm: &mut Vm, cursor: u32, _prefixes: Prefixes) -> Result<(), VmError> {
+pub(crate) fn pop_reg(vm: &mut Vm, cursor: u32, prefixes: Prefixes) -> Result<(), VmError> {
     let opcode = vm.read_u8(cursor)?;
     let reg = opcode - 0x58;
-    let value = vm.pop()?;
-    vm.set_reg32(reg, value);
+    let value = pop_operand(vm, prefixes)?;
+    set_regv(vm, reg, prefixes, value);
     vm.set_eip(cursor + 1);
     Ok(())
 }
 
-pub(crate) fn push_imm8(vm: &mut Vm, cursor: u32, _prefixes: Prefixes) -> Result<(), VmError> {
+pub(crate) fn push_imm8(vm: &mut Vm, cursor: u32, prefixes: Prefixes) -> Result<(), VmError> {
     let imm = vm.read_u8(cursor + 1)? as i8 as i32 as u32;
-    vm.push(imm)?;
+    push_operand(vm, prefixes, imm)?;
     vm.set_eip(cursor + 2);
     Ok(())
 }
 
-pub(crate) fn push_imm32(vm: &mut Vm, cursor: u32, _prefixes: Prefixes) -> Result<(), VmError> {
-    let imm = vm.read_u32(cursor + 1)?;
-    vm.push(imm)?;
-    vm.set_eip(cursor + 5);
+pub(crate) fn push_imm32(vm: &mut Vm, cursor: u32, prefixes: Prefixes) -> Result<(), VmError> {
+    let (imm, len) = read_immv(vm, cursor + 1, prefixes)?;
+    push_operand(vm, prefixes, imm)?;
+    vm.set_eip(cursor + 1 + len);
     Ok(())
 }
 
 pub(crate) fn pop_rm32(vm: &mut Vm, cursor: u32, prefixes: Prefixes) -> Result<(), VmError> {
-    let modrm = decode_modrm(vm, cursor + 1)?;
+    let modrm = decode_modrm(vm, cursor + 1, prefixes)?;
     if modrm.reg != 0 {
         return Err(VmError::UnsupportedInstruction(0x8F));
     }
-    let value = vm.pop()?;
-    write_rm32(vm, &modrm, prefixes.segment_base, value)?;
+    let value = pop_operand(vm, prefixes)?;
+    write_rmv(vm, &modrm, prefixes, value)?;
     vm.set_eip(cursor + 1 + modrm.len as u32);
     Ok(())
 }
 
 pub(crate) fn push_rm32(vm: &mut Vm, modrm: &ModRm, prefixes: Prefixes) -> Result<(), VmError> {
-    let value = read_rm32(vm, modrm, prefixes.segment_base)?;
-    vm.push(value)?;
+    let value = read_rmv(vm, modrm, prefixes)?;
+    push_operand(vm, prefixes, value)?;
+    Ok(())
+}
+
+// PUSHF/POPF (66) move only the low word of EFLAGS.
+pub(crate) fn pushfd(vm: &mut Vm, cursor: u32, prefixes: Prefixes) -> Result<(), VmError> {
+    let flags = vm.eflags();
+    push_operand(vm, prefixes, flags)?;
+    vm.set_eip(cursor + 1);
+    Ok(())
+}
+
+pub(crate) fn popfd(vm: &mut Vm, cursor: u32, prefixes: Prefixes) -> Result<(), VmError> {
+    let mut flags = pop_operand(vm, prefixes)?;
+    if prefixes.operand_size_16 {
+        flags |= vm.eflags() & 0xFFFF_0000;
+    }
+    vm.set_eflags(flags);
+    vm.set_eip(cursor + 1);
     Ok(())
 }
 
-pub(crate) fn pushfd(vm: &mut Vm, cursor: u32, _prefixes: Prefixes) -> Result<(), VmError> {
-    let flags = pack_eflags(vm);
-    vm.push(flags)?;
+// PUSHA/POPA (66) save the 16-bit registers.
+pub(crate) fn pushad(vm: &mut Vm, cursor: u32, prefixes: Prefixes) -> Result<(), VmError> {
+    let esp = regv(vm, REG_ESP, prefixes);
+    for reg in 0..8 {
+        let value = if reg == REG_ESP {
+            esp
+        } else {
+            regv(vm, reg, prefixes)
+        };
+        push_operand(vm, prefixes, value)?;
+    }
     vm.set_eip(cursor + 1);
     Ok(())
 }
 
-pub(crate) fn leave(vm: &mut Vm, cursor: u32, _prefixes: Prefixes) -> Result<(), VmError> {
+// POPAD discards the saved ESP.
+pub(crate) fn popad(vm: &mut Vm, cursor: u32, prefixes: Prefixes) -> Result<(), VmError> {
+    for reg in (0..8).rev() {
+        let value = pop_operand(vm, prefixes)?;
+        if reg != REG_ESP {
+            set_regv(vm, reg, prefixes, value);
+        }
+    }
+    vm.set_eip(cursor + 1);
+    Ok(())
+}
+
+pub(crate) fn enter(vm: &mut Vm, cursor: u32, _prefixes: Prefixes) -> Result<(), VmError> {
+    let size = vm.read_u16(cursor + 1)? as u32;
+    let level = vm.read_u8(cursor + 3)? & 0x1F;
+    let ebp = vm.reg32(REG_EBP);
+    vm.push(ebp)?;
+    let frame = vm.reg32(REG_ESP);
+    if level > 0 {
+        // Copy the enclosing frames' pointers, then link this one.
+        for depth in 1..level as u32 {
+            let outer = vm.read_u32(ebp.wrapping_sub(depth * 4))?;
+            vm.push(outer)?;
+        }
+        vm.push(frame)?;
+    }
+    vm.set_reg32(REG_EBP, frame);
+    let esp = vm.reg32(REG_ESP).wrapping_sub(size);
+    vm.set_reg32(REG_ESP, esp);
+    vm.set_eip(cursor + 4);
+    Ok(())
+}
+
+pub(crate) fn leave(vm: &mut Vm, cursor: u32, prefixes: Prefixes) -> Result<(), VmError> {
     let ebp = vm.reg32(REG_EBP);
     vm.set_reg32(REG_ESP, ebp);
-    let value = vm.pop()?;
-    vm.set_reg32(REG_EBP, value);
+    let value = pop_operand(vm, prefixes)?;
+    set_regv(vm, REG_EBP, prefixes, value);
     vm.set_eip(cursor + 1);
     Ok(())
 }
@@ -1,12 +1,12 @@
 use crate::vm::{Vm, VmError, REG_AL, REG_EAX};
 
 use crate::architecture::intel::x86::ins::core::{
-    decode_modrm, read_rm16, read_rm32, read_rm8, update_flags_sub16, update_flags_sub32,
+    decode_modrm, operand_width, read_immv, read_rm8, read_rmv, regv, update_flags_sub,
     update_flags_sub8, ModRm, Prefixes,
 };
 
 pub(crate) fn cmp_rm8_r8(vm: &mut Vm, cursor: u32, prefixes: Prefixes) -> Result<(), VmError> {
-    let modrm = decode_modrm(vm, cursor + 1)?;
+    let modrm = decode_modrm(vm, cursor + 1, prefixes)?;
     let dst = read_rm8(vm, &modrm, prefixes.segment_base)?;
     let src = vm.reg8(modrm.reg);
     let result = dst.wrapping_sub(src);
@@ -16,24 +16,17 @@ pub(crate) fn cmp_rm8_r8(vm: &mut Vm, cursor: u32, prefixes: Prefixes) -> Result
 }
 
 pub(crate) fn cmp_rm32_r32(vm: &mut Vm, cursor: u32, prefixes: Prefixes) -> Result<(), VmError> {
-    let modrm = decode_modrm(vm, cursor + 1)?;
-    if prefixes.operand_size_16 {
-        let dst = read_rm16(vm, &modrm, prefixes.segment_base)?;
-        let src = vm.reg16(modrm.reg);
-        let result = dst.wrapping_sub(src);
-        update_flags_sub16(vm, dst, src, result);
-    } else {
-        let dst = read_rm32(vm, &modrm, prefixes.segment_base)?;
-        let src = vm.reg32(modrm.reg);
-        let result = dst.wrapping_sub(src);
-        update_flags_sub32(vm, dst, src, result);
-    }
+    let modrm = decode_modrm(vm, cursor + 1, prefixes)?;
+    let dst = read_rmv(vm, &modrm, prefixes)?;
+    let src = regv(vm, modrm.reg, prefixes);
+    let result = dst.wrapping_sub(src);
+    update_flags_sub(vm, operand_width(prefixes), dst, src, result);
     vm.set_eip(cursor + 1 + modrm.len as u32);
     Ok(())
 }
 
 pub(crate) fn cmp_r8_rm8(vm: &mut Vm, cursor: u32, prefixes: Prefixes) -> Result<(), VmError> {
-    let modrm = decode_modrm(vm, cursor + 1)?;
+    let modrm = decode_modrm(vm, cursor + 1, prefixes)?;
     let src = read_rm8(vm, &modrm, prefixes.segment_base)?;
     let dst = vm.reg8(modrm.reg);
     let result = dst.wrapping_sub(src);
@@ -43,11 +36,11 @@ pub(crate) fn cmp_r8_rm8(vm: &mut Vm, cursor: u32, prefixes: Prefixes) -> Result
 }
 
 pub(crate) fn cmp_r32_rm32(vm: &mut Vm, cursor: u32, prefixes: Prefixes) -> Result<(), VmError> {
-    let modrm = decode_modrm(vm, cursor + 1)?;
-    let src = read_rm32(vm, &modrm, prefixes.segment_base)?;
-    let dst = vm.reg32(modrm.reg);
+    let modrm = decode_modrm(vm, cursor + 1, prefixes)?;
+    let src = read_rmv(vm, &modrm, prefixes)?;
+    let dst = regv(vm, modrm.reg, prefixes);
     let result = dst.wrapping_sub(src);
-    update_flags_sub32(vm, dst, src, result);
+    update_flags_sub(vm, operand_width(prefixes), dst, src, result);
     vm.set_eip(cursor + 1 + modrm.len as u32);
     Ok(())
 }
@@ -61,12 +54,12 @@ pub(crate) fn cmp_al_imm8(vm: &mut Vm, cursor: u32, _prefixes: Prefixes) -> Resu
     Ok(())
 }
 
-pub(crate) fn cmp_eax_imm32(vm: &mut Vm, cursor: u32, _prefixes: Prefixes) -> Result<(), VmError> {
-    let imm = vm.read_u32(cursor + 1)?;
-    let dst = vm.reg32(REG_EAX);
+pub(crate) fn cmp_eax_imm32(vm: &mut Vm, cursor: u32, prefixes: Prefixes) -> Result<(), VmError> {
+    let (imm, len) = read_immv(vm, cursor + 1, prefixes)?;
+    let dst = regv(vm, REG_EAX, prefixes);
     let result = dst.wrapping_sub(imm);
-    update_flags_sub32(vm, dst, imm, result);
-    vm.set_eip(cursor + 5);
+    update_flags_sub(vm, operand_width(prefixes), dst, imm, result);
+    vm.set_eip(cursor + 1 + len);
     Ok(())
 }
 
@@ -76,9 +69,9 @@ pub(crate) fn cmp_rm32_imm(
     prefixes: Prefixes,
     imm: u32,
 ) -> Result<(), VmError> {
-    let dst = read_rm32(vm, modrm, prefixes.segment_base)?;
+    let dst = read_rmv(vm, modrm, prefixes)?;
     let result = dst.wrapping_sub(imm);
-    update_flags_sub32(vm, dst, imm, result);
+    update_flags_sub(vm, operand_width(prefixes), dst, imm, result);
     Ok(())
 }
 
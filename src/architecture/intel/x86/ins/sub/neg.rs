@@ -1,8 +1,8 @@
 use crate::vm::{Vm, VmError};
 
 use crate::architecture::intel::x86::ins::core::{
-    read_rm32, read_rm8, update_flags_sub32, update_flags_sub8, write_rm32, write_rm8, ModRm,
-    Prefixes,
+    operand_width, read_rm8, read_rmv, update_flags_sub, update_flags_sub8, write_rm8, write_rmv,
+    ModRm, Prefixes,
 };
 
 pub(crate) fn neg_rm8(vm: &mut Vm, modrm: &ModRm, prefixes: Prefixes) -> Result<(), VmError> {
@@ -14,9 +14,9 @@ pub(crate) fn neg_rm8(vm: &mut Vm, modrm: &ModRm, prefixes: Prefixes) -> Result<
 }
 
 pub(crate) fn neg_rm32(vm: &mut Vm, modrm: &ModRm, prefixes: Prefixes) -> Result<(), VmError> {
-    let value = read_rm32(vm, modrm, prefixes.segment_base)?;
-    let result = (0u32).wrapping_sub(value);
-    update_flags_sub32(vm, 0, value, result);
-    write_rm32(vm, modrm, prefixes.segment_base, result)?;
+    let value = read_rmv(vm, modrm, prefixes)?;
+    let result = 0u32.wrapping_sub(value);
+    update_flags_sub(vm, operand_width(prefixes), 0, value, result);
+    write_rmv(vm, modrm, prefixes, result)?;
     Ok(())
 }
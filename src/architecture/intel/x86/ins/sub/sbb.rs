@@ -1,50 +1,46 @@
 use crate::vm::{Vm, VmError, REG_AL, REG_EAX};
 
 use crate::architecture::intel::x86::ins::core::{
-    decode_modrm, read_rm32, read_rm8, sbb32, sbb8, update_flags_sub32_with_cf,
-    update_flags_sub8_with_cf, write_rm32, write_rm8, ModRm, Prefixes,
+    decode_modrm, operand_width, read_immv, read_rm8, read_rmv, regv, sbb, sbb8, set_regv,
+    write_rm8, write_rmv, ModRm, Prefixes,
 };
 
 pub(crate) fn sbb_rm8_r8(vm: &mut Vm, cursor: u32, prefixes: Prefixes) -> Result<(), VmError> {
-    let modrm = decode_modrm(vm, cursor + 1)?;
+    let modrm = decode_modrm(vm, cursor + 1, prefixes)?;
     let lhs = read_rm8(vm, &modrm, prefixes.segment_base)?;
     let rhs = vm.reg8(modrm.reg);
-    let (result, cf) = sbb8(vm, lhs, rhs);
+    let result = sbb8(vm, lhs, rhs);
     write_rm8(vm, &modrm, prefixes.segment_base, result)?;
-    update_flags_sub8_with_cf(vm, lhs, rhs, result, cf);
     vm.set_eip(cursor + 1 + modrm.len as u32);
     Ok(())
 }
 
 pub(crate) fn sbb_rm32_r32(vm: &mut Vm, cursor: u32, prefixes: Prefixes) -> Result<(), VmError> {
-    let modrm = decode_modrm(vm, cursor + 1)?;
-    let lhs = read_rm32(vm, &modrm, prefixes.segment_base)?;
-    let rhs = vm.reg32(modrm.reg);
-    let (result, cf) = sbb32(vm, lhs, rhs);
-    write_rm32(vm, &modrm, prefixes.segment_base, result)?;
-    update_flags_sub32_with_cf(vm, lhs, rhs, result, cf);
+    let modrm = decode_modrm(vm, cursor + 1, prefixes)?;
+    let lhs = read_rmv(vm, &modrm, prefixes)?;
+    let rhs = regv(vm, modrm.reg, prefixes);
+    let result = sbb(vm, operand_width(prefixes), lhs, rhs);
+    write_rmv(vm, &modrm, prefixes, result)?;
     vm.set_eip(cursor + 1 + modrm.len as u32);
     Ok(())
 }
 
 pub(crate) fn sbb_r8_rm8(vm: &mut Vm, cursor: u32, prefixes: Prefixes) -> Result<(), VmError> {
-    let modrm = decode_modrm(vm, cursor + 1)?;
+    let modrm = decode_modrm(vm, cursor + 1, prefixes)?;
     let lhs = vm.reg8(modrm.reg);
     let rhs = read_rm8(vm, &modrm, prefixes.segment_base)?;
-    let (result, cf) = sbb8(vm, lhs, rhs);
+    let result = sbb8(vm, lhs, rhs);
     vm.set_reg8(modrm.reg, result);
-    update_flags_sub8_with_cf(vm, lhs, rhs, result, cf);
     vm.set_eip(cursor + 1 + modrm.len as u32);
     Ok(())
 }
 
 pub(crate) fn sbb_r32_rm32(vm: &mut Vm, cursor: u32, prefixes: Prefixes) -> Result<(), VmError> {
-    let modrm = decode_modrm(vm, cursor + 1)?;
-    let lhs = vm.reg32(modrm.reg);
-    let rhs = read_rm32(vm, &modrm, prefixes.segment_base)?;
-    let (result, cf) = sbb32(vm, lhs, rhs);
-    vm.set_reg32(modrm.reg, result);
-    update_flags_sub32_with_cf(vm, lhs, rhs, result, cf);
+    let modrm = decode_modrm(vm, cursor + 1, prefixes)?;
+    let lhs = regv(vm, modrm.reg, prefixes);
+    let rhs = read_rmv(vm, &modrm, prefixes)?;
+    let result = sbb(vm, operand_width(prefixes), lhs, rhs);
+    set_regv(vm, modrm.reg, prefixes, result);
     vm.set_eip(cursor + 1 + modrm.len as u32);
     Ok(())
 }
@@ -52,20 +48,18 @@ pub(crate) fn sbb_r32_rm32(vm: &mut Vm, cursor: u32, prefixes: Prefixes) -> Resu
 pub(crate) fn sbb_al_imm8(vm: &mut Vm, cursor: u32, _prefixes: Prefixes) -> Result<(), VmError> {
     let imm = vm.read_u8(cursor + 1)?;
     let lhs = vm.reg8(REG_AL);
-    let (result, cf) = sbb8(vm, lhs, imm);
+    let result = sbb8(vm, lhs, imm);
     vm.set_reg8(REG_AL, result);
-    update_flags_sub8_with_cf(vm, lhs, imm, result, cf);
     vm.set_eip(cursor + 2);
     Ok(())
 }
 
-pub(crate) fn sbb_eax_imm32(vm: &mut Vm, cursor: u32, _prefixes: Prefixes) -> Result<(), VmError> {
-    let imm = vm.read_u32(cursor + 1)?;
-    let lhs = vm.reg32(REG_EAX);
-    let (result, cf) = sbb32(vm, lhs, imm);
-    vm.set_reg32(REG_EAX, result);
-    update_flags_sub32_with_cf(vm, lhs, imm, result, cf);
-    vm.set_eip(cursor + 5);
+pub(crate) fn sbb_eax_imm32(vm: &mut Vm, cursor: u32, prefixes: Prefixes) -> Result<(), VmError> {
+    let (imm, len) = read_immv(vm, cursor + 1, prefixes)?;
+    let lhs = regv(vm, REG_EAX, prefixes);
+    let result = sbb(vm, operand_width(prefixes), lhs, imm);
+    set_regv(vm, REG_EAX, prefixes, result);
+    vm.set_eip(cursor + 1 + len);
     Ok(())
 }
 
@@ -75,10 +69,9 @@ pub(crate) fn sbb_rm32_imm(
     prefixes: Prefixes,
     imm: u32,
 ) -> Result<(), VmError> {
-    let dst = read_rm32(vm, modrm, prefixes.segment_base)?;
-    let (result, cf) = sbb32(vm, dst, imm);
-    write_rm32(vm, modrm, prefixes.segment_base, result)?;
-    update_flags_sub32_with_cf(vm, dst, imm, result, cf);
+    let dst = read_rmv(vm, modrm, prefixes)?;
+    let result = sbb(vm, operand_width(prefixes), dst, imm);
+    write_rmv(vm, modrm, prefixes, result)?;
     Ok(())
 }
 
@@ -89,8 +82,7 @@ pub(crate) fn sbb_rm8_imm(
     imm: u8,
 ) -> Result<(), VmError> {
     let dst = read_rm8(vm, modrm, prefixes.segment_base)?;
-    let (result, cf) = sbb8(vm, dst, imm);
+    let result = sbb8(vm, dst, imm);
     write_rm8(vm, modrm, prefixes.segment_base, result)?;
-    update_flags_sub8_with_cf(vm, dst, imm, result, cf);
     Ok(())
 }
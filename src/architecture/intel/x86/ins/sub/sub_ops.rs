@@ -1,12 +1,12 @@
 use crate::vm::{Vm, VmError, REG_AL, REG_EAX};
 
 use crate::architecture::intel::x86::ins::core::{
-    decode_modrm, read_rm32, read_rm8, update_flags_sub32, update_flags_sub8, write_rm32,
-    write_rm8, ModRm, Prefixes,
+    decode_modrm, operand_width, read_immv, read_rm8, read_rmv, regv, set_regv, update_flags_sub,
+    update_flags_sub8, write_rm8, write_rmv, ModRm, Prefixes,
 };
 
 pub(crate) fn sub_rm8_r8(vm: &mut Vm, cursor: u32, prefixes: Prefixes) -> Result<(), VmError> {
-    let modrm = decode_modrm(vm, cursor + 1)?;
+    let modrm = decode_modrm(vm, cursor + 1, prefixes)?;
     let rhs = vm.reg8(modrm.reg);
     let lhs = read_rm8(vm, &modrm, prefixes.segment_base)?;
     let result = lhs.wrapping_sub(rhs);
@@ -17,18 +17,18 @@ pub(crate) fn sub_rm8_r8(vm: &mut Vm, cursor: u32, prefixes: Prefixes) -> Result
 }
 
 pub(crate) fn sub_rm32_r32(vm: &mut Vm, cursor: u32, prefixes: Prefixes) -> Result<(), VmError> {
-    let modrm = decode_modrm(vm, cursor + 1)?;
-    let rhs = vm.reg32(modrm.reg);
-    let lhs = read_rm32(vm, &modrm, prefixes.segment_base)?;
+    let modrm = decode_modrm(vm, cursor + 1, prefixes)?;
+    let rhs = regv(vm, modrm.reg, prefixes);
+    let lhs = read_rmv(vm, &modrm, prefixes)?;
     let result = lhs.wrapping_sub(rhs);
-    write_rm32(vm, &modrm, prefixes.segment_base, result)?;
-    update_flags_sub32(vm, lhs, rhs, result);
+    write_rmv(vm, &modrm, prefixes, result)?;
+    update_flags_sub(vm, operand_width(prefixes), lhs, rhs, result);
     vm.set_eip(cursor + 1 + modrm.len as u32);
     Ok(())
 }
 
 pub(crate) fn sub_r8_rm8(vm: &mut Vm, cursor: u32, prefixes: Prefixes) -> Result<(), VmError> {
-    let modrm = decode_modrm(vm, cursor + 1)?;
+    let modrm = decode_modrm(vm, cursor + 1, prefixes)?;
     let rhs = read_rm8(vm, &modrm, prefixes.segment_base)?;
     let lhs = vm.reg8(modrm.reg);
     let result = lhs.wrapping_sub(rhs);
@@ -39,12 +39,12 @@ pub(crate) fn sub_r8_rm8(vm: &mut Vm, cursor: u32, prefixes: Prefixes) -> Result
 }
 
 pub(crate) fn sub_r32_rm32(vm: &mut Vm, cursor: u32, prefixes: Prefixes) -> Result<(), VmError> {
-    let modrm = decode_modrm(vm, cursor + 1)?;
-    let rhs = read_rm32(vm, &modrm, prefixes.segment_base)?;
-    let lhs = vm.reg32(modrm.reg);
+    let modrm = decode_modrm(vm, cursor + 1, prefixes)?;
+    let rhs = read_rmv(vm, &modrm, prefixes)?;
+    let lhs = regv(vm, modrm.reg, prefixes);
     let result = lhs.wrapping_sub(rhs);
-    vm.set_reg32(modrm.reg, result);
-    update_flags_sub32(vm, lhs, rhs, result);
+    set_regv(vm, modrm.reg, prefixes, result);
+    update_flags_sub(vm, operand_width(prefixes), lhs, rhs, result);
     vm.set_eip(cursor + 1 + modrm.len as u32);
     Ok(())
 }
@@ -59,13 +59,13 @@ pub(crate) fn sub_al_imm8(vm: &mut Vm, cursor: u32, _prefixes: Prefixes) -> Resu
     Ok(())
 }
 
-pub(crate) fn sub_eax_imm32(vm: &mut Vm, cursor: u32, _prefixes: Prefixes) -> Result<(), VmError> {
-    let imm = vm.read_u32(cursor + 1)?;
-    let lhs = vm.reg32(REG_EAX);
+pub(crate) fn sub_eax_imm32(vm: &mut Vm, cursor: u32, prefixes: Prefixes) -> Result<(), VmError> {
+    let (imm, len) = read_immv(vm, cursor + 1, prefixes)?;
+    let lhs = regv(vm, REG_EAX, prefixes);
     let result = lhs.wrapping_sub(imm);
-    vm.set_reg32(REG_EAX, result);
-    update_flags_sub32(vm, lhs, imm, result);
-    vm.set_eip(cursor + 5);
+    set_regv(vm, REG_EAX, prefixes, result);
+    update_flags_sub(vm, operand_width(prefixes), lhs, imm, result);
+    vm.set_eip(cursor + 1 + len);
     Ok(())
 }
 
@@ -75,10 +75,10 @@ pub(crate) fn sub_rm32_imm(
     prefixes: Prefixes,
     imm: u32,
 ) -> Result<(), VmError> {
-    let dst = read_rm32(vm, modrm, prefixes.segment_base)?;
+    let dst = read_rmv(vm, modrm, prefixes)?;
     let result = dst.wrapping_sub(imm);
-    write_rm32(vm, modrm, prefixes.segment_base, result)?;
-    update_flags_sub32(vm, dst, imm, result);
+    write_rmv(vm, modrm, prefixes, result)?;
+    update_flags_sub(vm, operand_width(prefixes), dst, imm, result);
     Ok(())
 }
 
@@ -1,22 +1,49 @@
 //! x86 system instruction handlers.
 
-use crate::vm::{Vm, VmError, REG_AL, REG_EAX, REG_EBX, REG_ECX, REG_EDX};
+use crate::vm::{
+    AccessKind, Vm, VmError, REG_AL, REG_EAX, REG_EBX, REG_ECX, REG_EDX, STATUS_ASSERTION_FAILURE,
+    STATUS_BREAKPOINT, STATUS_ILLEGAL_INSTRUCTION, STATUS_PRIVILEGED_INSTRUCTION,
+    STATUS_STACK_BUFFER_OVERRUN,
+};
 
-use super::core::Prefixes;
+use super::core::{decode_modrm, Prefixes};
+
+// CPUID leaf 1 EDX: x87 FPU, TSC, CMPXCHG8B, CMOV, SSE and SSE2.
+const CPUID_FEATURES_EDX: u32 = (1 << 0) | (1 << 4) | (1 << 8) | (1 << 15) | (1 << 25) | (1 << 26);
 
 pub(crate) fn nop(vm: &mut Vm, cursor: u32, _prefixes: Prefixes) -> Result<(), VmError> {
     vm.set_eip(cursor + 1);
     Ok(())
 }
 
-pub(crate) fn int3(vm: &mut Vm, cursor: u32, _prefixes: Prefixes) -> Result<(), VmError> {
-    vm.set_eip(cursor + 1);
-    Ok(())
+// Like Windows, breakpoints report the address of the int3 itself, so a handler that
+// continues execution must step EIP past it.
+pub(crate) fn int3(_vm: &mut Vm, cursor: u32, _prefixes: Prefixes) -> Result<(), VmError> {
+    Err(VmError::Exception {
+        code: STATUS_BREAKPOINT,
+        address: cursor,
+    })
 }
 
 pub(crate) fn int(vm: &mut Vm, cursor: u32, _prefixes: Prefixes) -> Result<(), VmError> {
-    vm.set_eip(cursor + 2);
-    Ok(())
+    let code = match vm.read_u8(cursor + 1)? {
+        0x03 => STATUS_BREAKPOINT,
+        // __fastfail
+        0x29 => STATUS_STACK_BUFFER_OVERRUN,
+        0x2C => STATUS_ASSERTION_FAILURE,
+        // Any other vector is a general-protection fault in user mode.
+        _ => {
+            return Err(VmError::AccessViolation {
+                addr: 0xFFFF_FFFF,
+                access: AccessKind::Read,
+                eip: cursor,
+            })
+        }
+    };
+    Err(VmError::Exception {
+        code,
+        address: cursor,
+    })
 }
 
 pub(crate) fn cpuid(vm: &mut Vm, cursor: u32, _prefixes: Prefixes) -> Result<(), VmError> {
@@ -27,6 +54,12 @@ pub(crate) fn cpuid(vm: &mut Vm, cursor: u32, _prefixes: Prefixes) -> Result<(),
             vm.set_reg32(REG_EDX, 0x6c65_746e);
             vm.set_reg32(REG_EBX, 0x4965_6e69);
         }
+        1 => {
+            vm.set_reg32(REG_EAX, 0);
+            vm.set_reg32(REG_ECX, 0);
+            vm.set_reg32(REG_EDX, CPUID_FEATURES_EDX);
+            vm.set_reg32(REG_EBX, 0);
+        }
         _ => {
             vm.set_reg32(REG_EAX, 0);
             vm.set_reg32(REG_ECX, 0);
@@ -56,10 +89,60 @@ pub(crate) fn salc(vm: &mut Vm, cursor: u32, _prefixes: Prefixes) -> Result<(),
     Ok(())
 }
 
-pub(crate) fn cdq(vm: &mut Vm, cursor: u32, _prefixes: Prefixes) -> Result<(), VmError> {
-    let eax = vm.reg32(REG_EAX) as i32;
-    let edx = if eax < 0 { 0xFFFF_FFFF } else { 0 };
-    vm.set_reg32(REG_EDX, edx);
+pub(crate) fn cdq(vm: &mut Vm, cursor: u32, prefixes: Prefixes) -> Result<(), VmError> {
+    if prefixes.operand_size_16 {
+        // CWD
+        let ax = vm.reg16(REG_EAX) as i16;
+        vm.set_reg16(REG_EDX, if ax < 0 { 0xFFFF } else { 0 });
+    } else {
+        let eax = vm.reg32(REG_EAX) as i32;
+        let edx = if eax < 0 { 0xFFFF_FFFF } else { 0 };
+        vm.set_reg32(REG_EDX, edx);
+    }
+    vm.set_eip(cursor + 1);
+    Ok(())
+}
+
+// CWDE, or CBW with an operand-size override.
+pub(crate) fn cwde(vm: &mut Vm, cursor: u32, prefixes: Prefixes) -> Result<(), VmError> {
+    if prefixes.operand_size_16 {
+        let al = vm.reg8(REG_AL) as i8;
+        vm.set_reg16(REG_EAX, al as i16 as u16);
+    } else {
+        let ax = vm.reg16(REG_EAX) as i16;
+        vm.set_reg32(REG_EAX, ax as i32 as u32);
+    }
     vm.set_eip(cursor + 1);
     Ok(())
 }
+
+// The time-stamp counter counts retired instructions and idle virtual time, not host time.
+pub(crate) fn rdtsc(vm: &mut Vm, cursor: u32, _prefixes: Prefixes) -> Result<(), VmError> {
+    let ticks = vm.timestamp_counter();
+    vm.set_reg32(REG_EAX, ticks as u32);
+    vm.set_reg32(REG_EDX, (ticks >> 32) as u32);
+    vm.set_eip(cursor + 2);
+    Ok(())
+}
+
+// Multi-byte NOP (0F 1F /0), as emitted for code alignment.
+pub(crate) fn nop_rm32(vm: &mut Vm, cursor: u32, prefixes: Prefixes) -> Result<(), VmError> {
+    let modrm = decode_modrm(vm, cursor + 2, prefixes)?;
+    vm.set_eip(cursor + 2 + modrm.len as u32);
+    Ok(())
+}
+
+pub(crate) fn ud2(_vm: &mut Vm, cursor: u32, _prefixes: Prefixes) -> Result<(), VmError> {
+    Err(VmError::Exception {
+        code: STATUS_ILLEGAL_INSTRUCTION,
+        address: cursor,
+    })
+}
+
+// HLT, CLI/STI and port I/O fault in user mode.
+pub(crate) fn privileged(_vm: &mut Vm, cursor: u32, _prefixes: Prefixes) -> Result<(), VmError> {
+    Err(VmError::Exception {
+        code: STATUS_PRIVILEGED_INSTRUCTION,
+        address: cursor,
+    })
+}
@@ -1,12 +1,16 @@
 //! x86 executor and instruction registry.
 
+mod cache;
+pub mod disasm;
 mod ins;
 
 use std::sync::OnceLock;
 
 use crate::vm::{Vm, VmError};
 
-use ins::{build_instruction_set, parse_prefixes, InstructionSet};
+use ins::{build_instruction_set, InstructionSet};
+
+pub(crate) use cache::DecodeCache;
 
 #[derive(Clone, Copy)]
 pub struct X86Executor;
@@ -17,9 +21,8 @@ impl X86Executor {
     }
 
     pub fn step(&self, vm: &mut Vm) -> Result<(), VmError> {
-        let (cursor, prefixes) = parse_prefixes(vm, vm.eip())?;
-        let opcode = vm.read_u8(cursor)?;
-        instruction_set().execute(opcode, vm, cursor, prefixes)
+        let decoded = cache::fetch(vm, instruction_set())?;
+        (decoded.handler)(vm, decoded.cursor, decoded.prefixes)
     }
 
     pub(crate) fn supported_opcodes(&self) -> (Vec<u8>, Vec<u8>) {
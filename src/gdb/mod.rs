@@ -0,0 +1,596 @@
+//! GDB remote serial protocol server, so `gdb` (or any RSP client) can debug
+//! guest code running in a `Vm`.
+//!
+//! ```no_run
+//! use pe_vm::gdb::GdbServer;
+//! # fn run(vm: &mut pe_vm::Vm, pe: &pe_vm::PeFile) -> Result<(), pe_vm::VmError> {
+//! let server = GdbServer::bind("127.0.0.1:1234")?;
+//! // In gdb: `target remote 127.0.0.1:1234`.
+//! server.serve(vm, |vm| vm.call_export(pe, "Add"))?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! The server drives the `Vm` debugger: software and hardware breakpoints map to
+//! `Vm::add_breakpoint`, watchpoints to `Vm::add_watchpoint`, and `step` to
+//! `Vm::step`. The guest appears as a single thread whose registers follow gdb's
+//! default i386 layout.
+
+mod packet;
+mod registers;
+
+use std::cell::Cell;
+use std::collections::BTreeSet;
+use std::io;
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::rc::Rc;
+
+use crate::vm::{DebugAction, DebugHook, Register, StopReason, Vm, VmError, WatchKind};
+
+use packet::{from_hex, parse_hex_u32, to_hex, unescape, Connection, Incoming};
+
+const TARGET_XML: &str = concat!(
+    r#"<?xml version="1.0"?><!DOCTYPE target SYSTEM "gdb-target.dtd">"#,
+    "<target version=\"1.0\"><architecture>i386</architecture></target>"
+);
+
+const SIGINT: u8 = 2;
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+const SIGABRT: u8 = 6;
+const SIGSEGV: u8 = 11;
+
+// Largest memory transfer answered in one packet.
+const MAX_TRANSFER: u32 = 0x800;
+// Instructions between checks for a Ctrl-C from the client.
+const POLL_INTERVAL: u32 = 0x400;
+
+/// Listens for one gdb connection at a time.
+pub struct GdbServer {
+    listener: TcpListener,
+}
+
+impl GdbServer {
+    /// Listen on `addr`; use a loopback address, the protocol has no authentication.
+    pub fn bind(addr: impl ToSocketAddrs) -> io::Result<Self> {
+        Ok(Self {
+            listener: TcpListener::bind(addr)?,
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Wait for a client, then call `start` (typically `Vm::execute` or an
+    /// `execute_export_*` helper) with the run stopped before its first guest
+    /// instruction, and serve the client until the run ends or it detaches.
+    ///
+    /// The server installs its own `DebugHook` while it runs. If the client
+    /// kills the session or disconnects, the run is left suspended for
+    /// `Vm::resume`; a guest fault is reported to the client and returned.
+    pub fn serve(
+        &self,
+        vm: &mut Vm,
+        start: impl FnOnce(&mut Vm) -> Result<(), VmError>,
+    ) -> Result<(), VmError> {
+        let (stream, _) = self.listener.accept()?;
+        stream.set_nodelay(true)?;
+        let mut session = Session::new(stream)?;
+        vm.set_debug_hook(Some(Box::new(session.hook()?)));
+        let result = session.run(vm, start);
+        vm.set_debug_hook(None);
+        session.clear_stops(vm);
+        result
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    /// Stop at the first instruction of the run.
+    Starting,
+    Running,
+    /// The client sent Ctrl-C.
+    Interrupted,
+}
+
+// Stops the run on request: before it starts, and when the client interrupts.
+struct Attach {
+    stream: TcpStream,
+    mode: Rc<Cell<Mode>>,
+    countdown: u32,
+}
+
+impl DebugHook for Attach {
+    fn on_instruction(&mut self, _vm: &mut Vm, _eip: u32) -> DebugAction {
+        if self.mode.get() == Mode::Running {
+            self.countdown -= 1;
+            if self.countdown == 0 {
+                self.countdown = POLL_INTERVAL;
+                if self.interrupt_pending() {
+                    self.mode.set(Mode::Interrupted);
+                }
+            }
+        }
+        // Keep asking: a stop is only honoured once the host is not inside a callback.
+        match self.mode.get() {
+            Mode::Running => DebugAction::Continue,
+            Mode::Starting | Mode::Interrupted => DebugAction::Stop,
+        }
+    }
+}
+
+impl Attach {
+    // The stream is non-blocking while the guest runs.
+    fn interrupt_pending(&mut self) -> bool {
+        let mut byte = [0u8; 1];
+        if !matches!(self.stream.peek(&mut byte), Ok(1)) || byte[0] != 0x03 {
+            return false;
+        }
+        io::Read::read(&mut self.stream, &mut byte).is_ok()
+    }
+}
+
+enum Action {
+    Reply(Vec<u8>),
+    Resume { step: bool },
+    NoAck,
+    Detach,
+    Kill,
+}
+
+struct Session {
+    conn: Connection<TcpStream>,
+    mode: Rc<Cell<Mode>>,
+    breakpoints: BTreeSet<u32>,
+    watchpoints: Vec<(u32, u32, WatchKind)>,
+    last_stop: String,
+}
+
+impl Session {
+    fn new(stream: TcpStream) -> io::Result<Self> {
+        Ok(Self {
+            conn: Connection::new(stream),
+            mode: Rc::new(Cell::new(Mode::Starting)),
+            breakpoints: BTreeSet::new(),
+            watchpoints: Vec::new(),
+            last_stop: String::new(),
+        })
+    }
+
+    fn hook(&self) -> io::Result<Attach> {
+        Ok(Attach {
+            stream: self.conn.stream().try_clone()?,
+            mode: self.mode.clone(),
+            countdown: POLL_INTERVAL,
+        })
+    }
+
+    fn run(
+        &mut self,
+        vm: &mut Vm,
+        start: impl FnOnce(&mut Vm) -> Result<(), VmError>,
+    ) -> Result<(), VmError> {
+        let first = match start(vm) {
+            Err(VmError::Stopped(reason)) => Ok(reason),
+            Ok(()) => Ok(StopReason::Exited),
+            Err(err) => Err(err),
+        };
+        let mut outcome = self.record_stop(vm, first);
+        loop {
+            let packet = match self.conn.receive()? {
+                Incoming::Packet(packet) => packet,
+                Incoming::Interrupt => continue,
+                Incoming::Closed => return Ok(()),
+            };
+            match self.handle(vm, &packet) {
+                Action::Reply(reply) => self.conn.send(&reply)?,
+                Action::NoAck => {
+                    self.conn.send(b"OK")?;
+                    self.conn.disable_acks();
+                }
+                Action::Resume { step } => {
+                    if outcome.is_none() {
+                        let result = self.resume(vm, step)?;
+                        outcome = self.record_stop(vm, result);
+                    }
+                    self.conn.send(self.last_stop.as_bytes())?;
+                    if let Some(result) = outcome.take() {
+                        return result;
+                    }
+                }
+                Action::Detach => {
+                    self.conn.send(b"OK")?;
+                    if let Some(result) = outcome {
+                        return result;
+                    }
+                    self.clear_stops(vm);
+                    vm.set_debug_hook(None);
+                    loop {
+                        if vm.resume()? == StopReason::Exited {
+                            return Ok(());
+                        }
+                    }
+                }
+                Action::Kill => return outcome.unwrap_or(Ok(())),
+            }
+        }
+    }
+
+    fn resume(&mut self, vm: &mut Vm, step: bool) -> io::Result<Result<StopReason, VmError>> {
+        self.mode.set(Mode::Running);
+        self.conn.stream().set_nonblocking(true)?;
+        let result = if step { vm.step() } else { vm.resume() };
+        self.conn.stream().set_nonblocking(false)?;
+        Ok(result)
+    }
+
+    // Remember the stop reply for `?`. Returns the outcome once the run is over.
+    fn record_stop(
+        &mut self,
+        vm: &Vm,
+        result: Result<StopReason, VmError>,
+    ) -> Option<Result<(), VmError>> {
+        let (reply, outcome) = match result {
+            Ok(StopReason::Exited) => (
+                format!("W{:02x}", vm.register(Register::Eax) & 0xFF),
+                Some(Ok(())),
+            ),
+            Ok(StopReason::Breakpoint(addr)) if self.breakpoints.contains(&addr) => {
+                (format!("T{SIGTRAP:02x}swbreak:;"), None)
+            }
+            Ok(StopReason::Watchpoint(hit)) => {
+                let watch = self.watchpoints.iter().find(|(start, len, _)| {
+                    hit.addr < start.wrapping_add(*len) && *start < hit.addr.wrapping_add(hit.len)
+                });
+                match watch {
+                    Some(&(start, _, kind)) => {
+                        let name = match kind {
+                            WatchKind::Write => "watch",
+                            WatchKind::Read => "rwatch",
+                            WatchKind::ReadWrite => "awatch",
+                        };
+                        let addr = hit.addr.max(start);
+                        (format!("T{SIGTRAP:02x}{name}:{addr:x};"), None)
+                    }
+                    None => (format!("S{SIGTRAP:02x}"), None),
+                }
+            }
+            Ok(StopReason::Hook(_)) if self.mode.get() == Mode::Interrupted => {
+                (format!("S{SIGINT:02x}"), None)
+            }
+            Ok(_) => (format!("S{SIGTRAP:02x}"), None),
+            Err(err) => {
+                let signal = match err {
+                    VmError::AccessViolation { .. } => SIGSEGV,
+                    VmError::UnsupportedInstruction(_) => SIGILL,
+                    _ => SIGABRT,
+                };
+                (format!("X{signal:02x}"), Some(Err(err)))
+            }
+        };
+        self.mode.set(Mode::Running);
+        self.last_stop = reply;
+        outcome
+    }
+
+    fn handle(&mut self, vm: &mut Vm, packet: &[u8]) -> Action {
+        let reply = |text: &str| Action::Reply(text.as_bytes().to_vec());
+        let Some((&command, args)) = packet.split_first() else {
+            return reply("");
+        };
+        match command {
+            b'?' => reply(&self.last_stop.clone()),
+            b'g' => {
+                let bytes: Vec<u8> = (0..registers::REGISTER_COUNT)
+                    .flat_map(|index| registers::read(vm, index))
+                    .collect();
+                reply(&to_hex(&bytes))
+            }
+            b'G' => {
+                let Some(mut bytes) = from_hex(args) else {
+                    return reply("E01");
+                };
+                for index in 0..registers::REGISTER_COUNT {
+                    let size = registers::size(index);
+                    if bytes.len() < size {
+                        break;
+                    }
+                    let rest = bytes.split_off(size);
+                    registers::write(vm, index, &bytes);
+                    bytes = rest;
+                }
+                reply("OK")
+            }
+            b'p' => match parse_hex_u32(args).map(|index| index as usize) {
+                Some(index) if index < registers::REGISTER_COUNT => {
+                    reply(&to_hex(&registers::read(vm, index)))
+                }
+                _ => reply("E01"),
+            },
+            b'P' => {
+                let parsed = split_once(args, b'=').and_then(|(index, value)| {
+                    Some((parse_hex_u32(index)? as usize, from_hex(value)?))
+                });
+                match parsed {
+                    Some((index, value))
+                        if index < registers::REGISTER_COUNT
+                            && value.len() == registers::size(index) =>
+                    {
+                        registers::write(vm, index, &value);
+                        reply("OK")
+                    }
+                    _ => reply("E01"),
+                }
+            }
+            b'm' => match parse_range(args) {
+                Some((addr, len)) => {
+                    let bytes: Vec<u8> = (0..len.min(MAX_TRANSFER))
+                        .map_while(|offset| vm.read_u8(addr.wrapping_add(offset)).ok())
+                        .collect();
+                    if bytes.is_empty() && len != 0 {
+                        reply("E14")
+                    } else {
+                        reply(&to_hex(&bytes))
+                    }
+                }
+                None => reply("E01"),
+            },
+            b'M' | b'X' => {
+                let parsed = split_once(args, b':').and_then(|(range, data)| {
+                    let (addr, len) = parse_range(range)?;
+                    let data = if command == b'M' {
+                        from_hex(data)?
+                    } else {
+                        unescape(data)
+                    };
+                    (data.len() == len as usize).then_some((addr, data))
+                });
+                match parsed {
+                    Some((_, data)) if data.is_empty() => reply("OK"),
+                    Some((addr, data)) => match vm.patch_memory(addr, &data) {
+                        Ok(()) => reply("OK"),
+                        Err(_) => reply("E14"),
+                    },
+                    None => reply("E01"),
+                }
+            }
+            b'Z' | b'z' => reply(self.set_stop(vm, command == b'Z', args)),
+            b'c' | b'C' | b's' | b'S' => {
+                // `c addr` resumes elsewhere; a signal to deliver (`C sig`) is ignored.
+                let addr = match command {
+                    b'c' | b's' => args,
+                    _ => split_once(args, b';').map_or(&[][..], |(_, addr)| addr),
+                };
+                if let Some(addr) = parse_hex_u32(addr) {
+                    vm.set_register(Register::Eip, addr);
+                }
+                Action::Resume {
+                    step: matches!(command, b's' | b'S'),
+                }
+            }
+            b'D' => Action::Detach,
+            b'k' => Action::Kill,
+            b'H' | b'T' => reply("OK"),
+            _ => self.query(packet),
+        }
+    }
+
+    fn query(&self, packet: &[u8]) -> Action {
+        let reply = |text: &str| Action::Reply(text.as_bytes().to_vec());
+        if packet.starts_with(b"qSupported") {
+            return reply(
+                "PacketSize=1000;qXfer:features:read+;swbreak+;hwbreak+;QStartNoAckMode+",
+            );
+        }
+        if packet == b"QStartNoAckMode" {
+            return Action::NoAck;
+        }
+        if let Some(rest) = packet.strip_prefix(b"qXfer:features:read:target.xml:") {
+            let Some((offset, len)) = parse_range(rest) else {
+                return reply("E01");
+            };
+            let xml = TARGET_XML.as_bytes();
+            let start = (offset as usize).min(xml.len());
+            let end = start.saturating_add(len as usize).min(xml.len());
+            let marker = if end == xml.len() { b'l' } else { b'm' };
+            let mut out = vec![marker];
+            out.extend_from_slice(&xml[start..end]);
+            return Action::Reply(out);
+        }
+        if let Some(action) = packet.strip_prefix(b"vCont;") {
+            return Action::Resume {
+                step: matches!(action.first(), Some(b's' | b'S')),
+            };
+        }
+        match packet {
+            b"vCont?" => reply("vCont;c;C;s;S"),
+            b"qAttached" => reply("1"),
+            b"qC" => reply("QC1"),
+            b"qfThreadInfo" => reply("m1"),
+            b"qsThreadInfo" => reply("l"),
+            _ => reply(""),
+        }
+    }
+
+    // `Z type,addr,kind` / `z type,addr,kind`.
+    fn set_stop(&mut self, vm: &mut Vm, insert: bool, args: &[u8]) -> &'static str {
+        let mut fields = args.split(|&byte| byte == b',');
+        let (Some(kind), Some(addr), Some(len)) = (fields.next(), fields.next(), fields.next())
+        else {
+            return "E01";
+        };
+        let (Some(addr), Some(len)) = (parse_hex_u32(addr), parse_hex_u32(len)) else {
+            return "E01";
+        };
+        let watch = match kind {
+            b"0" | b"1" => {
+                if insert {
+                    vm.add_breakpoint(addr);
+                    self.breakpoints.insert(addr);
+                } else if self.breakpoints.remove(&addr) {
+                    vm.remove_breakpoint(addr);
+                }
+                return "OK";
+            }
+            b"2" => WatchKind::Write,
+            b"3" => WatchKind::Read,
+            b"4" => WatchKind::ReadWrite,
+            _ => return "",
+        };
+        if insert {
+            vm.add_watchpoint(addr, len, watch);
+            self.watchpoints.push((addr, len, watch));
+        } else if let Some(index) = self
+            .watchpoints
+            .iter()
+            .position(|&entry| entry == (addr, len, watch))
+        {
+            self.watchpoints.remove(index);
+            vm.remove_watchpoint(addr);
+            // Put back the others that start at the same address.
+            for &(start, len, kind) in &self.watchpoints {
+                if start == addr {
+                    vm.add_watchpoint(start, len, kind);
+                }
+            }
+        }
+        "OK"
+    }
+
+    // Remove what the client set, so it does not outlive the session.
+    fn clear_stops(&mut self, vm: &mut Vm) {
+        for addr in std::mem::take(&mut self.breakpoints) {
+            vm.remove_breakpoint(addr);
+        }
+        for (addr, _, _) in std::mem::take(&mut self.watchpoints) {
+            vm.remove_watchpoint(addr);
+        }
+    }
+}
+
+fn split_once(bytes: &[u8], separator: u8) -> Option<(&[u8], &[u8])> {
+    let index = bytes.iter().position(|&byte| byte == separator)?;
+    Some((&bytes[..index], &bytes[index + 1..]))
+}
+
+// `addr,len` in hex.
+fn parse_range(args: &[u8]) -> Option<(u32, u32)> {
+    let (addr, len) = split_once(args, b',')?;
+    Some((parse_hex_u32(addr)?, parse_hex_u32(len)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use std::time::Duration;
+
+    use super::*;
+    use crate::vm::{Architecture, VmConfig};
+
+    fn create_test_vm(code: &[u8]) -> Vm {
+        let config = VmConfig::new()
+            .architecture(Architecture::X86)
+            .execution_limit(0);
+        let mut vm = Vm::new(config).expect("vm");
+        vm.map_memory(0x1000, 0x10000).expect("map");
+        vm.set_register(Register::Esp, 0x10FFC);
+        vm.patch_memory(0x3000, code).expect("code");
+        vm
+    }
+
+    // A scripted client: sends each packet and checks the reply.
+    struct Client(Connection<TcpStream>);
+
+    impl Client {
+        fn connect(addr: SocketAddr) -> Self {
+            Client(Connection::new(TcpStream::connect(addr).expect("connect")))
+        }
+
+        fn request(&mut self, packet: &str) -> String {
+            self.0.send(packet.as_bytes()).expect("send");
+            match self.0.receive().expect("receive") {
+                Incoming::Packet(reply) => String::from_utf8(reply).expect("utf8"),
+                other => panic!("no reply to {packet}: {other:?}"),
+            }
+        }
+    }
+
+    #[test]
+    fn client_breaks_steps_watches_and_runs_to_exit() {
+        let mut vm = create_test_vm(&[
+            0xB8, 0x01, 0x00, 0x00, 0x00, // mov eax, 1
+            0xBB, 0x02, 0x00, 0x00, 0x00, // mov ebx, 2
+            0xA3, 0x00, 0x50, 0x00, 0x00, // mov [0x5000], eax
+            0x01, 0xD8, // add eax, ebx
+            0xC3, // ret
+        ]);
+        let server = GdbServer::bind("127.0.0.1:0").expect("bind");
+        let addr = server.local_addr().expect("addr");
+        let client = thread::spawn(move || {
+            let mut gdb = Client::connect(addr);
+            assert!(gdb.request("qSupported:swbreak+").contains("swbreak+"));
+            assert_eq!(gdb.request("QStartNoAckMode"), "OK");
+            gdb.0.disable_acks();
+            assert!(gdb
+                .request("qXfer:features:read:target.xml:0,1000")
+                .contains("<architecture>i386</architecture>"));
+            assert_eq!(gdb.request("?"), "S05");
+            let regs = gdb.request("g");
+            assert_eq!(regs.len(), 308 * 2);
+            assert_eq!(&regs[64..72], "00300000");
+
+            assert_eq!(gdb.request("Z0,3005,1"), "OK");
+            assert_eq!(gdb.request("c"), "T05swbreak:;");
+            assert_eq!(gdb.request("p8"), "05300000");
+            assert_eq!(gdb.request("P0=2a000000"), "OK");
+            assert_eq!(gdb.request("s"), "S05");
+            assert_eq!(gdb.request("p8"), "0a300000");
+            assert_eq!(gdb.request("p3"), "02000000");
+
+            assert_eq!(gdb.request("M5000,4:01020304"), "OK");
+            assert_eq!(gdb.request("m5000,4"), "01020304");
+            assert_eq!(gdb.request("m3000,2"), "b801");
+            assert_eq!(gdb.request("Z2,5000,4"), "OK");
+            assert_eq!(gdb.request("vCont;c"), "T05watch:5000;");
+            assert_eq!(gdb.request("m5000,4"), "2a000000");
+
+            assert_eq!(gdb.request("z2,5000,4"), "OK");
+            assert_eq!(gdb.request("z0,3005,1"), "OK");
+            assert_eq!(gdb.request("c"), "W2c");
+        });
+        server
+            .serve(&mut vm, |vm| vm.execute(0x3000))
+            .expect("serve");
+        client.join().expect("client");
+        assert_eq!(vm.register(Register::Eax), 44);
+        assert!(!vm.is_suspended());
+    }
+
+    #[test]
+    fn ctrl_c_interrupts_a_running_guest() {
+        let mut vm = create_test_vm(&[0xEB, 0xFE]); // jmp $
+        let server = GdbServer::bind("127.0.0.1:0").expect("bind");
+        let addr = server.local_addr().expect("addr");
+        let client = thread::spawn(move || {
+            let mut gdb = Client::connect(addr);
+            assert_eq!(gdb.request("?"), "S05");
+            gdb.0.send(b"c").expect("send");
+            thread::sleep(Duration::from_millis(50));
+            use std::io::Write;
+            let mut stream = gdb.0.stream().try_clone().expect("clone");
+            stream.write_all(&[0x03]).expect("interrupt");
+            match gdb.0.receive().expect("receive") {
+                Incoming::Packet(reply) => assert_eq!(reply, b"S02"),
+                other => panic!("unexpected {other:?}"),
+            }
+            assert_eq!(gdb.request("p8"), "00300000");
+            gdb.0.send(b"k").expect("kill");
+        });
+        server
+            .serve(&mut vm, |vm| vm.execute(0x3000))
+            .expect("serve");
+        client.join().expect("client");
+        assert!(vm.is_suspended());
+    }
+}
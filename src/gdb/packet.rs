@@ -0,0 +1,246 @@
+//! Remote serial protocol framing: `$payload#checksum` packets, acks and hex.
+
+use std::io::{self, Read, Write};
+
+/// What arrived from the client.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Incoming {
+    Packet(Vec<u8>),
+    /// A bare `0x03`, sent when the user presses Ctrl-C.
+    Interrupt,
+    /// The client closed the connection.
+    Closed,
+}
+
+pub(crate) struct Connection<S> {
+    stream: S,
+    ack: bool,
+    buffer: Vec<u8>,
+    pos: usize,
+}
+
+impl<S: Read + Write> Connection<S> {
+    pub(crate) fn new(stream: S) -> Self {
+        Self {
+            stream,
+            ack: true,
+            buffer: Vec::new(),
+            pos: 0,
+        }
+    }
+
+    /// Stop sending and expecting `+` acknowledgements (`QStartNoAckMode`).
+    pub(crate) fn disable_acks(&mut self) {
+        self.ack = false;
+    }
+
+    pub(crate) fn receive(&mut self) -> io::Result<Incoming> {
+        loop {
+            let Some(byte) = self.next_byte()? else {
+                return Ok(Incoming::Closed);
+            };
+            match byte {
+                b'$' => {}
+                0x03 => return Ok(Incoming::Interrupt),
+                // Acks, nacks and line noise between packets.
+                _ => continue,
+            }
+            let mut payload = Vec::new();
+            loop {
+                match self.next_byte()? {
+                    None => return Ok(Incoming::Closed),
+                    Some(b'#') => break,
+                    Some(byte) => payload.push(byte),
+                }
+            }
+            let mut sum = [0u8; 2];
+            for digit in &mut sum {
+                match self.next_byte()? {
+                    None => return Ok(Incoming::Closed),
+                    Some(byte) => *digit = byte,
+                }
+            }
+            let valid = parse_hex_u32(&sum) == Some(checksum(&payload) as u32);
+            if self.ack {
+                self.stream.write_all(if valid { b"+" } else { b"-" })?;
+                self.stream.flush()?;
+            }
+            if valid {
+                return Ok(Incoming::Packet(payload));
+            }
+        }
+    }
+
+    pub(crate) fn send(&mut self, payload: &[u8]) -> io::Result<()> {
+        let packet = encode(payload);
+        loop {
+            self.stream.write_all(&packet)?;
+            self.stream.flush()?;
+            if !self.ack {
+                return Ok(());
+            }
+            match self.next_byte()? {
+                Some(b'-') => continue,
+                // Anything but a nack counts as received; a packet that arrives
+                // instead of the ack is left for `receive`.
+                Some(b'+') | None => return Ok(()),
+                Some(_) => {
+                    self.pos -= 1;
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    pub(crate) fn stream(&self) -> &S {
+        &self.stream
+    }
+
+    fn next_byte(&mut self) -> io::Result<Option<u8>> {
+        if self.pos == self.buffer.len() {
+            self.buffer.resize(4096, 0);
+            let read = loop {
+                match self.stream.read(&mut self.buffer) {
+                    Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                    result => break result?,
+                }
+            };
+            self.buffer.truncate(read);
+            self.pos = 0;
+            if read == 0 {
+                return Ok(None);
+            }
+        }
+        self.pos += 1;
+        Ok(Some(self.buffer[self.pos - 1]))
+    }
+}
+
+pub(crate) fn checksum(payload: &[u8]) -> u8 {
+    payload
+        .iter()
+        .fold(0u8, |sum, byte| sum.wrapping_add(*byte))
+}
+
+/// Frame `payload`, escaping the bytes the protocol reserves.
+pub(crate) fn encode(payload: &[u8]) -> Vec<u8> {
+    let mut body = Vec::with_capacity(payload.len());
+    for &byte in payload {
+        if matches!(byte, b'$' | b'#' | b'}' | b'*') {
+            body.extend_from_slice(&[b'}', byte ^ 0x20]);
+        } else {
+            body.push(byte);
+        }
+    }
+    let mut packet = Vec::with_capacity(body.len() + 4);
+    packet.push(b'$');
+    packet.extend_from_slice(&body);
+    packet.extend_from_slice(format!("#{:02x}", checksum(&body)).as_bytes());
+    packet
+}
+
+/// Undo the `}` escaping of binary data (`X` packets).
+pub(crate) fn unescape(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len());
+    let mut bytes = data.iter();
+    while let Some(&byte) = bytes.next() {
+        if byte == b'}' {
+            if let Some(&next) = bytes.next() {
+                out.push(next ^ 0x20);
+            }
+        } else {
+            out.push(byte);
+        }
+    }
+    out
+}
+
+pub(crate) fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+pub(crate) fn from_hex(text: &[u8]) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    text.chunks(2)
+        .map(|pair| parse_hex_u32(pair).map(|value| value as u8))
+        .collect()
+}
+
+pub(crate) fn parse_hex_u32(text: &[u8]) -> Option<u32> {
+    if text.is_empty() || text.len() > 8 || !text.iter().all(u8::is_ascii_hexdigit) {
+        return None;
+    }
+    let text = std::str::from_utf8(text).ok()?;
+    u32::from_str_radix(text, 16).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // An in-memory stream: reads come from `input`, writes collect in `output`.
+    struct Script {
+        input: io::Cursor<Vec<u8>>,
+        output: Vec<u8>,
+    }
+
+    impl Read for Script {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for Script {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.output.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn connection(input: &[u8]) -> Connection<Script> {
+        Connection::new(Script {
+            input: io::Cursor::new(input.to_vec()),
+            output: Vec::new(),
+        })
+    }
+
+    #[test]
+    fn receives_packets_and_acks_by_checksum() {
+        let mut conn = connection(b"+$qC#b4$g#00\x03$?#3f");
+        assert_eq!(conn.receive().unwrap(), Incoming::Packet(b"qC".to_vec()));
+        // The bad checksum is nacked and skipped.
+        assert_eq!(conn.receive().unwrap(), Incoming::Interrupt);
+        assert_eq!(conn.receive().unwrap(), Incoming::Packet(b"?".to_vec()));
+        assert_eq!(conn.receive().unwrap(), Incoming::Closed);
+        assert_eq!(conn.stream().output, b"+-+");
+    }
+
+    #[test]
+    fn sends_escaped_packets_and_retries_on_nack() {
+        let mut conn = connection(b"-+");
+        conn.send(b"a#b").unwrap();
+        let packet = b"$a}\x03b#43";
+        assert_eq!(conn.stream().output, [&packet[..], &packet[..]].concat());
+
+        let mut quiet = connection(b"");
+        quiet.disable_acks();
+        quiet.send(b"OK").unwrap();
+        assert_eq!(quiet.stream().output, b"$OK#9a");
+    }
+
+    #[test]
+    fn hex_and_binary_helpers_round_trip() {
+        assert_eq!(to_hex(&[0x00, 0xAB, 0x7F]), "00ab7f");
+        assert_eq!(from_hex(b"00ab7f"), Some(vec![0x00, 0xAB, 0x7F]));
+        assert_eq!(from_hex(b"0g"), None);
+        assert_eq!(parse_hex_u32(b"401000"), Some(0x40_1000));
+        assert_eq!(parse_hex_u32(b"100000000"), None);
+        assert_eq!(unescape(b"a}\x03}]"), b"a#}".to_vec());
+    }
+}
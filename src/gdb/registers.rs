@@ -0,0 +1,92 @@
+//! The i386 register file in gdb's numbering: the general registers, EIP,
+//! EFLAGS and segments, the x87 stack and control registers, then XMM0-7 and
+//! MXCSR (gdb's default i386 target description).
+
+use crate::vm::{Register, Vm};
+
+/// Registers 0-40; `g` packets carry them back to back.
+pub(crate) const REGISTER_COUNT: usize = 41;
+
+const GENERAL: [Register; 10] = [
+    Register::Eax,
+    Register::Ecx,
+    Register::Edx,
+    Register::Ebx,
+    Register::Esp,
+    Register::Ebp,
+    Register::Esi,
+    Register::Edi,
+    Register::Eip,
+    Register::Eflags,
+];
+
+// Flat user-mode selectors as a 32-bit Windows process sees them.
+const SEGMENTS: [u32; 6] = [0x1B, 0x23, 0x23, 0x23, 0x3B, 0x00];
+
+const ST0: usize = 16;
+const FCTRL: usize = 24;
+const FSTAT: usize = 25;
+const FTAG: usize = 26;
+const XMM0: usize = 32;
+const MXCSR: usize = 40;
+
+pub(crate) fn size(index: usize) -> usize {
+    match index {
+        ST0..FCTRL => 10,
+        XMM0..MXCSR => 16,
+        _ => 4,
+    }
+}
+
+pub(crate) fn read(vm: &Vm, index: usize) -> Vec<u8> {
+    match index {
+        0..10 => vm.register(GENERAL[index]).to_le_bytes().to_vec(),
+        10..ST0 => SEGMENTS[index - 10].to_le_bytes().to_vec(),
+        ST0..FCTRL => vm.fpu_st_bytes(index - ST0).unwrap_or([0; 10]).to_vec(),
+        FCTRL => (vm.fpu_control() as u32).to_le_bytes().to_vec(),
+        FSTAT => (vm.fpu_status() as u32).to_le_bytes().to_vec(),
+        FTAG => tag_word(vm).to_le_bytes().to_vec(),
+        XMM0..MXCSR => vm.xmm((index - XMM0) as u8).to_vec(),
+        MXCSR => vm.mxcsr_bits().to_le_bytes().to_vec(),
+        // The last instruction and operand pointers are not tracked.
+        _ => vec![0; 4],
+    }
+}
+
+/// Store `bytes` (exactly `size(index)` long) into register `index`. Segments,
+/// the tag word and the instruction pointers are read-only.
+pub(crate) fn write(vm: &mut Vm, index: usize, bytes: &[u8]) {
+    let word = || u32::from_le_bytes(bytes[..4].try_into().expect("4 bytes"));
+    match index {
+        0..10 => vm.set_register(GENERAL[index], word()),
+        ST0..FCTRL => {
+            let value: [u8; 10] = bytes.try_into().expect("10 bytes");
+            // Leave empty registers empty unless gdb stores a real value.
+            if vm.fpu_st_bytes(index - ST0).is_some() || value != [0; 10] {
+                let _ = vm.fpu_set_st_bytes(index - ST0, value);
+            }
+        }
+        FCTRL => vm.fpu_set_control(word() as u16),
+        FSTAT => vm.fpu_set_status(word() as u16),
+        XMM0..MXCSR => vm.set_xmm((index - XMM0) as u8, bytes.try_into().expect("16 bytes")),
+        MXCSR => {
+            let _ = vm.set_mxcsr_bits(word());
+        }
+        _ => {}
+    }
+}
+
+// The full tag word, indexed by physical register; valid registers are all
+// reported as 00 since gdb only distinguishes empty ones.
+fn tag_word(vm: &Vm) -> u32 {
+    let top = (vm.fpu_status() >> 11) & 7;
+    (0..8u16).fold(0, |tags, st| {
+        let physical = (top + st) & 7;
+        let tag = if vm.fpu_st_bytes(st as usize).is_some() {
+            0
+        } else {
+            3
+        };
+        tags | (tag << (physical * 2))
+    })
+}
@@ -3,11 +3,13 @@
 mod api;
 mod architecture;
 pub mod ext;
+pub mod gdb;
 mod pe;
 pub mod settings;
 mod vm;
 
 pub use api::{Pe, SymbolExecutor};
+pub use architecture::intel::x86::disasm;
 pub use pe::{
     BoundForwarderRef, BoundImportDescriptor, BoundImportDirectory, ClrDirectory, DataDirectory,
     DebugDirectory, DebugDirectoryEntry, DelayImportDescriptor, DelayImportDirectory,
@@ -19,6 +21,11 @@ pub use pe::{
 };
 pub use vm::windows;
 pub use vm::{
-    host_create_thread, host_message_box_a, host_printf, Architecture, ComOutParam, ExecuteOptions,
-    HostCall, MessageBoxMode, Os, PathMapping, SandboxConfig, Value, Vm, VmConfig, VmError,
+    host_create_thread, host_message_box_a, host_printf, parse_trace, read_trace, AccessKind,
+    AllocationSite, Architecture, ComOutParam, Coverage, DebugAction, DebugHook, ExecuteOptions,
+    Flag, FsMetadata, FsOpenOptions, FsTimes, GuestFile, GuestFs, HeapApi, HeapIssue,
+    HeapIssueKind, HeapLeak, HeapReport, HostArg, HostCall, HostFs, LoadedModule, MemoryFs,
+    MemoryWrite, MessageBoxMode, ModuleCoverage, Os, OverlayFs, PathAccess, PathMapping, Register,
+    SandboxConfig, StopReason, TraceBuffer, TraceFormat, TraceOptions, TraceRecord, TraceSink,
+    TraceWriter, UnmappedPaths, Value, Vm, VmConfig, VmError, WatchHit, WatchKind,
 };
@@ -14,6 +14,8 @@ mod resource;
 mod security;
 mod tls;
 
+use crate::architecture::intel::x86::disasm::{self, Instruction};
+
 use super::error::PeParseError;
 use super::image::PeImage;
 use super::types::*;
@@ -191,6 +193,41 @@ impl PeFile {
             .map(|symbol| symbol.rva)
     }
 
+    /// Disassemble up to `count` instructions at `rva` straight from the file
+    /// bytes, stopping at the end of the section's raw data. Addresses assume the
+    /// preferred image base.
+    pub fn disassemble_rva(
+        &self,
+        image: &[u8],
+        rva: u32,
+        count: usize,
+    ) -> Option<Vec<Instruction>> {
+        let offset = self.rva_to_offset(rva)? as usize;
+        let end = if rva < self.optional_header.size_of_headers {
+            self.optional_header.size_of_headers as usize
+        } else {
+            let section = self.sections.iter().find(|section| {
+                rva >= section.virtual_address
+                    && rva - section.virtual_address < section.virtual_size.max(section.raw_size)
+            })?;
+            section.raw_ptr.saturating_add(section.raw_size) as usize
+        };
+        let bytes = image.get(offset..end.min(image.len()))?;
+        let address = self.image_base().wrapping_add(rva);
+        let read_u8 = |addr: u32| bytes.get(addr.wrapping_sub(address) as usize).copied();
+        Some(disasm::disassemble_with(read_u8, address, count))
+    }
+
+    /// Disassemble up to `count` instructions at the named export.
+    pub fn disassemble_export(
+        &self,
+        image: &[u8],
+        name: &str,
+        count: usize,
+    ) -> Option<Vec<Instruction>> {
+        self.disassemble_rva(image, self.export_rva(name)?, count)
+    }
+
     pub fn load_image(
         &self,
         image: &[u8],
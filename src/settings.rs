@@ -5,7 +5,9 @@ use serde::Deserialize;
 use serde_yaml::Value as YamlValue;
 
 use crate::vm::windows;
-use crate::vm::{Architecture, Os, PathMapping, SandboxConfig, VmConfig, VmError};
+use crate::vm::{
+    Architecture, Os, PathAccess, PathMapping, SandboxConfig, UnmappedPaths, VmConfig, VmError,
+};
 
 const SETTINGS_FILES: [SettingsFileSpec; 2] = [
     SettingsFileSpec {
@@ -338,50 +340,92 @@ fn merge_yaml_map(out: &mut PathMapping, map: &serde_yaml::Mapping) {
 
 fn parse_sandbox(value: &YamlValue) -> Option<SandboxConfig> {
     match value {
-        YamlValue::Mapping(map) => parse_sandbox_map(map),
+        YamlValue::Mapping(map) => parse_sandbox_map(SandboxConfig::new(), map),
         YamlValue::Sequence(seq) => {
+            let mut config = None;
             for item in seq {
                 match item {
                     YamlValue::String(name) if name.eq_ignore_ascii_case("network") => {
-                        return Some(SandboxConfig::new().enable_network(""));
+                        let base: SandboxConfig = config.unwrap_or_default();
+                        config = Some(base.enable_network(""));
                     }
                     YamlValue::Mapping(map) => {
-                        if let Some(config) = parse_sandbox_map(map) {
-                            return Some(config);
+                        let base = config.clone().unwrap_or_default();
+                        if let Some(next) = parse_sandbox_map(base, map) {
+                            config = Some(next);
                         }
                     }
                     _ => {}
                 }
             }
-            None
+            config
         }
         _ => None,
     }
 }
 
-fn parse_sandbox_map(map: &serde_yaml::Mapping) -> Option<SandboxConfig> {
+fn parse_sandbox_map(base: SandboxConfig, map: &serde_yaml::Mapping) -> Option<SandboxConfig> {
+    let mut config = base;
+    let mut found = false;
     for (key, value) in map {
         let Some(name) = key.as_str() else { continue };
-        if !name.eq_ignore_ascii_case("network") {
+        if name.eq_ignore_ascii_case("network") {
+            config = parse_network(config, value);
+        } else if name.eq_ignore_ascii_case("filesystem") {
+            config = parse_filesystem(config, value);
+        } else {
             continue;
         }
-        return Some(parse_network(value));
+        found = true;
     }
-    None
+    found.then_some(config)
 }
 
-fn parse_network(value: &YamlValue) -> SandboxConfig {
+fn parse_network(config: SandboxConfig, value: &YamlValue) -> SandboxConfig {
     match value {
         YamlValue::Mapping(map) => {
             let fallback = map
                 .get(YamlValue::String("fallback_host".to_string()))
                 .and_then(|value| value.as_str())
                 .unwrap_or("");
-            SandboxConfig::new().enable_network(fallback)
+            config.enable_network(fallback)
+        }
+        YamlValue::String(host) => config.enable_network(host),
+        _ => config.enable_network(""),
+    }
+}
+
+// filesystem:
+//   unmapped: deny | allow | /host/dir
+//   read_only: ["C:\\Program Files"]
+fn parse_filesystem(config: SandboxConfig, value: &YamlValue) -> SandboxConfig {
+    let YamlValue::Mapping(map) = value else {
+        return config;
+    };
+    let mut config = config;
+    if let Some(unmapped) = map
+        .get(YamlValue::String("unmapped".to_string()))
+        .and_then(|value| value.as_str())
+    {
+        let policy = match unmapped {
+            name if name.eq_ignore_ascii_case("deny") => UnmappedPaths::Deny,
+            name if name.eq_ignore_ascii_case("allow") => UnmappedPaths::Allow,
+            dir => UnmappedPaths::Redirect(dir.to_string()),
+        };
+        config = config.unmapped_paths(policy);
+    }
+    for (key, access) in [
+        ("read_only", PathAccess::ReadOnly),
+        ("read_write", PathAccess::ReadWrite),
+    ] {
+        let Some(YamlValue::Sequence(paths)) = map.get(YamlValue::String(key.to_string())) else {
+            continue;
+        };
+        for path in paths.iter().filter_map(|path| path.as_str()) {
+            config = config.path_access(path, access);
         }
-        YamlValue::String(host) => SandboxConfig::new().enable_network(host),
-        _ => SandboxConfig::new().enable_network(""),
     }
+    config
 }
 
 fn load_registry(path: &str) -> Result<windows::registry::Registry, VmError> {
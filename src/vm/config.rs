@@ -1,8 +1,9 @@
 use std::collections::BTreeMap;
 use std::path::Path;
+use std::sync::Arc;
 
 use super::windows;
-use super::VmError;
+use super::{GuestFs, VmError, PAGE_SIZE};
 use crate::settings::BypassSettings;
 
 #[derive(Debug, Clone, Copy)]
@@ -18,6 +19,9 @@ pub enum Architecture {
     X86_64,
 }
 
+const DEFAULT_HEAP_SIZE: u32 = 0x20_0000;
+const DEFAULT_THREAD_QUANTUM: u64 = 10_000;
+
 pub type PathMapping = BTreeMap<String, String>;
 
 pub(crate) const fn stdcall_args(args: u32) -> u32 {
@@ -30,8 +34,13 @@ pub struct VmConfig {
     architecture: Architecture,
     properties: Option<windows::registry::Registry>,
     paths: PathMapping,
+    mounts: BTreeMap<String, Arc<dyn GuestFs>>,
     font_path: Option<String>,
     execution_limit: u64,
+    heap_size: u32,
+    heap_debug: bool,
+    thread_quantum: u64,
+    decode_cache: bool,
     sandbox: Option<SandboxConfig>,
     bypass: BypassSettings,
 }
@@ -43,8 +52,13 @@ impl VmConfig {
             architecture: Architecture::X86,
             properties: None,
             paths: PathMapping::new(),
+            mounts: BTreeMap::new(),
             font_path: None,
             execution_limit: 1_000_000,
+            heap_size: DEFAULT_HEAP_SIZE,
+            heap_debug: false,
+            thread_quantum: DEFAULT_THREAD_QUANTUM,
+            decode_cache: true,
             sandbox: None,
             bypass: BypassSettings::default(),
         }
@@ -104,6 +118,18 @@ impl VmConfig {
         &mut self.paths
     }
 
+    /// Serve guest paths under `guest_prefix` from `fs` rather than the host
+    /// filesystem. A mount wins over a `paths` mapping with the same prefix.
+    pub fn mount(self, guest_prefix: impl Into<String>, fs: impl GuestFs + 'static) -> Self {
+        let mut config = self;
+        config.mounts.insert(guest_prefix.into(), Arc::new(fs));
+        config
+    }
+
+    pub(crate) fn mounts_ref(&self) -> &BTreeMap<String, Arc<dyn GuestFs>> {
+        &self.mounts
+    }
+
     pub fn font_path(self, path: impl Into<String>) -> Self {
         let mut config = self;
         config.font_path = Some(path.into());
@@ -124,6 +150,51 @@ impl VmConfig {
         self.execution_limit
     }
 
+    /// Initial size of the process heap; it grows into free address space when exhausted.
+    pub fn heap_size(self, size: u32) -> Self {
+        let mut config = self;
+        config.heap_size = size.div_ceil(PAGE_SIZE).max(1) * PAGE_SIZE;
+        config
+    }
+
+    pub fn heap_size_value(&self) -> u32 {
+        self.heap_size
+    }
+
+    /// Guard heap blocks with canaries and track invalid frees and leaks; see `Vm::heap_report`.
+    pub fn heap_debug(self, enabled: bool) -> Self {
+        let mut config = self;
+        config.heap_debug = enabled;
+        config
+    }
+
+    pub fn heap_debug_value(&self) -> bool {
+        self.heap_debug
+    }
+
+    /// Instructions a guest thread runs before the scheduler switches to the next ready thread.
+    pub fn thread_quantum(self, steps: u64) -> Self {
+        let mut config = self;
+        config.thread_quantum = steps.max(1);
+        config
+    }
+
+    pub fn thread_quantum_value(&self) -> u64 {
+        self.thread_quantum
+    }
+
+    /// Cache decoded instructions per address; on by default. Turning it off decodes
+    /// every instruction afresh, which is only useful to measure the cache.
+    pub fn decode_cache(self, enabled: bool) -> Self {
+        let mut config = self;
+        config.decode_cache = enabled;
+        config
+    }
+
+    pub fn decode_cache_value(&self) -> bool {
+        self.decode_cache
+    }
+
     pub fn sandbox(self, sandbox: SandboxConfig) -> Self {
         let mut config = self;
         config.sandbox = Some(sandbox);
@@ -156,11 +227,13 @@ impl Default for VmConfig {
     }
 }
 
-// Sandbox configuration for host-side controls like network access.
+// Sandbox configuration for host-side controls like network and file access.
 #[derive(Debug, Clone)]
 pub struct SandboxConfig {
     network_enabled: bool,
     network_fallback_host: Option<String>,
+    unmapped_paths: UnmappedPaths,
+    path_access: BTreeMap<String, PathAccess>,
 }
 
 impl SandboxConfig {
@@ -168,6 +241,8 @@ impl SandboxConfig {
         Self {
             network_enabled: false,
             network_fallback_host: None,
+            unmapped_paths: UnmappedPaths::Allow,
+            path_access: BTreeMap::new(),
         }
     }
 
@@ -193,6 +268,29 @@ impl SandboxConfig {
     pub fn network_fallback_host(&self) -> Option<&str> {
         self.network_fallback_host.as_deref()
     }
+
+    /// What guest paths outside every path mapping and mount resolve to.
+    pub fn unmapped_paths(self, policy: UnmappedPaths) -> Self {
+        let mut config = self;
+        config.unmapped_paths = policy;
+        config
+    }
+
+    pub fn unmapped_paths_value(&self) -> &UnmappedPaths {
+        &self.unmapped_paths
+    }
+
+    /// Restrict guest paths under `guest_prefix`; the longest matching prefix
+    /// applies, and paths without a rule are read-write.
+    pub fn path_access(self, guest_prefix: impl Into<String>, access: PathAccess) -> Self {
+        let mut config = self;
+        config.path_access.insert(guest_prefix.into(), access);
+        config
+    }
+
+    pub fn path_access_rules(&self) -> &BTreeMap<String, PathAccess> {
+        &self.path_access
+    }
 }
 
 impl Default for SandboxConfig {
@@ -201,6 +299,26 @@ impl Default for SandboxConfig {
     }
 }
 
+/// Sandbox policy for guest paths no mapping covers.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum UnmappedPaths {
+    /// Use the path on the host as given.
+    #[default]
+    Allow,
+    /// Report the path as not found.
+    Deny,
+    /// Serve the path from this host directory instead, with the drive letter
+    /// as the first component (`C:\Temp\a.txt` becomes `<dir>/C/Temp/a.txt`).
+    Redirect(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PathAccess {
+    ReadOnly,
+    #[default]
+    ReadWrite,
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
 pub enum MessageBoxMode {
     Stdout,
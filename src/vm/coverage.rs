@@ -0,0 +1,378 @@
+//! Guest code coverage: executed basic blocks per module, keyed by RVA.
+//!
+//! Blocks are stored relative to their module's base, so runs that load a DLL at
+//! different addresses, in different `Vm`s or processes, merge into one view.
+//! `Coverage` reads and writes drcov files (the format of DynamoRIO's drcov
+//! tool, which Lighthouse, Lightkeeper and Cartographer load into IDA, Ghidra and
+//! Binary Ninja) and writes Lighthouse's `module+offset` text lists.
+
+use std::collections::{BTreeMap, HashSet};
+use std::fmt::Write as _;
+use std::path::Path;
+
+use super::{module_key, VmError};
+
+/// Executed code of one module.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ModuleCoverage {
+    name: String,
+    path: String,
+    base: u32,
+    size: u32,
+    // Block RVA to its length in bytes.
+    blocks: BTreeMap<u32, u32>,
+}
+
+impl ModuleCoverage {
+    /// File name of the module, as it appears in `path`.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// Load base the module was first seen at.
+    pub fn base(&self) -> u32 {
+        self.base
+    }
+
+    pub fn size(&self) -> u32 {
+        self.size
+    }
+
+    /// Executed basic blocks as `(rva, length)`, in address order. Blocks from
+    /// different runs may overlap.
+    pub fn blocks(&self) -> impl Iterator<Item = (u32, u32)> + '_ {
+        self.blocks.iter().map(|(rva, len)| (*rva, *len))
+    }
+
+    /// Whether the byte at `rva` belongs to an executed block.
+    pub fn contains(&self, rva: u32) -> bool {
+        self.blocks
+            .range(..=rva)
+            .next_back()
+            .is_some_and(|(start, len)| rva - start < *len)
+    }
+
+    /// Number of distinct code bytes executed.
+    pub fn covered_bytes(&self) -> u32 {
+        let mut covered = 0u32;
+        let mut end = 0u32;
+        for (&rva, &len) in &self.blocks {
+            let block_end = rva.saturating_add(len);
+            if block_end > end {
+                covered += block_end - rva.max(end);
+                end = block_end;
+            }
+        }
+        covered
+    }
+
+    fn add_block(&mut self, rva: u32, len: u32) {
+        let entry = self.blocks.entry(rva).or_insert(len);
+        *entry = (*entry).max(len);
+    }
+}
+
+/// Executed basic blocks of every module a run touched.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Coverage {
+    // Keyed by `module_key`, so names differing only in case merge.
+    modules: BTreeMap<String, ModuleCoverage>,
+}
+
+impl Coverage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.modules.values().all(|module| module.blocks.is_empty())
+    }
+
+    pub fn modules(&self) -> impl Iterator<Item = &ModuleCoverage> {
+        self.modules.values()
+    }
+
+    /// Coverage of a module by file name or path, case-insensitively.
+    pub fn module(&self, name: &str) -> Option<&ModuleCoverage> {
+        self.modules.get(&module_key(name))
+    }
+
+    /// Add another run's blocks. Modules match by file name, not by base.
+    pub fn merge(&mut self, other: &Coverage) {
+        for module in other.modules.values() {
+            let merged = self.module_entry(&module.path, module.base, module.size);
+            for (&rva, &len) in &module.blocks {
+                merged.add_block(rva, len);
+            }
+        }
+    }
+
+    /// Record a block executed in the module loaded from `path`.
+    pub(crate) fn add_block(&mut self, path: &str, base: u32, size: u32, rva: u32, len: u32) {
+        self.module_entry(path, base, size).add_block(rva, len);
+    }
+
+    fn module_entry(&mut self, path: &str, base: u32, size: u32) -> &mut ModuleCoverage {
+        let module = self
+            .modules
+            .entry(module_key(path))
+            .or_insert_with(|| ModuleCoverage {
+                name: file_name(path).to_string(),
+                path: path.to_string(),
+                base,
+                size,
+                blocks: BTreeMap::new(),
+            });
+        module.size = module.size.max(size);
+        module
+    }
+
+    /// Serialize as a drcov version 2 file with a binary block table.
+    pub fn to_drcov(&self) -> Vec<u8> {
+        let modules: Vec<&ModuleCoverage> = self.modules.values().collect();
+        let mut header = String::new();
+        header.push_str("DRCOV VERSION: 2\nDRCOV FLAVOR: pe_vm\n");
+        let _ = writeln!(header, "Module Table: version 2, count {}", modules.len());
+        header.push_str("Columns: id, base, end, entry, checksum, timestamp, path\n");
+        for (id, module) in modules.iter().enumerate() {
+            let _ = writeln!(
+                header,
+                "{id:2}, 0x{:08x}, 0x{:08x}, 0x{:08x}, 0x{:08x}, 0x{:08x}, {}",
+                module.base,
+                module.base.wrapping_add(module.size),
+                0,
+                0,
+                0,
+                module.path
+            );
+        }
+        let blocks: Vec<(u16, u32, u32)> = modules
+            .iter()
+            .enumerate()
+            .flat_map(|(id, module)| {
+                module
+                    .blocks
+                    .iter()
+                    .flat_map(move |(&rva, &len)| split_block(id as u16, rva, len))
+            })
+            .collect();
+        let _ = writeln!(header, "BB Table: {} bbs", blocks.len());
+        let mut out = header.into_bytes();
+        for (id, rva, len) in blocks {
+            out.extend_from_slice(&rva.to_le_bytes());
+            out.extend_from_slice(&(len as u16).to_le_bytes());
+            out.extend_from_slice(&id.to_le_bytes());
+        }
+        out
+    }
+
+    /// Parse a drcov file (versions 2 to 4, binary block table).
+    pub fn from_drcov(data: &[u8]) -> Result<Self, VmError> {
+        let mut reader = LineReader { data, pos: 0 };
+        let version = reader.line()?;
+        if !version.starts_with("DRCOV VERSION:") {
+            return Err(invalid("missing DRCOV VERSION"));
+        }
+        let mut line = reader.line()?;
+        if line.starts_with("DRCOV FLAVOR:") {
+            line = reader.line()?;
+        }
+        let count = line
+            .strip_prefix("Module Table:")
+            .and_then(|rest| rest.rsplit("count").next())
+            .and_then(|count| count.trim().parse::<usize>().ok())
+            .ok_or_else(|| invalid("bad module table header"))?;
+        let columns_line = reader.line()?;
+        let columns: Vec<&str> = columns_line
+            .strip_prefix("Columns:")
+            .ok_or_else(|| invalid("missing module columns"))?
+            .split(',')
+            .map(str::trim)
+            .collect();
+        let column = |name: &str| {
+            columns
+                .iter()
+                .position(|column| *column == name)
+                .ok_or_else(|| invalid("missing module column"))
+        };
+        let (base_col, end_col, path_col) = (
+            column("base").or_else(|_| column("start"))?,
+            column("end")?,
+            column("path")?,
+        );
+
+        let mut modules = Vec::with_capacity(count);
+        for _ in 0..count {
+            let row = reader.line()?;
+            // The path is last and may itself contain commas.
+            let fields: Vec<&str> = row.splitn(columns.len(), ',').map(str::trim).collect();
+            if fields.len() != columns.len() {
+                return Err(invalid("short module row"));
+            }
+            let base = parse_hex(fields[base_col])?;
+            let end = parse_hex(fields[end_col])?;
+            modules.push((fields[path_col].to_string(), base, end.saturating_sub(base)));
+        }
+
+        let bb_count = reader
+            .line()?
+            .strip_prefix("BB Table:")
+            .and_then(|rest| rest.split_whitespace().next())
+            .and_then(|count| count.parse::<usize>().ok())
+            .ok_or_else(|| invalid("bad BB table header"))?;
+        let table = data
+            .get(reader.pos..)
+            .filter(|table| table.len() >= bb_count * 8)
+            .ok_or_else(|| invalid("truncated BB table"))?;
+
+        let mut coverage = Coverage::new();
+        for (path, base, size) in &modules {
+            coverage.module_entry(path, *base, *size);
+        }
+        for entry in table.chunks_exact(8).take(bb_count) {
+            let rva = u32::from_le_bytes([entry[0], entry[1], entry[2], entry[3]]);
+            let len = u16::from_le_bytes([entry[4], entry[5]]) as u32;
+            let id = u16::from_le_bytes([entry[6], entry[7]]) as usize;
+            let (path, base, size) = modules.get(id).ok_or_else(|| invalid("bad module id"))?;
+            coverage.add_block(path, *base, *size, rva, len);
+        }
+        Ok(coverage)
+    }
+
+    pub fn write_drcov(&self, path: impl AsRef<Path>) -> Result<(), VmError> {
+        std::fs::write(path, self.to_drcov())?;
+        Ok(())
+    }
+
+    pub fn read_drcov(path: impl AsRef<Path>) -> Result<Self, VmError> {
+        Self::from_drcov(&std::fs::read(path)?)
+    }
+
+    /// One `module+rva` line (hex RVA) per executed block, Lighthouse's
+    /// module+offset format.
+    pub fn to_module_offsets(&self) -> String {
+        let mut out = String::new();
+        for module in self.modules.values() {
+            for &rva in module.blocks.keys() {
+                let _ = writeln!(out, "{}+{rva:x}", module.name);
+            }
+        }
+        out
+    }
+}
+
+/// Instruction addresses a run executed, attributed to modules when asked.
+#[derive(Debug, Default)]
+pub(crate) struct CoverageCollector {
+    pub(crate) hits: HashSet<u32>,
+    /// Blocks of modules unloaded (or replaced) since collection started.
+    pub(crate) done: Coverage,
+}
+
+// drcov block sizes are 16 bits; longer runs become several entries.
+fn split_block(id: u16, rva: u32, len: u32) -> impl Iterator<Item = (u16, u32, u32)> {
+    let max = u16::MAX as u32;
+    (0..len.div_ceil(max)).map(move |chunk| {
+        let offset = chunk * max;
+        (id, rva + offset, (len - offset).min(max))
+    })
+}
+
+fn file_name(path: &str) -> &str {
+    path.rsplit(['\\', '/']).next().unwrap_or(path)
+}
+
+fn parse_hex(text: &str) -> Result<u32, VmError> {
+    let digits = text.trim_start_matches("0x").trim_start_matches("0X");
+    u64::from_str_radix(digits, 16)
+        .ok()
+        .and_then(|value| u32::try_from(value).ok())
+        .ok_or_else(|| invalid("bad module address"))
+}
+
+fn invalid(msg: &str) -> VmError {
+    VmError::Io(std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        format!("malformed drcov file: {msg}"),
+    ))
+}
+
+struct LineReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> LineReader<'a> {
+    fn line(&mut self) -> Result<&'a str, VmError> {
+        let rest = &self.data[self.pos..];
+        let len = rest
+            .iter()
+            .position(|byte| *byte == b'\n')
+            .ok_or_else(|| invalid("truncated header"))?;
+        self.pos += len + 1;
+        let line = std::str::from_utf8(&rest[..len]).map_err(|_| invalid("header is not UTF-8"))?;
+        Ok(line.trim_end_matches('\r'))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample() -> Coverage {
+        let mut coverage = Coverage::new();
+        coverage.add_block("C:\\app\\Legacy.dll", 0x1000_0000, 0x5000, 0x1000, 0x10);
+        coverage.add_block("C:\\app\\Legacy.dll", 0x1000_0000, 0x5000, 0x1020, 4);
+        coverage.add_block("C:\\app\\main.exe", 0x40_0000, 0x3000, 0x1000, 0x2_0000);
+        coverage
+    }
+
+    #[test]
+    fn drcov_round_trip() {
+        let data = sample().to_drcov();
+        let text = String::from_utf8_lossy(&data);
+        assert!(text.starts_with(
+            "DRCOV VERSION: 2\nDRCOV FLAVOR: pe_vm\nModule Table: version 2, count 2\n\
+             Columns: id, base, end, entry, checksum, timestamp, path\n\
+             \x200, 0x10000000, 0x10005000, 0x00000000, 0x00000000, 0x00000000, C:\\app\\Legacy.dll\n"
+        ));
+        // The 0x20000-byte block needs three 16-bit entries.
+        assert!(text.contains("BB Table: 5 bbs\n"));
+
+        let parsed = Coverage::from_drcov(&data).expect("parse");
+        let legacy = parsed.module("legacy.dll").expect("module");
+        assert_eq!(legacy.name(), "Legacy.dll");
+        assert_eq!(legacy.base(), 0x1000_0000);
+        assert_eq!(
+            legacy.blocks().collect::<Vec<_>>(),
+            [(0x1000, 0x10), (0x1020, 4)]
+        );
+        let main = parsed.module("MAIN.EXE").expect("module");
+        assert_eq!(main.covered_bytes(), 0x2_0000);
+        assert!(Coverage::from_drcov(&data[..data.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn merge_unions_blocks_by_module_name() {
+        let mut merged = sample();
+        let mut other = Coverage::new();
+        // Loaded at another base in the other run.
+        other.add_block("D:\\LEGACY.DLL", 0x2000_0000, 0x5000, 0x1008, 0x10);
+        other.add_block("D:\\LEGACY.DLL", 0x2000_0000, 0x5000, 0x3000, 2);
+        merged.merge(&other);
+
+        let legacy = merged.module("legacy.dll").expect("module");
+        assert_eq!(legacy.base(), 0x1000_0000);
+        assert_eq!(legacy.covered_bytes(), 0x18 + 4 + 2);
+        assert!(legacy.contains(0x1017));
+        assert!(!legacy.contains(0x1018));
+        assert_eq!(
+            merged.to_module_offsets(),
+            "Legacy.dll+1000\nLegacy.dll+1008\nLegacy.dll+1020\nLegacy.dll+3000\nmain.exe+1000\n"
+        );
+    }
+}
@@ -0,0 +1,263 @@
+//! Debugger state: breakpoints, watchpoints, import breaks and the hook tools
+//! observe them through.
+//!
+//! Only the outermost run can stop. Guest code that the host re-enters (window
+//! procedures, COM calls, exception filters) still reports to the hook, but a
+//! `Stop` there is treated as `Continue` because the host frames above it cannot
+//! be suspended.
+
+use std::cell::Cell;
+use std::collections::{BTreeSet, HashSet};
+
+use super::{AccessKind, HostFunction, Vm};
+
+/// A 32-bit register addressable through `Vm::register`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Register {
+    Eax,
+    Ecx,
+    Edx,
+    Ebx,
+    Esp,
+    Ebp,
+    Esi,
+    Edi,
+    Eip,
+    Eflags,
+}
+
+/// An EFLAGS bit addressable through `Vm::flag`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Flag {
+    Carry,
+    Parity,
+    Adjust,
+    Zero,
+    Sign,
+    Direction,
+    Overflow,
+}
+
+/// Which accesses a watchpoint reports. Host functions' accesses count too, as do
+/// the executor's reads of instruction bytes, so watch data rather than code.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchKind {
+    Read,
+    Write,
+    ReadWrite,
+}
+
+/// A guest access that overlapped a watchpoint.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WatchHit {
+    pub addr: u32,
+    pub len: u32,
+    pub access: AccessKind,
+    /// The instruction that made the access; EIP has already moved past it.
+    pub eip: u32,
+}
+
+/// Why a debugged run handed control back.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StopReason {
+    /// EIP reached an execution breakpoint; the instruction has not run.
+    Breakpoint(u32),
+    Watchpoint(WatchHit),
+    /// A call reached a host import matched by `Vm::break_on_import`. EIP is the
+    /// import's address and its arguments are on the stack above the return address.
+    ImportCall {
+        name: String,
+        addr: u32,
+    },
+    /// `Vm::step` or `Vm::step_over` finished.
+    Step,
+    /// `Vm::run_until` reached its address.
+    Reached(u32),
+    /// A hook asked to stop before the instruction at this address.
+    Hook(u32),
+    /// The suspended call returned; EAX holds its result.
+    Exited,
+}
+
+/// What the run does after a hook callback.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DebugAction {
+    Continue,
+    Stop,
+}
+
+/// Callbacks for debugger events. Every method has a default, so tools implement
+/// only what they need; returning `Stop` suspends the run with the matching
+/// `StopReason`.
+pub trait DebugHook {
+    /// Before every instruction of a run with the hook installed.
+    fn on_instruction(&mut self, _vm: &mut Vm, _eip: u32) -> DebugAction {
+        DebugAction::Continue
+    }
+
+    fn on_breakpoint(&mut self, _vm: &mut Vm, _addr: u32) -> DebugAction {
+        DebugAction::Stop
+    }
+
+    fn on_watchpoint(&mut self, _vm: &mut Vm, _hit: &WatchHit) -> DebugAction {
+        DebugAction::Stop
+    }
+
+    /// `name` is `module!function` as bound in the import table.
+    fn on_import_call(&mut self, _vm: &mut Vm, _name: &str) -> DebugAction {
+        DebugAction::Stop
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Watchpoint {
+    pub(crate) start: u32,
+    pub(crate) len: u32,
+    pub(crate) kind: WatchKind,
+}
+
+impl Watchpoint {
+    fn matches(&self, addr: u32, len: u32, access: AccessKind) -> bool {
+        let wanted = match access {
+            AccessKind::Read | AccessKind::Execute => self.kind != WatchKind::Write,
+            AccessKind::Write => self.kind != WatchKind::Read,
+        };
+        let end = addr as u64 + len.max(1) as u64;
+        let watch_end = self.start as u64 + self.len.max(1) as u64;
+        wanted && (addr as u64) < watch_end && (self.start as u64) < end
+    }
+}
+
+/// Where a stepping command stops.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum StepTarget {
+    /// After one instruction.
+    Into,
+    /// When EIP reaches `addr` with ESP at or above `esp` (past a CALL's return).
+    Over {
+        addr: u32,
+        esp: u32,
+    },
+    Until(u32),
+}
+
+#[derive(Default)]
+pub(crate) struct Debugger {
+    pub(crate) breakpoints: BTreeSet<u32>,
+    pub(crate) watchpoints: Vec<Watchpoint>,
+    /// Lowercased `module!function` or bare function names.
+    pub(crate) import_breaks: HashSet<String>,
+    pub(crate) hook: Option<Box<dyn DebugHook>>,
+    pub(crate) step: Option<StepTarget>,
+    /// Thread whose outermost call is suspended, waiting for `Vm::resume`.
+    pub(crate) session: Option<u32>,
+    /// Nesting of `run_threads`; only depth 1 may stop.
+    pub(crate) depth: u32,
+    /// Address already reported, so resuming does not stop on it again.
+    pub(crate) resume_at: Option<u32>,
+    /// Host import the run stopped in front of, with the address it was called at.
+    pub(crate) import: Option<(u32, HostFunction)>,
+    /// Stop decided inside an instruction, reported once it completes.
+    pub(crate) stop: Option<StopReason>,
+    // Set by memory accessors, which only hold `&Vm`.
+    hit: Cell<Option<(u32, u32, AccessKind)>>,
+}
+
+impl Debugger {
+    /// Whether the run loop has to consult the debugger at all.
+    pub(crate) fn active(&self) -> bool {
+        !self.breakpoints.is_empty()
+            || !self.watchpoints.is_empty()
+            || !self.import_breaks.is_empty()
+            || self.hook.is_some()
+            || self.step.is_some()
+            || self.import.is_some()
+    }
+
+    /// Forget a suspended run before a new outermost call replaces it.
+    pub(crate) fn end_session(&mut self) {
+        self.session = None;
+        self.step = None;
+        self.resume_at = None;
+        self.import = None;
+        self.stop = None;
+    }
+
+    /// Record the first watched access of the current instruction.
+    pub(crate) fn record_access(&self, addr: u32, len: u32, access: AccessKind) {
+        if self.watchpoints.is_empty() || self.hit.get().is_some() {
+            return;
+        }
+        if self
+            .watchpoints
+            .iter()
+            .any(|watch| watch.matches(addr, len, access))
+        {
+            self.hit.set(Some((addr, len, access)));
+        }
+    }
+
+    /// The access recorded since the last call, attributed to the instruction at `eip`.
+    pub(crate) fn take_hit(&self, eip: u32) -> Option<WatchHit> {
+        self.hit.take().map(|(addr, len, access)| WatchHit {
+            addr,
+            len,
+            access,
+            eip,
+        })
+    }
+
+    pub(crate) fn breaks_on_import(&self, label: &str) -> bool {
+        if self.import_breaks.is_empty() {
+            return false;
+        }
+        let label = label.to_ascii_lowercase();
+        let function = label.rsplit('!').next().unwrap_or(&label);
+        self.import_breaks.contains(&label) || self.import_breaks.contains(function)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn watchpoints_match_overlapping_accesses_of_their_kind() {
+        let watch = Watchpoint {
+            start: 0x1000,
+            len: 4,
+            kind: WatchKind::Write,
+        };
+        assert!(watch.matches(0x0FFE, 4, AccessKind::Write));
+        assert!(watch.matches(0x1003, 1, AccessKind::Write));
+        assert!(!watch.matches(0x1004, 4, AccessKind::Write));
+        assert!(!watch.matches(0x1000, 4, AccessKind::Read));
+
+        let mut debugger = Debugger::default();
+        debugger.watchpoints.push(Watchpoint {
+            start: 0xFFFF_FFFC,
+            len: 4,
+            kind: WatchKind::ReadWrite,
+        });
+        debugger.record_access(0xFFFF_FFFE, 2, AccessKind::Read);
+        debugger.record_access(0xFFFF_FFFC, 4, AccessKind::Write);
+        let hit = debugger.take_hit(0x401000).expect("hit");
+        assert_eq!(
+            (hit.addr, hit.access, hit.eip),
+            (0xFFFF_FFFE, AccessKind::Read, 0x401000)
+        );
+        assert!(debugger.take_hit(0x401000).is_none());
+    }
+
+    #[test]
+    fn import_breaks_match_qualified_or_bare_names() {
+        let mut debugger = Debugger::default();
+        debugger.import_breaks.insert("messageboxa".to_string());
+        debugger
+            .import_breaks
+            .insert("kernel32.dll!getlasterror".to_string());
+        assert!(debugger.breaks_on_import("USER32.dll!MessageBoxA"));
+        assert!(debugger.breaks_on_import("KERNEL32.dll!GetLastError"));
+        assert!(!debugger.breaks_on_import("ntdll.dll!GetLastError"));
+    }
+}
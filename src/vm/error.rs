@@ -2,17 +2,32 @@ use std::fmt;
 
 use crate::pe::PeParseError;
 
+use super::{AccessKind, StopReason};
+
 #[derive(Debug)]
 pub enum VmError {
     Io(std::io::Error),
     Pe(PeParseError),
     MemoryOutOfRange,
+    /// Guest access to an unmapped page or one whose protection denies the access.
+    AccessViolation {
+        addr: u32,
+        access: AccessKind,
+        eip: u32,
+    },
     OutOfMemory,
     FpuStackOverflow,
     FpuStackUnderflow,
     DivideError,
     UnsupportedInstruction(u8),
     ExecutionLimit,
+    /// A guest exception (a breakpoint, `int` or RaiseException code) that no handler took.
+    Exception {
+        code: u32,
+        address: u32,
+    },
+    /// Every guest thread is blocked and none of their waits can ever be satisfied.
+    Deadlock,
     MissingExport(String),
     MissingImports(Vec<String>),
     NoImage,
@@ -23,6 +38,14 @@ pub enum VmError {
         dll: String,
         function: String,
     },
+    /// The debugger suspended the run; `Vm::resume` continues it.
+    Stopped(StopReason),
+    /// A replayed run made a host call the recording does not have at this point.
+    ReplayDivergence {
+        seq: u64,
+        expected: Option<String>,
+        found: String,
+    },
 }
 
 impl fmt::Display for VmError {
@@ -31,12 +54,20 @@ impl fmt::Display for VmError {
             VmError::Io(err) => write!(f, "io error: {err}"),
             VmError::Pe(err) => write!(f, "pe error: {err}"),
             VmError::MemoryOutOfRange => write!(f, "memory out of range"),
+            VmError::AccessViolation { addr, access, eip } => write!(
+                f,
+                "access violation: {access} of 0x{addr:08X} at eip=0x{eip:08X}"
+            ),
             VmError::OutOfMemory => write!(f, "out of memory"),
             VmError::FpuStackOverflow => write!(f, "fpu stack overflow"),
             VmError::FpuStackUnderflow => write!(f, "fpu stack underflow"),
             VmError::DivideError => write!(f, "divide error"),
             VmError::UnsupportedInstruction(op) => write!(f, "unsupported instruction 0x{op:02X}"),
             VmError::ExecutionLimit => write!(f, "execution limit reached"),
+            VmError::Exception { code, address } => {
+                write!(f, "unhandled exception 0x{code:08X} at 0x{address:08X}")
+            }
+            VmError::Deadlock => write!(f, "deadlock: all guest threads are blocked"),
             VmError::MissingExport(name) => write!(f, "missing export: {name}"),
             VmError::MissingImports(list) => write!(f, "missing imports: {}", list.join(", ")),
             VmError::NoImage => write!(f, "no image loaded"),
@@ -51,6 +82,23 @@ impl fmt::Display for VmError {
             VmError::NotImplemented { dll, function } => {
                 write!(f, "not implemented: {dll}!{function}")
             }
+            VmError::Stopped(reason) => write!(f, "stopped by debugger: {reason:?}"),
+            VmError::ReplayDivergence {
+                seq,
+                expected: Some(expected),
+                found,
+            } => write!(
+                f,
+                "replay diverged at host call {seq}: expected {expected}, got {found}"
+            ),
+            VmError::ReplayDivergence {
+                seq,
+                expected: None,
+                found,
+            } => write!(
+                f,
+                "replay diverged at host call {seq}: recording ended before {found}"
+            ),
         }
     }
 }
@@ -0,0 +1,304 @@
+//! Structured exception handling state: exception records, the vectored handler
+//! lists and the per-thread stack of dispatches in progress.
+//!
+//! Dispatch runs on the guest thread itself. Each handler is called with
+//! [`DISPATCH_RETURN`] as its return address, and reaching that address hands the
+//! result back to the dispatcher, so handlers that never return (an `__except`
+//! block taking over) simply abandon their dispatch. MSVC C++ catch blocks work
+//! the same way: they return to [`CATCH_RETURN`] with their continuation address.
+
+use super::{AccessKind, VmError};
+
+pub(crate) const STATUS_BREAKPOINT: u32 = 0x8000_0003;
+pub(crate) const STATUS_ACCESS_VIOLATION: u32 = 0xC000_0005;
+pub(crate) const STATUS_ILLEGAL_INSTRUCTION: u32 = 0xC000_001D;
+pub(crate) const STATUS_NONCONTINUABLE_EXCEPTION: u32 = 0xC000_0025;
+pub(crate) const STATUS_INVALID_DISPOSITION: u32 = 0xC000_0026;
+pub(crate) const STATUS_UNWIND: u32 = 0xC000_0027;
+pub(crate) const STATUS_INTEGER_DIVIDE_BY_ZERO: u32 = 0xC000_0094;
+pub(crate) const STATUS_PRIVILEGED_INSTRUCTION: u32 = 0xC000_0096;
+pub(crate) const STATUS_STACK_BUFFER_OVERRUN: u32 = 0xC000_0409;
+pub(crate) const STATUS_ASSERTION_FAILURE: u32 = 0xC000_0420;
+/// `'msc' | 0xE0000000`: the code `_CxxThrowException` raises.
+pub(crate) const EH_EXCEPTION_NUMBER: u32 = 0xE06D_7363;
+/// First parameter of a C++ exception record (`EH_MAGIC_NUMBER1`).
+pub(crate) const EH_MAGIC_NUMBER: u32 = 0x1993_0520;
+
+pub(crate) const EXCEPTION_NONCONTINUABLE: u32 = 0x1;
+pub(crate) const EXCEPTION_UNWINDING: u32 = 0x2;
+pub(crate) const EXCEPTION_EXIT_UNWIND: u32 = 0x4;
+pub(crate) const EXCEPTION_MAXIMUM_PARAMETERS: usize = 15;
+
+// Vectored handler and unhandled-exception filter results.
+pub(crate) const EXCEPTION_EXECUTE_HANDLER: u32 = 1;
+pub(crate) const EXCEPTION_CONTINUE_SEARCH: u32 = 0;
+pub(crate) const EXCEPTION_CONTINUE_EXECUTION: u32 = 0xFFFF_FFFF;
+
+// EXCEPTION_DISPOSITION values returned by frame-based handlers.
+pub(crate) const DISPOSITION_CONTINUE_EXECUTION: u32 = 0;
+pub(crate) const DISPOSITION_CONTINUE_SEARCH: u32 = 1;
+pub(crate) const DISPOSITION_NESTED_EXCEPTION: u32 = 2;
+
+/// `FS:[0]` value terminating the registration chain.
+pub(crate) const END_OF_CHAIN: u32 = 0xFFFF_FFFF;
+/// Return address given to guest exception handlers; never mapped.
+pub(crate) const DISPATCH_RETURN: u32 = 0xFFFF_FFF0;
+/// Return address given to C++ catch blocks; never mapped.
+pub(crate) const CATCH_RETURN: u32 = 0xFFFF_FFE0;
+
+/// Host-side copy of an `EXCEPTION_RECORD`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct ExceptionRecord {
+    pub(crate) code: u32,
+    pub(crate) flags: u32,
+    pub(crate) address: u32,
+    pub(crate) params: Vec<u32>,
+}
+
+impl ExceptionRecord {
+    pub(crate) fn new(code: u32, address: u32) -> Self {
+        Self {
+            code,
+            flags: 0,
+            address,
+            params: Vec::new(),
+        }
+    }
+
+    /// The guest exception a CPU fault at `eip` raises; `None` for emulator errors.
+    pub(crate) fn from_fault(err: &VmError, eip: u32) -> Option<Self> {
+        match *err {
+            VmError::AccessViolation { addr, access, eip } => Some(Self {
+                params: vec![access.exception_code(), addr],
+                ..Self::new(STATUS_ACCESS_VIOLATION, eip)
+            }),
+            VmError::DivideError => Some(Self::new(STATUS_INTEGER_DIVIDE_BY_ZERO, eip)),
+            // __fastfail ends the process without running any handler.
+            VmError::Exception { code, .. } if code == STATUS_STACK_BUFFER_OVERRUN => None,
+            VmError::Exception { code, address } => Some(Self::new(code, address)),
+            _ => None,
+        }
+    }
+
+    /// The error reported to the host when no handler dealt with the exception.
+    pub(crate) fn into_error(self) -> VmError {
+        match self.code {
+            STATUS_ACCESS_VIOLATION if self.params.len() >= 2 => {
+                let access = match self.params[0] {
+                    1 => AccessKind::Write,
+                    8 => AccessKind::Execute,
+                    _ => AccessKind::Read,
+                };
+                VmError::AccessViolation {
+                    addr: self.params[1],
+                    access,
+                    eip: self.address,
+                }
+            }
+            STATUS_INTEGER_DIVIDE_BY_ZERO => VmError::DivideError,
+            code => VmError::Exception {
+                code,
+                address: self.address,
+            },
+        }
+    }
+}
+
+/// Handlers registered with AddVectoredExceptionHandler/AddVectoredContinueHandler.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct VectoredHandlers {
+    exception_handlers: Vec<(u32, u32)>,
+    continue_handlers: Vec<(u32, u32)>,
+    next_cookie: u32,
+}
+
+impl VectoredHandlers {
+    /// Register `handler` first or last in its list; returns the removal cookie.
+    pub(crate) fn add(&mut self, continue_handler: bool, first: bool, handler: u32) -> u32 {
+        self.next_cookie += 1;
+        let cookie = self.next_cookie;
+        let list = self.list_mut(continue_handler);
+        if first {
+            list.insert(0, (cookie, handler));
+        } else {
+            list.push((cookie, handler));
+        }
+        cookie
+    }
+
+    pub(crate) fn remove(&mut self, continue_handler: bool, cookie: u32) -> bool {
+        let list = self.list_mut(continue_handler);
+        let before = list.len();
+        list.retain(|&(id, _)| id != cookie);
+        list.len() != before
+    }
+
+    pub(crate) fn get(&self, continue_handler: bool, index: usize) -> Option<u32> {
+        let list = if continue_handler {
+            &self.continue_handlers
+        } else {
+            &self.exception_handlers
+        };
+        list.get(index).map(|&(_, handler)| handler)
+    }
+
+    fn list_mut(&mut self, continue_handler: bool) -> &mut Vec<(u32, u32)> {
+        if continue_handler {
+            &mut self.continue_handlers
+        } else {
+            &mut self.exception_handlers
+        }
+    }
+}
+
+/// Which handler a dispatch is currently waiting on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum DispatchStage {
+    /// Vectored exception handler `index`.
+    Vectored(usize),
+    /// The handler of the `FS:[0]` registration at this address.
+    Frame(u32),
+    /// The filter set with SetUnhandledExceptionFilter.
+    Filter,
+    /// Vectored continue handler `index`, run before execution resumes.
+    Continue(usize),
+}
+
+/// An exception being dispatched. Its guest structures sit just below the stack
+/// pointer at the time of the exception, from `bottom` upwards.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct ExceptionDispatch {
+    pub(crate) stage: DispatchStage,
+    pub(crate) bottom: u32,
+    pub(crate) pointers: u32,
+    pub(crate) dispatcher_context: u32,
+    pub(crate) record: u32,
+    pub(crate) context: u32,
+}
+
+/// Something a host call asked for that can only happen once it has returned.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum ExceptionRequest {
+    /// RaiseException: dispatch with the caller's registers as the context.
+    Raise(ExceptionRecord),
+    /// A frame handler handed control to an `__except` block.
+    Transfer { eip: u32, esp: u32, ebp: u32 },
+}
+
+/// Per-thread exception state.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct ExceptionState {
+    pub(crate) pending: Option<ExceptionRequest>,
+    pub(crate) dispatches: Vec<ExceptionDispatch>,
+    pub(crate) catches: Vec<CatchBlock>,
+}
+
+impl ExceptionState {
+    /// Forget dispatches whose handlers took over instead of returning: their
+    /// structures lie below a stack pointer that has since moved back up past them.
+    pub(crate) fn drop_abandoned(&mut self, esp: u32) {
+        self.dispatches.retain(|dispatch| dispatch.bottom > esp);
+    }
+
+    /// Remove the catch blocks a continuation at `esp` has left behind.
+    pub(crate) fn take_abandoned_catches(&mut self, esp: u32) -> Vec<CatchBlock> {
+        let keep = self
+            .catches
+            .iter()
+            .position(|catch| catch.bottom <= esp)
+            .unwrap_or(self.catches.len());
+        self.catches.split_off(keep)
+    }
+}
+
+/// A C++ catch block running on the current thread.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct CatchBlock {
+    /// Registration node of the function the catch belongs to.
+    pub(crate) frame: u32,
+    /// Thrown object and its `ThrowInfo`, destroyed when the catch finishes.
+    pub(crate) object: u32,
+    pub(crate) throw_info: u32,
+    /// Stack pointer the catch block started with.
+    pub(crate) bottom: u32,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn access_violation_round_trips_through_record() {
+        let err = VmError::AccessViolation {
+            addr: 0x10,
+            access: AccessKind::Write,
+            eip: 0x40_1000,
+        };
+        let record = ExceptionRecord::from_fault(&err, 0).unwrap();
+        assert_eq!(record.code, STATUS_ACCESS_VIOLATION);
+        assert_eq!(record.params, vec![1, 0x10]);
+        assert!(matches!(
+            record.into_error(),
+            VmError::AccessViolation {
+                addr: 0x10,
+                access: AccessKind::Write,
+                eip: 0x40_1000
+            }
+        ));
+        assert!(ExceptionRecord::from_fault(&VmError::ExecutionLimit, 0).is_none());
+        let fast_fail = VmError::Exception {
+            code: STATUS_STACK_BUFFER_OVERRUN,
+            address: 0,
+        };
+        assert!(ExceptionRecord::from_fault(&fast_fail, 0).is_none());
+    }
+
+    #[test]
+    fn vectored_handlers_keep_registration_order() {
+        let mut handlers = VectoredHandlers::default();
+        let a = handlers.add(false, false, 0x100);
+        handlers.add(false, true, 0x200);
+        handlers.add(true, false, 0x300);
+        assert_eq!(handlers.get(false, 0), Some(0x200));
+        assert_eq!(handlers.get(false, 1), Some(0x100));
+        assert!(handlers.remove(false, a));
+        assert!(!handlers.remove(false, a));
+        assert_eq!(handlers.get(false, 1), None);
+        assert_eq!(handlers.get(true, 0), Some(0x300));
+    }
+
+    #[test]
+    fn abandoned_dispatches_are_dropped() {
+        let dispatch = |bottom| ExceptionDispatch {
+            stage: DispatchStage::Filter,
+            bottom,
+            pointers: bottom,
+            dispatcher_context: bottom + 8,
+            record: bottom + 0x10,
+            context: bottom + 0x60,
+        };
+        let mut state = ExceptionState::default();
+        state.dispatches.push(dispatch(0x8000));
+        state.dispatches.push(dispatch(0x7000));
+        state.drop_abandoned(0x6F00);
+        assert_eq!(state.dispatches.len(), 2);
+        state.drop_abandoned(0x7800);
+        assert_eq!(state.dispatches, vec![dispatch(0x8000)]);
+    }
+
+    #[test]
+    fn continuations_take_the_catches_they_leave() {
+        let catch = |bottom| CatchBlock {
+            frame: bottom + 0x100,
+            object: bottom + 0x80,
+            throw_info: 0x40_1000,
+            bottom,
+        };
+        let mut state = ExceptionState::default();
+        state.catches.push(catch(0x8000));
+        state.catches.push(catch(0x7000));
+        assert!(state.take_abandoned_catches(0x6F00).is_empty());
+        assert_eq!(state.take_abandoned_catches(0x7800), vec![catch(0x7000)]);
+        assert_eq!(state.catches, vec![catch(0x8000)]);
+    }
+}
@@ -0,0 +1,306 @@
+//! The EFLAGS register.
+//!
+//! Arithmetic instructions only record their operands and result; the six status
+//! flags are derived from them when something reads them. Most results are
+//! overwritten before any flag is tested, so this keeps the hot path cheap.
+
+/// The operation whose operands the status flags are derived from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum FlagOp {
+    /// Status flags are stored verbatim in the EFLAGS bits.
+    Explicit,
+    Add,
+    /// Add with carry; the carry-in is kept in `carry`.
+    Adc,
+    Sub,
+    /// Subtract with borrow; the borrow-in is kept in `carry`.
+    Sbb,
+    /// AND/OR/XOR/TEST: CF, OF and AF clear.
+    Logic,
+    /// INC/DEC leave CF alone; the previous value is kept in `carry`.
+    Inc,
+    Dec,
+    /// Shifts, rotates and the like: ZF/SF/PF from the result, CF and OF as computed
+    /// by the instruction (kept in `carry` and `overflow`).
+    Result,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Flags {
+    op: FlagOp,
+    /// Operand width in bits: 8, 16 or 32.
+    width: u8,
+    a: u32,
+    b: u32,
+    result: u32,
+    carry: bool,
+    overflow: bool,
+    /// Control and system bits, plus the status bits while `op` is `Explicit`.
+    eflags: u32,
+}
+
+impl Default for Flags {
+    fn default() -> Self {
+        Self {
+            op: FlagOp::Explicit,
+            width: 32,
+            a: 0,
+            b: 0,
+            result: 0,
+            carry: false,
+            overflow: false,
+            eflags: Self::RESERVED | Self::IF,
+        }
+    }
+}
+
+impl Flags {
+    pub(crate) const CF: u32 = 1 << 0;
+    pub(crate) const PF: u32 = 1 << 2;
+    pub(crate) const AF: u32 = 1 << 4;
+    pub(crate) const ZF: u32 = 1 << 6;
+    pub(crate) const SF: u32 = 1 << 7;
+    pub(crate) const TF: u32 = 1 << 8;
+    pub(crate) const IF: u32 = 1 << 9;
+    pub(crate) const DF: u32 = 1 << 10;
+    pub(crate) const OF: u32 = 1 << 11;
+    const NT: u32 = 1 << 14;
+    const AC: u32 = 1 << 18;
+    const ID: u32 = 1 << 21;
+    // Bit 1 of EFLAGS always reads as set.
+    const RESERVED: u32 = 1 << 1;
+    const STATUS: u32 = Self::CF | Self::PF | Self::AF | Self::ZF | Self::SF | Self::OF;
+    /// Bits `popfd` may change in user mode; IF and IOPL are silently kept.
+    const USER_WRITABLE: u32 = Self::STATUS | Self::TF | Self::DF | Self::NT | Self::AC | Self::ID;
+
+    /// Record a flag-setting operation on `width`-bit operands.
+    pub(crate) fn record(&mut self, op: FlagOp, width: u8, a: u32, b: u32, result: u32) {
+        let carry = match op {
+            FlagOp::Inc | FlagOp::Dec => self.cf(),
+            _ => false,
+        };
+        self.record_with_carry(op, width, a, b, result, carry);
+    }
+
+    /// Like [`Flags::record`] for ADC/SBB, whose flags depend on the incoming carry.
+    pub(crate) fn record_with_carry(
+        &mut self,
+        op: FlagOp,
+        width: u8,
+        a: u32,
+        b: u32,
+        result: u32,
+        carry: bool,
+    ) {
+        self.eflags &= !Self::STATUS;
+        self.op = op;
+        self.width = width;
+        self.a = a;
+        self.b = b;
+        self.result = result;
+        self.carry = carry;
+        self.overflow = false;
+    }
+
+    /// ZF/SF/PF from `result`, CF and OF as given, AF clear.
+    pub(crate) fn record_result(&mut self, width: u8, result: u32, cf: bool, of: bool) {
+        self.record_with_carry(FlagOp::Result, width, 0, 0, result, cf);
+        self.overflow = of;
+    }
+
+    fn mask(&self) -> u32 {
+        match self.width {
+            8 => 0xFF,
+            16 => 0xFFFF,
+            _ => 0xFFFF_FFFF,
+        }
+    }
+
+    fn sign(&self) -> u32 {
+        1 << (self.width - 1)
+    }
+
+    pub(crate) fn cf(&self) -> bool {
+        let mask = self.mask();
+        let (a, b, result) = (self.a & mask, self.b & mask, self.result & mask);
+        match self.op {
+            FlagOp::Explicit => self.eflags & Self::CF != 0,
+            FlagOp::Add => result < a,
+            FlagOp::Adc => result < a || (self.carry && result == a),
+            FlagOp::Sub => a < b,
+            FlagOp::Sbb => a < b || (self.carry && a == b),
+            FlagOp::Logic => false,
+            FlagOp::Inc | FlagOp::Dec | FlagOp::Result => self.carry,
+        }
+    }
+
+    pub(crate) fn zf(&self) -> bool {
+        match self.op {
+            FlagOp::Explicit => self.eflags & Self::ZF != 0,
+            _ => self.result & self.mask() == 0,
+        }
+    }
+
+    pub(crate) fn sf(&self) -> bool {
+        match self.op {
+            FlagOp::Explicit => self.eflags & Self::SF != 0,
+            _ => self.result & self.sign() != 0,
+        }
+    }
+
+    pub(crate) fn pf(&self) -> bool {
+        match self.op {
+            FlagOp::Explicit => self.eflags & Self::PF != 0,
+            // Parity of the low byte only, whatever the operand size.
+            _ => (self.result as u8).count_ones().is_multiple_of(2),
+        }
+    }
+
+    pub(crate) fn af(&self) -> bool {
+        match self.op {
+            FlagOp::Explicit => self.eflags & Self::AF != 0,
+            FlagOp::Logic | FlagOp::Result => false,
+            _ => (self.a ^ self.b ^ self.result) & 0x10 != 0,
+        }
+    }
+
+    pub(crate) fn of(&self) -> bool {
+        let sign = self.sign();
+        let (a, b, result) = (self.a, self.b, self.result);
+        match self.op {
+            FlagOp::Explicit => self.eflags & Self::OF != 0,
+            FlagOp::Add | FlagOp::Adc | FlagOp::Inc => (a ^ result) & (b ^ result) & sign != 0,
+            FlagOp::Sub | FlagOp::Sbb | FlagOp::Dec => (a ^ b) & (a ^ result) & sign != 0,
+            FlagOp::Logic => false,
+            FlagOp::Result => self.overflow,
+        }
+    }
+
+    pub(crate) fn df(&self) -> bool {
+        self.eflags & Self::DF != 0
+    }
+
+    // Evaluate the pending operation into the EFLAGS bits.
+    fn materialize(&mut self) {
+        if self.op == FlagOp::Explicit {
+            return;
+        }
+        self.eflags = self.to_eflags();
+        self.op = FlagOp::Explicit;
+    }
+
+    /// Set or clear individual bits (status or control), keeping the rest.
+    pub(crate) fn set(&mut self, bits: u32, value: bool) {
+        self.materialize();
+        if value {
+            self.eflags |= bits;
+        } else {
+            self.eflags &= !bits;
+        }
+    }
+
+    pub(crate) fn to_eflags(self) -> u32 {
+        let mut value = (self.eflags & !Self::STATUS) | Self::RESERVED;
+        for (set, bit) in [
+            (self.cf(), Self::CF),
+            (self.pf(), Self::PF),
+            (self.af(), Self::AF),
+            (self.zf(), Self::ZF),
+            (self.sf(), Self::SF),
+            (self.of(), Self::OF),
+        ] {
+            if set {
+                value |= bit;
+            }
+        }
+        value
+    }
+
+    /// `popfd`: load the bits user-mode code may change.
+    pub(crate) fn load_user(&mut self, value: u32) {
+        self.materialize();
+        self.eflags = (self.eflags & !Self::USER_WRITABLE) | (value & Self::USER_WRITABLE);
+    }
+
+    /// `lahf`: SF, ZF, AF, PF and CF as the low byte of EFLAGS.
+    pub(crate) fn low_byte(self) -> u8 {
+        self.to_eflags() as u8
+    }
+
+    /// `sahf`: replace SF, ZF, AF, PF and CF from `value`.
+    pub(crate) fn load_low_byte(&mut self, value: u8) {
+        self.materialize();
+        let bits = Self::SF | Self::ZF | Self::AF | Self::PF | Self::CF;
+        self.eflags = (self.eflags & !bits) | (value as u32 & bits);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn add_derives_all_status_flags() {
+        let mut flags = Flags::default();
+        flags.record(FlagOp::Add, 8, 0x7F, 0x01, 0x80);
+        assert!(!flags.cf());
+        assert!(flags.of());
+        assert!(flags.sf());
+        assert!(!flags.zf());
+        assert!(flags.af());
+        assert!(!flags.pf());
+        flags.record(FlagOp::Add, 32, 0xFFFF_FFFF, 1, 0);
+        assert!(flags.cf() && flags.zf() && flags.pf() && flags.af() && !flags.of());
+    }
+
+    #[test]
+    fn sub_with_borrow_uses_carry_in() {
+        let mut flags = Flags::default();
+        flags.record_with_carry(FlagOp::Sbb, 32, 5, 5, 0xFFFF_FFFF, true);
+        assert!(flags.cf());
+        assert!(flags.sf());
+        flags.record_with_carry(FlagOp::Sbb, 32, 5, 5, 0, false);
+        assert!(!flags.cf());
+        assert!(flags.zf());
+    }
+
+    #[test]
+    fn inc_keeps_carry() {
+        let mut flags = Flags::default();
+        flags.set(Flags::CF, true);
+        flags.record(FlagOp::Inc, 32, 0xFFFF_FFFF, 1, 0);
+        assert!(flags.cf());
+        assert!(flags.zf());
+        flags.record(FlagOp::Logic, 32, 0, 0, 1);
+        flags.record(FlagOp::Dec, 32, 1, 1, 0);
+        assert!(!flags.cf());
+    }
+
+    #[test]
+    fn eflags_round_trip_keeps_direction_and_masks_system_bits() {
+        let mut flags = Flags::default();
+        flags.record(FlagOp::Sub, 16, 1, 2, 0xFFFF);
+        flags.set(Flags::DF, true);
+        let value = flags.to_eflags();
+        assert_eq!(
+            value & (Flags::CF | Flags::SF | Flags::DF | Flags::PF),
+            Flags::CF | Flags::SF | Flags::DF | Flags::PF
+        );
+        let mut copy = Flags::default();
+        copy.load_user(value);
+        assert_eq!(copy.to_eflags(), value);
+
+        let mut loaded = Flags::default();
+        loaded.load_user(0xFFFF_FFFF & !Flags::IF);
+        assert!(loaded.df());
+        assert!(loaded.to_eflags() & Flags::IF != 0);
+    }
+
+    #[test]
+    fn low_byte_round_trips() {
+        let mut flags = Flags::default();
+        flags.load_low_byte(0xD5);
+        assert!(flags.sf() && flags.zf() && flags.af() && flags.pf() && flags.cf());
+        assert_eq!(flags.low_byte(), 0xD7);
+    }
+}
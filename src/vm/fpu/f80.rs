@@ -0,0 +1,975 @@
+//! 80-bit extended-precision values and the arithmetic the x87 performs on them.
+//!
+//! Results are computed exactly and rounded once, honouring the precision and
+//! rounding controls, so they match the hardware bit for bit. Transcendental
+//! functions are the exception: they go through `f64`.
+
+use std::cmp::Ordering;
+
+// Status-word exception bits raised by the operations here.
+pub(crate) const EXC_INVALID: u16 = 1 << 0;
+pub(crate) const EXC_DENORMAL: u16 = 1 << 1;
+pub(crate) const EXC_ZERO_DIVIDE: u16 = 1 << 2;
+pub(crate) const EXC_OVERFLOW: u16 = 1 << 3;
+pub(crate) const EXC_UNDERFLOW: u16 = 1 << 4;
+pub(crate) const EXC_PRECISION: u16 = 1 << 5;
+/// Not an exception: C1 is set when the result was rounded up.
+pub(crate) const ROUNDED_UP: u16 = 1 << 9;
+
+const EXP_BIAS: i32 = 16383;
+const EXP_MAX: u16 = 0x7FFF;
+const INTEGER_BIT: u64 = 1 << 63;
+const QUIET_BIT: u64 = 1 << 62;
+
+/// The RC field of the control word.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Rounding {
+    Nearest,
+    Down,
+    Up,
+    Zero,
+}
+
+impl Rounding {
+    pub(crate) fn from_control(control: u16) -> Self {
+        match (control >> 10) & 3 {
+            0 => Rounding::Nearest,
+            1 => Rounding::Down,
+            2 => Rounding::Up,
+            _ => Rounding::Zero,
+        }
+    }
+
+    // Whether a value with discarded bits `lower` (half at `half`) rounds away from zero.
+    fn round_up(self, negative: bool, odd: bool, lower: u128, half: u128) -> bool {
+        match self {
+            Rounding::Nearest => lower > half || (lower == half && odd),
+            Rounding::Down => negative && lower != 0,
+            Rounding::Up => !negative && lower != 0,
+            Rounding::Zero => false,
+        }
+    }
+}
+
+/// Rounding state for one operation: RC plus the significand width (PC for
+/// arithmetic, the memory format's width for stores).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct RoundMode {
+    pub(crate) rounding: Rounding,
+    pub(crate) bits: u32,
+}
+
+impl RoundMode {
+    /// Full 64-bit precision with the given rounding.
+    pub(crate) fn extended(rounding: Rounding) -> Self {
+        Self { rounding, bits: 64 }
+    }
+
+    /// RC and PC as arithmetic instructions use them.
+    pub(crate) fn from_control(control: u16) -> Self {
+        let bits = match (control >> 8) & 3 {
+            0 => 24,
+            2 => 53,
+            _ => 64,
+        };
+        Self {
+            rounding: Rounding::from_control(control),
+            bits,
+        }
+    }
+}
+
+/// FXAM classes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Class {
+    Zero,
+    Denormal,
+    Normal,
+    Infinity,
+    Nan,
+    /// Pseudo-NaNs, pseudo-infinities and unnormals; invalid operands since the 387.
+    Unsupported,
+}
+
+// A finite non-zero value `sig * 2^(exp - 63)` with bit 63 of `sig` set.
+#[derive(Debug, Clone, Copy)]
+struct Parts {
+    negative: bool,
+    exp: i32,
+    sig: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub(crate) struct F80 {
+    sign_exp: u16,
+    mantissa: u64,
+}
+
+impl F80 {
+    pub(crate) const ZERO: F80 = F80::from_raw(0, 0);
+    pub(crate) const ONE: F80 = F80::from_raw(0x3FFF, INTEGER_BIT);
+    /// The QNaN written for masked invalid operations.
+    pub(crate) const INDEFINITE: F80 = F80::from_raw(0xFFFF, 0xC000_0000_0000_0000);
+    pub(crate) const LOG2_10: F80 = F80::from_raw(0x4000, 0xD49A_784B_CD1B_8AFE);
+    pub(crate) const LOG2_E: F80 = F80::from_raw(0x3FFF, 0xB8AA_3B29_5C17_F0BC);
+    pub(crate) const PI: F80 = F80::from_raw(0x4000, 0xC90F_DAA2_2168_C235);
+    pub(crate) const LOG10_2: F80 = F80::from_raw(0x3FFD, 0x9A20_9A84_FBCF_F799);
+    pub(crate) const LN_2: F80 = F80::from_raw(0x3FFE, 0xB172_17F7_D1CF_79AC);
+
+    pub(crate) const fn from_raw(sign_exp: u16, mantissa: u64) -> Self {
+        Self { sign_exp, mantissa }
+    }
+
+    pub(crate) fn from_bytes(bytes: [u8; 10]) -> Self {
+        let mut mantissa = [0u8; 8];
+        mantissa.copy_from_slice(&bytes[..8]);
+        Self::from_raw(
+            u16::from_le_bytes([bytes[8], bytes[9]]),
+            u64::from_le_bytes(mantissa),
+        )
+    }
+
+    pub(crate) fn to_bytes(self) -> [u8; 10] {
+        let mut bytes = [0u8; 10];
+        bytes[..8].copy_from_slice(&self.mantissa.to_le_bytes());
+        bytes[8..].copy_from_slice(&self.sign_exp.to_le_bytes());
+        bytes
+    }
+
+    fn exponent(self) -> u16 {
+        self.sign_exp & EXP_MAX
+    }
+
+    pub(crate) fn is_negative(self) -> bool {
+        self.sign_exp & 0x8000 != 0
+    }
+
+    pub(crate) fn class(self) -> Class {
+        let exp = self.exponent();
+        let integer = self.mantissa & INTEGER_BIT != 0;
+        match exp {
+            0 if self.mantissa == 0 => Class::Zero,
+            0 => Class::Denormal,
+            EXP_MAX if !integer => Class::Unsupported,
+            EXP_MAX if self.mantissa << 1 == 0 => Class::Infinity,
+            EXP_MAX => Class::Nan,
+            _ if !integer => Class::Unsupported,
+            _ => Class::Normal,
+        }
+    }
+
+    pub(crate) fn is_nan(self) -> bool {
+        matches!(self.class(), Class::Nan | Class::Unsupported)
+    }
+
+    fn is_signaling(self) -> bool {
+        self.class() == Class::Nan && self.mantissa & QUIET_BIT == 0
+    }
+
+    pub(crate) fn is_zero(self) -> bool {
+        self.class() == Class::Zero
+    }
+
+    fn is_infinite(self) -> bool {
+        self.class() == Class::Infinity
+    }
+
+    pub(crate) fn negate(self) -> Self {
+        Self::from_raw(self.sign_exp ^ 0x8000, self.mantissa)
+    }
+
+    pub(crate) fn abs(self) -> Self {
+        Self::from_raw(self.sign_exp & EXP_MAX, self.mantissa)
+    }
+
+    fn zero(negative: bool) -> Self {
+        Self::from_raw(if negative { 0x8000 } else { 0 }, 0)
+    }
+
+    fn infinity(negative: bool) -> Self {
+        Self::from_raw(EXP_MAX | if negative { 0x8000 } else { 0 }, INTEGER_BIT)
+    }
+
+    fn parts(self) -> Option<Parts> {
+        let negative = self.is_negative();
+        match self.class() {
+            Class::Normal => Some(Parts {
+                negative,
+                exp: self.exponent() as i32 - EXP_BIAS,
+                sig: self.mantissa,
+            }),
+            Class::Denormal => {
+                let shift = self.mantissa.leading_zeros();
+                Some(Parts {
+                    negative,
+                    exp: 1 - EXP_BIAS - shift as i32,
+                    sig: self.mantissa << shift,
+                })
+            }
+            _ => None,
+        }
+    }
+
+    // Round `sig * 2^(exp - 127)` to `mode`, returning the value and the exceptions raised.
+    // `sig` must keep any discarded bits ORed into its lowest bit.
+    fn round(negative: bool, exp: i32, sig: u128, mode: RoundMode) -> (Self, u16) {
+        if sig == 0 {
+            return (Self::zero(negative), 0);
+        }
+        let shift = sig.leading_zeros();
+        let mut sig = sig << shift;
+        let mut biased = exp - shift as i32 + EXP_BIAS;
+        let tiny = biased <= 0;
+        if tiny {
+            sig = shift_right_sticky(sig, (1 - biased) as u32);
+            biased = 0;
+        }
+        let drop = 128 - mode.bits;
+        let half = 1u128 << (drop - 1);
+        let lower = sig & ((1u128 << drop) - 1);
+        sig -= lower;
+        let mut flags = 0;
+        if lower != 0 {
+            flags |= EXC_PRECISION;
+            if tiny {
+                flags |= EXC_UNDERFLOW;
+            }
+        }
+        let odd = (sig >> drop) & 1 != 0;
+        if mode.rounding.round_up(negative, odd, lower, half) {
+            flags |= ROUNDED_UP;
+            match sig.checked_add(1u128 << drop) {
+                Some(value) => sig = value,
+                None => {
+                    sig = 1u128 << 127;
+                    biased += 1;
+                }
+            }
+            if biased == 0 && sig >> 127 != 0 {
+                biased = 1;
+            }
+        }
+        if biased >= EXP_MAX as i32 {
+            let to_infinity = match mode.rounding {
+                Rounding::Nearest => true,
+                Rounding::Down => negative,
+                Rounding::Up => !negative,
+                Rounding::Zero => false,
+            };
+            let value = if to_infinity {
+                Self::infinity(negative)
+            } else {
+                let largest = !0u64 << (64 - mode.bits);
+                Self::from_raw(0x7FFE | sign_bit(negative), largest)
+            };
+            return (value, EXC_OVERFLOW | EXC_PRECISION);
+        }
+        let mantissa = (sig >> 64) as u64;
+        (
+            Self::from_raw(biased as u16 | sign_bit(negative), mantissa),
+            flags,
+        )
+    }
+
+    // NaN propagation: an SNaN operand is invalid; the larger significand wins.
+    fn propagate_nan(a: Self, b: Self) -> (Self, u16) {
+        let flags =
+            if a.is_signaling() || b.is_signaling() || a.is_unsupported() || b.is_unsupported() {
+                EXC_INVALID
+            } else {
+                0
+            };
+        let quiet = |value: Self| {
+            if value.is_unsupported() {
+                Self::INDEFINITE
+            } else {
+                Self::from_raw(value.sign_exp, value.mantissa | QUIET_BIT)
+            }
+        };
+        let value = match (a.is_nan(), b.is_nan()) {
+            (true, true) if (b.mantissa << 1) > (a.mantissa << 1) => quiet(b),
+            (true, _) => quiet(a),
+            _ => quiet(b),
+        };
+        (value, flags)
+    }
+
+    /// The QNaN result when either operand is a NaN.
+    pub(crate) fn nan_operands(self, other: Self) -> Option<(Self, u16)> {
+        (self.is_nan() || other.is_nan()).then(|| Self::propagate_nan(self, other))
+    }
+
+    /// FLD m32real/m64real: an SNaN is quietened and raises an invalid operation.
+    pub(crate) fn quiet_signaling(self) -> (Self, u16) {
+        if self.is_signaling() {
+            (
+                Self::from_raw(self.sign_exp, self.mantissa | QUIET_BIT),
+                EXC_INVALID,
+            )
+        } else {
+            (self, 0)
+        }
+    }
+
+    fn is_unsupported(self) -> bool {
+        self.class() == Class::Unsupported
+    }
+
+    fn denormal_flag(self) -> u16 {
+        if self.class() == Class::Denormal {
+            EXC_DENORMAL
+        } else {
+            0
+        }
+    }
+
+    pub(crate) fn add(self, other: Self, mode: RoundMode) -> (Self, u16) {
+        if self.is_nan() || other.is_nan() {
+            return Self::propagate_nan(self, other);
+        }
+        let flags = self.denormal_flag() | other.denormal_flag();
+        match (self.is_infinite(), other.is_infinite()) {
+            (true, true) if self.is_negative() != other.is_negative() => {
+                return (Self::INDEFINITE, EXC_INVALID)
+            }
+            (true, _) => return (self, flags),
+            (_, true) => return (other, flags),
+            _ => {}
+        }
+        let (a, b) = match (self.parts(), other.parts()) {
+            (None, None) => {
+                // Zero plus zero: the sign survives only when both agree.
+                let negative = if self.is_negative() == other.is_negative() {
+                    self.is_negative()
+                } else {
+                    mode.rounding == Rounding::Down
+                };
+                return (Self::zero(negative), flags);
+            }
+            (None, Some(_)) => return round_exact(other, mode, flags),
+            (Some(_), None) => return round_exact(self, mode, flags),
+            (Some(a), Some(b)) => (a, b),
+        };
+        let (big, small) = if (a.exp, a.sig) >= (b.exp, b.sig) {
+            (a, b)
+        } else {
+            (b, a)
+        };
+        let big_sig = (big.sig as u128) << 63;
+        let small_sig = shift_right_sticky((small.sig as u128) << 63, (big.exp - small.exp) as u32);
+        let (value, round_flags) = if big.negative == small.negative {
+            Self::round(big.negative, big.exp + 1, big_sig + small_sig, mode)
+        } else if big_sig == small_sig {
+            (Self::zero(mode.rounding == Rounding::Down), 0)
+        } else {
+            Self::round(big.negative, big.exp + 1, big_sig - small_sig, mode)
+        };
+        (value, flags | round_flags)
+    }
+
+    pub(crate) fn sub(self, other: Self, mode: RoundMode) -> (Self, u16) {
+        if other.is_nan() {
+            return Self::propagate_nan(self, other);
+        }
+        self.add(other.negate(), mode)
+    }
+
+    pub(crate) fn mul(self, other: Self, mode: RoundMode) -> (Self, u16) {
+        if self.is_nan() || other.is_nan() {
+            return Self::propagate_nan(self, other);
+        }
+        let negative = self.is_negative() != other.is_negative();
+        let flags = self.denormal_flag() | other.denormal_flag();
+        if self.is_infinite() || other.is_infinite() {
+            if self.is_zero() || other.is_zero() {
+                return (Self::INDEFINITE, EXC_INVALID);
+            }
+            return (Self::infinity(negative), flags);
+        }
+        let (Some(a), Some(b)) = (self.parts(), other.parts()) else {
+            return (Self::zero(negative), flags);
+        };
+        let product = a.sig as u128 * b.sig as u128;
+        let (value, round_flags) = Self::round(negative, a.exp + b.exp + 1, product, mode);
+        (value, flags | round_flags)
+    }
+
+    pub(crate) fn div(self, other: Self, mode: RoundMode) -> (Self, u16) {
+        if self.is_nan() || other.is_nan() {
+            return Self::propagate_nan(self, other);
+        }
+        let negative = self.is_negative() != other.is_negative();
+        let flags = self.denormal_flag() | other.denormal_flag();
+        match (self.is_infinite(), other.is_infinite()) {
+            (true, true) => return (Self::INDEFINITE, EXC_INVALID),
+            (true, false) => return (Self::infinity(negative), flags),
+            (false, true) => return (Self::zero(negative), flags),
+            _ => {}
+        }
+        let Some(b) = other.parts() else {
+            if self.is_zero() {
+                return (Self::INDEFINITE, EXC_INVALID);
+            }
+            return (Self::infinity(negative), flags | EXC_ZERO_DIVIDE);
+        };
+        let Some(a) = self.parts() else {
+            return (Self::zero(negative), flags);
+        };
+        // Two rounds of long division give 127 or 128 quotient bits.
+        let dividend = (a.sig as u128) << 63;
+        let divisor = b.sig as u128;
+        let high = dividend / divisor;
+        let remainder = dividend % divisor;
+        let low = (remainder << 64) / divisor;
+        let sticky = !(remainder << 64).is_multiple_of(divisor);
+        let quotient = (high << 64) | low | sticky as u128;
+        let (value, round_flags) = Self::round(negative, a.exp - b.exp, quotient, mode);
+        (value, flags | round_flags)
+    }
+
+    pub(crate) fn sqrt(self, mode: RoundMode) -> (Self, u16) {
+        if self.is_nan() {
+            return Self::propagate_nan(self, self);
+        }
+        if self.is_zero() {
+            return (self, 0);
+        }
+        if self.is_negative() {
+            return (Self::INDEFINITE, EXC_INVALID);
+        }
+        if self.is_infinite() {
+            return (self, 0);
+        }
+        let Some(a) = self.parts() else {
+            return (self, 0);
+        };
+        // Scale the significand so the exponent left over is even.
+        let exp = a.exp - 63;
+        let scale = if (exp - 64) % 2 == 0 { 64 } else { 63 };
+        let radicand = (a.sig as u128) << scale;
+        let root = radicand.isqrt();
+        let remainder = radicand - root * root;
+        // The true root is never exactly halfway between two integers.
+        let guard = remainder > root;
+        let sig = (root << 64) | ((guard as u128) << 63) | (remainder != 0) as u128;
+        let (value, flags) = Self::round(false, (exp - scale) / 2 + 63, sig, mode);
+        (value, flags | self.denormal_flag())
+    }
+
+    /// FCOM/FUCOM ordering; `None` when unordered.
+    pub(crate) fn compare(self, other: Self) -> Option<Ordering> {
+        if self.is_nan() || other.is_nan() {
+            return None;
+        }
+        if self.is_zero() && other.is_zero() {
+            return Some(Ordering::Equal);
+        }
+        let key = |value: Self| {
+            let magnitude = ((value.exponent() as u128) << 64) | value.mantissa as u128;
+            if value.is_negative() {
+                -(magnitude as i128)
+            } else {
+                magnitude as i128
+            }
+        };
+        Some(key(self).cmp(&key(other)))
+    }
+
+    /// Exceptions a comparison raises: any NaN for FCOM, only SNaNs for FUCOM.
+    pub(crate) fn compare_flags(self, other: Self, unordered: bool) -> u16 {
+        let invalid = if unordered {
+            self.is_signaling()
+                || other.is_signaling()
+                || self.is_unsupported()
+                || other.is_unsupported()
+        } else {
+            self.is_nan() || other.is_nan()
+        };
+        let mut flags = self.denormal_flag() | other.denormal_flag();
+        if invalid {
+            flags |= EXC_INVALID;
+        }
+        flags
+    }
+
+    /// FRNDINT.
+    pub(crate) fn round_to_integral(self, rounding: Rounding) -> (Self, u16) {
+        if self.is_nan() {
+            return Self::propagate_nan(self, self);
+        }
+        let Some(parts) = self.parts() else {
+            return (self, 0);
+        };
+        if parts.exp >= 63 {
+            return (self, 0);
+        }
+        let (magnitude, inexact) = integer_magnitude(parts, rounding);
+        let (value, _) = Self::round(
+            parts.negative,
+            127,
+            magnitude,
+            RoundMode::extended(rounding),
+        );
+        let flags = if inexact { EXC_PRECISION } else { 0 };
+        (value, flags | self.denormal_flag())
+    }
+
+    /// FIST/FISTP (`truncate` for FISTTP) to a signed integer of `bits` bits.
+    /// Out-of-range values give `None`; the caller stores the integer indefinite.
+    pub(crate) fn to_int(self, bits: u32, rounding: Rounding) -> (Option<i64>, u16) {
+        if self.is_zero() {
+            return (Some(0), 0);
+        }
+        let Some(parts) = self.parts() else {
+            return (None, EXC_INVALID);
+        };
+        if parts.exp >= 64 {
+            return (None, EXC_INVALID);
+        }
+        let (magnitude, inexact) = integer_magnitude(parts, rounding);
+        let limit = 1i128 << (bits - 1);
+        let value = if parts.negative {
+            -(magnitude as i128)
+        } else {
+            magnitude as i128
+        };
+        if value < -limit || value >= limit {
+            return (None, EXC_INVALID);
+        }
+        let flags = if inexact { EXC_PRECISION } else { 0 };
+        (Some(value as i64), flags | self.denormal_flag())
+    }
+
+    pub(crate) fn from_i64(value: i64) -> Self {
+        let (value, _) = Self::round(
+            value < 0,
+            127,
+            value.unsigned_abs() as u128,
+            RoundMode::extended(Rounding::Nearest),
+        );
+        value
+    }
+
+    pub(crate) fn from_f64(value: f64) -> Self {
+        from_ieee(value.to_bits(), 52, 11)
+    }
+
+    pub(crate) fn from_f32(value: f32) -> Self {
+        from_ieee(value.to_bits() as u64, 23, 8)
+    }
+
+    /// FST m64real.
+    pub(crate) fn to_f64(self, rounding: Rounding) -> (f64, u16) {
+        let (bits, flags) = self.to_ieee(52, 11, rounding);
+        (f64::from_bits(bits), flags)
+    }
+
+    /// FST m32real.
+    pub(crate) fn to_f32(self, rounding: Rounding) -> (f32, u16) {
+        let (bits, flags) = self.to_ieee(23, 8, rounding);
+        (f32::from_bits(bits as u32), flags)
+    }
+
+    /// Nearest `f64`, for host-side consumers and transcendental functions.
+    pub(crate) fn as_f64(self) -> f64 {
+        self.to_f64(Rounding::Nearest).0
+    }
+
+    fn to_ieee(self, fraction_bits: u32, exponent_bits: u32, rounding: Rounding) -> (u64, u16) {
+        let exp_max = (1u64 << exponent_bits) - 1;
+        let bias = (exp_max >> 1) as i32;
+        let sign = (self.is_negative() as u64) << (fraction_bits + exponent_bits);
+        let nan_payload = |mantissa: u64| (mantissa << 1) >> (64 - fraction_bits);
+        match self.class() {
+            Class::Zero => return (sign, 0),
+            Class::Infinity => return (sign | (exp_max << fraction_bits), 0),
+            Class::Nan => {
+                let payload = nan_payload(self.mantissa | QUIET_BIT);
+                let flags = if self.is_signaling() { EXC_INVALID } else { 0 };
+                return (sign | (exp_max << fraction_bits) | payload, flags);
+            }
+            Class::Unsupported => {
+                let payload = nan_payload(Self::INDEFINITE.mantissa);
+                return (sign | (exp_max << fraction_bits) | payload, EXC_INVALID);
+            }
+            Class::Normal | Class::Denormal => {}
+        }
+        let parts = self.parts().expect("finite");
+        let mode = RoundMode {
+            rounding,
+            bits: fraction_bits + 1,
+        };
+        // Rounding at the target's own subnormal boundary: pre-shift tiny values.
+        let min_exp = 1 - bias;
+        let mut sig = (parts.sig as u128) << 64;
+        let mut exp = parts.exp;
+        let mut flags = self.denormal_flag();
+        if exp < min_exp {
+            sig = shift_right_sticky(sig, (min_exp - exp) as u32);
+            exp = min_exp;
+        }
+        let drop = 128 - mode.bits;
+        let half = 1u128 << (drop - 1);
+        let lower = sig & ((1u128 << drop) - 1);
+        sig -= lower;
+        let tiny = sig >> 127 == 0;
+        if lower != 0 {
+            flags |= EXC_PRECISION;
+            if tiny {
+                flags |= EXC_UNDERFLOW;
+            }
+        }
+        let odd = (sig >> drop) & 1 != 0;
+        if rounding.round_up(parts.negative, odd, lower, half) {
+            flags |= ROUNDED_UP;
+            match sig.checked_add(1u128 << drop) {
+                Some(value) => sig = value,
+                None => {
+                    sig = 1u128 << 127;
+                    exp += 1;
+                }
+            }
+        }
+        let normal = sig >> 127 != 0;
+        let biased = if normal { exp + bias } else { 0 };
+        if biased >= exp_max as i32 {
+            let to_infinity = match rounding {
+                Rounding::Nearest => true,
+                Rounding::Down => parts.negative,
+                Rounding::Up => !parts.negative,
+                Rounding::Zero => false,
+            };
+            let bits = if to_infinity {
+                exp_max << fraction_bits
+            } else {
+                ((exp_max - 1) << fraction_bits) | ((1u64 << fraction_bits) - 1)
+            };
+            return (sign | bits, flags | EXC_OVERFLOW | EXC_PRECISION);
+        }
+        let fraction = ((sig >> drop) as u64) & ((1u64 << fraction_bits) - 1);
+        (sign | ((biased as u64) << fraction_bits) | fraction, flags)
+    }
+
+    /// FSCALE: multiply by two to the power of `scale` truncated towards zero.
+    pub(crate) fn scale(self, scale: Self, mode: RoundMode) -> (Self, u16) {
+        if self.is_nan() || scale.is_nan() {
+            return Self::propagate_nan(self, scale);
+        }
+        if scale.is_infinite() {
+            return match (scale.is_negative(), self.is_zero(), self.is_infinite()) {
+                (false, true, _) | (true, _, true) => (Self::INDEFINITE, EXC_INVALID),
+                (false, _, _) => (Self::infinity(self.is_negative()), 0),
+                (true, _, _) => (Self::zero(self.is_negative()), 0),
+            };
+        }
+        let Some(parts) = self.parts() else {
+            return (self, 0);
+        };
+        let (amount, _) = scale.to_int(64, Rounding::Zero);
+        let amount = amount
+            .unwrap_or(if scale.is_negative() {
+                i64::MIN
+            } else {
+                i64::MAX
+            })
+            .clamp(-0x10000, 0x10000) as i32;
+        let (value, flags) = Self::round(
+            parts.negative,
+            parts.exp + amount,
+            (parts.sig as u128) << 64,
+            mode,
+        );
+        (value, flags | self.denormal_flag())
+    }
+
+    /// FXTRACT: `(exponent, significand)`.
+    pub(crate) fn extract(self) -> (Self, Self, u16) {
+        if self.is_nan() {
+            let (value, flags) = Self::propagate_nan(self, self);
+            return (value, value, flags);
+        }
+        if self.is_zero() {
+            return (Self::infinity(true), self, EXC_ZERO_DIVIDE);
+        }
+        if self.is_infinite() {
+            return (Self::infinity(false), self, 0);
+        }
+        let parts = self.parts().expect("finite");
+        let significand = Self::from_raw(EXP_BIAS as u16 | sign_bit(parts.negative), parts.sig);
+        (
+            Self::from_i64(parts.exp as i64),
+            significand,
+            self.denormal_flag(),
+        )
+    }
+
+    /// FPREM (truncating) or FPREM1 (round-to-nearest quotient).
+    ///
+    /// Returns the partial remainder, the low three quotient bits and whether the
+    /// reduction is complete; large exponent gaps take several instructions, as on hardware.
+    pub(crate) fn remainder(self, divisor: Self, nearest: bool) -> (Self, u8, bool, u16) {
+        if self.is_nan() || divisor.is_nan() {
+            let (value, flags) = Self::propagate_nan(self, divisor);
+            return (value, 0, true, flags);
+        }
+        if self.is_infinite() || divisor.is_zero() {
+            return (Self::INDEFINITE, 0, true, EXC_INVALID);
+        }
+        let flags = self.denormal_flag() | divisor.denormal_flag();
+        if divisor.is_infinite() || self.is_zero() {
+            return (self, 0, true, flags);
+        }
+        let (a, b) = (
+            self.parts().expect("finite"),
+            divisor.parts().expect("finite"),
+        );
+        let gap = a.exp - b.exp;
+        if gap < 0 {
+            if nearest && gap == -1 && a.sig > b.sig {
+                // |a| > |b| / 2: the quotient rounds to one.
+                let (value, round_flags) = self.sub(
+                    if a.negative == b.negative {
+                        divisor
+                    } else {
+                        divisor.negate()
+                    },
+                    RoundMode::extended(Rounding::Nearest),
+                );
+                return (value, 1, true, flags | round_flags);
+            }
+            return (self, 0, true, flags);
+        }
+        if gap >= 64 {
+            // Partial reduction by a power-of-two multiple of the divisor.
+            let step = 32 + gap % 32;
+            let rem = ((a.sig as u128) << step) % b.sig as u128;
+            let (value, _) = Self::round(
+                a.negative,
+                a.exp - step,
+                rem << 64,
+                RoundMode::extended(Rounding::Nearest),
+            );
+            let value = if rem == 0 {
+                Self::zero(a.negative)
+            } else {
+                value
+            };
+            return (value, 0, false, flags);
+        }
+        let dividend = (a.sig as u128) << gap;
+        let mut quotient = dividend / b.sig as u128;
+        let mut rem = dividend % b.sig as u128;
+        let mut negative = a.negative;
+        if nearest {
+            let twice = rem * 2;
+            if twice > b.sig as u128 || (twice == b.sig as u128 && quotient & 1 != 0) {
+                rem = b.sig as u128 - rem;
+                quotient += 1;
+                negative = !negative;
+            }
+        }
+        let value = if rem == 0 {
+            Self::zero(a.negative)
+        } else {
+            Self::round(
+                negative,
+                b.exp,
+                rem << 64,
+                RoundMode::extended(Rounding::Nearest),
+            )
+            .0
+        };
+        (value, (quotient & 7) as u8, true, flags)
+    }
+
+    /// FBLD: 18 packed BCD digits and a sign byte.
+    pub(crate) fn from_bcd(bytes: [u8; 10]) -> Self {
+        let mut value = 0i64;
+        for byte in bytes[..9].iter().rev() {
+            value = value * 100 + (byte >> 4) as i64 * 10 + (byte & 0xF) as i64;
+        }
+        let value = Self::from_i64(value);
+        if bytes[9] & 0x80 != 0 {
+            value.negate()
+        } else {
+            value
+        }
+    }
+
+    /// FBSTP; `None` when the value does not fit in 18 digits.
+    pub(crate) fn to_bcd(self, rounding: Rounding) -> (Option<[u8; 10]>, u16) {
+        let (value, flags) = self.to_int(64, rounding);
+        let Some(value) = value.filter(|value| value.unsigned_abs() < 1_000_000_000_000_000_000)
+        else {
+            return (None, flags | EXC_INVALID);
+        };
+        let mut bytes = [0u8; 10];
+        let mut digits = value.unsigned_abs();
+        for byte in bytes[..9].iter_mut() {
+            let low = (digits % 10) as u8;
+            let high = ((digits / 10) % 10) as u8;
+            *byte = (high << 4) | low;
+            digits /= 100;
+        }
+        if self.is_negative() {
+            bytes[9] = 0x80;
+        }
+        (Some(bytes), flags)
+    }
+}
+
+fn sign_bit(negative: bool) -> u16 {
+    if negative {
+        0x8000
+    } else {
+        0
+    }
+}
+
+fn shift_right_sticky(value: u128, shift: u32) -> u128 {
+    match shift {
+        0 => value,
+        1..=127 => (value >> shift) | ((value & ((1u128 << shift) - 1)) != 0) as u128,
+        _ => (value != 0) as u128,
+    }
+}
+
+// Round a lone finite operand to the precision control (an add with zero).
+fn round_exact(value: F80, mode: RoundMode, flags: u16) -> (F80, u16) {
+    let parts = value.parts().expect("finite");
+    let (value, round_flags) =
+        F80::round(parts.negative, parts.exp, (parts.sig as u128) << 64, mode);
+    (value, flags | round_flags)
+}
+
+// The magnitude of `parts` rounded to an integer; `exp` must be below 64.
+fn integer_magnitude(parts: Parts, rounding: Rounding) -> (u128, bool) {
+    let sig = (parts.sig as u128) << 64;
+    let (integer, lower, half) = if parts.exp < -1 {
+        (0, 1, 2)
+    } else {
+        let shift = (127 - parts.exp) as u32;
+        if shift >= 128 {
+            // 0.5 <= |x| < 1
+            (0, sig, 1u128 << 127)
+        } else {
+            (
+                sig >> shift,
+                sig & ((1u128 << shift) - 1),
+                1u128 << (shift - 1),
+            )
+        }
+    };
+    let up = rounding.round_up(parts.negative, integer & 1 != 0, lower, half);
+    (integer + up as u128, lower != 0)
+}
+
+fn from_ieee(bits: u64, fraction_bits: u32, exponent_bits: u32) -> F80 {
+    let exp_max = (1u64 << exponent_bits) - 1;
+    let bias = (exp_max >> 1) as i32;
+    let negative = bits >> (fraction_bits + exponent_bits) & 1 != 0;
+    let exp = (bits >> fraction_bits) & exp_max;
+    let fraction = bits & ((1u64 << fraction_bits) - 1);
+    let sign = sign_bit(negative);
+    if exp == exp_max {
+        let mantissa = INTEGER_BIT | (fraction << (63 - fraction_bits));
+        return F80::from_raw(EXP_MAX | sign, mantissa);
+    }
+    if exp == 0 {
+        if fraction == 0 {
+            return F80::zero(negative);
+        }
+        let shift = fraction.leading_zeros();
+        let mantissa = fraction << shift;
+        let unbiased = 1 - bias - (shift as i32 - (63 - fraction_bits as i32));
+        return F80::from_raw((unbiased + EXP_BIAS) as u16 | sign, mantissa);
+    }
+    let mantissa = INTEGER_BIT | (fraction << (63 - fraction_bits));
+    F80::from_raw((exp as i32 - bias + EXP_BIAS) as u16 | sign, mantissa)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn nearest() -> RoundMode {
+        RoundMode::extended(Rounding::Nearest)
+    }
+
+    #[test]
+    fn converts_to_and_from_double() {
+        for value in [1.0, -2.5, 0.1, 1e300, 5e-324, f64::MAX, -0.0] {
+            let extended = F80::from_f64(value);
+            assert_eq!(
+                extended.to_f64(Rounding::Nearest).0.to_bits(),
+                value.to_bits()
+            );
+        }
+        assert!(F80::from_f64(f64::NAN).is_nan());
+        assert_eq!(F80::from_f32(0.75).as_f64(), 0.75);
+    }
+
+    #[test]
+    fn arithmetic_keeps_extended_precision() {
+        let third = F80::ONE.div(F80::from_i64(3), nearest()).0;
+        assert_eq!(third, F80::from_raw(0x3FFD, 0xAAAA_AAAA_AAAA_AAAB));
+        // 2^64 + 1 is exact at 64 bits of precision but not in a double.
+        let big = F80::from_i64(1 << 62).mul(F80::from_i64(4), nearest()).0;
+        let sum = big.add(F80::ONE, nearest());
+        assert_eq!(sum.1 & EXC_PRECISION, EXC_PRECISION);
+        let sum = F80::from_i64(i64::MAX).add(F80::ONE, nearest()).0;
+        assert_eq!(sum, F80::from_raw(0x403E, INTEGER_BIT));
+        let (root, flags) = F80::from_i64(16).sqrt(nearest());
+        assert_eq!((root, flags), (F80::from_i64(4), 0));
+        let double = RoundMode::from_control(0x027F);
+        let third = F80::ONE.div(F80::from_i64(3), double).0;
+        assert_eq!(third.as_f64(), 1.0 / 3.0);
+    }
+
+    #[test]
+    fn special_values_follow_x87_rules() {
+        let (value, flags) = F80::ONE.div(F80::ZERO, nearest());
+        assert!(value.is_infinite() && flags == EXC_ZERO_DIVIDE);
+        let (value, flags) = F80::ZERO.div(F80::ZERO, nearest());
+        assert_eq!((value, flags), (F80::INDEFINITE, EXC_INVALID));
+        assert_eq!(
+            F80::from_i64(-1).sqrt(nearest()),
+            (F80::INDEFINITE, EXC_INVALID)
+        );
+        assert_eq!(F80::INDEFINITE.compare(F80::ONE), None);
+        assert_eq!(F80::ZERO.negate().compare(F80::ZERO), Some(Ordering::Equal));
+    }
+
+    #[test]
+    fn integer_conversion_honours_rounding_control() {
+        let value = F80::from_f64(-2.5);
+        assert_eq!(value.to_int(32, Rounding::Nearest).0, Some(-2));
+        assert_eq!(value.to_int(32, Rounding::Down).0, Some(-3));
+        assert_eq!(value.to_int(32, Rounding::Zero).0, Some(-2));
+        assert_eq!(F80::from_f64(1e10).to_int(32, Rounding::Nearest).0, None);
+        assert_eq!(
+            F80::from_f64(0.5).round_to_integral(Rounding::Up).0,
+            F80::ONE
+        );
+    }
+
+    #[test]
+    fn partial_remainder_reports_quotient_bits() {
+        let (value, quotient, complete, _) = F80::from_i64(17).remainder(F80::from_i64(5), false);
+        assert_eq!((value, quotient, complete), (F80::from_i64(2), 3, true));
+        let (value, quotient, _, _) = F80::from_i64(17).remainder(F80::from_i64(5), true);
+        assert_eq!((value, quotient), (F80::from_i64(2), 3));
+        let (value, _, _, _) = F80::from_i64(18).remainder(F80::from_i64(5), true);
+        assert_eq!(value, F80::from_i64(-2));
+    }
+
+    #[test]
+    fn packed_bcd_round_trips() {
+        let value = F80::from_i64(-1234567890);
+        let (bytes, _) = value.to_bcd(Rounding::Nearest);
+        let bytes = bytes.expect("fits");
+        assert_eq!(bytes[0], 0x90);
+        assert_eq!(bytes[9], 0x80);
+        assert_eq!(F80::from_bcd(bytes), value);
+    }
+}
@@ -0,0 +1,429 @@
+//! x87 FPU register state.
+//!
+//! Registers hold 80-bit extended values ([`F80`]). Faults are reported the way
+//! the hardware does it: the exception bits accumulate in the status word, and an
+//! unmasked one is raised on the next waiting FPU instruction.
+
+mod f80;
+
+pub(crate) use f80::*;
+
+use super::{Vm, VmError, USER_CODE_SELECTOR, USER_DATA_SELECTOR};
+
+// Status word bits beyond the exception flags.
+pub(crate) const FSW_STACK_FAULT: u16 = 1 << 6;
+pub(crate) const FSW_ERROR_SUMMARY: u16 = 1 << 7;
+pub(crate) const FSW_C0: u16 = 1 << 8;
+pub(crate) const FSW_C1: u16 = 1 << 9;
+pub(crate) const FSW_C2: u16 = 1 << 10;
+pub(crate) const FSW_C3: u16 = 1 << 14;
+const FSW_BUSY: u16 = 1 << 15;
+const FSW_TOP_SHIFT: u16 = 11;
+const FSW_TOP: u16 = 7 << FSW_TOP_SHIFT;
+const FSW_EXCEPTIONS: u16 = 0x3F;
+pub(crate) const FSW_CONDITION: u16 = FSW_C0 | FSW_C1 | FSW_C2 | FSW_C3;
+
+/// FNINIT control word: all exceptions masked, 64-bit precision, round to nearest.
+pub(crate) const FCW_INIT: u16 = 0x037F;
+/// Control word Windows starts 32-bit processes with: as FNINIT but 53-bit precision.
+const FCW_PROCESS_DEFAULT: u16 = 0x027F;
+
+const TAG_VALID: u16 = 0;
+const TAG_ZERO: u16 = 1;
+const TAG_SPECIAL: u16 = 2;
+const TAG_EMPTY: u16 = 3;
+
+/// Size of the FNSTENV image in 32-bit protected mode.
+pub(crate) const FPU_ENV_SIZE: usize = 28;
+/// Size of the FNSAVE image: the environment followed by ST(0)..ST(7).
+pub(crate) const FPU_SAVE_SIZE: usize = FPU_ENV_SIZE + 80;
+
+pub(crate) const STATUS_FLOAT_DENORMAL_OPERAND: u32 = 0xC000_008D;
+pub(crate) const STATUS_FLOAT_DIVIDE_BY_ZERO: u32 = 0xC000_008E;
+pub(crate) const STATUS_FLOAT_INEXACT_RESULT: u32 = 0xC000_008F;
+pub(crate) const STATUS_FLOAT_INVALID_OPERATION: u32 = 0xC000_0090;
+pub(crate) const STATUS_FLOAT_OVERFLOW: u32 = 0xC000_0091;
+pub(crate) const STATUS_FLOAT_STACK_CHECK: u32 = 0xC000_0092;
+pub(crate) const STATUS_FLOAT_UNDERFLOW: u32 = 0xC000_0093;
+
+#[derive(Debug, Clone)]
+pub(crate) struct FpuState {
+    /// Physical registers R0..R7; ST(i) is `stack[(top + i) & 7]`.
+    pub(crate) stack: [F80; 8],
+    pub(crate) valid: [bool; 8],
+    pub(crate) top: u8,
+    pub(crate) control_word: u16,
+    /// Status word without the TOP field, which lives in `top`.
+    pub(crate) status_word: u16,
+    /// Last non-control instruction, as FNSTENV reports it.
+    pub(crate) instruction_pointer: u32,
+    pub(crate) opcode: u16,
+    pub(crate) data_pointer: u32,
+}
+
+impl Default for FpuState {
+    fn default() -> Self {
+        Self {
+            control_word: FCW_PROCESS_DEFAULT,
+            ..Self::initialized()
+        }
+    }
+}
+
+impl FpuState {
+    /// The state FNINIT leaves behind.
+    pub(crate) fn initialized() -> Self {
+        Self {
+            stack: [F80::ZERO; 8],
+            valid: [false; 8],
+            top: 0,
+            control_word: FCW_INIT,
+            status_word: 0,
+            instruction_pointer: 0,
+            opcode: 0,
+            data_pointer: 0,
+        }
+    }
+
+    fn st_index(&self, index: usize) -> usize {
+        (self.top as usize + index) & 7
+    }
+
+    fn tag_word(&self) -> u16 {
+        let mut tags = 0;
+        for (reg, value) in self.stack.iter().enumerate() {
+            let tag = if !self.valid[reg] {
+                TAG_EMPTY
+            } else {
+                match value.class() {
+                    Class::Normal => TAG_VALID,
+                    Class::Zero => TAG_ZERO,
+                    _ => TAG_SPECIAL,
+                }
+            };
+            tags |= tag << (reg * 2);
+        }
+        tags
+    }
+}
+
+impl Vm {
+    /// ST(`index`) as the nearest `f64`.
+    pub fn fpu_st(&self, index: usize) -> Result<f64, VmError> {
+        if index >= 8 {
+            return Err(VmError::UnsupportedInstruction(0));
+        }
+        self.fpu_get(index)
+            .map(F80::as_f64)
+            .ok_or(VmError::FpuStackUnderflow)
+    }
+
+    /// ST(`index`) in its 80-bit memory layout, or `None` when empty or out of range.
+    pub fn fpu_st_bytes(&self, index: usize) -> Option<[u8; 10]> {
+        if index >= 8 {
+            return None;
+        }
+        self.fpu_get(index).map(F80::to_bytes)
+    }
+
+    /// Load ST(`index`) from its 80-bit memory layout and mark it valid.
+    pub fn fpu_set_st_bytes(&mut self, index: usize, bytes: [u8; 10]) -> Result<(), VmError> {
+        if index >= 8 {
+            return Err(VmError::InvalidConfig("fpu register index out of range"));
+        }
+        self.fpu_set(index, F80::from_bytes(bytes));
+        Ok(())
+    }
+
+    /// ST(`index`), or `None` when that register is empty.
+    pub(crate) fn fpu_get(&self, index: usize) -> Option<F80> {
+        let reg = self.fpu.st_index(index);
+        self.fpu.valid[reg].then_some(self.fpu.stack[reg])
+    }
+
+    pub(crate) fn fpu_set(&mut self, index: usize, value: F80) {
+        let reg = self.fpu.st_index(index);
+        self.fpu.stack[reg] = value;
+        self.fpu.valid[reg] = true;
+    }
+
+    /// Push onto the register stack. A full stack is a stack fault: masked, the
+    /// indefinite QNaN is pushed instead; unmasked, the stack is left alone.
+    pub(crate) fn fpu_push(&mut self, value: F80) {
+        let reg = self.fpu.st_index(7);
+        if self.fpu.valid[reg] {
+            self.fpu_stack_fault(true);
+            if !self.fpu_masked(EXC_INVALID) {
+                return;
+            }
+            self.fpu.top = reg as u8;
+            self.fpu.stack[reg] = F80::INDEFINITE;
+            return;
+        }
+        self.fpu.top = reg as u8;
+        self.fpu.stack[reg] = value;
+        self.fpu.valid[reg] = true;
+    }
+
+    /// Mark ST(0) empty and pop it.
+    pub(crate) fn fpu_pop(&mut self) {
+        let reg = self.fpu.st_index(0);
+        self.fpu.valid[reg] = false;
+        self.fpu.top = (self.fpu.top + 1) & 7;
+    }
+
+    pub(crate) fn fpu_free(&mut self, index: usize) {
+        let reg = self.fpu.st_index(index);
+        self.fpu.valid[reg] = false;
+    }
+
+    /// FINCSTP/FDECSTP.
+    pub(crate) fn fpu_rotate(&mut self, up: bool) {
+        self.fpu.top = if up {
+            (self.fpu.top + 1) & 7
+        } else {
+            self.fpu.top.wrapping_sub(1) & 7
+        };
+    }
+
+    pub fn fpu_control(&self) -> u16 {
+        self.fpu.control_word
+    }
+
+    pub fn fpu_set_control(&mut self, value: u16) {
+        self.fpu.control_word = value | 0x40;
+        self.fpu_update_summary();
+    }
+
+    /// Rounding and precision control for arithmetic results.
+    pub(crate) fn fpu_round_mode(&self) -> RoundMode {
+        RoundMode::from_control(self.fpu.control_word)
+    }
+
+    pub(crate) fn fpu_rounding(&self) -> Rounding {
+        Rounding::from_control(self.fpu.control_word)
+    }
+
+    /// The status word with TOP in bits 11-13.
+    pub fn fpu_status(&self) -> u16 {
+        (self.fpu.status_word & !FSW_TOP) | ((self.fpu.top as u16) << FSW_TOP_SHIFT)
+    }
+
+    pub fn fpu_set_status(&mut self, value: u16) {
+        self.fpu.status_word = value & !FSW_TOP;
+        self.fpu.top = ((value & FSW_TOP) >> FSW_TOP_SHIFT) as u8;
+    }
+
+    /// Replace C0-C3.
+    pub(crate) fn fpu_set_condition(&mut self, bits: u16) {
+        self.fpu.status_word = (self.fpu.status_word & !FSW_CONDITION) | (bits & FSW_CONDITION);
+    }
+
+    /// FNCLEX.
+    pub(crate) fn fpu_clear_exceptions(&mut self) {
+        self.fpu.status_word &= !(FSW_EXCEPTIONS | FSW_STACK_FAULT | FSW_ERROR_SUMMARY | FSW_BUSY);
+    }
+
+    pub(crate) fn fpu_reset(&mut self) {
+        self.fpu = FpuState::default();
+    }
+
+    /// FNINIT.
+    pub(crate) fn fpu_init(&mut self) {
+        self.fpu = FpuState::initialized();
+    }
+
+    fn fpu_masked(&self, exceptions: u16) -> bool {
+        self.fpu.control_word & exceptions == exceptions
+    }
+
+    /// Record the exceptions an operation raised; C1 reports whether it rounded up.
+    pub(crate) fn fpu_raise(&mut self, flags: u16) {
+        let c1 = if flags & ROUNDED_UP != 0 { FSW_C1 } else { 0 };
+        self.fpu.status_word = (self.fpu.status_word & !FSW_C1) | c1 | (flags & FSW_EXCEPTIONS);
+        self.fpu_update_summary();
+    }
+
+    /// An empty source register (`overflow == false`) or a full stack on push.
+    pub(crate) fn fpu_stack_fault(&mut self, overflow: bool) {
+        let c1 = if overflow { FSW_C1 } else { 0 };
+        self.fpu.status_word =
+            (self.fpu.status_word & !FSW_C1) | c1 | EXC_INVALID | FSW_STACK_FAULT;
+        self.fpu_update_summary();
+    }
+
+    /// Unmasked invalid-operation, denormal and zero-divide exceptions leave the
+    /// destination untouched.
+    pub(crate) fn fpu_blocks_result(&self, flags: u16) -> bool {
+        flags & (EXC_INVALID | EXC_DENORMAL | EXC_ZERO_DIVIDE) & !self.fpu.control_word != 0
+    }
+
+    /// Whether masked invalid-operation results should be written.
+    pub(crate) fn fpu_invalid_masked(&self) -> bool {
+        self.fpu_masked(EXC_INVALID)
+    }
+
+    fn fpu_update_summary(&mut self) {
+        let unmasked = self.fpu.status_word & !self.fpu.control_word & FSW_EXCEPTIONS;
+        if unmasked != 0 {
+            self.fpu.status_word |= FSW_ERROR_SUMMARY | FSW_BUSY;
+        } else {
+            self.fpu.status_word &= !(FSW_ERROR_SUMMARY | FSW_BUSY);
+        }
+    }
+
+    /// Raise a pending unmasked exception; waiting FPU instructions and FWAIT call this.
+    ///
+    /// The error summary is cleared first so a handler that resumes without touching
+    /// the FPU does not fault again; the exception flags themselves stay set.
+    pub(crate) fn fpu_check_pending(&mut self, address: u32) -> Result<(), VmError> {
+        if self.fpu.status_word & FSW_ERROR_SUMMARY == 0 {
+            return Ok(());
+        }
+        let unmasked = self.fpu.status_word & !self.fpu.control_word & FSW_EXCEPTIONS;
+        self.fpu.status_word &= !(FSW_ERROR_SUMMARY | FSW_BUSY);
+        let code = if unmasked & EXC_INVALID != 0 {
+            if self.fpu.status_word & FSW_STACK_FAULT != 0 {
+                STATUS_FLOAT_STACK_CHECK
+            } else {
+                STATUS_FLOAT_INVALID_OPERATION
+            }
+        } else if unmasked & EXC_DENORMAL != 0 {
+            STATUS_FLOAT_DENORMAL_OPERAND
+        } else if unmasked & EXC_ZERO_DIVIDE != 0 {
+            STATUS_FLOAT_DIVIDE_BY_ZERO
+        } else if unmasked & EXC_OVERFLOW != 0 {
+            STATUS_FLOAT_OVERFLOW
+        } else if unmasked & EXC_UNDERFLOW != 0 {
+            STATUS_FLOAT_UNDERFLOW
+        } else {
+            STATUS_FLOAT_INEXACT_RESULT
+        };
+        Err(VmError::Exception { code, address })
+    }
+
+    /// Remember the last non-control instruction for FNSTENV/FNSAVE.
+    pub(crate) fn fpu_note_instruction(&mut self, address: u32, opcode: u16, operand: u32) {
+        self.fpu.instruction_pointer = address;
+        self.fpu.opcode = opcode & 0x7FF;
+        self.fpu.data_pointer = operand;
+    }
+
+    /// The FNSTENV image (32-bit protected-mode layout).
+    pub(crate) fn fpu_env_image(&self) -> [u8; FPU_ENV_SIZE] {
+        let words = [
+            0xFFFF_0000 | self.fpu.control_word as u32,
+            0xFFFF_0000 | self.fpu_status() as u32,
+            0xFFFF_0000 | self.fpu.tag_word() as u32,
+            self.fpu.instruction_pointer,
+            ((self.fpu.opcode as u32) << 16) | USER_CODE_SELECTOR,
+            self.fpu.data_pointer,
+            0xFFFF_0000 | USER_DATA_SELECTOR,
+        ];
+        let mut image = [0u8; FPU_ENV_SIZE];
+        for (chunk, word) in image.chunks_exact_mut(4).zip(words) {
+            chunk.copy_from_slice(&word.to_le_bytes());
+        }
+        image
+    }
+
+    /// FLDENV: tags other than empty are recomputed from the register contents.
+    pub(crate) fn fpu_load_env(&mut self, image: &[u8]) {
+        let word = |index: usize| {
+            u32::from_le_bytes(image[index * 4..index * 4 + 4].try_into().expect("4 bytes"))
+        };
+        self.fpu.control_word = word(0) as u16;
+        self.fpu_set_status(word(1) as u16);
+        let tags = word(2) as u16;
+        for reg in 0..8 {
+            self.fpu.valid[reg] = (tags >> (reg * 2)) & 3 != TAG_EMPTY;
+        }
+        self.fpu.instruction_pointer = word(3);
+        self.fpu.opcode = ((word(4) >> 16) & 0x7FF) as u16;
+        self.fpu.data_pointer = word(5);
+        self.fpu_update_summary();
+    }
+
+    /// The FNSAVE image: environment, then ST(0)..ST(7).
+    pub(crate) fn fpu_save_image(&self) -> [u8; FPU_SAVE_SIZE] {
+        let mut image = [0u8; FPU_SAVE_SIZE];
+        image[..FPU_ENV_SIZE].copy_from_slice(&self.fpu_env_image());
+        for index in 0..8 {
+            let value = self.fpu.stack[self.fpu.st_index(index)];
+            let offset = FPU_ENV_SIZE + index * 10;
+            image[offset..offset + 10].copy_from_slice(&value.to_bytes());
+        }
+        image
+    }
+
+    /// FRSTOR.
+    pub(crate) fn fpu_load_image(&mut self, image: &[u8]) {
+        self.fpu_load_env(&image[..FPU_ENV_SIZE]);
+        for index in 0..8 {
+            let offset = FPU_ENV_SIZE + index * 10;
+            let bytes: [u8; 10] = image[offset..offset + 10].try_into().expect("10 bytes");
+            let reg = self.fpu.st_index(index);
+            self.fpu.stack[reg] = F80::from_bytes(bytes);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::{Architecture, VmConfig};
+
+    fn create_test_vm() -> Vm {
+        Vm::new(VmConfig::new().architecture(Architecture::X86)).expect("vm")
+    }
+
+    #[test]
+    fn push_onto_full_stack_is_masked_stack_fault() {
+        let mut vm = create_test_vm();
+        for value in 0..8 {
+            vm.fpu_push(F80::from_i64(value));
+        }
+        vm.fpu_push(F80::ONE);
+        assert_eq!(vm.fpu_get(0), Some(F80::INDEFINITE));
+        let status = vm.fpu_status();
+        assert_eq!(
+            status & (FSW_STACK_FAULT | FSW_C1),
+            FSW_STACK_FAULT | FSW_C1
+        );
+        assert!(vm.fpu_check_pending(0).is_ok());
+    }
+
+    #[test]
+    fn unmasked_exception_is_raised_on_next_check() {
+        let mut vm = create_test_vm();
+        vm.fpu_set_control(FCW_INIT & !EXC_ZERO_DIVIDE);
+        vm.fpu_raise(EXC_ZERO_DIVIDE);
+        assert!(vm.fpu_blocks_result(EXC_ZERO_DIVIDE));
+        match vm.fpu_check_pending(0x401000) {
+            Err(VmError::Exception { code, address }) => {
+                assert_eq!(code, STATUS_FLOAT_DIVIDE_BY_ZERO);
+                assert_eq!(address, 0x401000);
+            }
+            other => panic!("unexpected {other:?}"),
+        }
+        assert!(vm.fpu_check_pending(0x401000).is_ok());
+        assert_ne!(vm.fpu_status() & EXC_ZERO_DIVIDE, 0);
+    }
+
+    #[test]
+    fn save_image_round_trips() {
+        let mut vm = create_test_vm();
+        vm.fpu_push(F80::PI);
+        vm.fpu_push(F80::from_f64(-2.5));
+        vm.fpu_set_condition(FSW_C3);
+        let image = vm.fpu_save_image();
+        assert_eq!(u16::from_le_bytes([image[8], image[9]]), 0x0FFF);
+        vm.fpu_init();
+        assert_eq!(vm.fpu_get(0), None);
+        vm.fpu_load_image(&image);
+        assert_eq!(vm.fpu_st(0).unwrap(), -2.5);
+        assert_eq!(vm.fpu_get(1), Some(F80::PI));
+        assert_eq!(vm.fpu_status() & FSW_C3, FSW_C3);
+        assert_eq!(vm.fpu_control(), FCW_PROCESS_DEFAULT);
+    }
+}
@@ -0,0 +1,229 @@
+//! Free-list heap allocator backing HeapAlloc and host-side allocations.
+//!
+//! A heap owns one or more segments of guest address space. Free space is
+//! kept in an address-ordered map (for coalescing) plus a size index (for
+//! best-fit lookups); the allocator never touches guest memory itself.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+/// Pseudo-handle returned by GetProcessHeap.
+pub(crate) const PROCESS_HEAP: u32 = 0x1000;
+
+/// Minimum alignment and size granularity of heap blocks.
+pub(crate) const HEAP_ALIGN: u32 = 8;
+
+#[derive(Debug, Clone, Copy)]
+struct Block {
+    // Requested size, as reported by HeapSize.
+    size: u32,
+    // Bytes actually reserved for the block.
+    span: u32,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct Heap {
+    segments: Vec<(u32, u32)>,
+    free: BTreeMap<u32, u32>,
+    by_size: BTreeSet<(u32, u32)>,
+    used: BTreeMap<u32, Block>,
+    max_size: Option<u32>,
+}
+
+impl Heap {
+    /// Create a heap over `[start, start + size)`. `max_size` caps growth; `None` is growable.
+    pub(crate) fn new(start: u32, size: u32, max_size: Option<u32>) -> Self {
+        let mut heap = Self {
+            segments: Vec::new(),
+            free: BTreeMap::new(),
+            by_size: BTreeSet::new(),
+            used: BTreeMap::new(),
+            max_size,
+        };
+        heap.add_segment(start, size);
+        heap
+    }
+
+    /// Hand a new range of address space to the heap.
+    pub(crate) fn add_segment(&mut self, start: u32, size: u32) {
+        let aligned = align_up(start, HEAP_ALIGN);
+        let size = size.saturating_sub(aligned - start) & !(HEAP_ALIGN - 1);
+        if size == 0 {
+            return;
+        }
+        self.segments.push((aligned, size));
+        self.insert_free(aligned, size);
+    }
+
+    pub(crate) fn segments(&self) -> &[(u32, u32)] {
+        &self.segments
+    }
+
+    /// Total bytes owned by the heap across all segments.
+    pub(crate) fn capacity(&self) -> u64 {
+        self.segments.iter().map(|&(_, size)| size as u64).sum()
+    }
+
+    /// Whether another segment of `size` bytes stays within the heap's maximum.
+    pub(crate) fn can_grow(&self, size: u32) -> bool {
+        match self.max_size {
+            Some(max) => self.capacity() + size as u64 <= max as u64,
+            None => true,
+        }
+    }
+
+    /// Best-fit allocation of `size` bytes aligned to `align`.
+    pub(crate) fn alloc(&mut self, size: u32, align: u32) -> Option<u32> {
+        let align = align.max(HEAP_ALIGN).checked_next_power_of_two()?;
+        let span = block_span(size)?;
+        let (start, len, addr) = self.by_size.range((span, 0)..).find_map(|&(len, start)| {
+            let addr = align_up_checked(start, align)?;
+            let end = addr as u64 + span as u64;
+            (end <= start as u64 + len as u64).then_some((start, len, addr))
+        })?;
+        self.remove_free(start, len);
+        if addr > start {
+            self.insert_free(start, addr - start);
+        }
+        let tail = (start + len) - (addr + span);
+        if tail > 0 {
+            self.insert_free(addr + span, tail);
+        }
+        self.used.insert(addr, Block { size, span });
+        Some(addr)
+    }
+
+    /// Release a block and merge it with free neighbours. Returns its requested size.
+    pub(crate) fn free(&mut self, ptr: u32) -> Option<u32> {
+        let block = self.used.remove(&ptr)?;
+        let mut start = ptr;
+        let mut len = block.span;
+        if let Some((&prev, &prev_len)) = self.free.range(..ptr).next_back() {
+            if prev + prev_len == ptr {
+                self.remove_free(prev, prev_len);
+                start = prev;
+                len += prev_len;
+            }
+        }
+        if let Some(next_len) = self.free.get(&(ptr + block.span)).copied() {
+            self.remove_free(ptr + block.span, next_len);
+            len += next_len;
+        }
+        self.insert_free(start, len);
+        Some(block.size)
+    }
+
+    /// Grow or shrink a block without moving it. Returns the old requested size.
+    pub(crate) fn resize_in_place(&mut self, ptr: u32, size: u32) -> Option<u32> {
+        let block = *self.used.get(&ptr)?;
+        let span = block_span(size)?;
+        let end = ptr + block.span;
+        if span > block.span {
+            let next_len = self.free.get(&end).copied()?;
+            if block.span as u64 + next_len as u64 >= span as u64 {
+                self.remove_free(end, next_len);
+                let rest = block.span + next_len - span;
+                if rest > 0 {
+                    self.insert_free(ptr + span, rest);
+                }
+            } else {
+                return None;
+            }
+        } else if span < block.span {
+            let mut rest = block.span - span;
+            if let Some(next_len) = self.free.get(&end).copied() {
+                self.remove_free(end, next_len);
+                rest += next_len;
+            }
+            self.insert_free(ptr + span, rest);
+        }
+        self.used.insert(ptr, Block { size, span });
+        Some(block.size)
+    }
+
+    pub(crate) fn size_of(&self, ptr: u32) -> Option<u32> {
+        self.used.get(&ptr).map(|block| block.size)
+    }
+
+    /// Live allocations as `(address, requested size)` pairs in address order.
+    pub(crate) fn allocations(&self) -> impl Iterator<Item = (u32, u32)> + '_ {
+        self.used.iter().map(|(&addr, block)| (addr, block.size))
+    }
+
+    fn insert_free(&mut self, start: u32, len: u32) {
+        self.free.insert(start, len);
+        self.by_size.insert((len, start));
+    }
+
+    fn remove_free(&mut self, start: u32, len: u32) {
+        self.free.remove(&start);
+        self.by_size.remove(&(len, start));
+    }
+}
+
+fn block_span(size: u32) -> Option<u32> {
+    align_up_checked(size.max(1), HEAP_ALIGN)
+}
+
+fn align_up(value: u32, align: u32) -> u32 {
+    align_up_checked(value, align).unwrap_or(!(align - 1))
+}
+
+fn align_up_checked(value: u32, align: u32) -> Option<u32> {
+    Some(value.checked_add(align - 1)? & !(align - 1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn freed_blocks_are_reused() {
+        let mut heap = Heap::new(0x1_0000, 0x100, None);
+        let a = heap.alloc(0x40, 8).unwrap();
+        let b = heap.alloc(0x40, 8).unwrap();
+        assert_eq!(heap.free(a), Some(0x40));
+        assert_eq!(heap.alloc(0x20, 8), Some(a));
+        assert_ne!(a, b);
+    }
+
+    #[test]
+    fn neighbours_coalesce() {
+        let mut heap = Heap::new(0x1_0000, 0x60, None);
+        let a = heap.alloc(0x20, 8).unwrap();
+        let b = heap.alloc(0x20, 8).unwrap();
+        let c = heap.alloc(0x20, 8).unwrap();
+        assert_eq!(heap.alloc(1, 8), None);
+        heap.free(a);
+        heap.free(c);
+        heap.free(b);
+        assert_eq!(heap.alloc(0x60, 8), Some(0x1_0000));
+    }
+
+    #[test]
+    fn aligned_allocations_leave_padding_free() {
+        let mut heap = Heap::new(0x1_0008, 0x200, None);
+        let ptr = heap.alloc(0x10, 0x40).unwrap();
+        assert_eq!(ptr % 0x40, 0);
+        assert_eq!(heap.alloc(0x8, 8), Some(0x1_0008));
+    }
+
+    #[test]
+    fn resize_in_place_uses_following_free_space() {
+        let mut heap = Heap::new(0x1_0000, 0x100, None);
+        let a = heap.alloc(0x10, 8).unwrap();
+        assert_eq!(heap.resize_in_place(a, 0x80), Some(0x10));
+        assert_eq!(heap.size_of(a), Some(0x80));
+        let b = heap.alloc(0x10, 8).unwrap();
+        assert_eq!(b, a + 0x80);
+        assert_eq!(heap.resize_in_place(a, 0x90), None);
+        assert_eq!(heap.resize_in_place(a, 0x20), Some(0x80));
+        assert_eq!(heap.alloc(0x60, 8), Some(a + 0x20));
+    }
+
+    #[test]
+    fn growth_respects_maximum() {
+        let heap = Heap::new(0x1_0000, 0x1000, Some(0x2000));
+        assert!(heap.can_grow(0x1000));
+        assert!(!heap.can_grow(0x1001));
+    }
+}
@@ -0,0 +1,92 @@
+//! Opt-in heap checking: red zones around blocks, invalid frees and leak tracking.
+
+use std::collections::BTreeMap;
+
+/// Bytes of canary guarding each side of a block.
+pub(crate) const RED_ZONE: u32 = 16;
+/// Red-zone fill, matching the MSVC debug CRT's "no man's land" byte.
+pub(crate) const CANARY: u8 = 0xFD;
+/// Fill for freed blocks so use-after-free reads stand out.
+pub(crate) const FREED_FILL: u8 = 0xDD;
+pub(crate) const MAX_STACK_FRAMES: usize = 16;
+
+/// Guest API family an allocation came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HeapApi {
+    HeapAlloc,
+    Malloc,
+    GlobalAlloc,
+    SysAllocString,
+}
+
+/// Where a heap operation happened: the return address of the API call and the
+/// return addresses found by walking the guest's EBP chain.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AllocationSite {
+    pub eip: u32,
+    pub call_stack: Vec<u32>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HeapIssueKind {
+    DoubleFree,
+    UnknownFree,
+    /// The canary after the block was overwritten.
+    BufferOverrun,
+    /// The canary before the block was overwritten.
+    BufferUnderrun,
+}
+
+/// A heap misuse detected while heap debugging was enabled.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HeapIssue {
+    pub kind: HeapIssueKind,
+    pub addr: u32,
+    /// Size of the affected block, or 0 when the pointer was never allocated.
+    pub size: u32,
+    /// Where the problem was detected (the free, realloc or the report itself).
+    pub site: AllocationSite,
+    pub allocated_at: Option<AllocationSite>,
+}
+
+/// A block allocated through a guest API that was still live when the report was taken.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HeapLeak {
+    pub addr: u32,
+    pub size: u32,
+    pub api: HeapApi,
+    pub allocated_at: AllocationSite,
+}
+
+/// Result of [`Vm::heap_report`](crate::Vm::heap_report).
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HeapReport {
+    pub issues: Vec<HeapIssue>,
+    pub leaks: Vec<HeapLeak>,
+}
+
+impl HeapReport {
+    pub fn is_clean(&self) -> bool {
+        self.issues.is_empty() && self.leaks.is_empty()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct DebugBlock {
+    pub(crate) heap: u32,
+    pub(crate) raw: u32,
+    pub(crate) size: u32,
+    // Bytes between the raw block and the user pointer (the front red zone).
+    pub(crate) front: u32,
+    // None for host-internal allocations, which are checked but never reported as leaks.
+    pub(crate) api: Option<HeapApi>,
+    pub(crate) site: AllocationSite,
+}
+
+/// Heap debugging state, keyed by the pointers handed to the guest.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct HeapDebug {
+    pub(crate) blocks: BTreeMap<u32, DebugBlock>,
+    pub(crate) freed: BTreeMap<u32, DebugBlock>,
+    pub(crate) issues: Vec<HeapIssue>,
+}
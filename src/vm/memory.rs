@@ -0,0 +1,546 @@
+//! Sparse, page-granular guest address space.
+
+use std::collections::BTreeMap;
+use std::fmt;
+
+pub(crate) const PAGE_SIZE: u32 = 0x1000;
+const PAGE_SHIFT: u32 = 12;
+
+// Windows page protection values, stored per page as-is so VirtualQuery can echo them.
+pub(crate) const PAGE_NOACCESS: u32 = 0x01;
+pub(crate) const PAGE_READONLY: u32 = 0x02;
+pub(crate) const PAGE_READWRITE: u32 = 0x04;
+pub(crate) const PAGE_WRITECOPY: u32 = 0x08;
+pub(crate) const PAGE_EXECUTE: u32 = 0x10;
+pub(crate) const PAGE_EXECUTE_READ: u32 = 0x20;
+pub(crate) const PAGE_EXECUTE_READWRITE: u32 = 0x40;
+pub(crate) const PAGE_EXECUTE_WRITECOPY: u32 = 0x80;
+// PAGE_GUARD, PAGE_NOCACHE and PAGE_WRITECOMBINE do not change access rights here.
+const PAGE_MODIFIERS: u32 = 0x100 | 0x200 | 0x400;
+
+type Page = Box<[u8; PAGE_SIZE as usize]>;
+
+/// Kind of memory access that faulted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessKind {
+    Read,
+    Write,
+    Execute,
+}
+
+impl AccessKind {
+    /// `ExceptionInformation[0]` value Windows reports for `EXCEPTION_ACCESS_VIOLATION`.
+    pub fn exception_code(self) -> u32 {
+        match self {
+            AccessKind::Read => 0,
+            AccessKind::Write => 1,
+            AccessKind::Execute => 8,
+        }
+    }
+}
+
+impl fmt::Display for AccessKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AccessKind::Read => write!(f, "read"),
+            AccessKind::Write => write!(f, "write"),
+            AccessKind::Execute => write!(f, "execute"),
+        }
+    }
+}
+
+/// True when `protect` (a `PAGE_*` value) permits `access`.
+pub(crate) fn protection_allows(protect: u32, access: AccessKind) -> bool {
+    matches!(
+        (protect & !PAGE_MODIFIERS, access),
+        (PAGE_READONLY | PAGE_EXECUTE_READ, AccessKind::Read)
+            | (
+                PAGE_READWRITE | PAGE_WRITECOPY,
+                AccessKind::Read | AccessKind::Write
+            )
+            | (PAGE_EXECUTE_READWRITE | PAGE_EXECUTE_WRITECOPY, _)
+            | (PAGE_EXECUTE | PAGE_EXECUTE_READ, AccessKind::Execute)
+    )
+}
+
+/// True for the `PAGE_*` values VirtualAlloc/VirtualProtect accept.
+pub(crate) fn is_valid_protection(protect: u32) -> bool {
+    matches!(
+        protect & !PAGE_MODIFIERS,
+        PAGE_NOACCESS
+            | PAGE_READONLY
+            | PAGE_READWRITE
+            | PAGE_WRITECOPY
+            | PAGE_EXECUTE
+            | PAGE_EXECUTE_READ
+            | PAGE_EXECUTE_READWRITE
+            | PAGE_EXECUTE_WRITECOPY
+    )
+}
+
+#[derive(Debug, Clone)]
+struct PageEntry {
+    // None while the page is only reserved.
+    data: Option<Page>,
+    protect: u32,
+    // Instructions decoded from this page are cached by the executor.
+    code: bool,
+}
+
+/// State of one mapped page as reported to VirtualQuery.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct PageInfo {
+    pub(crate) protect: u32,
+    pub(crate) committed: bool,
+}
+
+/// Guest memory keyed by page number; unmapped pages fault instead of reading as zero.
+#[derive(Debug, Default, Clone)]
+pub(crate) struct GuestMemory {
+    pages: BTreeMap<u32, PageEntry>,
+    // Watched code pages changed since the executor last looked.
+    modified_code: Vec<u32>,
+}
+
+impl GuestMemory {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.pages.is_empty()
+    }
+
+    pub(crate) fn clear(&mut self) {
+        let pages = std::mem::take(&mut self.pages);
+        self.modified_code.extend(
+            pages
+                .into_iter()
+                .filter(|(_, entry)| entry.code)
+                .map(|(page, _)| page),
+        );
+    }
+
+    /// Total number of mapped or reserved bytes.
+    #[cfg(test)]
+    pub(crate) fn mapped_size(&self) -> u64 {
+        self.pages.len() as u64 * PAGE_SIZE as u64
+    }
+
+    /// Map zero-filled read/write pages covering `addr..addr + size`.
+    ///
+    /// Pages that are already committed keep their data and protection.
+    pub(crate) fn map(&mut self, addr: u32, size: u32) {
+        let Some(range) = page_range(addr, size) else {
+            return;
+        };
+        for page in range {
+            let entry = self.pages.entry(page).or_insert(PageEntry {
+                data: None,
+                protect: PAGE_READWRITE,
+                code: false,
+            });
+            if entry.data.is_none() {
+                entry.data = Some(zero_page());
+                entry.protect = PAGE_READWRITE;
+            }
+        }
+    }
+
+    /// Reserve address space without backing it; any access faults until committed.
+    pub(crate) fn reserve(&mut self, addr: u32, size: u32) {
+        let Some(range) = page_range(addr, size) else {
+            return;
+        };
+        for page in range {
+            self.pages.entry(page).or_insert(PageEntry {
+                data: None,
+                protect: PAGE_NOACCESS,
+                code: false,
+            });
+        }
+    }
+
+    /// Commit reserved or committed pages with `protect`; new commits read as zero.
+    pub(crate) fn commit(&mut self, addr: u32, size: u32, protect: u32) -> Result<(), u32> {
+        let Some(range) = page_range(addr, size) else {
+            return Ok(());
+        };
+        if let Some(page) = range.clone().find(|page| !self.pages.contains_key(page)) {
+            return Err(page << PAGE_SHIFT);
+        }
+        self.release_code(range.clone());
+        for (_, entry) in self.pages.range_mut(range) {
+            if entry.data.is_none() {
+                entry.data = Some(zero_page());
+            }
+            entry.protect = protect;
+        }
+        Ok(())
+    }
+
+    /// Drop the backing of pages in range, keeping them reserved.
+    pub(crate) fn decommit(&mut self, addr: u32, size: u32) {
+        let Some(range) = page_range(addr, size) else {
+            return;
+        };
+        self.release_code(range.clone());
+        for (_, entry) in self.pages.range_mut(range) {
+            entry.data = None;
+            entry.protect = PAGE_NOACCESS;
+        }
+    }
+
+    pub(crate) fn unmap(&mut self, addr: u32, size: u32) {
+        let Some(range) = page_range(addr, size) else {
+            return;
+        };
+        self.release_code(range.clone());
+        for page in range {
+            self.pages.remove(&page);
+        }
+    }
+
+    /// Change the protection of committed pages, returning the previous protection of the first.
+    pub(crate) fn protect(&mut self, addr: u32, size: u32, protect: u32) -> Result<u32, u32> {
+        let range = page_range(addr, size.max(1)).expect("non-empty range");
+        if let Some(page) = range.clone().find(|page| !self.is_committed_page(*page)) {
+            return Err((page << PAGE_SHIFT).max(addr));
+        }
+        let old = self.pages[range.start()].protect;
+        self.release_code(range.clone());
+        for (_, entry) in self.pages.range_mut(range) {
+            entry.protect = protect;
+        }
+        Ok(old)
+    }
+
+    /// Flag the committed pages covering `addr..addr + len` as holding cached instructions.
+    ///
+    /// The next write, remap or protection change to one of them is reported once by
+    /// [`GuestMemory::take_modified_code`].
+    pub(crate) fn watch_code(&mut self, addr: u32, len: u32) {
+        let Some(range) = page_range(addr, len) else {
+            return;
+        };
+        for (_, entry) in self.pages.range_mut(range) {
+            entry.code = entry.data.is_some();
+        }
+    }
+
+    /// Watched pages that changed since the last call, as page numbers.
+    pub(crate) fn take_modified_code(&mut self) -> Vec<u32> {
+        std::mem::take(&mut self.modified_code)
+    }
+
+    pub(crate) fn page_info(&self, addr: u32) -> Option<PageInfo> {
+        self.pages.get(&(addr >> PAGE_SHIFT)).map(|entry| PageInfo {
+            protect: entry.protect,
+            committed: entry.data.is_some(),
+        })
+    }
+
+    /// Bytes from `addr`'s page onwards (at most `limit`) whose pages share its state.
+    pub(crate) fn run_length(&self, addr: u32, limit: u32) -> u32 {
+        let Some(info) = self.page_info(addr) else {
+            return 0;
+        };
+        let mut length = 0u32;
+        let mut page = addr >> PAGE_SHIFT;
+        while length < limit && self.page_info(page << PAGE_SHIFT) == Some(info) {
+            length = length.saturating_add(PAGE_SIZE);
+            if page == u32::MAX >> PAGE_SHIFT {
+                break;
+            }
+            page += 1;
+        }
+        length
+    }
+
+    /// Start of the first mapped or reserved page at or above `addr`.
+    pub(crate) fn next_used(&self, addr: u32) -> Option<u32> {
+        self.pages
+            .range((addr >> PAGE_SHIFT)..)
+            .next()
+            .map(|(page, _)| page << PAGE_SHIFT)
+    }
+
+    /// Start of the gap-free run of mapped or reserved pages containing `addr`.
+    pub(crate) fn run_start(&self, addr: u32) -> u32 {
+        let mut page = addr >> PAGE_SHIFT;
+        while page > 0 && self.pages.contains_key(&(page - 1)) {
+            page -= 1;
+        }
+        page << PAGE_SHIFT
+    }
+
+    /// True when `addr` lies in a committed page.
+    pub(crate) fn is_mapped(&self, addr: u32) -> bool {
+        self.is_committed_page(addr >> PAGE_SHIFT)
+    }
+
+    /// True when no page in `addr..addr + size` is mapped or reserved.
+    pub(crate) fn is_range_free(&self, addr: u32, size: u32) -> bool {
+        match page_range(addr, size) {
+            Some(range) => self.pages.range(range).next().is_none(),
+            None => true,
+        }
+    }
+
+    /// Find the lowest `align`-aligned free range of `size` bytes at or above `hint`.
+    pub(crate) fn find_free(&self, hint: u32, size: u32, align: u32) -> Option<u32> {
+        let align = align.max(PAGE_SIZE) as u64;
+        let size = (size.max(1) as u64).div_ceil(PAGE_SIZE as u64) * PAGE_SIZE as u64;
+        let mut candidate = (hint as u64).div_ceil(align) * align;
+        while candidate + size <= 1u64 << 32 {
+            let first = (candidate >> PAGE_SHIFT) as u32;
+            let last = ((candidate + size - 1) >> PAGE_SHIFT) as u32;
+            match self.pages.range(first..=last).next_back() {
+                None => return Some(candidate as u32),
+                Some((page, _)) => {
+                    let next = (*page as u64 + 1) << PAGE_SHIFT;
+                    candidate = next.div_ceil(align) * align;
+                }
+            }
+        }
+        None
+    }
+
+    /// Fail with the first address in range that does not permit `access`.
+    pub(crate) fn check(&self, addr: u32, len: usize, access: AccessKind) -> Result<(), u32> {
+        match self.first_denied(addr, len, Some(access)) {
+            Some(fault) => Err(fault),
+            None => Ok(()),
+        }
+    }
+
+    /// Copy guest bytes into `buf`, returning the first unreadable address on failure.
+    pub(crate) fn read(&self, addr: u32, buf: &mut [u8]) -> Result<(), u32> {
+        self.check(addr, buf.len(), AccessKind::Read)?;
+        let mut done = 0usize;
+        while done < buf.len() {
+            let (page, offset) = split(addr.wrapping_add(done as u32));
+            let chunk = (PAGE_SIZE as usize - offset).min(buf.len() - done);
+            let data = self.pages[&page].data.as_ref().expect("page checked above");
+            buf[done..done + chunk].copy_from_slice(&data[offset..offset + chunk]);
+            done += chunk;
+        }
+        Ok(())
+    }
+
+    /// Copy `bytes` into guest memory; nothing is written if any target page denies writes.
+    pub(crate) fn write(&mut self, addr: u32, bytes: &[u8]) -> Result<(), u32> {
+        self.check(addr, bytes.len(), AccessKind::Write)?;
+        self.copy_in(addr, bytes);
+        Ok(())
+    }
+
+    /// Write committed pages regardless of protection, as the loader does for the IAT.
+    pub(crate) fn write_unchecked(&mut self, addr: u32, bytes: &[u8]) -> Result<(), u32> {
+        if let Some(fault) = self.first_denied(addr, bytes.len(), None) {
+            return Err(fault);
+        }
+        self.copy_in(addr, bytes);
+        Ok(())
+    }
+
+    pub(crate) fn fill(&mut self, addr: u32, value: u8, len: usize) -> Result<(), u32> {
+        self.check(addr, len, AccessKind::Write)?;
+        let mut done = 0usize;
+        while done < len {
+            let (data, offset, chunk) = self.chunk_mut(addr, done, len);
+            data[offset..offset + chunk].fill(value);
+            done += chunk;
+        }
+        Ok(())
+    }
+
+    fn copy_in(&mut self, addr: u32, bytes: &[u8]) {
+        let mut done = 0usize;
+        while done < bytes.len() {
+            let (data, offset, chunk) = self.chunk_mut(addr, done, bytes.len());
+            data[offset..offset + chunk].copy_from_slice(&bytes[done..done + chunk]);
+            done += chunk;
+        }
+    }
+
+    fn chunk_mut(&mut self, addr: u32, done: usize, len: usize) -> (&mut Page, usize, usize) {
+        let (page, offset) = split(addr.wrapping_add(done as u32));
+        let chunk = (PAGE_SIZE as usize - offset).min(len - done);
+        let entry = self.pages.get_mut(&page).expect("page checked above");
+        if entry.code {
+            entry.code = false;
+            self.modified_code.push(page);
+        }
+        let data = entry.data.as_mut().expect("page checked above");
+        (data, offset, chunk)
+    }
+
+    fn release_code(&mut self, range: std::ops::RangeInclusive<u32>) {
+        for (page, entry) in self.pages.range_mut(range) {
+            if entry.code {
+                entry.code = false;
+                self.modified_code.push(*page);
+            }
+        }
+    }
+
+    fn is_committed_page(&self, page: u32) -> bool {
+        self.pages
+            .get(&page)
+            .is_some_and(|entry| entry.data.is_some())
+    }
+
+    // An `access` of None only requires the pages to be committed.
+    fn first_denied(&self, addr: u32, len: usize, access: Option<AccessKind>) -> Option<u32> {
+        if len == 0 {
+            return None;
+        }
+        let end = addr as u64 + len as u64;
+        if end > 1u64 << 32 {
+            // Accesses that wrap past the top of the address space fault at address 0.
+            return Some(0);
+        }
+        let mut page = addr >> PAGE_SHIFT;
+        let last = ((end - 1) >> PAGE_SHIFT) as u32;
+        loop {
+            let allowed = match (self.pages.get(&page), access) {
+                (Some(entry), _) if entry.data.is_none() => false,
+                (Some(entry), Some(access)) => protection_allows(entry.protect, access),
+                (Some(_), None) => true,
+                (None, _) => false,
+            };
+            if !allowed {
+                return Some((page << PAGE_SHIFT).max(addr));
+            }
+            if page == last {
+                return None;
+            }
+            page += 1;
+        }
+    }
+}
+
+fn zero_page() -> Page {
+    Box::new([0u8; PAGE_SIZE as usize])
+}
+
+fn split(addr: u32) -> (u32, usize) {
+    (addr >> PAGE_SHIFT, (addr & (PAGE_SIZE - 1)) as usize)
+}
+
+fn page_range(addr: u32, size: u32) -> Option<std::ops::RangeInclusive<u32>> {
+    if size == 0 {
+        return None;
+    }
+    let last = (addr as u64 + size as u64 - 1).min(u32::MAX as u64) as u32;
+    Some((addr >> PAGE_SHIFT)..=(last >> PAGE_SHIFT))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn map_anywhere_and_cross_page_access() {
+        let mut memory = GuestMemory::new();
+        memory.map(0x0000_1000, 0x2000);
+        memory.map(0xFFFF_F000, 0x1000);
+        assert!(memory.write(0x1FFE, &[1, 2, 3, 4]).is_ok());
+        let mut buf = [0u8; 4];
+        memory.read(0x1FFE, &mut buf).unwrap();
+        assert_eq!(buf, [1, 2, 3, 4]);
+        assert!(memory.write(0xFFFF_FFFC, &[9; 4]).is_ok());
+        assert_eq!(memory.read(0xFFFF_FFFE, &mut buf), Err(0));
+        assert_eq!(memory.mapped_size(), 0x3000);
+    }
+
+    #[test]
+    fn partial_writes_fault_without_side_effects() {
+        let mut memory = GuestMemory::new();
+        memory.map(0x4000, 0x1000);
+        assert_eq!(memory.write(0x4FFE, &[0xAA; 4]), Err(0x5000));
+        let mut buf = [0u8; 2];
+        memory.read(0x4FFE, &mut buf).unwrap();
+        assert_eq!(buf, [0, 0]);
+        assert_eq!(memory.read(0x3000, &mut buf), Err(0x3000));
+    }
+
+    #[test]
+    fn find_free_skips_mapped_ranges() {
+        let mut memory = GuestMemory::new();
+        memory.map(0x1000_0000, 0x1_0000);
+        memory.reserve(0x1001_0000, 0x1000);
+        assert_eq!(
+            memory.find_free(0x1000_0000, 0x2000, 0x1_0000),
+            Some(0x1002_0000)
+        );
+        assert!(memory.is_range_free(0x1002_0000, 0x1_0000));
+        memory.unmap(0x1000_0000, 0x1_0000);
+        assert_eq!(
+            memory.find_free(0x1000_0000, 0x1_0000, 0x1_0000),
+            Some(0x1000_0000)
+        );
+        assert!(!memory.is_mapped(0x1000_0000));
+    }
+
+    #[test]
+    fn protections_gate_each_access_kind() {
+        let mut memory = GuestMemory::new();
+        memory.map(0x1000, 0x3000);
+        assert_eq!(
+            memory.protect(0x1000, 0x1000, PAGE_EXECUTE_READ),
+            Ok(PAGE_READWRITE)
+        );
+        memory.protect(0x2000, 0x1000, PAGE_NOACCESS).unwrap();
+        assert_eq!(memory.write(0x1000, &[1]), Err(0x1000));
+        assert!(memory.check(0x1000, 1, AccessKind::Execute).is_ok());
+        assert_eq!(memory.check(0x3000, 1, AccessKind::Execute), Err(0x3000));
+        let mut buf = [0u8; 8];
+        assert_eq!(memory.read(0x1FFC, &mut buf), Err(0x2000));
+        assert!(memory.write_unchecked(0x1000, &[0xC3]).is_ok());
+        memory.read(0x1000, &mut buf[..1]).unwrap();
+        assert_eq!(buf[0], 0xC3);
+    }
+
+    #[test]
+    fn reserved_pages_fault_until_committed() {
+        let mut memory = GuestMemory::new();
+        memory.reserve(0x10_0000, 0x2000);
+        assert!(!memory.is_mapped(0x10_0000));
+        assert_eq!(memory.write(0x10_0000, &[1]), Err(0x10_0000));
+        memory.commit(0x10_1000, 0x1000, PAGE_READWRITE).unwrap();
+        assert!(memory.write(0x10_1000, &[1]).is_ok());
+        assert_eq!(memory.run_length(0x10_0000, u32::MAX), 0x1000);
+        memory.decommit(0x10_1000, 0x1000);
+        assert_eq!(
+            memory.page_info(0x10_1000),
+            Some(PageInfo {
+                protect: PAGE_NOACCESS,
+                committed: false,
+            })
+        );
+        assert_eq!(
+            memory.commit(0x10_2000, 0x1000, PAGE_READWRITE),
+            Err(0x10_2000)
+        );
+    }
+
+    #[test]
+    fn watched_code_pages_report_changes_once() {
+        let mut memory = GuestMemory::new();
+        memory.map(0x1000, 0x3000);
+        memory.watch_code(0x1FFE, 4);
+        memory.write(0x3000, &[1]).unwrap();
+        assert!(memory.take_modified_code().is_empty());
+        memory.write(0x2000, &[1]).unwrap();
+        memory.write(0x2001, &[1]).unwrap();
+        assert_eq!(memory.take_modified_code(), vec![2]);
+        memory.protect(0x1000, 1, PAGE_EXECUTE_READ).unwrap();
+        assert_eq!(memory.take_modified_code(), vec![1]);
+        assert!(memory.take_modified_code().is_empty());
+        memory.watch_code(0x3000, 1);
+        memory.unmap(0x3000, 0x1000);
+        assert_eq!(memory.take_modified_code(), vec![3]);
+    }
+}
@@ -1,19 +1,65 @@
 //! VM configuration and core types.
 
 mod config;
+mod coverage;
+mod debugger;
 mod error;
+mod exceptions;
+mod flags;
+mod fpu;
+mod heap;
+mod heap_debug;
 mod host;
+mod memory;
+mod modules;
 mod registers;
+mod sse;
 mod state;
+mod sync;
+mod threads;
+mod trace;
 mod types;
+mod vfs;
 
 pub mod windows;
 
 pub use config::*;
+pub use coverage::{Coverage, ModuleCoverage};
+pub use debugger::{DebugAction, DebugHook, Flag, Register, StopReason, WatchHit, WatchKind};
 pub use error::VmError;
+pub use heap_debug::{AllocationSite, HeapApi, HeapIssue, HeapIssueKind, HeapLeak, HeapReport};
 pub use host::{host_create_thread, host_message_box_a, host_printf};
+pub use memory::AccessKind;
+pub use modules::LoadedModule;
 pub use state::{HostCall, Vm};
+pub use trace::{
+    parse_trace, read_trace, HostArg, MemoryWrite, TraceBuffer, TraceFormat, TraceOptions,
+    TraceRecord, TraceSink, TraceWriter,
+};
 pub use types::{ComOutParam, ExecuteOptions, Value};
+pub use vfs::{
+    FsMetadata, FsOpenOptions, FsTimes, GuestFile, GuestFs, HostFs, MemoryFs, OverlayFs,
+};
 
+pub(crate) use coverage::CoverageCollector;
+pub(crate) use debugger::Debugger;
+pub(crate) use exceptions::*;
+pub(crate) use flags::{FlagOp, Flags};
+pub(crate) use fpu::*;
+pub(crate) use heap::{Heap, HEAP_ALIGN, PROCESS_HEAP};
+pub(crate) use heap_debug::{
+    DebugBlock, HeapDebug, CANARY, FREED_FILL, MAX_STACK_FRAMES, RED_ZONE,
+};
+pub(crate) use memory::*;
+pub(crate) use modules::{module_key, ModuleTable};
 pub(crate) use registers::*;
-pub(crate) use state::{FileHandle, Flags, HostFunction, OsState, PendingThread, Registers};
+pub(crate) use sse::*;
+pub(crate) use state::{
+    CrtFd, CrtIo, CrtTarget, FileHandle, FindEntry, HostFunction, OsState, Registers, VirtualRegion,
+};
+pub(crate) use sync::{NameError, SyncObject, SyncObjects};
+pub(crate) use threads::{
+    GuestThread, ThreadContext, ThreadState, Threads, Wait, WaitKind, INFINITE, MAIN_THREAD_ID,
+    STILL_ACTIVE, WAIT_ABANDONED_0, WAIT_FAILED, WAIT_OBJECT_0, WAIT_TIMEOUT,
+};
+pub(crate) use trace::{coalesce_writes, Replay, Tracer};
@@ -0,0 +1,241 @@
+//! Loaded module table shared by the loader and the kernel32 module APIs.
+
+use crate::pe::ExportSymbol;
+
+// Host-implemented DLLs get synthetic handles that never overlap guest images.
+const HOST_HANDLE_TOP: u32 = 0x7FF0_0000;
+const HOST_HANDLE_STRIDE: u32 = 0x0001_0000;
+
+/// A PE image (or host stub DLL) registered with the VM loader.
+#[derive(Debug, Clone)]
+pub struct LoadedModule {
+    name: String,
+    path: String,
+    base: u32,
+    size: u32,
+    entry_point: u32,
+    exports: Vec<ExportSymbol>,
+    load_count: u32,
+    pinned: bool,
+    dll: bool,
+    host: bool,
+}
+
+impl LoadedModule {
+    pub(crate) fn guest(
+        path: &str,
+        base: u32,
+        size: u32,
+        entry_point: u32,
+        exports: Vec<ExportSymbol>,
+        dll: bool,
+    ) -> Self {
+        Self {
+            name: module_key(path),
+            path: path.to_string(),
+            base,
+            size,
+            entry_point,
+            exports,
+            load_count: 1,
+            pinned: false,
+            dll,
+            host: false,
+        }
+    }
+
+    /// Lowercase file name used for lookups (e.g. `helper.dll`).
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Guest path the module was loaded from.
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// Module handle (`HMODULE`), which is the load base for guest images.
+    pub fn base(&self) -> u32 {
+        self.base
+    }
+
+    pub fn size(&self) -> u32 {
+        self.size
+    }
+
+    /// Absolute entry point address, or 0 when the image has none.
+    pub fn entry_point(&self) -> u32 {
+        self.entry_point
+    }
+
+    pub fn exports(&self) -> &[ExportSymbol] {
+        &self.exports
+    }
+
+    /// True when the image has `IMAGE_FILE_DLL` set, so its entry point is `DllMain`.
+    pub fn is_dll(&self) -> bool {
+        self.dll
+    }
+
+    /// True for DLLs that are implemented by host stubs rather than mapped images.
+    pub fn is_host(&self) -> bool {
+        self.host
+    }
+
+    pub fn load_count(&self) -> u32 {
+        self.load_count
+    }
+
+    pub(crate) fn contains(&self, addr: u32) -> bool {
+        !self.host && addr >= self.base && addr - self.base < self.size
+    }
+
+    pub(crate) fn set_path(&mut self, path: &str) {
+        self.name = module_key(path);
+        self.path = path.to_string();
+    }
+
+    pub(crate) fn pin(&mut self) {
+        self.pinned = true;
+    }
+
+    pub(crate) fn add_ref(&mut self) {
+        self.load_count = self.load_count.saturating_add(1);
+    }
+
+    // Returns true when the module should be unloaded.
+    pub(crate) fn release(&mut self) -> bool {
+        if self.pinned || self.host {
+            return false;
+        }
+        self.load_count = self.load_count.saturating_sub(1);
+        self.load_count == 0
+    }
+
+    pub(crate) fn export(&self, name: &str) -> Option<&ExportSymbol> {
+        if let Some(ordinal) = name.strip_prefix('#') {
+            let ordinal = ordinal.parse::<u16>().ok()?;
+            return self.export_by_ordinal(ordinal);
+        }
+        self.exports
+            .iter()
+            .find(|symbol| symbol.name.as_deref() == Some(name))
+    }
+
+    pub(crate) fn export_by_ordinal(&self, ordinal: u16) -> Option<&ExportSymbol> {
+        self.exports.iter().find(|symbol| symbol.ordinal == ordinal)
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+pub(crate) struct ModuleTable {
+    modules: Vec<LoadedModule>,
+    host_count: u32,
+}
+
+impl ModuleTable {
+    pub(crate) fn clear(&mut self) {
+        self.modules.clear();
+        self.host_count = 0;
+    }
+
+    pub(crate) fn iter(&self) -> impl Iterator<Item = &LoadedModule> {
+        self.modules.iter()
+    }
+
+    pub(crate) fn insert(&mut self, module: LoadedModule) {
+        self.modules.retain(|entry| entry.base != module.base);
+        self.modules.push(module);
+    }
+
+    pub(crate) fn insert_host(&mut self, name: &str) -> u32 {
+        if let Some(existing) = self.by_name(name) {
+            return existing.base;
+        }
+        self.host_count += 1;
+        let base = HOST_HANDLE_TOP - self.host_count * HOST_HANDLE_STRIDE;
+        let key = module_key(name);
+        let path = format!("C:\\Windows\\System32\\{key}");
+        self.modules.push(LoadedModule {
+            name: key,
+            path,
+            base,
+            size: 0,
+            entry_point: 0,
+            exports: Vec::new(),
+            load_count: 1,
+            pinned: true,
+            dll: true,
+            host: true,
+        });
+        base
+    }
+
+    pub(crate) fn remove(&mut self, base: u32) -> Option<LoadedModule> {
+        let index = self.modules.iter().position(|entry| entry.base == base)?;
+        Some(self.modules.remove(index))
+    }
+
+    pub(crate) fn by_handle(&self, base: u32) -> Option<&LoadedModule> {
+        self.modules.iter().find(|entry| entry.base == base)
+    }
+
+    pub(crate) fn by_handle_mut(&mut self, base: u32) -> Option<&mut LoadedModule> {
+        self.modules.iter_mut().find(|entry| entry.base == base)
+    }
+
+    pub(crate) fn by_name(&self, name: &str) -> Option<&LoadedModule> {
+        let key = module_key(name);
+        self.modules.iter().find(|entry| entry.name == key)
+    }
+
+    pub(crate) fn by_addr(&self, addr: u32) -> Option<&LoadedModule> {
+        self.modules.iter().find(|entry| entry.contains(addr))
+    }
+}
+
+/// Normalize a module name or path to the lowercase file name used as the table key.
+pub(crate) fn module_key(name: &str) -> String {
+    let file = name.rsplit(['\\', '/']).next().unwrap_or(name);
+    let mut key = file.trim().to_ascii_lowercase();
+    if let Some(stripped) = key.strip_suffix('.') {
+        // A trailing dot means "no extension" to the Windows loader.
+        key = stripped.to_string();
+    } else if !key.contains('.') {
+        key.push_str(".dll");
+    }
+    key
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn module_key_normalizes_paths_and_extensions() {
+        assert_eq!(module_key("C:\\App\\Helper.DLL"), "helper.dll");
+        assert_eq!(module_key("kernel32"), "kernel32.dll");
+        assert_eq!(module_key("/opt/app/plugin.ocx"), "plugin.ocx");
+        assert_eq!(module_key("noext."), "noext");
+    }
+
+    #[test]
+    fn host_handles_are_stable_and_distinct() {
+        let mut table = ModuleTable::default();
+        let kernel32 = table.insert_host("KERNEL32.dll");
+        let user32 = table.insert_host("user32");
+        assert_ne!(kernel32, user32);
+        assert_eq!(table.insert_host("kernel32.dll"), kernel32);
+        assert!(table.by_handle(user32).unwrap().is_host());
+    }
+
+    #[test]
+    fn release_unloads_only_unpinned_modules() {
+        let mut module = LoadedModule::guest("C:\\a.dll", 0x1000_0000, 0x1000, 0, Vec::new(), true);
+        module.add_ref();
+        assert!(!module.release());
+        assert!(module.release());
+        module.pin();
+        assert!(!module.release());
+    }
+}
@@ -98,3 +98,8 @@ pub(crate) const REG_AH: u8 = Reg8::Ah as u8;
 pub(crate) const REG_CH: u8 = Reg8::Ch as u8;
 pub(crate) const REG_DH: u8 = Reg8::Dh as u8;
 pub(crate) const REG_BH: u8 = Reg8::Bh as u8;
+
+// User-mode selectors of 32-bit Windows.
+pub(crate) const USER_CODE_SELECTOR: u32 = 0x1B;
+pub(crate) const USER_DATA_SELECTOR: u32 = 0x23;
+pub(crate) const TEB_SELECTOR: u32 = 0x3B;
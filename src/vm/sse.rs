@@ -0,0 +1,400 @@
+//! SSE control and status register.
+//!
+//! MXCSR shares the exception flag layout of the x87 status word; the masks sit
+//! seven bits higher. Unlike the x87, SIMD exceptions are precise: an unmasked one
+//! leaves the destination untouched and faults on the instruction itself.
+
+use super::{Rounding, Vm, VmError};
+
+pub(crate) const STATUS_FLOAT_MULTIPLE_FAULTS: u32 = 0xC000_02B4;
+pub(crate) const STATUS_FLOAT_MULTIPLE_TRAPS: u32 = 0xC000_02B5;
+
+const MXCSR_FLAGS: u32 = 0x3F;
+const MXCSR_DAZ: u32 = 1 << 6;
+const MXCSR_MASK_SHIFT: u32 = 7;
+const MXCSR_RC_SHIFT: u32 = 13;
+const MXCSR_FTZ: u32 = 1 << 15;
+/// Bits LDMXCSR may set; anything else is a general-protection fault.
+const MXCSR_WRITABLE: u32 = 0xFFFF;
+// Invalid operation, denormal and divide-by-zero are detected before computing.
+const PRE_COMPUTATION: u32 = 0x07;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Mxcsr(u32);
+
+impl Default for Mxcsr {
+    /// All exceptions masked, round to nearest.
+    fn default() -> Self {
+        Self(0x1F80)
+    }
+}
+
+impl Mxcsr {
+    pub(crate) fn bits(self) -> u32 {
+        self.0
+    }
+
+    pub(crate) fn rounding(self) -> Rounding {
+        match (self.0 >> MXCSR_RC_SHIFT) & 3 {
+            0 => Rounding::Nearest,
+            1 => Rounding::Down,
+            2 => Rounding::Up,
+            _ => Rounding::Zero,
+        }
+    }
+
+    /// Denormal source operands are read as zero.
+    pub(crate) fn denormals_are_zero(self) -> bool {
+        self.0 & MXCSR_DAZ != 0
+    }
+
+    /// Underflowing results become zero when underflow is masked.
+    pub(crate) fn flush_to_zero(self) -> bool {
+        self.0 & MXCSR_FTZ != 0
+    }
+
+    pub(crate) fn masked(self, flags: u32) -> bool {
+        self.unmasked(flags) == 0
+    }
+
+    fn unmasked(self, flags: u32) -> u32 {
+        flags & MXCSR_FLAGS & !(self.0 >> MXCSR_MASK_SHIFT)
+    }
+}
+
+impl Vm {
+    pub(crate) fn mxcsr(&self) -> Mxcsr {
+        self.mxcsr
+    }
+
+    pub fn mxcsr_bits(&self) -> u32 {
+        self.mxcsr.bits()
+    }
+
+    /// Replace MXCSR; setting a reserved bit is rejected.
+    pub fn set_mxcsr_bits(&mut self, value: u32) -> Result<(), VmError> {
+        if value & !MXCSR_WRITABLE != 0 {
+            return Err(VmError::InvalidConfig("reserved MXCSR bits"));
+        }
+        self.mxcsr = Mxcsr(value);
+        Ok(())
+    }
+
+    /// LDMXCSR; reserved bits raise a general-protection fault at `address`.
+    pub(crate) fn load_mxcsr(&mut self, value: u32, address: u32) -> Result<(), VmError> {
+        if value & !MXCSR_WRITABLE != 0 {
+            return Err(VmError::Exception {
+                code: super::STATUS_ACCESS_VIOLATION,
+                address,
+            });
+        }
+        self.mxcsr = Mxcsr(value);
+        Ok(())
+    }
+
+    /// Record the exceptions a SIMD instruction raised. When any is unmasked the
+    /// caller must not write its result: the fault is returned instead.
+    pub(crate) fn simd_raise(&mut self, flags: u32, address: u32) -> Result<(), VmError> {
+        let flags = flags & MXCSR_FLAGS;
+        self.mxcsr.0 |= flags;
+        let unmasked = self.mxcsr.unmasked(flags);
+        if unmasked == 0 {
+            return Ok(());
+        }
+        let code = if unmasked & PRE_COMPUTATION != 0 {
+            STATUS_FLOAT_MULTIPLE_FAULTS
+        } else {
+            STATUS_FLOAT_MULTIPLE_TRAPS
+        };
+        Err(VmError::Exception { code, address })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::{Architecture, Flag, VmConfig};
+
+    const DATA: u32 = 0x5000;
+
+    fn create_test_vm() -> Vm {
+        let mut vm = Vm::new(VmConfig::new().architecture(Architecture::X86)).expect("vm");
+        vm.memory.map(0x1000, 0x10000);
+        vm.base = 0x1000;
+        vm.stack_top = 0x1000 + 0x10000 - 4;
+        vm.regs.esp = vm.stack_top;
+        vm.fs_base = 0x1000;
+        vm.write_u32(0x1000, 0xFFFF_FFFF).expect("teb");
+        vm
+    }
+
+    // Run `code` with EBX pointing at DATA; the code ends with `ret`.
+    fn run(vm: &mut Vm, code: &[u8]) {
+        let mut program = vec![0xBB]; // mov ebx, DATA
+        program.extend_from_slice(&DATA.to_le_bytes());
+        program.extend_from_slice(code);
+        vm.write_bytes(0x3000, &program).expect("code");
+        vm.execute(0x3000).expect("execute");
+    }
+
+    fn write_f64s(vm: &mut Vm, addr: u32, values: &[f64]) {
+        for (index, value) in values.iter().enumerate() {
+            let addr = addr + index as u32 * 8;
+            vm.write_bytes(addr, &value.to_le_bytes()).expect("data");
+        }
+    }
+
+    fn write_f32s(vm: &mut Vm, addr: u32, values: &[f32]) {
+        for (index, value) in values.iter().enumerate() {
+            let addr = addr + index as u32 * 4;
+            vm.write_bytes(addr, &value.to_le_bytes()).expect("data");
+        }
+    }
+
+    fn xmm_f64(vm: &Vm, index: u8) -> [f64; 2] {
+        let value = vm.xmm(index);
+        [0, 1].map(|lane| f64::from_le_bytes(value[lane * 8..lane * 8 + 8].try_into().unwrap()))
+    }
+
+    fn xmm_f32(vm: &Vm, index: u8) -> [f32; 4] {
+        let value = vm.xmm(index);
+        [0, 1, 2, 3]
+            .map(|lane| f32::from_le_bytes(value[lane * 4..lane * 4 + 4].try_into().unwrap()))
+    }
+
+    fn pattern(start: u8) -> [u8; 16] {
+        std::array::from_fn(|index| start + index as u8)
+    }
+
+    #[test]
+    fn unmasked_divide_by_zero_faults() {
+        let mut vm = Vm::new(VmConfig::new().architecture(Architecture::X86)).expect("vm");
+        assert!(vm.simd_raise(0x24, 0x1000).is_ok());
+        assert_eq!(vm.mxcsr().bits(), 0x1FA4);
+        vm.load_mxcsr(0x1F80 & !(0x04 << 7), 0x1000)
+            .expect("ldmxcsr");
+        match vm.simd_raise(0x04, 0x2000) {
+            Err(VmError::Exception { code, address }) => {
+                assert_eq!(code, STATUS_FLOAT_MULTIPLE_FAULTS);
+                assert_eq!(address, 0x2000);
+            }
+            other => panic!("unexpected {other:?}"),
+        }
+        assert!(vm.load_mxcsr(0x1_0000, 0x1000).is_err());
+    }
+
+    #[test]
+    fn scalar_and_packed_moves() {
+        let mut vm = create_test_vm();
+        write_f64s(&mut vm, DATA, &[1.5]);
+        write_f32s(&mut vm, DATA + 8, &[2.5]);
+        vm.write_bytes(DATA + 0x10, &pattern(0x10)).unwrap();
+        vm.write_bytes(DATA + 0x21, &pattern(0x20)).unwrap();
+        vm.write_bytes(DATA + 0x70, &[0xFF; 16]).unwrap();
+        run(
+            &mut vm,
+            &[
+                0x0F, 0x10, 0x43, 0x70, // movups xmm0, [ebx+0x70]
+                0x0F, 0x10, 0x4B, 0x70, // movups xmm1, [ebx+0x70]
+                0xF2, 0x0F, 0x10, 0x03, // movsd xmm0, [ebx]
+                0xF3, 0x0F, 0x10, 0x4B, 0x08, // movss xmm1, [ebx+8]
+                0x0F, 0x28, 0x53, 0x10, // movaps xmm2, [ebx+0x10]
+                0x0F, 0x10, 0x5B, 0x21, // movups xmm3, [ebx+0x21]
+                0x0F, 0x28, 0xE2, // movaps xmm4, xmm2
+                0xF2, 0x0F, 0x10, 0xE0, // movsd xmm4, xmm0
+                0x0F, 0x28, 0xEB, // movaps xmm5, xmm3
+                0xF3, 0x0F, 0x10, 0xE9, // movss xmm5, xmm1
+                0x0F, 0x11, 0x5B, 0x41, // movups [ebx+0x41], xmm3
+                0xF2, 0x0F, 0x11, 0x43, 0x60, // movsd [ebx+0x60], xmm0
+                0xF3, 0x0F, 0x11, 0x4B, 0x68, // movss [ebx+0x68], xmm1
+                0xC3, // ret
+            ],
+        );
+
+        // Loads from memory clear the rest of the register.
+        assert_eq!(xmm_f64(&vm, 0), [1.5, 0.0]);
+        assert_eq!(xmm_f32(&vm, 1), [2.5, 0.0, 0.0, 0.0]);
+        assert_eq!(vm.xmm(2), pattern(0x10));
+        assert_eq!(vm.xmm(3), pattern(0x20));
+        // Register to register scalar moves keep it.
+        let mut merged = pattern(0x10);
+        merged[..8].copy_from_slice(&1.5f64.to_le_bytes());
+        assert_eq!(vm.xmm(4), merged);
+        let mut merged = pattern(0x20);
+        merged[..4].copy_from_slice(&2.5f32.to_le_bytes());
+        assert_eq!(vm.xmm(5), merged);
+
+        assert_eq!(vm.read_bytes(DATA + 0x41, 16).unwrap(), pattern(0x20));
+        assert_eq!(vm.read_bytes(DATA + 0x60, 8).unwrap(), 1.5f64.to_le_bytes());
+        assert_eq!(vm.read_bytes(DATA + 0x68, 4).unwrap(), 2.5f32.to_le_bytes());
+        assert_eq!(vm.read_bytes(DATA + 0x6C, 4).unwrap(), [0; 4]);
+    }
+
+    #[test]
+    fn scalar_double_arithmetic() {
+        let mut vm = create_test_vm();
+        write_f64s(&mut vm, DATA, &[6.0, 4.0, 2.25, 0.0, 1.0, 7.0]);
+        run(
+            &mut vm,
+            &[
+                0xF2, 0x0F, 0x10, 0x03, // movsd xmm0, [ebx]
+                0xF2, 0x0F, 0x10, 0x4B, 0x08, // movsd xmm1, [ebx+8]
+                0x0F, 0x10, 0x53, 0x20, // movups xmm2, [ebx+0x20]
+                0xF2, 0x0F, 0x58, 0xD1, // addsd xmm2, xmm1
+                0x0F, 0x28, 0xD8, // movaps xmm3, xmm0
+                0xF2, 0x0F, 0x59, 0xD9, // mulsd xmm3, xmm1
+                0x0F, 0x28, 0xE0, // movaps xmm4, xmm0
+                0xF2, 0x0F, 0x5E, 0xE1, // divsd xmm4, xmm1
+                0xF2, 0x0F, 0x51, 0x6B, 0x10, // sqrtsd xmm5, [ebx+0x10]
+                0xF2, 0x0F, 0x5E, 0x43, 0x18, // divsd xmm0, [ebx+0x18]
+                0xC3, // ret
+            ],
+        );
+
+        assert_eq!(xmm_f64(&vm, 2), [5.0, 7.0]);
+        assert_eq!(xmm_f64(&vm, 3), [24.0, 0.0]);
+        assert_eq!(xmm_f64(&vm, 4), [1.5, 0.0]);
+        assert_eq!(xmm_f64(&vm, 5)[0], 1.5);
+        // Masked divide by zero gives infinity and sets ZE.
+        assert_eq!(xmm_f64(&vm, 0)[0], f64::INFINITY);
+        assert_ne!(vm.mxcsr_bits() & 0x4, 0);
+    }
+
+    #[test]
+    fn integer_conversions_and_indefinite_result() {
+        let mut vm = create_test_vm();
+        vm.write_u32(DATA, 123).unwrap();
+        write_f64s(&mut vm, DATA + 8, &[2.75, -2.75, 3.0e9, f64::NAN]);
+        run(
+            &mut vm,
+            &[
+                0xB8, 0xF9, 0xFF, 0xFF, 0xFF, // mov eax, -7
+                0xF2, 0x0F, 0x2A, 0xC0, // cvtsi2sd xmm0, eax
+                0xF2, 0x0F, 0x2A, 0x0B, // cvtsi2sd xmm1, [ebx]
+                0xF2, 0x0F, 0x2C, 0x4B, 0x08, // cvttsd2si ecx, [ebx+8]
+                0xF2, 0x0F, 0x2C, 0x53, 0x10, // cvttsd2si edx, [ebx+0x10]
+                0xF2, 0x0F, 0x2D, 0x43, 0x08, // cvtsd2si eax, [ebx+8]
+                0xF2, 0x0F, 0x2C, 0x73, 0x18, // cvttsd2si esi, [ebx+0x18]
+                0xF2, 0x0F, 0x2C, 0x7B, 0x20, // cvttsd2si edi, [ebx+0x20]
+                0xC3, // ret
+            ],
+        );
+
+        assert_eq!(xmm_f64(&vm, 0)[0], -7.0);
+        assert_eq!(xmm_f64(&vm, 1)[0], 123.0);
+        assert_eq!(vm.regs.ecx, 2);
+        assert_eq!(vm.regs.edx, -2i32 as u32);
+        assert_eq!(vm.regs.eax, 3);
+        // Out of range and NaN inputs give the integer indefinite and set IE.
+        assert_eq!(vm.regs.esi, 0x8000_0000);
+        assert_eq!(vm.regs.edi, 0x8000_0000);
+        assert_ne!(vm.mxcsr_bits() & 0x1, 0);
+    }
+
+    // ZF, PF and CF after comparing `a` with `b`; also whether IE was raised.
+    fn compare(ordered: bool, a: f64, b: f64) -> (bool, bool, bool, bool) {
+        let mut vm = create_test_vm();
+        write_f64s(&mut vm, DATA, &[a, b]);
+        let opcode = if ordered { 0x2F } else { 0x2E };
+        run(
+            &mut vm,
+            &[
+                0xF2, 0x0F, 0x10, 0x03, // movsd xmm0, [ebx]
+                0x66, 0x0F, opcode, 0x43, 0x08, // (u)comisd xmm0, [ebx+8]
+                0xC3, // ret
+            ],
+        );
+        (
+            vm.flag(Flag::Zero),
+            vm.flag(Flag::Parity),
+            vm.flag(Flag::Carry),
+            vm.mxcsr_bits() & 0x1 != 0,
+        )
+    }
+
+    #[test]
+    fn comisd_and_ucomisd_set_zf_pf_cf() {
+        for ordered in [true, false] {
+            assert_eq!(compare(ordered, 2.0, 1.0), (false, false, false, false));
+            assert_eq!(compare(ordered, 1.0, 2.0), (false, false, true, false));
+            assert_eq!(compare(ordered, 2.0, 2.0), (true, false, false, false));
+        }
+        // Unordered: COMISD signals on a quiet NaN, UCOMISD does not.
+        assert_eq!(compare(true, f64::NAN, 1.0), (true, true, true, true));
+        assert_eq!(compare(false, 1.0, f64::NAN), (true, true, true, false));
+    }
+
+    #[test]
+    fn integer_compare_mask_shuffle_and_unpack() {
+        let mut vm = create_test_vm();
+        let a = pattern(0);
+        let mut b = pattern(0);
+        b[3] = 0xAA;
+        b[9] = 0xBB;
+        vm.write_bytes(DATA, &a).unwrap();
+        vm.write_bytes(DATA + 0x10, &b).unwrap();
+        run(
+            &mut vm,
+            &[
+                0x66, 0x0F, 0x6F, 0x03, // movdqa xmm0, [ebx]
+                0x66, 0x0F, 0x6F, 0x4B, 0x10, // movdqa xmm1, [ebx+0x10]
+                0x66, 0x0F, 0x74, 0xC1, // pcmpeqb xmm0, xmm1
+                0x66, 0x0F, 0xD7, 0xC0, // pmovmskb eax, xmm0
+                0x66, 0x0F, 0x70, 0x13, 0x1B, // pshufd xmm2, [ebx], 0x1B
+                0x66, 0x0F, 0x6F, 0x1B, // movdqa xmm3, [ebx]
+                0x66, 0x0F, 0x60, 0x5B, 0x10, // punpcklbw xmm3, [ebx+0x10]
+                0x66, 0x0F, 0x6F, 0x23, // movdqa xmm4, [ebx]
+                0x66, 0x0F, 0x61, 0xE1, // punpcklwd xmm4, xmm1
+                0x66, 0x0F, 0x6F, 0x2B, // movdqa xmm5, [ebx]
+                0x66, 0x0F, 0x62, 0xE9, // punpckldq xmm5, xmm1
+                0x66, 0x0F, 0x6F, 0x33, // movdqa xmm6, [ebx]
+                0x66, 0x0F, 0x6C, 0xF1, // punpcklqdq xmm6, xmm1
+                0xC3, // ret
+            ],
+        );
+
+        let equal: [u8; 16] = std::array::from_fn(|i| if a[i] == b[i] { 0xFF } else { 0 });
+        assert_eq!(vm.xmm(0), equal);
+        assert_eq!(vm.regs.eax, 0xFFFF & !(1 << 3 | 1 << 9));
+        let reversed: [u8; 16] = std::array::from_fn(|i| a[(3 - i / 4) * 4 + i % 4]);
+        assert_eq!(vm.xmm(2), reversed);
+        for (index, width) in [(3, 1), (4, 2), (5, 4), (6, 8)] {
+            let unpacked: [u8; 16] = std::array::from_fn(|i| {
+                let (element, byte) = (i / width, i % width);
+                let source = if element % 2 == 0 { &a } else { &b };
+                source[element / 2 * width + byte]
+            });
+            assert_eq!(vm.xmm(index), unpacked, "unpack width {width}");
+        }
+    }
+
+    #[test]
+    fn mandatory_prefix_selects_the_form_of_a_shared_opcode() {
+        let mut vm = create_test_vm();
+        write_f32s(&mut vm, DATA, &[1.0, 2.0, 3.0, 4.0, 10.0, 20.0, 30.0, 40.0]);
+        write_f64s(&mut vm, DATA + 0x20, &[1.5, 2.5, 0.25, 0.5]);
+        run(
+            &mut vm,
+            &[
+                0x0F, 0x28, 0x03, // movaps xmm0, [ebx]
+                0x0F, 0x28, 0xC8, // movaps xmm1, xmm0
+                0x0F, 0x58, 0x43, 0x10, // addps xmm0, [ebx+0x10]
+                0xF3, 0x0F, 0x58, 0x4B, 0x10, // addss xmm1, [ebx+0x10]
+                0x66, 0x0F, 0x28, 0x53, 0x20, // movapd xmm2, [ebx+0x20]
+                0x66, 0x0F, 0x28, 0xDA, // movapd xmm3, xmm2
+                0x66, 0x0F, 0x28, 0xE2, // movapd xmm4, xmm2
+                0x66, 0x0F, 0x58, 0x53, 0x30, // addpd xmm2, [ebx+0x30]
+                0xF2, 0x0F, 0x58, 0x5B, 0x30, // addsd xmm3, [ebx+0x30]
+                0x66, 0xF2, 0x0F, 0x58, 0x63,
+                0x30, // addsd xmm4, [ebx+0x30] (F2 wins over 66)
+                0xC3, // ret
+            ],
+        );
+
+        assert_eq!(xmm_f32(&vm, 0), [11.0, 22.0, 33.0, 44.0]);
+        assert_eq!(xmm_f32(&vm, 1), [11.0, 2.0, 3.0, 4.0]);
+        assert_eq!(xmm_f64(&vm, 2), [1.75, 3.0]);
+        assert_eq!(xmm_f64(&vm, 3), [1.75, 2.5]);
+        assert_eq!(xmm_f64(&vm, 4), [1.75, 2.5]);
+    }
+}
@@ -1,10 +1,14 @@
-use std::collections::{BTreeMap, HashMap};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
 use std::sync::{Arc, Mutex};
 
-use crate::architecture::intel::x86::X86Executor;
+use crate::architecture::intel::x86::{DecodeCache, X86Executor};
 use crate::pe::ResourceDirectory;
 
-use super::{windows, ComOutParam, MessageBoxMode, VmConfig, VmError};
+use super::{
+    windows, ComOutParam, CoverageCollector, Debugger, Flags, FpuState, FsMetadata, GuestFile,
+    GuestMemory, Heap, HeapDebug, MessageBoxMode, ModuleTable, Mxcsr, SyncObjects, Threads, Tracer,
+    VectoredHandlers, VmConfig,
+};
 
 // OS-specific state stored in the VM without exposing platform details.
 pub(crate) enum OsState {
@@ -29,63 +33,36 @@ pub struct Registers {
     pub eip: u32,
 }
 
-#[derive(Debug, Default, Clone, Copy)]
-pub(crate) struct Flags {
-    pub(crate) cf: bool,
-    pub(crate) zf: bool,
-    pub(crate) sf: bool,
-    pub(crate) of: bool,
-}
-
-#[derive(Debug, Default, Clone)]
-pub(crate) struct FpuState {
-    pub(crate) stack: [f64; 8],
-    pub(crate) valid: [bool; 8],
-    pub(crate) top: u8,
-    pub(crate) control_word: u16,
-    #[allow(dead_code)]
-    pub(crate) status_word: u16,
-    #[allow(dead_code)]
-    pub(crate) tag_word: u16,
-}
-
-impl FpuState {
-    fn st_index(&self, index: usize) -> usize {
-        (self.top as usize + index) & 7
-    }
-
-    fn top_index(&self) -> usize {
-        self.top as usize
-    }
-}
-
 #[derive(Clone, Copy)]
 pub(crate) struct HostFunction {
     pub(crate) func: HostCall,
     pub(crate) stack_cleanup: u32,
 }
 
-#[derive(Debug, Clone)]
-pub(crate) struct PendingThread {
-    pub(crate) entry: u32,
-    pub(crate) param: u32,
+// A VirtualAlloc reservation and the protection it was allocated with.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct VirtualRegion {
+    pub(crate) size: u32,
+    pub(crate) protect: u32,
 }
 
 pub struct Vm {
     pub(super) config: VmConfig,
     pub(super) os_state: OsState,
     pub(super) base: u32,
-    pub(super) memory: Vec<u8>,
+    pub(super) memory: GuestMemory,
     pub(super) regs: Registers,
-    // Minimal SSE state for XMM register operations.
     pub(super) xmm: [[u8; 16]; 8],
+    pub(super) mxcsr: Mxcsr,
     pub(super) flags: Flags,
     pub(super) stack_top: u32,
     pub(super) stack_depth: u32,
     pub(super) heap_start: usize,
     pub(super) heap_end: usize,
-    pub(super) heap_cursor: usize,
-    pub(super) heap_allocs: HashMap<u32, usize>,
+    pub(super) heaps: BTreeMap<u32, Heap>,
+    pub(super) heap_debug: Option<HeapDebug>,
+    pub(super) virtual_allocs: BTreeMap<u32, VirtualRegion>,
+    pub(super) nx_enabled: bool,
     pub(super) fs_base: u32,
     pub(super) gs_base: u32,
     pub(super) env: BTreeMap<String, String>,
@@ -100,10 +77,14 @@ pub struct Vm {
     pub(super) registry_next_handle: u32,
     pub(super) file_handles: HashMap<u32, FileHandle>,
     pub(super) file_next_handle: u32,
-    pub(super) virtual_files: HashMap<String, Vec<u8>>,
+    pub(super) find_handles: HashMap<u32, VecDeque<FindEntry>>,
+    pub(super) crt_io: CrtIo,
+    pub(super) current_dir: String,
+    pub(super) drive_dirs: BTreeMap<u8, String>,
     pub(super) tls_values: HashMap<u32, u32>,
     pub(super) tls_next_index: u32,
     pub(super) unhandled_exception_filter: u32,
+    pub(super) vectored_handlers: VectoredHandlers,
     pub(super) message_box_mode: MessageBoxMode,
     pub(super) onexit_tables: BTreeMap<u32, Vec<u32>>,
     pub(super) default_onexit_table: u32,
@@ -112,89 +93,73 @@ pub struct Vm {
     pub(super) imports_by_ordinal: HashMap<String, HostFunction>,
     pub(super) imports_by_iat: HashMap<u32, HostFunction>,
     pub(super) imports_by_iat_name: HashMap<u32, String>,
+    pub(super) host_modules: HashSet<String>,
+    pub(super) modules: ModuleTable,
     pub(super) dynamic_imports: HashMap<String, u32>,
     pub(super) dynamic_import_next: u32,
-    pub(super) pending_threads: Vec<PendingThread>,
-    pub(super) next_thread_handle: u32,
+    pub(super) threads: Threads,
+    pub(super) sync: SyncObjects,
     pub(super) stdout: Arc<Mutex<Vec<u8>>>,
     pub(super) executor: X86Executor,
+    pub(super) decode_cache: DecodeCache,
     pub(super) fpu: FpuState,
+    pub(super) debugger: Debugger,
+    pub(super) tracer: Tracer,
+    pub(super) coverage: Option<CoverageCollector>,
 }
 
-#[derive(Debug, Clone)]
 pub(crate) struct FileHandle {
-    pub(crate) path: String,
-    pub(crate) cursor: usize,
+    pub(crate) file: Box<dyn GuestFile>,
     pub(crate) readable: bool,
     pub(crate) writable: bool,
 }
 
-impl Vm {
-    pub(crate) fn fpu_push(&mut self, value: f64) -> Result<(), VmError> {
-        let new_top = (self.fpu.top.wrapping_sub(1)) & 7;
-        let idx = new_top as usize;
-        if self.fpu.valid[idx] {
-            return Err(VmError::FpuStackOverflow);
-        }
-        self.fpu.top = new_top;
-        self.fpu.stack[idx] = value;
-        self.fpu.valid[idx] = true;
-        Ok(())
-    }
-
-    pub(crate) fn fpu_pop(&mut self) -> Result<f64, VmError> {
-        let idx = self.fpu.top_index();
-        if !self.fpu.valid[idx] {
-            return Err(VmError::FpuStackUnderflow);
-        }
-        let value = self.fpu.stack[idx];
-        self.fpu.valid[idx] = false;
-        self.fpu.top = (self.fpu.top + 1) & 7;
-        Ok(value)
-    }
-
-    pub fn fpu_st(&self, index: usize) -> Result<f64, VmError> {
-        if index >= 8 {
-            return Err(VmError::UnsupportedInstruction(0));
-        }
-        let idx = self.fpu.st_index(index);
-        if !self.fpu.valid[idx] {
-            return Err(VmError::FpuStackUnderflow);
-        }
-        Ok(self.fpu.stack[idx])
-    }
-
-    pub(crate) fn fpu_set_st(&mut self, index: usize, value: f64) -> Result<(), VmError> {
-        if index >= 8 {
-            return Err(VmError::UnsupportedInstruction(0));
-        }
-        let idx = self.fpu.st_index(index);
-        if !self.fpu.valid[idx] {
-            return Err(VmError::FpuStackUnderflow);
-        }
-        self.fpu.stack[idx] = value;
-        Ok(())
-    }
-
-    pub(crate) fn fpu_control(&self) -> u16 {
-        self.fpu.control_word
-    }
-
-    pub(crate) fn fpu_set_control(&mut self, value: u16) {
-        self.fpu.control_word = value;
-    }
+/// The C runtime's low-level I/O state: the `_open` descriptor table and the
+/// guest `FILE` structures handed out by `fopen` and `__iob_func`.
+pub(crate) struct CrtIo {
+    pub(crate) fds: BTreeMap<i32, CrtFd>,
+    /// Guest address of the `_iob` array, zero until first used.
+    pub(crate) iob: u32,
+    /// Guest `FILE`s allocated once the `_iob` array was full.
+    pub(crate) streams: Vec<u32>,
+    /// Guest cells holding `errno` and `_doserrno`, zero until first used.
+    pub(crate) errno: u32,
+    pub(crate) umask: u32,
+    /// The key pushed back by `_ungetch`.
+    pub(crate) ungetch: Option<u32>,
+    /// Sequence number of the next `tmpnam` name.
+    pub(crate) tmp_next: u32,
+    /// The static buffer `tmpnam` returns when given none, zero until used.
+    pub(crate) tmpnam: u32,
+}
 
-    #[allow(dead_code)]
-    pub(crate) fn fpu_status(&self) -> u16 {
-        self.fpu.status_word
-    }
+/// A CRT file descriptor.
+#[derive(Clone)]
+pub(crate) struct CrtFd {
+    pub(crate) target: CrtTarget,
+    /// Text mode: CRLF pairs read as LF, LF writes as CRLF and Ctrl+Z ends input.
+    pub(crate) text: bool,
+    pub(crate) append: bool,
+    /// A text-mode read hit Ctrl+Z; reads return nothing until the next seek.
+    pub(crate) eof: bool,
+    /// Removed once the last descriptor on the file closes (`_O_TEMPORARY`).
+    pub(crate) delete_on_close: Option<String>,
+}
 
-    #[allow(dead_code)]
-    pub(crate) fn fpu_set_status(&mut self, value: u16) {
-        self.fpu.status_word = value;
-    }
+#[derive(Clone)]
+pub(crate) enum CrtTarget {
+    /// A kernel32 file handle.
+    File(u32),
+    /// The console: output goes to the VM's stdout, input is always at its end.
+    Console,
+    /// One end of a `_pipe`; both ends share the queued bytes.
+    Pipe(Arc<Mutex<VecDeque<u8>>>),
+}
 
-    pub(crate) fn fpu_reset(&mut self) {
-        self.fpu = FpuState::default();
-    }
+/// A directory entry returned by a `FindFirstFile` search.
+pub(crate) struct FindEntry {
+    pub(crate) name: String,
+    /// The 8.3 alias, empty when `name` already fits 8.3.
+    pub(crate) short_name: String,
+    pub(crate) metadata: FsMetadata,
 }
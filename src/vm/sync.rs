@@ -0,0 +1,366 @@
+//! Kernel synchronization objects: events, mutexes, semaphores and the user-mode locks
+//! (critical sections, SRW locks, condition variables) that are keyed by guest address.
+//!
+//! Objects only track signaled state and ownership; blocking and waking threads is
+//! done by the scheduler, which asks [`SyncObjects::is_signaled`] and then
+//! [`SyncObjects::acquire`] on behalf of each waiter.
+
+use std::collections::{BTreeSet, HashMap};
+
+const FIRST_SYNC_HANDLE: u32 = 0x5000_0000;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum SyncObject {
+    Event {
+        manual_reset: bool,
+        signaled: bool,
+    },
+    /// Also backs critical sections, whose guest struct lives at `critical_section`.
+    Mutex {
+        owner: Option<u32>,
+        recursion: u32,
+        abandoned: bool,
+        critical_section: Option<u32>,
+    },
+    Semaphore {
+        count: u32,
+        maximum: u32,
+    },
+    SrwLock {
+        exclusive: Option<u32>,
+        shared: u32,
+    },
+    /// Threads woken by WakeConditionVariable that have not yet resumed.
+    ConditionVariable {
+        notified: BTreeSet<u32>,
+    },
+    /// An INIT_ONCE: `owner` is running the initialization, `context` is what it stored.
+    InitOnce {
+        owner: Option<u32>,
+        done: bool,
+        context: u32,
+    },
+}
+
+impl SyncObject {
+    pub(crate) fn mutex(owner: Option<u32>) -> Self {
+        SyncObject::Mutex {
+            owner,
+            recursion: u32::from(owner.is_some()),
+            abandoned: false,
+            critical_section: None,
+        }
+    }
+
+    fn same_kind(&self, other: &SyncObject) -> bool {
+        std::mem::discriminant(self) == std::mem::discriminant(other)
+    }
+
+    /// Whether a wait by `thread` would be satisfied right now.
+    pub(crate) fn is_signaled(&self, thread: u32, shared: bool) -> bool {
+        match self {
+            SyncObject::Event { signaled, .. } => *signaled,
+            SyncObject::Mutex { owner, .. } => owner.is_none_or(|owner| owner == thread),
+            SyncObject::Semaphore { count, .. } => *count > 0,
+            SyncObject::SrwLock {
+                exclusive,
+                shared: readers,
+            } => exclusive.is_none() && (shared || *readers == 0),
+            SyncObject::ConditionVariable { notified } => notified.contains(&thread),
+            SyncObject::InitOnce { owner, .. } => owner.is_none(),
+        }
+    }
+
+    /// Apply the side effects of a satisfied wait. Returns true if a mutex was abandoned.
+    pub(crate) fn acquire(&mut self, thread: u32, shared: bool) -> bool {
+        match self {
+            SyncObject::Event {
+                manual_reset,
+                signaled,
+            } => {
+                if !*manual_reset {
+                    *signaled = false;
+                }
+                false
+            }
+            SyncObject::Mutex {
+                owner,
+                recursion,
+                abandoned,
+                ..
+            } => {
+                *owner = Some(thread);
+                *recursion += 1;
+                std::mem::take(abandoned)
+            }
+            SyncObject::Semaphore { count, .. } => {
+                *count = count.saturating_sub(1);
+                false
+            }
+            SyncObject::SrwLock {
+                exclusive,
+                shared: readers,
+            } => {
+                if shared {
+                    *readers += 1;
+                } else {
+                    *exclusive = Some(thread);
+                }
+                false
+            }
+            SyncObject::ConditionVariable { notified } => {
+                notified.remove(&thread);
+                false
+            }
+            SyncObject::InitOnce { owner, done, .. } => {
+                if !*done {
+                    *owner = Some(thread);
+                }
+                false
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Entry {
+    object: SyncObject,
+    name: Option<String>,
+    handles: u32,
+}
+
+/// Handle table for synchronization objects. Several handles may refer to one named object.
+#[derive(Debug, Clone)]
+pub(crate) struct SyncObjects {
+    objects: HashMap<u32, Entry>,
+    handles: HashMap<u32, u32>,
+    names: HashMap<String, u32>,
+    by_address: HashMap<u32, u32>,
+    next_handle: u32,
+    next_id: u32,
+}
+
+impl Default for SyncObjects {
+    fn default() -> Self {
+        Self {
+            objects: HashMap::new(),
+            handles: HashMap::new(),
+            names: HashMap::new(),
+            by_address: HashMap::new(),
+            next_handle: FIRST_SYNC_HANDLE,
+            next_id: 1,
+        }
+    }
+}
+
+/// Why a named create or open failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum NameError {
+    /// The name belongs to an object of another type.
+    WrongType,
+    NotFound,
+}
+
+impl SyncObjects {
+    /// Create an object, or open the existing one when `name` is already taken.
+    ///
+    /// Returns the new handle and whether the named object already existed.
+    pub(crate) fn create(
+        &mut self,
+        object: SyncObject,
+        name: Option<String>,
+    ) -> Result<(u32, bool), NameError> {
+        if let Some(id) = name.as_ref().and_then(|name| self.names.get(name)).copied() {
+            if !self.objects[&id].object.same_kind(&object) {
+                return Err(NameError::WrongType);
+            }
+            return Ok((self.add_handle(id), true));
+        }
+        let id = self.next_id;
+        self.next_id += 1;
+        if let Some(name) = &name {
+            self.names.insert(name.clone(), id);
+        }
+        self.objects.insert(
+            id,
+            Entry {
+                object,
+                name,
+                handles: 0,
+            },
+        );
+        Ok((self.add_handle(id), false))
+    }
+
+    /// Open a new handle to a named object of the same type as `kind`.
+    pub(crate) fn open(&mut self, name: &str, kind: &SyncObject) -> Result<u32, NameError> {
+        let id = *self.names.get(name).ok_or(NameError::NotFound)?;
+        if !self.objects[&id].object.same_kind(kind) {
+            return Err(NameError::WrongType);
+        }
+        Ok(self.add_handle(id))
+    }
+
+    /// Close a handle; the object goes away with its last handle.
+    pub(crate) fn close(&mut self, handle: u32) -> bool {
+        let Some(id) = self.handles.remove(&handle) else {
+            return false;
+        };
+        if let Some(entry) = self.objects.get_mut(&id) {
+            entry.handles -= 1;
+            if entry.handles == 0 {
+                if let Some(name) = &entry.name {
+                    self.names.remove(name);
+                }
+                self.objects.remove(&id);
+            }
+        }
+        true
+    }
+
+    pub(crate) fn get(&self, handle: u32) -> Option<&SyncObject> {
+        let id = self.handles.get(&handle)?;
+        self.objects.get(id).map(|entry| &entry.object)
+    }
+
+    pub(crate) fn get_mut(&mut self, handle: u32) -> Option<&mut SyncObject> {
+        let id = self.handles.get(&handle)?;
+        self.objects.get_mut(id).map(|entry| &mut entry.object)
+    }
+
+    /// Handle of the lock stored at a guest address, created on first use.
+    pub(crate) fn at_address(&mut self, addr: u32, make: impl FnOnce() -> SyncObject) -> u32 {
+        if let Some(&handle) = self.by_address.get(&addr) {
+            return handle;
+        }
+        let (handle, _) = self
+            .create(make(), None)
+            .expect("unnamed objects always create");
+        self.by_address.insert(addr, handle);
+        handle
+    }
+
+    /// Replace the lock at a guest address with a fresh object (Initialize*).
+    pub(crate) fn reset_address(&mut self, addr: u32, object: SyncObject) -> u32 {
+        self.remove_address(addr);
+        self.at_address(addr, || object)
+    }
+
+    pub(crate) fn remove_address(&mut self, addr: u32) {
+        if let Some(handle) = self.by_address.remove(&addr) {
+            self.close(handle);
+        }
+    }
+
+    /// Release every mutex still owned by a thread that exited.
+    pub(crate) fn abandon_owned_by(&mut self, thread: u32) {
+        for entry in self.objects.values_mut() {
+            if let SyncObject::Mutex {
+                owner,
+                recursion,
+                abandoned,
+                ..
+            } = &mut entry.object
+            {
+                if *owner == Some(thread) {
+                    *owner = None;
+                    *recursion = 0;
+                    *abandoned = true;
+                }
+            }
+        }
+    }
+
+    fn add_handle(&mut self, id: u32) -> u32 {
+        let handle = self.next_handle;
+        self.next_handle = self.next_handle.wrapping_add(4);
+        self.handles.insert(handle, id);
+        if let Some(entry) = self.objects.get_mut(&id) {
+            entry.handles += 1;
+        }
+        handle
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(manual_reset: bool) -> SyncObject {
+        SyncObject::Event {
+            manual_reset,
+            signaled: true,
+        }
+    }
+
+    #[test]
+    fn auto_reset_event_wakes_one_waiter() {
+        let mut auto = event(false);
+        assert!(auto.is_signaled(2, false));
+        auto.acquire(2, false);
+        assert!(!auto.is_signaled(3, false));
+
+        let mut manual = event(true);
+        manual.acquire(2, false);
+        assert!(manual.is_signaled(3, false));
+    }
+
+    #[test]
+    fn mutex_is_recursive_for_its_owner() {
+        let mut mutex = SyncObject::mutex(Some(1));
+        assert!(mutex.is_signaled(1, false));
+        assert!(!mutex.is_signaled(2, false));
+        mutex.acquire(1, false);
+        assert!(matches!(mutex, SyncObject::Mutex { recursion: 2, .. }));
+    }
+
+    #[test]
+    fn srw_lock_allows_many_readers_or_one_writer() {
+        let mut lock = SyncObject::SrwLock {
+            exclusive: None,
+            shared: 0,
+        };
+        lock.acquire(1, true);
+        assert!(lock.is_signaled(2, true));
+        assert!(!lock.is_signaled(2, false));
+        let mut lock = SyncObject::SrwLock {
+            exclusive: None,
+            shared: 0,
+        };
+        lock.acquire(1, false);
+        assert!(!lock.is_signaled(2, true));
+    }
+
+    #[test]
+    fn named_objects_share_state_until_last_close() {
+        let mut table = SyncObjects::default();
+        let (first, existed) = table.create(event(true), Some("ready".into())).unwrap();
+        assert!(!existed);
+        let (second, existed) = table.create(event(false), Some("ready".into())).unwrap();
+        assert!(existed);
+        assert_ne!(first, second);
+        assert_eq!(
+            table.create(SyncObject::mutex(None), Some("ready".into())),
+            Err(NameError::WrongType)
+        );
+        if let Some(SyncObject::Event { signaled, .. }) = table.get_mut(first) {
+            *signaled = false;
+        }
+        assert!(!table.get(second).unwrap().is_signaled(1, false));
+        assert!(table.close(first));
+        assert!(table.open("ready", &event(true)).is_ok());
+        table.close(second);
+        assert!(table.get(second).is_none());
+    }
+
+    #[test]
+    fn exiting_owner_abandons_mutex() {
+        let mut table = SyncObjects::default();
+        let (handle, _) = table.create(SyncObject::mutex(Some(2)), None).unwrap();
+        table.abandon_owned_by(2);
+        let mutex = table.get_mut(handle).unwrap();
+        assert!(mutex.is_signaled(3, false));
+        assert!(mutex.acquire(3, false));
+        assert!(!mutex.acquire(3, false));
+    }
+}
@@ -0,0 +1,424 @@
+//! Guest thread table and the state behind the cooperative scheduler.
+//!
+//! Only one guest thread runs at a time. The running thread's registers live in
+//! the `Vm` itself; every other thread keeps a saved [`ThreadContext`]. Time is
+//! virtual: it only moves forward when every thread is waiting on a timeout.
+
+use std::collections::{BTreeMap, HashMap};
+
+use super::{ExceptionState, Flags, FpuState, Mxcsr, Registers, VmError};
+
+pub(crate) const MAIN_THREAD_ID: u32 = 1;
+/// GetExitCodeThread value for a thread that has not exited.
+pub(crate) const STILL_ACTIVE: u32 = 259;
+pub(crate) const WAIT_OBJECT_0: u32 = 0;
+pub(crate) const WAIT_ABANDONED_0: u32 = 0x80;
+pub(crate) const WAIT_TIMEOUT: u32 = 0x102;
+pub(crate) const WAIT_FAILED: u32 = 0xFFFF_FFFF;
+pub(crate) const INFINITE: u32 = 0xFFFF_FFFF;
+
+const FIRST_THREAD_HANDLE: u32 = 0x6000_0000;
+const TSC_TICKS_PER_MS: u64 = 1_000_000;
+
+/// CPU and per-thread OS state saved while a thread is switched out.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct ThreadContext {
+    pub(crate) regs: Registers,
+    pub(crate) flags: Flags,
+    pub(crate) xmm: [[u8; 16]; 8],
+    pub(crate) mxcsr: Mxcsr,
+    pub(crate) fpu: FpuState,
+    pub(crate) stack_top: u32,
+    pub(crate) stack_depth: u32,
+    pub(crate) fs_base: u32,
+    pub(crate) last_error: u32,
+    pub(crate) tls_values: HashMap<u32, u32>,
+}
+
+/// What a blocked thread is waiting for and how its wait result is reported.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum WaitKind {
+    /// WaitFor*Object(s): any (or all) of the handles, reported as `WAIT_OBJECT_0 + index`.
+    Objects { wait_all: bool },
+    /// Acquire a single lock handle, then report `result`. A critical section re-entered
+    /// after a condition wait gets back the `recursion` count it had before.
+    Lock {
+        shared: bool,
+        result: u32,
+        recursion: Option<u32>,
+    },
+    /// SleepConditionVariable*: wait to be woken, then re-acquire `lock`.
+    Condition {
+        lock: u32,
+        shared: bool,
+        recursion: Option<u32>,
+    },
+    /// InitOnce*: wait for the thread running an initialization. Once it is done the
+    /// context goes to `context`; if it failed, a `claim` waiter takes it over and is told
+    /// through `pending`, and any other waiter reports FALSE.
+    InitOnce {
+        claim: bool,
+        pending: u32,
+        context: u32,
+    },
+}
+
+/// A blocking wait: satisfied by its handles as described by `kind`, or by the deadline.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Wait {
+    pub(crate) handles: Vec<u32>,
+    pub(crate) kind: WaitKind,
+    /// Virtual time in milliseconds; `None` waits forever.
+    pub(crate) deadline: Option<u64>,
+}
+
+impl Wait {
+    /// Wait for any of `handles`; no handles makes this a plain sleep.
+    pub(crate) fn any(handles: Vec<u32>, deadline: Option<u64>) -> Self {
+        Wait {
+            handles,
+            kind: WaitKind::Objects { wait_all: false },
+            deadline,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum ThreadState {
+    Ready,
+    Blocked(Wait),
+    // Host code is running the other threads until they go idle; resumed before the clock moves.
+    Parked,
+    Exited(u32),
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct GuestThread {
+    pub(crate) state: ThreadState,
+    // Order in which the thread last blocked, so waiters are served first-come first-served.
+    pub(crate) wait_order: u64,
+    pub(crate) suspend_count: u32,
+    /// Lowest usable stack address; 0 for the main thread, whose stack sits above the heap.
+    pub(crate) stack_limit: u32,
+    /// Mapped stack and TEB as `(start, size)`, released when the thread exits.
+    pub(crate) region: Option<(u32, u32)>,
+    pub(crate) context: ThreadContext,
+    pub(crate) exceptions: ExceptionState,
+}
+
+impl GuestThread {
+    fn is_runnable(&self) -> bool {
+        self.state == ThreadState::Ready && self.suspend_count == 0
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct Threads {
+    threads: BTreeMap<u32, GuestThread>,
+    handles: HashMap<u32, u32>,
+    current: u32,
+    next_id: u32,
+    next_handle: u32,
+    // Threads suspended inside a host call; only the innermost one may be resumed.
+    pinned: Vec<u32>,
+    clock: u64,
+    // Instructions executed by every thread, the base of the virtual time-stamp counter.
+    retired: u64,
+    slice: u64,
+    waits_started: u64,
+    yield_requested: bool,
+    // True while an outermost execute loop can switch threads after a host call returns.
+    pub(crate) dispatching: bool,
+    // Error raised by guest code that ran inside a host call; re-raised once the call returns.
+    pub(crate) fault: Option<VmError>,
+}
+
+impl Default for Threads {
+    fn default() -> Self {
+        let main = GuestThread {
+            state: ThreadState::Ready,
+            wait_order: 0,
+            suspend_count: 0,
+            stack_limit: 0,
+            region: None,
+            context: ThreadContext::default(),
+            exceptions: ExceptionState::default(),
+        };
+        Self {
+            threads: BTreeMap::from([(MAIN_THREAD_ID, main)]),
+            handles: HashMap::new(),
+            current: MAIN_THREAD_ID,
+            next_id: MAIN_THREAD_ID + 1,
+            next_handle: FIRST_THREAD_HANDLE,
+            pinned: Vec::new(),
+            clock: 0,
+            retired: 0,
+            slice: 0,
+            waits_started: 0,
+            yield_requested: false,
+            dispatching: false,
+            fault: None,
+        }
+    }
+}
+
+impl Threads {
+    pub(crate) fn current_id(&self) -> u32 {
+        self.current
+    }
+
+    pub(crate) fn current(&self) -> &GuestThread {
+        &self.threads[&self.current]
+    }
+
+    pub(crate) fn current_mut(&mut self) -> &mut GuestThread {
+        self.threads
+            .get_mut(&self.current)
+            .expect("current thread is always in the table")
+    }
+
+    pub(crate) fn get_mut(&mut self, id: u32) -> Option<&mut GuestThread> {
+        self.threads.get_mut(&id)
+    }
+
+    pub(crate) fn set_current(&mut self, id: u32) {
+        self.current = id;
+        self.slice = 0;
+        self.yield_requested = false;
+    }
+
+    /// Threads other than the running one, whose contexts are saved.
+    pub(crate) fn others_mut(&mut self) -> impl Iterator<Item = &mut GuestThread> + '_ {
+        let current = self.current;
+        self.threads
+            .iter_mut()
+            .filter(move |(&id, _)| id != current)
+            .map(|(_, thread)| thread)
+    }
+
+    pub(crate) fn has_others(&self) -> bool {
+        self.threads.len() > 1
+    }
+
+    /// Add a thread and return its `(id, handle)`.
+    pub(crate) fn insert(&mut self, thread: GuestThread) -> (u32, u32) {
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+        let handle = self.next_handle;
+        self.next_handle = self.next_handle.wrapping_add(1);
+        self.threads.insert(id, thread);
+        self.handles.insert(handle, id);
+        (id, handle)
+    }
+
+    pub(crate) fn by_handle(&self, handle: u32) -> Option<&GuestThread> {
+        self.threads.get(self.handles.get(&handle)?)
+    }
+
+    pub(crate) fn by_handle_mut(&mut self, handle: u32) -> Option<&mut GuestThread> {
+        self.threads.get_mut(self.handles.get(&handle)?)
+    }
+
+    pub(crate) fn id_of(&self, handle: u32) -> Option<u32> {
+        self.handles.get(&handle).copied()
+    }
+
+    pub(crate) fn is_signaled(&self, handle: u32) -> bool {
+        self.by_handle(handle)
+            .is_some_and(|thread| matches!(thread.state, ThreadState::Exited(_)))
+    }
+
+    pub(crate) fn clock(&self) -> u64 {
+        self.clock
+    }
+
+    /// Virtual time-stamp counter: one tick per retired instruction, plus a 1 GHz
+    /// clock's worth for every millisecond skipped while all threads waited.
+    pub(crate) fn timestamp(&self) -> u64 {
+        self.clock
+            .wrapping_mul(TSC_TICKS_PER_MS)
+            .wrapping_add(self.retired)
+    }
+
+    pub(crate) fn retire(&mut self) {
+        self.retired = self.retired.wrapping_add(1);
+    }
+
+    pub(crate) fn request_yield(&mut self) {
+        self.yield_requested = true;
+    }
+
+    pub(crate) fn pin(&mut self, id: u32) {
+        self.pinned.push(id);
+    }
+
+    pub(crate) fn unpin(&mut self) {
+        self.pinned.pop();
+    }
+
+    /// Count one executed instruction; true when the scheduler should pick a thread.
+    pub(crate) fn tick(&mut self, quantum: u64) -> bool {
+        if self.yield_requested || !self.current().is_runnable() {
+            return true;
+        }
+        if self.threads.len() == 1 {
+            return false;
+        }
+        self.slice += 1;
+        self.slice >= quantum
+    }
+
+    pub(crate) fn block_current(&mut self, wait: Wait) {
+        self.waits_started += 1;
+        let order = self.waits_started;
+        let thread = self.current_mut();
+        thread.state = ThreadState::Blocked(wait);
+        thread.wait_order = order;
+    }
+
+    /// Blocked threads in the order they started waiting.
+    pub(crate) fn waiters(&self) -> Vec<u32> {
+        let mut waiters: Vec<_> = self
+            .threads
+            .iter()
+            .filter(|(_, thread)| matches!(thread.state, ThreadState::Blocked(_)))
+            .map(|(&id, thread)| (thread.wait_order, id))
+            .collect();
+        waiters.sort_unstable();
+        waiters.into_iter().map(|(_, id)| id).collect()
+    }
+
+    pub(crate) fn wait_of(&self, id: u32) -> Option<&Wait> {
+        match &self.threads.get(&id)?.state {
+            ThreadState::Blocked(wait) => Some(wait),
+            _ => None,
+        }
+    }
+
+    /// Round-robin pick: the first runnable thread after the current one, wrapping around.
+    pub(crate) fn next_ready(&self) -> Option<u32> {
+        let resumable = |id: &u32, thread: &GuestThread| {
+            thread.is_runnable() && (!self.pinned.contains(id) || self.pinned.last() == Some(id))
+        };
+        let after = self.threads.range(self.current + 1..);
+        let before = self.threads.range(..=self.current);
+        after
+            .chain(before)
+            .find(|(id, thread)| resumable(id, thread))
+            .map(|(&id, _)| id)
+    }
+
+    /// With nothing runnable, resume the innermost parked thread or jump the clock to
+    /// the next deadline. False means no thread can ever make progress again.
+    pub(crate) fn advance(&mut self) -> bool {
+        if let Some(&id) = self.pinned.last() {
+            if let Some(thread) = self.threads.get_mut(&id) {
+                if thread.state == ThreadState::Parked {
+                    thread.state = ThreadState::Ready;
+                    return true;
+                }
+            }
+        }
+        let deadline = self
+            .threads
+            .values()
+            .filter_map(|thread| match &thread.state {
+                ThreadState::Blocked(wait) => wait.deadline,
+                _ => None,
+            })
+            .min();
+        match deadline {
+            Some(deadline) => {
+                self.clock = self.clock.max(deadline);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn worker() -> GuestThread {
+        GuestThread {
+            state: ThreadState::Ready,
+            wait_order: 0,
+            suspend_count: 0,
+            stack_limit: 0,
+            region: None,
+            context: ThreadContext::default(),
+            exceptions: ExceptionState::default(),
+        }
+    }
+
+    #[test]
+    fn round_robin_wraps_to_current() {
+        let mut threads = Threads::default();
+        let (a, _) = threads.insert(worker());
+        let (b, _) = threads.insert(worker());
+        assert_eq!(threads.next_ready(), Some(a));
+        threads.set_current(b);
+        assert_eq!(threads.next_ready(), Some(MAIN_THREAD_ID));
+        threads.current_mut().suspend_count = 1;
+        threads.get_mut(MAIN_THREAD_ID).unwrap().state = ThreadState::Exited(0);
+        assert_eq!(threads.next_ready(), Some(a));
+    }
+
+    #[test]
+    fn waiters_are_listed_in_blocking_order() {
+        let mut threads = Threads::default();
+        let (a, _) = threads.insert(worker());
+        let (b, _) = threads.insert(worker());
+        threads.set_current(b);
+        threads.block_current(Wait::any(Vec::new(), None));
+        threads.set_current(MAIN_THREAD_ID);
+        threads.block_current(Wait::any(Vec::new(), None));
+        threads.set_current(a);
+        assert_eq!(threads.waiters(), vec![b, MAIN_THREAD_ID]);
+        assert!(threads.wait_of(a).is_none());
+    }
+
+    #[test]
+    fn idle_clock_jumps_to_earliest_deadline() {
+        let mut threads = Threads::default();
+        let (id, handle) = threads.insert(worker());
+        threads.block_current(Wait::any(vec![handle], Some(50)));
+        threads.get_mut(id).unwrap().state = ThreadState::Blocked(Wait::any(Vec::new(), Some(20)));
+        assert!(threads.advance());
+        assert_eq!(threads.clock(), 20);
+        threads.get_mut(id).unwrap().state = ThreadState::Blocked(Wait::any(Vec::new(), None));
+        assert!(threads.advance());
+        assert_eq!(threads.clock(), 50);
+        threads.block_current(Wait::any(Vec::new(), None));
+        assert!(!threads.advance());
+    }
+
+    #[test]
+    fn timestamp_counts_retired_instructions_and_idle_time() {
+        let mut threads = Threads::default();
+        assert_eq!(threads.timestamp(), 0);
+        threads.retire();
+        threads.retire();
+        assert_eq!(threads.timestamp(), 2);
+        threads.block_current(Wait::any(Vec::new(), Some(3)));
+        assert!(threads.advance());
+        assert_eq!(threads.timestamp(), 3 * TSC_TICKS_PER_MS + 2);
+    }
+
+    #[test]
+    fn only_innermost_pinned_thread_is_resumable() {
+        let mut threads = Threads::default();
+        let (a, _) = threads.insert(worker());
+        threads.pin(MAIN_THREAD_ID);
+        threads.pin(a);
+        threads.set_current(a);
+        threads.current_mut().state = ThreadState::Parked;
+        assert_eq!(threads.next_ready(), None);
+        assert!(threads.advance());
+        assert_eq!(threads.next_ready(), Some(a));
+        threads.unpin();
+        assert_eq!(threads.next_ready(), Some(MAIN_THREAD_ID));
+    }
+}
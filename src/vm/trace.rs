@@ -0,0 +1,1321 @@
+//! Structured execution traces and the host-call log that replay feeds back.
+//!
+//! A trace is a stream of `TraceRecord`s handed to a `TraceSink`. `TraceWriter`
+//! stores them as JSON lines or in a compact binary form, and `read_trace` loads
+//! either one back for inspection or for `Vm::replay`.
+
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::rc::Rc;
+
+use serde_yaml::Value as YamlValue;
+
+use super::{AccessKind, Register, VmError};
+
+/// Which records a trace includes. Host calls, faults, module mappings, exception
+/// dispatches and import problems are always recorded; per-instruction and memory records are opt-in because a
+/// run produces millions of them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TraceOptions {
+    instructions: bool,
+    memory: bool,
+    host_calls: bool,
+}
+
+impl TraceOptions {
+    pub fn new() -> Self {
+        Self {
+            instructions: false,
+            memory: false,
+            host_calls: true,
+        }
+    }
+
+    /// Every record kind, including one per instruction and memory access.
+    pub fn all() -> Self {
+        Self::new().instructions(true).memory(true)
+    }
+
+    pub fn instructions(self, enabled: bool) -> Self {
+        Self {
+            instructions: enabled,
+            ..self
+        }
+    }
+
+    pub fn memory(self, enabled: bool) -> Self {
+        Self {
+            memory: enabled,
+            ..self
+        }
+    }
+
+    pub fn host_calls(self, enabled: bool) -> Self {
+        Self {
+            host_calls: enabled,
+            ..self
+        }
+    }
+
+    pub fn instructions_value(&self) -> bool {
+        self.instructions
+    }
+
+    pub fn memory_value(&self) -> bool {
+        self.memory
+    }
+
+    pub fn host_calls_value(&self) -> bool {
+        self.host_calls
+    }
+}
+
+impl Default for TraceOptions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// One event of a traced run.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TraceRecord {
+    /// An instruction that completed, with the registers it changed. EIP is left
+    /// out; the next record's address shows where control went.
+    Instruction {
+        eip: u32,
+        bytes: Vec<u8>,
+        changes: Vec<(Register, u32)>,
+    },
+    /// A guest memory access, written before the record of the instruction (or
+    /// host call) that made it. Host functions' accesses carry the EIP of the
+    /// instruction that called them; instruction fetches count as reads.
+    Memory {
+        eip: u32,
+        addr: u32,
+        access: AccessKind,
+        data: Vec<u8>,
+    },
+    /// A host import was entered. `seq` numbers calls in the order they start.
+    HostCall {
+        seq: u64,
+        name: String,
+        addr: u32,
+        args: Vec<HostArg>,
+    },
+    /// Host call `seq` returned. `writes` holds the final contents of the guest
+    /// memory it changed, including writes made by guest code it called back.
+    HostReturn {
+        seq: u64,
+        eax: u32,
+        ecx: u32,
+        edx: u32,
+        last_error: u32,
+        writes: Vec<MemoryWrite>,
+    },
+    /// The host started a nested guest call on a fresh stack slice; `stack`
+    /// holds the first words above ESP.
+    NestedCall {
+        entry: u32,
+        esp: u32,
+        stack: Vec<u32>,
+    },
+    /// An import that no host module implements, reported while binding.
+    UnresolvedImport {
+        name: String,
+    },
+    /// Guest code called an unresolved import.
+    MissingImport {
+        name: String,
+        addr: u32,
+    },
+    /// A guest image was mapped at `base`; `name` is its guest path.
+    ModuleMapped {
+        name: String,
+        base: u32,
+        size: u32,
+    },
+    /// Exception `code` raised at `address` is being dispatched to the guest's
+    /// handlers, starting from `esp`.
+    Exception {
+        code: u32,
+        address: u32,
+        esp: u32,
+    },
+    /// The instruction at `eip` failed; `code` lists the instructions from there.
+    Fault {
+        eip: u32,
+        error: String,
+        code: String,
+    },
+    ExecutionLimit {
+        eip: u32,
+        code: String,
+    },
+}
+
+/// A stack argument of a host call.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HostArg {
+    pub value: u32,
+    /// The string `value` points to, when it looks like one.
+    pub text: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MemoryWrite {
+    pub addr: u32,
+    pub bytes: Vec<u8>,
+}
+
+/// Receives trace records as the VM produces them.
+pub trait TraceSink {
+    fn record(&mut self, record: &TraceRecord) -> io::Result<()>;
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// A sink that keeps records in memory; clones share the same buffer, so one
+/// can be handed to the VM and the other read afterwards.
+#[derive(Debug, Clone, Default)]
+pub struct TraceBuffer {
+    records: Rc<RefCell<Vec<TraceRecord>>>,
+}
+
+impl TraceBuffer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn records(&self) -> Vec<TraceRecord> {
+        self.records.borrow().clone()
+    }
+
+    pub fn take(&self) -> Vec<TraceRecord> {
+        std::mem::take(&mut *self.records.borrow_mut())
+    }
+}
+
+impl TraceSink for TraceBuffer {
+    fn record(&mut self, record: &TraceRecord) -> io::Result<()> {
+        self.records.borrow_mut().push(record.clone());
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TraceFormat {
+    /// One JSON object per line, tagged by `"type"`.
+    JsonLines,
+    /// `BINARY_MAGIC`, then one tag byte and little-endian fields per record.
+    Binary,
+}
+
+const BINARY_MAGIC: &[u8; 8] = b"PEVMTRC1";
+
+/// Writes records to a stream in either `TraceFormat`.
+pub struct TraceWriter<W: Write> {
+    out: W,
+    format: TraceFormat,
+    started: bool,
+}
+
+impl TraceWriter<BufWriter<File>> {
+    pub fn create(path: impl AsRef<Path>, format: TraceFormat) -> Result<Self, VmError> {
+        Ok(Self::new(BufWriter::new(File::create(path)?), format))
+    }
+}
+
+impl<W: Write> TraceWriter<W> {
+    pub fn new(out: W, format: TraceFormat) -> Self {
+        Self {
+            out,
+            format,
+            started: false,
+        }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+}
+
+impl<W: Write> TraceSink for TraceWriter<W> {
+    fn record(&mut self, record: &TraceRecord) -> io::Result<()> {
+        match self.format {
+            TraceFormat::JsonLines => {
+                let mut line = encode_json(record);
+                line.push('\n');
+                self.out.write_all(line.as_bytes())
+            }
+            TraceFormat::Binary => {
+                if !self.started {
+                    self.started = true;
+                    self.out.write_all(BINARY_MAGIC)?;
+                }
+                let mut buf = Vec::new();
+                encode_binary(record, &mut buf);
+                self.out.write_all(&buf)
+            }
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+/// Load a trace written by `TraceWriter`, in either format.
+pub fn read_trace(path: impl AsRef<Path>) -> Result<Vec<TraceRecord>, VmError> {
+    parse_trace(&std::fs::read(path)?)
+}
+
+/// Decode trace records from the bytes of a JSON-lines or binary trace.
+pub fn parse_trace(data: &[u8]) -> Result<Vec<TraceRecord>, VmError> {
+    let records = match data.strip_prefix(BINARY_MAGIC.as_slice()) {
+        Some(body) => {
+            let mut reader = BinaryReader { data: body, pos: 0 };
+            let mut records = Vec::new();
+            while reader.pos < body.len() {
+                records.push(reader.record()?);
+            }
+            records
+        }
+        None => {
+            let text = std::str::from_utf8(data).map_err(|_| invalid("trace is not UTF-8"))?;
+            text.lines()
+                .filter(|line| !line.trim().is_empty())
+                .map(decode_json)
+                .collect::<io::Result<_>>()?
+        }
+    };
+    Ok(records)
+}
+
+// The same order gdb and `Vm::register` use; binary records store the index.
+pub(crate) const REGISTERS: [Register; 10] = [
+    Register::Eax,
+    Register::Ecx,
+    Register::Edx,
+    Register::Ebx,
+    Register::Esp,
+    Register::Ebp,
+    Register::Esi,
+    Register::Edi,
+    Register::Eip,
+    Register::Eflags,
+];
+
+fn register_name(reg: Register) -> &'static str {
+    match reg {
+        Register::Eax => "eax",
+        Register::Ecx => "ecx",
+        Register::Edx => "edx",
+        Register::Ebx => "ebx",
+        Register::Esp => "esp",
+        Register::Ebp => "ebp",
+        Register::Esi => "esi",
+        Register::Edi => "edi",
+        Register::Eip => "eip",
+        Register::Eflags => "eflags",
+    }
+}
+
+fn access_code(access: AccessKind) -> u8 {
+    match access {
+        AccessKind::Read => 0,
+        AccessKind::Write => 1,
+        AccessKind::Execute => 2,
+    }
+}
+
+fn access_from_code(code: u8) -> io::Result<AccessKind> {
+    match code {
+        0 => Ok(AccessKind::Read),
+        1 => Ok(AccessKind::Write),
+        2 => Ok(AccessKind::Execute),
+        _ => Err(invalid("bad access kind")),
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("malformed trace: {msg}"),
+    )
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+fn from_hex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|at| u8::from_str_radix(text.get(at..at + 2)?, 16).ok())
+        .collect()
+}
+
+fn json_string(out: &mut String, text: &str) {
+    out.push('"');
+    for ch in text.chars() {
+        match ch {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            // YAML, which reads the lines back, rejects raw control characters.
+            ch if ch.is_control() || matches!(ch, '\u{FFFE}' | '\u{FFFF}') => {
+                let _ = write!(out, "\\u{:04x}", ch as u32);
+            }
+            ch => out.push(ch),
+        }
+    }
+    out.push('"');
+}
+
+fn encode_json(record: &TraceRecord) -> String {
+    let mut out = String::from("{");
+    match record {
+        TraceRecord::Instruction {
+            eip,
+            bytes,
+            changes,
+        } => {
+            let _ = write!(
+                out,
+                "\"type\":\"instruction\",\"eip\":{eip},\"bytes\":\"{}\",\"regs\":{{",
+                to_hex(bytes)
+            );
+            for (idx, (reg, value)) in changes.iter().enumerate() {
+                let sep = if idx == 0 { "" } else { "," };
+                let _ = write!(out, "{sep}\"{}\":{value}", register_name(*reg));
+            }
+            out.push('}');
+        }
+        TraceRecord::Memory {
+            eip,
+            addr,
+            access,
+            data,
+        } => {
+            let _ = write!(
+                out,
+                "\"type\":\"memory\",\"eip\":{eip},\"addr\":{addr},\"access\":\"{access}\",\"data\":\"{}\"",
+                to_hex(data)
+            );
+        }
+        TraceRecord::HostCall {
+            seq,
+            name,
+            addr,
+            args,
+        } => {
+            let _ = write!(out, "\"type\":\"host_call\",\"seq\":{seq},\"name\":");
+            json_string(&mut out, name);
+            let _ = write!(out, ",\"addr\":{addr},\"args\":[");
+            for (idx, arg) in args.iter().enumerate() {
+                let sep = if idx == 0 { "" } else { "," };
+                let _ = write!(out, "{sep}{{\"value\":{}", arg.value);
+                if let Some(text) = &arg.text {
+                    out.push_str(",\"text\":");
+                    json_string(&mut out, text);
+                }
+                out.push('}');
+            }
+            out.push(']');
+        }
+        TraceRecord::HostReturn {
+            seq,
+            eax,
+            ecx,
+            edx,
+            last_error,
+            writes,
+        } => {
+            let _ = write!(
+                out,
+                "\"type\":\"host_return\",\"seq\":{seq},\"eax\":{eax},\"ecx\":{ecx},\"edx\":{edx},\"last_error\":{last_error},\"writes\":["
+            );
+            for (idx, write) in writes.iter().enumerate() {
+                let sep = if idx == 0 { "" } else { "," };
+                let _ = write!(
+                    out,
+                    "{sep}{{\"addr\":{},\"bytes\":\"{}\"}}",
+                    write.addr,
+                    to_hex(&write.bytes)
+                );
+            }
+            out.push(']');
+        }
+        TraceRecord::NestedCall { entry, esp, stack } => {
+            let words: Vec<String> = stack.iter().map(u32::to_string).collect();
+            let _ = write!(
+                out,
+                "\"type\":\"nested_call\",\"entry\":{entry},\"esp\":{esp},\"stack\":[{}]",
+                words.join(",")
+            );
+        }
+        TraceRecord::UnresolvedImport { name } => {
+            out.push_str("\"type\":\"unresolved_import\",\"name\":");
+            json_string(&mut out, name);
+        }
+        TraceRecord::MissingImport { name, addr } => {
+            out.push_str("\"type\":\"missing_import\",\"name\":");
+            json_string(&mut out, name);
+            let _ = write!(out, ",\"addr\":{addr}");
+        }
+        TraceRecord::ModuleMapped { name, base, size } => {
+            out.push_str("\"type\":\"module_mapped\",\"name\":");
+            json_string(&mut out, name);
+            let _ = write!(out, ",\"base\":{base},\"size\":{size}");
+        }
+        TraceRecord::Exception { code, address, esp } => {
+            let _ = write!(
+                out,
+                "\"type\":\"exception\",\"code\":{code},\"address\":{address},\"esp\":{esp}"
+            );
+        }
+        TraceRecord::Fault { eip, error, code } => {
+            let _ = write!(out, "\"type\":\"fault\",\"eip\":{eip},\"error\":");
+            json_string(&mut out, error);
+            out.push_str(",\"code\":");
+            json_string(&mut out, code);
+        }
+        TraceRecord::ExecutionLimit { eip, code } => {
+            let _ = write!(out, "\"type\":\"execution_limit\",\"eip\":{eip},\"code\":");
+            json_string(&mut out, code);
+        }
+    }
+    out.push('}');
+    out
+}
+
+// JSON is a subset of YAML's flow style, so the YAML parser the settings loader
+// already depends on reads the lines back.
+fn decode_json(line: &str) -> io::Result<TraceRecord> {
+    let value: YamlValue = serde_yaml::from_str(line).map_err(|err| invalid(&err.to_string()))?;
+    let fields = Fields(&value);
+    let record = match fields.str("type")? {
+        "instruction" => {
+            let regs = value
+                .get("regs")
+                .and_then(YamlValue::as_mapping)
+                .ok_or_else(|| invalid("missing regs"))?;
+            let mut changes = Vec::with_capacity(regs.len());
+            for (name, reg_value) in regs {
+                let reg = REGISTERS
+                    .into_iter()
+                    .find(|reg| Some(register_name(*reg)) == name.as_str())
+                    .ok_or_else(|| invalid("unknown register"))?;
+                changes.push((reg, as_u32(reg_value)?));
+            }
+            TraceRecord::Instruction {
+                eip: fields.u32("eip")?,
+                bytes: fields.bytes("bytes")?,
+                changes,
+            }
+        }
+        "memory" => TraceRecord::Memory {
+            eip: fields.u32("eip")?,
+            addr: fields.u32("addr")?,
+            access: match fields.str("access")? {
+                "read" => AccessKind::Read,
+                "write" => AccessKind::Write,
+                "execute" => AccessKind::Execute,
+                _ => return Err(invalid("bad access kind")),
+            },
+            data: fields.bytes("data")?,
+        },
+        "host_call" => TraceRecord::HostCall {
+            seq: fields.u64("seq")?,
+            name: fields.str("name")?.to_string(),
+            addr: fields.u32("addr")?,
+            args: fields
+                .list("args")?
+                .iter()
+                .map(|arg| {
+                    let arg = Fields(arg);
+                    Ok(HostArg {
+                        value: arg.u32("value")?,
+                        text: arg
+                            .0
+                            .get("text")
+                            .and_then(YamlValue::as_str)
+                            .map(str::to_string),
+                    })
+                })
+                .collect::<io::Result<_>>()?,
+        },
+        "host_return" => TraceRecord::HostReturn {
+            seq: fields.u64("seq")?,
+            eax: fields.u32("eax")?,
+            ecx: fields.u32("ecx")?,
+            edx: fields.u32("edx")?,
+            last_error: fields.u32("last_error")?,
+            writes: fields
+                .list("writes")?
+                .iter()
+                .map(|write| {
+                    let write = Fields(write);
+                    Ok(MemoryWrite {
+                        addr: write.u32("addr")?,
+                        bytes: write.bytes("bytes")?,
+                    })
+                })
+                .collect::<io::Result<_>>()?,
+        },
+        "nested_call" => TraceRecord::NestedCall {
+            entry: fields.u32("entry")?,
+            esp: fields.u32("esp")?,
+            stack: fields
+                .list("stack")?
+                .iter()
+                .map(as_u32)
+                .collect::<io::Result<_>>()?,
+        },
+        "unresolved_import" => TraceRecord::UnresolvedImport {
+            name: fields.str("name")?.to_string(),
+        },
+        "missing_import" => TraceRecord::MissingImport {
+            name: fields.str("name")?.to_string(),
+            addr: fields.u32("addr")?,
+        },
+        "module_mapped" => TraceRecord::ModuleMapped {
+            name: fields.str("name")?.to_string(),
+            base: fields.u32("base")?,
+            size: fields.u32("size")?,
+        },
+        "exception" => TraceRecord::Exception {
+            code: fields.u32("code")?,
+            address: fields.u32("address")?,
+            esp: fields.u32("esp")?,
+        },
+        "fault" => TraceRecord::Fault {
+            eip: fields.u32("eip")?,
+            error: fields.str("error")?.to_string(),
+            code: fields.str("code")?.to_string(),
+        },
+        "execution_limit" => TraceRecord::ExecutionLimit {
+            eip: fields.u32("eip")?,
+            code: fields.str("code")?.to_string(),
+        },
+        _ => return Err(invalid("unknown record type")),
+    };
+    Ok(record)
+}
+
+struct Fields<'a>(&'a YamlValue);
+
+impl Fields<'_> {
+    fn get(&self, name: &str) -> io::Result<&YamlValue> {
+        self.0
+            .get(name)
+            .ok_or_else(|| invalid(&format!("missing {name}")))
+    }
+
+    fn u32(&self, name: &str) -> io::Result<u32> {
+        as_u32(self.get(name)?)
+    }
+
+    fn u64(&self, name: &str) -> io::Result<u64> {
+        self.get(name)?
+            .as_u64()
+            .ok_or_else(|| invalid(&format!("bad {name}")))
+    }
+
+    fn str(&self, name: &str) -> io::Result<&str> {
+        self.get(name)?
+            .as_str()
+            .ok_or_else(|| invalid(&format!("bad {name}")))
+    }
+
+    fn bytes(&self, name: &str) -> io::Result<Vec<u8>> {
+        from_hex(self.str(name)?).ok_or_else(|| invalid(&format!("bad {name}")))
+    }
+
+    fn list(&self, name: &str) -> io::Result<&Vec<YamlValue>> {
+        self.get(name)?
+            .as_sequence()
+            .ok_or_else(|| invalid(&format!("bad {name}")))
+    }
+}
+
+fn as_u32(value: &YamlValue) -> io::Result<u32> {
+    value
+        .as_u64()
+        .and_then(|value| u32::try_from(value).ok())
+        .ok_or_else(|| invalid("bad number"))
+}
+
+const TAG_INSTRUCTION: u8 = 0;
+const TAG_MEMORY: u8 = 1;
+const TAG_HOST_CALL: u8 = 2;
+const TAG_HOST_RETURN: u8 = 3;
+const TAG_NESTED_CALL: u8 = 4;
+const TAG_UNRESOLVED_IMPORT: u8 = 5;
+const TAG_MISSING_IMPORT: u8 = 6;
+const TAG_FAULT: u8 = 7;
+const TAG_EXECUTION_LIMIT: u8 = 8;
+const TAG_MODULE_MAPPED: u8 = 9;
+const TAG_EXCEPTION: u8 = 10;
+
+fn put_u32(buf: &mut Vec<u8>, value: u32) {
+    buf.extend_from_slice(&value.to_le_bytes());
+}
+
+fn put_bytes(buf: &mut Vec<u8>, bytes: &[u8]) {
+    put_u32(buf, bytes.len() as u32);
+    buf.extend_from_slice(bytes);
+}
+
+fn encode_binary(record: &TraceRecord, buf: &mut Vec<u8>) {
+    match record {
+        TraceRecord::Instruction {
+            eip,
+            bytes,
+            changes,
+        } => {
+            // Instructions are at most 15 bytes and change at most 10 registers.
+            buf.push(TAG_INSTRUCTION);
+            put_u32(buf, *eip);
+            buf.push(bytes.len() as u8);
+            buf.extend_from_slice(bytes);
+            buf.push(changes.len() as u8);
+            for (reg, value) in changes {
+                let index = REGISTERS.iter().position(|known| known == reg).unwrap_or(0);
+                buf.push(index as u8);
+                put_u32(buf, *value);
+            }
+        }
+        TraceRecord::Memory {
+            eip,
+            addr,
+            access,
+            data,
+        } => {
+            buf.push(TAG_MEMORY);
+            put_u32(buf, *eip);
+            put_u32(buf, *addr);
+            buf.push(access_code(*access));
+            put_bytes(buf, data);
+        }
+        TraceRecord::HostCall {
+            seq,
+            name,
+            addr,
+            args,
+        } => {
+            buf.push(TAG_HOST_CALL);
+            buf.extend_from_slice(&seq.to_le_bytes());
+            put_bytes(buf, name.as_bytes());
+            put_u32(buf, *addr);
+            put_u32(buf, args.len() as u32);
+            for arg in args {
+                put_u32(buf, arg.value);
+                match &arg.text {
+                    Some(text) => {
+                        buf.push(1);
+                        put_bytes(buf, text.as_bytes());
+                    }
+                    None => buf.push(0),
+                }
+            }
+        }
+        TraceRecord::HostReturn {
+            seq,
+            eax,
+            ecx,
+            edx,
+            last_error,
+            writes,
+        } => {
+            buf.push(TAG_HOST_RETURN);
+            buf.extend_from_slice(&seq.to_le_bytes());
+            for value in [eax, ecx, edx, last_error] {
+                put_u32(buf, *value);
+            }
+            put_u32(buf, writes.len() as u32);
+            for write in writes {
+                put_u32(buf, write.addr);
+                put_bytes(buf, &write.bytes);
+            }
+        }
+        TraceRecord::NestedCall { entry, esp, stack } => {
+            buf.push(TAG_NESTED_CALL);
+            put_u32(buf, *entry);
+            put_u32(buf, *esp);
+            put_u32(buf, stack.len() as u32);
+            for word in stack {
+                put_u32(buf, *word);
+            }
+        }
+        TraceRecord::UnresolvedImport { name } => {
+            buf.push(TAG_UNRESOLVED_IMPORT);
+            put_bytes(buf, name.as_bytes());
+        }
+        TraceRecord::MissingImport { name, addr } => {
+            buf.push(TAG_MISSING_IMPORT);
+            put_bytes(buf, name.as_bytes());
+            put_u32(buf, *addr);
+        }
+        TraceRecord::ModuleMapped { name, base, size } => {
+            buf.push(TAG_MODULE_MAPPED);
+            put_bytes(buf, name.as_bytes());
+            put_u32(buf, *base);
+            put_u32(buf, *size);
+        }
+        TraceRecord::Exception { code, address, esp } => {
+            buf.push(TAG_EXCEPTION);
+            for value in [code, address, esp] {
+                put_u32(buf, *value);
+            }
+        }
+        TraceRecord::Fault { eip, error, code } => {
+            buf.push(TAG_FAULT);
+            put_u32(buf, *eip);
+            put_bytes(buf, error.as_bytes());
+            put_bytes(buf, code.as_bytes());
+        }
+        TraceRecord::ExecutionLimit { eip, code } => {
+            buf.push(TAG_EXECUTION_LIMIT);
+            put_u32(buf, *eip);
+            put_bytes(buf, code.as_bytes());
+        }
+    }
+}
+
+struct BinaryReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl BinaryReader<'_> {
+    fn take(&mut self, len: usize) -> io::Result<&[u8]> {
+        let end = self
+            .pos
+            .checked_add(len)
+            .filter(|end| *end <= self.data.len())
+            .ok_or_else(|| invalid("truncated record"))?;
+        let bytes = &self.data[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> io::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> io::Result<u32> {
+        Ok(u32::from_le_bytes(
+            self.take(4)?.try_into().expect("4 bytes"),
+        ))
+    }
+
+    fn u64(&mut self) -> io::Result<u64> {
+        Ok(u64::from_le_bytes(
+            self.take(8)?.try_into().expect("8 bytes"),
+        ))
+    }
+
+    fn bytes(&mut self) -> io::Result<Vec<u8>> {
+        let len = self.u32()? as usize;
+        Ok(self.take(len)?.to_vec())
+    }
+
+    fn string(&mut self) -> io::Result<String> {
+        String::from_utf8(self.bytes()?).map_err(|_| invalid("string is not UTF-8"))
+    }
+
+    fn record(&mut self) -> io::Result<TraceRecord> {
+        let record = match self.u8()? {
+            TAG_INSTRUCTION => {
+                let eip = self.u32()?;
+                let len = self.u8()? as usize;
+                let bytes = self.take(len)?.to_vec();
+                let count = self.u8()?;
+                let mut changes = Vec::with_capacity(count as usize);
+                for _ in 0..count {
+                    let reg = *REGISTERS
+                        .get(self.u8()? as usize)
+                        .ok_or_else(|| invalid("unknown register"))?;
+                    changes.push((reg, self.u32()?));
+                }
+                TraceRecord::Instruction {
+                    eip,
+                    bytes,
+                    changes,
+                }
+            }
+            TAG_MEMORY => TraceRecord::Memory {
+                eip: self.u32()?,
+                addr: self.u32()?,
+                access: access_from_code(self.u8()?)?,
+                data: self.bytes()?,
+            },
+            TAG_HOST_CALL => {
+                let seq = self.u64()?;
+                let name = self.string()?;
+                let addr = self.u32()?;
+                let count = self.u32()?;
+                let mut args = Vec::new();
+                for _ in 0..count {
+                    let value = self.u32()?;
+                    let text = match self.u8()? {
+                        0 => None,
+                        _ => Some(self.string()?),
+                    };
+                    args.push(HostArg { value, text });
+                }
+                TraceRecord::HostCall {
+                    seq,
+                    name,
+                    addr,
+                    args,
+                }
+            }
+            TAG_HOST_RETURN => {
+                let seq = self.u64()?;
+                let [eax, ecx, edx, last_error] =
+                    [self.u32()?, self.u32()?, self.u32()?, self.u32()?];
+                let count = self.u32()?;
+                let mut writes = Vec::new();
+                for _ in 0..count {
+                    writes.push(MemoryWrite {
+                        addr: self.u32()?,
+                        bytes: self.bytes()?,
+                    });
+                }
+                TraceRecord::HostReturn {
+                    seq,
+                    eax,
+                    ecx,
+                    edx,
+                    last_error,
+                    writes,
+                }
+            }
+            TAG_NESTED_CALL => {
+                let entry = self.u32()?;
+                let esp = self.u32()?;
+                let count = self.u32()?;
+                let stack = (0..count).map(|_| self.u32()).collect::<io::Result<_>>()?;
+                TraceRecord::NestedCall { entry, esp, stack }
+            }
+            TAG_UNRESOLVED_IMPORT => TraceRecord::UnresolvedImport {
+                name: self.string()?,
+            },
+            TAG_MISSING_IMPORT => TraceRecord::MissingImport {
+                name: self.string()?,
+                addr: self.u32()?,
+            },
+            TAG_MODULE_MAPPED => TraceRecord::ModuleMapped {
+                name: self.string()?,
+                base: self.u32()?,
+                size: self.u32()?,
+            },
+            TAG_EXCEPTION => TraceRecord::Exception {
+                code: self.u32()?,
+                address: self.u32()?,
+                esp: self.u32()?,
+            },
+            TAG_FAULT => TraceRecord::Fault {
+                eip: self.u32()?,
+                error: self.string()?,
+                code: self.string()?,
+            },
+            TAG_EXECUTION_LIMIT => TraceRecord::ExecutionLimit {
+                eip: self.u32()?,
+                code: self.string()?,
+            },
+            _ => return Err(invalid("unknown record tag")),
+        };
+        Ok(record)
+    }
+}
+
+/// The recorded outcome of one host call.
+#[derive(Debug, Clone)]
+pub(crate) struct RecordedReturn {
+    pub(crate) eax: u32,
+    pub(crate) ecx: u32,
+    pub(crate) edx: u32,
+    pub(crate) last_error: u32,
+    pub(crate) writes: Vec<MemoryWrite>,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct RecordedCall {
+    pub(crate) name: String,
+    /// `None` when the recording ended inside the call.
+    pub(crate) result: Option<RecordedReturn>,
+}
+
+/// Host calls of a recording, in the order they started.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Replay {
+    pub(crate) calls: Vec<RecordedCall>,
+    pub(crate) next: usize,
+}
+
+impl Replay {
+    pub(crate) fn new(records: &[TraceRecord]) -> Self {
+        let mut calls = BTreeMap::new();
+        for record in records {
+            match record {
+                TraceRecord::HostCall { seq, name, .. } => {
+                    calls.insert(
+                        *seq,
+                        RecordedCall {
+                            name: name.clone(),
+                            result: None,
+                        },
+                    );
+                }
+                TraceRecord::HostReturn {
+                    seq,
+                    eax,
+                    ecx,
+                    edx,
+                    last_error,
+                    writes,
+                } => {
+                    if let Some(call) = calls.get_mut(seq) {
+                        call.result = Some(RecordedReturn {
+                            eax: *eax,
+                            ecx: *ecx,
+                            edx: *edx,
+                            last_error: *last_error,
+                            writes: writes.clone(),
+                        });
+                    }
+                }
+                _ => {}
+            }
+        }
+        Self {
+            calls: calls.into_values().collect(),
+            next: 0,
+        }
+    }
+}
+
+/// Guest bytes a host call changed: address to (value before, value now).
+pub(crate) type WriteCapture = BTreeMap<u32, (u8, u8)>;
+
+/// Split captured bytes into contiguous runs of their current values.
+pub(crate) fn coalesce_writes(capture: &WriteCapture) -> Vec<MemoryWrite> {
+    let mut writes: Vec<MemoryWrite> = Vec::new();
+    for (&addr, &(_, new)) in capture {
+        match writes.last_mut() {
+            Some(run) if run.addr.wrapping_add(run.bytes.len() as u32) == addr => {
+                run.bytes.push(new)
+            }
+            _ => writes.push(MemoryWrite {
+                addr,
+                bytes: vec![new],
+            }),
+        }
+    }
+    writes
+}
+
+/// Trace and replay state of a VM.
+#[derive(Default)]
+pub(crate) struct Tracer {
+    sink: Option<Box<dyn TraceSink>>,
+    options: TraceOptions,
+    // The first error the sink returned; tracing stops there.
+    error: Option<io::Error>,
+    /// EIP of the instruction being executed, for memory records.
+    pub(crate) eip: u32,
+    // Filled by memory reads, which only hold `&Vm`; written out before the
+    // next record.
+    accesses: RefCell<Vec<TraceRecord>>,
+    /// Bytes and registers of the current instruction before it ran.
+    pub(crate) before: Option<(Vec<u8>, [u32; 10])>,
+    /// Host calls started so far; the next call's `seq`.
+    pub(crate) calls: u64,
+    /// One per host call in progress, innermost last.
+    pub(crate) captures: Vec<WriteCapture>,
+    pub(crate) replay: Option<Replay>,
+}
+
+impl Tracer {
+    pub(crate) fn set_sink(&mut self, sink: Box<dyn TraceSink>, options: TraceOptions) {
+        self.sink = Some(sink);
+        self.options = options;
+        self.error = None;
+    }
+
+    /// Flush and drop the sink, returning the first error it reported.
+    pub(crate) fn finish(&mut self) -> io::Result<()> {
+        if self.sink.is_some() {
+            self.flush_accesses();
+        }
+        let flushed = match self.sink.take() {
+            Some(mut sink) => sink.flush(),
+            None => Ok(()),
+        };
+        self.before = None;
+        match self.error.take() {
+            Some(err) => Err(err),
+            None => flushed,
+        }
+    }
+
+    pub(crate) fn is_on(&self) -> bool {
+        self.sink.is_some()
+    }
+
+    pub(crate) fn traces_instructions(&self) -> bool {
+        self.sink.is_some() && self.options.instructions
+    }
+
+    pub(crate) fn traces_memory(&self) -> bool {
+        self.sink.is_some() && self.options.memory
+    }
+
+    pub(crate) fn traces_host_calls(&self) -> bool {
+        self.sink.is_some() && self.options.host_calls
+    }
+
+    /// Whether the run loop has to call into the tracer around every step.
+    pub(crate) fn per_step(&self) -> bool {
+        self.sink.is_some() && (self.options.instructions || self.options.memory)
+    }
+
+    pub(crate) fn record_access(&self, addr: u32, access: AccessKind, data: &[u8]) {
+        if !self.traces_memory() {
+            return;
+        }
+        self.accesses.borrow_mut().push(TraceRecord::Memory {
+            eip: self.eip,
+            addr,
+            access,
+            data: data.to_vec(),
+        });
+    }
+
+    /// Note a write while host calls are in progress; the innermost one owns it.
+    pub(crate) fn capture_write(&mut self, addr: u32, old: &[u8], new: &[u8]) {
+        let Some(capture) = self.captures.last_mut() else {
+            return;
+        };
+        for (offset, (&old, &new)) in old.iter().zip(new).enumerate() {
+            capture
+                .entry(addr.wrapping_add(offset as u32))
+                .or_insert((old, old))
+                .1 = new;
+        }
+    }
+
+    /// Close the innermost capture; what it saw also counts for the call around it.
+    pub(crate) fn end_capture(&mut self) -> WriteCapture {
+        let capture = self.captures.pop().unwrap_or_default();
+        if let Some(outer) = self.captures.last_mut() {
+            for (&addr, &(old, new)) in &capture {
+                outer.entry(addr).or_insert((old, old)).1 = new;
+            }
+        }
+        capture
+    }
+
+    pub(crate) fn emit(&mut self, record: TraceRecord) {
+        if self.sink.is_none() {
+            return;
+        }
+        self.flush_accesses();
+        self.write(&record);
+    }
+
+    pub(crate) fn flush_accesses(&mut self) {
+        let accesses = std::mem::take(&mut *self.accesses.borrow_mut());
+        for access in &accesses {
+            self.write(access);
+        }
+    }
+
+    fn write(&mut self, record: &TraceRecord) {
+        let Some(sink) = self.sink.as_mut() else {
+            return;
+        };
+        if let Err(err) = sink.record(record) {
+            self.error = Some(err);
+            self.sink = None;
+            self.accesses.borrow_mut().clear();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample_records() -> Vec<TraceRecord> {
+        vec![
+            TraceRecord::Memory {
+                eip: 0x40_1000,
+                addr: 0x50_0000,
+                access: AccessKind::Write,
+                data: vec![1, 2, 3, 4],
+            },
+            TraceRecord::Instruction {
+                eip: 0x40_1000,
+                bytes: vec![0xB8, 0x01, 0x00, 0x00, 0x00],
+                changes: vec![(Register::Eax, 1), (Register::Eflags, 0x202)],
+            },
+            TraceRecord::HostCall {
+                seq: 7,
+                name: "KERNEL32.dll!CreateFileA".to_string(),
+                addr: 0x7000_0000,
+                args: vec![
+                    HostArg {
+                        value: 0x50_0010,
+                        text: Some("C:\\temp\\\"a\"\tb\u{1}é".to_string()),
+                    },
+                    HostArg {
+                        value: 0xFFFF_FFFF,
+                        text: None,
+                    },
+                ],
+            },
+            TraceRecord::HostReturn {
+                seq: 7,
+                eax: 0x100,
+                ecx: 0,
+                edx: 5,
+                last_error: 183,
+                writes: vec![MemoryWrite {
+                    addr: 0x50_0020,
+                    bytes: vec![0xAA, 0xBB],
+                }],
+            },
+            TraceRecord::NestedCall {
+                entry: 0x40_2000,
+                esp: 0x60_0000,
+                stack: vec![0, 1, 2],
+            },
+            TraceRecord::UnresolvedImport {
+                name: "USER32.dll!#12".to_string(),
+            },
+            TraceRecord::MissingImport {
+                name: "USER32.dll!Foo".to_string(),
+                addr: 0x40_3000,
+            },
+            TraceRecord::ModuleMapped {
+                name: "C:\\app\\plugin.dll".to_string(),
+                base: 0x1000_0000,
+                size: 0x2_0000,
+            },
+            TraceRecord::Exception {
+                code: 0xE06D_7363,
+                address: 0x40_1004,
+                esp: 0x60_0000,
+            },
+            TraceRecord::Fault {
+                eip: 0x40_1005,
+                error: "unsupported instruction 0x0F".to_string(),
+                code: "0x00401005: ud2".to_string(),
+            },
+            TraceRecord::ExecutionLimit {
+                eip: 0x40_1005,
+                code: String::new(),
+            },
+        ]
+    }
+
+    fn write_all(format: TraceFormat) -> Vec<u8> {
+        let mut writer = TraceWriter::new(Vec::new(), format);
+        for record in sample_records() {
+            writer.record(&record).expect("record");
+        }
+        writer.into_inner()
+    }
+
+    #[test]
+    fn json_lines_round_trip() {
+        let data = write_all(TraceFormat::JsonLines);
+        let text = String::from_utf8(data.clone()).expect("utf-8");
+        assert_eq!(text.lines().count(), sample_records().len());
+        assert!(text.starts_with(
+            "{\"type\":\"memory\",\"eip\":4198400,\"addr\":5242880,\"access\":\"write\",\"data\":\"01020304\"}\n"
+        ));
+        assert_eq!(parse_trace(&data).expect("parse"), sample_records());
+    }
+
+    #[test]
+    fn binary_round_trip() {
+        let data = write_all(TraceFormat::Binary);
+        assert!(data.starts_with(BINARY_MAGIC));
+        assert_eq!(parse_trace(&data).expect("parse"), sample_records());
+        assert!(parse_trace(&data[..data.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn replay_pairs_calls_with_returns_in_start_order() {
+        let records = vec![
+            TraceRecord::HostCall {
+                seq: 0,
+                name: "outer".to_string(),
+                addr: 0,
+                args: Vec::new(),
+            },
+            TraceRecord::HostCall {
+                seq: 1,
+                name: "inner".to_string(),
+                addr: 0,
+                args: Vec::new(),
+            },
+            TraceRecord::HostReturn {
+                seq: 1,
+                eax: 2,
+                ecx: 0,
+                edx: 0,
+                last_error: 0,
+                writes: Vec::new(),
+            },
+        ];
+        let replay = Replay::new(&records);
+        let names: Vec<_> = replay.calls.iter().map(|call| call.name.as_str()).collect();
+        assert_eq!(names, ["outer", "inner"]);
+        assert!(replay.calls[0].result.is_none());
+        assert_eq!(replay.calls[1].result.as_ref().map(|ret| ret.eax), Some(2));
+    }
+
+    #[test]
+    fn nested_captures_merge_into_the_outer_call() {
+        let mut tracer = Tracer::default();
+        tracer.captures.push(WriteCapture::new());
+        tracer.capture_write(0x100, &[1, 2], &[3, 4]);
+        tracer.captures.push(WriteCapture::new());
+        tracer.capture_write(0x101, &[4], &[5]);
+        tracer.capture_write(0x102, &[6], &[7]);
+        let inner = tracer.end_capture();
+        assert_eq!(
+            coalesce_writes(&inner),
+            [MemoryWrite {
+                addr: 0x101,
+                bytes: vec![5, 7],
+            }]
+        );
+        let outer = tracer.end_capture();
+        assert_eq!(outer[&0x101], (2, 5));
+        assert_eq!(
+            coalesce_writes(&outer),
+            [MemoryWrite {
+                addr: 0x100,
+                bytes: vec![3, 5, 7],
+            }]
+        );
+    }
+}
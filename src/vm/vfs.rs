@@ -0,0 +1,1103 @@
+//! Guest filesystem backends.
+//!
+//! Guest file APIs go through a `GuestFs` picked by the longest guest path
+//! prefix: either a backend mounted with `VmConfig::mount`, or, for plain
+//! `VmConfig::paths` mappings and unmapped paths, the host filesystem. Backends
+//! see paths relative to their mount point, with `/` between components.
+//! Files are read and written in place through `GuestFile`, never loaded whole.
+
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::fmt;
+use std::fs;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::SystemTime;
+
+/// A filesystem the guest can be given a view of.
+pub trait GuestFs: fmt::Debug + Send + Sync {
+    fn open(&self, path: &str, options: &FsOpenOptions) -> io::Result<Box<dyn GuestFile>>;
+
+    fn metadata(&self, path: &str) -> io::Result<FsMetadata>;
+
+    fn remove_file(&self, path: &str) -> io::Result<()>;
+
+    /// Create a directory and any missing parents.
+    fn create_dir_all(&self, path: &str) -> io::Result<()>;
+
+    /// Names of the entries in a directory, in no particular order.
+    fn read_dir(&self, path: &str) -> io::Result<Vec<String>>;
+
+    /// Remove an empty directory.
+    fn remove_dir(&self, _path: &str) -> io::Result<()> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+
+    /// Move a file or directory within this filesystem, replacing an existing
+    /// file at `to`. Moves between filesystems are done by copying instead.
+    fn rename(&self, _from: &str, _to: &str) -> io::Result<()> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+
+    fn set_read_only(&self, _path: &str, _read_only: bool) -> io::Result<()> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+}
+
+/// An open guest file. Dropping it closes the file; call `sync` first to find
+/// out whether buffered writes reached the backing store.
+pub trait GuestFile: Read + Write + Seek + Send {
+    fn size(&mut self) -> io::Result<u64>;
+
+    fn set_len(&mut self, len: u64) -> io::Result<()>;
+
+    /// Write buffered data back to the backing store.
+    fn sync(&mut self) -> io::Result<()> {
+        self.flush()
+    }
+
+    fn metadata(&mut self) -> io::Result<FsMetadata> {
+        self.size().map(FsMetadata::file)
+    }
+
+    /// Change the timestamps that are set in `times`; the others are left as
+    /// they are.
+    fn set_times(&mut self, _times: FsTimes) -> io::Result<()> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+}
+
+/// How to open a guest file, mirroring `std::fs::OpenOptions`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FsOpenOptions {
+    read: bool,
+    write: bool,
+    create: bool,
+    create_new: bool,
+    truncate: bool,
+}
+
+impl FsOpenOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn read(self, read: bool) -> Self {
+        let mut options = self;
+        options.read = read;
+        options
+    }
+
+    pub fn read_value(&self) -> bool {
+        self.read
+    }
+
+    pub fn write(self, write: bool) -> Self {
+        let mut options = self;
+        options.write = write;
+        options
+    }
+
+    pub fn write_value(&self) -> bool {
+        self.write
+    }
+
+    /// Create the file if it does not exist.
+    pub fn create(self, create: bool) -> Self {
+        let mut options = self;
+        options.create = create;
+        options
+    }
+
+    pub fn create_value(&self) -> bool {
+        self.create
+    }
+
+    /// Create the file, failing if it already exists.
+    pub fn create_new(self, create_new: bool) -> Self {
+        let mut options = self;
+        options.create_new = create_new;
+        options
+    }
+
+    pub fn create_new_value(&self) -> bool {
+        self.create_new
+    }
+
+    pub fn truncate(self, truncate: bool) -> Self {
+        let mut options = self;
+        options.truncate = truncate;
+        options
+    }
+
+    pub fn truncate_value(&self) -> bool {
+        self.truncate
+    }
+
+    // Whether opening may change the file, which requires write access on the host.
+    pub(crate) fn modifies(&self) -> bool {
+        self.write || self.create || self.create_new || self.truncate
+    }
+
+    fn std_options(&self) -> fs::OpenOptions {
+        let mut options = fs::OpenOptions::new();
+        options
+            .read(self.read || !self.modifies())
+            .write(self.modifies())
+            .create(self.create)
+            .create_new(self.create_new)
+            .truncate(self.truncate);
+        options
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FsMetadata {
+    is_dir: bool,
+    size: u64,
+    read_only: bool,
+    times: FsTimes,
+}
+
+impl FsMetadata {
+    pub fn file(size: u64) -> Self {
+        Self {
+            is_dir: false,
+            size,
+            read_only: false,
+            times: FsTimes::default(),
+        }
+    }
+
+    pub fn dir() -> Self {
+        Self {
+            is_dir: true,
+            size: 0,
+            read_only: false,
+            times: FsTimes::default(),
+        }
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.is_dir
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    pub fn read_only(self, read_only: bool) -> Self {
+        let mut metadata = self;
+        metadata.read_only = read_only;
+        metadata
+    }
+
+    pub fn read_only_value(&self) -> bool {
+        self.read_only
+    }
+
+    pub fn times(self, times: FsTimes) -> Self {
+        let mut metadata = self;
+        metadata.times = times;
+        metadata
+    }
+
+    pub fn times_value(&self) -> FsTimes {
+        self.times
+    }
+}
+
+impl From<fs::Metadata> for FsMetadata {
+    fn from(metadata: fs::Metadata) -> Self {
+        let times = FsTimes {
+            created: metadata.created().ok(),
+            accessed: metadata.accessed().ok(),
+            modified: metadata.modified().ok(),
+        };
+        Self {
+            is_dir: metadata.is_dir(),
+            size: metadata.len(),
+            read_only: metadata.permissions().readonly(),
+            times,
+        }
+    }
+}
+
+/// File timestamps. A backend that does not track one leaves it `None`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FsTimes {
+    created: Option<SystemTime>,
+    accessed: Option<SystemTime>,
+    modified: Option<SystemTime>,
+}
+
+impl FsTimes {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn created(self, time: SystemTime) -> Self {
+        let mut times = self;
+        times.created = Some(time);
+        times
+    }
+
+    pub fn created_value(&self) -> Option<SystemTime> {
+        self.created
+    }
+
+    pub fn accessed(self, time: SystemTime) -> Self {
+        let mut times = self;
+        times.accessed = Some(time);
+        times
+    }
+
+    pub fn accessed_value(&self) -> Option<SystemTime> {
+        self.accessed
+    }
+
+    pub fn modified(self, time: SystemTime) -> Self {
+        let mut times = self;
+        times.modified = Some(time);
+        times
+    }
+
+    pub fn modified_value(&self) -> Option<SystemTime> {
+        self.modified
+    }
+
+    // Apply the timestamps that are set on top of `self`.
+    fn update(&mut self, other: FsTimes) {
+        self.created = other.created.or(self.created);
+        self.accessed = other.accessed.or(self.accessed);
+        self.modified = other.modified.or(self.modified);
+    }
+
+    // The subset the host can change: creation times are fixed on Unix.
+    fn std_times(&self) -> fs::FileTimes {
+        let mut times = fs::FileTimes::new();
+        if let Some(accessed) = self.accessed {
+            times = times.set_accessed(accessed);
+        }
+        if let Some(modified) = self.modified {
+            times = times.set_modified(modified);
+        }
+        times
+    }
+}
+
+/// A host directory passed straight through. Reads and writes hit the host
+/// files directly.
+#[derive(Debug, Clone)]
+pub struct HostFs {
+    root: PathBuf,
+}
+
+impl HostFs {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self { root: root.into() }
+    }
+
+    // Paths outside any mount are used as given.
+    pub(crate) fn passthrough() -> Self {
+        Self::new(PathBuf::new())
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    fn host_path(&self, path: &str) -> PathBuf {
+        host_path(&self.root, path)
+    }
+}
+
+impl GuestFs for HostFs {
+    fn open(&self, path: &str, options: &FsOpenOptions) -> io::Result<Box<dyn GuestFile>> {
+        let file = options.std_options().open(self.host_path(path))?;
+        Ok(Box::new(file))
+    }
+
+    fn metadata(&self, path: &str) -> io::Result<FsMetadata> {
+        fs::metadata(self.host_path(path)).map(FsMetadata::from)
+    }
+
+    fn remove_file(&self, path: &str) -> io::Result<()> {
+        fs::remove_file(self.host_path(path))
+    }
+
+    fn create_dir_all(&self, path: &str) -> io::Result<()> {
+        fs::create_dir_all(self.host_path(path))
+    }
+
+    fn read_dir(&self, path: &str) -> io::Result<Vec<String>> {
+        host_read_dir(&self.host_path(path))
+    }
+
+    fn remove_dir(&self, path: &str) -> io::Result<()> {
+        fs::remove_dir(self.host_path(path))
+    }
+
+    fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+        fs::rename(self.host_path(from), self.host_path(to))
+    }
+
+    fn set_read_only(&self, path: &str, read_only: bool) -> io::Result<()> {
+        host_set_read_only(&self.host_path(path), read_only)
+    }
+}
+
+impl GuestFile for fs::File {
+    fn size(&mut self) -> io::Result<u64> {
+        fs::File::metadata(self).map(|metadata| metadata.len())
+    }
+
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        fs::File::set_len(self, len)
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        self.sync_data()
+    }
+
+    fn metadata(&mut self) -> io::Result<FsMetadata> {
+        fs::File::metadata(self).map(FsMetadata::from)
+    }
+
+    fn set_times(&mut self, times: FsTimes) -> io::Result<()> {
+        fs::File::set_times(self, times.std_times())
+    }
+}
+
+/// Files held in memory. Clones share the same contents, so a host can keep one
+/// to seed inputs and inspect what the guest wrote.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryFs {
+    inner: Arc<Mutex<MemoryTree>>,
+}
+
+#[derive(Debug, Default)]
+struct MemoryTree {
+    files: BTreeMap<String, Arc<Mutex<MemoryNode>>>,
+    dirs: BTreeSet<String>,
+}
+
+#[derive(Debug)]
+struct MemoryNode {
+    data: Vec<u8>,
+    read_only: bool,
+    times: FsTimes,
+}
+
+impl MemoryNode {
+    fn new(data: Vec<u8>) -> Arc<Mutex<Self>> {
+        let now = SystemTime::now();
+        let times = FsTimes::new().created(now).accessed(now).modified(now);
+        Arc::new(Mutex::new(Self {
+            data,
+            read_only: false,
+            times,
+        }))
+    }
+
+    fn metadata(&self) -> FsMetadata {
+        FsMetadata::file(self.data.len() as u64)
+            .read_only(self.read_only)
+            .times(self.times)
+    }
+
+    fn touch(&mut self) {
+        self.times.modified = Some(SystemTime::now());
+    }
+}
+
+impl MemoryFs {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add or replace a file, creating its parent directories.
+    pub fn insert(&self, path: &str, data: impl Into<Vec<u8>>) {
+        let path = memory_key(path);
+        let mut tree = self.tree();
+        tree.add_parents(&path);
+        tree.files.insert(path, MemoryNode::new(data.into()));
+    }
+
+    /// Current contents of a file.
+    pub fn get(&self, path: &str) -> Option<Vec<u8>> {
+        let data = self.tree().files.get(&memory_key(path))?.clone();
+        let data = lock(&data).data.clone();
+        Some(data)
+    }
+
+    /// Paths of all files, in order.
+    pub fn files(&self) -> Vec<String> {
+        self.tree().files.keys().cloned().collect()
+    }
+
+    fn tree(&self) -> MutexGuard<'_, MemoryTree> {
+        lock(&self.inner)
+    }
+}
+
+impl MemoryTree {
+    fn add_parents(&mut self, path: &str) {
+        let mut end = 0;
+        while let Some(pos) = path[end..].find('/') {
+            end += pos;
+            self.dirs.insert(path[..end].to_string());
+            end += 1;
+        }
+    }
+
+    fn is_dir(&self, path: &str) -> bool {
+        path.is_empty() || self.dirs.contains(path)
+    }
+
+    fn has_parent(&self, path: &str) -> bool {
+        path.rsplit_once('/')
+            .is_none_or(|(parent, _)| self.is_dir(parent))
+    }
+
+    fn has_children(&self, path: &str) -> bool {
+        let prefix = format!("{path}/");
+        self.dirs
+            .iter()
+            .chain(self.files.keys())
+            .any(|entry| entry.starts_with(&prefix))
+    }
+
+    // Re-key a directory and everything under it.
+    fn move_dir(&mut self, from: &str, to: &str) {
+        let prefix = format!("{from}/");
+        let moved = |entry: &String| {
+            entry
+                .strip_prefix(&prefix)
+                .map(|rest| format!("{to}/{rest}"))
+        };
+        let dirs: Vec<String> = self.dirs.iter().filter_map(moved).collect();
+        self.dirs
+            .retain(|entry| entry != from && !entry.starts_with(&prefix));
+        self.dirs.insert(to.to_string());
+        self.dirs.extend(dirs);
+        let files: Vec<String> = self
+            .files
+            .keys()
+            .filter(|key| key.starts_with(&prefix))
+            .cloned()
+            .collect();
+        for key in files {
+            let node = self.files.remove(&key).expect("listed file");
+            self.files.insert(moved(&key).expect("prefixed key"), node);
+        }
+    }
+}
+
+impl GuestFs for MemoryFs {
+    fn open(&self, path: &str, options: &FsOpenOptions) -> io::Result<Box<dyn GuestFile>> {
+        let path = memory_key(path);
+        let mut tree = self.tree();
+        if tree.is_dir(&path) {
+            return Err(io::ErrorKind::IsADirectory.into());
+        }
+        let node = match tree.files.get(&path) {
+            Some(_) if options.create_new => return Err(io::ErrorKind::AlreadyExists.into()),
+            Some(node) if options.modifies() && lock(node).read_only => {
+                return Err(io::ErrorKind::PermissionDenied.into());
+            }
+            Some(node) => node.clone(),
+            None if options.create || options.create_new => {
+                if !tree.has_parent(&path) {
+                    return Err(io::ErrorKind::NotFound.into());
+                }
+                let node = MemoryNode::new(Vec::new());
+                tree.files.insert(path, node.clone());
+                node
+            }
+            None => return Err(io::ErrorKind::NotFound.into()),
+        };
+        if options.truncate {
+            let mut node = lock(&node);
+            node.data.clear();
+            node.touch();
+        }
+        Ok(Box::new(MemoryFile { node, pos: 0 }))
+    }
+
+    fn metadata(&self, path: &str) -> io::Result<FsMetadata> {
+        let path = memory_key(path);
+        let tree = self.tree();
+        if tree.is_dir(&path) {
+            return Ok(FsMetadata::dir());
+        }
+        let node = tree.files.get(&path).ok_or(io::ErrorKind::NotFound)?;
+        let metadata = lock(node).metadata();
+        Ok(metadata)
+    }
+
+    fn remove_file(&self, path: &str) -> io::Result<()> {
+        let path = memory_key(path);
+        let mut tree = self.tree();
+        let node = tree.files.get(&path).ok_or(io::ErrorKind::NotFound)?;
+        if lock(node).read_only {
+            return Err(io::ErrorKind::PermissionDenied.into());
+        }
+        tree.files.remove(&path);
+        Ok(())
+    }
+
+    fn create_dir_all(&self, path: &str) -> io::Result<()> {
+        let path = memory_key(path);
+        let mut tree = self.tree();
+        if tree.files.contains_key(&path) {
+            return Err(io::ErrorKind::AlreadyExists.into());
+        }
+        tree.add_parents(&path);
+        if !path.is_empty() {
+            tree.dirs.insert(path);
+        }
+        Ok(())
+    }
+
+    fn read_dir(&self, path: &str) -> io::Result<Vec<String>> {
+        let path = memory_key(path);
+        let tree = self.tree();
+        if !tree.is_dir(&path) {
+            return Err(io::ErrorKind::NotFound.into());
+        }
+        let names = tree
+            .dirs
+            .iter()
+            .chain(tree.files.keys())
+            .filter_map(|entry| {
+                let (parent, name) = entry.rsplit_once('/').unwrap_or(("", entry));
+                (parent == path).then(|| name.to_string())
+            })
+            .collect();
+        Ok(names)
+    }
+
+    fn remove_dir(&self, path: &str) -> io::Result<()> {
+        let path = memory_key(path);
+        let mut tree = self.tree();
+        if tree.files.contains_key(&path) {
+            return Err(io::ErrorKind::NotADirectory.into());
+        }
+        if path.is_empty() || !tree.dirs.contains(&path) {
+            return Err(io::ErrorKind::NotFound.into());
+        }
+        if tree.has_children(&path) {
+            return Err(io::ErrorKind::DirectoryNotEmpty.into());
+        }
+        tree.dirs.remove(&path);
+        Ok(())
+    }
+
+    fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+        let (from, to) = (memory_key(from), memory_key(to));
+        let mut tree = self.tree();
+        if !tree.has_parent(&to) {
+            return Err(io::ErrorKind::NotFound.into());
+        }
+        if tree.is_dir(&to) {
+            return Err(io::ErrorKind::AlreadyExists.into());
+        }
+        if let Some(node) = tree.files.remove(&from) {
+            tree.files.insert(to, node);
+            return Ok(());
+        }
+        if from.is_empty() || !tree.dirs.contains(&from) {
+            return Err(io::ErrorKind::NotFound.into());
+        }
+        if tree.files.contains_key(&to) {
+            return Err(io::ErrorKind::AlreadyExists.into());
+        }
+        if to.starts_with(&format!("{from}/")) {
+            return Err(io::ErrorKind::InvalidInput.into());
+        }
+        tree.move_dir(&from, &to);
+        Ok(())
+    }
+
+    fn set_read_only(&self, path: &str, read_only: bool) -> io::Result<()> {
+        let path = memory_key(path);
+        let tree = self.tree();
+        if let Some(node) = tree.files.get(&path) {
+            lock(node).read_only = read_only;
+            return Ok(());
+        }
+        // Windows ignores the read-only attribute on directories.
+        if tree.is_dir(&path) {
+            return Ok(());
+        }
+        Err(io::ErrorKind::NotFound.into())
+    }
+}
+
+struct MemoryFile {
+    node: Arc<Mutex<MemoryNode>>,
+    pos: u64,
+}
+
+impl Read for MemoryFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let node = lock(&self.node);
+        let data = &node.data;
+        let start = usize::try_from(self.pos)
+            .unwrap_or(usize::MAX)
+            .min(data.len());
+        let count = buf.len().min(data.len() - start);
+        buf[..count].copy_from_slice(&data[start..start + count]);
+        self.pos += count as u64;
+        Ok(count)
+    }
+}
+
+impl Write for MemoryFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut node = lock(&self.node);
+        let start = usize::try_from(self.pos).map_err(|_| io::ErrorKind::FileTooLarge)?;
+        let end = start + buf.len();
+        if node.data.len() < end {
+            node.data.resize(end, 0);
+        }
+        node.data[start..end].copy_from_slice(buf);
+        node.touch();
+        self.pos = end as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for MemoryFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let (base, offset) = match pos {
+            SeekFrom::Start(offset) => {
+                self.pos = offset;
+                return Ok(offset);
+            }
+            SeekFrom::Current(offset) => (self.pos, offset),
+            SeekFrom::End(offset) => (lock(&self.node).data.len() as u64, offset),
+        };
+        self.pos = base
+            .checked_add_signed(offset)
+            .ok_or(io::ErrorKind::InvalidInput)?;
+        Ok(self.pos)
+    }
+}
+
+impl GuestFile for MemoryFile {
+    fn size(&mut self) -> io::Result<u64> {
+        Ok(lock(&self.node).data.len() as u64)
+    }
+
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        let len = usize::try_from(len).map_err(|_| io::ErrorKind::FileTooLarge)?;
+        let mut node = lock(&self.node);
+        node.data.resize(len, 0);
+        node.touch();
+        Ok(())
+    }
+
+    fn metadata(&mut self) -> io::Result<FsMetadata> {
+        Ok(lock(&self.node).metadata())
+    }
+
+    fn set_times(&mut self, times: FsTimes) -> io::Result<()> {
+        lock(&self.node).times.update(times);
+        Ok(())
+    }
+}
+
+/// Copy-on-write view of a host directory: files are read from `base` until
+/// the guest modifies them, at which point they are copied into `scratch` and
+/// changed there. `base` is never written. Deleting a file that only exists in
+/// `base` hides it for the lifetime of this overlay and its clones.
+#[derive(Debug, Clone)]
+pub struct OverlayFs {
+    base: PathBuf,
+    scratch: PathBuf,
+    deleted: Arc<Mutex<HashSet<String>>>,
+}
+
+impl OverlayFs {
+    pub fn new(base: impl Into<PathBuf>, scratch: impl Into<PathBuf>) -> Self {
+        Self {
+            base: base.into(),
+            scratch: scratch.into(),
+            deleted: Arc::default(),
+        }
+    }
+
+    pub fn base(&self) -> &Path {
+        &self.base
+    }
+
+    pub fn scratch(&self) -> &Path {
+        &self.scratch
+    }
+
+    fn is_deleted(&self, path: &str) -> bool {
+        lock(&self.deleted).contains(path)
+    }
+
+    fn undelete(&self, path: &str) {
+        lock(&self.deleted).remove(path);
+    }
+
+    // The visible copy of `path`, upper layer first.
+    fn lookup(&self, path: &str) -> Option<PathBuf> {
+        if self.is_deleted(path) {
+            return None;
+        }
+        [&self.scratch, &self.base]
+            .into_iter()
+            .map(|root| host_path(root, path))
+            .find(|path| path.exists())
+    }
+
+    fn copy_up(&self, path: &str, options: &FsOpenOptions) -> io::Result<PathBuf> {
+        let upper = host_path(&self.scratch, path);
+        if upper.exists() {
+            return Ok(upper);
+        }
+        let parent = path.rsplit_once('/').map_or("", |(parent, _)| parent);
+        if !host_path(&self.base, parent).is_dir() && !host_path(&self.scratch, parent).is_dir() {
+            return Err(io::ErrorKind::NotFound.into());
+        }
+        if let Some(parent) = upper.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let lower = host_path(&self.base, path);
+        if !self.is_deleted(path) && lower.is_file() {
+            if options.truncate {
+                fs::File::create(&upper)?;
+            } else {
+                fs::copy(&lower, &upper)?;
+            }
+        }
+        Ok(upper)
+    }
+}
+
+impl GuestFs for OverlayFs {
+    fn open(&self, path: &str, options: &FsOpenOptions) -> io::Result<Box<dyn GuestFile>> {
+        let path = memory_key(path);
+        if !options.modifies() {
+            let host = self.lookup(&path).ok_or(io::ErrorKind::NotFound)?;
+            return Ok(Box::new(options.std_options().open(host)?));
+        }
+        let exists = self.lookup(&path).is_some();
+        if !(exists || options.create || options.create_new) {
+            return Err(io::ErrorKind::NotFound.into());
+        }
+        if exists && options.create_new {
+            return Err(io::ErrorKind::AlreadyExists.into());
+        }
+        let upper = self.copy_up(&path, options)?;
+        let file = options.std_options().open(upper)?;
+        self.undelete(&path);
+        Ok(Box::new(file))
+    }
+
+    fn metadata(&self, path: &str) -> io::Result<FsMetadata> {
+        let host = self
+            .lookup(&memory_key(path))
+            .ok_or(io::ErrorKind::NotFound)?;
+        fs::metadata(host).map(FsMetadata::from)
+    }
+
+    fn remove_file(&self, path: &str) -> io::Result<()> {
+        let path = memory_key(path);
+        let host = self.lookup(&path).ok_or(io::ErrorKind::NotFound)?;
+        if host.is_dir() {
+            return Err(io::ErrorKind::IsADirectory.into());
+        }
+        let upper = host_path(&self.scratch, &path);
+        if upper.exists() {
+            fs::remove_file(upper)?;
+        }
+        if host_path(&self.base, &path).exists() {
+            lock(&self.deleted).insert(path);
+        }
+        Ok(())
+    }
+
+    fn create_dir_all(&self, path: &str) -> io::Result<()> {
+        let path = memory_key(path);
+        fs::create_dir_all(host_path(&self.scratch, &path))?;
+        let mut deleted = lock(&self.deleted);
+        let mut end = path.len();
+        loop {
+            deleted.remove(&path[..end]);
+            match path[..end].rfind('/') {
+                Some(pos) => end = pos,
+                None => return Ok(()),
+            }
+        }
+    }
+
+    fn read_dir(&self, path: &str) -> io::Result<Vec<String>> {
+        let path = memory_key(path);
+        let mut found = false;
+        let mut names = BTreeSet::new();
+        for root in [&self.scratch, &self.base] {
+            let Ok(entries) = host_read_dir(&host_path(root, &path)) else {
+                continue;
+            };
+            found = true;
+            names.extend(entries);
+        }
+        if !found {
+            return Err(io::ErrorKind::NotFound.into());
+        }
+        let names = names
+            .into_iter()
+            .filter(|name| {
+                let entry = if path.is_empty() {
+                    name.clone()
+                } else {
+                    format!("{path}/{name}")
+                };
+                !self.is_deleted(&entry)
+            })
+            .collect();
+        Ok(names)
+    }
+
+    fn remove_dir(&self, path: &str) -> io::Result<()> {
+        let path = memory_key(path);
+        let host = self.lookup(&path).ok_or(io::ErrorKind::NotFound)?;
+        if !host.is_dir() {
+            return Err(io::ErrorKind::NotADirectory.into());
+        }
+        if path.is_empty() {
+            return Err(io::ErrorKind::PermissionDenied.into());
+        }
+        if !self.read_dir(&path)?.is_empty() {
+            return Err(io::ErrorKind::DirectoryNotEmpty.into());
+        }
+        let upper = host_path(&self.scratch, &path);
+        if upper.exists() {
+            fs::remove_dir(upper)?;
+        }
+        if host_path(&self.base, &path).exists() {
+            lock(&self.deleted).insert(path);
+        }
+        Ok(())
+    }
+
+    // Only files move; a directory would have to be copied up whole.
+    fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+        let (from, to) = (memory_key(from), memory_key(to));
+        let host = self.lookup(&from).ok_or(io::ErrorKind::NotFound)?;
+        if host.is_dir() {
+            return Err(io::ErrorKind::Unsupported.into());
+        }
+        if self.lookup(&to).is_some_and(|path| path.is_dir()) {
+            return Err(io::ErrorKind::AlreadyExists.into());
+        }
+        let upper_from = self.copy_up(&from, &FsOpenOptions::new().write(true))?;
+        let upper_to = self.copy_up(&to, &FsOpenOptions::new().write(true).truncate(true))?;
+        fs::rename(upper_from, upper_to)?;
+        self.undelete(&to);
+        if host_path(&self.base, &from).exists() {
+            lock(&self.deleted).insert(from);
+        }
+        Ok(())
+    }
+
+    fn set_read_only(&self, path: &str, read_only: bool) -> io::Result<()> {
+        let path = memory_key(path);
+        let host = self.lookup(&path).ok_or(io::ErrorKind::NotFound)?;
+        if host.is_dir() {
+            return Ok(());
+        }
+        let upper = self.copy_up(&path, &FsOpenOptions::new().write(true))?;
+        host_set_read_only(&upper, read_only)
+    }
+}
+
+fn host_path(root: &Path, path: &str) -> PathBuf {
+    if root.as_os_str().is_empty() {
+        return PathBuf::from(path);
+    }
+    let mut host = root.to_path_buf();
+    host.extend(path.split(['/', '\\']).filter(|part| !part.is_empty()));
+    host
+}
+
+fn host_read_dir(dir: &Path) -> io::Result<Vec<String>> {
+    let mut names = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        names.push(entry?.file_name().to_string_lossy().into_owned());
+    }
+    Ok(names)
+}
+
+fn host_set_read_only(path: &Path, read_only: bool) -> io::Result<()> {
+    let mut permissions = fs::metadata(path)?.permissions();
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = permissions.mode();
+        permissions.set_mode(if read_only {
+            mode & !0o222
+        } else {
+            mode | 0o200
+        });
+    }
+    #[cfg(not(unix))]
+    permissions.set_readonly(read_only);
+    fs::set_permissions(path, permissions)
+}
+
+fn memory_key(path: &str) -> String {
+    path.split(['/', '\\'])
+        .filter(|part| !part.is_empty())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+// A panicking guest call must not wedge every later file operation.
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("pe_vm_vfs_{name}_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).expect("temp dir");
+        dir
+    }
+
+    fn read_all(fs: &dyn GuestFs, path: &str) -> Vec<u8> {
+        let mut file = fs
+            .open(path, &FsOpenOptions::new().read(true))
+            .expect("open");
+        let mut data = Vec::new();
+        file.read_to_end(&mut data).expect("read");
+        data
+    }
+
+    #[test]
+    fn memory_fs_shares_contents_between_clones() {
+        let fs = MemoryFs::new();
+        fs.insert("data/in.bin", b"abc".to_vec());
+        let guest = fs.clone();
+        let options = FsOpenOptions::new().write(true).create(true);
+        let mut file = guest.open("data/out.txt", &options).expect("create");
+        file.write_all(b"hello").expect("write");
+        file.seek(SeekFrom::Start(1)).expect("seek");
+        file.write_all(b"E").expect("write");
+        drop(file);
+
+        assert_eq!(fs.get("data/out.txt").as_deref(), Some(&b"hEllo"[..]));
+        assert!(guest.metadata("data").expect("dir").is_dir());
+        let mut names = guest.read_dir("data").expect("list");
+        names.sort();
+        assert_eq!(names, ["in.bin", "out.txt"]);
+        assert_eq!(guest.read_dir("").expect("root"), ["data"]);
+        assert_eq!(read_all(&guest, "data/in.bin"), b"abc");
+        assert!(guest.open("missing/out.txt", &options).is_err());
+        guest.remove_file("data/in.bin").expect("remove");
+        assert_eq!(fs.files(), ["data/out.txt"]);
+    }
+
+    #[test]
+    fn overlay_writes_to_scratch_and_leaves_base_alone() {
+        let base = temp_dir("overlay_base");
+        let scratch = temp_dir("overlay_scratch");
+        fs::create_dir_all(base.join("cfg")).expect("dir");
+        fs::write(base.join("cfg").join("app.ini"), b"[app]\nmode=1\n").expect("seed");
+        let overlay = OverlayFs::new(&base, &scratch);
+
+        let options = FsOpenOptions::new().read(true).write(true);
+        let mut file = overlay.open("cfg/app.ini", &options).expect("open");
+        file.seek(SeekFrom::End(-2)).expect("seek");
+        file.write_all(b"2\n").expect("write");
+        file.sync().expect("sync");
+        drop(file);
+
+        assert_eq!(read_all(&overlay, "cfg/app.ini"), b"[app]\nmode=2\n");
+        assert_eq!(
+            fs::read(base.join("cfg").join("app.ini")).expect("base"),
+            b"[app]\nmode=1\n"
+        );
+        assert_eq!(
+            fs::read(scratch.join("cfg").join("app.ini")).expect("scratch"),
+            b"[app]\nmode=2\n"
+        );
+
+        overlay.remove_file("cfg/app.ini").expect("remove");
+        assert!(overlay.metadata("cfg/app.ini").is_err());
+        assert!(overlay.read_dir("cfg").expect("list").is_empty());
+        assert!(base.join("cfg").join("app.ini").exists());
+        let create = FsOpenOptions::new().write(true).create(true);
+        drop(overlay.open("cfg/app.ini", &create).expect("recreate"));
+        assert_eq!(overlay.metadata("cfg/app.ini").expect("meta").size(), 0);
+
+        let _ = fs::remove_dir_all(base);
+        let _ = fs::remove_dir_all(scratch);
+    }
+
+    #[test]
+    fn memory_fs_moves_directories_and_honors_read_only() {
+        let fs = MemoryFs::new();
+        fs.insert("old/sub/a.txt", b"a".to_vec());
+        fs.insert("old/b.txt", b"b".to_vec());
+        fs.create_dir_all("empty").expect("mkdir");
+
+        fs.rename("old", "new").expect("move dir");
+        assert_eq!(fs.files(), ["new/b.txt", "new/sub/a.txt"]);
+        assert!(fs.metadata("old").is_err());
+        assert_eq!(
+            fs.remove_dir("new").map_err(|err| err.kind()),
+            Err(io::ErrorKind::DirectoryNotEmpty)
+        );
+        fs.remove_dir("empty").expect("rmdir");
+        assert!(fs.metadata("empty").is_err());
+
+        fs.set_read_only("new/b.txt", true).expect("attrib");
+        assert!(fs.metadata("new/b.txt").expect("meta").read_only_value());
+        let write = FsOpenOptions::new().write(true);
+        assert!(fs.open("new/b.txt", &write).is_err());
+        assert!(fs.remove_file("new/b.txt").is_err());
+        fs.set_read_only("new/b.txt", false).expect("attrib");
+
+        let mut file = fs.open("new/b.txt", &write).expect("open");
+        let earlier = SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1_000_000);
+        file.set_times(FsTimes::new().modified(earlier))
+            .expect("times");
+        let times = file.metadata().expect("meta").times_value();
+        assert_eq!(times.modified_value(), Some(earlier));
+        assert!(times.created_value().is_some());
+    }
+
+    #[test]
+    fn overlay_rename_hides_the_base_copy() {
+        let base = temp_dir("rename_base");
+        let scratch = temp_dir("rename_scratch");
+        fs::write(base.join("a.txt"), b"base").expect("seed");
+        let overlay = OverlayFs::new(&base, &scratch);
+
+        overlay.rename("a.txt", "b.txt").expect("rename");
+        assert!(overlay.metadata("a.txt").is_err());
+        assert_eq!(read_all(&overlay, "b.txt"), b"base");
+        assert!(base.join("a.txt").exists());
+        assert_eq!(overlay.read_dir("").expect("list"), ["b.txt"]);
+
+        let _ = fs::remove_dir_all(base);
+        let _ = fs::remove_dir_all(scratch);
+    }
+}
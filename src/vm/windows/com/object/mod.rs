@@ -187,8 +187,8 @@ impl ComObject {
             ComBackend::Dispatch(dispatch) => dispatch.invoke(vm, dispid, args),
             ComBackend::InProc(inproc) => inproc.invoke(vm, dispid, args, flags),
         };
-        // Drain queued threads after COM calls to emulate asynchronous work.
-        let _ = vm.run_pending_threads();
+        // Let guest threads started by the call make progress, emulating asynchronous work.
+        let _ = vm.run_other_threads();
         result
     }
 }
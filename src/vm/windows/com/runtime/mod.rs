@@ -34,7 +34,7 @@ impl Com {
         dispatch: &DispatchHandle,
     ) -> Result<ComObject, VmError> {
         let (normalized, dll_path, host_path) = loader::resolve_inproc_path(vm, dispatch.clsid())?;
-        let image = std::fs::read(&host_path)?;
+        let image = vm.file_read_all(&dll_path)?;
         let _ = PeFile::parse(&image)?;
 
         Ok(ComObject::new_dispatch(
@@ -47,7 +47,7 @@ impl Com {
 
     pub fn create_instance_inproc(&self, vm: &mut Vm, clsid: &str) -> Result<ComObject, VmError> {
         let (normalized, dll_path, host_path) = loader::resolve_inproc_path(vm, clsid)?;
-        let image = std::fs::read(&host_path)?;
+        let image = vm.file_read_all(&dll_path)?;
         let file = PeFile::parse(&image)?;
         let typelib = match crate::vm::windows::oleaut32::typelib::load_from_bytes(&image) {
             Ok(lib) => Some(lib),
@@ -9,13 +9,14 @@ pub(super) fn recover_dispatch_from_heap(
     internal_create: u32,
 ) -> Option<u32> {
     let vtable = find_vtable_from_internal_create(vm, file, internal_create)?;
+    let allocations = vm.heap_allocations();
     if std::env::var("PE_VM_TRACE").is_ok() {
         eprintln!(
             "[pe_vm] recover IDispatch: vtable=0x{vtable:08X} heap_allocs={}",
-            vm.heap_allocs.len()
+            allocations.len()
         );
     }
-    for (&ptr, &size) in vm.heap_allocs.iter() {
+    for (ptr, size) in allocations {
         if size < 4 {
             continue;
         }
@@ -40,7 +41,9 @@ pub(super) fn recover_dispatch_from_heap(
         }
         let size = section.virtual_size.max(section.raw_size) as usize;
         let start = section.virtual_address as usize;
-        let end = start.saturating_add(size).min(vm.memory.len());
+        let end = start
+            .saturating_add(size)
+            .min(file.optional_header.size_of_image as usize);
         let mut offset = start;
         while offset + 4 <= end {
             let addr = base.wrapping_add(offset as u32);
@@ -0,0 +1,158 @@
+use std::collections::HashSet;
+
+use crate::architecture::intel::x86::disasm::{self, Flow};
+use crate::vm::*;
+
+impl Vm {
+    /// Start recording which guest instructions run. Collection continues across
+    /// calls until `stop_coverage`; starting again keeps what was collected.
+    pub fn start_coverage(&mut self) {
+        self.coverage.get_or_insert_with(CoverageCollector::default);
+    }
+
+    pub fn is_collecting_coverage(&self) -> bool {
+        self.coverage.is_some()
+    }
+
+    /// Basic blocks executed since collection started, per loaded module. Code
+    /// outside guest images (heap thunks, host stubs) is not attributed.
+    pub fn coverage(&self) -> Coverage {
+        let Some(collector) = &self.coverage else {
+            return Coverage::new();
+        };
+        let mut coverage = collector.done.clone();
+        self.attribute_coverage(&collector.hits, None, &mut coverage);
+        coverage
+    }
+
+    /// Return the coverage so far and start over with none, still collecting.
+    pub fn take_coverage(&mut self) -> Coverage {
+        let coverage = self.coverage();
+        if self.coverage.is_some() {
+            self.coverage = Some(CoverageCollector::default());
+        }
+        coverage
+    }
+
+    /// Stop collecting and return what was collected.
+    pub fn stop_coverage(&mut self) -> Coverage {
+        let coverage = self.coverage();
+        self.coverage = None;
+        coverage
+    }
+
+    /// Attribute hits in the module at `base` (every module when `None`) before
+    /// its image goes away.
+    pub(super) fn flush_coverage(&mut self, base: Option<u32>) {
+        let Some(mut collector) = self.coverage.take() else {
+            return;
+        };
+        self.attribute_coverage(&collector.hits, base, &mut collector.done);
+        match base.and_then(|base| self.modules.by_handle(base)) {
+            Some(module) => collector.hits.retain(|addr| !module.contains(*addr)),
+            None => collector.hits.clear(),
+        }
+        self.coverage = Some(collector);
+    }
+
+    // Group executed instructions into blocks: runs of adjacent instructions that
+    // end at the first branch, call or return.
+    fn attribute_coverage(&self, hits: &HashSet<u32>, only: Option<u32>, coverage: &mut Coverage) {
+        let modules = self
+            .modules
+            .iter()
+            .filter(|module| !module.is_host() && only.is_none_or(|base| module.base() == base));
+        for module in modules {
+            let mut addrs: Vec<u32> = hits
+                .iter()
+                .copied()
+                .filter(|addr| module.contains(*addr))
+                .collect();
+            addrs.sort_unstable();
+            let mut idx = 0;
+            while idx < addrs.len() {
+                let start = addrs[idx];
+                let mut end;
+                loop {
+                    let ins = disasm::disassemble_with(|at| self.peek_u8(at), addrs[idx], 1).pop();
+                    end =
+                        addrs[idx].wrapping_add(ins.as_ref().map_or(1, |ins| ins.length() as u32));
+                    idx += 1;
+                    let sequential = ins.is_some_and(|ins| ins.flow == Flow::Sequential);
+                    if !sequential || idx == addrs.len() || addrs[idx] != end {
+                        break;
+                    }
+                }
+                coverage.add_block(
+                    module.path(),
+                    module.base(),
+                    module.size(),
+                    start - module.base(),
+                    end.wrapping_sub(start),
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::vm::*;
+
+    fn create_test_vm() -> Vm {
+        let mut vm = Vm::new(VmConfig::new().architecture(Architecture::X86)).expect("vm");
+        vm.memory.map(0x1000, 0x10000);
+        vm.base = 0x1000;
+        vm.stack_top = 0x1000 + 0x10000 - 4;
+        vm.regs.esp = vm.stack_top;
+        vm.fs_base = 0x1000;
+        vm.write_u32(0x1000, 0xFFFF_FFFF).expect("teb");
+        vm.modules.insert(LoadedModule::guest(
+            "C:\\app\\legacy.dll",
+            0x2000,
+            0x4000,
+            0,
+            Vec::new(),
+            true,
+        ));
+        vm
+    }
+
+    #[test]
+    fn collects_basic_blocks_per_module_by_rva() {
+        let mut vm = create_test_vm();
+        let code: &[u8] = &[
+            0x31, 0xC0, // xor eax, eax
+            0x83, 0xF9, 0x00, // cmp ecx, 0
+            0x74, 0x01, // je +1
+            0x40, // inc eax
+            0xE8, 0x03, 0x00, 0x00, 0x00, // call 0x3010
+            0xC3, // ret
+            0x90, 0x90, // (never run)
+            0x40, // inc eax
+            0xC3, // ret
+        ];
+        vm.write_bytes(0x3000, code).expect("code");
+        vm.start_coverage();
+        vm.regs.ecx = 0;
+        vm.execute(0x3000).expect("run");
+
+        let coverage = vm.coverage();
+        let module = coverage.module("LEGACY.DLL").expect("module");
+        assert_eq!(module.name(), "legacy.dll");
+        // The je was taken, so `inc eax` at 0x1007 never ran.
+        assert_eq!(
+            module.blocks().collect::<Vec<_>>(),
+            [(0x1000, 7), (0x1008, 5), (0x100D, 1), (0x1010, 2)]
+        );
+
+        // A second run that falls through adds the missing instruction.
+        vm.regs.ecx = 1;
+        vm.execute(0x3000).expect("run");
+        let module = vm.stop_coverage();
+        let module = module.module("legacy.dll").expect("module");
+        assert!(module.contains(0x1007));
+        assert!(!module.contains(0x100E));
+        assert!(!vm.is_collecting_coverage());
+    }
+}
@@ -0,0 +1,428 @@
+use std::collections::VecDeque;
+use std::io;
+use std::sync::{Arc, Mutex};
+
+use crate::vm::*;
+
+const CTRL_Z: u8 = 0x1A;
+
+impl CrtIo {
+    /// Descriptors 0, 1 and 2 start out as text-mode console streams.
+    pub(crate) fn new() -> Self {
+        Self {
+            fds: (0..3)
+                .map(|fd| (fd, CrtFd::new(CrtTarget::Console, true)))
+                .collect(),
+            iob: 0,
+            streams: Vec::new(),
+            errno: 0,
+            umask: 0,
+            ungetch: None,
+            tmp_next: 1,
+            tmpnam: 0,
+        }
+    }
+}
+
+impl CrtFd {
+    pub(crate) fn new(target: CrtTarget, text: bool) -> Self {
+        Self {
+            target,
+            text,
+            append: false,
+            eof: false,
+            delete_on_close: None,
+        }
+    }
+}
+
+impl Vm {
+    /// Open `path` on the lowest free descriptor. `temporary` files are
+    /// deleted when their last descriptor closes.
+    pub(crate) fn crt_open(
+        &mut self,
+        path: &str,
+        options: FsOpenOptions,
+        text: bool,
+        append: bool,
+        temporary: bool,
+    ) -> io::Result<i32> {
+        if self
+            .file_metadata(path)
+            .is_ok_and(|metadata| metadata.is_dir())
+        {
+            return Err(io::ErrorKind::PermissionDenied.into());
+        }
+        let handle = self.file_open(path, options).map_err(|err| match err {
+            VmError::Io(err) => err,
+            _ => io::ErrorKind::NotFound.into(),
+        })?;
+        let mut fd = CrtFd::new(CrtTarget::File(handle), text);
+        fd.append = append;
+        fd.delete_on_close = temporary.then(|| path.to_string());
+        Ok(self.crt_insert_fd(fd))
+    }
+
+    /// Bind `fd` to the lowest free descriptor number.
+    pub(crate) fn crt_insert_fd(&mut self, fd: CrtFd) -> i32 {
+        let free = (0..)
+            .find(|number| !self.crt_io.fds.contains_key(number))
+            .unwrap_or_default();
+        self.crt_io.fds.insert(free, fd);
+        free
+    }
+
+    pub(crate) fn crt_fd(&self, fd: i32) -> Option<&CrtFd> {
+        self.crt_io.fds.get(&fd)
+    }
+
+    pub(crate) fn crt_fd_mut(&mut self, fd: i32) -> Option<&mut CrtFd> {
+        self.crt_io.fds.get_mut(&fd)
+    }
+
+    /// Create a pipe and return its read and write descriptors.
+    pub(crate) fn crt_pipe(&mut self, text: bool) -> (i32, i32) {
+        let queue = Arc::new(Mutex::new(VecDeque::new()));
+        let read = self.crt_insert_fd(CrtFd::new(CrtTarget::Pipe(queue.clone()), text));
+        let write = self.crt_insert_fd(CrtFd::new(CrtTarget::Pipe(queue), text));
+        (read, write)
+    }
+
+    /// Close a descriptor. The file handle behind it closes with the last
+    /// descriptor using it. Returns `false` for unknown descriptors.
+    pub(crate) fn crt_close(&mut self, fd: i32) -> bool {
+        let Some(entry) = self.crt_io.fds.remove(&fd) else {
+            return false;
+        };
+        if let CrtTarget::File(handle) = entry.target {
+            let shared = self
+                .crt_io
+                .fds
+                .values()
+                .any(|other| matches!(other.target, CrtTarget::File(other) if other == handle));
+            if !shared {
+                self.file_close(handle);
+                if let Some(path) = entry.delete_on_close {
+                    let _ = self.file_delete(&path);
+                }
+            }
+        }
+        true
+    }
+
+    pub(crate) fn crt_dup(&mut self, fd: i32) -> Option<i32> {
+        let copy = self.crt_io.fds.get(&fd)?.clone();
+        Some(self.crt_insert_fd(copy))
+    }
+
+    /// Make `target` another descriptor for `fd`, closing what it was.
+    pub(crate) fn crt_dup2(&mut self, fd: i32, target: i32) -> bool {
+        let Some(copy) = self.crt_io.fds.get(&fd).cloned() else {
+            return false;
+        };
+        if fd != target {
+            self.crt_close(target);
+            self.crt_io.fds.insert(target, copy);
+        }
+        true
+    }
+
+    /// Read up to `len` bytes. Text-mode descriptors turn CRLF into LF and stop
+    /// at Ctrl+Z, so fewer bytes than available may come back. `None` for
+    /// unknown descriptors.
+    pub(crate) fn crt_read(&mut self, fd: i32, len: usize) -> Option<io::Result<Vec<u8>>> {
+        let entry = self.crt_io.fds.get(&fd)?;
+        if entry.eof || len == 0 {
+            return Some(Ok(Vec::new()));
+        }
+        let (target, text) = (entry.target.clone(), entry.text);
+        let raw = match self.crt_read_raw(&target, len) {
+            Ok(raw) => raw,
+            Err(err) => return Some(Err(err)),
+        };
+        if !text {
+            return Some(Ok(raw));
+        }
+        let mut data = Vec::with_capacity(raw.len());
+        let mut index = 0;
+        while index < raw.len() {
+            let byte = raw[index];
+            index += 1;
+            match byte {
+                CTRL_Z => {
+                    if let Some(entry) = self.crt_io.fds.get_mut(&fd) {
+                        entry.eof = true;
+                    }
+                    break;
+                }
+                b'\r' if index < raw.len() => {
+                    if raw[index] == b'\n' {
+                        index += 1;
+                        data.push(b'\n');
+                    } else {
+                        data.push(b'\r');
+                    }
+                }
+                // A CR ending the chunk needs one more byte to decide.
+                b'\r' => {
+                    let next = self.crt_read_raw(&target, 1).unwrap_or_default();
+                    if next.first() == Some(&b'\n') {
+                        data.push(b'\n');
+                    } else {
+                        data.push(b'\r');
+                        if let Some(&byte) = next.first() {
+                            self.crt_unread(&target, byte);
+                        }
+                    }
+                }
+                _ => data.push(byte),
+            }
+        }
+        Some(Ok(data))
+    }
+
+    fn crt_read_raw(&mut self, target: &CrtTarget, len: usize) -> io::Result<Vec<u8>> {
+        match target {
+            CrtTarget::File(handle) => self
+                .file_read(*handle, len)
+                .unwrap_or_else(|| Err(io::ErrorKind::InvalidInput.into())),
+            CrtTarget::Console => Ok(Vec::new()),
+            CrtTarget::Pipe(queue) => {
+                let mut queue = queue.lock().map_err(|_| io::ErrorKind::BrokenPipe)?;
+                let take = len.min(queue.len());
+                Ok(queue.drain(..take).collect())
+            }
+        }
+    }
+
+    fn crt_unread(&mut self, target: &CrtTarget, byte: u8) {
+        match target {
+            CrtTarget::File(handle) => {
+                self.file_seek(*handle, -1, 1);
+            }
+            CrtTarget::Console => {}
+            CrtTarget::Pipe(queue) => {
+                if let Ok(mut queue) = queue.lock() {
+                    queue.push_front(byte);
+                }
+            }
+        }
+    }
+
+    /// Write `bytes`, returning how many of them were consumed. Text-mode
+    /// descriptors write LF as CRLF, except on the console, whose host side
+    /// expects plain newlines. `None` for unknown descriptors.
+    pub(crate) fn crt_write(&mut self, fd: i32, bytes: &[u8]) -> Option<io::Result<usize>> {
+        let entry = self.crt_io.fds.get(&fd)?;
+        let (target, append) = (entry.target.clone(), entry.append);
+        let data = if entry.text && !matches!(target, CrtTarget::Console) {
+            let mut data = Vec::with_capacity(bytes.len());
+            for &byte in bytes {
+                if byte == b'\n' {
+                    data.push(b'\r');
+                }
+                data.push(byte);
+            }
+            data
+        } else {
+            bytes.to_vec()
+        };
+        let result = match target {
+            CrtTarget::File(handle) => {
+                if append {
+                    self.file_seek(handle, 0, 2);
+                }
+                self.file_write(handle, &data)
+                    .unwrap_or_else(|| Err(io::ErrorKind::InvalidInput.into()))
+            }
+            CrtTarget::Console => {
+                self.write_stdout(&String::from_utf8_lossy(&data));
+                Ok(data.len())
+            }
+            CrtTarget::Pipe(queue) => match queue.lock() {
+                Ok(mut queue) => {
+                    queue.extend(&data);
+                    Ok(data.len())
+                }
+                Err(_) => Err(io::ErrorKind::BrokenPipe.into()),
+            },
+        };
+        Some(result.map(|_| bytes.len()))
+    }
+
+    /// Move a file descriptor's position, `origin` being `SEEK_SET`,
+    /// `SEEK_CUR` or `SEEK_END`. Seeking clears a text-mode Ctrl+Z stop.
+    pub(crate) fn crt_seek(
+        &mut self,
+        fd: i32,
+        offset: i64,
+        origin: u32,
+    ) -> Option<io::Result<u64>> {
+        let entry = self.crt_io.fds.get_mut(&fd)?;
+        let CrtTarget::File(handle) = entry.target else {
+            return Some(Err(io::ErrorKind::NotSeekable.into()));
+        };
+        entry.eof = false;
+        Some(
+            self.file_seek(handle, offset, origin)
+                .ok_or_else(|| io::ErrorKind::InvalidInput.into()),
+        )
+    }
+
+    pub(crate) fn crt_fd_size(&mut self, fd: i32) -> Option<io::Result<u64>> {
+        let CrtTarget::File(handle) = self.crt_io.fds.get(&fd)?.target else {
+            return Some(Err(io::ErrorKind::NotSeekable.into()));
+        };
+        Some(
+            self.file_size(handle)
+                .ok_or_else(|| io::ErrorKind::InvalidInput.into()),
+        )
+    }
+
+    /// Cut or zero-extend a file to `size` bytes, keeping its position.
+    pub(crate) fn crt_set_size(&mut self, fd: i32, size: u64) -> Option<io::Result<()>> {
+        let CrtTarget::File(handle) = self.crt_io.fds.get(&fd)?.target else {
+            return Some(Err(io::ErrorKind::InvalidInput.into()));
+        };
+        let (Some(pos), Ok(size)) = (self.file_seek(handle, 0, 1), i64::try_from(size)) else {
+            return Some(Err(io::ErrorKind::InvalidInput.into()));
+        };
+        self.file_seek(handle, size, 0);
+        let result = self
+            .file_set_end(handle)
+            .unwrap_or_else(|| Err(io::ErrorKind::InvalidInput.into()));
+        self.file_seek(handle, pos as i64, 0);
+        Some(result)
+    }
+
+    /// Push buffered writes to the backing store (`_commit`).
+    pub(crate) fn crt_commit(&mut self, fd: i32) -> Option<io::Result<()>> {
+        match self.crt_io.fds.get(&fd)?.target {
+            CrtTarget::File(handle) => Some(self.file_flush(handle).unwrap_or(Ok(()))),
+            _ => Some(Ok(())),
+        }
+    }
+
+    /// Metadata of the file behind a descriptor; `None` for unknown
+    /// descriptors and for consoles and pipes.
+    pub(crate) fn crt_fd_metadata(&mut self, fd: i32) -> Option<io::Result<FsMetadata>> {
+        let CrtTarget::File(handle) = self.crt_io.fds.get(&fd)?.target else {
+            return None;
+        };
+        self.file_handle_metadata(handle)
+    }
+
+    pub(crate) fn crt_iob(&self) -> u32 {
+        self.crt_io.iob
+    }
+
+    pub(crate) fn set_crt_iob(&mut self, iob: u32) {
+        self.crt_io.iob = iob;
+    }
+
+    pub(crate) fn crt_streams(&self) -> &[u32] {
+        &self.crt_io.streams
+    }
+
+    pub(crate) fn crt_add_stream(&mut self, stream: u32) {
+        self.crt_io.streams.push(stream);
+    }
+
+    /// Guest address of the `errno` cell, followed by `_doserrno`. Allocated
+    /// on first use.
+    pub(crate) fn crt_errno_cell(&mut self) -> u32 {
+        if self.crt_io.errno == 0 {
+            self.crt_io.errno = self.heap_alloc(8);
+        }
+        self.crt_io.errno
+    }
+
+    /// Replace the `_umask` permission mask, returning the previous one.
+    pub(crate) fn crt_set_umask(&mut self, mask: u32) -> u32 {
+        std::mem::replace(&mut self.crt_io.umask, mask)
+    }
+
+    pub(crate) fn crt_umask(&self) -> u32 {
+        self.crt_io.umask
+    }
+
+    /// Push back a console key. Only one key can wait; returns `false` when
+    /// one already does.
+    pub(crate) fn crt_set_ungetch(&mut self, key: u32) -> bool {
+        if self.crt_io.ungetch.is_some() {
+            return false;
+        }
+        self.crt_io.ungetch = Some(key);
+        true
+    }
+
+    pub(crate) fn crt_take_ungetch(&mut self) -> Option<u32> {
+        self.crt_io.ungetch.take()
+    }
+
+    pub(crate) fn crt_peek_ungetch(&self) -> Option<u32> {
+        self.crt_io.ungetch
+    }
+
+    /// The next `tmpnam` sequence number.
+    pub(crate) fn crt_next_tmp(&mut self) -> u32 {
+        let next = self.crt_io.tmp_next;
+        self.crt_io.tmp_next = next.wrapping_add(1).max(1);
+        next
+    }
+
+    /// The static `tmpnam` result buffer, room for `MAX_PATH` wide characters.
+    pub(crate) fn crt_tmpnam_buffer(&mut self) -> u32 {
+        if self.crt_io.tmpnam == 0 {
+            self.crt_io.tmpnam = self.heap_alloc(520);
+        }
+        self.crt_io.tmpnam
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::vm::*;
+
+    #[test]
+    fn text_descriptors_translate_line_ends() {
+        let fs = MemoryFs::new();
+        let mut vm = Vm::new(VmConfig::new().mount("C:\\data", fs.clone())).expect("vm");
+        let options = FsOpenOptions::new().read(true).write(true).create(true);
+
+        let fd = vm
+            .crt_open("C:\\data\\notes.txt", options, true, false, false)
+            .expect("open");
+        assert_eq!(fd, 3);
+        assert_eq!(
+            vm.crt_write(fd, b"one\ntwo\n").expect("fd").expect("write"),
+            8
+        );
+        let copy = vm.crt_dup(fd).expect("dup");
+        assert!(vm.crt_close(fd));
+        vm.crt_seek(copy, 0, 0).expect("fd").expect("seek");
+        let raw = vm.file_read_all("C:\\data\\notes.txt").expect("raw");
+        assert_eq!(raw, b"one\r\ntwo\r\n");
+        // A CR at the end of a chunk peeks at the next byte.
+        assert_eq!(vm.crt_read(copy, 4).expect("fd").expect("read"), b"one\n");
+        assert_eq!(vm.crt_read(copy, 64).expect("fd").expect("read"), b"two\n");
+        assert!(vm.crt_close(copy));
+        assert!(vm.crt_read(copy, 1).is_none());
+
+        let temp = vm
+            .crt_open("C:\\data\\scratch.tmp", options, false, false, true)
+            .expect("temp");
+        vm.crt_write(temp, b"\x1A\r\n").expect("fd").expect("write");
+        vm.crt_fd_mut(temp).expect("fd").text = true;
+        vm.crt_seek(temp, 0, 0).expect("fd").expect("seek");
+        assert!(vm.crt_read(temp, 8).expect("fd").expect("read").is_empty());
+        assert!(vm.crt_fd(temp).expect("fd").eof);
+        vm.crt_close(temp);
+        assert!(!vm.file_exists("C:\\data\\scratch.tmp"));
+
+        let (read, write) = vm.crt_pipe(false);
+        vm.crt_write(write, b"piped").expect("fd").expect("write");
+        assert_eq!(vm.crt_read(read, 3).expect("fd").expect("read"), b"pip");
+        assert!(vm.crt_seek(read, 0, 0).expect("fd").is_err());
+    }
+}
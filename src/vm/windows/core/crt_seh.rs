@@ -0,0 +1,156 @@
+use crate::vm::*;
+
+// MSVC `__try` frames extend the EXCEPTION_REGISTRATION_RECORD `{Next, Handler}`:
+//   [frame - 8]   saved ESP for the __except block
+//   [frame - 4]   EXCEPTION_POINTERS seen by GetExceptionInformation
+//   [frame + 8]   scope table (XOR-encoded with the security cookie for EH4)
+//   [frame + 12]  current try level
+// and the function's EBP sits right above them, at frame + 16.
+const FRAME_SAVED_ESP: u32 = 8;
+const FRAME_EXCEPTION_POINTERS: u32 = 4;
+const FRAME_SCOPE_TABLE: u32 = 0x08;
+const FRAME_TRY_LEVEL: u32 = 0x0C;
+const FRAME_EBP: u32 = 0x10;
+
+// Scope records: {EnclosingLevel, FilterFunc, HandlerFunc}. A null filter marks __finally.
+const SCOPE_RECORD_SIZE: u32 = 12;
+// EH4 tables start with the GS/EH cookie offsets.
+const EH4_SCOPE_RECORDS: u32 = 0x10;
+
+/// Scope-table flavour of `_except_handler3` and `_except_handler4`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ScopeTable {
+    Eh3,
+    Eh4 { cookie: u32 },
+}
+
+impl ScopeTable {
+    fn new(cookie: Option<u32>) -> Self {
+        match cookie {
+            Some(cookie) => ScopeTable::Eh4 { cookie },
+            None => ScopeTable::Eh3,
+        }
+    }
+
+    fn outermost(self) -> u32 {
+        match self {
+            ScopeTable::Eh3 => 0xFFFF_FFFF,
+            ScopeTable::Eh4 { .. } => 0xFFFF_FFFE,
+        }
+    }
+
+    fn record(self, encoded_table: u32, level: u32) -> u32 {
+        let (table, records) = match self {
+            ScopeTable::Eh3 => (encoded_table, 0),
+            ScopeTable::Eh4 { cookie } => (encoded_table ^ cookie, EH4_SCOPE_RECORDS),
+        };
+        table
+            .wrapping_add(records)
+            .wrapping_add(level.wrapping_mul(SCOPE_RECORD_SIZE))
+    }
+}
+
+impl Vm {
+    /// `_except_handler3`/`_except_handler4_common`: run the `__except` filters of
+    /// `frame` from its current try level outwards. A filter that accepts unwinds the
+    /// frames above, runs the inner `__finally` blocks and hands control to its block.
+    ///
+    /// `cookie` is the security cookie EH4 scope tables are encoded with; `None` means EH3.
+    pub(crate) fn scope_table_handler(
+        &mut self,
+        cookie: Option<u32>,
+        record: u32,
+        frame: u32,
+        context: u32,
+    ) -> Result<u32, VmError> {
+        let kind = ScopeTable::new(cookie);
+        let flags = self.read_u32(record + 4)?;
+        if flags & (EXCEPTION_UNWINDING | EXCEPTION_EXIT_UNWIND) != 0 {
+            self.local_unwind(cookie, frame, kind.outermost())?;
+            return Ok(DISPOSITION_CONTINUE_SEARCH);
+        }
+        let ebp = frame + FRAME_EBP;
+        let table = self.read_u32(frame + FRAME_SCOPE_TABLE)?;
+        let saved_esp = self.regs.esp;
+        let pointers = saved_esp.wrapping_sub(8);
+        self.write_u32(pointers, record)?;
+        self.write_u32(pointers + 4, context)?;
+        self.write_u32(frame - FRAME_EXCEPTION_POINTERS, pointers)?;
+        self.regs.esp = pointers;
+        let result = (|| {
+            let mut level = self.read_u32(frame + FRAME_TRY_LEVEL)?;
+            while level != kind.outermost() {
+                let scope = kind.record(table, level);
+                let enclosing = self.read_u32(scope)?;
+                let filter = self.read_u32(scope + 4)?;
+                if filter != 0 {
+                    let verdict = self.call_guest(filter, Some(ebp), &[])? as i32;
+                    if verdict < 0 {
+                        return Ok(DISPOSITION_CONTINUE_EXECUTION);
+                    }
+                    if verdict >= EXCEPTION_EXECUTE_HANDLER as i32 {
+                        let handler = self.read_u32(scope + 8)?;
+                        self.unwind_frames(frame, record)?;
+                        self.local_unwind(cookie, frame, level)?;
+                        self.write_u32(frame + FRAME_TRY_LEVEL, enclosing)?;
+                        let esp = self.read_u32(frame - FRAME_SAVED_ESP)?;
+                        self.transfer_to_handler(handler, esp, ebp);
+                        return Ok(DISPOSITION_CONTINUE_SEARCH);
+                    }
+                }
+                // Enclosing scopes always have lower indices; anything else is corrupt.
+                if enclosing != kind.outermost() && enclosing as i32 >= level as i32 {
+                    break;
+                }
+                level = enclosing;
+            }
+            Ok(DISPOSITION_CONTINUE_SEARCH)
+        })();
+        self.regs.esp = saved_esp;
+        result
+    }
+
+    /// `_local_unwind2`/`_local_unwind4`: run the `__finally` blocks of `frame` from its
+    /// current try level out to `stop_level`.
+    pub(crate) fn local_unwind(
+        &mut self,
+        cookie: Option<u32>,
+        frame: u32,
+        stop_level: u32,
+    ) -> Result<(), VmError> {
+        let kind = ScopeTable::new(cookie);
+        let ebp = frame + FRAME_EBP;
+        let table = self.read_u32(frame + FRAME_SCOPE_TABLE)?;
+        loop {
+            let level = self.read_u32(frame + FRAME_TRY_LEVEL)?;
+            if level == kind.outermost() || level == stop_level {
+                return Ok(());
+            }
+            let scope = kind.record(table, level);
+            let enclosing = self.read_u32(scope)?;
+            let filter = self.read_u32(scope + 4)?;
+            let handler = self.read_u32(scope + 8)?;
+            // Leave the scope first so a fault in the __finally block does not rerun it.
+            self.write_u32(frame + FRAME_TRY_LEVEL, enclosing)?;
+            if filter == 0 {
+                self.call_guest(handler, Some(ebp), &[])?;
+            }
+            if enclosing != kind.outermost() && enclosing as i32 >= level as i32 {
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// `_except_handler4_common(cookie_ptr, cookie_check, record, frame, context,
+/// dispatcher_context)`, exported by both MSVCR100 and VCRUNTIME140.
+pub(crate) fn except_handler4_common(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (cookie_ptr, _cookie_check, record, frame, context, _dispatcher_context) =
+        vm_args!(vm, stack_ptr; u32, u32, u32, u32, u32, u32);
+    let cookie = vm.read_u32(cookie_ptr).unwrap_or(0);
+    vm.scope_table_handler(Some(cookie), record, frame, context)
+        .unwrap_or_else(|err| {
+            vm.set_guest_fault(err);
+            DISPOSITION_CONTINUE_SEARCH
+        })
+}
@@ -0,0 +1,698 @@
+use crate::vm::*;
+
+// EXCEPTION_RECORD fields a C++ frame handler looks at.
+const RECORD_CODE: u32 = 0x00;
+const RECORD_FLAGS: u32 = 0x04;
+const RECORD_PARAM_COUNT: u32 = 0x10;
+const RECORD_PARAMS: u32 = 0x14;
+
+// C++ frames register `{Next, Handler, State}` at EBP-0xC, with the ESP to resume
+// catch continuations at just below the node.
+const FRAME_SAVED_ESP: u32 = 4;
+const FRAME_STATE: u32 = 0x08;
+const FRAME_EBP: u32 = 0x0C;
+
+// FuncInfo.
+const FUNC_MAGIC: u32 = 0x00;
+const FUNC_MAX_STATE: u32 = 0x04;
+const FUNC_UNWIND_MAP: u32 = 0x08;
+const FUNC_TRY_BLOCKS: u32 = 0x0C;
+const FUNC_TRY_BLOCK_MAP: u32 = 0x10;
+const FUNC_EH_FLAGS: u32 = 0x20;
+// FuncInfo versions from VC7 on carry ESTypeList and EHFlags.
+const FUNC_MAGIC_VC8: u32 = 0x1993_0522;
+// Compiled with /EHs: catch(...) does not see structured exceptions.
+const FI_EHS_FLAG: u32 = 0x1;
+
+const UNWIND_ENTRY_SIZE: u32 = 8;
+// TryBlockMapEntry: {TryLow, TryHigh, CatchHigh, NumCatches, HandlerArray}.
+const TRY_BLOCK_SIZE: u32 = 20;
+// HandlerType: {Adjectives, TypeDescriptor, CatchObjectDisplacement, Handler}.
+const HANDLER_TYPE_SIZE: u32 = 16;
+const HT_IS_CONST: u32 = 0x1;
+const HT_IS_VOLATILE: u32 = 0x2;
+const HT_IS_REFERENCE: u32 = 0x8;
+
+// ThrowInfo: {Attributes, Destructor, ForwardCompat, CatchableTypeArray}.
+const THROW_DESTRUCTOR: u32 = 0x04;
+const THROW_CATCHABLE_TYPES: u32 = 0x0C;
+const TI_IS_CONST: u32 = 0x1;
+const TI_IS_VOLATILE: u32 = 0x2;
+
+// CatchableType: {Properties, TypeDescriptor, PMD, SizeOrOffset, CopyFunction}.
+const CATCHABLE_TYPE: u32 = 0x04;
+const CATCHABLE_PMD: u32 = 0x08;
+const CATCHABLE_SIZE: u32 = 0x14;
+const CATCHABLE_COPY: u32 = 0x18;
+const CT_IS_SIMPLE_TYPE: u32 = 0x1;
+const CT_BY_REFERENCE_ONLY: u32 = 0x2;
+const CT_HAS_VIRTUAL_BASE: u32 = 0x4;
+
+const TYPE_DESCRIPTOR_NAME: u32 = 0x08;
+
+const NO_STATE: i32 = -1;
+
+impl Vm {
+    /// `_CxxThrowException`: raise the C++ exception for `object`. A null object and
+    /// `ThrowInfo` is `throw;`, which rethrows the exception of the innermost catch.
+    pub(crate) fn throw_cxx_exception(&mut self, object: u32, throw_info: u32, address: u32) {
+        let (object, throw_info) = match self.threads.current().exceptions.catches.last() {
+            Some(catch) if object == 0 && throw_info == 0 => (catch.object, catch.throw_info),
+            _ => (object, throw_info),
+        };
+        self.raise_exception(ExceptionRecord {
+            flags: EXCEPTION_NONCONTINUABLE,
+            params: vec![EH_MAGIC_NUMBER, object, throw_info],
+            ..ExceptionRecord::new(EH_EXCEPTION_NUMBER, address)
+        });
+    }
+
+    /// `__CxxFrameHandler3`: find a catch for the exception among the try blocks of
+    /// `func_info` covering the frame's current state, or run its destructors when
+    /// the frame is being unwound.
+    pub(crate) fn cxx_frame_handler(
+        &mut self,
+        record: u32,
+        frame: u32,
+        func_info: u32,
+    ) -> Result<u32, VmError> {
+        let flags = self.read_u32(record + RECORD_FLAGS)?;
+        if flags & (EXCEPTION_UNWINDING | EXCEPTION_EXIT_UNWIND) != 0 {
+            self.unwind_to_state(frame, func_info, NO_STATE)?;
+            return Ok(DISPOSITION_CONTINUE_SEARCH);
+        }
+        let try_blocks = self.read_u32(func_info + FUNC_TRY_BLOCKS)?;
+        if try_blocks == 0 {
+            return Ok(DISPOSITION_CONTINUE_SEARCH);
+        }
+        let thrown = self.thrown_object(record)?;
+        if thrown.is_none() && !self.catches_structured_exceptions(func_info)? {
+            return Ok(DISPOSITION_CONTINUE_SEARCH);
+        }
+        let state = self.read_u32(frame + FRAME_STATE)? as i32;
+        let map = self.read_u32(func_info + FUNC_TRY_BLOCK_MAP)?;
+        for index in 0..try_blocks {
+            let entry = map.wrapping_add(index.wrapping_mul(TRY_BLOCK_SIZE));
+            let try_low = self.read_u32(entry)? as i32;
+            let try_high = self.read_u32(entry + 4)? as i32;
+            if state < try_low || state > try_high {
+                continue;
+            }
+            let catches = self.read_u32(entry + 12)?;
+            let handlers = self.read_u32(entry + 16)?;
+            for slot in 0..catches {
+                let handler = handlers.wrapping_add(slot.wrapping_mul(HANDLER_TYPE_SIZE));
+                let matched = match thrown {
+                    Some((object, throw_info)) => self
+                        .find_catchable(handler, throw_info)?
+                        .map(|catchable| (object, throw_info, Some(catchable))),
+                    None if self.is_catch_all(handler)? => Some((0, 0, None)),
+                    None => None,
+                };
+                let Some(caught) = matched else {
+                    continue;
+                };
+                self.enter_catch(record, frame, func_info, entry, handler, caught)?;
+                return Ok(DISPOSITION_CONTINUE_SEARCH);
+            }
+        }
+        Ok(DISPOSITION_CONTINUE_SEARCH)
+    }
+
+    // A catch block returned to CATCH_RETURN with its continuation address in EAX.
+    pub(super) fn catch_block_returned(&mut self) -> Result<(), VmError> {
+        let Some(catch) = self.threads.current_mut().exceptions.catches.pop() else {
+            return Err(VmError::AccessViolation {
+                addr: CATCH_RETURN,
+                access: AccessKind::Execute,
+                eip: CATCH_RETURN,
+            });
+        };
+        let continuation = self.regs.eax;
+        let esp = self.read_u32(catch.frame - FRAME_SAVED_ESP)?;
+        let mut finished = self
+            .threads
+            .current_mut()
+            .exceptions
+            .take_abandoned_catches(esp);
+        finished.push(catch);
+        let mut destroyed = Vec::new();
+        for catch in finished {
+            if catch.object == 0 || destroyed.contains(&catch.object) {
+                continue;
+            }
+            destroyed.push(catch.object);
+            let destructor = self.read_u32(catch.throw_info + THROW_DESTRUCTOR)?;
+            if destructor != 0 {
+                self.call_method(destructor, catch.object, &[])?;
+            }
+        }
+        self.regs.esp = esp;
+        self.regs.ebp = catch.frame + FRAME_EBP;
+        self.regs.eip = continuation;
+        Ok(())
+    }
+
+    // Unwind the frames above and the inner scopes of this one, copy the exception
+    // into the catch parameter and start the catch block once the handler returns.
+    // `caught` is the thrown object, its ThrowInfo and the catchable type that matched.
+    fn enter_catch(
+        &mut self,
+        record: u32,
+        frame: u32,
+        func_info: u32,
+        try_block: u32,
+        handler: u32,
+        caught: (u32, u32, Option<u32>),
+    ) -> Result<(), VmError> {
+        let (object, throw_info, catchable) = caught;
+        let ebp = frame + FRAME_EBP;
+        self.unwind_frames(frame, record)?;
+        if let Some(catchable) = catchable {
+            self.build_catch_object(ebp, handler, object, catchable)?;
+        }
+        let try_low = self.read_u32(try_block)? as i32;
+        let try_high = self.read_u32(try_block + 4)?;
+        self.unwind_to_state(frame, func_info, try_low)?;
+        self.write_u32(frame + FRAME_STATE, try_high.wrapping_add(1))?;
+
+        // The catch block runs below everything still live, the thrown object included.
+        let bottom = self.regs.esp.wrapping_sub(0x10) & !0xF;
+        self.write_u32(bottom, CATCH_RETURN)?;
+        let exceptions = &mut self.threads.current_mut().exceptions;
+        exceptions.dispatches.pop();
+        exceptions.catches.push(CatchBlock {
+            frame,
+            object,
+            throw_info,
+            bottom,
+        });
+        let funclet = self.read_u32(handler + 12)?;
+        self.transfer_to_handler(funclet, bottom, ebp);
+        Ok(())
+    }
+
+    // Run the unwind actions (destructors) from the frame's current state out to `target`.
+    fn unwind_to_state(&mut self, frame: u32, func_info: u32, target: i32) -> Result<(), VmError> {
+        let max_state = self.read_u32(func_info + FUNC_MAX_STATE)? as i32;
+        let map = self.read_u32(func_info + FUNC_UNWIND_MAP)?;
+        let ebp = frame + FRAME_EBP;
+        let mut state = self.read_u32(frame + FRAME_STATE)? as i32;
+        while state != target && state >= 0 && state < max_state {
+            let entry = map.wrapping_add((state as u32).wrapping_mul(UNWIND_ENTRY_SIZE));
+            let next = self.read_u32(entry)? as i32;
+            let action = self.read_u32(entry + 4)?;
+            // Leave the state first so a fault in the destructor does not rerun it.
+            self.write_u32(frame + FRAME_STATE, next as u32)?;
+            if action != 0 {
+                self.call_guest(action, Some(ebp), &[])?;
+            }
+            // Enclosing states always have lower numbers; anything else is corrupt.
+            if next >= state {
+                break;
+            }
+            state = next;
+        }
+        Ok(())
+    }
+
+    // `(object, ThrowInfo)` of a C++ exception record, `None` for other exceptions.
+    fn thrown_object(&self, record: u32) -> Result<Option<(u32, u32)>, VmError> {
+        if self.read_u32(record + RECORD_CODE)? != EH_EXCEPTION_NUMBER
+            || self.read_u32(record + RECORD_PARAM_COUNT)? < 3
+            || self.read_u32(record + RECORD_PARAMS)? != EH_MAGIC_NUMBER
+        {
+            return Ok(None);
+        }
+        let object = self.read_u32(record + RECORD_PARAMS + 4)?;
+        let throw_info = self.read_u32(record + RECORD_PARAMS + 8)?;
+        Ok(Some((object, throw_info)).filter(|_| throw_info != 0))
+    }
+
+    fn catches_structured_exceptions(&self, func_info: u32) -> Result<bool, VmError> {
+        let magic = self.read_u32(func_info + FUNC_MAGIC)?;
+        Ok(magic < FUNC_MAGIC_VC8 || self.read_u32(func_info + FUNC_EH_FLAGS)? & FI_EHS_FLAG == 0)
+    }
+
+    fn is_catch_all(&self, handler: u32) -> Result<bool, VmError> {
+        let descriptor = self.read_u32(handler + 4)?;
+        Ok(descriptor == 0 || self.read_u8(descriptor + TYPE_DESCRIPTOR_NAME)? == 0)
+    }
+
+    // The first catchable type of the thrown object that `handler` accepts.
+    fn find_catchable(&self, handler: u32, throw_info: u32) -> Result<Option<u32>, VmError> {
+        let types = self.read_u32(throw_info + THROW_CATCHABLE_TYPES)?;
+        let count = self.read_u32(types)?;
+        for index in 0..count {
+            let catchable = self.read_u32(types + 4 + index * 4)?;
+            if self.type_matches(handler, catchable, throw_info)? {
+                return Ok(Some(catchable));
+            }
+        }
+        Ok(None)
+    }
+
+    fn type_matches(&self, handler: u32, catchable: u32, throw_info: u32) -> Result<bool, VmError> {
+        if self.is_catch_all(handler)? {
+            return Ok(true);
+        }
+        let wanted = self.read_u32(handler + 4)?;
+        let thrown = self.read_u32(catchable + CATCHABLE_TYPE)?;
+        if wanted != thrown
+            && self.read_c_string(wanted + TYPE_DESCRIPTOR_NAME)?
+                != self.read_c_string(thrown + TYPE_DESCRIPTOR_NAME)?
+        {
+            return Ok(false);
+        }
+        let adjectives = self.read_u32(handler)?;
+        let properties = self.read_u32(catchable)?;
+        let attributes = self.read_u32(throw_info)?;
+        Ok(
+            !(properties & CT_BY_REFERENCE_ONLY != 0 && adjectives & HT_IS_REFERENCE == 0
+                || attributes & TI_IS_CONST != 0 && adjectives & HT_IS_CONST == 0
+                || attributes & TI_IS_VOLATILE != 0 && adjectives & HT_IS_VOLATILE == 0),
+        )
+    }
+
+    // Initialise the catch parameter at `ebp + CatchObjectDisplacement`.
+    fn build_catch_object(
+        &mut self,
+        ebp: u32,
+        handler: u32,
+        object: u32,
+        catchable: u32,
+    ) -> Result<(), VmError> {
+        let displacement = self.read_u32(handler + 8)?;
+        if displacement == 0 || self.is_catch_all(handler)? {
+            return Ok(());
+        }
+        let dest = ebp.wrapping_add(displacement);
+        let adjectives = self.read_u32(handler)?;
+        let properties = self.read_u32(catchable)?;
+        let size = self.read_u32(catchable + CATCHABLE_SIZE)?;
+        if adjectives & HT_IS_REFERENCE != 0 {
+            let target = if properties & CT_IS_SIMPLE_TYPE != 0 {
+                object
+            } else {
+                self.adjust_pointer(object, catchable)?
+            };
+            return self.write_u32(dest, target);
+        }
+        if properties & CT_IS_SIMPLE_TYPE != 0 {
+            self.copy_guest_bytes(dest, object, size)?;
+            // Thrown pointers are adjusted to the base class the handler asked for.
+            if size == 4 {
+                let pointer = self.read_u32(object)?;
+                if pointer != 0 {
+                    let adjusted = self.adjust_pointer(pointer, catchable)?;
+                    self.write_u32(dest, adjusted)?;
+                }
+            }
+            return Ok(());
+        }
+        let source = self.adjust_pointer(object, catchable)?;
+        let copy = self.read_u32(catchable + CATCHABLE_COPY)?;
+        if copy == 0 {
+            return self.copy_guest_bytes(dest, source, size);
+        }
+        if properties & CT_HAS_VIRTUAL_BASE != 0 {
+            self.call_method(copy, dest, &[source, 1])?;
+        } else {
+            self.call_method(copy, dest, &[source])?;
+        }
+        Ok(())
+    }
+
+    // __AdjustPointer: apply the PMD of `catchable` to `this`.
+    fn adjust_pointer(&self, this: u32, catchable: u32) -> Result<u32, VmError> {
+        let mdisp = self.read_u32(catchable + CATCHABLE_PMD)?;
+        let pdisp = self.read_u32(catchable + CATCHABLE_PMD + 4)? as i32;
+        let vdisp = self.read_u32(catchable + CATCHABLE_PMD + 8)?;
+        let mut result = this.wrapping_add(mdisp);
+        if pdisp >= 0 {
+            let vbtable = self.read_u32(this.wrapping_add(pdisp as u32))?;
+            let offset = self.read_u32(vbtable.wrapping_add(vdisp))?;
+            result = result.wrapping_add(offset).wrapping_add(pdisp as u32);
+        }
+        Ok(result)
+    }
+
+    fn copy_guest_bytes(&mut self, dest: u32, source: u32, size: u32) -> Result<(), VmError> {
+        for offset in 0..size {
+            let value = self.read_u8(source.wrapping_add(offset))?;
+            self.write_u8(dest.wrapping_add(offset), value)?;
+        }
+        Ok(())
+    }
+
+    // Call a __thiscall member function (constructor, destructor) on `this`.
+    fn call_method(&mut self, entry: u32, this: u32, args: &[u32]) -> Result<u32, VmError> {
+        let ecx = std::mem::replace(&mut self.regs.ecx, this);
+        let result = self.call_guest(entry, None, args);
+        self.regs.ecx = ecx;
+        result
+    }
+}
+
+/// `_CxxThrowException(object, throw_info)`, exported by both MSVCR100 and VCRUNTIME140.
+pub(crate) fn cxx_throw_exception(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (object, throw_info) = vm_args!(vm, stack_ptr; u32, u32);
+    let address = vm.read_u32(stack_ptr).unwrap_or(0);
+    vm.throw_cxx_exception(object, throw_info, address);
+    0
+}
+
+/// `__CxxFrameHandler`, `__CxxFrameHandler2` and `__CxxFrameHandler3`. The
+/// compiler-generated thunk loads the function's FuncInfo into EAX first.
+pub(crate) fn cxx_frame_handler(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (record, frame, _context, _dispatcher_context) =
+        vm_args!(vm, stack_ptr; u32, u32, u32, u32);
+    let func_info = vm.regs.eax;
+    vm.cxx_frame_handler(record, frame, func_info)
+        .unwrap_or_else(|err| {
+            vm.set_guest_fault(err);
+            DISPOSITION_CONTINUE_SEARCH
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const THROW_IAT: u32 = 0x3600;
+    const HANDLER_IAT: u32 = 0x3604;
+    const RESULT: u32 = 0x3700;
+    const THROWN_DESTROYED: u32 = 0x3704;
+    const LOCAL_DESTROYED: u32 = 0x3708;
+    const OBJECT_DTOR: u32 = 0x3800;
+    const LOCAL_DTOR: u32 = 0x3810;
+
+    const INT_TYPE: u32 = 0x3500;
+    const BASE_TYPE: u32 = 0x3520;
+    const DERIVED_TYPE: u32 = 0x3540;
+    const INT_THROW_INFO: u32 = 0x3400;
+    const DERIVED_THROW_INFO: u32 = 0x3450;
+
+    // Two framed functions: the outer one at 0x3000 and the one it calls at 0x3100,
+    // each with its handler thunk and FuncInfo.
+    const OUTER: Function = Function {
+        code: 0x3000,
+        thunk: 0x3200,
+        func_info: 0x3300,
+    };
+    const INNER: Function = Function {
+        code: 0x3100,
+        thunk: 0x3210,
+        func_info: 0x3380,
+    };
+
+    struct Function {
+        code: u32,
+        thunk: u32,
+        func_info: u32,
+    }
+
+    impl Function {
+        // Where the try block resumes after a catch, and the catch funclet itself.
+        fn continuation(&self) -> u32 {
+            self.code + 0x40
+        }
+
+        fn funclet(&self) -> u32 {
+            self.code + 0x60
+        }
+    }
+
+    fn create_test_vm() -> Vm {
+        let mut vm = Vm::new(VmConfig::new().architecture(Architecture::X86)).expect("vm");
+        vm.memory.map(0x1000, 0x10000);
+        vm.base = 0x1000;
+        vm.stack_top = 0x1000 + 0x10000 - 4;
+        vm.regs.esp = vm.stack_top;
+        vm.fs_base = 0x1000;
+        vm.write_u32(0x1000, 0xFFFF_FFFF).expect("teb");
+        vm.imports_by_iat.insert(
+            0x5000,
+            HostFunction {
+                func: cxx_throw_exception,
+                stack_cleanup: 8,
+            },
+        );
+        vm.imports_by_iat.insert(
+            0x5010,
+            HostFunction {
+                func: cxx_frame_handler,
+                stack_cleanup: 0,
+            },
+        );
+        write_words(&mut vm, THROW_IAT, &[0x5000, 0x5010]);
+
+        write_type_descriptor(&mut vm, INT_TYPE, b".H\0");
+        write_type_descriptor(&mut vm, BASE_TYPE, b".?AVBase@@\0");
+        write_type_descriptor(&mut vm, DERIVED_TYPE, b".?AVDerived@@\0");
+        // `int`, destroyed through OBJECT_DTOR so tests can count it.
+        write_words(&mut vm, INT_THROW_INFO, &[0, OBJECT_DTOR, 0, 0x3420]);
+        write_words(&mut vm, 0x3420, &[1, 0x3428]);
+        write_words(
+            &mut vm,
+            0x3428,
+            &[CT_IS_SIMPLE_TYPE, INT_TYPE, 0, u32::MAX, 0, 4, 0],
+        );
+        // `class Derived : A, Base`, with Base four bytes in.
+        write_words(&mut vm, DERIVED_THROW_INFO, &[0, 0, 0, 0x3460]);
+        write_words(&mut vm, 0x3460, &[2, 0x3470, 0x3490]);
+        write_words(&mut vm, 0x3470, &[0, DERIVED_TYPE, 0, u32::MAX, 0, 8, 0]);
+        write_words(&mut vm, 0x3490, &[0, BASE_TYPE, 4, u32::MAX, 0, 4, 0]);
+        write_bytes(&mut vm, OBJECT_DTOR, &increment(THROWN_DESTROYED));
+        write_bytes(&mut vm, LOCAL_DTOR, &increment(LOCAL_DESTROYED));
+        vm
+    }
+
+    fn write_bytes(vm: &mut Vm, addr: u32, bytes: &[u8]) {
+        for (offset, byte) in bytes.iter().enumerate() {
+            vm.write_u8(addr + offset as u32, *byte).expect("write");
+        }
+    }
+
+    fn write_words(vm: &mut Vm, addr: u32, words: &[u32]) {
+        for (index, word) in words.iter().enumerate() {
+            vm.write_u32(addr + index as u32 * 4, *word).expect("write");
+        }
+    }
+
+    fn write_type_descriptor(vm: &mut Vm, addr: u32, name: &[u8]) {
+        write_words(vm, addr, &[0, 0]);
+        write_bytes(vm, addr + TYPE_DESCRIPTOR_NAME, name);
+    }
+
+    // inc dword [counter]; ret
+    fn increment(counter: u32) -> Vec<u8> {
+        let mut code = vec![0xFF, 0x05];
+        code.extend_from_slice(&counter.to_le_bytes());
+        code.push(0xC3);
+        code
+    }
+
+    // Register the frame with `function`'s handler thunk and enter state 0, then run `body`.
+    fn write_function(vm: &mut Vm, function: &Function, body: &[u8]) {
+        let mut code = vec![
+            0x55, // push ebp
+            0x89, 0xE5, // mov ebp, esp
+            0x6A, 0xFF, // push -1
+            0x68, // push handler thunk
+        ];
+        code.extend_from_slice(&function.thunk.to_le_bytes());
+        code.extend_from_slice(&[
+            0x64, 0xFF, 0x35, 0x00, 0x00, 0x00, 0x00, // push fs:[0]
+            0x64, 0x89, 0x25, 0x00, 0x00, 0x00, 0x00, // mov fs:[0], esp
+            0x83, 0xEC, 0x08, // sub esp, 8
+            0x89, 0x65, 0xF0, // mov [ebp-0x10], esp
+            0xC7, 0x45, 0xFC, 0x00, 0x00, 0x00, 0x00, // mov [ebp-4], 0
+        ]);
+        code.extend_from_slice(body);
+        code.push(0xCC); // int3
+        write_bytes(vm, function.code, &code);
+
+        // The continuation returns RESULT after unlinking the frame.
+        let mut continuation = vec![0xA1]; // mov eax, [RESULT]
+        continuation.extend_from_slice(&RESULT.to_le_bytes());
+        continuation.extend_from_slice(&[
+            0x8B, 0x4D, 0xF4, // mov ecx, [ebp-0xC]
+            0x64, 0x89, 0x0D, 0x00, 0x00, 0x00, 0x00, // mov fs:[0], ecx
+            0x89, 0xEC, // mov esp, ebp
+            0x5D, // pop ebp
+            0xC3, // ret
+        ]);
+        write_bytes(vm, function.continuation(), &continuation);
+
+        let mut thunk = vec![0xB8]; // mov eax, FuncInfo
+        thunk.extend_from_slice(&function.func_info.to_le_bytes());
+        thunk.extend_from_slice(&[0xFF, 0x25]); // jmp [__CxxFrameHandler3]
+        thunk.extend_from_slice(&HANDLER_IAT.to_le_bytes());
+        write_bytes(vm, function.thunk, &thunk);
+    }
+
+    // FuncInfo with one unwind action for state 0 and, if `handlers` is not empty,
+    // a try block around state 0 with `(adjectives, type descriptor, funclet)` catches.
+    fn write_func_info(
+        vm: &mut Vm,
+        function: &Function,
+        action: u32,
+        handlers: &[(u32, u32, u32)],
+    ) {
+        let base = function.func_info;
+        let try_blocks = u32::from(!handlers.is_empty());
+        write_words(
+            vm,
+            base,
+            &[0x1993_0520, 1, base + 0x20, try_blocks, base + 0x28, 0, 0],
+        );
+        write_words(vm, base + 0x20, &[u32::MAX, action]);
+        write_words(
+            vm,
+            base + 0x28,
+            &[0, 0, 1, handlers.len() as u32, base + 0x40],
+        );
+        for (index, &(adjectives, descriptor, funclet)) in handlers.iter().enumerate() {
+            write_words(
+                vm,
+                base + 0x40 + index as u32 * HANDLER_TYPE_SIZE,
+                &[adjectives, descriptor, 0xFFFF_FFEC, funclet],
+            );
+        }
+    }
+
+    // push value; mov eax, esp; push ThrowInfo; push eax; call [_CxxThrowException]
+    fn throw(values: &[u8], throw_info: u32) -> Vec<u8> {
+        let mut code = Vec::new();
+        for &value in values.iter().rev() {
+            code.extend_from_slice(&[0x6A, value]);
+        }
+        code.extend_from_slice(&[0x89, 0xE0, 0x68]);
+        code.extend_from_slice(&throw_info.to_le_bytes());
+        code.extend_from_slice(&[0x50, 0xFF, 0x15]);
+        code.extend_from_slice(&THROW_IAT.to_le_bytes());
+        code
+    }
+
+    // `throw;`: _CxxThrowException(NULL, NULL)
+    fn rethrow() -> Vec<u8> {
+        let mut code = vec![0x6A, 0x00, 0x6A, 0x00, 0xFF, 0x15];
+        code.extend_from_slice(&THROW_IAT.to_le_bytes());
+        code.push(0xCC);
+        code
+    }
+
+    // mov eax, function; call eax
+    fn call(function: &Function) -> Vec<u8> {
+        let mut code = vec![0xB8];
+        code.extend_from_slice(&function.code.to_le_bytes());
+        code.extend_from_slice(&[0xFF, 0xD0]);
+        code
+    }
+
+    // Store `[ebp-0x14]` (dereferenced for a reference parameter) plus `add` in
+    // RESULT, then resume at `function`'s continuation.
+    fn catch_funclet(function: &Function, by_reference: bool, add: u8) -> Vec<u8> {
+        let mut code = vec![0x8B, 0x45, 0xEC]; // mov eax, [ebp-0x14]
+        if by_reference {
+            code.extend_from_slice(&[0x8B, 0x00]); // mov eax, [eax]
+        }
+        code.extend_from_slice(&[0x83, 0xC0, add]); // add eax, add
+        code.push(0xA3); // mov [RESULT], eax
+        code.extend_from_slice(&RESULT.to_le_bytes());
+        code.push(0xB8); // mov eax, continuation
+        code.extend_from_slice(&function.continuation().to_le_bytes());
+        code.push(0xC3);
+        code
+    }
+
+    fn run(vm: &mut Vm) -> u32 {
+        vm.execute(OUTER.code).expect("execute");
+        assert_eq!(vm.read_u32(0x1000).unwrap(), 0xFFFF_FFFF);
+        vm.regs.eax
+    }
+
+    #[test]
+    fn catch_block_receives_thrown_int() {
+        let mut vm = create_test_vm();
+        // try { throw 7; } catch (int value) { return value + 1; }
+        write_function(&mut vm, &OUTER, &throw(&[7], INT_THROW_INFO));
+        write_func_info(&mut vm, &OUTER, 0, &[(0, INT_TYPE, OUTER.funclet())]);
+        write_bytes(&mut vm, OUTER.funclet(), &catch_funclet(&OUTER, false, 1));
+
+        assert_eq!(run(&mut vm), 8);
+        assert_eq!(vm.read_u32(THROWN_DESTROYED).unwrap(), 1);
+    }
+
+    #[test]
+    fn base_class_handler_matches_through_catchable_types() {
+        let mut vm = create_test_vm();
+        // try { throw Derived(); } catch (int) { ... } catch (Base& base) { ... }
+        write_function(&mut vm, &OUTER, &throw(&[0x11, 0x22], DERIVED_THROW_INFO));
+        let int_funclet = OUTER.funclet() + 0x20;
+        write_func_info(
+            &mut vm,
+            &OUTER,
+            0,
+            &[
+                (0, INT_TYPE, int_funclet),
+                (HT_IS_REFERENCE, BASE_TYPE, OUTER.funclet()),
+            ],
+        );
+        write_bytes(&mut vm, OUTER.funclet(), &catch_funclet(&OUTER, true, 0));
+        write_bytes(&mut vm, int_funclet, &catch_funclet(&OUTER, false, 0x80));
+
+        // The reference points at the Base subobject, not at the Derived object.
+        assert_eq!(run(&mut vm), 0x22);
+    }
+
+    #[test]
+    fn catch_all_takes_any_thrown_type() {
+        let mut vm = create_test_vm();
+        // try { throw Derived(); } catch (...) { ... }
+        write_function(&mut vm, &OUTER, &throw(&[0x11, 0x22], DERIVED_THROW_INFO));
+        write_func_info(&mut vm, &OUTER, 0, &[(0, 0, OUTER.funclet())]);
+        let mut funclet = vec![0xC7, 0x05]; // mov dword [RESULT], 0x99
+        funclet.extend_from_slice(&RESULT.to_le_bytes());
+        funclet.extend_from_slice(&0x99u32.to_le_bytes());
+        funclet.push(0xB8);
+        funclet.extend_from_slice(&OUTER.continuation().to_le_bytes());
+        funclet.push(0xC3);
+        write_bytes(&mut vm, OUTER.funclet(), &funclet);
+
+        assert_eq!(run(&mut vm), 0x99);
+    }
+
+    #[test]
+    fn rethrow_reaches_the_enclosing_catch_and_destroys_the_object_once() {
+        let mut vm = create_test_vm();
+        // try { inner(); } catch (int value) { return value + 2; }
+        write_function(&mut vm, &OUTER, &call(&INNER));
+        write_func_info(&mut vm, &OUTER, 0, &[(0, INT_TYPE, OUTER.funclet())]);
+        write_bytes(&mut vm, OUTER.funclet(), &catch_funclet(&OUTER, false, 2));
+        // inner: try { throw 7; } catch (int) { throw; }
+        write_function(&mut vm, &INNER, &throw(&[7], INT_THROW_INFO));
+        write_func_info(&mut vm, &INNER, 0, &[(0, INT_TYPE, INNER.funclet())]);
+        write_bytes(&mut vm, INNER.funclet(), &rethrow());
+
+        assert_eq!(run(&mut vm), 9);
+        assert_eq!(vm.read_u32(THROWN_DESTROYED).unwrap(), 1);
+    }
+
+    #[test]
+    fn unwinding_runs_destructors_of_intermediate_frames() {
+        let mut vm = create_test_vm();
+        // try { inner(); } catch (int value) { return value + 3; }
+        write_function(&mut vm, &OUTER, &call(&INNER));
+        write_func_info(&mut vm, &OUTER, 0, &[(0, INT_TYPE, OUTER.funclet())]);
+        write_bytes(&mut vm, OUTER.funclet(), &catch_funclet(&OUTER, false, 3));
+        // inner: Local local; throw 7;
+        write_function(&mut vm, &INNER, &throw(&[7], INT_THROW_INFO));
+        write_func_info(&mut vm, &INNER, LOCAL_DTOR, &[]);
+
+        assert_eq!(run(&mut vm), 10);
+        assert_eq!(vm.read_u32(LOCAL_DESTROYED).unwrap(), 1);
+        assert_eq!(vm.read_u32(THROWN_DESTROYED).unwrap(), 1);
+    }
+}
@@ -0,0 +1,514 @@
+use crate::vm::debugger::{StepTarget, Watchpoint};
+use crate::vm::*;
+
+use crate::architecture::intel::x86::disasm::Flow;
+
+impl Vm {
+    /// Stop before the instruction at `addr` executes.
+    pub fn add_breakpoint(&mut self, addr: u32) {
+        self.debugger.breakpoints.insert(addr);
+    }
+
+    pub fn remove_breakpoint(&mut self, addr: u32) -> bool {
+        self.debugger.breakpoints.remove(&addr)
+    }
+
+    /// Stop after an instruction or host call that touches `addr..addr + len`.
+    pub fn add_watchpoint(&mut self, addr: u32, len: u32, kind: WatchKind) {
+        self.debugger.watchpoints.push(Watchpoint {
+            start: addr,
+            len,
+            kind,
+        });
+    }
+
+    /// Remove every watchpoint starting at `addr`.
+    pub fn remove_watchpoint(&mut self, addr: u32) -> bool {
+        let before = self.debugger.watchpoints.len();
+        self.debugger
+            .watchpoints
+            .retain(|watch| watch.start != addr);
+        self.debugger.watchpoints.len() != before
+    }
+
+    /// Stop when guest code calls or jumps to the import `name`, given either as
+    /// `module!function` or as a bare function name matching any module.
+    pub fn break_on_import(&mut self, name: &str) {
+        self.debugger
+            .import_breaks
+            .insert(name.to_ascii_lowercase());
+    }
+
+    pub fn remove_import_break(&mut self, name: &str) -> bool {
+        self.debugger
+            .import_breaks
+            .remove(&name.to_ascii_lowercase())
+    }
+
+    /// Install `hook`, replacing the previous one; `None` removes it.
+    pub fn set_debug_hook(&mut self, hook: Option<Box<dyn DebugHook>>) {
+        self.debugger.hook = hook;
+    }
+
+    /// Whether an outermost call stopped with `VmError::Stopped` and can be resumed.
+    pub fn is_suspended(&self) -> bool {
+        self.debugger.session.is_some()
+    }
+
+    /// Continue a stopped run until the next stop, or `StopReason::Exited` once the
+    /// suspended call returns.
+    pub fn resume(&mut self) -> Result<StopReason, VmError> {
+        let Some(root) = self.debugger.session.take() else {
+            self.debugger.step = None;
+            return Err(VmError::InvalidConfig("no suspended run to resume"));
+        };
+        let dispatching = std::mem::replace(&mut self.threads.dispatching, true);
+        let result = self.run_threads(|vm| vm.threads.current_id() == root && vm.regs.eip == 0);
+        self.threads.dispatching = dispatching;
+        match result {
+            Ok(()) => {
+                self.debugger.end_session();
+                Ok(StopReason::Exited)
+            }
+            Err(VmError::Stopped(reason)) => {
+                self.debugger.session = Some(root);
+                Ok(reason)
+            }
+            Err(err) => {
+                self.debugger.end_session();
+                self.resume_thread_after_error(root);
+                Err(err)
+            }
+        }
+    }
+
+    /// Execute one instruction of a stopped run. A call to a host import counts
+    /// as one instruction.
+    pub fn step(&mut self) -> Result<StopReason, VmError> {
+        self.debugger.step = Some(StepTarget::Into);
+        self.resume()
+    }
+
+    /// Like `step`, but run a CALL until it returns to the next instruction.
+    pub fn step_over(&mut self) -> Result<StopReason, VmError> {
+        let eip = self.regs.eip;
+        let target = match self.disassemble(eip, 1).first() {
+            Some(ins) if ins.flow == Flow::Call => StepTarget::Over {
+                addr: ins.next_address(),
+                esp: self.regs.esp,
+            },
+            _ => StepTarget::Into,
+        };
+        self.debugger.step = Some(target);
+        self.resume()
+    }
+
+    /// Continue a stopped run until EIP reaches `addr`.
+    pub fn run_until(&mut self, addr: u32) -> Result<StopReason, VmError> {
+        self.debugger.step = Some(StepTarget::Until(addr));
+        self.resume()
+    }
+
+    /// Write guest memory as a debugger does: page protections are ignored and
+    /// watchpoints do not fire.
+    pub fn patch_memory(&mut self, addr: u32, bytes: &[u8]) -> Result<(), VmError> {
+        self.loader_write(addr, bytes)
+    }
+
+    pub fn register(&self, reg: Register) -> u32 {
+        match reg {
+            Register::Eax => self.regs.eax,
+            Register::Ecx => self.regs.ecx,
+            Register::Edx => self.regs.edx,
+            Register::Ebx => self.regs.ebx,
+            Register::Esp => self.regs.esp,
+            Register::Ebp => self.regs.ebp,
+            Register::Esi => self.regs.esi,
+            Register::Edi => self.regs.edi,
+            Register::Eip => self.regs.eip,
+            Register::Eflags => self.eflags(),
+        }
+    }
+
+    /// Writing `Eflags` changes only the bits POPFD may change in user mode.
+    pub fn set_register(&mut self, reg: Register, value: u32) {
+        match reg {
+            Register::Eax => self.regs.eax = value,
+            Register::Ecx => self.regs.ecx = value,
+            Register::Edx => self.regs.edx = value,
+            Register::Ebx => self.regs.ebx = value,
+            Register::Esp => self.regs.esp = value,
+            Register::Ebp => self.regs.ebp = value,
+            Register::Esi => self.regs.esi = value,
+            Register::Edi => self.regs.edi = value,
+            Register::Eip => self.regs.eip = value,
+            Register::Eflags => self.set_eflags(value),
+        }
+    }
+
+    pub fn flag(&self, flag: Flag) -> bool {
+        self.eflags() & flag_bit(flag) != 0
+    }
+
+    pub fn set_flag(&mut self, flag: Flag, value: bool) {
+        self.flags.set(flag_bit(flag), value);
+    }
+
+    fn can_stop(&self) -> bool {
+        self.debugger.depth == 1 && self.stack_depth == 0
+    }
+
+    // Run `call` against the installed hook, which is taken out while it runs so
+    // it can be handed the VM.
+    fn debug_hook(
+        &mut self,
+        call: impl FnOnce(&mut dyn DebugHook, &mut Vm) -> DebugAction,
+    ) -> Option<DebugAction> {
+        let mut hook = self.debugger.hook.take()?;
+        let action = call(hook.as_mut(), self);
+        if self.debugger.hook.is_none() {
+            self.debugger.hook = Some(hook);
+        }
+        Some(action)
+    }
+
+    /// Debugger checks before the instruction at `eip` runs.
+    pub(super) fn debug_before_step(&mut self, eip: u32) -> Option<StopReason> {
+        self.debugger.take_hit(eip);
+        if self.debugger.resume_at.take() == Some(eip) {
+            return None;
+        }
+        let stoppable = self.can_stop();
+        let mut reason = None;
+        if self.debug_hook(|hook, vm| hook.on_instruction(vm, eip)) == Some(DebugAction::Stop) {
+            reason = Some(StopReason::Hook(eip));
+        }
+        if reason.is_none() && self.debugger.breakpoints.contains(&eip) {
+            let action = self
+                .debug_hook(|hook, vm| hook.on_breakpoint(vm, eip))
+                .unwrap_or(DebugAction::Stop);
+            if action == DebugAction::Stop {
+                reason = Some(StopReason::Breakpoint(eip));
+            }
+        }
+        if reason.is_none() && stoppable {
+            reason = match self.debugger.step {
+                Some(StepTarget::Until(addr)) if addr == eip => Some(StopReason::Reached(eip)),
+                Some(StepTarget::Over { addr, esp }) if addr == eip && self.regs.esp >= esp => {
+                    Some(StopReason::Step)
+                }
+                _ => None,
+            };
+        }
+        if !stoppable {
+            return None;
+        }
+        if reason.is_some() {
+            self.debugger.resume_at = Some(eip);
+            self.debugger.step = None;
+        }
+        reason
+    }
+
+    /// Run the host import a stop was reported in front of, if EIP is still there.
+    pub(super) fn debug_pending_import(&mut self, eip: u32) -> Option<Result<(), VmError>> {
+        match self.debugger.import.take() {
+            Some((addr, host)) if addr == eip => Some(self.call_host_tail(addr, host)),
+            pending => {
+                self.debugger.import = pending;
+                None
+            }
+        }
+    }
+
+    /// Debugger checks after the instruction that started at `eip` completed.
+    pub(super) fn debug_after_step(&mut self, eip: u32) -> Option<StopReason> {
+        let stoppable = self.can_stop();
+        let mut reason = self.debugger.stop.take();
+        if reason.is_none() {
+            if let Some(hit) = self.debugger.take_hit(eip) {
+                let action = self
+                    .debug_hook(|hook, vm| hook.on_watchpoint(vm, &hit))
+                    .unwrap_or(DebugAction::Stop);
+                if action == DebugAction::Stop {
+                    reason = Some(StopReason::Watchpoint(hit));
+                }
+            }
+        }
+        if reason.is_none() && matches!(self.debugger.step, Some(StepTarget::Into)) {
+            reason = Some(StopReason::Step);
+        }
+        if !stoppable {
+            return None;
+        }
+        if reason.is_some() {
+            self.debugger.step = None;
+        }
+        reason
+    }
+
+    /// Stop in front of a host import the debugger breaks on. When this returns
+    /// `true` the call has been redirected: EIP is `addr` (with `return_eip`
+    /// pushed for a CALL) and the import runs when the stop is resumed.
+    pub(super) fn debug_import_call(
+        &mut self,
+        addr: u32,
+        host: HostFunction,
+        return_eip: Option<u32>,
+    ) -> Result<bool, VmError> {
+        if self.debugger.import_breaks.is_empty() {
+            return Ok(false);
+        }
+        let Some(name) = self.imports_by_iat_name.get(&addr).cloned() else {
+            return Ok(false);
+        };
+        if !self.debugger.breaks_on_import(&name) {
+            return Ok(false);
+        }
+        let action = self
+            .debug_hook(|hook, vm| hook.on_import_call(vm, &name))
+            .unwrap_or(DebugAction::Stop);
+        if action == DebugAction::Continue || !self.can_stop() {
+            return Ok(false);
+        }
+        if let Some(return_eip) = return_eip {
+            self.push(return_eip)?;
+        }
+        self.regs.eip = addr;
+        self.debugger.resume_at = Some(addr);
+        self.debugger.import = Some((addr, host));
+        self.debugger.stop = Some(StopReason::ImportCall { name, addr });
+        Ok(true)
+    }
+}
+
+fn flag_bit(flag: Flag) -> u32 {
+    match flag {
+        Flag::Carry => Flags::CF,
+        Flag::Parity => Flags::PF,
+        Flag::Adjust => Flags::AF,
+        Flag::Zero => Flags::ZF,
+        Flag::Sign => Flags::SF,
+        Flag::Direction => Flags::DF,
+        Flag::Overflow => Flags::OF,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    use crate::vm::*;
+
+    fn create_test_vm() -> Vm {
+        let mut vm = Vm::new(VmConfig::new().architecture(Architecture::X86)).expect("vm");
+        vm.memory.map(0x1000, 0x10000);
+        vm.base = 0x1000;
+        vm.stack_top = 0x1000 + 0x10000 - 4;
+        vm.regs.esp = vm.stack_top;
+        vm.fs_base = 0x1000;
+        vm.write_u32(0x1000, 0xFFFF_FFFF).expect("teb");
+        vm
+    }
+
+    fn stopped(result: Result<(), VmError>) -> StopReason {
+        match result {
+            Err(VmError::Stopped(reason)) => reason,
+            other => panic!("expected a stop, got {other:?}"),
+        }
+    }
+
+    fn get_tick_count(_vm: &mut Vm, _stack_ptr: u32) -> u32 {
+        42
+    }
+
+    #[test]
+    fn breakpoint_step_and_resume() {
+        let mut vm = create_test_vm();
+        let code: &[u8] = &[
+            0xB8, 0x01, 0x00, 0x00, 0x00, // mov eax, 1
+            0xBB, 0x02, 0x00, 0x00, 0x00, // mov ebx, 2
+            0x01, 0xD8, // add eax, ebx
+            0xC3, // ret
+        ];
+        vm.write_bytes(0x3000, code).expect("code");
+        vm.add_breakpoint(0x3005);
+
+        assert_eq!(stopped(vm.execute(0x3000)), StopReason::Breakpoint(0x3005));
+        assert!(vm.is_suspended());
+        assert_eq!(vm.register(Register::Eip), 0x3005);
+        assert_eq!(vm.register(Register::Eax), 1);
+        assert_eq!(vm.register(Register::Ebx), 0);
+
+        assert_eq!(vm.step().expect("step"), StopReason::Step);
+        assert_eq!(vm.register(Register::Eip), 0x300A);
+        assert_eq!(vm.register(Register::Ebx), 2);
+
+        vm.set_register(Register::Ebx, 10);
+        assert_eq!(vm.resume().expect("resume"), StopReason::Exited);
+        assert_eq!(vm.register(Register::Eax), 11);
+        assert!(!vm.is_suspended());
+        assert!(vm.resume().is_err());
+    }
+
+    #[test]
+    fn step_over_and_run_until() {
+        let mut vm = create_test_vm();
+        let code: &[u8] = &[
+            0xE8, 0x04, 0x00, 0x00, 0x00, // call 0x3009
+            0x89, 0xC1, // mov ecx, eax
+            0x41, // inc ecx
+            0xC3, // ret
+            0xB8, 0x07, 0x00, 0x00, 0x00, // mov eax, 7
+            0xC3, // ret
+        ];
+        vm.write_bytes(0x3000, code).expect("code");
+        vm.add_breakpoint(0x3000);
+
+        assert_eq!(stopped(vm.execute(0x3000)), StopReason::Breakpoint(0x3000));
+        assert_eq!(vm.step_over().expect("step over"), StopReason::Step);
+        assert_eq!(vm.register(Register::Eip), 0x3005);
+        assert_eq!(vm.register(Register::Eax), 7);
+
+        assert_eq!(
+            vm.run_until(0x3008).expect("run"),
+            StopReason::Reached(0x3008)
+        );
+        assert_eq!(vm.register(Register::Ecx), 8);
+        assert_eq!(vm.resume().expect("resume"), StopReason::Exited);
+    }
+
+    #[test]
+    fn watchpoint_reports_the_accessing_instruction() {
+        let mut vm = create_test_vm();
+        let code: &[u8] = &[
+            0xA1, 0x00, 0x50, 0x00, 0x00, // mov eax, [0x5000]
+            0xC7, 0x05, 0x02, 0x50, 0x00, 0x00, 0x05, 0x00, 0x00,
+            0x00, // mov dword [0x5002], 5
+            0xC3, // ret
+        ];
+        vm.write_bytes(0x3000, code).expect("code");
+        vm.add_watchpoint(0x5004, 2, WatchKind::Write);
+
+        let StopReason::Watchpoint(hit) = stopped(vm.execute(0x3000)) else {
+            panic!("expected a watchpoint");
+        };
+        assert_eq!(
+            (hit.addr, hit.len, hit.access, hit.eip),
+            (0x5002, 4, AccessKind::Write, 0x3005)
+        );
+        assert_eq!(vm.register(Register::Eip), 0x300F);
+        assert_eq!(vm.read_u32(0x5002).expect("read"), 5);
+
+        assert!(vm.remove_watchpoint(0x5004));
+        assert_eq!(vm.resume().expect("resume"), StopReason::Exited);
+    }
+
+    #[test]
+    fn import_break_stops_before_the_host_call() {
+        let mut vm = create_test_vm();
+        vm.imports_by_iat.insert(
+            0x5000,
+            HostFunction {
+                func: get_tick_count,
+                stack_cleanup: 0,
+            },
+        );
+        vm.imports_by_iat_name
+            .insert(0x5000, "KERNEL32.dll!GetTickCount".to_string());
+        let code: &[u8] = &[
+            0xFF, 0x15, 0x00, 0x50, 0x00, 0x00, // call [0x5000]
+            0x40, // inc eax
+            0xC3, // ret
+        ];
+        vm.write_bytes(0x3000, code).expect("code");
+        vm.break_on_import("gettickcount");
+
+        assert_eq!(
+            stopped(vm.execute(0x3000)),
+            StopReason::ImportCall {
+                name: "KERNEL32.dll!GetTickCount".to_string(),
+                addr: 0x5000,
+            }
+        );
+        let esp = vm.register(Register::Esp);
+        assert_eq!(vm.read_u32(esp).expect("return address"), 0x3006);
+
+        assert_eq!(vm.step().expect("step"), StopReason::Step);
+        assert_eq!(vm.register(Register::Eip), 0x3006);
+        assert_eq!(vm.register(Register::Eax), 42);
+        assert_eq!(vm.resume().expect("resume"), StopReason::Exited);
+        assert_eq!(vm.register(Register::Eax), 43);
+    }
+
+    struct Counter {
+        instructions: Rc<Cell<u32>>,
+        stop_at: u32,
+    }
+
+    impl DebugHook for Counter {
+        fn on_instruction(&mut self, _vm: &mut Vm, eip: u32) -> DebugAction {
+            self.instructions.set(self.instructions.get() + 1);
+            if eip == self.stop_at {
+                DebugAction::Stop
+            } else {
+                DebugAction::Continue
+            }
+        }
+
+        fn on_breakpoint(&mut self, vm: &mut Vm, _addr: u32) -> DebugAction {
+            vm.set_register(Register::Ecx, 0x55);
+            DebugAction::Continue
+        }
+    }
+
+    #[test]
+    fn hook_sees_every_instruction_and_can_decline_stops() {
+        let mut vm = create_test_vm();
+        let code: &[u8] = &[
+            0x31, 0xC9, // xor ecx, ecx
+            0x90, // nop
+            0x89, 0xC8, // mov eax, ecx
+            0xC3, // ret
+        ];
+        vm.write_bytes(0x3000, code).expect("code");
+        let instructions = Rc::new(Cell::new(0));
+        vm.set_debug_hook(Some(Box::new(Counter {
+            instructions: instructions.clone(),
+            stop_at: 0x3005,
+        })));
+        vm.add_breakpoint(0x3002);
+
+        assert_eq!(stopped(vm.execute(0x3000)), StopReason::Hook(0x3005));
+        assert_eq!(instructions.get(), 4);
+        assert_eq!(vm.register(Register::Eax), 0x55);
+        assert_eq!(vm.resume().expect("resume"), StopReason::Exited);
+        assert_eq!(instructions.get(), 4);
+    }
+
+    #[test]
+    fn register_flag_and_extended_state_accessors() {
+        let mut vm = create_test_vm();
+        vm.set_flag(Flag::Carry, true);
+        vm.set_flag(Flag::Zero, true);
+        assert!(vm.flag(Flag::Carry) && vm.flag(Flag::Zero) && !vm.flag(Flag::Sign));
+        assert_eq!(vm.register(Register::Eflags) & 0x41, 0x41);
+        vm.set_register(Register::Eflags, 0x0000_0880);
+        assert!(vm.flag(Flag::Overflow) && vm.flag(Flag::Sign) && !vm.flag(Flag::Carry));
+
+        vm.set_xmm(3, [0xAB; 16]);
+        assert_eq!(vm.xmm(3), [0xAB; 16]);
+
+        let one = [0, 0, 0, 0, 0, 0, 0, 0x80, 0xFF, 0x3F];
+        assert_eq!(vm.fpu_st_bytes(0), None);
+        vm.fpu_set_st_bytes(0, one).expect("st0");
+        assert_eq!(vm.fpu_st_bytes(0), Some(one));
+        assert_eq!(vm.fpu_st(0).expect("st0"), 1.0);
+        assert!(vm.fpu_set_st_bytes(8, one).is_err());
+
+        assert!(vm.set_mxcsr_bits(0x1_0000).is_err());
+        vm.set_mxcsr_bits(0x9FC0).expect("mxcsr");
+        assert_eq!(vm.mxcsr_bits(), 0x9FC0);
+    }
+}
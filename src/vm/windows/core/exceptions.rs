@@ -0,0 +1,399 @@
+use crate::vm::*;
+
+// EXCEPTION_RECORD layout.
+const RECORD_CODE: u32 = 0x00;
+const RECORD_FLAGS: u32 = 0x04;
+const RECORD_NESTED: u32 = 0x08;
+const RECORD_ADDRESS: u32 = 0x0C;
+const RECORD_PARAM_COUNT: u32 = 0x10;
+const RECORD_PARAMS: u32 = 0x14;
+
+// x86 CONTEXT layout.
+const CONTEXT_SIZE: u32 = 0x2CC;
+const CONTEXT_FULL: u32 = 0x0001_0007;
+const CONTEXT_FLAGS: u32 = 0x00;
+const CONTEXT_SEG_GS: u32 = 0x8C;
+const CONTEXT_SEG_FS: u32 = 0x90;
+const CONTEXT_SEG_ES: u32 = 0x94;
+const CONTEXT_SEG_DS: u32 = 0x98;
+const CONTEXT_EDI: u32 = 0x9C;
+const CONTEXT_ESI: u32 = 0xA0;
+const CONTEXT_EBX: u32 = 0xA4;
+const CONTEXT_EDX: u32 = 0xA8;
+const CONTEXT_ECX: u32 = 0xAC;
+const CONTEXT_EAX: u32 = 0xB0;
+const CONTEXT_EBP: u32 = 0xB4;
+const CONTEXT_EIP: u32 = 0xB8;
+const CONTEXT_SEG_CS: u32 = 0xBC;
+const CONTEXT_EFLAGS: u32 = 0xC0;
+const CONTEXT_ESP: u32 = 0xC4;
+const CONTEXT_SEG_SS: u32 = 0xC8;
+
+// Dispatch area below the faulting ESP: EXCEPTION_POINTERS, the dispatcher context
+// handed to frame handlers, then the record and context themselves.
+const AREA_POINTERS: u32 = 0x00;
+const AREA_DISPATCHER_CONTEXT: u32 = 0x08;
+const AREA_RECORD: u32 = 0x10;
+const AREA_CONTEXT: u32 = 0x60;
+const AREA_SIZE: u32 = AREA_CONTEXT + CONTEXT_SIZE;
+
+impl Vm {
+    /// Head of the current thread's `FS:[0]` registration chain.
+    pub(crate) fn exception_list(&self) -> u32 {
+        self.read_u32(self.fs_base).unwrap_or(END_OF_CHAIN)
+    }
+
+    pub(crate) fn set_exception_list(&mut self, frame: u32) -> Result<(), VmError> {
+        self.write_u32(self.fs_base, frame)
+    }
+
+    pub(crate) fn add_vectored_handler(
+        &mut self,
+        continue_handler: bool,
+        first: bool,
+        handler: u32,
+    ) -> u32 {
+        self.vectored_handlers.add(continue_handler, first, handler)
+    }
+
+    pub(crate) fn remove_vectored_handler(&mut self, continue_handler: bool, cookie: u32) -> bool {
+        self.vectored_handlers.remove(continue_handler, cookie)
+    }
+
+    /// RaiseException: the exception is dispatched as soon as the host call returns,
+    /// with the caller's registers as its context.
+    pub(crate) fn raise_exception(&mut self, record: ExceptionRecord) {
+        self.threads.current_mut().exceptions.pending = Some(ExceptionRequest::Raise(record));
+    }
+
+    /// Resume at an `__except` block once the current host call returns.
+    pub(crate) fn transfer_to_handler(&mut self, eip: u32, esp: u32, ebp: u32) {
+        self.threads.current_mut().exceptions.pending =
+            Some(ExceptionRequest::Transfer { eip, esp, ebp });
+    }
+
+    /// RtlUnwind: call the handler of every registration above `target` with the
+    /// unwinding flags set, unlinking each one. `record` is the exception being
+    /// unwound, or 0 for a plain `STATUS_UNWIND`; a zero `target` unwinds the chain.
+    pub(crate) fn unwind_frames(&mut self, target: u32, record: u32) -> Result<(), VmError> {
+        let saved_esp = self.regs.esp;
+        let bottom = saved_esp.wrapping_sub(AREA_SIZE) & !0xF;
+        let context = bottom + AREA_CONTEXT;
+        let dispatcher_context = bottom + AREA_DISPATCHER_CONTEXT;
+        let mut flags = EXCEPTION_UNWINDING;
+        if target == 0 {
+            flags |= EXCEPTION_EXIT_UNWIND;
+        }
+        let result = (|| {
+            self.write_context(context)?;
+            self.write_u32(dispatcher_context, 0)?;
+            let record = if record == 0 {
+                let address = self.read_u32(saved_esp)?;
+                let unwind = ExceptionRecord {
+                    flags,
+                    ..ExceptionRecord::new(STATUS_UNWIND, address)
+                };
+                self.write_exception_record(bottom + AREA_RECORD, &unwind)?;
+                bottom + AREA_RECORD
+            } else {
+                let current = self.read_u32(record + RECORD_FLAGS)?;
+                self.write_u32(record + RECORD_FLAGS, current | flags)?;
+                record
+            };
+            self.regs.esp = bottom;
+            loop {
+                let frame = self.exception_list();
+                if frame == target || frame == END_OF_CHAIN {
+                    return Ok(());
+                }
+                let handler = self.read_u32(frame + 4)?;
+                self.call_guest(handler, None, &[record, frame, context, dispatcher_context])?;
+                let next = self.read_u32(frame)?;
+                self.set_exception_list(next)?;
+                // Registrations only ever move up the stack; anything else is corrupt.
+                if next <= frame {
+                    return Ok(());
+                }
+            }
+        })();
+        self.regs.esp = saved_esp;
+        result
+    }
+
+    // Raise a CPU fault as a guest exception. Faults the guest cannot see (emulator
+    // limits) and exceptions nothing handles come back as errors.
+    pub(super) fn raise_fault(&mut self, err: VmError) -> Result<(), VmError> {
+        match ExceptionRecord::from_fault(&err, self.regs.eip) {
+            Some(record) => self.begin_dispatch(record),
+            None => Err(err),
+        }
+    }
+
+    // Act on what the last host call asked for.
+    pub(super) fn run_exception_request(&mut self) -> Result<(), VmError> {
+        let Some(request) = self.threads.current_mut().exceptions.pending.take() else {
+            return Ok(());
+        };
+        match request {
+            ExceptionRequest::Raise(record) => self.begin_dispatch(record),
+            ExceptionRequest::Transfer { eip, esp, ebp } => {
+                self.regs.eip = eip;
+                self.regs.esp = esp;
+                self.regs.ebp = ebp;
+                self.threads.current_mut().exceptions.drop_abandoned(esp);
+                Ok(())
+            }
+        }
+    }
+
+    // A guest handler returned to DISPATCH_RETURN with its verdict in EAX.
+    pub(super) fn exception_handler_returned(&mut self) -> Result<(), VmError> {
+        let Some(dispatch) = self.threads.current().exceptions.dispatches.last().copied() else {
+            return Err(VmError::AccessViolation {
+                addr: DISPATCH_RETURN,
+                access: AccessKind::Execute,
+                eip: DISPATCH_RETURN,
+            });
+        };
+        let result = self.regs.eax;
+        let next = match dispatch.stage {
+            DispatchStage::Vectored(_) if result == EXCEPTION_CONTINUE_EXECUTION => {
+                DispatchStage::Continue(0)
+            }
+            DispatchStage::Vectored(index) => DispatchStage::Vectored(index + 1),
+            DispatchStage::Frame(frame) => match result {
+                DISPOSITION_CONTINUE_EXECUTION => {
+                    let flags = self.read_u32(dispatch.record + RECORD_FLAGS)?;
+                    if flags & EXCEPTION_NONCONTINUABLE != 0 {
+                        return self.unhandled_exception(Some(STATUS_NONCONTINUABLE_EXCEPTION));
+                    }
+                    DispatchStage::Continue(0)
+                }
+                DISPOSITION_CONTINUE_SEARCH | DISPOSITION_NESTED_EXCEPTION => {
+                    match self.read_u32(frame) {
+                        // Registrations only ever move up the stack; anything else is corrupt.
+                        Ok(next) if next > frame => DispatchStage::Frame(next),
+                        _ => DispatchStage::Filter,
+                    }
+                }
+                _ => return self.unhandled_exception(Some(STATUS_INVALID_DISPOSITION)),
+            },
+            DispatchStage::Filter if result == EXCEPTION_CONTINUE_EXECUTION => {
+                DispatchStage::Continue(0)
+            }
+            DispatchStage::Filter => return self.unhandled_exception(None),
+            DispatchStage::Continue(_) if result == EXCEPTION_CONTINUE_EXECUTION => {
+                return self.resume_from_context();
+            }
+            DispatchStage::Continue(index) => DispatchStage::Continue(index + 1),
+        };
+        self.enter_stage(next)
+    }
+
+    fn begin_dispatch(&mut self, record: ExceptionRecord) -> Result<(), VmError> {
+        self.trace_exception(record.code, record.address);
+        let esp = self.regs.esp;
+        self.threads.current_mut().exceptions.drop_abandoned(esp);
+        let bottom = esp.wrapping_sub(AREA_SIZE) & !0xF;
+        let dispatch = ExceptionDispatch {
+            stage: DispatchStage::Vectored(0),
+            bottom,
+            pointers: bottom + AREA_POINTERS,
+            dispatcher_context: bottom + AREA_DISPATCHER_CONTEXT,
+            record: bottom + AREA_RECORD,
+            context: bottom + AREA_CONTEXT,
+        };
+        // Without room on the stack for the dispatch there is nobody to hand it to.
+        if self.write_dispatch(&dispatch, &record).is_err() {
+            return Err(record.into_error());
+        }
+        self.threads
+            .current_mut()
+            .exceptions
+            .dispatches
+            .push(dispatch);
+        self.enter_stage(DispatchStage::Vectored(0))
+    }
+
+    // Call the first handler at or after `stage`, or finish the dispatch if none is left.
+    fn enter_stage(&mut self, mut stage: DispatchStage) -> Result<(), VmError> {
+        let Some(dispatch) = self.threads.current().exceptions.dispatches.last().copied() else {
+            return Ok(());
+        };
+        loop {
+            match stage {
+                DispatchStage::Vectored(index) => match self.vectored_handlers.get(false, index) {
+                    Some(handler) => {
+                        return self.call_exception_handler(stage, handler, &[dispatch.pointers])
+                    }
+                    None => stage = DispatchStage::Frame(self.exception_list()),
+                },
+                DispatchStage::Frame(frame) => {
+                    let handler = (frame != END_OF_CHAIN && frame & 3 == 0)
+                        .then(|| self.read_u32(frame + 4).ok())
+                        .flatten();
+                    match handler {
+                        Some(handler) => {
+                            let args = [
+                                dispatch.record,
+                                frame,
+                                dispatch.context,
+                                dispatch.dispatcher_context,
+                            ];
+                            return self.call_exception_handler(stage, handler, &args);
+                        }
+                        None => stage = DispatchStage::Filter,
+                    }
+                }
+                DispatchStage::Filter => {
+                    let filter = self.unhandled_exception_filter;
+                    if filter == 0 {
+                        return self.unhandled_exception(None);
+                    }
+                    return self.call_exception_handler(stage, filter, &[dispatch.pointers]);
+                }
+                DispatchStage::Continue(index) => match self.vectored_handlers.get(true, index) {
+                    Some(handler) => {
+                        return self.call_exception_handler(stage, handler, &[dispatch.pointers])
+                    }
+                    None => return self.resume_from_context(),
+                },
+            }
+        }
+    }
+
+    // Call a handler on the guest stack just below the dispatch area. Host-implemented
+    // handlers run right away and leave EIP at DISPATCH_RETURN like guest ones would.
+    fn call_exception_handler(
+        &mut self,
+        stage: DispatchStage,
+        handler: u32,
+        args: &[u32],
+    ) -> Result<(), VmError> {
+        let Some(dispatch) = self.threads.current_mut().exceptions.dispatches.last_mut() else {
+            return Ok(());
+        };
+        dispatch.stage = stage;
+        self.regs.esp = dispatch.bottom;
+        for &arg in args.iter().rev() {
+            self.push(arg)?;
+        }
+        self.push(DISPATCH_RETURN)?;
+        if !self.try_jump_import(handler)? {
+            self.regs.eip = handler;
+        }
+        Ok(())
+    }
+
+    // Nothing handled the exception: put the faulting state back and report it.
+    fn unhandled_exception(&mut self, code: Option<u32>) -> Result<(), VmError> {
+        let Some(dispatch) = self.threads.current_mut().exceptions.dispatches.pop() else {
+            return Ok(());
+        };
+        let mut record = self.read_exception_record(dispatch.record)?;
+        if let Some(code) = code {
+            record = ExceptionRecord::new(code, record.address);
+        }
+        self.load_context(dispatch.context)?;
+        Err(record.into_error())
+    }
+
+    fn resume_from_context(&mut self) -> Result<(), VmError> {
+        let Some(dispatch) = self.threads.current_mut().exceptions.dispatches.pop() else {
+            return Ok(());
+        };
+        self.load_context(dispatch.context)
+    }
+
+    fn write_dispatch(
+        &mut self,
+        dispatch: &ExceptionDispatch,
+        record: &ExceptionRecord,
+    ) -> Result<(), VmError> {
+        self.write_context(dispatch.context)?;
+        self.write_exception_record(dispatch.record, record)?;
+        self.write_u32(dispatch.pointers, dispatch.record)?;
+        self.write_u32(dispatch.pointers + 4, dispatch.context)?;
+        self.write_u32(dispatch.dispatcher_context, 0)
+    }
+
+    fn write_exception_record(
+        &mut self,
+        addr: u32,
+        record: &ExceptionRecord,
+    ) -> Result<(), VmError> {
+        let count = record.params.len().min(EXCEPTION_MAXIMUM_PARAMETERS);
+        self.write_u32(addr + RECORD_CODE, record.code)?;
+        self.write_u32(addr + RECORD_FLAGS, record.flags)?;
+        self.write_u32(addr + RECORD_NESTED, 0)?;
+        self.write_u32(addr + RECORD_ADDRESS, record.address)?;
+        self.write_u32(addr + RECORD_PARAM_COUNT, count as u32)?;
+        for (index, &param) in record.params[..count].iter().enumerate() {
+            self.write_u32(addr + RECORD_PARAMS + index as u32 * 4, param)?;
+        }
+        Ok(())
+    }
+
+    fn read_exception_record(&self, addr: u32) -> Result<ExceptionRecord, VmError> {
+        let count = self
+            .read_u32(addr + RECORD_PARAM_COUNT)?
+            .min(EXCEPTION_MAXIMUM_PARAMETERS as u32);
+        let params = (0..count)
+            .map(|index| self.read_u32(addr + RECORD_PARAMS + index * 4))
+            .collect::<Result<_, _>>()?;
+        Ok(ExceptionRecord {
+            code: self.read_u32(addr + RECORD_CODE)?,
+            flags: self.read_u32(addr + RECORD_FLAGS)?,
+            address: self.read_u32(addr + RECORD_ADDRESS)?,
+            params,
+        })
+    }
+
+    fn write_context(&mut self, addr: u32) -> Result<(), VmError> {
+        self.write_bytes(addr, &[0; CONTEXT_SIZE as usize])?;
+        let regs = self.regs.clone();
+        for (offset, value) in [
+            (CONTEXT_FLAGS, CONTEXT_FULL),
+            (CONTEXT_SEG_GS, 0),
+            (CONTEXT_SEG_FS, TEB_SELECTOR),
+            (CONTEXT_SEG_ES, USER_DATA_SELECTOR),
+            (CONTEXT_SEG_DS, USER_DATA_SELECTOR),
+            (CONTEXT_EDI, regs.edi),
+            (CONTEXT_ESI, regs.esi),
+            (CONTEXT_EBX, regs.ebx),
+            (CONTEXT_EDX, regs.edx),
+            (CONTEXT_ECX, regs.ecx),
+            (CONTEXT_EAX, regs.eax),
+            (CONTEXT_EBP, regs.ebp),
+            (CONTEXT_EIP, regs.eip),
+            (CONTEXT_SEG_CS, USER_CODE_SELECTOR),
+            (CONTEXT_EFLAGS, self.flags.to_eflags()),
+            (CONTEXT_ESP, regs.esp),
+            (CONTEXT_SEG_SS, USER_DATA_SELECTOR),
+        ] {
+            self.write_u32(addr + offset, value)?;
+        }
+        Ok(())
+    }
+
+    // Resume from a CONTEXT that handlers may have edited.
+    fn load_context(&mut self, addr: u32) -> Result<(), VmError> {
+        let read = |offset| self.read_u32(addr + offset);
+        let regs = Registers {
+            eax: read(CONTEXT_EAX)?,
+            ecx: read(CONTEXT_ECX)?,
+            edx: read(CONTEXT_EDX)?,
+            ebx: read(CONTEXT_EBX)?,
+            esp: read(CONTEXT_ESP)?,
+            ebp: read(CONTEXT_EBP)?,
+            esi: read(CONTEXT_ESI)?,
+            edi: read(CONTEXT_EDI)?,
+            eip: read(CONTEXT_EIP)?,
+        };
+        let eflags = read(CONTEXT_EFLAGS)?;
+        self.regs = regs;
+        // Like NtContinue, only the bits user mode may change are taken over.
+        self.flags.load_user(eflags);
+        Ok(())
+    }
+}
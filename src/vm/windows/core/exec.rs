@@ -35,78 +35,34 @@ impl Vm {
         if self.memory.is_empty() {
             return Err(VmError::NoImage);
         }
+        let outermost = self.stack_depth == 0 && self.debugger.depth == 0;
+        if outermost {
+            self.debugger.end_session();
+        }
         self.regs.eip = entry;
         self.push(0)?;
 
-        let limit = self.config.execution_limit_value();
-        let mut steps = 0u64;
-        while self.regs.eip != 0 {
-            if limit != 0 && steps > limit {
-                if std::env::var("PE_VM_TRACE").is_ok() {
-                    let eip = self.regs.eip;
-                    let start = eip.wrapping_sub(8);
-                    let mut bytes = [0u8; 40];
-                    for (idx, slot) in bytes.iter_mut().enumerate() {
-                        *slot = self.read_u8(start.wrapping_add(idx as u32)).unwrap_or(0);
-                    }
-                    let hex = bytes
-                        .iter()
-                        .map(|value| format!("{value:02X}"))
-                        .collect::<Vec<_>>()
-                        .join(" ");
-                    eprintln!(
-                        "[pe_vm] execution limit at eip=0x{eip:08X} eax=0x{:08X} ecx=0x{:08X} edx=0x{:08X} edi=0x{:08X} bytes@0x{start:08X}={hex}",
-                        self.regs.eax,
-                        self.regs.ecx,
-                        self.regs.edx,
-                        self.regs.edi
-                    );
-                }
-                return Err(VmError::ExecutionLimit);
-            }
-            let executor = self.executor;
-            if let Err(err) = executor.step(self) {
-                if std::env::var("PE_VM_TRACE_UNSUPPORTED").is_ok() {
-                    let eip = self.regs.eip;
-                    let start = eip.wrapping_sub(8);
-                    let mut bytes = [0u8; 32];
-                    for (idx, slot) in bytes.iter_mut().enumerate() {
-                        *slot = self.read_u8(start.wrapping_add(idx as u32)).unwrap_or(0);
-                    }
-                    let hex = bytes
-                        .iter()
-                        .map(|value| format!("{value:02X}"))
-                        .collect::<Vec<_>>()
-                        .join(" ");
-                    eprintln!(
-                        "[pe_vm] step error at eip=0x{eip:08X} err={err:?} bytes@0x{start:08X}={hex}"
-                    );
-                }
-                return Err(err);
-            }
-            steps += 1;
+        // Only the outermost loop switches threads; nested calls finish on the calling thread.
+        let root = self.threads.current_id();
+        let dispatching = std::mem::replace(&mut self.threads.dispatching, self.stack_depth == 0);
+        let result = self.run_threads(|vm| vm.threads.current_id() == root && vm.regs.eip == 0);
+        match &result {
+            // The run stays where it stopped until `resume` continues it.
+            Err(VmError::Stopped(_)) if outermost => self.debugger.session = Some(root),
+            Err(_) => self.resume_thread_after_error(root),
+            Ok(()) => {}
         }
-
-        Ok(())
-    }
-
-    pub(crate) fn queue_thread(&mut self, entry: u32, param: u32) -> u32 {
-        let handle = self.next_thread_handle;
-        self.next_thread_handle = self.next_thread_handle.wrapping_add(1);
-        self.pending_threads.push(PendingThread { entry, param });
-        handle
+        self.threads.dispatching = dispatching;
+        result
     }
 
-    pub(crate) fn run_pending_threads(&mut self) -> usize {
-        if self.pending_threads.is_empty() {
-            return 0;
-        }
-        let tasks = std::mem::take(&mut self.pending_threads);
-        let count = tasks.len();
-        for task in tasks {
-            let _ = self.execute_at_with_stack(task.entry, &[Value::U32(task.param)]);
-        }
-        count
+    pub(super) fn step_traced(&mut self) -> Result<(), VmError> {
+        let executor = self.executor;
+        let Err(err) = executor.step(self) else {
+            return Ok(());
+        };
+        self.trace_fault(self.regs.eip, &err);
+        Err(err)
     }
 
     pub(crate) fn execute_at_with_stack(
@@ -136,8 +92,7 @@ impl Vm {
             let stack_bottom = stack_top
                 .checked_sub(NESTED_STACK_SLICE_SIZE)
                 .ok_or(VmError::OutOfMemory)?;
-            let stack_base = self.base + self.heap_end as u32;
-            if stack_bottom < stack_base {
+            if stack_bottom < self.stack_limit() {
                 return Err(VmError::OutOfMemory);
             }
             self.stack_depth = depth;
@@ -149,15 +104,7 @@ impl Vm {
             self.flags = Flags::default();
 
             self.apply_values(values)?;
-            if std::env::var("PE_VM_TRACE_STACK").is_ok() {
-                let mut line = format!("[pe_vm] stack prep esp=0x{:08X}", self.regs.esp);
-                for idx in 0..6 {
-                    let addr = self.regs.esp.wrapping_add((idx * 4) as u32);
-                    let value = self.read_u32(addr).unwrap_or(0);
-                    line.push_str(&format!(" +0x{:02X}=0x{value:08X}", idx * 4));
-                }
-                eprintln!("{line}");
-            }
+            self.trace_nested_call(entry);
             self.execute(entry)?;
             Ok(self.regs.eax)
         })();
@@ -199,8 +146,7 @@ impl Vm {
             let stack_bottom = stack_top
                 .checked_sub(NESTED_STACK_SLICE_SIZE)
                 .ok_or(VmError::OutOfMemory)?;
-            let stack_base = self.base + self.heap_end as u32;
-            if stack_bottom < stack_base {
+            if stack_bottom < self.stack_limit() {
                 return Err(VmError::OutOfMemory);
             }
             self.stack_depth = depth;
@@ -213,15 +159,7 @@ impl Vm {
             self.flags = Flags::default();
 
             self.apply_values(values)?;
-            if std::env::var("PE_VM_TRACE_STACK").is_ok() {
-                let mut line = format!("[pe_vm] stack prep esp=0x{:08X}", self.regs.esp);
-                for idx in 0..6 {
-                    let addr = self.regs.esp.wrapping_add((idx * 4) as u32);
-                    let value = self.read_u32(addr).unwrap_or(0);
-                    line.push_str(&format!(" +0x{:02X}=0x{value:08X}", idx * 4));
-                }
-                eprintln!("{line}");
-            }
+            self.trace_nested_call(entry);
             self.execute(entry)?;
             Ok(self.regs.eax)
         })();
@@ -235,10 +173,52 @@ impl Vm {
         result
     }
 
-    pub(crate) fn call_host(&mut self, host: HostFunction, return_eip: u32) -> Result<(), VmError> {
+    /// Run a guest function (or host stub) to completion below the current stack pointer.
+    ///
+    /// Unlike `execute_at_with_stack` this stays on the live stack, so frame handlers,
+    /// filters and `__finally` blocks can run with `ebp` pointing at their parent frame.
+    pub(crate) fn call_guest(
+        &mut self,
+        entry: u32,
+        ebp: Option<u32>,
+        args: &[u32],
+    ) -> Result<u32, VmError> {
+        let saved_regs = self.regs.clone();
+        let saved_flags = self.flags;
+        let saved_stack_depth = self.stack_depth;
+
+        let result = (|| {
+            for &arg in args.iter().rev() {
+                self.push(arg)?;
+            }
+            if let Some(host) = self.imports_by_iat.get(&entry).copied() {
+                self.push(0)?;
+                return self.invoke_host(entry, host, self.regs.esp);
+            }
+            if let Some(ebp) = ebp {
+                self.regs.ebp = ebp;
+            }
+            self.stack_depth = saved_stack_depth + 1;
+            self.execute(entry)?;
+            Ok(self.regs.eax)
+        })();
+
+        self.regs = saved_regs;
+        self.flags = saved_flags;
+        self.stack_depth = saved_stack_depth;
+
+        result
+    }
+
+    pub(crate) fn call_host(
+        &mut self,
+        addr: u32,
+        host: HostFunction,
+        return_eip: u32,
+    ) -> Result<(), VmError> {
         self.push(return_eip)?;
         let stack_ptr = self.regs.esp;
-        let ret = (host.func)(self, stack_ptr);
+        let ret = self.invoke_host(addr, host, stack_ptr)?;
         self.regs.eax = ret;
         let ret_addr = self.pop()?;
         self.regs.esp = self.regs.esp.wrapping_add(host.stack_cleanup);
@@ -246,9 +226,9 @@ impl Vm {
         Ok(())
     }
 
-    pub(crate) fn call_host_tail(&mut self, host: HostFunction) -> Result<(), VmError> {
+    pub(crate) fn call_host_tail(&mut self, addr: u32, host: HostFunction) -> Result<(), VmError> {
         let stack_ptr = self.regs.esp;
-        let ret = (host.func)(self, stack_ptr);
+        let ret = self.invoke_host(addr, host, stack_ptr)?;
         self.regs.eax = ret;
         let ret_addr = self.pop()?;
         self.regs.esp = self.regs.esp.wrapping_add(host.stack_cleanup);
@@ -1,133 +1,290 @@
-use std::path::Path;
+use std::io::{self, Read, Seek, SeekFrom, Write};
 
 use crate::vm::*;
 
+use super::paths::ResolvedPath;
+
 impl Vm {
     pub(crate) fn file_open(
         &mut self,
         guest_path: &str,
-        readable: bool,
-        writable: bool,
-        create: bool,
-        truncate: bool,
+        options: FsOpenOptions,
     ) -> Result<u32, VmError> {
-        let host_path = self.map_path(guest_path);
-        let path = Path::new(&host_path);
-        if create {
-            if let Some(parent) = path.parent() {
-                let _ = std::fs::create_dir_all(parent);
-            }
-            let _ = std::fs::OpenOptions::new()
-                .create(true)
-                .truncate(true)
-                .write(true)
-                .open(path);
-        }
-        if truncate {
-            let _ = std::fs::OpenOptions::new()
-                .write(true)
-                .truncate(true)
-                .open(path);
+        let resolved = self.resolve_fs(guest_path)?;
+        if resolved.read_only && options.modifies() {
+            return Err(VmError::Io(io::ErrorKind::PermissionDenied.into()));
         }
-        let mut data = Vec::new();
-        if readable {
-            if let Ok(bytes) = std::fs::read(path) {
-                data = bytes;
-            }
-        }
-        self.virtual_files.insert(host_path.clone(), data);
+        let file = resolved.fs.open(&resolved.path, &options)?;
         let handle = self.file_next_handle;
         self.file_next_handle = self.file_next_handle.wrapping_add(1);
         self.file_handles.insert(
             handle,
             FileHandle {
-                path: host_path,
-                cursor: 0,
-                readable,
-                writable,
+                file,
+                readable: options.read_value(),
+                writable: options.write_value(),
             },
         );
         Ok(handle)
     }
 
+    /// Flush and close a file handle. Returns `false` for unknown handles.
     pub(crate) fn file_close(&mut self, handle: u32) -> bool {
-        self.file_handles.remove(&handle).is_some()
+        let Some(mut file) = self.file_handles.remove(&handle) else {
+            return false;
+        };
+        let _ = file.file.flush();
+        true
+    }
+
+    /// Push a handle's buffered writes to its backing store.
+    pub(crate) fn file_flush(&mut self, handle: u32) -> Option<io::Result<()>> {
+        let file = self.file_handles.get_mut(&handle)?;
+        Some(file.file.sync())
     }
 
     pub(crate) fn file_exists(&self, guest_path: &str) -> bool {
-        let host_path = self.map_path(guest_path);
-        let is_dir_hint = guest_path.ends_with(['\\', '/']);
-        if is_dir_hint
-            && !Path::new(&host_path).exists()
-            && std::fs::create_dir_all(&host_path).is_ok()
-        {
-            return true;
+        self.file_metadata(guest_path).is_ok()
+    }
+
+    pub(crate) fn file_metadata(&self, guest_path: &str) -> io::Result<FsMetadata> {
+        let resolved = self.resolve_fs(guest_path)?;
+        resolved.fs.metadata(&resolved.path)
+    }
+
+    pub(crate) fn file_delete(&mut self, guest_path: &str) -> io::Result<()> {
+        let resolved = self.writable_path(guest_path)?;
+        resolved.fs.remove_file(&resolved.path)
+    }
+
+    pub(crate) fn file_create_dir(&mut self, guest_path: &str) -> io::Result<()> {
+        let resolved = self.writable_path(guest_path)?;
+        resolved.fs.create_dir_all(&resolved.path)
+    }
+
+    pub(crate) fn file_remove_dir(&mut self, guest_path: &str) -> io::Result<()> {
+        let resolved = self.writable_path(guest_path)?;
+        resolved.fs.remove_dir(&resolved.path)
+    }
+
+    pub(crate) fn file_set_read_only(
+        &mut self,
+        guest_path: &str,
+        read_only: bool,
+    ) -> io::Result<()> {
+        let resolved = self.writable_path(guest_path)?;
+        resolved.fs.set_read_only(&resolved.path, read_only)
+    }
+
+    /// Copy a file's contents, last-write time and read-only flag.
+    pub(crate) fn file_copy(&mut self, from: &str, to: &str, overwrite: bool) -> io::Result<()> {
+        let source = self.resolve_fs(from)?;
+        let target = self.writable_path(to)?;
+        copy_file(&source, &target, overwrite)
+    }
+
+    /// Move a file or directory. Within one mount or mapping this is a rename;
+    /// files moving between backends are copied and the original deleted.
+    pub(crate) fn file_move(&mut self, from: &str, to: &str, replace: bool) -> io::Result<()> {
+        let source = self.writable_path(from)?;
+        let target = self.writable_path(to)?;
+        let metadata = source.fs.metadata(&source.path)?;
+        if let Ok(existing) = target.fs.metadata(&target.path) {
+            if !replace || existing.is_dir() || metadata.is_dir() {
+                return Err(io::ErrorKind::AlreadyExists.into());
+            }
+        }
+        if source.mount == target.mount {
+            match source.fs.rename(&source.path, &target.path) {
+                Err(err) if err.kind() == io::ErrorKind::Unsupported && !metadata.is_dir() => {}
+                result => return result,
+            }
         }
-        if let Some(data) = self.virtual_files.get(&host_path) {
-            !data.is_empty() || Path::new(&host_path).exists()
-        } else {
-            Path::new(&host_path).exists()
+        if metadata.is_dir() {
+            return Err(io::ErrorKind::CrossesDevices.into());
         }
+        copy_file(&source, &target, true)?;
+        source.fs.remove_file(&source.path)
+    }
+
+    /// Names of the entries in a guest directory.
+    pub(crate) fn file_list_dir(&self, guest_dir: &str) -> io::Result<Vec<String>> {
+        let resolved = self.resolve_fs(guest_dir)?;
+        resolved.fs.read_dir(&resolved.path)
+    }
+
+    /// Read a whole guest file, for loaders that need the image in memory.
+    pub(crate) fn file_read_all(&self, guest_path: &str) -> Result<Vec<u8>, VmError> {
+        read_all(self.resolve_fs(guest_path)?)
+    }
+
+    /// Read a file the host asked to load, outside the sandbox policy.
+    pub(crate) fn host_read_all(&self, path: &str) -> Result<Vec<u8>, VmError> {
+        read_all(self.resolve_fs_unchecked(path))
     }
 
-    pub(crate) fn file_delete(&mut self, guest_path: &str) -> bool {
-        let host_path = self.map_path(guest_path);
-        self.virtual_files.remove(&host_path);
-        std::fs::remove_file(host_path).is_ok()
+    fn writable_path(&self, guest_path: &str) -> io::Result<ResolvedPath> {
+        let resolved = self.resolve_fs(guest_path)?;
+        if resolved.read_only {
+            return Err(io::ErrorKind::PermissionDenied.into());
+        }
+        Ok(resolved)
     }
 
-    pub(crate) fn file_read(&mut self, handle: u32, len: usize) -> Option<Vec<u8>> {
+    /// Read up to `len` bytes at the handle's position. `None` for handles that
+    /// are not open files.
+    pub(crate) fn file_read(&mut self, handle: u32, len: usize) -> Option<io::Result<Vec<u8>>> {
         let file = self.file_handles.get_mut(&handle)?;
         if !file.readable {
-            return Some(Vec::new());
+            return Some(Err(io::ErrorKind::PermissionDenied.into()));
         }
-        let data = self.virtual_files.get(&file.path)?;
-        let start = file.cursor.min(data.len());
-        let end = (start + len).min(data.len());
-        file.cursor = end;
-        Some(data[start..end].to_vec())
+        let mut data = Vec::new();
+        let result = (&mut file.file).take(len as u64).read_to_end(&mut data);
+        Some(result.map(|_| data))
     }
 
-    pub(crate) fn file_write(&mut self, handle: u32, bytes: &[u8]) -> Option<usize> {
+    pub(crate) fn file_write(&mut self, handle: u32, bytes: &[u8]) -> Option<io::Result<usize>> {
         let file = self.file_handles.get_mut(&handle)?;
         if !file.writable {
-            return Some(0);
+            return Some(Err(io::ErrorKind::PermissionDenied.into()));
         }
-        let data = self.virtual_files.entry(file.path.clone()).or_default();
-        let start = file.cursor;
-        let end = start + bytes.len();
-        if data.len() < end {
-            data.resize(end, 0);
+        Some(file.file.write_all(bytes).map(|_| bytes.len()))
+    }
+
+    pub(crate) fn file_handle_metadata(&mut self, handle: u32) -> Option<io::Result<FsMetadata>> {
+        let file = self.file_handles.get_mut(&handle)?;
+        Some(file.file.metadata())
+    }
+
+    pub(crate) fn file_set_times(&mut self, handle: u32, times: FsTimes) -> Option<io::Result<()>> {
+        let file = self.file_handles.get_mut(&handle)?;
+        if !file.writable {
+            return Some(Err(io::ErrorKind::PermissionDenied.into()));
         }
-        data[start..end].copy_from_slice(bytes);
-        file.cursor = end;
-        Some(bytes.len())
+        Some(file.file.set_times(times))
     }
 
-    pub(crate) fn file_size(&self, handle: u32) -> Option<u32> {
-        let file = self.file_handles.get(&handle)?;
-        let data = self.virtual_files.get(&file.path)?;
-        u32::try_from(data.len()).ok()
+    pub(crate) fn file_size(&mut self, handle: u32) -> Option<u64> {
+        let file = self.file_handles.get_mut(&handle)?;
+        file.file.size().ok()
     }
 
     pub(crate) fn file_seek(&mut self, handle: u32, offset: i64, method: u32) -> Option<u64> {
         let file = self.file_handles.get_mut(&handle)?;
-        let len = self
-            .virtual_files
-            .get(&file.path)
-            .map(|data| data.len() as i64)
-            .unwrap_or(0);
-        let base = match method {
-            0 => 0,
-            1 => file.cursor as i64,
-            2 => len,
+        let pos = match method {
+            0 => SeekFrom::Start(u64::try_from(offset).ok()?),
+            1 => SeekFrom::Current(offset),
+            2 => SeekFrom::End(offset),
             _ => return None,
         };
-        let mut next = base + offset;
-        if next < 0 {
-            next = 0;
+        file.file.seek(pos).ok()
+    }
+
+    /// Cut or extend the file at the handle's current position.
+    pub(crate) fn file_set_end(&mut self, handle: u32) -> Option<io::Result<()>> {
+        let file = self.file_handles.get_mut(&handle)?;
+        if !file.writable {
+            return Some(Err(io::ErrorKind::PermissionDenied.into()));
         }
-        file.cursor = next as usize;
-        Some(next as u64)
+        let result = file
+            .file
+            .stream_position()
+            .and_then(|pos| file.file.set_len(pos));
+        Some(result)
+    }
+}
+
+fn copy_file(source: &ResolvedPath, target: &ResolvedPath, overwrite: bool) -> io::Result<()> {
+    let metadata = source.fs.metadata(&source.path)?;
+    if metadata.is_dir() {
+        return Err(io::ErrorKind::IsADirectory.into());
+    }
+    let mut input = source
+        .fs
+        .open(&source.path, &FsOpenOptions::new().read(true))?;
+    let options = FsOpenOptions::new()
+        .write(true)
+        .create(overwrite)
+        .create_new(!overwrite)
+        .truncate(overwrite);
+    let mut output = target.fs.open(&target.path, &options)?;
+    io::copy(&mut input, &mut output)?;
+    if let Some(modified) = metadata.times_value().modified_value() {
+        // Not every backend keeps timestamps; the copy itself still counts.
+        let _ = output.set_times(FsTimes::new().modified(modified));
+    }
+    output.sync()?;
+    drop(output);
+    if metadata.read_only_value() {
+        let _ = target.fs.set_read_only(&target.path, true);
+    }
+    Ok(())
+}
+
+fn read_all(resolved: ResolvedPath) -> Result<Vec<u8>, VmError> {
+    let options = FsOpenOptions::new().read(true);
+    let mut file = resolved.fs.open(&resolved.path, &options)?;
+    let mut data = Vec::new();
+    file.read_to_end(&mut data)?;
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::vm::*;
+
+    #[test]
+    fn mounted_memory_fs_streams_reads_and_writes() {
+        let fs = MemoryFs::new();
+        fs.insert("in.txt", b"0123456789".to_vec());
+        let config = VmConfig::new().mount("C:\\data", fs.clone());
+        let mut vm = Vm::new(config).expect("vm");
+
+        let read = FsOpenOptions::new().read(true);
+        let handle = vm.file_open("C:\\DATA\\in.txt", read).expect("open");
+        assert_eq!(
+            vm.file_read(handle, 4).expect("handle").expect("read"),
+            b"0123"
+        );
+        assert_eq!(vm.file_seek(handle, -2, 2), Some(8));
+        assert_eq!(
+            vm.file_read(handle, 16).expect("handle").expect("read"),
+            b"89"
+        );
+        assert!(vm.file_write(handle, b"x").expect("handle").is_err());
+        assert_eq!(vm.file_size(handle), Some(10));
+        assert!(vm.file_close(handle));
+
+        let write = FsOpenOptions::new().write(true).create(true);
+        let handle = vm.file_open("C:/data/sub/out.bin", write);
+        assert!(handle.is_err(), "parent directory is missing");
+        vm.file_create_dir("C:\\data\\sub").expect("mkdir");
+        let handle = vm
+            .file_open("C:\\data\\sub\\out.bin", write)
+            .expect("create");
+        let wrote = vm.file_write(handle, b"\x00\xFFbinary").expect("handle");
+        assert_eq!(wrote.expect("write"), 8);
+        assert!(vm.file_flush(handle).expect("handle").is_ok());
+        assert!(vm.file_close(handle));
+        assert_eq!(
+            fs.get("sub/out.bin").as_deref(),
+            Some(&b"\x00\xFFbinary"[..])
+        );
+
+        assert!(vm.file_metadata("C:\\data\\sub").expect("dir").is_dir());
+        vm.file_delete("C:\\data\\in.txt").expect("delete");
+        assert!(!vm.file_exists("C:\\data\\in.txt"));
+        assert!(vm.file_open("C:\\data\\in.txt", read).is_err());
+    }
+
+    #[test]
+    fn probing_a_directory_does_not_create_it() {
+        let dir = std::env::temp_dir().join(format!("pe_vm_probe_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let mut paths = PathMapping::new();
+        paths.insert("C:\\".to_string(), dir.to_string_lossy().into_owned());
+        let vm = Vm::new(VmConfig::new().paths(paths)).expect("vm");
+        assert!(!vm.file_exists("C:\\missing\\"));
+        assert!(!dir.join("missing").exists());
     }
 }
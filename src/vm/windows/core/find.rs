@@ -0,0 +1,189 @@
+use std::collections::VecDeque;
+use std::io;
+
+use crate::vm::*;
+
+use super::paths::{is_short_name, root_len, short_name};
+
+impl Vm {
+    /// Start a `FindFirstFile` search for `pattern`, a directory followed by a
+    /// mask of `*` and `?` wildcards. Returns the search handle and the first
+    /// match; `NotADirectory` when the directory is missing and `NotFound`
+    /// when nothing matches. Without `short_names` the entries' 8.3 aliases are
+    /// left empty, though the mask still matches against them.
+    pub(crate) fn find_first(
+        &mut self,
+        pattern: &str,
+        short_names: bool,
+    ) -> io::Result<(u32, FindEntry)> {
+        let full = self.full_path(pattern);
+        let split = full.rfind('\\').map_or(0, |idx| idx + 1);
+        let (dir, mask) = full.split_at(split);
+        if mask.is_empty() {
+            return Err(io::ErrorKind::NotFound.into());
+        }
+        let mut names = self.file_list_dir(dir).map_err(|err| match err.kind() {
+            io::ErrorKind::NotFound => io::ErrorKind::NotADirectory.into(),
+            _ => err,
+        })?;
+        names.sort_by_key(|name| name.to_lowercase());
+        // Every directory but a drive root lists itself and its parent first.
+        if dir.len() > root_len(dir, true) + 1 {
+            names.splice(0..0, [".".to_string(), "..".to_string()]);
+        }
+
+        let mut entries = VecDeque::new();
+        for name in &names {
+            let short = if is_short_name(name) {
+                String::new()
+            } else {
+                short_name(name, &names)
+            };
+            if !wildcard_match(mask, name) && (short.is_empty() || !wildcard_match(mask, &short)) {
+                continue;
+            }
+            let Ok(metadata) = self.file_metadata(&format!("{dir}{name}")) else {
+                continue;
+            };
+            entries.push_back(FindEntry {
+                name: name.clone(),
+                short_name: if short_names { short } else { String::new() },
+                metadata,
+            });
+        }
+        let first = entries.pop_front().ok_or(io::ErrorKind::NotFound)?;
+        let handle = self.file_next_handle;
+        self.file_next_handle = self.file_next_handle.wrapping_add(1);
+        self.find_handles.insert(handle, entries);
+        Ok((handle, first))
+    }
+
+    /// The next match of a search. `None` for unknown handles, `Some(None)`
+    /// once the search is exhausted.
+    pub(crate) fn find_next(&mut self, handle: u32) -> Option<Option<FindEntry>> {
+        let entries = self.find_handles.get_mut(&handle)?;
+        Some(entries.pop_front())
+    }
+
+    pub(crate) fn find_close(&mut self, handle: u32) -> bool {
+        self.find_handles.remove(&handle).is_some()
+    }
+}
+
+// Case-insensitive DOS wildcard match. `*.*` matches names without a dot too.
+fn wildcard_match(mask: &str, name: &str) -> bool {
+    let mask: Vec<char> = mask.to_lowercase().chars().collect();
+    let name: Vec<char> = name.to_lowercase().chars().collect();
+    if mask.ends_with(&['.', '*']) && matches(&mask[..mask.len() - 2], &name) {
+        return true;
+    }
+    matches(&mask, &name)
+}
+
+// Greedy matcher: on a mismatch, retry from the last `*` with it covering one more
+// character. Earlier stars never need revisiting, so the cost is O(mask * name).
+fn matches(mask: &[char], name: &[char]) -> bool {
+    let (mut m, mut n) = (0, 0);
+    // Mask index after the last `*`, and the name index that star resumes from.
+    let mut star = None;
+    while n < name.len() {
+        match mask.get(m) {
+            Some('*') => {
+                m += 1;
+                star = Some((m, n));
+            }
+            Some(&c) if c == '?' || c == name[n] => {
+                m += 1;
+                n += 1;
+            }
+            _ => match star {
+                Some((star_m, star_n)) => {
+                    m = star_m;
+                    n = star_n + 1;
+                    star = Some((star_m, n));
+                }
+                None => return false,
+            },
+        }
+    }
+    mask[m..].iter().all(|&c| c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::wildcard_match;
+    use crate::vm::*;
+
+    #[test]
+    fn wildcards_follow_dos_rules() {
+        assert!(wildcard_match("*.*", "README"));
+        assert!(wildcard_match("*.DLL", "plugin.dll"));
+        assert!(wildcard_match("plug??.dll", "plugin.dll"));
+        assert!(!wildcard_match("plug?.dll", "plugin.dll"));
+        assert!(!wildcard_match("*.txt", "notes.txt.bak"));
+        assert!(wildcard_match("a*b*c", "aXbYbZc"));
+        assert!(wildcard_match("*?", "x"));
+        assert!(!wildcard_match("*?", ""));
+        assert!(wildcard_match("**", ""));
+    }
+
+    #[test]
+    fn many_stars_do_not_backtrack_exponentially() {
+        let name = "a".repeat(200);
+        assert!(!wildcard_match("*a*a*a*a*a*a*a*a*a*a*b", &name));
+        assert!(wildcard_match("*a*a*a*a*a*a*a*a*a*a*", &name));
+    }
+
+    #[test]
+    fn searches_list_matching_entries_in_name_order() {
+        let fs = MemoryFs::new();
+        fs.insert("plugins/b.dll", b"bb".to_vec());
+        fs.insert("plugins/A.DLL", b"a".to_vec());
+        fs.insert("plugins/readme.txt", Vec::new());
+        fs.insert("plugins/Long Plugin Name.dll", Vec::new());
+        let mut vm = Vm::new(VmConfig::new().mount("C:\\app", fs)).expect("vm");
+
+        let (handle, first) = vm
+            .find_first("C:\\app\\plugins\\*.dll", true)
+            .expect("find");
+        assert_eq!(first.name, "A.DLL");
+        assert_eq!(first.metadata.size(), 1);
+        let mut rest = Vec::new();
+        while let Some(entry) = vm.find_next(handle).expect("handle") {
+            rest.push((entry.name, entry.short_name));
+        }
+        assert_eq!(
+            rest,
+            [
+                ("b.dll".to_string(), String::new()),
+                (
+                    "Long Plugin Name.dll".to_string(),
+                    "LONGPL~1.DLL".to_string()
+                ),
+            ]
+        );
+        assert!(vm.find_close(handle));
+        assert!(vm.find_next(handle).is_none());
+
+        let (handle, first) = vm.find_first("C:\\app\\plugins\\*", true).expect("find");
+        assert_eq!(first.name, ".");
+        assert!(first.metadata.is_dir());
+        vm.find_close(handle);
+        let (_, first) = vm
+            .find_first("C:\\app\\plugins\\LONGPL~1.DLL", false)
+            .expect("alias");
+        assert_eq!(first.name, "Long Plugin Name.dll");
+        assert!(first.short_name.is_empty());
+
+        let missing = vm
+            .find_first("C:\\app\\plugins\\*.exe", true)
+            .err()
+            .expect("no match");
+        assert_eq!(missing.kind(), std::io::ErrorKind::NotFound);
+        let missing = vm
+            .find_first("C:\\app\\nowhere\\*", true)
+            .err()
+            .expect("no dir");
+        assert_eq!(missing.kind(), std::io::ErrorKind::NotADirectory);
+    }
+}
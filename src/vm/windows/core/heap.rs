@@ -1,66 +1,216 @@
 use crate::vm::*;
 
+// Heap segments are carved out of free address space like VirtualAlloc reservations.
+const HEAP_SEGMENT_ALIGN: u32 = 0x1_0000;
+const HEAP_SEGMENT_HINT: u32 = 0x0100_0000;
+const HEAP_GROWTH: u32 = 0x10_0000;
+
 impl Vm {
     // Allocate bytes in the VM heap for host-side helpers (COM/BSTR/etc).
     pub(crate) fn alloc_bytes(&mut self, bytes: &[u8], align: usize) -> Result<u32, VmError> {
+        let size = u32::try_from(bytes.len()).map_err(|_| VmError::OutOfMemory)?;
+        let align = u32::try_from(align.max(1)).map_err(|_| VmError::OutOfMemory)?;
+        let ptr = self.heap_alloc_in(PROCESS_HEAP, size, align)?;
+        self.write_from(ptr, bytes)?;
+        Ok(ptr)
+    }
+
+    pub(crate) fn heap_alloc(&mut self, size: usize) -> u32 {
+        let Ok(size) = u32::try_from(size) else {
+            return 0;
+        };
+        self.heap_alloc_zeroed(PROCESS_HEAP, size).unwrap_or(0)
+    }
+
+    pub(crate) fn heap_free(&mut self, ptr: u32) -> bool {
+        self.heap_free_in(PROCESS_HEAP, ptr)
+    }
+
+    /// HeapCreate: map a new heap and return its handle (the base of its first segment).
+    ///
+    /// A zero `maximum` makes the heap growable; otherwise the heap is fixed at `maximum` bytes.
+    pub(crate) fn heap_create(&mut self, initial: u32, maximum: u32) -> Result<u32, VmError> {
         if self.memory.is_empty() {
             return Err(VmError::NoImage);
         }
-        let align = align.max(1);
-        let mask = align - 1;
-        let mut offset = self.heap_cursor;
-        if offset & mask != 0 {
-            offset = (offset + mask) & !mask;
+        let (size, max_size) = if maximum == 0 {
+            (initial.max(HEAP_SEGMENT_ALIGN), None)
+        } else {
+            (maximum.max(initial), Some(maximum.max(initial)))
+        };
+        let size = align_to(size, PAGE_SIZE).ok_or(VmError::OutOfMemory)?;
+        let start = self.map_heap_segment(size)?;
+        self.heaps.insert(start, Heap::new(start, size, max_size));
+        Ok(start)
+    }
+
+    /// HeapDestroy: unmap every segment of a heap created by HeapCreate.
+    pub(crate) fn heap_destroy(&mut self, handle: u32) -> bool {
+        if handle == PROCESS_HEAP {
+            return false;
         }
-        let end = offset + bytes.len();
-        if end > self.heap_end {
-            return Err(VmError::OutOfMemory);
+        let Some(heap) = self.heaps.remove(&handle) else {
+            return false;
+        };
+        for &(start, size) in heap.segments() {
+            self.memory.unmap(start, size);
         }
-        self.trace_write(
-            "alloc_bytes",
-            self.base + offset as u32,
-            bytes.len(),
-            Some(bytes),
-        );
-        self.memory[offset..end].copy_from_slice(bytes);
-        self.heap_cursor = end;
-        Ok(self.base + offset as u32)
+        self.debug_forget_heap(handle);
+        true
     }
 
-    pub(crate) fn heap_alloc(&mut self, size: usize) -> u32 {
-        let ptr = self.alloc_bytes(&vec![0u8; size], 8).unwrap_or(0);
-        if ptr != 0 {
-            self.heap_allocs.insert(ptr, size);
+    pub(crate) fn heap_alloc_in(
+        &mut self,
+        handle: u32,
+        size: u32,
+        align: u32,
+    ) -> Result<u32, VmError> {
+        if self.heap_debug.is_some() {
+            return self.debug_alloc(handle, size, align);
         }
-        ptr
+        self.raw_heap_alloc(handle, size, align)
     }
 
-    pub(crate) fn heap_realloc(&mut self, ptr: u32, size: usize) -> u32 {
-        if ptr == 0 {
-            return self.heap_alloc(size);
+    pub(super) fn raw_heap_alloc(
+        &mut self,
+        handle: u32,
+        size: u32,
+        align: u32,
+    ) -> Result<u32, VmError> {
+        let heap = self
+            .heap_mut(handle)?
+            .ok_or(VmError::InvalidConfig("invalid heap handle"))?;
+        if let Some(ptr) = heap.alloc(size, align) {
+            return Ok(ptr);
         }
-        let new_ptr = self.alloc_bytes(&vec![0u8; size], 8).unwrap_or(0);
-        if new_ptr == 0 {
-            return 0;
+        self.grow_heap(handle, size.saturating_add(align))?;
+        self.heap_mut(handle)?
+            .and_then(|heap| heap.alloc(size, align))
+            .ok_or(VmError::OutOfMemory)
+    }
+
+    pub(crate) fn heap_alloc_zeroed(&mut self, handle: u32, size: u32) -> Result<u32, VmError> {
+        let ptr = self.heap_alloc_in(handle, size, HEAP_ALIGN)?;
+        self.memset(ptr, 0, size as usize)?;
+        Ok(ptr)
+    }
+
+    /// Resize a block, moving it only when it cannot grow in place.
+    pub(crate) fn heap_realloc_in(
+        &mut self,
+        handle: u32,
+        ptr: u32,
+        size: u32,
+        in_place_only: bool,
+    ) -> Result<u32, VmError> {
+        if self.heap_debug.is_some() {
+            // Make sure a lazily created process heap exists before validating the handle.
+            self.heap_mut(handle)?;
+            return self.debug_realloc(handle, ptr, size, in_place_only);
         }
-        if let Some(old_size) = self.heap_allocs.get(&ptr).copied() {
-            let copy_len = old_size.min(size);
-            for offset in 0..copy_len {
-                if let Ok(value) = self.read_u8(ptr.wrapping_add(offset as u32)) {
-                    let _ = self.write_u8(new_ptr.wrapping_add(offset as u32), value);
-                }
+        let heap = self
+            .heap_mut(handle)?
+            .ok_or(VmError::InvalidConfig("invalid heap handle"))?;
+        let old_size = heap
+            .size_of(ptr)
+            .ok_or(VmError::InvalidConfig("pointer not allocated from heap"))?;
+        if heap.resize_in_place(ptr, size).is_some() {
+            if size > old_size {
+                self.memset(ptr + old_size, 0, (size - old_size) as usize)?;
             }
+            return Ok(ptr);
         }
-        self.heap_allocs.remove(&ptr);
-        self.heap_allocs.insert(new_ptr, size);
-        new_ptr
+        if in_place_only {
+            return Err(VmError::OutOfMemory);
+        }
+        let new_ptr = self.heap_alloc_zeroed(handle, size)?;
+        let mut buf = vec![0u8; old_size.min(size) as usize];
+        self.memory
+            .read(ptr, &mut buf)
+            .map_err(|_| VmError::MemoryOutOfRange)?;
+        self.write_from(new_ptr, &buf)?;
+        self.heap_free_in(handle, ptr);
+        Ok(new_ptr)
     }
 
-    pub(crate) fn heap_free(&mut self, ptr: u32) -> bool {
-        self.heap_allocs.remove(&ptr).is_some()
+    pub(crate) fn heap_free_in(&mut self, handle: u32, ptr: u32) -> bool {
+        if self.heap_debug.is_some() {
+            return self.debug_free(handle, ptr);
+        }
+        match self.heap_mut(handle) {
+            Ok(Some(heap)) => heap.free(ptr).is_some(),
+            _ => false,
+        }
+    }
+
+    pub(crate) fn heap_size_in(&self, handle: u32, ptr: u32) -> Option<u32> {
+        if self.heap_debug.is_some() {
+            return self.debug_block(handle, ptr).map(|block| block.size);
+        }
+        self.heaps.get(&handle)?.size_of(ptr)
     }
 
-    pub(crate) fn heap_size(&self, ptr: u32) -> Option<usize> {
-        self.heap_allocs.get(&ptr).copied()
+    /// Live allocations across every heap as `(address, size)` pairs.
+    pub(crate) fn heap_allocations(&self) -> Vec<(u32, u32)> {
+        if let Some(debug) = self.heap_debug.as_ref() {
+            return debug
+                .blocks
+                .iter()
+                .map(|(&ptr, block)| (ptr, block.size))
+                .collect();
+        }
+        self.heaps
+            .values()
+            .flat_map(|heap| heap.allocations())
+            .collect()
     }
+
+    // The process heap is created on first use from the region load_image set aside.
+    pub(super) fn heap_mut(&mut self, handle: u32) -> Result<Option<&mut Heap>, VmError> {
+        if self.memory.is_empty() {
+            return Err(VmError::NoImage);
+        }
+        if handle == PROCESS_HEAP && !self.heaps.contains_key(&PROCESS_HEAP) {
+            let start = self.base.wrapping_add(self.heap_start as u32);
+            let size = self.heap_end.saturating_sub(self.heap_start) as u32;
+            self.heaps
+                .insert(PROCESS_HEAP, Heap::new(start, size, None));
+        }
+        Ok(self.heaps.get_mut(&handle))
+    }
+
+    fn grow_heap(&mut self, handle: u32, min_size: u32) -> Result<(), VmError> {
+        let size = align_to(min_size, HEAP_SEGMENT_ALIGN)
+            .ok_or(VmError::OutOfMemory)?
+            .max(HEAP_GROWTH);
+        let can_grow = self
+            .heaps
+            .get(&handle)
+            .is_some_and(|heap| heap.can_grow(size));
+        if !can_grow {
+            return Err(VmError::OutOfMemory);
+        }
+        let start = self.map_heap_segment(size)?;
+        if let Some(heap) = self.heaps.get_mut(&handle) {
+            heap.add_segment(start, size);
+        }
+        Ok(())
+    }
+
+    fn map_heap_segment(&mut self, size: u32) -> Result<u32, VmError> {
+        let start = self
+            .memory
+            .find_free(HEAP_SEGMENT_HINT, size, HEAP_SEGMENT_ALIGN)
+            .or_else(|| {
+                self.memory
+                    .find_free(HEAP_SEGMENT_ALIGN, size, HEAP_SEGMENT_ALIGN)
+            })
+            .ok_or(VmError::OutOfMemory)?;
+        self.memory.map(start, size);
+        Ok(start)
+    }
+}
+
+fn align_to(value: u32, align: u32) -> Option<u32> {
+    Some(value.checked_add(align - 1)? & !(align - 1))
 }
@@ -0,0 +1,237 @@
+use crate::vm::*;
+
+impl Vm {
+    /// Heap misuse found so far plus guest allocations that are still live.
+    ///
+    /// Empty unless heap debugging was enabled with [`VmConfig::heap_debug`].
+    pub fn heap_report(&self) -> HeapReport {
+        let Some(debug) = self.heap_debug.as_ref() else {
+            return HeapReport::default();
+        };
+        let mut issues = debug.issues.clone();
+        let site = self.allocation_site();
+        for (&ptr, block) in &debug.blocks {
+            for kind in self.red_zone_damage(ptr, block) {
+                issues.push(block_issue(kind, ptr, block, site.clone()));
+            }
+        }
+        let leaks = debug
+            .blocks
+            .iter()
+            .filter_map(|(&ptr, block)| {
+                Some(HeapLeak {
+                    addr: ptr,
+                    size: block.size,
+                    api: block.api?,
+                    allocated_at: block.site.clone(),
+                })
+            })
+            .collect();
+        HeapReport { issues, leaks }
+    }
+
+    /// Mark a block as allocated through a guest API so it shows up in leak reports.
+    pub(crate) fn heap_debug_tag(&mut self, ptr: u32, api: HeapApi) {
+        if let Some(block) = self
+            .heap_debug
+            .as_mut()
+            .and_then(|debug| debug.blocks.get_mut(&ptr))
+        {
+            block.api = Some(api);
+        }
+    }
+
+    pub(super) fn debug_alloc(
+        &mut self,
+        handle: u32,
+        size: u32,
+        align: u32,
+    ) -> Result<u32, VmError> {
+        let align = align.max(HEAP_ALIGN).next_power_of_two();
+        let front = RED_ZONE.div_ceil(align) * align;
+        let total = size
+            .checked_add(front + RED_ZONE)
+            .ok_or(VmError::OutOfMemory)?;
+        let raw = self.raw_heap_alloc(handle, total, align)?;
+        let ptr = raw + front;
+        self.memory
+            .fill(raw, CANARY, front as usize)
+            .map_err(|_| VmError::MemoryOutOfRange)?;
+        self.memory
+            .fill(ptr + size, CANARY, RED_ZONE as usize)
+            .map_err(|_| VmError::MemoryOutOfRange)?;
+        let block = DebugBlock {
+            heap: handle,
+            raw,
+            size,
+            front,
+            api: None,
+            site: self.allocation_site(),
+        };
+        if let Some(debug) = self.heap_debug.as_mut() {
+            debug.freed.remove(&ptr);
+            debug.blocks.insert(ptr, block);
+        }
+        Ok(ptr)
+    }
+
+    pub(super) fn debug_free(&mut self, handle: u32, ptr: u32) -> bool {
+        let Some(block) = self.debug_block(handle, ptr) else {
+            self.report_bad_free(ptr);
+            return false;
+        };
+        self.check_red_zones(ptr, &block);
+        let _ = self.memory.fill(ptr, FREED_FILL, block.size as usize);
+        let freed = self
+            .heaps
+            .get_mut(&handle)
+            .is_some_and(|heap| heap.free(block.raw).is_some());
+        if let Some(debug) = self.heap_debug.as_mut() {
+            debug.blocks.remove(&ptr);
+            debug.freed.insert(ptr, block);
+        }
+        freed
+    }
+
+    // Debug blocks always move on realloc so stale pointers are caught by the next free.
+    pub(super) fn debug_realloc(
+        &mut self,
+        handle: u32,
+        ptr: u32,
+        size: u32,
+        in_place_only: bool,
+    ) -> Result<u32, VmError> {
+        let Some(block) = self.debug_block(handle, ptr) else {
+            self.report_bad_free(ptr);
+            return Err(VmError::InvalidConfig("pointer not allocated from heap"));
+        };
+        if in_place_only {
+            if size > block.size {
+                return Err(VmError::OutOfMemory);
+            }
+            self.check_red_zones(ptr, &block);
+            self.memory
+                .fill(ptr + size, CANARY, RED_ZONE as usize)
+                .map_err(|_| VmError::MemoryOutOfRange)?;
+            if let Some(block) = self
+                .heap_debug
+                .as_mut()
+                .and_then(|debug| debug.blocks.get_mut(&ptr))
+            {
+                block.size = size;
+            }
+            return Ok(ptr);
+        }
+        let new_ptr = self.debug_alloc(handle, size, HEAP_ALIGN)?;
+        let mut buf = vec![0u8; block.size.min(size) as usize];
+        self.memory
+            .read(ptr, &mut buf)
+            .map_err(|_| VmError::MemoryOutOfRange)?;
+        buf.resize(size as usize, 0);
+        self.write_from(new_ptr, &buf)?;
+        if let Some(api) = block.api {
+            self.heap_debug_tag(new_ptr, api);
+        }
+        self.debug_free(handle, ptr);
+        Ok(new_ptr)
+    }
+
+    pub(super) fn debug_block(&self, handle: u32, ptr: u32) -> Option<DebugBlock> {
+        let block = self.heap_debug.as_ref()?.blocks.get(&ptr)?;
+        (block.heap == handle).then(|| block.clone())
+    }
+
+    // HeapDestroy releases every block at once, so none of them are leaks.
+    pub(super) fn debug_forget_heap(&mut self, handle: u32) {
+        if let Some(debug) = self.heap_debug.as_mut() {
+            debug.blocks.retain(|_, block| block.heap != handle);
+            debug.freed.retain(|_, block| block.heap != handle);
+        }
+    }
+
+    fn report_bad_free(&mut self, ptr: u32) {
+        let site = self.allocation_site();
+        let Some(debug) = self.heap_debug.as_mut() else {
+            return;
+        };
+        let issue = match debug.freed.get(&ptr) {
+            Some(block) => block_issue(HeapIssueKind::DoubleFree, ptr, block, site),
+            None => HeapIssue {
+                kind: HeapIssueKind::UnknownFree,
+                addr: ptr,
+                size: 0,
+                site,
+                allocated_at: None,
+            },
+        };
+        debug.issues.push(issue);
+    }
+
+    fn check_red_zones(&mut self, ptr: u32, block: &DebugBlock) {
+        let damage = self.red_zone_damage(ptr, block);
+        if damage.is_empty() {
+            return;
+        }
+        let site = self.allocation_site();
+        if let Some(debug) = self.heap_debug.as_mut() {
+            for kind in damage {
+                debug
+                    .issues
+                    .push(block_issue(kind, ptr, block, site.clone()));
+            }
+        }
+    }
+
+    fn red_zone_damage(&self, ptr: u32, block: &DebugBlock) -> Vec<HeapIssueKind> {
+        let intact = |addr: u32, len: u32| {
+            let mut buf = vec![0u8; len as usize];
+            self.memory.read(addr, &mut buf).is_ok() && buf.iter().all(|&b| b == CANARY)
+        };
+        let mut damage = Vec::new();
+        if !intact(block.raw, block.front) {
+            damage.push(HeapIssueKind::BufferUnderrun);
+        }
+        if !intact(ptr + block.size, RED_ZONE) {
+            damage.push(HeapIssueKind::BufferOverrun);
+        }
+        damage
+    }
+
+    // Host stubs run with the guest's return address at [esp]; callers further up
+    // are found by following saved EBP values.
+    fn allocation_site(&self) -> AllocationSite {
+        let eip = self.read_u32(self.regs.esp).unwrap_or(0);
+        let mut call_stack = Vec::new();
+        let mut frame = self.regs.ebp;
+        while call_stack.len() < MAX_STACK_FRAMES && frame != 0 {
+            let (Ok(next), Ok(ret)) = (self.read_u32(frame), self.read_u32(frame.wrapping_add(4)))
+            else {
+                break;
+            };
+            if ret == 0 {
+                break;
+            }
+            call_stack.push(ret);
+            if next <= frame {
+                break;
+            }
+            frame = next;
+        }
+        AllocationSite { eip, call_stack }
+    }
+}
+
+fn block_issue(
+    kind: HeapIssueKind,
+    ptr: u32,
+    block: &DebugBlock,
+    site: AllocationSite,
+) -> HeapIssue {
+    HeapIssue {
+        kind,
+        addr: ptr,
+        size: block.size,
+        site,
+        allocated_at: Some(block.site.clone()),
+    }
+}
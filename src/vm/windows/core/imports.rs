@@ -31,6 +31,7 @@ impl Vm {
                 stack_cleanup,
             },
         );
+        self.host_modules.insert(module_key(module));
     }
 
     pub fn register_import_any(&mut self, name: &str, func: HostCall) {
@@ -79,11 +80,15 @@ impl Vm {
                 stack_cleanup,
             },
         );
+        self.host_modules.insert(module_key(module));
     }
 
     pub fn resolve_imports(&mut self, pe: &PeFile) -> Result<(), VmError> {
-        self.imports_by_iat.clear();
-        self.imports_by_iat_name.clear();
+        self.resolve_module_imports(pe, self.base)
+    }
+
+    // Bind a mapped module's IAT: guest modules first, host stubs second.
+    pub(crate) fn resolve_module_imports(&mut self, pe: &PeFile, base: u32) -> Result<(), VmError> {
         let mut missing = Vec::new();
         for import in &pe.imports {
             let mut resolved = None;
@@ -94,6 +99,13 @@ impl Vm {
             } else {
                 format!("{}!<unknown>", import.module)
             };
+            let addr = base + import.iat_rva;
+            if let Some(target) = self.resolve_guest_import(base, import)? {
+                self.imports_by_iat.remove(&addr);
+                self.imports_by_iat_name.insert(addr, label);
+                self.loader_write(addr, &target.to_le_bytes())?;
+                continue;
+            }
             if let Some(name) = &import.name {
                 if let Some(func) = self
                     .imports_by_name
@@ -117,10 +129,17 @@ impl Vm {
             }
 
             if let Some(func) = resolved {
-                let addr = self.base + import.iat_rva;
                 self.imports_by_iat.insert(addr, func);
                 self.imports_by_iat_name.insert(addr, label.clone());
-                if let Ok(value) = self.read_u32(addr) {
+                if base != self.base {
+                    // Raw thunk values collide between images, so secondary modules
+                    // get a unique host-call address written into the slot instead.
+                    let stub = self.dynamic_import_next;
+                    self.dynamic_import_next = self.dynamic_import_next.wrapping_add(4);
+                    self.imports_by_iat.insert(stub, func);
+                    self.imports_by_iat_name.insert(stub, label);
+                    self.loader_write(addr, &stub.to_le_bytes())?;
+                } else if let Ok(value) = self.read_u32(addr) {
                     if value != 0 {
                         self.imports_by_iat.insert(value, func);
                         self.imports_by_iat_name.insert(value, label.clone());
@@ -128,14 +147,7 @@ impl Vm {
                 }
             } else {
                 missing.push(label.clone());
-                if std::env::var("PE_VM_TRACE").is_ok() {
-                    if let Some(name) = &import.name {
-                        eprintln!("[pe_vm] Unresolved import: {}!{}", import.module, name);
-                    } else if let Some(ordinal) = import.ordinal {
-                        eprintln!("[pe_vm] Unresolved import: {}!#{}", import.module, ordinal);
-                    }
-                }
-                let addr = self.base + import.iat_rva;
+                self.trace_unresolved_import(&label);
                 self.imports_by_iat_name.insert(addr, label.clone());
                 if let Ok(value) = self.read_u32(addr) {
                     if value != 0 {
@@ -151,6 +163,24 @@ impl Vm {
         }
     }
 
+    // Hand out a callable address for a host stub exported by a specific DLL.
+    pub(crate) fn resolve_host_proc(&mut self, module: &str, name: &str) -> Option<u32> {
+        let key = import_key(module, name);
+        let Some(host) = self.imports_by_name.get(&key).copied() else {
+            return self.resolve_dynamic_import(name);
+        };
+        if let Some(addr) = self.dynamic_imports.get(&key) {
+            return Some(*addr);
+        }
+        let addr = self.dynamic_import_next;
+        self.dynamic_import_next = self.dynamic_import_next.wrapping_add(4);
+        self.imports_by_iat.insert(addr, host);
+        self.imports_by_iat_name
+            .insert(addr, format!("{module}!{name}"));
+        self.dynamic_imports.insert(key, addr);
+        Some(addr)
+    }
+
     pub(crate) fn resolve_dynamic_import(&mut self, name: &str) -> Option<u32> {
         let key = name.to_ascii_lowercase();
         if let Some(addr) = self.dynamic_imports.get(&key) {
@@ -168,24 +198,13 @@ impl Vm {
 
     pub(crate) fn try_call_import(&mut self, addr: u32, return_eip: u32) -> Result<bool, VmError> {
         if let Some(host) = self.imports_by_iat.get(&addr).copied() {
-            if std::env::var("PE_VM_TRACE_IMPORTS").is_ok() {
-                if let Some(name) = self.imports_by_iat_name.get(&addr) {
-                    eprintln!("[pe_vm] Import call: {name} addr=0x{addr:08X}");
-                }
-            }
-            self.call_host(host, return_eip)?;
-            if std::env::var("PE_VM_TRACE_IMPORTS").is_ok() {
-                if let Some(name) = self.imports_by_iat_name.get(&addr) {
-                    eprintln!("[pe_vm] Import return: {name} eax=0x{:08X}", self.regs.eax);
-                }
+            if self.debug_import_call(addr, host, Some(return_eip))? {
+                return Ok(true);
             }
+            self.call_host(addr, host, return_eip)?;
             Ok(true)
         } else {
-            if std::env::var("PE_VM_TRACE").is_ok() {
-                if let Some(name) = self.imports_by_iat_name.get(&addr) {
-                    eprintln!("[pe_vm] Missing import call: {name} addr=0x{addr:08X}");
-                }
-            }
+            self.trace_missing_import(addr);
             if std::env::var("PE_VM_ABORT_ON_MISSING_IMPORT").is_ok()
                 && self.imports_by_iat_name.contains_key(&addr)
             {
@@ -197,7 +216,10 @@ impl Vm {
 
     pub(crate) fn try_jump_import(&mut self, addr: u32) -> Result<bool, VmError> {
         if let Some(host) = self.imports_by_iat.get(&addr).copied() {
-            self.call_host_tail(host)?;
+            if self.debug_import_call(addr, host, None)? {
+                return Ok(true);
+            }
+            self.call_host_tail(addr, host)?;
             Ok(true)
         } else {
             Ok(false)
@@ -1,10 +1,9 @@
-use std::collections::{BTreeMap, HashMap};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::sync::{Arc, Mutex};
 
-use crate::architecture::intel::x86::X86Executor;
+use crate::architecture::intel::x86::{DecodeCache, X86Executor};
 use crate::pe::ResourceDirectory;
 
-use crate::vm::state::FpuState;
 use crate::vm::*;
 
 impl Vm {
@@ -17,20 +16,25 @@ impl Vm {
             Os::Unix => OsState::Unix,
             Os::Mac => OsState::Mac,
         };
+        let heap_debug = config.heap_debug_value().then(HeapDebug::default);
+        let decode_cache = DecodeCache::new(config.decode_cache_value());
         let mut vm = Self {
             config,
             os_state,
             base: 0,
-            memory: Vec::new(),
+            memory: GuestMemory::new(),
             regs: Registers::default(),
             xmm: [[0u8; 16]; 8],
+            mxcsr: Mxcsr::default(),
             flags: Flags::default(),
             stack_top: 0,
             stack_depth: 0,
             heap_start: 0,
             heap_end: 0,
-            heap_cursor: 0,
-            heap_allocs: HashMap::new(),
+            heaps: BTreeMap::new(),
+            heap_debug,
+            virtual_allocs: BTreeMap::new(),
+            nx_enabled: false,
             fs_base: 0,
             gs_base: 0,
             env: BTreeMap::new(),
@@ -45,10 +49,14 @@ impl Vm {
             registry_next_handle: 0x1000_0000,
             file_handles: HashMap::new(),
             file_next_handle: 0x2000,
-            virtual_files: HashMap::new(),
+            find_handles: HashMap::new(),
+            crt_io: CrtIo::new(),
+            current_dir: "C:\\".to_string(),
+            drive_dirs: BTreeMap::new(),
             tls_values: HashMap::new(),
             tls_next_index: 1,
             unhandled_exception_filter: 0,
+            vectored_handlers: VectoredHandlers::default(),
             message_box_mode: MessageBoxMode::default(),
             onexit_tables: BTreeMap::new(),
             default_onexit_table: 0,
@@ -57,13 +65,19 @@ impl Vm {
             imports_by_ordinal: HashMap::new(),
             imports_by_iat: HashMap::new(),
             imports_by_iat_name: HashMap::new(),
+            host_modules: HashSet::new(),
+            modules: ModuleTable::default(),
             dynamic_imports: HashMap::new(),
             dynamic_import_next: 0x7000_0000,
-            pending_threads: Vec::new(),
-            next_thread_handle: 0x6000_0000,
+            threads: Threads::default(),
+            sync: SyncObjects::default(),
             stdout: Arc::new(Mutex::new(Vec::new())),
             executor: X86Executor::new(),
+            decode_cache,
             fpu: FpuState::default(),
+            debugger: Debugger::default(),
+            tracer: Tracer::default(),
+            coverage: None,
         };
         // Register default Windows stubs up front for import resolution.
         if matches!(vm.config.os_value(), Os::Windows) {
@@ -107,11 +121,13 @@ impl Vm {
     }
 
     pub(crate) fn contains_addr(&self, addr: u32) -> bool {
-        addr >= self.base && addr.wrapping_sub(self.base) < self.memory.len() as u32
+        self.memory.is_mapped(addr)
     }
 
     pub(crate) fn set_image_path(&mut self, path: impl Into<String>) {
-        self.image_path = Some(path.into());
+        let path = path.into();
+        self.rename_main_module(&path);
+        self.image_path = Some(path);
     }
 
     pub(crate) fn image_path(&self) -> Option<&str> {
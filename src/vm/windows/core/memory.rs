@@ -3,6 +3,10 @@ use crate::pe::PeFile;
 use crate::vm::*;
 
 const NULL_PAGE_LIMIT: u32 = 0x1000;
+const IMAGE_SCN_MEM_EXECUTE: u32 = 0x2000_0000;
+const IMAGE_SCN_MEM_READ: u32 = 0x4000_0000;
+const IMAGE_SCN_MEM_WRITE: u32 = 0x8000_0000;
+const IMAGE_DLLCHARACTERISTICS_NX_COMPAT: u16 = 0x0100;
 
 impl Vm {
     pub fn load(pe: &PeFile, image: &[u8]) -> Result<Self, VmError> {
@@ -12,44 +16,70 @@ impl Vm {
     }
 
     pub fn load_image(&mut self, pe: &PeFile, image: &[u8]) -> Result<(), VmError> {
-        let mut loaded = pe.load_image(image, None)?;
+        let loaded = pe.load_image(image, None)?;
         let fs_size = 0x1000usize;
-        let heap_size = 0x200000usize;
+        let heap_size = self.config.heap_size_value() as usize;
         let stack_size = 0x100000usize;
         let image_size = loaded.memory.len();
         let fs_start = image_size;
         let heap_start = fs_start + fs_size;
         let heap_end = heap_start + heap_size;
+        let total_size = (heap_end + stack_size) as u64;
 
-        loaded
-            .memory
-            .resize(image_size + fs_size + heap_size + stack_size, 0);
         let base = loaded.base;
-        let stack_top = base + loaded.memory.len() as u32;
+        if base as u64 + total_size > 1u64 << 32 {
+            return Err(VmError::OutOfMemory);
+        }
+        let stack_top = base + total_size as u32;
 
+        // The FS page, heap and stack still sit directly above the image.
+        self.flush_coverage(None);
+        self.memory.clear();
+        self.memory.map(base, total_size as u32);
+        self.memory
+            .write(base, &loaded.memory)
+            .map_err(|_| VmError::MemoryOutOfRange)?;
+        self.protect_image(base, pe, image_size as u32);
+        // Like the 32-bit OptIn DEP policy, only NX-compatible images get execute checks.
+        self.nx_enabled =
+            pe.optional_header.dll_characteristics & IMAGE_DLLCHARACTERISTICS_NX_COMPAT != 0;
         self.base = base;
-        self.memory = loaded.memory;
         self.regs = Registers {
             esp: stack_top,
             ..Registers::default()
         };
         self.xmm = [[0u8; 16]; 8];
+        self.mxcsr = Mxcsr::default();
         self.flags = Flags::default();
         self.stack_top = stack_top;
         self.stack_depth = 0;
         self.heap_start = heap_start;
         self.heap_end = heap_end;
-        self.heap_cursor = heap_start;
-        self.heap_allocs.clear();
+        self.heaps.clear();
+        if self.heap_debug.is_some() {
+            self.heap_debug = Some(HeapDebug::default());
+        }
+        self.virtual_allocs.clear();
         self.fs_base = base + fs_start as u32;
         self.gs_base = 0;
+        self.threads = Threads::default();
+        self.sync = SyncObjects::default();
+        self.vectored_handlers = VectoredHandlers::default();
+        self.write_teb(
+            self.fs_base,
+            stack_top,
+            base + heap_end as u32,
+            MAIN_THREAD_ID,
+        )?;
         self.imports_by_iat.clear();
+        self.imports_by_iat_name.clear();
         self.dynamic_imports.clear();
         self.dynamic_import_next = 0x7000_0000;
         self.string_overlays.clear();
         self.resource_dir = pe.directories.resource.clone();
         self.resource_sizes.clear();
         self.fpu_reset();
+        self.register_main_module(pe, image_size as u32);
         Ok(())
     }
 
@@ -81,147 +111,202 @@ impl Vm {
     }
 
     pub fn read_u8(&self, addr: u32) -> Result<u8, VmError> {
-        if addr < self.base && addr < NULL_PAGE_LIMIT {
-            return Ok(0);
-        }
-        let offset = self.addr_to_offset(addr)?;
-        self.memory
-            .get(offset)
-            .copied()
-            .ok_or(VmError::MemoryOutOfRange)
+        let mut buf = [0u8; 1];
+        self.read_into(addr, &mut buf)?;
+        Ok(buf[0])
     }
 
     pub fn read_u16(&self, addr: u32) -> Result<u16, VmError> {
-        if addr < self.base && addr < NULL_PAGE_LIMIT {
-            return Ok(0);
-        }
-        let offset = self.addr_to_offset(addr)?;
-        if offset + 2 > self.memory.len() {
-            return Err(VmError::MemoryOutOfRange);
-        }
-        Ok(u16::from_le_bytes([
-            self.memory[offset],
-            self.memory[offset + 1],
-        ]))
+        let mut buf = [0u8; 2];
+        self.read_into(addr, &mut buf)?;
+        Ok(u16::from_le_bytes(buf))
     }
 
     pub fn read_u32(&self, addr: u32) -> Result<u32, VmError> {
-        if addr < self.base && addr < NULL_PAGE_LIMIT {
-            return Ok(0);
-        }
-        let offset = self.addr_to_offset(addr)?;
-        if offset + 4 > self.memory.len() {
-            return Err(VmError::MemoryOutOfRange);
-        }
-        Ok(u32::from_le_bytes([
-            self.memory[offset],
-            self.memory[offset + 1],
-            self.memory[offset + 2],
-            self.memory[offset + 3],
-        ]))
+        let mut buf = [0u8; 4];
+        self.read_into(addr, &mut buf)?;
+        Ok(u32::from_le_bytes(buf))
     }
 
     pub fn read_u64(&self, addr: u32) -> Result<u64, VmError> {
-        if addr < self.base && addr < NULL_PAGE_LIMIT {
-            return Ok(0);
-        }
-        let offset = self.addr_to_offset(addr)?;
-        if offset + 8 > self.memory.len() {
-            return Err(VmError::MemoryOutOfRange);
-        }
-        Ok(u64::from_le_bytes([
-            self.memory[offset],
-            self.memory[offset + 1],
-            self.memory[offset + 2],
-            self.memory[offset + 3],
-            self.memory[offset + 4],
-            self.memory[offset + 5],
-            self.memory[offset + 6],
-            self.memory[offset + 7],
-        ]))
+        let mut buf = [0u8; 8];
+        self.read_into(addr, &mut buf)?;
+        Ok(u64::from_le_bytes(buf))
+    }
+
+    pub(crate) fn read_bytes(&self, addr: u32, len: usize) -> Result<Vec<u8>, VmError> {
+        let mut buf = vec![0u8; len];
+        self.read_into(addr, &mut buf)?;
+        Ok(buf)
     }
 
     pub(crate) fn write_u8(&mut self, addr: u32, value: u8) -> Result<(), VmError> {
-        if addr < self.base && addr < NULL_PAGE_LIMIT {
-            return Ok(());
-        }
-        let offset = self.addr_to_offset(addr)?;
-        self.trace_write("write_u8", addr, 1, Some(&[value]));
-        if let Some(slot) = self.memory.get_mut(offset) {
-            *slot = value;
-            Ok(())
-        } else {
-            Err(VmError::MemoryOutOfRange)
-        }
+        self.write_from(addr, &[value])
     }
 
     pub(crate) fn write_u16(&mut self, addr: u32, value: u16) -> Result<(), VmError> {
-        if addr < self.base && addr < NULL_PAGE_LIMIT {
+        self.write_from(addr, &value.to_le_bytes())
+    }
+
+    pub(crate) fn write_u32(&mut self, addr: u32, value: u32) -> Result<(), VmError> {
+        self.write_from(addr, &value.to_le_bytes())
+    }
+
+    pub(crate) fn write_u64(&mut self, addr: u32, value: u64) -> Result<(), VmError> {
+        self.write_from(addr, &value.to_le_bytes())
+    }
+
+    pub(crate) fn write_bytes(&mut self, addr: u32, bytes: &[u8]) -> Result<(), VmError> {
+        self.write_from(addr, bytes)
+    }
+
+    pub(crate) fn memset(&mut self, addr: u32, value: u8, len: usize) -> Result<(), VmError> {
+        if self.is_null_page(addr) {
             return Ok(());
         }
-        let offset = self.addr_to_offset(addr)?;
-        if offset + 2 > self.memory.len() {
-            return Err(VmError::MemoryOutOfRange);
+        let old = self.capture_old(addr, len);
+        self.memory
+            .fill(addr, value, len)
+            .map_err(|fault| self.memory_error(fault, AccessKind::Write))?;
+        self.debugger
+            .record_access(addr, len as u32, AccessKind::Write);
+        if self.tracer.traces_memory() || old.is_some() {
+            let bytes = vec![value; len];
+            self.tracer.record_access(addr, AccessKind::Write, &bytes);
+            if let Some(old) = old {
+                self.tracer.capture_write(addr, &old, &bytes);
+            }
         }
-        let bytes = value.to_le_bytes();
-        self.trace_write("write_u16", addr, bytes.len(), Some(&bytes));
-        self.memory[offset..offset + 2].copy_from_slice(&bytes);
         Ok(())
     }
 
-    pub(crate) fn write_u32(&mut self, addr: u32, value: u32) -> Result<(), VmError> {
-        if addr < self.base && addr < NULL_PAGE_LIMIT {
-            return Ok(());
-        }
-        let offset = self.addr_to_offset(addr)?;
-        if offset + 4 > self.memory.len() {
+    /// Map zero-filled pages covering `addr..addr + size` anywhere in the 32-bit space.
+    ///
+    /// Pages that are already mapped keep their contents.
+    pub fn map_memory(&mut self, addr: u32, size: u32) -> Result<(), VmError> {
+        if size == 0 || addr as u64 + size as u64 > 1u64 << 32 {
             return Err(VmError::MemoryOutOfRange);
         }
-        let bytes = value.to_le_bytes();
-        self.trace_write("write_u32", addr, bytes.len(), Some(&bytes));
-        self.memory[offset..offset + 4].copy_from_slice(&bytes);
+        self.memory.map(addr, size);
         Ok(())
     }
 
-    pub(crate) fn write_u64(&mut self, addr: u32, value: u64) -> Result<(), VmError> {
-        if addr < self.base && addr < NULL_PAGE_LIMIT {
-            return Ok(());
+    pub fn unmap_memory(&mut self, addr: u32, size: u32) {
+        self.memory.unmap(addr, size);
+    }
+
+    pub fn is_mapped(&self, addr: u32) -> bool {
+        self.memory.is_mapped(addr)
+    }
+
+    /// Change the `PAGE_*` protection of committed pages, returning the previous value.
+    pub fn protect_memory(&mut self, addr: u32, size: u32, protect: u32) -> Result<u32, VmError> {
+        if !is_valid_protection(protect) {
+            return Err(VmError::InvalidConfig("invalid page protection"));
         }
-        let offset = self.addr_to_offset(addr)?;
-        if offset + 8 > self.memory.len() {
-            return Err(VmError::MemoryOutOfRange);
+        self.memory
+            .protect(addr, size, protect)
+            .map_err(|fault| self.memory_error(fault, AccessKind::Read))
+    }
+
+    /// Current `PAGE_*` protection of the committed page containing `addr`.
+    pub fn memory_protection(&self, addr: u32) -> Option<u32> {
+        self.memory
+            .page_info(addr)
+            .filter(|info| info.committed)
+            .map(|info| info.protect)
+    }
+
+    // Instruction fetch check; without DEP any readable page may be executed.
+    pub(crate) fn check_execute(&self, addr: u32) -> Result<(), VmError> {
+        let access = if self.nx_enabled {
+            AccessKind::Execute
+        } else {
+            AccessKind::Read
+        };
+        self.memory
+            .check(addr, 1, access)
+            .map_err(|fault| self.memory_error(fault, AccessKind::Execute))
+    }
+
+    // Instruction bytes the executor cached; see `GuestMemory::watch_code`.
+    pub(crate) fn watch_code(&mut self, addr: u32, len: u32) {
+        self.memory.watch_code(addr, len);
+    }
+
+    pub(crate) fn take_modified_code(&mut self) -> Vec<u32> {
+        self.memory.take_modified_code()
+    }
+
+    // Loader writes (IAT binding, image mapping) ignore page protections.
+    pub(super) fn loader_write(&mut self, addr: u32, bytes: &[u8]) -> Result<(), VmError> {
+        let old = self.capture_old(addr, bytes.len());
+        self.memory
+            .write_unchecked(addr, bytes)
+            .map_err(|fault| self.memory_error(fault, AccessKind::Write))?;
+        if let Some(old) = old {
+            self.tracer.capture_write(addr, &old, bytes);
         }
-        let bytes = value.to_le_bytes();
-        self.trace_write("write_u64", addr, bytes.len(), Some(&bytes));
-        self.memory[offset..offset + 8].copy_from_slice(&bytes);
         Ok(())
     }
 
-    pub(crate) fn write_bytes(&mut self, addr: u32, bytes: &[u8]) -> Result<(), VmError> {
-        if addr < self.base && addr < NULL_PAGE_LIMIT {
-            return Ok(());
+    // Apply section characteristics to a mapped image; headers stay read-only.
+    pub(super) fn protect_image(&mut self, base: u32, pe: &PeFile, size: u32) {
+        let _ = self.memory.protect(base, size, PAGE_READONLY);
+        let alignment = pe.optional_header.section_alignment.max(PAGE_SIZE);
+        for section in &pe.sections {
+            let span = if section.virtual_size != 0 {
+                section.virtual_size
+            } else {
+                section.raw_size
+            };
+            if span == 0 {
+                continue;
+            }
+            let start = section.virtual_address;
+            let span = span.div_ceil(alignment).saturating_mul(alignment);
+            let span = span.min(size.saturating_sub(start));
+            if span == 0 {
+                continue;
+            }
+            let protect = section_protection(section.characteristics);
+            let _ = self.memory.protect(base.wrapping_add(start), span, protect);
         }
-        let offset = self.addr_to_offset(addr)?;
-        let end = offset.saturating_add(bytes.len());
-        if end > self.memory.len() {
-            return Err(VmError::MemoryOutOfRange);
+    }
+
+    // The null page reads as zero and swallows writes unless something maps it explicitly.
+    fn is_null_page(&self, addr: u32) -> bool {
+        addr < NULL_PAGE_LIMIT && !self.memory.is_mapped(addr)
+    }
+
+    fn read_into(&self, addr: u32, buf: &mut [u8]) -> Result<(), VmError> {
+        self.debugger
+            .record_access(addr, buf.len() as u32, AccessKind::Read);
+        if self.is_null_page(addr) {
+            buf.fill(0);
+        } else {
+            self.memory
+                .read(addr, buf)
+                .map_err(|fault| self.memory_error(fault, AccessKind::Read))?;
         }
-        self.trace_write("write_bytes", addr, bytes.len(), Some(bytes));
-        self.memory[offset..end].copy_from_slice(bytes);
+        self.tracer.record_access(addr, AccessKind::Read, buf);
         Ok(())
     }
 
-    pub(crate) fn memset(&mut self, addr: u32, value: u8, len: usize) -> Result<(), VmError> {
-        if addr < self.base && addr < NULL_PAGE_LIMIT {
+    pub(super) fn write_from(&mut self, addr: u32, bytes: &[u8]) -> Result<(), VmError> {
+        self.debugger
+            .record_access(addr, bytes.len() as u32, AccessKind::Write);
+        if self.is_null_page(addr) {
             return Ok(());
         }
-        let offset = self.addr_to_offset(addr)?;
-        let end = offset.saturating_add(len);
-        if end > self.memory.len() {
-            return Err(VmError::MemoryOutOfRange);
+        let old = self.capture_old(addr, bytes.len());
+        self.memory
+            .write(addr, bytes)
+            .map_err(|fault| self.memory_error(fault, AccessKind::Write))?;
+        self.tracer.record_access(addr, AccessKind::Write, bytes);
+        if let Some(old) = old {
+            self.tracer.capture_write(addr, &old, bytes);
         }
-        self.trace_write("memset", addr, len, Some(&[value]));
-        self.memory[offset..end].fill(value);
         Ok(())
     }
 
@@ -238,146 +323,39 @@ impl Vm {
         Ok(value)
     }
 
-    fn addr_to_offset(&self, addr: u32) -> Result<usize, VmError> {
-        if addr < self.base {
-            self.log_memory_error(addr);
-            return Err(VmError::MemoryOutOfRange);
-        }
-        let offset = (addr - self.base) as usize;
-        if offset >= self.memory.len() {
-            self.log_memory_error(addr);
-            return Err(VmError::MemoryOutOfRange);
-        }
-        Ok(offset)
-    }
-
-    fn log_memory_error(&self, addr: u32) {
-        if std::env::var("PE_VM_TRACE").is_ok() {
-            eprintln!(
-                "[pe_vm] memory out of range: addr=0x{addr:08X} eip=0x{:08X} base=0x{:08X} size=0x{:08X}",
-                self.regs.eip,
-                self.base,
-                self.memory.len()
-            );
-            eprintln!(
-                "[pe_vm] regs: eax=0x{:08X} ecx=0x{:08X} edx=0x{:08X} ebx=0x{:08X} esp=0x{:08X} ebp=0x{:08X} esi=0x{:08X} edi=0x{:08X}",
-                self.regs.eax,
-                self.regs.ecx,
-                self.regs.edx,
-                self.regs.ebx,
-                self.regs.esp,
-                self.regs.ebp,
-                self.regs.esi,
-                self.regs.edi
-            );
-            if let Ok(value) = self.read_u32(self.regs.edi) {
-                eprintln!("[pe_vm] mem[edi]=0x{value:08X}");
-            }
-            if let Ok(value) = self.read_u32(self.regs.edi.wrapping_add(0x0C)) {
-                eprintln!("[pe_vm] mem[edi+0x0C]=0x{value:08X}");
-            }
-            if let Ok(value) = self.read_u32(self.regs.ebp.wrapping_add(0x08)) {
-                eprintln!("[pe_vm] mem[ebp+0x08]=0x{value:08X}");
-            }
-            if let Ok(value) = self.read_u32(self.regs.ebp.wrapping_add(0x0C)) {
-                eprintln!("[pe_vm] mem[ebp+0x0C]=0x{value:08X}");
-            }
-            if let Ok(value) = self.read_u32(self.regs.ebp.wrapping_add(0x10)) {
-                eprintln!("[pe_vm] mem[ebp+0x10]=0x{value:08X}");
-            }
-        }
-    }
-
-    pub(crate) fn trace_write(&self, label: &str, addr: u32, len: usize, bytes: Option<&[u8]>) {
-        let Ok(watch) = std::env::var("PE_VM_TRACE_ADDR") else {
-            return;
-        };
-        let mut hit = false;
-        for token in watch.split(|ch: char| ch == ',' || ch.is_whitespace()) {
-            let token = token.trim();
-            if token.is_empty() {
-                continue;
-            }
-            let (start, end) = match parse_watch_range(token) {
-                Some(range) => range,
-                None => continue,
-            };
-            let write_end = addr.saturating_add(len.saturating_sub(1) as u32);
-            if write_end < start || addr > end {
-                continue;
-            }
-            hit = true;
-            break;
-        }
-        if !hit {
-            return;
-        }
-        let end = addr.saturating_add(len.saturating_sub(1) as u32);
-        let preview = bytes
-            .map(format_bytes)
-            .unwrap_or_else(|| "<none>".to_string());
-        let inst = read_inst_bytes(self, self.regs.eip, 8);
-        eprintln!(
-            "[pe_vm] trace_write {label} addr=0x{addr:08X}..0x{end:08X} len={len} eip=0x{:08X} esp=0x{:08X} inst={inst} preview={preview}",
-            self.regs.eip,
-            self.regs.esp
-        );
-    }
-}
-
-fn parse_watch_range(token: &str) -> Option<(u32, u32)> {
-    let token = token.trim();
-    if token.is_empty() {
-        return None;
-    }
-    if let Some((start, end)) = token.split_once('-') {
-        let start = parse_watch_addr(start)?;
-        let end = parse_watch_addr(end)?;
-        let (min, max) = if start <= end {
-            (start, end)
-        } else {
-            (end, start)
-        };
-        return Some((min, max));
+    /// Push a word, as the 16-bit operand-size forms of PUSH do.
+    pub(crate) fn push16(&mut self, value: u16) -> Result<(), VmError> {
+        let new_esp = self.regs.esp.wrapping_sub(2);
+        self.write_u16(new_esp, value)?;
+        self.regs.esp = new_esp;
+        Ok(())
     }
-    let addr = parse_watch_addr(token)?;
-    Some((addr, addr))
-}
 
-fn parse_watch_addr(token: &str) -> Option<u32> {
-    let token = token.trim();
-    if let Some(hex) = token
-        .strip_prefix("0x")
-        .or_else(|| token.strip_prefix("0X"))
-    {
-        return u32::from_str_radix(hex, 16).ok();
+    pub(crate) fn pop16(&mut self) -> Result<u16, VmError> {
+        let value = self.read_u16(self.regs.esp)?;
+        self.regs.esp = self.regs.esp.wrapping_add(2);
+        Ok(value)
     }
-    token.parse::<u32>().ok()
-}
 
-fn format_bytes(bytes: &[u8]) -> String {
-    let limit = bytes.len().min(16);
-    let mut out = String::new();
-    for (idx, byte) in bytes.iter().take(limit).enumerate() {
-        if idx > 0 {
-            out.push(' ');
+    fn memory_error(&self, addr: u32, access: AccessKind) -> VmError {
+        VmError::AccessViolation {
+            addr,
+            access,
+            eip: self.regs.eip,
         }
-        out.push_str(&format!("{byte:02X}"));
-    }
-    if bytes.len() > limit {
-        out.push_str(" ...");
     }
-    out
 }
 
-fn read_inst_bytes(vm: &Vm, addr: u32, len: usize) -> String {
-    let mut out = String::new();
-    for idx in 0..len {
-        let byte = vm.read_u8(addr.wrapping_add(idx as u32)).unwrap_or(0);
-        if idx > 0 {
-            out.push(' ');
-        }
-        out.push_str(&format!("{byte:02X}"));
+fn section_protection(characteristics: u32) -> u32 {
+    let execute = characteristics & IMAGE_SCN_MEM_EXECUTE != 0;
+    let read = characteristics & IMAGE_SCN_MEM_READ != 0;
+    let write = characteristics & IMAGE_SCN_MEM_WRITE != 0;
+    match (execute, read, write) {
+        (true, _, true) => PAGE_EXECUTE_READWRITE,
+        (true, true, false) => PAGE_EXECUTE_READ,
+        (true, false, false) => PAGE_EXECUTE,
+        (false, _, true) => PAGE_READWRITE,
+        (false, true, false) => PAGE_READONLY,
+        (false, false, false) => PAGE_NOACCESS,
     }
-    out
 }
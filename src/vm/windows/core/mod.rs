@@ -1,15 +1,31 @@
 //! VM execution core.
 
 mod com;
+mod coverage;
+mod crt_io;
+mod crt_seh;
+mod cxx_eh;
+mod debugger;
 mod env;
+mod exceptions;
 mod exec;
 mod file;
+mod find;
 mod heap;
+mod heap_debug;
 mod imports;
 mod init;
 mod memory;
+mod modules;
 mod paths;
 mod registers;
 mod registry;
 mod state;
+mod sync;
+mod threads;
 mod tls;
+mod trace;
+mod virtual_memory;
+
+pub(crate) use crt_seh::except_handler4_common;
+pub(crate) use cxx_eh::{cxx_frame_handler, cxx_throw_exception};
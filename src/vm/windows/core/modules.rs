@@ -0,0 +1,347 @@
+use crate::pe::{ImportSymbol, PeFile};
+
+use crate::vm::*;
+
+const MODULE_ALIGNMENT: u32 = 0x1_0000;
+const DLL_PROCESS_DETACH: u32 = 0;
+const DLL_PROCESS_ATTACH: u32 = 1;
+const IMAGE_FILE_RELOCS_STRIPPED: u16 = 0x0001;
+const IMAGE_FILE_DLL: u16 = 0x2000;
+const DEFAULT_IMAGE_PATH: &str = "C:\\pe_vm\\module.dll";
+const SYSTEM_DIRECTORY: &str = "C:\\Windows\\System32";
+const MAX_FORWARDER_DEPTH: u32 = 8;
+
+// LoadLibraryEx flags that map the image without binding imports or calling DllMain.
+const DONT_RESOLVE_DLL_REFERENCES: u32 = 0x0000_0001;
+const LOAD_LIBRARY_AS_DATAFILE: u32 = 0x0000_0002;
+const LOAD_LIBRARY_AS_IMAGE_RESOURCE: u32 = 0x0000_0020;
+const LOAD_LIBRARY_AS_DATAFILE_EXCLUSIVE: u32 = 0x0000_0040;
+const LOAD_LIBRARY_MAP_ONLY: u32 = DONT_RESOLVE_DLL_REFERENCES
+    | LOAD_LIBRARY_AS_DATAFILE
+    | LOAD_LIBRARY_AS_IMAGE_RESOURCE
+    | LOAD_LIBRARY_AS_DATAFILE_EXCLUSIVE;
+
+impl Vm {
+    /// Load a DLL by name or guest path, bind its imports and run `DllMain`.
+    ///
+    /// Host-implemented DLLs (kernel32, user32, ...) return a pseudo handle instead
+    /// of being mapped. Loading an already loaded module bumps its load count.
+    pub fn load_module(&mut self, name: &str) -> Result<u32, VmError> {
+        self.load_library(name, 0)
+    }
+
+    /// Map an already parsed image as an additional module and initialize it.
+    pub fn load_module_image(
+        &mut self,
+        guest_path: &str,
+        pe: &PeFile,
+        image: &[u8],
+    ) -> Result<u32, VmError> {
+        if let Some(existing) = self.modules.by_name(guest_path) {
+            let base = existing.base();
+            if let Some(module) = self.modules.by_handle_mut(base) {
+                module.add_ref();
+            }
+            return Ok(base);
+        }
+        let base = self.map_module(guest_path, pe, image)?;
+        let result = self
+            .resolve_module_imports(pe, base)
+            .and_then(|_| self.attach_module(base));
+        if let Err(err) = result {
+            self.unload_module(base);
+            return Err(err);
+        }
+        Ok(base)
+    }
+
+    /// Drop one reference to a module, running `DLL_PROCESS_DETACH` when it reaches zero.
+    pub fn free_module(&mut self, handle: u32) -> bool {
+        let Some(module) = self.modules.by_handle_mut(handle) else {
+            return false;
+        };
+        if !module.release() {
+            return true;
+        }
+        let entry = module.entry_point();
+        let dll = module.is_dll();
+        if dll && entry != 0 {
+            let args = [
+                Value::U32(handle),
+                Value::U32(DLL_PROCESS_DETACH),
+                Value::U32(0),
+            ];
+            let _ = self.execute_at_with_stack(entry, &args);
+        }
+        self.flush_coverage(Some(handle));
+        self.unload_module(handle);
+        true
+    }
+
+    // Drop the module table entry, the image pages and the imports bound inside them.
+    fn unload_module(&mut self, base: u32) {
+        if let Some(module) = self.modules.remove(base) {
+            let end = base.wrapping_add(module.size());
+            self.imports_by_iat
+                .retain(|addr, _| *addr < base || *addr >= end);
+            self.unmap_memory(base, module.size());
+        }
+    }
+
+    /// Iterate the loaded module table, main image first.
+    pub fn modules(&self) -> impl Iterator<Item = &LoadedModule> {
+        self.modules.iter()
+    }
+
+    pub fn module(&self, name: &str) -> Option<&LoadedModule> {
+        self.modules.by_name(name)
+    }
+
+    pub fn module_by_handle(&self, handle: u32) -> Option<&LoadedModule> {
+        self.modules.by_handle(handle)
+    }
+
+    /// Resolve an export of a loaded module (`#n` selects by ordinal), following forwarders.
+    pub fn module_proc_address(&mut self, handle: u32, name: &str) -> Option<u32> {
+        self.module_proc_address_depth(handle, name, 0)
+    }
+
+    pub(crate) fn module_by_addr(&self, addr: u32) -> Option<&LoadedModule> {
+        self.modules.by_addr(addr)
+    }
+
+    pub(crate) fn main_module_path(&self) -> &str {
+        self.image_path.as_deref().unwrap_or(DEFAULT_IMAGE_PATH)
+    }
+
+    // Find a loaded module or a host-implemented DLL without touching the disk.
+    pub(crate) fn module_handle(&mut self, name: &str) -> Option<u32> {
+        if let Some(module) = self.modules.by_name(name) {
+            return Some(module.base());
+        }
+        if self.is_host_module(name) {
+            return Some(self.modules.insert_host(name));
+        }
+        None
+    }
+
+    pub(crate) fn load_library(&mut self, name: &str, flags: u32) -> Result<u32, VmError> {
+        if let Some(existing) = self.modules.by_name(name) {
+            let base = existing.base();
+            if let Some(module) = self.modules.by_handle_mut(base) {
+                module.add_ref();
+            }
+            return Ok(base);
+        }
+        if self.is_host_module(name) {
+            return Ok(self.modules.insert_host(name));
+        }
+        let path = self.find_module_file(name, None).ok_or_else(|| {
+            VmError::Io(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("module not found: {name}"),
+            ))
+        })?;
+        let image = self.file_read_all(&path)?;
+        let file = PeFile::parse(&image)?;
+        if flags & LOAD_LIBRARY_MAP_ONLY != 0 {
+            return self.map_module(&path, &file, &image);
+        }
+        self.load_module_image(&path, &file, &image)
+    }
+
+    pub(super) fn register_main_module(&mut self, pe: &PeFile, size: u32) {
+        self.modules.clear();
+        let entry_rva = pe.optional_header.address_of_entry_point;
+        let entry = if entry_rva == 0 {
+            0
+        } else {
+            self.base.wrapping_add(entry_rva)
+        };
+        let mut module = LoadedModule::guest(
+            self.main_module_path(),
+            self.base,
+            size,
+            entry,
+            pe.exports.clone(),
+            pe.file_header.characteristics & IMAGE_FILE_DLL != 0,
+        );
+        module.pin();
+        self.modules.insert(module);
+    }
+
+    pub(super) fn rename_main_module(&mut self, path: &str) {
+        let base = self.base;
+        if let Some(module) = self.modules.by_handle_mut(base) {
+            module.set_path(path);
+        }
+    }
+
+    // Map the image at its preferred base when free, otherwise relocate it to the next gap.
+    fn map_module(&mut self, guest_path: &str, pe: &PeFile, image: &[u8]) -> Result<u32, VmError> {
+        if self.memory.is_empty() {
+            return Err(VmError::NoImage);
+        }
+        let size = pe.optional_header.size_of_image;
+        let preferred = pe.image_base();
+        let base = if preferred != 0
+            && preferred as u64 + size as u64 <= 1u64 << 32
+            && self.memory.is_range_free(preferred, size)
+        {
+            preferred
+        } else {
+            if pe.file_header.characteristics & IMAGE_FILE_RELOCS_STRIPPED != 0 {
+                return Err(VmError::InvalidConfig("module has no relocations"));
+            }
+            self.memory
+                .find_free(preferred, size, MODULE_ALIGNMENT)
+                .or_else(|| {
+                    self.memory
+                        .find_free(MODULE_ALIGNMENT, size, MODULE_ALIGNMENT)
+                })
+                .ok_or(VmError::OutOfMemory)?
+        };
+        let loaded = pe.load_image(image, Some(base))?;
+        self.map_memory(base, loaded.memory.len() as u32)?;
+        self.loader_write(base, &loaded.memory)?;
+        self.protect_image(base, pe, loaded.memory.len() as u32);
+
+        let dll = pe.file_header.characteristics & IMAGE_FILE_DLL != 0;
+        let entry_rva = pe.optional_header.address_of_entry_point;
+        let entry = if entry_rva == 0 {
+            0
+        } else {
+            base.wrapping_add(entry_rva)
+        };
+        self.modules.insert(LoadedModule::guest(
+            guest_path,
+            base,
+            loaded.memory.len() as u32,
+            entry,
+            pe.exports.clone(),
+            dll,
+        ));
+        self.trace_module_mapped(guest_path, base, loaded.memory.len() as u32);
+        Ok(base)
+    }
+
+    fn attach_module(&mut self, base: u32) -> Result<(), VmError> {
+        let Some(module) = self.modules.by_handle(base) else {
+            return Ok(());
+        };
+        let entry = module.entry_point();
+        if !module.is_dll() || entry == 0 {
+            return Ok(());
+        }
+        let args = [
+            Value::U32(base),
+            Value::U32(DLL_PROCESS_ATTACH),
+            Value::U32(0),
+        ];
+        if self.execute_at_with_stack(entry, &args)? == 0 {
+            return Err(VmError::InvalidConfig("DllMain returned failure"));
+        }
+        Ok(())
+    }
+
+    // Bind an import to a guest module, loading it from disk when it is not host-implemented.
+    pub(super) fn resolve_guest_import(
+        &mut self,
+        importer: u32,
+        import: &ImportSymbol,
+    ) -> Result<Option<u32>, VmError> {
+        let module_base = match self.modules.by_name(&import.module) {
+            Some(module) if module.is_host() => return Ok(None),
+            Some(module) => module.base(),
+            None => {
+                if self.is_host_module(&import.module) {
+                    return Ok(None);
+                }
+                let Some(path) = self.find_module_file(&import.module, Some(importer)) else {
+                    return Ok(None);
+                };
+                let image = self.file_read_all(&path)?;
+                let file = PeFile::parse(&image)?;
+                let base = self.load_module_image(&path, &file, &image)?;
+                // Static dependencies stay loaded for the lifetime of the importer.
+                if let Some(module) = self.modules.by_handle_mut(base) {
+                    module.pin();
+                }
+                base
+            }
+        };
+        let symbol = match (&import.name, import.ordinal) {
+            (Some(name), _) => name.clone(),
+            (None, Some(ordinal)) => format!("#{ordinal}"),
+            (None, None) => return Ok(None),
+        };
+        Ok(self.module_proc_address(module_base, &symbol))
+    }
+
+    fn module_proc_address_depth(&mut self, handle: u32, name: &str, depth: u32) -> Option<u32> {
+        let module = self.modules.by_handle(handle)?;
+        if module.is_host() {
+            let module_name = module.name().to_string();
+            return self.resolve_host_proc(&module_name, name);
+        }
+        let symbol = module.export(name)?;
+        let Some(forwarder) = symbol.forwarder.clone() else {
+            if symbol.rva == 0 {
+                return None;
+            }
+            return Some(module.base().wrapping_add(symbol.rva));
+        };
+        if depth >= MAX_FORWARDER_DEPTH {
+            return None;
+        }
+        // Forwarders look like "NTDLL.RtlAllocateHeap" or "NTDLL.#12".
+        let (target_module, target_name) = forwarder.split_once('.')?;
+        let target = match self.module_handle(target_module) {
+            Some(target) => target,
+            None => self.load_library(target_module, 0).ok()?,
+        };
+        self.module_proc_address_depth(target, target_name, depth + 1)
+    }
+
+    fn is_host_module(&self, name: &str) -> bool {
+        let key = module_key(name);
+        self.host_modules.contains(&key)
+            || key.starts_with("api-ms-win-")
+            || key.starts_with("ext-ms-")
+    }
+
+    // Search order: explicit path, importer directory, main image directory, system directory.
+    fn find_module_file(&self, name: &str, importer: Option<u32>) -> Option<String> {
+        if name.contains(['\\', '/', ':']) {
+            return self.file_exists(name).then(|| name.to_string());
+        }
+        let file = if name.contains('.') {
+            name.trim_end_matches('.').to_string()
+        } else {
+            format!("{name}.dll")
+        };
+        let mut dirs = Vec::new();
+        if let Some(module) = importer.and_then(|base| self.modules.by_handle(base)) {
+            dirs.extend(parent_dir(module.path()).map(str::to_string));
+        }
+        dirs.extend(parent_dir(self.main_module_path()).map(str::to_string));
+        dirs.push(SYSTEM_DIRECTORY.to_string());
+        dirs.dedup();
+        dirs.into_iter()
+            .map(|dir| join_path(&dir, &file))
+            .find(|candidate| self.file_exists(candidate))
+    }
+}
+
+fn parent_dir(path: &str) -> Option<&str> {
+    path.rsplit_once(['\\', '/']).map(|(dir, _)| dir)
+}
+
+fn join_path(dir: &str, file: &str) -> String {
+    let sep = if dir.contains('/') && !dir.contains('\\') {
+        '/'
+    } else {
+        '\\'
+    };
+    format!("{dir}{sep}{file}")
+}
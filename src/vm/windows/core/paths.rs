@@ -1,3 +1,7 @@
+use std::collections::BTreeMap;
+use std::io;
+use std::sync::Arc;
+
 use crate::vm::*;
 
 impl Vm {
@@ -7,20 +11,9 @@ impl Vm {
         }
         let is_windows = matches!(self.config.os_value(), Os::Windows);
         let input = normalize_path(path, is_windows);
-        let mut best_match: Option<(&String, &String)> = None;
-        for (guest_prefix, host_prefix) in self.config.paths_ref() {
-            let guest = normalize_path(guest_prefix, is_windows);
-            if path_starts_with(&input, &guest, is_windows) {
-                let is_better = best_match
-                    .as_ref()
-                    .map(|(best, _)| guest.len() > normalize_path(best, is_windows).len())
-                    .unwrap_or(true);
-                if is_better {
-                    best_match = Some((guest_prefix, host_prefix));
-                }
-            }
-        }
-        let Some((guest_prefix, host_prefix)) = best_match else {
+        let Some((guest_prefix, host_prefix)) =
+            best_prefix(&input, self.config.paths_ref(), is_windows)
+        else {
             return input;
         };
         let guest = normalize_path(guest_prefix, is_windows);
@@ -46,6 +39,394 @@ impl Vm {
             format!("{host_prefix}{remainder}")
         }
     }
+
+    /// Absolute form of a guest path, as `GetFullPathName` returns it: relative
+    /// and drive-relative paths are joined to the current directory of their
+    /// drive, `\\?\` prefixes are dropped and `.`/`..` are resolved.
+    pub fn full_path(&self, path: &str) -> String {
+        let is_windows = matches!(self.config.os_value(), Os::Windows);
+        collapse_dots(&self.absolute_path(path), is_windows)
+    }
+
+    pub fn current_dir(&self) -> &str {
+        &self.current_dir
+    }
+
+    /// Change the guest's current directory. Each drive keeps its own, so
+    /// `D:file.txt` still resolves against the last directory used on `D:`.
+    pub fn set_current_dir(&mut self, path: &str) -> io::Result<()> {
+        let mut full = self.full_path(path);
+        // A drive root is always a directory, even when nothing backs it.
+        if full.len() > root_len(&full, true) + 1 {
+            if !self.file_metadata(&full)?.is_dir() {
+                return Err(io::ErrorKind::NotADirectory.into());
+            }
+            full = full.trim_end_matches('\\').to_string();
+        }
+        if let Some(drive) = drive_letter(&full) {
+            self.drive_dirs.insert(drive, full.clone());
+        }
+        self.current_dir = full;
+        Ok(())
+    }
+
+    /// Drive letters the guest can see: `C:` plus every drive a path mapping or
+    /// mount names.
+    pub(crate) fn drive_letters(&self) -> Vec<u8> {
+        let mut drives = vec![b'C'];
+        let prefixes = self
+            .config
+            .paths_ref()
+            .keys()
+            .chain(self.config.mounts_ref().keys());
+        drives.extend(prefixes.filter_map(|prefix| drive_letter(prefix)));
+        drives.sort_unstable();
+        drives.dedup();
+        drives
+    }
+
+    /// The 8.3 form of an existing path, for `GetShortPathName`. Names that
+    /// already fit 8.3 are kept; others get `~N` aliases numbered among their
+    /// siblings in name order.
+    pub(crate) fn short_path(&self, path: &str) -> io::Result<String> {
+        let (_, short) = self.walk_names(path)?;
+        Ok(self.relative_tail(path, short))
+    }
+
+    /// The long form of an existing path, expanding `~N` aliases and using the
+    /// case the names have on disk.
+    pub(crate) fn long_path(&self, path: &str) -> io::Result<String> {
+        let (long, _) = self.walk_names(path)?;
+        Ok(self.relative_tail(path, long))
+    }
+
+    /// The filesystem serving `path` and the path within it, under the sandbox
+    /// policy. Mounted backends and mapped host directories get the remainder
+    /// after their prefix, matched against their entries case-insensitively.
+    pub(crate) fn resolve_fs(&self, path: &str) -> io::Result<ResolvedPath> {
+        let Some(sandbox) = self.config.sandbox_config() else {
+            return Ok(self.resolve_fs_unchecked(path));
+        };
+        let is_windows = matches!(self.config.os_value(), Os::Windows);
+        let input = self.absolute_path(path);
+        let full = collapse_dots(&input, is_windows);
+        // `..` may move between guest directories, but not out of the mapping the
+        // path named, or a guest could reach the host directories around it.
+        let prefixes = self
+            .config
+            .paths_ref()
+            .keys()
+            .chain(self.config.mounts_ref().keys());
+        for prefix in prefixes {
+            let prefix = normalize_path(prefix, is_windows);
+            if path_starts_with(&input, &prefix, is_windows)
+                && !path_starts_with(&full, &prefix, is_windows)
+            {
+                return Err(io::ErrorKind::PermissionDenied.into());
+            }
+        }
+        let mapped = self.resolve_mapped(&full);
+        let mut resolved = match (mapped, sandbox.unmapped_paths_value()) {
+            (Some(mapped), _) => mapped,
+            (None, UnmappedPaths::Allow) => self.resolve_fs_unchecked(path),
+            (None, UnmappedPaths::Deny) => return Err(io::ErrorKind::NotFound.into()),
+            (None, UnmappedPaths::Redirect(dir)) => {
+                let fs: Arc<dyn GuestFs> = Arc::new(HostFs::new(dir));
+                let parts = full
+                    .split(['\\', '/'])
+                    .map(|part| part.trim_end_matches(':'))
+                    .filter(|part| !part.is_empty())
+                    .collect::<Vec<_>>();
+                let path = match_case(&*fs, &parts);
+                ResolvedPath {
+                    fs,
+                    path,
+                    mount: None,
+                    read_only: false,
+                }
+            }
+        };
+        resolved.read_only = best_prefix(&full, sandbox.path_access_rules(), is_windows)
+            .is_some_and(|(_, access)| *access == PathAccess::ReadOnly);
+        Ok(resolved)
+    }
+
+    /// Like `resolve_fs` without the sandbox policy. Paths no mapping covers
+    /// go to the host exactly as given.
+    pub(crate) fn resolve_fs_unchecked(&self, path: &str) -> ResolvedPath {
+        self.resolve_mapped(&self.full_path(path))
+            .unwrap_or_else(|| ResolvedPath {
+                fs: Arc::new(HostFs::passthrough()),
+                path: path.to_string(),
+                mount: None,
+                read_only: false,
+            })
+    }
+
+    // The mount or path mapping with the longest prefix covering `full`; a
+    // mount wins a tie.
+    fn resolve_mapped(&self, full: &str) -> Option<ResolvedPath> {
+        let is_windows = matches!(self.config.os_value(), Os::Windows);
+        let mounted = best_prefix(full, self.config.mounts_ref(), is_windows)
+            .map(|(prefix, fs)| (normalize_path(prefix, is_windows), fs.clone()));
+        let mapped =
+            best_prefix(full, self.config.paths_ref(), is_windows).map(|(prefix, host)| {
+                let fs: Arc<dyn GuestFs> = Arc::new(HostFs::new(host));
+                (normalize_path(prefix, is_windows), fs)
+            });
+        let (prefix, fs) = match (mounted, mapped) {
+            (Some(mount), Some(map)) if map.0.len() > mount.0.len() => map,
+            (Some(mount), _) => mount,
+            (None, map) => map?,
+        };
+        let remainder = full.get(prefix.len()..).unwrap_or("");
+        let parts = remainder
+            .split(['\\', '/'])
+            .filter(|part| !part.is_empty())
+            .collect::<Vec<_>>();
+        let path = match_case(&*fs, &parts);
+        Some(ResolvedPath {
+            fs,
+            path,
+            mount: Some(prefix.to_ascii_lowercase()),
+            read_only: false,
+        })
+    }
+
+    // Windows-absolute form of `path` with `.` and `..` still in place.
+    fn absolute_path(&self, path: &str) -> String {
+        if !matches!(self.config.os_value(), Os::Windows) {
+            return path.to_string();
+        }
+        let path = path.replace('/', "\\");
+        for prefix in ["\\\\?\\UNC\\", "\\\\.\\UNC\\", "\\??\\UNC\\"] {
+            if let Some(rest) = strip_prefix_ignore_case(&path, prefix) {
+                return format!("\\\\{rest}");
+            }
+        }
+        for prefix in ["\\\\?\\", "\\\\.\\", "\\??\\"] {
+            if let Some(rest) = path.strip_prefix(prefix) {
+                return rest.to_string();
+            }
+        }
+        if path.starts_with("\\\\") {
+            return path;
+        }
+        if let Some(drive) = drive_letter(&path) {
+            let rest = &path[2..];
+            if rest.starts_with('\\') {
+                return format!("{}:{rest}", drive as char);
+            }
+            return join_path(&self.drive_dir(drive), rest);
+        }
+        if path.starts_with('\\') {
+            let root = &self.current_dir[..root_len(&self.current_dir, true)];
+            return format!("{root}{path}");
+        }
+        join_path(&self.current_dir, &path)
+    }
+
+    fn drive_dir(&self, drive: u8) -> String {
+        if drive_letter(&self.current_dir) == Some(drive) {
+            return self.current_dir.clone();
+        }
+        self.drive_dirs
+            .get(&drive)
+            .cloned()
+            .unwrap_or_else(|| format!("{}:\\", drive as char))
+    }
+
+    // Actual and 8.3 names of every component of an existing path.
+    fn walk_names(&self, path: &str) -> io::Result<(String, String)> {
+        let full = self.full_path(path);
+        let (root, rest) = full.split_at(root_len(&full, true));
+        let mut long = root.to_string();
+        if rest.starts_with('\\') && drive_letter(root).is_some() {
+            long.push('\\');
+        }
+        let mut short = long.clone();
+        for part in rest.split('\\').filter(|part| !part.is_empty()) {
+            let names = self.file_list_dir(&long)?;
+            let name = find_entry(&names, part).ok_or(io::ErrorKind::NotFound)?;
+            short = join_path(&short, &short_name(&name, &names));
+            long = join_path(&long, &name);
+        }
+        Ok((long, short))
+    }
+
+    // Relative input gets relative output: keep as many trailing components
+    // as `input` had.
+    fn relative_tail(&self, input: &str, full: String) -> String {
+        let input = input.replace('/', "\\");
+        if input.starts_with('\\') || drive_letter(&input).is_some() {
+            return full;
+        }
+        let parts: Vec<&str> = input.split('\\').filter(|part| !part.is_empty()).collect();
+        if parts.iter().any(|part| *part == "." || *part == "..") {
+            return full;
+        }
+        let all: Vec<&str> = full.split('\\').collect();
+        all[all.len().saturating_sub(parts.len())..].join("\\")
+    }
+}
+
+pub(crate) struct ResolvedPath {
+    pub(crate) fs: Arc<dyn GuestFs>,
+    pub(crate) path: String,
+    /// Guest prefix of the mount or mapping serving the path; `None` outside
+    /// all of them. Paths with the same mount can be renamed into each other.
+    pub(crate) mount: Option<String>,
+    pub(crate) read_only: bool,
+}
+
+// Walk `parts` down `fs`, replacing each component with the entry it names
+// case-insensitively or by 8.3 alias. Components past the first missing one
+// (a file about to be created) are kept as given.
+fn match_case(fs: &dyn GuestFs, parts: &[&str]) -> String {
+    let mut matched = String::new();
+    let mut exists = true;
+    for part in parts {
+        let candidate = join_rel(&matched, part);
+        if exists && fs.metadata(&candidate).is_err() {
+            let entry = fs
+                .read_dir(&matched)
+                .ok()
+                .and_then(|names| find_entry(&names, part));
+            if let Some(entry) = entry {
+                matched = join_rel(&matched, &entry);
+                continue;
+            }
+            exists = false;
+        }
+        matched = candidate;
+    }
+    matched
+}
+
+fn join_rel(dir: &str, name: &str) -> String {
+    if dir.is_empty() {
+        name.to_string()
+    } else {
+        format!("{dir}/{name}")
+    }
+}
+
+fn join_path(dir: &str, name: &str) -> String {
+    if name.is_empty() {
+        dir.to_string()
+    } else if dir.ends_with('\\') {
+        format!("{dir}{name}")
+    } else {
+        format!("{dir}\\{name}")
+    }
+}
+
+// The directory entry `part` refers to: an exact match, a case-insensitive
+// one, or the long name behind an 8.3 alias.
+fn find_entry(names: &[String], part: &str) -> Option<String> {
+    if let Some(name) = names.iter().find(|name| *name == part) {
+        return Some(name.clone());
+    }
+    let folded = part.to_lowercase();
+    if let Some(name) = names.iter().find(|name| name.to_lowercase() == folded) {
+        return Some(name.clone());
+    }
+    if !part.contains('~') {
+        return None;
+    }
+    names
+        .iter()
+        .find(|name| !is_short_name(name) && short_name(name, names).eq_ignore_ascii_case(part))
+        .cloned()
+}
+
+// Whether `name` is already a valid 8.3 name and needs no alias.
+pub(super) fn is_short_name(name: &str) -> bool {
+    if name == "." || name == ".." {
+        return true;
+    }
+    let (base, ext) = name.rsplit_once('.').unwrap_or((name, ""));
+    (1..=8).contains(&base.len())
+        && ext.len() <= 3
+        && !(name.ends_with('.'))
+        && name
+            .chars()
+            .all(|c| c.is_ascii_graphic() && !SHORT_NAME_INVALID.contains(c))
+        && !base.contains('.')
+}
+
+const SHORT_NAME_INVALID: &str = "\"*+,/:;<=>?[\\]|";
+
+// Alias stem and extension: uppercase, spaces and extra dots dropped, other
+// characters 8.3 can't hold replaced with `_`.
+fn short_name_parts(name: &str) -> (String, String) {
+    let trimmed = name.trim_start_matches('.');
+    let (base, ext) = match trimmed.rsplit_once('.') {
+        Some((base, ext)) => (base, ext),
+        None => (trimmed, ""),
+    };
+    let clean = |text: &str, max: usize| -> String {
+        text.chars()
+            .filter(|c| *c != ' ' && *c != '.')
+            .map(|c| {
+                if c.is_ascii_graphic() && !SHORT_NAME_INVALID.contains(c) {
+                    c.to_ascii_uppercase()
+                } else {
+                    '_'
+                }
+            })
+            .take(max)
+            .collect()
+    };
+    (clean(base, 6), clean(ext, 3))
+}
+
+// The 8.3 name of `name` in a directory holding `siblings`. `~N` counts the
+// siblings with the same stem that sort before it.
+pub(super) fn short_name(name: &str, siblings: &[String]) -> String {
+    if is_short_name(name) {
+        return name.to_string();
+    }
+    let (stem, ext) = short_name_parts(name);
+    let mut clashes: Vec<&String> = siblings
+        .iter()
+        .filter(|other| {
+            !is_short_name(other) && short_name_parts(other) == (stem.clone(), ext.clone())
+        })
+        .collect();
+    clashes.sort_by_key(|other| other.to_lowercase());
+    let index = clashes.iter().position(|other| *other == name).unwrap_or(0) + 1;
+    let suffix = format!("~{index}");
+    let stem: String = stem.chars().take(8 - suffix.len()).collect();
+    if ext.is_empty() {
+        format!("{stem}{suffix}")
+    } else {
+        format!("{stem}{suffix}.{ext}")
+    }
+}
+
+fn drive_letter(path: &str) -> Option<u8> {
+    let bytes = path.as_bytes();
+    (bytes.len() >= 2 && bytes[1] == b':' && bytes[0].is_ascii_alphabetic())
+        .then(|| bytes[0].to_ascii_uppercase())
+}
+
+fn strip_prefix_ignore_case<'a>(path: &'a str, prefix: &str) -> Option<&'a str> {
+    let head = path.get(..prefix.len())?;
+    head.eq_ignore_ascii_case(prefix)
+        .then(|| &path[prefix.len()..])
+}
+
+// The entry whose guest prefix is the longest match for `input`.
+fn best_prefix<'a, V>(
+    input: &str,
+    entries: &'a BTreeMap<String, V>,
+    windows: bool,
+) -> Option<(&'a String, &'a V)> {
+    entries
+        .iter()
+        .filter(|(prefix, _)| path_starts_with(input, &normalize_path(prefix, windows), windows))
+        .max_by_key(|(prefix, _)| normalize_path(prefix, windows).len())
 }
 
 fn normalize_path(path: &str, windows: bool) -> String {
@@ -68,11 +449,131 @@ fn normalize_path(path: &str, windows: bool) -> String {
     normalized
 }
 
+// Resolve `.` and `..` lexically, the way Windows does before touching the
+// disk. `..` stops at the drive or share root.
+fn collapse_dots(path: &str, windows: bool) -> String {
+    let sep = if windows { '\\' } else { '/' };
+    let root_len = root_len(path, windows);
+    let (root, rest) = path.split_at(root_len);
+    let mut parts: Vec<&str> = Vec::new();
+    for part in rest.split(sep) {
+        match part {
+            "" | "." => {}
+            ".." => {
+                parts.pop();
+            }
+            part => parts.push(part),
+        }
+    }
+    let mut out = root.to_string();
+    if rest.starts_with(sep) || (windows && root_len == 2) {
+        out.push(sep);
+    }
+    out.push_str(&parts.join(&sep.to_string()));
+    if rest.ends_with(sep) && !parts.is_empty() {
+        out.push(sep);
+    }
+    out
+}
+
+// Length of the drive (`C:`) or share (`\\server\share`) part of a path.
+pub(super) fn root_len(path: &str, windows: bool) -> usize {
+    if windows && path.starts_with("\\\\") {
+        path.match_indices('\\')
+            .nth(3)
+            .map_or(path.len(), |(pos, _)| pos)
+    } else if windows && drive_letter(path).is_some() {
+        2
+    } else {
+        0
+    }
+}
+
+// Prefix match on whole components: `C:\app` covers `C:\app\x` but not `C:\apple`.
 fn path_starts_with(path: &str, prefix: &str, windows: bool) -> bool {
-    if windows {
+    let matches = if windows {
         path.to_ascii_lowercase()
             .starts_with(&prefix.to_ascii_lowercase())
     } else {
         path.starts_with(prefix)
+    };
+    matches
+        && (prefix.ends_with(['\\', '/'])
+            || path[prefix.len()..].is_empty()
+            || path[prefix.len()..].starts_with(['\\', '/']))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{short_name, Vm};
+    use crate::vm::*;
+
+    #[test]
+    fn full_paths_follow_per_drive_current_directories() {
+        let fs = MemoryFs::new();
+        fs.insert("app/bin/tool.exe", Vec::new());
+        fs.insert("work/data.txt", Vec::new());
+        let config = VmConfig::new().mount("C:\\", fs.clone()).mount("D:\\", fs);
+        let mut vm = Vm::new(config).expect("vm");
+
+        assert_eq!(vm.current_dir(), "C:\\");
+        vm.set_current_dir("app\\BIN").expect("cd");
+        assert_eq!(vm.current_dir(), "C:\\app\\BIN");
+        assert_eq!(vm.full_path("tool.exe"), "C:\\app\\BIN\\tool.exe");
+        assert_eq!(vm.full_path("..\\..\\..\\x"), "C:\\x");
+        assert_eq!(vm.full_path("\\work"), "C:\\work");
+
+        vm.set_current_dir("D:\\work").expect("cd");
+        assert_eq!(vm.full_path("C:tool.exe"), "C:\\app\\BIN\\tool.exe");
+        assert_eq!(vm.full_path("data.txt"), "D:\\work\\data.txt");
+        assert_eq!(vm.full_path("E:x"), "E:\\x");
+        assert!(vm.set_current_dir("C:\\missing").is_err());
+        assert!(vm.set_current_dir("D:\\work\\data.txt").is_err());
+
+        assert_eq!(vm.full_path("\\\\?\\C:\\a\\.\\b"), "C:\\a\\b");
+        assert_eq!(
+            vm.full_path("\\\\?\\UNC\\server\\share\\a\\..\\b"),
+            "\\\\server\\share\\b"
+        );
+        assert_eq!(vm.full_path("//server/share/../x"), "\\\\server\\share\\x");
+    }
+
+    #[test]
+    fn host_lookups_ignore_case_and_accept_short_names() {
+        let dir = std::env::temp_dir().join(format!("pe_vm_case_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("Program Files")).expect("mkdir");
+        std::fs::write(dir.join("Program Files").join("Saved Settings.ini"), b"ok").expect("write");
+        let mut paths = PathMapping::new();
+        paths.insert("C:\\".to_string(), dir.to_string_lossy().into_owned());
+        let vm = Vm::new(VmConfig::new().paths(paths)).expect("vm");
+
+        let data = vm.file_read_all("C:\\PROGRAM FILES\\saved settings.INI");
+        assert_eq!(data.expect("read"), b"ok");
+        assert!(vm.file_exists("C:\\PROGRA~1\\SAVEDS~1.INI"));
+        assert_eq!(
+            vm.short_path("C:\\program files\\saved settings.ini")
+                .expect("short"),
+            "C:\\PROGRA~1\\SAVEDS~1.INI"
+        );
+        assert_eq!(
+            vm.long_path("c:\\progra~1\\saveds~1.ini").expect("long"),
+            "C:\\Program Files\\Saved Settings.ini"
+        );
+        assert!(vm.short_path("C:\\Program Files\\missing.txt").is_err());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn short_names_number_colliding_siblings() {
+        let siblings: Vec<String> = ["Long File Two.txt", "long file one.txt", "short.txt"]
+            .iter()
+            .map(|name| name.to_string())
+            .collect();
+        assert_eq!(short_name("short.txt", &siblings), "short.txt");
+        assert_eq!(short_name("long file one.txt", &siblings), "LONGFI~1.TXT");
+        assert_eq!(short_name("Long File Two.txt", &siblings), "LONGFI~2.TXT");
+        assert_eq!(short_name("archive.tar.gz", &[]), "ARCHIV~1.GZ");
+        assert_eq!(short_name(".profile", &[]), "PROFIL~1");
     }
 }
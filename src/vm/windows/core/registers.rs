@@ -98,32 +98,102 @@ impl Vm {
         reg8_write!(self.regs, index, value);
     }
 
-    pub(crate) fn xmm(&self, index: u8) -> [u8; 16] {
+    /// XMM`index` as little-endian bytes; `index` must be below 8.
+    pub fn xmm(&self, index: u8) -> [u8; 16] {
         self.xmm[index as usize]
     }
 
-    pub(crate) fn set_xmm(&mut self, index: u8, value: [u8; 16]) {
+    pub fn set_xmm(&mut self, index: u8, value: [u8; 16]) {
         self.xmm[index as usize] = value;
     }
 
     pub(crate) fn zf(&self) -> bool {
-        self.flags.zf
+        self.flags.zf()
     }
 
     pub(crate) fn sf(&self) -> bool {
-        self.flags.sf
+        self.flags.sf()
     }
 
     pub(crate) fn of(&self) -> bool {
-        self.flags.of
+        self.flags.of()
     }
 
     pub(crate) fn cf(&self) -> bool {
-        self.flags.cf
+        self.flags.cf()
     }
 
-    pub(crate) fn set_flags(&mut self, zf: bool, sf: bool, of: bool, cf: bool) {
-        self.flags = Flags { cf, zf, sf, of };
+    pub(crate) fn pf(&self) -> bool {
+        self.flags.pf()
+    }
+
+    pub(crate) fn af(&self) -> bool {
+        self.flags.af()
+    }
+
+    pub(crate) fn df(&self) -> bool {
+        self.flags.df()
+    }
+
+    /// Record an arithmetic result; the status flags are derived from it on demand.
+    pub(crate) fn record_flags(&mut self, op: FlagOp, width: u8, a: u32, b: u32, result: u32) {
+        self.flags.record(op, width, a, b, result);
+    }
+
+    pub(crate) fn record_flags_with_carry(
+        &mut self,
+        op: FlagOp,
+        width: u8,
+        a: u32,
+        b: u32,
+        result: u32,
+        carry: bool,
+    ) {
+        self.flags.record_with_carry(op, width, a, b, result, carry);
+    }
+
+    /// ZF, SF and PF from `result`, with CF and OF computed by the instruction.
+    pub(crate) fn set_result_flags(&mut self, width: u8, result: u32, cf: bool, of: bool) {
+        self.flags.record_result(width, result, cf, of);
+    }
+
+    pub(crate) fn set_cf(&mut self, value: bool) {
+        self.flags.set(Flags::CF, value);
+    }
+
+    pub(crate) fn set_of(&mut self, value: bool) {
+        self.flags.set(Flags::OF, value);
+    }
+
+    pub(crate) fn set_zf(&mut self, value: bool) {
+        self.flags.set(Flags::ZF, value);
+    }
+
+    pub(crate) fn set_af(&mut self, value: bool) {
+        self.flags.set(Flags::AF, value);
+    }
+
+    pub(crate) fn set_df(&mut self, value: bool) {
+        self.flags.set(Flags::DF, value);
+    }
+
+    pub(crate) fn eflags(&self) -> u32 {
+        self.flags.to_eflags()
+    }
+
+    /// popfd: only the bits user-mode code may change are taken from `value`.
+    pub(crate) fn set_eflags(&mut self, value: u32) {
+        self.flags.load_user(value);
+    }
+
+    /// lahf: SF, ZF, AF, PF and CF in EFLAGS bit order.
+    pub(crate) fn low_flags(&self) -> u8 {
+        self.flags.low_byte()
+    }
+
+    /// sahf: replace SF, ZF, AF, PF and CF.
+    pub(crate) fn set_low_flags(&mut self, value: u8) {
+        self.flags.load_low_byte(value);
     }
 
     pub(crate) fn fs_base(&self) -> u32 {
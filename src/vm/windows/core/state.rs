@@ -1,3 +1,5 @@
+use crate::architecture::intel::x86::disasm::{self, Instruction};
+use crate::architecture::intel::x86::DecodeCache;
 use crate::vm::*;
 
 impl Vm {
@@ -17,10 +19,26 @@ impl Vm {
         self.message_box_mode = mode;
     }
 
+    /// Opcodes the executor implements: one-byte opcodes, then those following 0F.
+    /// Prefix bytes are not listed.
     pub fn supported_opcodes(&self) -> (Vec<u8>, Vec<u8>) {
         self.executor.supported_opcodes()
     }
 
+    /// Disassemble up to `count` instructions from guest memory at `addr`,
+    /// stopping early at the first unreadable byte.
+    pub fn disassemble(&self, addr: u32, count: usize) -> Vec<Instruction> {
+        disasm::disassemble_with(|at| self.read_u8(at).ok(), addr, count)
+    }
+
+    pub(crate) fn decode_cache(&self) -> &DecodeCache {
+        &self.decode_cache
+    }
+
+    pub(crate) fn decode_cache_mut(&mut self) -> &mut DecodeCache {
+        &mut self.decode_cache
+    }
+
     pub(crate) fn onexit_table_mut(&mut self, table_ptr: u32) -> &mut Vec<u32> {
         self.onexit_tables.entry(table_ptr).or_default()
     }
@@ -37,3 +55,426 @@ impl Vm {
         self.default_onexit_table = value;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::vm::{
+        AccessKind, Architecture, Flag, Vm, VmConfig, VmError, PAGE_NOACCESS,
+        STATUS_ILLEGAL_INSTRUCTION,
+    };
+
+    fn create_test_vm() -> Vm {
+        let mut vm = Vm::new(VmConfig::new().architecture(Architecture::X86)).expect("vm");
+        vm.memory.map(0x1000, 0x10000);
+        vm.base = 0x1000;
+        vm.stack_top = 0x1000 + 0x10000 - 4;
+        vm.regs.esp = vm.stack_top;
+        vm.fs_base = 0x1000;
+        vm.write_u32(0x1000, 0xFFFF_FFFF).expect("teb");
+        vm
+    }
+
+    #[test]
+    fn supported_opcodes_cover_integer_isa() {
+        let vm = create_test_vm();
+        let (primary, extended) = vm.supported_opcodes();
+        for opcode in [
+            0x60, 0x61, 0x86, 0x98, 0xA0, 0xA2, 0xC8, 0xD7, 0xE0, 0xE2, 0xE3,
+        ] {
+            assert!(primary.contains(&opcode), "missing {opcode:02X}");
+        }
+        for ext in [
+            0x1F, 0x31, 0x80, 0xA3, 0xA5, 0xAB, 0xBC, 0xBD, 0xC0, 0xC7, 0xC8,
+        ] {
+            assert!(extended.contains(&ext), "missing 0F {ext:02X}");
+        }
+    }
+
+    #[test]
+    fn executes_bit_scan_swap_loop_and_enter() {
+        let mut vm = create_test_vm();
+        let code: &[u8] = &[
+            0xC8, 0x08, 0x00, 0x00, // enter 8, 0
+            0xB8, 0x00, 0x01, 0x00, 0x00, // mov eax, 0x100
+            0x0F, 0xBC, 0xD8, // bsf ebx, eax
+            0x0F, 0xBD, 0xD0, // bsr edx, eax
+            0x0F, 0xC8, // bswap eax
+            0x31, 0xF6, // xor esi, esi
+            0xB9, 0x05, 0x00, 0x00, 0x00, // mov ecx, 5
+            0x46, // inc esi
+            0xE2, 0xFD, // loop -3
+            0x0F, 0xA4, 0xC6, 0x04, // shld esi, eax, 4
+            0xC9, // leave
+            0xC3, // ret
+        ];
+        vm.write_bytes(0x3000, code).expect("code");
+        vm.execute(0x3000).expect("execute");
+        assert_eq!(vm.regs.ebx, 8);
+        assert_eq!(vm.regs.edx, 8);
+        assert_eq!(vm.regs.eax, 0x0001_0000);
+        assert_eq!(vm.regs.ecx, 0);
+        assert_eq!(vm.regs.esi, 0x50);
+        assert_eq!(vm.regs.esp, vm.stack_top);
+    }
+
+    #[test]
+    fn executes_operand_size_prefixed_arithmetic() {
+        let mut vm = create_test_vm();
+        vm.write_bytes(0x5000, b"MZ").expect("data");
+        let code: &[u8] = &[
+            0xB8, 0x78, 0x56, 0x34, 0x12, // mov eax, 0x12345678
+            0x66, 0x05, 0xFF, 0xFF, // add ax, 0xFFFF
+            0x0F, 0x92, 0xC5, // setb ch
+            0xBB, 0x00, 0x50, 0x00, 0x00, // mov ebx, 0x5000
+            0x66, 0x81, 0x3B, 0x4D, 0x5A, // cmp word [ebx], 0x5A4D
+            0x0F, 0x94, 0xC1, // sete cl
+            0xBA, 0x00, 0x00, 0xAA, 0xAA, // mov edx, 0xAAAA0000
+            0x66, 0x6A, 0x7F, // push word 0x7F
+            0x66, 0x5A, // pop dx
+            0xBE, 0x0F, 0xF0, 0x34, 0x12, // mov esi, 0x1234F00F
+            0x66, 0xC1, 0xE6, 0x04, // shl si, 4
+            0x66, 0x0F, 0xAF, 0xF2, // imul si, dx
+            0xC3, // ret
+        ];
+        vm.write_bytes(0x3000, code).expect("code");
+        vm.execute(0x3000).expect("execute");
+        assert_eq!(vm.regs.eax, 0x1234_5677);
+        assert_eq!(vm.regs.ebx, 0x5000);
+        assert_eq!(vm.regs.ecx & 0xFFFF, 0x0101);
+        assert_eq!(vm.regs.edx, 0xAAAA_007F);
+        assert_eq!(vm.regs.esi, 0x1234_0000 | (0x00F0 * 0x7F));
+        assert_eq!(vm.regs.esp, vm.stack_top);
+    }
+
+    #[test]
+    fn decodes_address_size_segment_and_lock_prefixes() {
+        let mut vm = create_test_vm();
+        vm.write_u32(0x5000, 0xCAFE_BABE).expect("data");
+        vm.write_u32(0x5010, 0x1234).expect("data");
+        let code: &[u8] = &[
+            0xBB, 0x00, 0x50, 0x00, 0x00, // mov ebx, 0x5000
+            0xBE, 0x10, 0x00, 0x00, 0x00, // mov esi, 0x10
+            0x67, 0x8B, 0x00, // mov eax, [bx+si]
+            0x67, 0x8D, 0x4F, 0xF0, // lea ecx, [bx-0x10]
+            0x26, 0x8B, 0x13, // mov edx, es:[ebx]
+            0x2E, 0x75, 0x00, // jnz +0 (not-taken hint)
+            0xF0, 0xFF, 0x03, // lock inc dword [ebx]
+            0xBF, 0x00, 0x00, 0x01, 0x00, // mov edi, 0x10000
+            0x87, 0xF9, // xchg ecx, edi
+            0x67, 0xE3, 0x01, // jcxz +1
+            0xCC, // int3 (skipped)
+            0xC3, // ret
+        ];
+        vm.write_bytes(0x3000, code).expect("code");
+        vm.execute(0x3000).expect("execute");
+        assert_eq!(vm.regs.eax, 0x1234);
+        assert_eq!(vm.regs.edi, 0x4FF0);
+        assert_eq!(vm.regs.edx, 0xCAFE_BABE);
+        assert_eq!(vm.read_u32(0x5000).expect("read"), 0xCAFE_BABF);
+    }
+
+    #[test]
+    fn decode_cache_follows_code_writes_protection_and_fs_base() {
+        let mut vm = create_test_vm();
+        let code: &[u8] = &[
+            0xB8, 0x03, 0x00, 0x00, 0x00, // mov eax, 3
+            0xB9, 0x02, 0x00, 0x00, 0x00, // mov ecx, 2
+            0x01, 0xC2, // add edx, eax (patched to sub)
+            0xC6, 0x05, 0x0A, 0x30, 0x00, 0x00, 0x29, // mov byte [0x300A], 0x29
+            0xE2, 0xF5, // loop 0x300A
+            0xC3, // ret
+        ];
+        vm.write_bytes(0x3000, code).expect("code");
+        vm.execute(0x3000).expect("execute");
+        assert_eq!(vm.regs.edx, 0);
+
+        let code: &[u8] = &[
+            0x64, 0xA1, 0x04, 0x00, 0x00, 0x00, // mov eax, fs:[4]
+            0xC3, // ret
+        ];
+        vm.write_bytes(0x4000, code).expect("code");
+        vm.write_u32(0x1004, 0x1111).expect("teb");
+        vm.write_u32(0x2004, 0x2222).expect("teb");
+        vm.execute(0x4000).expect("execute");
+        assert_eq!(vm.regs.eax, 0x1111);
+        vm.fs_base = 0x2000;
+        vm.execute(0x4000).expect("execute");
+        assert_eq!(vm.regs.eax, 0x2222);
+
+        vm.fs_base = 0x1000;
+        vm.protect_memory(0x4000, 1, PAGE_NOACCESS)
+            .expect("protect");
+        let err = vm.execute(0x4000).expect_err("no access");
+        assert!(matches!(
+            err,
+            VmError::AccessViolation {
+                addr: 0x4000,
+                access: AccessKind::Execute,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn rejects_lock_on_register_destination() {
+        let mut vm = create_test_vm();
+        let code: &[u8] = &[
+            0xF0, 0x01, 0xC0, // lock add eax, eax
+            0xC3, // ret
+        ];
+        vm.write_bytes(0x3000, code).expect("code");
+        let err = vm.execute(0x3000).expect_err("lock");
+        assert!(matches!(
+            err,
+            VmError::Exception {
+                code: STATUS_ILLEGAL_INSTRUCTION,
+                address: 0x3000
+            }
+        ));
+    }
+
+    #[test]
+    fn rdtsc_counts_retired_instructions() {
+        let code: &[u8] = &[
+            0x0F, 0x31, // rdtsc
+            0x89, 0xC3, // mov ebx, eax
+            0x90, // nop
+            0x90, // nop
+            0x0F, 0x31, // rdtsc
+            0x29, 0xD8, // sub eax, ebx
+            0xC3, // ret
+        ];
+        let run = || {
+            let mut vm = create_test_vm();
+            vm.write_bytes(0x3000, code).expect("code");
+            vm.execute(0x3000).expect("execute");
+            (vm.regs.eax, vm.regs.ebx)
+        };
+        let (delta, first) = run();
+        assert_eq!(delta, 4);
+        assert_eq!(run(), (delta, first));
+    }
+
+    #[test]
+    fn executes_pushad_popad() {
+        let mut vm = create_test_vm();
+        let code: &[u8] = &[
+            0xB8, 0x11, 0x11, 0x11, 0x11, // mov eax, 0x11111111
+            0xB9, 0x22, 0x22, 0x22, 0x22, // mov ecx, 0x22222222
+            0xBA, 0x33, 0x33, 0x33, 0x33, // mov edx, 0x33333333
+            0xBB, 0x44, 0x44, 0x44, 0x44, // mov ebx, 0x44444444
+            0xBD, 0x55, 0x55, 0x55, 0x55, // mov ebp, 0x55555555
+            0x60, // pushad
+            0x8B, 0x44, 0x24, 0x0C, // mov eax, [esp+12]
+            0xA3, 0x00, 0x50, 0x00, 0x00, // mov [0x5000], eax
+            0x8B, 0x44, 0x24, 0x10, // mov eax, [esp+16]
+            0xA3, 0x04, 0x50, 0x00, 0x00, // mov [0x5004], eax
+            0xC7, 0x44, 0x24, 0x0C, 0x00, 0x00, 0x00, 0x00, // mov dword [esp+12], 0
+            0x31, 0xC0, // xor eax, eax
+            0x31, 0xC9, // xor ecx, ecx
+            0x31, 0xD2, // xor edx, edx
+            0x31, 0xDB, // xor ebx, ebx
+            0x31, 0xED, // xor ebp, ebp
+            0x61, // popad
+            0xC3, // ret
+        ];
+        vm.write_bytes(0x3000, code).expect("code");
+        vm.execute(0x3000).expect("execute");
+        assert_eq!(vm.regs.eax, 0x1111_1111);
+        assert_eq!(vm.regs.ecx, 0x2222_2222);
+        assert_eq!(vm.regs.edx, 0x3333_3333);
+        assert_eq!(vm.regs.ebx, 0x4444_4444);
+        assert_eq!(vm.regs.ebp, 0x5555_5555);
+        // The saved ESP is the value before PUSHAD; POPAD skips its slot.
+        assert_eq!(vm.read_u32(0x5000).expect("read"), vm.stack_top - 4);
+        assert_eq!(vm.read_u32(0x5004).expect("read"), 0x4444_4444);
+        assert_eq!(vm.regs.esp, vm.stack_top);
+    }
+
+    #[test]
+    fn executes_sign_extensions_and_xlat() {
+        let mut vm = create_test_vm();
+        vm.write_bytes(0x5100, &[0x10, 0x20, 0x30, 0x40])
+            .expect("table");
+        let code: &[u8] = &[
+            0xB8, 0x80, 0x56, 0x34, 0x12, // mov eax, 0x12345680
+            0x66, 0x98, // cbw
+            0x89, 0xC6, // mov esi, eax
+            0x98, // cwde
+            0x89, 0xC7, // mov edi, eax
+            0xB8, 0x00, 0x80, 0x00, 0x00, // mov eax, 0x8000
+            0xBA, 0x78, 0x56, 0x34, 0x12, // mov edx, 0x12345678
+            0x66, 0x99, // cwd
+            0xBB, 0x00, 0x51, 0x00, 0x00, // mov ebx, 0x5100
+            0xB0, 0x03, // mov al, 3
+            0xD7, // xlat
+            0xC3, // ret
+        ];
+        vm.write_bytes(0x3000, code).expect("code");
+        vm.execute(0x3000).expect("execute");
+        assert_eq!(vm.regs.esi, 0x1234_FF80);
+        assert_eq!(vm.regs.edi, 0xFFFF_FF80);
+        assert_eq!(vm.regs.edx, 0x1234_FFFF);
+        assert_eq!(vm.regs.eax, 0x8040);
+    }
+
+    #[test]
+    fn executes_cmpxchg8b_match_and_mismatch() {
+        let mut vm = create_test_vm();
+        vm.write_u32(0x5000, 0x3333_4444).expect("data");
+        vm.write_u32(0x5004, 0x1111_2222).expect("data");
+        let code: &[u8] = &[
+            0xBF, 0x00, 0x50, 0x00, 0x00, // mov edi, 0x5000
+            0xB8, 0x44, 0x44, 0x33, 0x33, // mov eax, 0x33334444
+            0xBA, 0x22, 0x22, 0x11, 0x11, // mov edx, 0x11112222
+            0xBB, 0xBB, 0xBB, 0xBB, 0xBB, // mov ebx, 0xBBBBBBBB
+            0xB9, 0xAA, 0xAA, 0xAA, 0xAA, // mov ecx, 0xAAAAAAAA
+            0x0F, 0xC7, 0x0F, // cmpxchg8b [edi]
+            0xC3, // ret
+        ];
+        vm.write_bytes(0x3000, code).expect("code");
+        vm.execute(0x3000).expect("execute");
+        assert!(vm.flag(Flag::Zero));
+        assert_eq!(vm.read_u32(0x5000).expect("read"), 0xBBBB_BBBB);
+        assert_eq!(vm.read_u32(0x5004).expect("read"), 0xAAAA_AAAA);
+        assert_eq!((vm.regs.edx, vm.regs.eax), (0x1111_2222, 0x3333_4444));
+
+        // Memory now differs from EDX:EAX, so it is loaded instead of replaced.
+        vm.execute(0x3000).expect("execute");
+        assert!(!vm.flag(Flag::Zero));
+        assert_eq!((vm.regs.edx, vm.regs.eax), (0xAAAA_AAAA, 0xBBBB_BBBB));
+        assert_eq!(vm.read_u32(0x5000).expect("read"), 0xBBBB_BBBB);
+        assert_eq!(vm.read_u32(0x5004).expect("read"), 0xAAAA_AAAA);
+    }
+
+    #[test]
+    fn executes_group_f6_multiply_and_divide() {
+        let mut vm = create_test_vm();
+        let code: &[u8] = &[
+            0xB8, 0x50, 0x00, 0x34, 0x12, // mov eax, 0x12340050
+            0xB1, 0x04, // mov cl, 4
+            0xF6, 0xE1, // mul cl
+            0x0F, 0x92, 0xC2, // setc dl
+            0x89, 0xC3, // mov ebx, eax
+            0xB0, 0xFE, // mov al, -2
+            0xB1, 0x03, // mov cl, 3
+            0xF6, 0xE9, // imul cl
+            0x0F, 0x92, 0xC6, // setc dh
+            0x89, 0xC6, // mov esi, eax
+            0x66, 0xB8, 0x07, 0x01, // mov ax, 263
+            0xB1, 0x0A, // mov cl, 10
+            0xF6, 0xF1, // div cl
+            0x89, 0xC7, // mov edi, eax
+            0x66, 0xB8, 0xF9, 0xFE, // mov ax, -263
+            0xF6, 0xF9, // idiv cl
+            0xC3, // ret
+        ];
+        vm.write_bytes(0x3000, code).expect("code");
+        vm.execute(0x3000).expect("execute");
+        assert_eq!(vm.regs.ebx, 0x1234_0140);
+        assert_eq!(vm.regs.esi, 0x1234_FFFA);
+        assert_eq!(vm.regs.edx & 0xFFFF, 0x0001);
+        assert_eq!(vm.regs.edi, 0x1234_031A);
+        assert_eq!(vm.regs.eax, 0x1234_FDE6);
+    }
+
+    #[test]
+    fn group_f6_divide_errors_fault_at_the_instruction() {
+        // (dividend in AX, divisor in CL, div or idiv)
+        for (dividend, divisor, modrm) in [
+            (5u16, 0u8, 0xF1u8), // div by zero
+            (0x1000, 2, 0xF1),   // quotient above 0xFF
+            (5, 0, 0xF9),        // idiv by zero
+            (0x0080, 1, 0xF9),   // quotient above i8::MAX
+            (0xFF00, 1, 0xF9),   // quotient below i8::MIN
+        ] {
+            let mut vm = create_test_vm();
+            let [lo, hi] = dividend.to_le_bytes();
+            let code: &[u8] = &[
+                0x66, 0xB8, lo, hi, // mov ax, dividend
+                0xB1, divisor, // mov cl, divisor
+                0xF6, modrm, // div/idiv cl
+                0xC3,  // ret
+            ];
+            vm.write_bytes(0x3000, code).expect("code");
+            let err = vm.execute(0x3000).expect_err("divide error");
+            assert!(
+                matches!(err, VmError::DivideError),
+                "{dividend:#X} / {divisor:#X}: {err:?}"
+            );
+            assert_eq!(vm.regs.eip, 0x3006);
+        }
+
+        // -128 / 1 is the last quotient IDIV r/m8 can represent.
+        let mut vm = create_test_vm();
+        let code: &[u8] = &[
+            0x66, 0xB8, 0x80, 0xFF, // mov ax, -128
+            0xB1, 0x01, // mov cl, 1
+            0xF6, 0xF9, // idiv cl
+            0xC3, // ret
+        ];
+        vm.write_bytes(0x3000, code).expect("code");
+        vm.execute(0x3000).expect("execute");
+        assert_eq!(vm.regs.eax & 0xFFFF, 0x0080);
+    }
+
+    #[test]
+    fn executes_loopz_loopnz_and_jecxz() {
+        let mut vm = create_test_vm();
+        let code: &[u8] = &[
+            0x31, 0xC0, // xor eax, eax
+            0xB9, 0x0A, 0x00, 0x00, 0x00, // mov ecx, 10
+            0x40, // inc eax
+            0x83, 0xF8, 0x03, // cmp eax, 3
+            0xE0, 0xFA, // loopnz -6
+            0x89, 0xCB, // mov ebx, ecx
+            0x31, 0xF6, // xor esi, esi
+            0xB9, 0x0A, 0x00, 0x00, 0x00, // mov ecx, 10
+            0x46, // inc esi
+            0x83, 0xFE, 0x01, // cmp esi, 1
+            0xE1, 0xFA, // loopz -6
+            0x89, 0xCA, // mov edx, ecx
+            0x31, 0xC9, // xor ecx, ecx
+            0xE3, 0x01, // jecxz +1
+            0xCC, // int3 (skipped)
+            0x41, // inc ecx
+            0xE3, 0x01, // jecxz +1 (not taken)
+            0x41, // inc ecx
+            0xC3, // ret
+        ];
+        vm.write_bytes(0x3000, code).expect("code");
+        vm.execute(0x3000).expect("execute");
+        assert_eq!((vm.regs.eax, vm.regs.ebx), (3, 7));
+        assert_eq!((vm.regs.esi, vm.regs.edx), (2, 8));
+        assert_eq!(vm.regs.ecx, 2);
+    }
+
+    #[test]
+    fn executes_byte_exchanges_and_moffs_forms() {
+        let mut vm = create_test_vm();
+        vm.write_u8(0x5001, 0x9A).expect("data");
+        let code: &[u8] = &[
+            0xB8, 0x11, 0x22, 0x33, 0x44, // mov eax, 0x44332211
+            0xBB, 0x55, 0x66, 0x77, 0x88, // mov ebx, 0x88776655
+            0xB9, 0xFF, 0xFF, 0x00, 0x00, // mov ecx, 0xFFFF
+            0x86, 0xC7, // xchg bh, al
+            0x0F, 0xC0, 0xDC, // xadd ah, bl
+            0xA2, 0x00, 0x50, 0x00, 0x00, // mov [0x5000], al
+            0x0F, 0xB0, 0x1D, 0x00, 0x50, 0x00, 0x00, // cmpxchg [0x5000], bl
+            0x0F, 0x94, 0xC1, // sete cl
+            0x0F, 0xB0, 0x3D, 0x00, 0x50, 0x00, 0x00, // cmpxchg [0x5000], bh
+            0x0F, 0x94, 0xC5, // sete ch
+            0x88, 0xC2, // mov dl, al
+            0xA0, 0x01, 0x50, 0x00, 0x00, // mov al, [0x5001]
+            0xC3, // ret
+        ];
+        vm.write_bytes(0x3000, code).expect("code");
+        vm.execute(0x3000).expect("execute");
+        assert_eq!(vm.regs.ebx, 0x8877_1122);
+        assert_eq!(vm.regs.ecx, 0x0001);
+        // The failed compare loaded the stored byte into AL.
+        assert_eq!(vm.regs.edx & 0xFF, 0x22);
+        assert_eq!(vm.regs.eax, 0x4433_779A);
+        assert_eq!(vm.read_u8(0x5000).expect("read"), 0x22);
+    }
+}
@@ -0,0 +1,717 @@
+use crate::vm::*;
+
+const ERROR_FILE_NOT_FOUND: u32 = 2;
+const ERROR_INVALID_HANDLE: u32 = 6;
+const ERROR_GEN_FAILURE: u32 = 31;
+const ERROR_INVALID_PARAMETER: u32 = 87;
+const ERROR_ALREADY_EXISTS: u32 = 183;
+const ERROR_NOT_OWNER: u32 = 288;
+const ERROR_TOO_MANY_POSTS: u32 = 298;
+const ERROR_TIMEOUT: u32 = 1460;
+
+// RTL_CRITICAL_SECTION layout.
+const CS_DEBUG_INFO: u32 = 0x00;
+const CS_LOCK_COUNT: u32 = 0x04;
+const CS_RECURSION_COUNT: u32 = 0x08;
+const CS_OWNING_THREAD: u32 = 0x0C;
+const CS_LOCK_SEMAPHORE: u32 = 0x10;
+const CS_SPIN_COUNT: u32 = 0x14;
+
+// Pseudo-handles from GetCurrentProcess/GetCurrentThread; waits on them never end.
+const CURRENT_PROCESS: u32 = 0xFFFF_FFFF;
+const CURRENT_THREAD: u32 = 0xFFFF_FFFE;
+
+enum Outcome {
+    // The wait is over; the value is the thread's EAX.
+    Done(u32),
+    // The first phase finished and the thread keeps waiting on something else.
+    Next(Wait),
+}
+
+impl Vm {
+    /// Create a named or unnamed event, mutex or semaphore.
+    ///
+    /// An existing name opens that object and sets `ERROR_ALREADY_EXISTS`; a name taken by
+    /// another object type fails with `ERROR_INVALID_HANDLE`.
+    pub(crate) fn create_sync_object(&mut self, object: SyncObject, name: Option<String>) -> u32 {
+        match self.sync.create(object, name) {
+            Ok((handle, existed)) => {
+                self.set_last_error(if existed { ERROR_ALREADY_EXISTS } else { 0 });
+                handle
+            }
+            Err(_) => {
+                self.set_last_error(ERROR_INVALID_HANDLE);
+                0
+            }
+        }
+    }
+
+    /// Open a named object of the same type as `kind`.
+    pub(crate) fn open_sync_object(&mut self, name: &str, kind: &SyncObject) -> u32 {
+        match self.sync.open(name, kind) {
+            Ok(handle) => handle,
+            Err(NameError::NotFound) => {
+                self.set_last_error(ERROR_FILE_NOT_FOUND);
+                0
+            }
+            Err(NameError::WrongType) => {
+                self.set_last_error(ERROR_INVALID_HANDLE);
+                0
+            }
+        }
+    }
+
+    pub(crate) fn sync_object(&self, handle: u32) -> Option<&SyncObject> {
+        self.sync.get(handle)
+    }
+
+    pub(crate) fn close_sync_handle(&mut self, handle: u32) -> bool {
+        self.sync.close(handle)
+    }
+
+    /// SetEvent/ResetEvent. Setting an event wakes the threads it satisfies right away.
+    pub(crate) fn set_event(&mut self, handle: u32, value: bool) -> bool {
+        let Some(SyncObject::Event { signaled, .. }) = self.sync.get_mut(handle) else {
+            self.set_last_error(ERROR_INVALID_HANDLE);
+            return false;
+        };
+        *signaled = value;
+        if value {
+            self.resolve_waits();
+        }
+        true
+    }
+
+    /// PulseEvent: release the current waiters, then leave the event reset.
+    pub(crate) fn pulse_event(&mut self, handle: u32) -> bool {
+        self.set_event(handle, true) && self.set_event(handle, false)
+    }
+
+    pub(crate) fn release_mutex(&mut self, handle: u32) -> bool {
+        let me = self.threads.current_id();
+        let Some(SyncObject::Mutex {
+            owner, recursion, ..
+        }) = self.sync.get_mut(handle)
+        else {
+            self.set_last_error(ERROR_INVALID_HANDLE);
+            return false;
+        };
+        if *owner != Some(me) {
+            self.set_last_error(ERROR_NOT_OWNER);
+            return false;
+        }
+        *recursion -= 1;
+        if *recursion == 0 {
+            *owner = None;
+        }
+        self.mirror_critical_section(handle);
+        self.resolve_waits();
+        true
+    }
+
+    /// ReleaseSemaphore: returns the previous count.
+    pub(crate) fn release_semaphore(&mut self, handle: u32, release: u32) -> Option<u32> {
+        let Some(SyncObject::Semaphore { count, maximum }) = self.sync.get_mut(handle) else {
+            self.set_last_error(ERROR_INVALID_HANDLE);
+            return None;
+        };
+        let previous = *count;
+        match previous.checked_add(release) {
+            _ if release == 0 => {
+                self.set_last_error(ERROR_INVALID_PARAMETER);
+                return None;
+            }
+            Some(next) if next <= *maximum => *count = next,
+            _ => {
+                self.set_last_error(ERROR_TOO_MANY_POSTS);
+                return None;
+            }
+        }
+        self.resolve_waits();
+        Some(previous)
+    }
+
+    /// WaitForSingleObject/WaitForMultipleObjects. A handle that is not a thread or sync
+    /// object of this VM fails the wait with `ERROR_INVALID_HANDLE`.
+    pub(crate) fn wait_for_objects(
+        &mut self,
+        handles: &[u32],
+        wait_all: bool,
+        timeout: u32,
+    ) -> u32 {
+        let wait = Wait {
+            handles: handles.to_vec(),
+            kind: WaitKind::Objects { wait_all },
+            deadline: self.deadline_after(timeout),
+        };
+        self.wait(wait)
+    }
+
+    pub(crate) fn initialize_critical_section(&mut self, addr: u32, spin_count: u32) {
+        self.sync.reset_address(addr, critical_section_mutex(addr));
+        let _ = self.write_u32(addr + CS_DEBUG_INFO, 0);
+        let _ = self.write_u32(addr + CS_LOCK_SEMAPHORE, 0);
+        let _ = self.write_u32(addr + CS_SPIN_COUNT, spin_count);
+        self.write_critical_section(addr, None, 0);
+    }
+
+    pub(crate) fn enter_critical_section(&mut self, addr: u32) {
+        let handle = self.critical_section(addr);
+        self.wait(lock_wait(handle, false));
+    }
+
+    pub(crate) fn try_enter_critical_section(&mut self, addr: u32) -> bool {
+        let handle = self.critical_section(addr);
+        self.try_lock(handle, false)
+    }
+
+    pub(crate) fn leave_critical_section(&mut self, addr: u32) {
+        let handle = self.critical_section(addr);
+        self.release_mutex(handle);
+    }
+
+    pub(crate) fn delete_critical_section(&mut self, addr: u32) {
+        self.sync.remove_address(addr);
+    }
+
+    /// SetCriticalSectionSpinCount: returns the previous spin count.
+    pub(crate) fn set_critical_section_spin_count(&mut self, addr: u32, spin_count: u32) -> u32 {
+        let previous = self.read_u32(addr + CS_SPIN_COUNT).unwrap_or(0);
+        let _ = self.write_u32(addr + CS_SPIN_COUNT, spin_count);
+        previous
+    }
+
+    pub(crate) fn initialize_srw_lock(&mut self, addr: u32) {
+        self.sync.reset_address(addr, srw_lock());
+    }
+
+    pub(crate) fn acquire_srw_lock(&mut self, addr: u32, shared: bool) {
+        let handle = self.sync.at_address(addr, srw_lock);
+        self.wait(lock_wait(handle, shared));
+    }
+
+    pub(crate) fn try_acquire_srw_lock(&mut self, addr: u32, shared: bool) -> bool {
+        let handle = self.sync.at_address(addr, srw_lock);
+        self.try_lock(handle, shared)
+    }
+
+    pub(crate) fn release_srw_lock(&mut self, addr: u32, shared: bool) {
+        let handle = self.sync.at_address(addr, srw_lock);
+        self.unlock_srw(handle, shared);
+        self.resolve_waits();
+    }
+
+    pub(crate) fn initialize_condition_variable(&mut self, addr: u32) {
+        self.sync.reset_address(addr, condition_variable());
+    }
+
+    /// SleepConditionVariableCS: release the critical section, wait to be woken (or time
+    /// out), then re-enter it as many times as it was entered. Returns TRUE when woken.
+    pub(crate) fn sleep_condition_variable_cs(&mut self, cv: u32, cs: u32, timeout: u32) -> u32 {
+        let lock = self.critical_section(cs);
+        let mut saved = 1;
+        if let Some(SyncObject::Mutex {
+            owner, recursion, ..
+        }) = self.sync.get_mut(lock)
+        {
+            saved = std::mem::take(recursion).max(1);
+            *owner = None;
+        }
+        self.mirror_critical_section(lock);
+        self.sleep_condition_variable(cv, lock, false, Some(saved), timeout)
+    }
+
+    /// SleepConditionVariableSRW: like the CS variant, in the lock mode the caller holds.
+    pub(crate) fn sleep_condition_variable_srw(
+        &mut self,
+        cv: u32,
+        srw: u32,
+        timeout: u32,
+        shared: bool,
+    ) -> u32 {
+        let lock = self.sync.at_address(srw, srw_lock);
+        self.unlock_srw(lock, shared);
+        self.sleep_condition_variable(cv, lock, shared, None, timeout)
+    }
+
+    /// WakeConditionVariable/WakeAllConditionVariable.
+    pub(crate) fn wake_condition_variable(&mut self, addr: u32, all: bool) {
+        let handle = self.sync.at_address(addr, condition_variable);
+        let sleepers: Vec<u32> = self
+            .threads
+            .waiters()
+            .into_iter()
+            .filter(|&id| {
+                self.threads.wait_of(id).is_some_and(|wait| {
+                    matches!(wait.kind, WaitKind::Condition { .. }) && wait.handles == [handle]
+                })
+            })
+            .collect();
+        let Some(SyncObject::ConditionVariable { notified }) = self.sync.get_mut(handle) else {
+            return;
+        };
+        for id in sleepers {
+            if notified.insert(id) && !all {
+                break;
+            }
+        }
+        self.resolve_waits();
+    }
+
+    /// InitOnceBeginInitialize: returns TRUE and sets `*pending` when the caller now runs
+    /// the initialization, or stores the context once it is complete. A synchronous caller
+    /// waits while another thread runs it; asynchronous callers may all run it at once.
+    pub(crate) fn begin_init_once(
+        &mut self,
+        addr: u32,
+        check_only: bool,
+        asynchronous: bool,
+        pending: u32,
+        context: u32,
+    ) -> u32 {
+        let handle = self.sync.at_address(addr, init_once);
+        match self.sync.get(handle) {
+            Some(&SyncObject::InitOnce {
+                done: true,
+                context: value,
+                ..
+            }) => {
+                self.write_init_once_result(pending, false, context, value);
+                1
+            }
+            _ if check_only => {
+                self.set_last_error(ERROR_GEN_FAILURE);
+                0
+            }
+            _ if asynchronous => {
+                self.write_init_once_result(pending, true, 0, 0);
+                1
+            }
+            _ => self.wait(init_once_wait(handle, true, pending, context)),
+        }
+    }
+
+    /// InitOnceComplete: store `context` and release the waiters, or with `failed` hand the
+    /// initialization to the next synchronous caller.
+    pub(crate) fn complete_init_once(
+        &mut self,
+        addr: u32,
+        context: u32,
+        failed: bool,
+        asynchronous: bool,
+    ) -> bool {
+        let me = self.threads.current_id();
+        let handle = self.sync.at_address(addr, init_once);
+        let Some(SyncObject::InitOnce {
+            owner,
+            done,
+            context: stored,
+        }) = self.sync.get_mut(handle)
+        else {
+            return false;
+        };
+        if *done || (!asynchronous && *owner != Some(me)) {
+            self.set_last_error(ERROR_GEN_FAILURE);
+            return false;
+        }
+        *owner = None;
+        if !failed {
+            *done = true;
+            *stored = context;
+        }
+        self.resolve_waits();
+        true
+    }
+
+    /// InitOnceExecuteOnce: run `callback(addr, param, context)` for the first caller and
+    /// return its BOOL; later callers get the stored context. Callers that arrive while it
+    /// runs wait for it, and return FALSE if it fails.
+    pub(crate) fn execute_init_once(
+        &mut self,
+        addr: u32,
+        callback: u32,
+        param: u32,
+        context: u32,
+    ) -> u32 {
+        let me = self.threads.current_id();
+        let handle = self.sync.at_address(addr, init_once);
+        match self.sync.get(handle) {
+            Some(&SyncObject::InitOnce {
+                done: true,
+                context: value,
+                ..
+            }) => {
+                self.write_init_once_result(0, false, context, value);
+                return 1;
+            }
+            Some(SyncObject::InitOnce { owner: None, .. }) => {
+                self.acquire_handle(me, handle, false);
+            }
+            _ => return self.wait(init_once_wait(handle, false, 0, context)),
+        }
+        let result = match self.call_guest(callback, None, &[addr, param, context]) {
+            Ok(result) => result,
+            Err(err) => {
+                self.set_guest_fault(err);
+                0
+            }
+        };
+        let value = match context {
+            0 => 0,
+            ptr => self.read_u32(ptr).unwrap_or(0),
+        };
+        self.complete_init_once(addr, value, result == 0, false);
+        result
+    }
+
+    pub(super) fn deadline_after(&self, timeout: u32) -> Option<u64> {
+        (timeout != INFINITE).then(|| self.threads.clock() + timeout as u64)
+    }
+
+    // Wake every blocked thread whose wait can now complete, oldest wait first.
+    pub(super) fn resolve_waits(&mut self) {
+        for id in self.threads.waiters() {
+            let Some(mut wait) = self.threads.wait_of(id).cloned() else {
+                continue;
+            };
+            loop {
+                match self.try_complete(id, &wait) {
+                    Some(Outcome::Done(result)) => {
+                        self.wake_thread(id, result);
+                        break;
+                    }
+                    Some(Outcome::Next(next)) => wait = next,
+                    None => {
+                        if let Some(thread) = self.threads.get_mut(id) {
+                            thread.state = ThreadState::Blocked(wait);
+                        }
+                        break;
+                    }
+                }
+            }
+        }
+    }
+
+    // Complete the wait immediately when possible, otherwise block the current thread.
+    fn wait(&mut self, mut wait: Wait) -> u32 {
+        let me = self.threads.current_id();
+        loop {
+            match self.try_complete(me, &wait) {
+                Some(Outcome::Done(result)) => return result,
+                Some(Outcome::Next(next)) => wait = next,
+                None => return self.block_current(wait),
+            }
+        }
+    }
+
+    fn try_lock(&mut self, handle: u32, shared: bool) -> bool {
+        let me = self.threads.current_id();
+        if !self.handle_signaled(me, handle, shared) {
+            return false;
+        }
+        self.acquire_handle(me, handle, shared);
+        true
+    }
+
+    fn sleep_condition_variable(
+        &mut self,
+        cv: u32,
+        lock: u32,
+        shared: bool,
+        recursion: Option<u32>,
+        timeout: u32,
+    ) -> u32 {
+        self.resolve_waits();
+        let handle = self.sync.at_address(cv, condition_variable);
+        let wait = Wait {
+            handles: vec![handle],
+            kind: WaitKind::Condition {
+                lock,
+                shared,
+                recursion,
+            },
+            deadline: self.deadline_after(timeout),
+        };
+        self.wait(wait)
+    }
+
+    fn try_complete(&mut self, id: u32, wait: &Wait) -> Option<Outcome> {
+        if matches!(wait.kind, WaitKind::Objects { .. })
+            && !wait.handles.iter().all(|&handle| self.is_waitable(handle))
+        {
+            self.set_thread_last_error(id, ERROR_INVALID_HANDLE);
+            return Some(Outcome::Done(WAIT_FAILED));
+        }
+        let shared = matches!(wait.kind, WaitKind::Lock { shared: true, .. });
+        let signaled: Vec<bool> = wait
+            .handles
+            .iter()
+            .map(|&handle| self.handle_signaled(id, handle, shared))
+            .collect();
+        let timed_out = wait
+            .deadline
+            .is_some_and(|deadline| deadline <= self.threads.clock());
+        match wait.kind {
+            WaitKind::Objects { wait_all: true } if !signaled.is_empty() => {
+                if signaled.iter().all(|&ready| ready) {
+                    let mut abandoned = false;
+                    for &handle in &wait.handles {
+                        abandoned |= self.acquire_handle(id, handle, false);
+                    }
+                    let base = if abandoned {
+                        WAIT_ABANDONED_0
+                    } else {
+                        WAIT_OBJECT_0
+                    };
+                    return Some(Outcome::Done(base));
+                }
+                timed_out.then_some(Outcome::Done(WAIT_TIMEOUT))
+            }
+            WaitKind::Objects { .. } => {
+                if let Some(index) = signaled.iter().position(|&ready| ready) {
+                    let base = if self.acquire_handle(id, wait.handles[index], false) {
+                        WAIT_ABANDONED_0
+                    } else {
+                        WAIT_OBJECT_0
+                    };
+                    return Some(Outcome::Done(base + index as u32));
+                }
+                // Sleep is a wait on nothing and returns 0.
+                let result = if wait.handles.is_empty() {
+                    0
+                } else {
+                    WAIT_TIMEOUT
+                };
+                timed_out.then_some(Outcome::Done(result))
+            }
+            WaitKind::Lock {
+                shared,
+                result,
+                recursion,
+            } => {
+                let handle = *wait.handles.first()?;
+                signaled[0].then(|| {
+                    self.acquire_handle(id, handle, shared);
+                    if let Some(count) = recursion {
+                        self.restore_recursion(handle, count);
+                    }
+                    Outcome::Done(result)
+                })
+            }
+            WaitKind::Condition {
+                lock,
+                shared,
+                recursion,
+            } => {
+                let handle = *wait.handles.first()?;
+                let woken = if signaled[0] {
+                    self.acquire_handle(id, handle, false);
+                    1
+                } else if timed_out {
+                    self.set_thread_last_error(id, ERROR_TIMEOUT);
+                    0
+                } else {
+                    return None;
+                };
+                let mut next = lock_wait(lock, shared);
+                next.kind = WaitKind::Lock {
+                    shared,
+                    result: woken,
+                    recursion,
+                };
+                Some(Outcome::Next(next))
+            }
+            WaitKind::InitOnce {
+                claim,
+                pending,
+                context,
+            } => {
+                let handle = *wait.handles.first()?;
+                if !signaled[0] {
+                    return None;
+                }
+                let result = match self.sync.get(handle) {
+                    Some(&SyncObject::InitOnce {
+                        done: true,
+                        context: value,
+                        ..
+                    }) => {
+                        self.write_init_once_result(pending, false, context, value);
+                        1
+                    }
+                    _ if claim => {
+                        self.acquire_handle(id, handle, false);
+                        self.write_init_once_result(pending, true, 0, 0);
+                        1
+                    }
+                    _ => {
+                        self.set_thread_last_error(id, ERROR_GEN_FAILURE);
+                        0
+                    }
+                };
+                Some(Outcome::Done(result))
+            }
+        }
+    }
+
+    fn is_waitable(&self, handle: u32) -> bool {
+        matches!(handle, CURRENT_PROCESS | CURRENT_THREAD)
+            || self.threads.id_of(handle).is_some()
+            || self.sync.get(handle).is_some()
+    }
+
+    fn handle_signaled(&self, id: u32, handle: u32, shared: bool) -> bool {
+        if self.threads.id_of(handle).is_some() {
+            return self.threads.is_signaled(handle);
+        }
+        self.sync
+            .get(handle)
+            .is_some_and(|object| object.is_signaled(id, shared))
+    }
+
+    // Returns true if the handle was a mutex abandoned by its previous owner.
+    fn acquire_handle(&mut self, id: u32, handle: u32, shared: bool) -> bool {
+        let Some(object) = self.sync.get_mut(handle) else {
+            return false;
+        };
+        let abandoned = object.acquire(id, shared);
+        self.mirror_critical_section(handle);
+        abandoned
+    }
+
+    fn restore_recursion(&mut self, handle: u32, count: u32) {
+        if let Some(SyncObject::Mutex { recursion, .. }) = self.sync.get_mut(handle) {
+            *recursion = count;
+        }
+        self.mirror_critical_section(handle);
+    }
+
+    // `*pending` (when asked for) and, once complete, `*context_ptr = context`.
+    fn write_init_once_result(
+        &mut self,
+        pending: u32,
+        is_pending: bool,
+        context_ptr: u32,
+        context: u32,
+    ) {
+        if pending != 0 {
+            let _ = self.write_u32(pending, is_pending as u32);
+        }
+        if context_ptr != 0 && !is_pending {
+            let _ = self.write_u32(context_ptr, context);
+        }
+    }
+
+    fn unlock_srw(&mut self, handle: u32, shared: bool) {
+        if let Some(SyncObject::SrwLock {
+            exclusive,
+            shared: readers,
+        }) = self.sync.get_mut(handle)
+        {
+            if shared {
+                *readers = readers.saturating_sub(1);
+            } else {
+                *exclusive = None;
+            }
+        }
+    }
+
+    fn wake_thread(&mut self, id: u32, result: u32) {
+        let current = id == self.threads.current_id();
+        if current {
+            self.regs.eax = result;
+        }
+        if let Some(thread) = self.threads.get_mut(id) {
+            thread.state = ThreadState::Ready;
+            if !current {
+                thread.context.regs.eax = result;
+            }
+        }
+    }
+
+    fn set_thread_last_error(&mut self, id: u32, value: u32) {
+        if id == self.threads.current_id() {
+            self.last_error = value;
+        } else if let Some(thread) = self.threads.get_mut(id) {
+            thread.context.last_error = value;
+        }
+    }
+
+    fn critical_section(&mut self, addr: u32) -> u32 {
+        self.sync.at_address(addr, || critical_section_mutex(addr))
+    }
+
+    // Keep OwningThread/RecursionCount/LockCount in the guest struct in step with the mutex.
+    fn mirror_critical_section(&mut self, handle: u32) {
+        if let Some(&SyncObject::Mutex {
+            owner,
+            recursion,
+            critical_section: Some(addr),
+            ..
+        }) = self.sync.get(handle)
+        {
+            self.write_critical_section(addr, owner, recursion);
+        }
+    }
+
+    fn write_critical_section(&mut self, addr: u32, owner: Option<u32>, recursion: u32) {
+        let lock_count = if owner.is_some() { 0 } else { u32::MAX };
+        let _ = self.write_u32(addr + CS_LOCK_COUNT, lock_count);
+        let _ = self.write_u32(addr + CS_RECURSION_COUNT, recursion);
+        let _ = self.write_u32(addr + CS_OWNING_THREAD, owner.unwrap_or(0));
+    }
+}
+
+fn lock_wait(handle: u32, shared: bool) -> Wait {
+    Wait {
+        handles: vec![handle],
+        kind: WaitKind::Lock {
+            shared,
+            result: 0,
+            recursion: None,
+        },
+        deadline: None,
+    }
+}
+
+fn init_once_wait(handle: u32, claim: bool, pending: u32, context: u32) -> Wait {
+    Wait {
+        handles: vec![handle],
+        kind: WaitKind::InitOnce {
+            claim,
+            pending,
+            context,
+        },
+        deadline: None,
+    }
+}
+
+fn critical_section_mutex(addr: u32) -> SyncObject {
+    SyncObject::Mutex {
+        owner: None,
+        recursion: 0,
+        abandoned: false,
+        critical_section: Some(addr),
+    }
+}
+
+fn srw_lock() -> SyncObject {
+    SyncObject::SrwLock {
+        exclusive: None,
+        shared: 0,
+    }
+}
+
+fn condition_variable() -> SyncObject {
+    SyncObject::ConditionVariable {
+        notified: Default::default(),
+    }
+}
+
+fn init_once() -> SyncObject {
+    SyncObject::InitOnce {
+        owner: None,
+        done: false,
+        context: 0,
+    }
+}
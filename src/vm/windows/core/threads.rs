@@ -0,0 +1,338 @@
+use crate::vm::*;
+
+const DEFAULT_THREAD_STACK: u32 = 0x10_0000;
+const THREAD_STACK_ALIGN: u32 = 0x1_0000;
+const THREAD_STACK_HINT: u32 = 0x0200_0000;
+const CURRENT_PROCESS_ID: u32 = 1;
+const TEB_EXCEPTION_LIST: u32 = 0x00;
+const TEB_STACK_BASE: u32 = 0x04;
+const TEB_STACK_LIMIT: u32 = 0x08;
+const TEB_SELF: u32 = 0x18;
+const TEB_PROCESS_ID: u32 = 0x20;
+const TEB_THREAD_ID: u32 = 0x24;
+
+impl Vm {
+    pub(crate) fn current_thread_id(&self) -> u32 {
+        self.threads.current_id()
+    }
+
+    /// RDTSC value, derived from virtual time so runs are reproducible.
+    pub(crate) fn timestamp_counter(&self) -> u64 {
+        self.threads.timestamp()
+    }
+
+    /// CreateThread: map a stack and TEB for a new thread and queue it on the scheduler.
+    ///
+    /// Returns `(handle, thread id)`. The thread first runs when the scheduler picks it.
+    pub(crate) fn spawn_thread(
+        &mut self,
+        start: u32,
+        param: u32,
+        stack_size: u32,
+        suspended: bool,
+    ) -> Result<(u32, u32), VmError> {
+        if self.memory.is_empty() {
+            return Err(VmError::NoImage);
+        }
+        let stack_size = match stack_size {
+            0 => DEFAULT_THREAD_STACK,
+            size => size
+                .checked_next_multiple_of(THREAD_STACK_ALIGN)
+                .ok_or(VmError::OutOfMemory)?,
+        };
+        // The TEB page sits directly above the stack.
+        let region_size = stack_size
+            .checked_add(PAGE_SIZE)
+            .ok_or(VmError::OutOfMemory)?;
+        let stack_limit = self
+            .memory
+            .find_free(THREAD_STACK_HINT, region_size, THREAD_STACK_ALIGN)
+            .or_else(|| {
+                self.memory
+                    .find_free(THREAD_STACK_ALIGN, region_size, THREAD_STACK_ALIGN)
+            })
+            .ok_or(VmError::OutOfMemory)?;
+        self.memory.map(stack_limit, region_size);
+        let stack_top = stack_limit + stack_size;
+        let teb = stack_top;
+
+        // The start routine returns to address 0, which ends the thread.
+        let esp = stack_top - 8;
+        self.write_u32(esp, 0)?;
+        self.write_u32(esp + 4, param)?;
+        let context = ThreadContext {
+            regs: Registers {
+                esp,
+                eip: start,
+                ..Registers::default()
+            },
+            stack_top,
+            fs_base: teb,
+            tls_values: self.tls_values.keys().map(|&index| (index, 0)).collect(),
+            ..ThreadContext::default()
+        };
+        let (id, handle) = self.threads.insert(GuestThread {
+            state: ThreadState::Ready,
+            wait_order: 0,
+            suspend_count: u32::from(suspended),
+            stack_limit,
+            region: Some((stack_limit, region_size)),
+            context,
+            exceptions: ExceptionState::default(),
+        });
+        self.write_teb(teb, stack_top, stack_limit, id)?;
+        Ok((handle, id))
+    }
+
+    /// ResumeThread: returns the previous suspend count, or `None` for an unknown handle.
+    pub(crate) fn resume_thread(&mut self, handle: u32) -> Option<u32> {
+        let thread = self.threads.by_handle_mut(handle)?;
+        let previous = thread.suspend_count;
+        thread.suspend_count = previous.saturating_sub(1);
+        Some(previous)
+    }
+
+    /// GetExitCodeThread: `STILL_ACTIVE` until the thread has exited.
+    pub(crate) fn thread_exit_code(&self, handle: u32) -> Option<u32> {
+        let thread = self.threads.by_handle(handle)?;
+        match thread.state {
+            ThreadState::Exited(code) => Some(code),
+            _ => Some(STILL_ACTIVE),
+        }
+    }
+
+    /// Sleep: block for `millis` of virtual time; zero just gives up the rest of the quantum.
+    pub(crate) fn sleep_current(&mut self, millis: u32) {
+        if millis == 0 {
+            self.yield_thread();
+            return;
+        }
+        let deadline = self.deadline_after(millis);
+        self.block_current(Wait::any(Vec::new(), deadline));
+    }
+
+    /// Let other ready threads run before the current one continues.
+    pub(crate) fn yield_thread(&mut self) {
+        if self.can_switch_threads() {
+            self.threads.request_yield();
+        }
+    }
+
+    /// Run every other thread until none of them is ready, then resume the current one.
+    ///
+    /// Host code uses this where the guest expects background threads to have made progress.
+    pub(crate) fn run_other_threads(&mut self) -> Result<(), VmError> {
+        if !self.threads.has_others() {
+            return Ok(());
+        }
+        self.threads.current_mut().state = ThreadState::Parked;
+        self.run_until_resumed()
+    }
+
+    // Called from host stubs in the middle of a guest call. When an outermost execute loop
+    // is running, the thread is switched out after the stub returns and the real result is
+    // written to EAX on wake-up; otherwise the other threads run right here until it wakes.
+    pub(super) fn block_current(&mut self, wait: Wait) -> u32 {
+        self.threads.block_current(wait);
+        if self.can_switch_threads() {
+            return WAIT_TIMEOUT;
+        }
+        match self.run_until_resumed() {
+            Ok(()) => self.regs.eax,
+            Err(err) => {
+                self.set_guest_fault(err);
+                WAIT_FAILED
+            }
+        }
+    }
+
+    /// Report an error from guest code a host call ran; it is raised once the call returns.
+    pub(crate) fn set_guest_fault(&mut self, err: VmError) {
+        self.threads.fault = Some(err);
+    }
+
+    fn can_switch_threads(&self) -> bool {
+        self.threads.dispatching && self.stack_depth == 0
+    }
+
+    fn run_until_resumed(&mut self) -> Result<(), VmError> {
+        let me = self.threads.current_id();
+        let saved_eax = self.regs.eax;
+        let dispatching = std::mem::replace(&mut self.threads.dispatching, true);
+        self.threads.pin(me);
+        let result = self.schedule().and_then(|()| {
+            self.run_threads(|vm| {
+                vm.threads.current_id() == me && vm.threads.current().state == ThreadState::Ready
+            })
+        });
+        if result.is_err() {
+            self.resume_thread_after_error(me);
+            self.regs.eax = saved_eax;
+        }
+        self.threads.unpin();
+        self.threads.dispatching = dispatching;
+        result
+    }
+
+    /// Step guest code, switching threads as the scheduler decides, until `done` holds.
+    pub(super) fn run_threads(&mut self, done: impl Fn(&Vm) -> bool) -> Result<(), VmError> {
+        self.debugger.depth += 1;
+        let result = self.run_threads_inner(done);
+        self.debugger.depth -= 1;
+        result
+    }
+
+    fn run_threads_inner(&mut self, done: impl Fn(&Vm) -> bool) -> Result<(), VmError> {
+        let limit = self.config.execution_limit_value();
+        let quantum = self.config.thread_quantum_value();
+        let mut steps = 0u64;
+        while !done(self) {
+            if self.threads.current().exceptions.pending.is_some() {
+                self.run_exception_request()?;
+                continue;
+            }
+            if self.regs.eip == 0 {
+                // A thread's start routine returned (or it called ExitThread).
+                self.finish_current_thread(self.regs.eax);
+                self.schedule()?;
+                continue;
+            }
+            if self.regs.eip == DISPATCH_RETURN {
+                self.exception_handler_returned()?;
+                continue;
+            }
+            if self.regs.eip == CATCH_RETURN {
+                self.catch_block_returned()?;
+                continue;
+            }
+            if limit != 0 && steps > limit {
+                self.trace_execution_limit();
+                return Err(VmError::ExecutionLimit);
+            }
+            let eip = self.regs.eip;
+            let debugging = self.debugger.active();
+            if debugging {
+                if let Some(reason) = self.debug_before_step(eip) {
+                    return Err(VmError::Stopped(reason));
+                }
+            }
+            let tracing = self.tracer.per_step();
+            if tracing {
+                self.trace_before_step(eip);
+            }
+            let result = match debugging.then(|| self.debug_pending_import(eip)).flatten() {
+                Some(result) => result,
+                None => self.step_traced(),
+            };
+            if tracing {
+                self.trace_after_step(eip, result.is_ok());
+            }
+            if let (Some(coverage), true) = (self.coverage.as_mut(), result.is_ok()) {
+                coverage.hits.insert(eip);
+            }
+            if let Err(err) = result {
+                self.raise_fault(err)?;
+            }
+            if let Some(err) = self.threads.fault.take() {
+                return Err(err);
+            }
+            if debugging {
+                if let Some(reason) = self.debug_after_step(eip) {
+                    return Err(VmError::Stopped(reason));
+                }
+            }
+            steps += 1;
+            self.threads.retire();
+            if self.stack_depth == 0 && self.threads.tick(quantum) {
+                self.schedule()?;
+            }
+        }
+        Ok(())
+    }
+
+    // Wake satisfied waits and switch to the next runnable thread, advancing virtual
+    // time when every thread is waiting on a timeout.
+    fn schedule(&mut self) -> Result<(), VmError> {
+        loop {
+            self.resolve_waits();
+            if let Some(next) = self.threads.next_ready() {
+                self.switch_to(next);
+                return Ok(());
+            }
+            if !self.threads.advance() {
+                return Err(VmError::Deadlock);
+            }
+        }
+    }
+
+    // After a fault or deadlock, hand the VM back to the thread the host was driving.
+    pub(super) fn resume_thread_after_error(&mut self, id: u32) {
+        self.switch_to(id);
+        self.threads.current_mut().state = ThreadState::Ready;
+    }
+
+    fn switch_to(&mut self, id: u32) {
+        let current = self.threads.current_id();
+        if id != current {
+            let saved = ThreadContext {
+                regs: std::mem::take(&mut self.regs),
+                flags: self.flags,
+                xmm: self.xmm,
+                mxcsr: self.mxcsr,
+                fpu: std::mem::take(&mut self.fpu),
+                stack_top: self.stack_top,
+                stack_depth: self.stack_depth,
+                fs_base: self.fs_base,
+                last_error: self.last_error,
+                tls_values: std::mem::take(&mut self.tls_values),
+            };
+            self.threads.current_mut().context = saved;
+            if let Some(thread) = self.threads.get_mut(id) {
+                let next = std::mem::take(&mut thread.context);
+                self.regs = next.regs;
+                self.flags = next.flags;
+                self.xmm = next.xmm;
+                self.mxcsr = next.mxcsr;
+                self.fpu = next.fpu;
+                self.stack_top = next.stack_top;
+                self.stack_depth = next.stack_depth;
+                self.fs_base = next.fs_base;
+                self.last_error = next.last_error;
+                self.tls_values = next.tls_values;
+            }
+        }
+        self.threads.set_current(id);
+    }
+
+    fn finish_current_thread(&mut self, exit_code: u32) {
+        self.sync.abandon_owned_by(self.threads.current_id());
+        let thread = self.threads.current_mut();
+        thread.state = ThreadState::Exited(exit_code);
+        if let Some((start, size)) = thread.region.take() {
+            self.memory.unmap(start, size);
+        }
+    }
+
+    /// Lowest address the current thread's stack may grow down to.
+    pub(super) fn stack_limit(&self) -> u32 {
+        match self.threads.current().stack_limit {
+            0 => self.base + self.heap_end as u32,
+            limit => limit,
+        }
+    }
+
+    pub(super) fn write_teb(
+        &mut self,
+        teb: u32,
+        stack_top: u32,
+        stack_limit: u32,
+        thread_id: u32,
+    ) -> Result<(), VmError> {
+        self.write_u32(teb + TEB_EXCEPTION_LIST, 0xFFFF_FFFF)?;
+        self.write_u32(teb + TEB_STACK_BASE, stack_top)?;
+        self.write_u32(teb + TEB_STACK_LIMIT, stack_limit)?;
+        self.write_u32(teb + TEB_SELF, teb)?;
+        self.write_u32(teb + TEB_PROCESS_ID, CURRENT_PROCESS_ID)?;
+        self.write_u32(teb + TEB_THREAD_ID, thread_id)
+    }
+}
@@ -5,6 +5,9 @@ impl Vm {
         let index = self.tls_next_index;
         self.tls_next_index = self.tls_next_index.wrapping_add(1);
         self.tls_values.insert(index, 0);
+        for thread in self.threads.others_mut() {
+            thread.context.tls_values.insert(index, 0);
+        }
         index
     }
 
@@ -22,6 +25,9 @@ impl Vm {
     }
 
     pub(crate) fn tls_free(&mut self, index: u32) -> bool {
+        for thread in self.threads.others_mut() {
+            thread.context.tls_values.remove(&index);
+        }
         self.tls_values.remove(&index).is_some()
     }
 }
@@ -0,0 +1,520 @@
+use std::path::Path;
+
+use crate::architecture::intel::x86::disasm;
+use crate::vm::trace::{RecordedReturn, WriteCapture, REGISTERS};
+use crate::vm::*;
+
+// cdecl host functions do not declare how many arguments they take, so this
+// many stack words are recorded for them.
+const CDECL_ARGS: u32 = 4;
+const MAX_ARG_TEXT: u32 = 256;
+// Pointers below this are small integers or handles, not strings.
+const MIN_STRING_ADDR: u32 = 0x10000;
+const NESTED_STACK_WORDS: u32 = 6;
+
+impl Vm {
+    /// Send trace records to `sink`. A previous sink is flushed and replaced;
+    /// the first error it reported is returned once the new one is installed.
+    pub fn set_trace_sink(
+        &mut self,
+        sink: Box<dyn TraceSink>,
+        options: TraceOptions,
+    ) -> Result<(), VmError> {
+        let previous = self.tracer.finish();
+        self.tracer.set_sink(sink, options);
+        Ok(previous?)
+    }
+
+    /// Trace to a new file at `path`; see `TraceWriter`.
+    pub fn trace_to_file(
+        &mut self,
+        path: impl AsRef<Path>,
+        format: TraceFormat,
+        options: TraceOptions,
+    ) -> Result<(), VmError> {
+        let writer = TraceWriter::create(path, format)?;
+        self.set_trace_sink(Box::new(writer), options)
+    }
+
+    /// Flush and remove the trace sink, reporting the first error writing to it.
+    pub fn finish_trace(&mut self) -> Result<(), VmError> {
+        Ok(self.tracer.finish()?)
+    }
+
+    /// Feed the host-call results of a recorded trace back to the guest.
+    ///
+    /// Start replaying at the point the recording started. Each host call still
+    /// runs, so handles, heaps, threads and guest callbacks stay consistent, but
+    /// afterwards its memory writes are undone and the recorded ones applied, and
+    /// EAX, ECX, EDX and the last error are set to the recorded values. Calls
+    /// must come in the recorded order; a different function fails the run with
+    /// `VmError::ReplayDivergence`. Replayed calls still reach the host, so use a
+    /// `SandboxConfig` to keep them off the network and file system.
+    pub fn replay(&mut self, records: &[TraceRecord]) {
+        self.tracer.replay = Some(Replay::new(records));
+    }
+
+    /// `replay` the host calls of a trace file in either format.
+    pub fn replay_from_file(&mut self, path: impl AsRef<Path>) -> Result<(), VmError> {
+        let records = read_trace(path)?;
+        self.replay(&records);
+        Ok(())
+    }
+
+    /// Recorded host calls the replay has not reached; `None` when not replaying.
+    pub fn replay_remaining(&self) -> Option<usize> {
+        self.tracer
+            .replay
+            .as_ref()
+            .map(|replay| replay.calls.len() - replay.next)
+    }
+
+    pub fn stop_replay(&mut self) {
+        self.tracer.replay = None;
+    }
+
+    pub(super) fn trace_before_step(&mut self, eip: u32) {
+        self.tracer.eip = eip;
+        if self.tracer.traces_instructions() {
+            let bytes = disasm::disassemble_with(|at| self.peek_u8(at), eip, 1)
+                .pop()
+                .map(|ins| ins.bytes)
+                .unwrap_or_default();
+            self.tracer.before = Some((bytes, self.register_snapshot()));
+        }
+    }
+
+    /// Write the record of the instruction at `eip`, unless it faulted.
+    pub(super) fn trace_after_step(&mut self, eip: u32, completed: bool) {
+        match self.tracer.before.take() {
+            Some((bytes, before)) if completed => {
+                let after = self.register_snapshot();
+                let changes = REGISTERS
+                    .iter()
+                    .zip(before.iter().zip(after))
+                    .filter(|(reg, (old, new))| **reg != Register::Eip && **old != *new)
+                    .map(|(reg, (_, new))| (*reg, new))
+                    .collect();
+                self.tracer.emit(TraceRecord::Instruction {
+                    eip,
+                    bytes,
+                    changes,
+                });
+            }
+            _ => self.tracer.flush_accesses(),
+        }
+    }
+
+    pub(super) fn trace_fault(&mut self, eip: u32, err: &VmError) {
+        if !self.tracer.is_on() {
+            return;
+        }
+        let code = self.trace_listing(eip);
+        self.tracer.emit(TraceRecord::Fault {
+            eip,
+            error: err.to_string(),
+            code,
+        });
+    }
+
+    pub(super) fn trace_execution_limit(&mut self) {
+        if !self.tracer.is_on() {
+            return;
+        }
+        let eip = self.regs.eip;
+        let code = self.trace_listing(eip);
+        self.tracer.emit(TraceRecord::ExecutionLimit { eip, code });
+    }
+
+    pub(super) fn trace_nested_call(&mut self, entry: u32) {
+        if !self.tracer.is_on() {
+            return;
+        }
+        let esp = self.regs.esp;
+        let stack = (0..NESTED_STACK_WORDS)
+            .map(|idx| self.peek_u32(esp.wrapping_add(idx * 4)).unwrap_or(0))
+            .collect();
+        self.tracer
+            .emit(TraceRecord::NestedCall { entry, esp, stack });
+    }
+
+    pub(super) fn trace_unresolved_import(&mut self, name: &str) {
+        self.tracer.emit(TraceRecord::UnresolvedImport {
+            name: name.to_string(),
+        });
+    }
+
+    pub(super) fn trace_module_mapped(&mut self, name: &str, base: u32, size: u32) {
+        self.tracer.emit(TraceRecord::ModuleMapped {
+            name: name.to_string(),
+            base,
+            size,
+        });
+    }
+
+    pub(super) fn trace_exception(&mut self, code: u32, address: u32) {
+        let esp = self.regs.esp;
+        self.tracer
+            .emit(TraceRecord::Exception { code, address, esp });
+    }
+
+    pub(super) fn trace_missing_import(&mut self, addr: u32) {
+        if !self.tracer.is_on() {
+            return;
+        }
+        if let Some(name) = self.imports_by_iat_name.get(&addr).cloned() {
+            self.tracer.emit(TraceRecord::MissingImport { name, addr });
+        }
+    }
+
+    /// Run the host function the guest called at `addr`, recording the call and
+    /// its effects when tracing and substituting the recorded ones when replaying.
+    pub(super) fn invoke_host(
+        &mut self,
+        addr: u32,
+        host: HostFunction,
+        stack_ptr: u32,
+    ) -> Result<u32, VmError> {
+        let tracing = self.tracer.traces_host_calls();
+        if !tracing && self.tracer.replay.is_none() {
+            return Ok((host.func)(self, stack_ptr));
+        }
+        let name = self
+            .imports_by_iat_name
+            .get(&addr)
+            .cloned()
+            .unwrap_or_else(|| format!("0x{addr:08X}"));
+        let recorded = self.next_recorded_call(&name)?;
+        let seq = self.tracer.calls;
+        self.tracer.calls += 1;
+        if tracing {
+            let args = self.host_args(&name, stack_ptr, host.stack_cleanup);
+            self.tracer.emit(TraceRecord::HostCall {
+                seq,
+                name,
+                addr,
+                args,
+            });
+        }
+
+        self.tracer.captures.push(WriteCapture::new());
+        let mut ret = (host.func)(self, stack_ptr);
+        let capture = self.tracer.end_capture();
+        let writes = match recorded {
+            Some(recorded) => {
+                ret = recorded.eax;
+                self.apply_recorded(&capture, &recorded);
+                recorded.writes
+            }
+            None => coalesce_writes(&capture),
+        };
+
+        if tracing {
+            self.tracer.emit(TraceRecord::HostReturn {
+                seq,
+                eax: ret,
+                ecx: self.regs.ecx,
+                edx: self.regs.edx,
+                last_error: self.last_error(),
+                writes,
+            });
+        }
+        Ok(ret)
+    }
+
+    /// The old contents of `addr..addr + len` while a host call's writes are
+    /// being captured.
+    pub(super) fn capture_old(&self, addr: u32, len: usize) -> Option<Vec<u8>> {
+        if self.tracer.captures.is_empty() {
+            return None;
+        }
+        let mut old = vec![0u8; len];
+        self.memory.read(addr, &mut old).ok()?;
+        Some(old)
+    }
+
+    // Claim the next recorded call, which has to be the same function.
+    fn next_recorded_call(&mut self, name: &str) -> Result<Option<RecordedReturn>, VmError> {
+        let Some(replay) = self.tracer.replay.as_mut() else {
+            return Ok(None);
+        };
+        let seq = replay.next as u64;
+        match replay.calls.get(replay.next) {
+            Some(call) if call.name == name => {
+                replay.next += 1;
+                Ok(call.result.clone())
+            }
+            call => Err(VmError::ReplayDivergence {
+                seq,
+                expected: call.map(|call| call.name.clone()),
+                found: name.to_string(),
+            }),
+        }
+    }
+
+    fn apply_recorded(&mut self, capture: &WriteCapture, recorded: &RecordedReturn) {
+        for (&addr, &(old, _)) in capture {
+            self.replay_write(addr, &[old]);
+        }
+        for write in &recorded.writes {
+            self.replay_write(write.addr, &write.bytes);
+        }
+        self.regs.ecx = recorded.ecx;
+        self.regs.edx = recorded.edx;
+        self.set_last_error(recorded.last_error);
+    }
+
+    // Replayed bytes land regardless of protection, as the host's writes did.
+    fn replay_write(&mut self, addr: u32, bytes: &[u8]) {
+        let old = self.capture_old(addr, bytes.len());
+        if self.memory.write_unchecked(addr, bytes).is_ok() {
+            if let Some(old) = old {
+                self.tracer.capture_write(addr, &old, bytes);
+            }
+        }
+    }
+
+    fn host_args(&self, name: &str, stack_ptr: u32, stack_cleanup: u32) -> Vec<HostArg> {
+        let count = match stack_cleanup {
+            0 => CDECL_ARGS,
+            bytes => bytes / 4,
+        };
+        let function = name.rsplit('!').next().unwrap_or(name);
+        let wide = function.ends_with('W');
+        (1..=count)
+            .map_while(|idx| self.peek_u32(stack_ptr.wrapping_add(idx * 4)))
+            .map(|value| HostArg {
+                value,
+                text: self.peek_string(value, wide),
+            })
+            .collect()
+    }
+
+    // A NUL-terminated run of printable characters at `addr`, if there is one.
+    fn peek_string(&self, addr: u32, wide: bool) -> Option<String> {
+        if addr < MIN_STRING_ADDR {
+            return None;
+        }
+        let text = if wide {
+            let units = (0..MAX_ARG_TEXT)
+                .map(|idx| {
+                    let at = addr.wrapping_add(idx * 2);
+                    Some(u16::from_le_bytes([
+                        self.peek_u8(at)?,
+                        self.peek_u8(at + 1)?,
+                    ]))
+                })
+                .take_while(|unit| *unit != Some(0))
+                .collect::<Option<Vec<u16>>>()?;
+            if units.len() == MAX_ARG_TEXT as usize {
+                return None;
+            }
+            String::from_utf16(&units).ok()?
+        } else {
+            let bytes = (0..MAX_ARG_TEXT)
+                .map(|idx| self.peek_u8(addr.wrapping_add(idx)))
+                .take_while(|byte| *byte != Some(0))
+                .collect::<Option<Vec<u8>>>()?;
+            if bytes.len() == MAX_ARG_TEXT as usize {
+                return None;
+            }
+            String::from_utf8_lossy(&bytes).into_owned()
+        };
+        let printable = text
+            .chars()
+            .all(|ch| !ch.is_control() || matches!(ch, '\t' | '\r' | '\n'));
+        (!text.is_empty() && printable).then_some(text)
+    }
+
+    // The instructions from EIP on, as `address: text` separated by semicolons.
+    fn trace_listing(&self, eip: u32) -> String {
+        disasm::disassemble_with(|at| self.peek_u8(at), eip, 4)
+            .iter()
+            .map(|ins| format!("0x{:08X}: {ins}", ins.address))
+            .collect::<Vec<_>>()
+            .join("; ")
+    }
+
+    fn register_snapshot(&self) -> [u32; 10] {
+        REGISTERS.map(|reg| self.register(reg))
+    }
+
+    // Guest reads on the tracer's behalf, kept out of the debugger and the trace.
+    pub(super) fn peek_u8(&self, addr: u32) -> Option<u8> {
+        let mut buf = [0u8; 1];
+        self.memory.read(addr, &mut buf).ok()?;
+        Some(buf[0])
+    }
+
+    fn peek_u32(&self, addr: u32) -> Option<u32> {
+        let mut buf = [0u8; 4];
+        self.memory.read(addr, &mut buf).ok()?;
+        Some(u32::from_le_bytes(buf))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    use crate::vm::*;
+
+    fn create_test_vm() -> Vm {
+        let mut vm = Vm::new(VmConfig::new().architecture(Architecture::X86)).expect("vm");
+        vm.memory.map(0x1000, 0x10000);
+        vm.base = 0x1000;
+        vm.stack_top = 0x1000 + 0x10000 - 4;
+        vm.regs.esp = vm.stack_top;
+        vm.fs_base = 0x1000;
+        vm.write_u32(0x1000, 0xFFFF_FFFF).expect("teb");
+        vm
+    }
+
+    static CLOCK: AtomicU32 = AtomicU32::new(0);
+
+    // Different on every call, like a clock or a socket read.
+    fn query_clock(vm: &mut Vm, stack_ptr: u32) -> u32 {
+        let now = CLOCK.fetch_add(1, Ordering::SeqCst) + 1;
+        let out = vm.read_u32(stack_ptr + 4).expect("out");
+        vm.write_u32(out, now).expect("write");
+        vm.set_last_error(now);
+        now * 10
+    }
+
+    // Calls QueryClockA(0x6000, "clock") and returns its result plus what it stored.
+    fn clock_vm(name: &str) -> Vm {
+        let mut vm = create_test_vm();
+        vm.imports_by_iat.insert(
+            0x5000,
+            HostFunction {
+                func: query_clock,
+                stack_cleanup: 8,
+            },
+        );
+        vm.imports_by_iat_name.insert(0x5000, name.to_string());
+        vm.write_bytes(0x1_0100, b"clock\0").expect("text");
+        let code: &[u8] = &[
+            0x68, 0x00, 0x01, 0x01, 0x00, // push 0x10100
+            0x68, 0x00, 0x60, 0x00, 0x00, // push 0x6000
+            0xFF, 0x15, 0x00, 0x50, 0x00, 0x00, // call [0x5000]
+            0x8B, 0x1D, 0x00, 0x60, 0x00, 0x00, // mov ebx, [0x6000]
+            0x01, 0xD8, // add eax, ebx
+            0xC3, // ret
+        ];
+        vm.write_bytes(0x3000, code).expect("code");
+        vm
+    }
+
+    #[test]
+    fn records_instructions_memory_and_host_calls_then_replays_them() {
+        let mut vm = clock_vm("KERNEL32.dll!QueryClockA");
+        let buffer = TraceBuffer::new();
+        vm.set_trace_sink(Box::new(buffer.clone()), TraceOptions::all())
+            .expect("sink");
+        vm.execute(0x3000).expect("run");
+        vm.finish_trace().expect("finish");
+        let recorded = vm.regs.eax;
+        let now = vm.read_u32(0x6000).expect("clock");
+        assert_eq!(recorded, now * 11);
+
+        let records = buffer.take();
+        assert!(records.contains(&TraceRecord::HostCall {
+            seq: 0,
+            name: "KERNEL32.dll!QueryClockA".to_string(),
+            addr: 0x5000,
+            args: vec![
+                HostArg {
+                    value: 0x6000,
+                    text: None,
+                },
+                HostArg {
+                    value: 0x1_0100,
+                    text: Some("clock".to_string()),
+                },
+            ],
+        }));
+        assert!(records.iter().any(|record| matches!(
+            record,
+            TraceRecord::HostReturn { seq: 0, eax, last_error, writes, .. }
+                if *eax == now * 10
+                    && *last_error == now
+                    && writes == &[MemoryWrite { addr: 0x6000, bytes: now.to_le_bytes().to_vec() }]
+        )));
+        assert!(records.contains(&TraceRecord::Memory {
+            eip: 0x3010,
+            addr: 0x6000,
+            access: AccessKind::Read,
+            data: now.to_le_bytes().to_vec(),
+        }));
+        assert!(records.contains(&TraceRecord::Instruction {
+            eip: 0x3010,
+            bytes: vec![0x8B, 0x1D, 0x00, 0x60, 0x00, 0x00],
+            changes: vec![(Register::Ebx, now)],
+        }));
+
+        // The clock has moved on, but the replayed run sees the recorded values.
+        let mut replayed = clock_vm("KERNEL32.dll!QueryClockA");
+        replayed.replay(&records);
+        replayed.execute(0x3000).expect("replay");
+        assert_eq!(replayed.regs.eax, recorded);
+        assert_eq!(replayed.read_u32(0x6000).expect("clock"), now);
+        assert_eq!(replayed.last_error(), now);
+        assert_eq!(replayed.replay_remaining(), Some(0));
+
+        let mut diverged = clock_vm("KERNEL32.dll!GetTickCount");
+        diverged.replay(&records);
+        match diverged.execute(0x3000) {
+            Err(VmError::ReplayDivergence {
+                seq: 0,
+                expected: Some(expected),
+                found,
+            }) => {
+                assert_eq!(expected, "KERNEL32.dll!QueryClockA");
+                assert_eq!(found, "KERNEL32.dll!GetTickCount");
+            }
+            other => panic!("expected a divergence, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn faults_are_recorded_with_a_listing() {
+        let mut vm = create_test_vm();
+        vm.write_bytes(0x3000, &[0xA1, 0x00, 0x00, 0x00, 0x80]) // mov eax, [0x80000000]
+            .expect("code");
+        let buffer = TraceBuffer::new();
+        vm.set_trace_sink(Box::new(buffer.clone()), TraceOptions::new())
+            .expect("sink");
+        assert!(vm.execute(0x3000).is_err());
+        let records = buffer.records();
+        assert!(
+            matches!(
+                records.first(),
+                Some(TraceRecord::Fault { eip: 0x3000, error, code })
+                    if error.contains("access violation") && code.starts_with("0x00003000: mov eax")
+            ),
+            "{records:?}"
+        );
+    }
+
+    #[test]
+    fn exception_dispatch_is_recorded() {
+        let mut vm = create_test_vm();
+        vm.write_bytes(0x3000, &[0x90, 0xCC]).expect("code"); // nop; int3
+        let buffer = TraceBuffer::new();
+        vm.set_trace_sink(Box::new(buffer.clone()), TraceOptions::new())
+            .expect("sink");
+        assert!(vm.execute(0x3000).is_err());
+        let records = buffer.records();
+        assert!(
+            records.iter().any(|record| matches!(
+                record,
+                TraceRecord::Exception {
+                    code: STATUS_BREAKPOINT,
+                    address: 0x3001,
+                    ..
+                }
+            )),
+            "{records:?}"
+        );
+    }
+}
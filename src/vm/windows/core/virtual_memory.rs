@@ -0,0 +1,186 @@
+use crate::vm::*;
+
+// VirtualAlloc reservations use the 64 KiB allocation granularity of Windows.
+const ALLOCATION_GRANULARITY: u32 = 0x1_0000;
+const VIRTUAL_ALLOC_HINT: u32 = 0x0100_0000;
+
+const MEM_COMMIT: u32 = 0x1000;
+const MEM_RESERVE: u32 = 0x2000;
+const MEM_FREE: u32 = 0x1_0000;
+const MEM_PRIVATE: u32 = 0x2_0000;
+const MEM_IMAGE: u32 = 0x100_0000;
+
+/// One `MEMORY_BASIC_INFORMATION` record as produced by VirtualQuery.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct MemoryRegionInfo {
+    pub(crate) base_address: u32,
+    pub(crate) allocation_base: u32,
+    pub(crate) allocation_protect: u32,
+    pub(crate) region_size: u32,
+    pub(crate) state: u32,
+    pub(crate) protect: u32,
+    pub(crate) kind: u32,
+}
+
+impl Vm {
+    /// VirtualAlloc: reserve and/or commit at `addr`, or at a free range when `addr` is 0.
+    pub(crate) fn virtual_alloc(
+        &mut self,
+        addr: u32,
+        size: u32,
+        alloc_type: u32,
+        protect: u32,
+    ) -> Result<u32, VmError> {
+        let commit = alloc_type & MEM_COMMIT != 0;
+        let reserve = alloc_type & MEM_RESERVE != 0 || addr == 0;
+        if size == 0 || !(commit || reserve) || !is_valid_protection(protect) {
+            return Err(VmError::InvalidConfig("invalid VirtualAlloc parameters"));
+        }
+        if self.memory.is_empty() {
+            return Err(VmError::NoImage);
+        }
+        let end = addr as u64 + size as u64;
+        if end > 1u64 << 32 {
+            return Err(VmError::MemoryOutOfRange);
+        }
+        let start = addr & !(PAGE_SIZE - 1);
+        if let Some((base, region)) = self.virtual_region(start) {
+            // Committing pages inside an existing reservation.
+            if !commit || alloc_type & MEM_RESERVE != 0 || end > base as u64 + region.size as u64 {
+                return Err(VmError::MemoryOutOfRange);
+            }
+            self.memory
+                .commit(start, (end - start as u64) as u32, protect)
+                .map_err(|_| VmError::MemoryOutOfRange)?;
+            return Ok(start);
+        }
+        if !reserve {
+            return Err(VmError::MemoryOutOfRange);
+        }
+        let (base, span) = if addr == 0 {
+            let base = self
+                .memory
+                .find_free(VIRTUAL_ALLOC_HINT, size, ALLOCATION_GRANULARITY)
+                .or_else(|| {
+                    self.memory
+                        .find_free(ALLOCATION_GRANULARITY, size, ALLOCATION_GRANULARITY)
+                })
+                .ok_or(VmError::OutOfMemory)?;
+            (base, align_page(size))
+        } else {
+            let base = addr & !(ALLOCATION_GRANULARITY - 1);
+            let span = u32::try_from(end - base as u64).map_err(|_| VmError::MemoryOutOfRange)?;
+            (base, align_page(span))
+        };
+        if !self.memory.is_range_free(base, span) {
+            return Err(VmError::MemoryOutOfRange);
+        }
+        self.memory.reserve(base, span);
+        if commit {
+            let commit_start = if addr == 0 { base } else { start };
+            let commit_size = if addr == 0 {
+                size
+            } else {
+                (end - start as u64) as u32
+            };
+            self.memory
+                .commit(commit_start, commit_size, protect)
+                .map_err(|_| VmError::MemoryOutOfRange)?;
+        }
+        self.virtual_allocs.insert(
+            base,
+            VirtualRegion {
+                size: span,
+                protect,
+            },
+        );
+        Ok(base)
+    }
+
+    /// VirtualFree: release a whole reservation or decommit pages inside one.
+    pub(crate) fn virtual_free(&mut self, addr: u32, size: u32, release: bool) -> bool {
+        if release {
+            if size != 0 {
+                return false;
+            }
+            let Some(region) = self.virtual_allocs.remove(&addr) else {
+                return false;
+            };
+            self.memory.unmap(addr, region.size);
+            return true;
+        }
+        let Some((base, region)) = self.virtual_region(addr) else {
+            return false;
+        };
+        let start = addr & !(PAGE_SIZE - 1);
+        let region_end = base as u64 + region.size as u64;
+        let end = if size == 0 {
+            region_end
+        } else {
+            (addr as u64 + size as u64).min(region_end)
+        };
+        self.memory.decommit(start, (end - start as u64) as u32);
+        true
+    }
+
+    /// VirtualQuery: describe the run of pages around `addr` that share one state.
+    pub(crate) fn query_memory(&self, addr: u32) -> MemoryRegionInfo {
+        let page = addr & !(PAGE_SIZE - 1);
+        let Some(info) = self.memory.page_info(page) else {
+            let next = self.memory.next_used(page).unwrap_or(0);
+            let region_size = if next == 0 {
+                0u32.wrapping_sub(page)
+            } else {
+                next - page
+            };
+            return MemoryRegionInfo {
+                base_address: page,
+                allocation_base: 0,
+                allocation_protect: 0,
+                region_size,
+                state: MEM_FREE,
+                protect: PAGE_NOACCESS,
+                kind: 0,
+            };
+        };
+        let (allocation_base, allocation_size, allocation_protect, kind) =
+            if let Some(module) = self.modules.by_addr(page) {
+                (
+                    module.base(),
+                    module.size(),
+                    PAGE_EXECUTE_WRITECOPY,
+                    MEM_IMAGE,
+                )
+            } else if let Some((base, region)) = self.virtual_region(page) {
+                (base, region.size, region.protect, MEM_PRIVATE)
+            } else {
+                let base = self.memory.run_start(page);
+                (base, u32::MAX - base, PAGE_READWRITE, MEM_PRIVATE)
+            };
+        let limit = (allocation_base as u64 + allocation_size as u64 - page as u64)
+            .min(u32::MAX as u64) as u32;
+        MemoryRegionInfo {
+            base_address: page,
+            allocation_base,
+            allocation_protect,
+            region_size: self.memory.run_length(page, limit),
+            state: if info.committed {
+                MEM_COMMIT
+            } else {
+                MEM_RESERVE
+            },
+            protect: if info.committed { info.protect } else { 0 },
+            kind,
+        }
+    }
+
+    // Find the VirtualAlloc reservation containing `addr`.
+    fn virtual_region(&self, addr: u32) -> Option<(u32, VirtualRegion)> {
+        let (base, region) = self.virtual_allocs.range(..=addr).next_back()?;
+        (addr - base < region.size).then_some((*base, *region))
+    }
+}
+
+fn align_page(size: u32) -> u32 {
+    size.div_ceil(PAGE_SIZE).saturating_mul(PAGE_SIZE)
+}
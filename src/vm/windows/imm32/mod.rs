@@ -32,13 +32,12 @@ mod tests {
                 .bypass(bypass),
         )
         .expect("vm");
-        vm.memory = vec![0u8; 0x10000];
+        vm.memory.map(0x1000, 0x10000);
         vm.base = 0x1000;
         vm.stack_top = 0x1000 + 0x10000 - 4;
         vm.regs.esp = vm.stack_top;
         vm.heap_start = 0x2000;
         vm.heap_end = 0x8000;
-        vm.heap_cursor = vm.heap_start;
         vm
     }
 
@@ -5,6 +5,8 @@ use crate::vm::windows::macros::{read_str_len, read_wstr_len};
 use crate::vm::Vm;
 use crate::vm_args;
 
+const ERROR_ACCESS_DENIED: u32 = 5;
+
 pub fn register(vm: &mut Vm) {
     vm.register_import_stdcall(
         DLL_NAME,
@@ -82,19 +84,25 @@ fn read_console_w(vm: &mut Vm, stack_ptr: u32) -> u32 {
 
 fn read_file(vm: &mut Vm, stack_ptr: u32) -> u32 {
     let (handle, buffer, count, bytes_read, _) = vm_args!(vm, stack_ptr; u32, u32, usize, u32, u32);
-    if let Some(bytes) = vm.file_read(handle, count) {
-        if buffer != 0 {
-            let _ = vm.write_bytes(buffer, &bytes);
-        }
-        if bytes_read != 0 {
-            let _ = vm.write_u32(bytes_read, bytes.len() as u32);
-        }
-        return 1;
-    }
     if bytes_read != 0 {
         let _ = vm.write_u32(bytes_read, 0);
     }
-    1
+    match vm.file_read(handle, count) {
+        Some(Ok(bytes)) => {
+            if buffer != 0 {
+                let _ = vm.write_bytes(buffer, &bytes);
+            }
+            if bytes_read != 0 {
+                let _ = vm.write_u32(bytes_read, bytes.len() as u32);
+            }
+            1
+        }
+        Some(Err(_)) => {
+            vm.set_last_error(ERROR_ACCESS_DENIED);
+            0
+        }
+        None => 1,
+    }
 }
 
 fn write_console_w(vm: &mut Vm, stack_ptr: u32) -> u32 {
@@ -112,13 +120,24 @@ fn write_console_w(vm: &mut Vm, stack_ptr: u32) -> u32 {
 fn write_file(vm: &mut Vm, stack_ptr: u32) -> u32 {
     let (handle, buffer, count, written, _) = vm_args!(vm, stack_ptr; u32, u32, usize, u32, u32);
     if buffer != 0 && count > 0 {
-        let bytes = read_str_len(vm, buffer, count);
-        if let Some(wrote) = vm.file_write(handle, bytes.as_bytes()) {
-            if written != 0 {
-                let _ = vm.write_u32(written, wrote as u32);
+        let data = vm.read_bytes(buffer, count).unwrap_or_default();
+        match vm.file_write(handle, &data) {
+            Some(Ok(wrote)) => {
+                if written != 0 {
+                    let _ = vm.write_u32(written, wrote as u32);
+                }
+                return 1;
+            }
+            Some(Err(_)) => {
+                if written != 0 {
+                    let _ = vm.write_u32(written, 0);
+                }
+                vm.set_last_error(ERROR_ACCESS_DENIED);
+                return 0;
             }
-            return 1;
+            None => {}
         }
+        let bytes = read_str_len(vm, buffer, count);
         vm.write_stdout(&bytes);
         if written != 0 {
             let _ = vm.write_u32(written, count as u32);
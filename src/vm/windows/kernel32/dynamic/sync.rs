@@ -1,3 +1,6 @@
+use crate::vm::windows::kernel32::sync::{
+    create_event_ex_w, create_semaphore_ex_w, initialize_critical_section_ex,
+};
 use crate::vm::Vm;
 
 pub(super) fn register(vm: &mut Vm) {
@@ -23,18 +26,6 @@ pub(super) fn register(vm: &mut Vm) {
     );
 }
 
-fn initialize_critical_section_ex(_vm: &mut Vm, _stack_ptr: u32) -> u32 {
-    1
-}
-
-fn create_event_ex_w(_vm: &mut Vm, _stack_ptr: u32) -> u32 {
-    1
-}
-
-fn create_semaphore_ex_w(_vm: &mut Vm, _stack_ptr: u32) -> u32 {
-    1
-}
-
 fn set_thread_stack_guarantee(_vm: &mut Vm, _stack_ptr: u32) -> u32 {
     1
 }
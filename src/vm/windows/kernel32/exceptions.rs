@@ -1,17 +1,13 @@
-//! Kernel32 exception-related stubs.
+//! Kernel32 structured exception handling: RaiseException, RtlUnwind, vectored
+//! handlers and the unhandled-exception filter.
 
-use crate::define_stub_fn;
 use crate::vm::windows::kernel32::DLL_NAME;
-use crate::vm::Vm;
+use crate::vm::{
+    ExceptionRecord, Vm, EXCEPTION_CONTINUE_SEARCH, EXCEPTION_MAXIMUM_PARAMETERS,
+    EXCEPTION_NONCONTINUABLE,
+};
 use crate::vm_args;
 
-define_stub_fn!(DLL_NAME, raise_exception, 0);
-define_stub_fn!(DLL_NAME, rtl_unwind, 0);
-define_stub_fn!(DLL_NAME, add_vectored_exception_handler, 1);
-define_stub_fn!(DLL_NAME, remove_vectored_exception_handler, 1);
-define_stub_fn!(DLL_NAME, add_vectored_continue_handler, 1);
-define_stub_fn!(DLL_NAME, remove_vectored_continue_handler, 1);
-
 pub fn register(vm: &mut Vm) {
     vm.register_import_stdcall(
         DLL_NAME,
@@ -63,6 +59,54 @@ pub fn register(vm: &mut Vm) {
     );
 }
 
+fn raise_exception(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (code, flags, count, args_ptr) = vm_args!(vm, stack_ptr; u32, u32, u32, u32);
+    let count = (count as usize).min(EXCEPTION_MAXIMUM_PARAMETERS) as u32;
+    let params = if args_ptr == 0 {
+        Vec::new()
+    } else {
+        (0..count)
+            .map(|index| vm.read_u32(args_ptr + index * 4).unwrap_or(0))
+            .collect()
+    };
+    let address = vm.read_u32(stack_ptr).unwrap_or(0);
+    vm.raise_exception(ExceptionRecord {
+        flags: flags & EXCEPTION_NONCONTINUABLE,
+        params,
+        ..ExceptionRecord::new(code, address)
+    });
+    0
+}
+
+fn rtl_unwind(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (target_frame, _target_ip, record, return_value) =
+        vm_args!(vm, stack_ptr; u32, u32, u32, u32);
+    if let Err(err) = vm.unwind_frames(target_frame, record) {
+        vm.set_guest_fault(err);
+    }
+    return_value
+}
+
+fn add_vectored_exception_handler(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (first, handler) = vm_args!(vm, stack_ptr; u32, u32);
+    vm.add_vectored_handler(false, first != 0, handler)
+}
+
+fn remove_vectored_exception_handler(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (cookie,) = vm_args!(vm, stack_ptr; u32);
+    vm.remove_vectored_handler(false, cookie) as u32
+}
+
+fn add_vectored_continue_handler(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (first, handler) = vm_args!(vm, stack_ptr; u32, u32);
+    vm.add_vectored_handler(true, first != 0, handler)
+}
+
+fn remove_vectored_continue_handler(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (cookie,) = vm_args!(vm, stack_ptr; u32);
+    vm.remove_vectored_handler(true, cookie) as u32
+}
+
 fn set_unhandled_exception_filter(vm: &mut Vm, stack_ptr: u32) -> u32 {
     let (filter,) = vm_args!(vm, stack_ptr; u32);
     let previous = vm.unhandled_exception_filter();
@@ -70,12 +114,85 @@ fn set_unhandled_exception_filter(vm: &mut Vm, stack_ptr: u32) -> u32 {
     previous
 }
 
-fn unhandled_exception_filter(_vm: &mut Vm, _stack_ptr: u32) -> u32 {
+// Called directly by CRT code; runs the registered filter, if any.
+fn unhandled_exception_filter(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (pointers,) = vm_args!(vm, stack_ptr; u32);
     if std::env::var("PE_VM_TRACE_IMPORTS").is_ok() {
-        eprintln!(
-            "[pe_vm] UnhandledExceptionFilter at eip=0x{:08X}",
-            _vm.eip()
-        );
+        eprintln!("[pe_vm] UnhandledExceptionFilter at eip=0x{:08X}", vm.eip());
+    }
+    let filter = vm.unhandled_exception_filter();
+    if filter == 0 {
+        return EXCEPTION_CONTINUE_SEARCH;
+    }
+    match vm.call_guest(filter, None, &[pointers]) {
+        Ok(result) => result,
+        Err(err) => {
+            vm.set_guest_fault(err);
+            EXCEPTION_CONTINUE_SEARCH
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::vm::{Architecture, Vm, VmConfig};
+
+    fn create_test_vm() -> Vm {
+        let mut vm = Vm::new(VmConfig::new().architecture(Architecture::X86)).expect("vm");
+        vm.memory.map(0x1000, 0x10000);
+        vm.base = 0x1000;
+        vm.stack_top = 0x1000 + 0x10000 - 4;
+        vm.regs.esp = vm.stack_top;
+        vm.heap_start = 0x2000;
+        vm.heap_end = 0x8000;
+        vm.fs_base = 0x1000;
+        vm.write_u32(0x1000, 0xFFFF_FFFF).expect("teb");
+        vm
+    }
+
+    #[test]
+    fn frame_handler_resumes_after_breakpoint() {
+        let mut vm = create_test_vm();
+        let code = [
+            0x68, 0x00, 0x31, 0x00, 0x00, // push handler
+            0x64, 0xFF, 0x35, 0x00, 0x00, 0x00, 0x00, // push fs:[0]
+            0x64, 0x89, 0x25, 0x00, 0x00, 0x00, 0x00, // mov fs:[0], esp
+            0x31, 0xC0, // xor eax, eax
+            0xCC, // int3
+            0x64, 0x8F, 0x05, 0x00, 0x00, 0x00, 0x00, // pop fs:[0]
+            0x83, 0xC4, 0x04, // add esp, 4
+            0xC3, // ret
+        ];
+        // handler: skip the int3 and set Eax in the context record.
+        let handler = [
+            0x8B, 0x44, 0x24, 0x0C, // mov eax, [esp+0xC]
+            0xFF, 0x80, 0xB8, 0x00, 0x00, 0x00, // inc dword [eax+Eip]
+            0xC7, 0x80, 0xB0, 0x00, 0x00, 0x00, 0x55, 0x00, 0x00, 0x00, // mov [eax+Eax], 0x55
+            0x31, 0xC0, // xor eax, eax (ExceptionContinueExecution)
+            0xC3, // ret
+        ];
+        for (i, byte) in code.iter().enumerate() {
+            vm.write_u8(0x3000 + i as u32, *byte).unwrap();
+        }
+        for (i, byte) in handler.iter().enumerate() {
+            vm.write_u8(0x3100 + i as u32, *byte).unwrap();
+        }
+        vm.execute(0x3000).expect("execute");
+        assert_eq!(vm.regs.eax, 0x55);
+        assert_eq!(vm.read_u32(0x1000).unwrap(), 0xFFFF_FFFF);
+    }
+
+    #[test]
+    fn unhandled_breakpoint_is_reported() {
+        let mut vm = create_test_vm();
+        vm.write_u8(0x3000, 0xCC).unwrap();
+        let err = vm.execute(0x3000).unwrap_err();
+        assert!(matches!(
+            err,
+            crate::vm::VmError::Exception {
+                code: 0x8000_0003,
+                address: 0x3000
+            }
+        ));
     }
-    0
 }
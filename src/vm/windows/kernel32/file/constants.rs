@@ -1,9 +1,12 @@
 #![allow(dead_code)]
 
+pub(super) const FILE_ATTRIBUTE_READONLY: u32 = 0x01;
 pub(super) const FILE_ATTRIBUTE_NORMAL: u32 = 0x80;
 pub(super) const FILE_ATTRIBUTE_DIRECTORY: u32 = 0x10;
 pub(super) const FILE_TYPE_DISK: u32 = 1;
+pub(super) const DRIVE_NO_ROOT_DIR: u32 = 1;
 pub(super) const DRIVE_FIXED: u32 = 3;
+pub(super) const DRIVE_REMOTE: u32 = 4;
 pub(super) const INVALID_HANDLE_VALUE: u32 = 0xFFFF_FFFF;
 pub(super) const INVALID_FILE_ATTRIBUTES: u32 = 0xFFFF_FFFF;
 
@@ -18,5 +21,24 @@ pub(super) const TRUNCATE_EXISTING: u32 = 5;
 
 pub(super) const FILE_BEGIN: u32 = 0;
 
+pub(super) const MOVEFILE_REPLACE_EXISTING: u32 = 0x1;
+pub(super) const MOVEFILE_COPY_ALLOWED: u32 = 0x2;
+pub(super) const MOVEFILE_DELAY_UNTIL_REBOOT: u32 = 0x4;
+
+// Fixed capacity reported for every drive.
+pub(super) const DISK_TOTAL_BYTES: u64 = 64 << 30;
+pub(super) const DISK_FREE_BYTES: u64 = 32 << 30;
+pub(super) const DISK_BYTES_PER_SECTOR: u32 = 512;
+pub(super) const DISK_SECTORS_PER_CLUSTER: u32 = 8;
+
 pub(super) const ERROR_FILE_NOT_FOUND: u32 = 2;
+pub(super) const ERROR_PATH_NOT_FOUND: u32 = 3;
+pub(super) const ERROR_ACCESS_DENIED: u32 = 5;
 pub(super) const ERROR_INVALID_HANDLE: u32 = 6;
+pub(super) const ERROR_NOT_SAME_DEVICE: u32 = 17;
+pub(super) const ERROR_NO_MORE_FILES: u32 = 18;
+pub(super) const ERROR_FILE_EXISTS: u32 = 80;
+pub(super) const ERROR_INVALID_PARAMETER: u32 = 87;
+pub(super) const ERROR_DIR_NOT_EMPTY: u32 = 145;
+pub(super) const ERROR_ALREADY_EXISTS: u32 = 183;
+pub(super) const ERROR_DIRECTORY: u32 = 267;
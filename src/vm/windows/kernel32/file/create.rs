@@ -1,11 +1,16 @@
-use crate::vm::Vm;
+use std::io;
+
+use crate::vm::{FsOpenOptions, Vm};
 use crate::vm_args;
 
 use super::constants::{
-    CREATE_ALWAYS, CREATE_NEW, ERROR_FILE_NOT_FOUND, GENERIC_READ, GENERIC_WRITE,
-    INVALID_HANDLE_VALUE, OPEN_ALWAYS, TRUNCATE_EXISTING,
+    CREATE_ALWAYS, CREATE_NEW, ERROR_ALREADY_EXISTS, ERROR_DIRECTORY, ERROR_FILE_NOT_FOUND,
+    ERROR_INVALID_PARAMETER, ERROR_NOT_SAME_DEVICE, ERROR_PATH_NOT_FOUND, FILE_ATTRIBUTE_READONLY,
+    GENERIC_READ, GENERIC_WRITE, INVALID_FILE_ATTRIBUTES, INVALID_HANDLE_VALUE,
+    MOVEFILE_COPY_ALLOWED, MOVEFILE_DELAY_UNTIL_REBOOT, MOVEFILE_REPLACE_EXISTING, OPEN_ALWAYS,
+    TRUNCATE_EXISTING,
 };
-use super::helpers::read_w_string;
+use super::helpers::{io_error_code, path_error_code, read_w_string, vm_error_code};
 
 pub(super) fn register(vm: &mut Vm) {
     vm.register_import_stdcall(
@@ -14,12 +19,24 @@ pub(super) fn register(vm: &mut Vm) {
         crate::vm::stdcall_args(3),
         copy_file_a,
     );
+    vm.register_import_stdcall(
+        "KERNEL32.dll",
+        "CopyFileW",
+        crate::vm::stdcall_args(3),
+        copy_file_w,
+    );
     vm.register_import_stdcall(
         "KERNEL32.dll",
         "CreateDirectoryA",
         crate::vm::stdcall_args(2),
         create_directory_a,
     );
+    vm.register_import_stdcall(
+        "KERNEL32.dll",
+        "CreateDirectoryW",
+        crate::vm::stdcall_args(2),
+        create_directory_w,
+    );
     vm.register_import_stdcall(
         "KERNEL32.dll",
         "CreateFileA",
@@ -38,6 +55,42 @@ pub(super) fn register(vm: &mut Vm) {
         crate::vm::stdcall_args(1),
         delete_file_a,
     );
+    vm.register_import_stdcall(
+        "KERNEL32.dll",
+        "DeleteFileW",
+        crate::vm::stdcall_args(1),
+        delete_file_w,
+    );
+    vm.register_import_stdcall(
+        "KERNEL32.dll",
+        "MoveFileA",
+        crate::vm::stdcall_args(2),
+        move_file_a,
+    );
+    vm.register_import_stdcall(
+        "KERNEL32.dll",
+        "MoveFileW",
+        crate::vm::stdcall_args(2),
+        move_file_w,
+    );
+    vm.register_import_stdcall(
+        "KERNEL32.dll",
+        "MoveFileExA",
+        crate::vm::stdcall_args(3),
+        move_file_ex_a,
+    );
+    vm.register_import_stdcall(
+        "KERNEL32.dll",
+        "MoveFileExW",
+        crate::vm::stdcall_args(3),
+        move_file_ex_w,
+    );
+    vm.register_import_stdcall(
+        "KERNEL32.dll",
+        "RemoveDirectoryA",
+        crate::vm::stdcall_args(1),
+        remove_directory_a,
+    );
     vm.register_import_stdcall(
         "KERNEL32.dll",
         "RemoveDirectoryW",
@@ -50,20 +103,68 @@ pub(super) fn register(vm: &mut Vm) {
         crate::vm::stdcall_args(2),
         set_file_attributes_a,
     );
+    vm.register_import_stdcall(
+        "KERNEL32.dll",
+        "SetFileAttributesW",
+        crate::vm::stdcall_args(2),
+        set_file_attributes_w,
+    );
+}
+
+fn copy_file_a(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (from, to, fail_if_exists) = vm_args!(vm, stack_ptr; str, str, u32);
+    copy_file(vm, &from, &to, fail_if_exists != 0)
 }
 
-fn copy_file_a(_vm: &mut Vm, _stack_ptr: u32) -> u32 {
-    1
+fn copy_file_w(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (from, to, fail_if_exists) = vm_args!(vm, stack_ptr; wstr, wstr, u32);
+    copy_file(vm, &from, &to, fail_if_exists != 0)
+}
+
+fn copy_file(vm: &mut Vm, from: &str, to: &str, fail_if_exists: bool) -> u32 {
+    match vm.file_copy(from, to, !fail_if_exists) {
+        Ok(()) => 1,
+        Err(err) => {
+            let code = path_error_code(vm, from, io_error_code(&err));
+            vm.set_last_error(code);
+            0
+        }
+    }
 }
 
 fn create_directory_a(vm: &mut Vm, stack_ptr: u32) -> u32 {
     let (path_ptr,) = vm_args!(vm, stack_ptr; u32);
     if path_ptr == 0 {
+        vm.set_last_error(ERROR_PATH_NOT_FOUND);
         return 0;
     }
     let path = vm.read_c_string(path_ptr).unwrap_or_default();
-    let host_path = vm.map_path(&path);
-    std::fs::create_dir_all(host_path).map(|_| 1).unwrap_or(0)
+    create_directory(vm, &path)
+}
+
+fn create_directory_w(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (path_ptr,) = vm_args!(vm, stack_ptr; u32);
+    if path_ptr == 0 {
+        vm.set_last_error(ERROR_PATH_NOT_FOUND);
+        return 0;
+    }
+    let path = read_w_string(vm, path_ptr);
+    create_directory(vm, &path)
+}
+
+fn create_directory(vm: &mut Vm, path: &str) -> u32 {
+    if vm.file_exists(path) {
+        vm.set_last_error(ERROR_ALREADY_EXISTS);
+        return 0;
+    }
+    match vm.file_create_dir(path) {
+        Ok(()) => 1,
+        Err(err) => {
+            let code = path_error_code(vm, path, io_error_code(&err));
+            vm.set_last_error(code);
+            0
+        }
+    }
 }
 
 fn create_file_a(vm: &mut Vm, stack_ptr: u32) -> u32 {
@@ -74,22 +175,10 @@ fn create_file_a(vm: &mut Vm, stack_ptr: u32) -> u32 {
         return INVALID_HANDLE_VALUE;
     }
     let path = vm.read_c_string(path_ptr).unwrap_or_default();
-    let readable = desired & GENERIC_READ != 0;
-    let writable = desired & GENERIC_WRITE != 0;
-    let create = matches!(disposition, CREATE_NEW | CREATE_ALWAYS | OPEN_ALWAYS);
-    let truncate = matches!(disposition, CREATE_ALWAYS | TRUNCATE_EXISTING);
     if std::env::var("PE_VM_TRACE").is_ok() {
-        eprintln!(
-            "[pe_vm] CreateFileA: {path} read={readable} write={writable} create={create} truncate={truncate}"
-        );
-    }
-    match vm.file_open(&path, readable, writable, create, truncate) {
-        Ok(handle) => handle,
-        Err(_) => {
-            vm.set_last_error(ERROR_FILE_NOT_FOUND);
-            INVALID_HANDLE_VALUE
-        }
+        eprintln!("[pe_vm] CreateFileA: {path} access={desired:#x} disposition={disposition}");
     }
+    create_file(vm, &path, desired, disposition)
 }
 
 fn create_file_w(vm: &mut Vm, stack_ptr: u32) -> u32 {
@@ -100,19 +189,30 @@ fn create_file_w(vm: &mut Vm, stack_ptr: u32) -> u32 {
         return INVALID_HANDLE_VALUE;
     }
     let path = read_w_string(vm, path_ptr);
-    let readable = desired & GENERIC_READ != 0;
-    let writable = desired & GENERIC_WRITE != 0;
-    let create = matches!(disposition, CREATE_NEW | CREATE_ALWAYS | OPEN_ALWAYS);
-    let truncate = matches!(disposition, CREATE_ALWAYS | TRUNCATE_EXISTING);
     if std::env::var("PE_VM_TRACE").is_ok() {
-        eprintln!(
-            "[pe_vm] CreateFileW: {path} read={readable} write={writable} create={create} truncate={truncate}"
-        );
+        eprintln!("[pe_vm] CreateFileW: {path} access={desired:#x} disposition={disposition}");
     }
-    match vm.file_open(&path, readable, writable, create, truncate) {
-        Ok(handle) => handle,
-        Err(_) => {
-            vm.set_last_error(ERROR_FILE_NOT_FOUND);
+    create_file(vm, &path, desired, disposition)
+}
+
+fn create_file(vm: &mut Vm, path: &str, desired: u32, disposition: u32) -> u32 {
+    let options = FsOpenOptions::new()
+        .read(desired & GENERIC_READ != 0)
+        .write(desired & GENERIC_WRITE != 0)
+        .create(matches!(disposition, CREATE_ALWAYS | OPEN_ALWAYS))
+        .create_new(disposition == CREATE_NEW)
+        .truncate(matches!(disposition, CREATE_ALWAYS | TRUNCATE_EXISTING));
+    let existed = options.create_value() && vm.file_exists(path);
+    match vm.file_open(path, options) {
+        Ok(handle) => {
+            // CREATE_ALWAYS and OPEN_ALWAYS succeed either way and report which
+            // case applied through the last error.
+            vm.set_last_error(if existed { ERROR_ALREADY_EXISTS } else { 0 });
+            handle
+        }
+        Err(err) => {
+            let code = path_error_code(vm, path, vm_error_code(&err));
+            vm.set_last_error(code);
             INVALID_HANDLE_VALUE
         }
     }
@@ -121,22 +221,345 @@ fn create_file_w(vm: &mut Vm, stack_ptr: u32) -> u32 {
 fn delete_file_a(vm: &mut Vm, stack_ptr: u32) -> u32 {
     let (path_ptr,) = vm_args!(vm, stack_ptr; u32);
     if path_ptr == 0 {
+        vm.set_last_error(ERROR_FILE_NOT_FOUND);
         return 0;
     }
     let path = vm.read_c_string(path_ptr).unwrap_or_default();
-    let existed = vm.file_exists(&path);
-    vm.file_delete(&path);
-    if existed {
-        1
-    } else {
-        0
+    delete_file(vm, &path)
+}
+
+fn delete_file_w(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (path_ptr,) = vm_args!(vm, stack_ptr; u32);
+    if path_ptr == 0 {
+        vm.set_last_error(ERROR_FILE_NOT_FOUND);
+        return 0;
+    }
+    let path = read_w_string(vm, path_ptr);
+    delete_file(vm, &path)
+}
+
+fn delete_file(vm: &mut Vm, path: &str) -> u32 {
+    match vm.file_delete(path) {
+        Ok(()) => 1,
+        Err(err) => {
+            let code = path_error_code(vm, path, io_error_code(&err));
+            vm.set_last_error(code);
+            0
+        }
+    }
+}
+
+fn move_file_a(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (from, to) = vm_args!(vm, stack_ptr; str, str);
+    move_file(vm, &from, Some(&to), MOVEFILE_COPY_ALLOWED)
+}
+
+fn move_file_w(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (from, to) = vm_args!(vm, stack_ptr; wstr, wstr);
+    move_file(vm, &from, Some(&to), MOVEFILE_COPY_ALLOWED)
+}
+
+fn move_file_ex_a(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (from, to_ptr, flags) = vm_args!(vm, stack_ptr; str, u32, u32);
+    let to = (to_ptr != 0).then(|| vm.read_c_string(to_ptr).unwrap_or_default());
+    move_file(vm, &from, to.as_deref(), flags)
+}
+
+fn move_file_ex_w(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (from, to_ptr, flags) = vm_args!(vm, stack_ptr; wstr, u32, u32);
+    let to = (to_ptr != 0).then(|| read_w_string(vm, to_ptr));
+    move_file(vm, &from, to.as_deref(), flags)
+}
+
+fn move_file(vm: &mut Vm, from: &str, to: Option<&str>, flags: u32) -> u32 {
+    // The guest never reboots, so scheduled moves and deletes never happen.
+    if flags & MOVEFILE_DELAY_UNTIL_REBOOT != 0 {
+        return 1;
+    }
+    let Some(to) = to else {
+        vm.set_last_error(ERROR_INVALID_PARAMETER);
+        return 0;
+    };
+    if flags & MOVEFILE_COPY_ALLOWED == 0
+        && volume(&vm.full_path(from)) != volume(&vm.full_path(to))
+    {
+        vm.set_last_error(ERROR_NOT_SAME_DEVICE);
+        return 0;
     }
+    match vm.file_move(from, to, flags & MOVEFILE_REPLACE_EXISTING != 0) {
+        Ok(()) => 1,
+        Err(err) => {
+            let code = match err.kind() {
+                io::ErrorKind::AlreadyExists => ERROR_ALREADY_EXISTS,
+                _ => path_error_code(vm, from, io_error_code(&err)),
+            };
+            vm.set_last_error(code);
+            0
+        }
+    }
+}
+
+// The drive letter or `\\server\share` a full path lives on.
+fn volume(path: &str) -> String {
+    match path.strip_prefix("\\\\") {
+        Some(rest) => rest.split('\\').take(2).collect::<Vec<_>>().join("\\"),
+        None => path.get(..2).unwrap_or_default().to_string(),
+    }
+    .to_ascii_uppercase()
+}
+
+fn remove_directory_a(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (path,) = vm_args!(vm, stack_ptr; str);
+    remove_directory(vm, &path)
+}
+
+fn remove_directory_w(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (path,) = vm_args!(vm, stack_ptr; wstr);
+    remove_directory(vm, &path)
 }
 
-fn remove_directory_w(_vm: &mut Vm, _stack_ptr: u32) -> u32 {
-    1
+fn remove_directory(vm: &mut Vm, path: &str) -> u32 {
+    match vm.file_remove_dir(path) {
+        Ok(()) => 1,
+        Err(err) => {
+            let code = match err.kind() {
+                io::ErrorKind::NotADirectory => ERROR_DIRECTORY,
+                _ => path_error_code(vm, path, io_error_code(&err)),
+            };
+            vm.set_last_error(code);
+            0
+        }
+    }
+}
+
+fn set_file_attributes_a(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (path, attributes) = vm_args!(vm, stack_ptr; str, u32);
+    set_file_attributes(vm, &path, attributes)
+}
+
+fn set_file_attributes_w(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (path, attributes) = vm_args!(vm, stack_ptr; wstr, u32);
+    set_file_attributes(vm, &path, attributes)
+}
+
+// Only the read-only bit is stored; hidden, system and archive are accepted and
+// dropped.
+fn set_file_attributes(vm: &mut Vm, path: &str, attributes: u32) -> u32 {
+    if attributes == INVALID_FILE_ATTRIBUTES {
+        vm.set_last_error(ERROR_INVALID_PARAMETER);
+        return 0;
+    }
+    let read_only = attributes & FILE_ATTRIBUTE_READONLY != 0;
+    let result = vm
+        .file_metadata(path)
+        .and_then(|_| vm.file_set_read_only(path, read_only));
+    match result {
+        Ok(()) => 1,
+        Err(err) if err.kind() == io::ErrorKind::Unsupported => 1,
+        Err(err) => {
+            let code = path_error_code(vm, path, io_error_code(&err));
+            vm.set_last_error(code);
+            0
+        }
+    }
 }
 
-fn set_file_attributes_a(_vm: &mut Vm, _stack_ptr: u32) -> u32 {
-    1
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::{
+        Architecture, GuestFs, MemoryFs, PathAccess, PathMapping, SandboxConfig, UnmappedPaths,
+        VmConfig,
+    };
+    use crate::vm_set_args;
+
+    use super::super::constants::{
+        ERROR_ACCESS_DENIED, ERROR_DIR_NOT_EMPTY, ERROR_FILE_EXISTS, OPEN_EXISTING,
+    };
+
+    fn create_test_vm(sandbox: SandboxConfig, paths: PathMapping) -> Vm {
+        let config = VmConfig::new()
+            .architecture(Architecture::X86)
+            .paths(paths)
+            .sandbox(sandbox);
+        let mut vm = Vm::new(config).expect("vm");
+        vm.memory.map(0x1000, 0x10000);
+        vm.base = 0x1000;
+        vm.stack_top = 0x1000 + 0x10000 - 4;
+        vm.regs.esp = vm.stack_top;
+        vm
+    }
+
+    fn create(vm: &mut Vm, path: &str, desired: u32, disposition: u32) -> u32 {
+        vm.write_bytes(0x2000, path.as_bytes()).expect("path");
+        vm.write_u8(0x2000 + path.len() as u32, 0).expect("nul");
+        let stack = vm.stack_top - 32;
+        vm_set_args!(vm, stack; 0x2000u32, desired, 0u32, 0u32, disposition, 0u32, 0u32);
+        create_file_a(vm, stack)
+    }
+
+    #[test]
+    fn sandbox_denies_unmapped_and_escaping_paths() {
+        let dir = std::env::temp_dir().join(format!("pe_vm_sandbox_{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("app")).expect("dir");
+        std::fs::write(dir.join("secret.txt"), b"host").expect("seed");
+        let mut paths = PathMapping::new();
+        paths.insert(
+            "C:\\app".to_string(),
+            dir.join("app").to_string_lossy().into_owned(),
+        );
+        let sandbox = SandboxConfig::new().unmapped_paths(UnmappedPaths::Deny);
+        let mut vm = create_test_vm(sandbox, paths);
+
+        let secret = dir.join("secret.txt").to_string_lossy().into_owned();
+        let result = create(&mut vm, &secret, GENERIC_READ, OPEN_EXISTING);
+        assert_eq!(result, INVALID_HANDLE_VALUE);
+        assert_eq!(vm.last_error(), ERROR_PATH_NOT_FOUND);
+
+        let result = create(
+            &mut vm,
+            "C:\\app\\..\\secret.txt",
+            GENERIC_READ,
+            OPEN_EXISTING,
+        );
+        assert_eq!(result, INVALID_HANDLE_VALUE);
+        assert_eq!(vm.last_error(), ERROR_ACCESS_DENIED);
+
+        let result = create(&mut vm, "C:\\app\\.\\new.txt", GENERIC_WRITE, CREATE_NEW);
+        assert_ne!(result, INVALID_HANDLE_VALUE);
+        assert!(dir.join("app").join("new.txt").exists());
+
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn sandbox_read_only_mapping_rejects_writes() {
+        let fs = MemoryFs::new();
+        fs.insert("cfg.ini", b"[app]".to_vec());
+        let sandbox = SandboxConfig::new().path_access("C:\\ro", PathAccess::ReadOnly);
+        let config = VmConfig::new()
+            .architecture(Architecture::X86)
+            .mount("C:\\ro", fs.clone())
+            .sandbox(sandbox);
+        let mut vm = Vm::new(config).expect("vm");
+        vm.memory.map(0x1000, 0x10000);
+        vm.stack_top = 0x1000 + 0x10000 - 4;
+
+        let result = create(&mut vm, "C:\\ro\\cfg.ini", GENERIC_READ, OPEN_EXISTING);
+        assert_ne!(result, INVALID_HANDLE_VALUE);
+        let result = create(&mut vm, "C:\\ro\\cfg.ini", GENERIC_WRITE, OPEN_EXISTING);
+        assert_eq!(result, INVALID_HANDLE_VALUE);
+        assert_eq!(vm.last_error(), ERROR_ACCESS_DENIED);
+
+        vm.write_bytes(0x2000, b"C:\\ro\\cfg.ini\0").expect("path");
+        let stack = vm.stack_top - 32;
+        vm_set_args!(vm, stack; 0x2000u32);
+        assert_eq!(delete_file_a(&mut vm, stack), 0);
+        assert_eq!(vm.last_error(), ERROR_ACCESS_DENIED);
+        assert_eq!(fs.get("cfg.ini").as_deref(), Some(&b"[app]"[..]));
+    }
+
+    #[test]
+    fn sandbox_redirects_unmapped_paths() {
+        let dir = std::env::temp_dir().join(format!("pe_vm_redirect_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("C").join("Temp")).expect("dir");
+        let redirect = UnmappedPaths::Redirect(dir.to_string_lossy().into_owned());
+        let sandbox = SandboxConfig::new().unmapped_paths(redirect);
+        let mut vm = create_test_vm(sandbox, PathMapping::new());
+
+        let result = create(&mut vm, "C:\\Temp\\out.log", GENERIC_WRITE, CREATE_ALWAYS);
+        assert_ne!(result, INVALID_HANDLE_VALUE);
+        assert!(dir.join("C").join("Temp").join("out.log").exists());
+
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    // Call a two-path API with both strings written into guest memory.
+    fn call_paths(
+        vm: &mut Vm,
+        func: fn(&mut Vm, u32) -> u32,
+        from: &str,
+        to: &str,
+        flags: u32,
+    ) -> u32 {
+        vm.write_bytes(0x2000, format!("{from}\0").as_bytes())
+            .expect("from");
+        vm.write_bytes(0x2200, format!("{to}\0").as_bytes())
+            .expect("to");
+        let stack = vm.stack_top - 32;
+        vm_set_args!(vm, stack; 0x2000u32, 0x2200u32, flags);
+        func(vm, stack)
+    }
+
+    #[test]
+    fn copy_move_and_remove_directory() {
+        let apps = MemoryFs::new();
+        apps.insert("app/cfg.ini", b"[app]".to_vec());
+        let other = MemoryFs::new();
+        let config = VmConfig::new()
+            .architecture(Architecture::X86)
+            .mount("C:\\", apps.clone())
+            .mount("D:\\", other.clone());
+        let mut vm = Vm::new(config).expect("vm");
+        vm.memory.map(0x1000, 0x10000);
+        vm.stack_top = 0x1000 + 0x10000 - 4;
+
+        let copied = call_paths(
+            &mut vm,
+            copy_file_a,
+            "C:\\app\\cfg.ini",
+            "C:\\app\\cfg.bak",
+            1,
+        );
+        assert_eq!(copied, 1);
+        let copied = call_paths(
+            &mut vm,
+            copy_file_a,
+            "C:\\app\\cfg.ini",
+            "C:\\app\\cfg.bak",
+            1,
+        );
+        assert_eq!(copied, 0);
+        assert_eq!(vm.last_error(), ERROR_FILE_EXISTS);
+
+        let moved = call_paths(
+            &mut vm,
+            move_file_ex_a,
+            "C:\\app\\cfg.bak",
+            "D:\\cfg.bak",
+            0,
+        );
+        assert_eq!(moved, 0);
+        assert_eq!(vm.last_error(), ERROR_NOT_SAME_DEVICE);
+        let flags = MOVEFILE_COPY_ALLOWED;
+        let moved = call_paths(
+            &mut vm,
+            move_file_ex_a,
+            "C:\\app\\cfg.bak",
+            "D:\\cfg.bak",
+            flags,
+        );
+        assert_eq!(moved, 1);
+        assert_eq!(other.get("cfg.bak").as_deref(), Some(&b"[app]"[..]));
+        assert!(apps.get("app/cfg.bak").is_none());
+
+        let moved = call_paths(&mut vm, move_file_ex_a, "C:\\app", "C:\\renamed", 0);
+        assert_eq!(moved, 1);
+        assert_eq!(apps.files(), ["renamed/cfg.ini"]);
+
+        vm.write_bytes(0x2000, b"C:\\renamed\0").expect("path");
+        let stack = vm.stack_top - 32;
+        vm_set_args!(vm, stack; 0x2000u32);
+        assert_eq!(remove_directory_a(&mut vm, stack), 0);
+        assert_eq!(vm.last_error(), ERROR_DIR_NOT_EMPTY);
+        vm.write_bytes(0x2000, b"C:\\renamed\\cfg.ini\0")
+            .expect("path");
+        assert_eq!(remove_directory_a(&mut vm, stack), 0);
+        assert_eq!(vm.last_error(), ERROR_DIRECTORY);
+        assert_eq!(delete_file_a(&mut vm, stack), 1);
+        vm.write_bytes(0x2000, b"C:\\renamed\0").expect("path");
+        assert_eq!(remove_directory_a(&mut vm, stack), 1);
+        assert!(apps.metadata("renamed").is_err());
+    }
 }
@@ -1,6 +1,14 @@
-use crate::vm::Vm;
+use crate::vm::{FindEntry, Vm};
 
-use super::constants::INVALID_HANDLE_VALUE;
+use super::constants::{ERROR_INVALID_HANDLE, ERROR_NO_MORE_FILES, INVALID_HANDLE_VALUE};
+use super::helpers::{file_attributes, io_error_code, write_file_times};
+
+// WIN32_FIND_DATA: attributes, three FILETIMEs, size, two reserved dwords, then
+// the long (MAX_PATH) and 8.3 (14) names in A or W characters.
+const FIND_DATA_NAME: u32 = 44;
+const MAX_PATH: usize = 260;
+const ALTERNATE_NAME_LEN: usize = 14;
+const FIND_EX_INFO_BASIC: u32 = 1;
 
 pub(super) fn register(vm: &mut Vm) {
     vm.register_import_stdcall(
@@ -15,22 +23,183 @@ pub(super) fn register(vm: &mut Vm) {
         crate::vm::stdcall_args(2),
         find_first_file_a,
     );
+    vm.register_import_stdcall(
+        "KERNEL32.dll",
+        "FindFirstFileW",
+        crate::vm::stdcall_args(2),
+        find_first_file_w,
+    );
+    vm.register_import_stdcall(
+        "KERNEL32.dll",
+        "FindFirstFileExA",
+        crate::vm::stdcall_args(6),
+        find_first_file_ex_a,
+    );
+    vm.register_import_stdcall(
+        "KERNEL32.dll",
+        "FindFirstFileExW",
+        crate::vm::stdcall_args(6),
+        find_first_file_ex_w,
+    );
     vm.register_import_stdcall(
         "KERNEL32.dll",
         "FindNextFileA",
         crate::vm::stdcall_args(2),
         find_next_file_a,
     );
+    vm.register_import_stdcall(
+        "KERNEL32.dll",
+        "FindNextFileW",
+        crate::vm::stdcall_args(2),
+        find_next_file_w,
+    );
+}
+
+fn find_close(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (handle,) = vm_args!(vm, stack_ptr; u32);
+    if vm.find_close(handle) {
+        1
+    } else {
+        vm.set_last_error(ERROR_INVALID_HANDLE);
+        0
+    }
+}
+
+fn find_first_file_a(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (pattern, data) = vm_args!(vm, stack_ptr; str, u32);
+    find_first(vm, &pattern, data, false, false)
 }
 
-fn find_close(_vm: &mut Vm, _stack_ptr: u32) -> u32 {
-    1
+fn find_first_file_w(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (pattern, data) = vm_args!(vm, stack_ptr; wstr, u32);
+    find_first(vm, &pattern, data, true, false)
 }
 
-fn find_first_file_a(_vm: &mut Vm, _stack_ptr: u32) -> u32 {
-    INVALID_HANDLE_VALUE
+// Search filters are only hints (FindExSearchLimitToDirectories may still return
+// files), so the full listing answers all of them.
+fn find_first_file_ex_a(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (pattern, level, data) = vm_args!(vm, stack_ptr; str, u32, u32);
+    find_first(vm, &pattern, data, false, level == FIND_EX_INFO_BASIC)
 }
 
-fn find_next_file_a(_vm: &mut Vm, _stack_ptr: u32) -> u32 {
-    0
+fn find_first_file_ex_w(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (pattern, level, data) = vm_args!(vm, stack_ptr; wstr, u32, u32);
+    find_first(vm, &pattern, data, true, level == FIND_EX_INFO_BASIC)
+}
+
+// FindExInfoBasic searches leave the 8.3 name empty.
+fn find_first(vm: &mut Vm, pattern: &str, data: u32, wide: bool, basic: bool) -> u32 {
+    match vm.find_first(pattern, !basic) {
+        Ok((handle, entry)) => {
+            write_find_data(vm, data, &entry, wide);
+            handle
+        }
+        Err(err) => {
+            vm.set_last_error(io_error_code(&err));
+            INVALID_HANDLE_VALUE
+        }
+    }
+}
+
+fn find_next_file_a(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (handle, data) = vm_args!(vm, stack_ptr; u32, u32);
+    find_next(vm, handle, data, false)
+}
+
+fn find_next_file_w(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (handle, data) = vm_args!(vm, stack_ptr; u32, u32);
+    find_next(vm, handle, data, true)
+}
+
+fn find_next(vm: &mut Vm, handle: u32, data: u32, wide: bool) -> u32 {
+    match vm.find_next(handle) {
+        Some(Some(entry)) => {
+            write_find_data(vm, data, &entry, wide);
+            1
+        }
+        Some(None) => {
+            vm.set_last_error(ERROR_NO_MORE_FILES);
+            0
+        }
+        None => {
+            vm.set_last_error(ERROR_INVALID_HANDLE);
+            0
+        }
+    }
+}
+
+fn write_find_data(vm: &mut Vm, ptr: u32, entry: &FindEntry, wide: bool) {
+    if ptr == 0 {
+        return;
+    }
+    let char_size = if wide { 2 } else { 1 };
+    let total = FIND_DATA_NAME as usize + (MAX_PATH + ALTERNATE_NAME_LEN) * char_size;
+    let _ = vm.write_bytes(ptr, &vec![0; total]);
+    let metadata = &entry.metadata;
+    let _ = vm.write_u32(ptr, file_attributes(metadata));
+    write_file_times(vm, ptr + 4, metadata);
+    let _ = vm.write_u32(ptr + 28, (metadata.size() >> 32) as u32);
+    let _ = vm.write_u32(ptr + 32, metadata.size() as u32);
+    let name = ptr + FIND_DATA_NAME;
+    let alternate = name + (MAX_PATH * char_size) as u32;
+    write_name(vm, name, &entry.name, MAX_PATH, wide);
+    write_name(vm, alternate, &entry.short_name, ALTERNATE_NAME_LEN, wide);
+}
+
+// Copy a name into a fixed, already zeroed field, leaving room for the NUL.
+fn write_name(vm: &mut Vm, ptr: u32, name: &str, capacity: usize, wide: bool) {
+    if wide {
+        for (index, unit) in name.encode_utf16().take(capacity - 1).enumerate() {
+            let _ = vm.write_u16(ptr + index as u32 * 2, unit);
+        }
+    } else {
+        let bytes = name.as_bytes();
+        let _ = vm.write_bytes(ptr, &bytes[..bytes.len().min(capacity - 1)]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::{Architecture, MemoryFs, VmConfig};
+
+    #[test]
+    fn find_data_w_carries_names_size_and_attributes() {
+        let fs = MemoryFs::new();
+        fs.insert("Plugin Host.dll", vec![0; 3]);
+        let config = VmConfig::new()
+            .architecture(Architecture::X86)
+            .mount("C:\\plugins", fs);
+        let mut vm = Vm::new(config).expect("vm");
+        vm.memory.map(0x1000, 0x10000);
+        vm.stack_top = 0x1000 + 0x10000 - 4;
+
+        for (index, unit) in "C:\\plugins\\*.dll\0".encode_utf16().enumerate() {
+            vm.write_u16(0x2000 + index as u32 * 2, unit)
+                .expect("pattern");
+        }
+        let stack = vm.stack_top - 32;
+        vm_set_args!(vm, stack; 0x2000u32, 0x3000u32);
+        let handle = find_first_file_w(&mut vm, stack);
+        assert_ne!(handle, INVALID_HANDLE_VALUE);
+
+        assert_eq!(vm.read_u32(0x3000).expect("attributes"), 0x80);
+        assert_eq!(vm.read_u32(0x3000 + 32).expect("size"), 3);
+        let read_name = |vm: &Vm, ptr: u32| {
+            let units: Vec<u16> = (0..)
+                .map(|index| vm.read_u16(ptr + index * 2).expect("name"))
+                .take_while(|unit| *unit != 0)
+                .collect();
+            String::from_utf16_lossy(&units)
+        };
+        assert_eq!(read_name(&vm, 0x3000 + 44), "Plugin Host.dll");
+        assert_eq!(read_name(&vm, 0x3000 + 44 + 520), "PLUGIN~1.DLL");
+
+        vm_set_args!(vm, stack; handle, 0x3000u32);
+        assert_eq!(find_next_file_w(&mut vm, stack), 0);
+        assert_eq!(vm.last_error(), ERROR_NO_MORE_FILES);
+        vm_set_args!(vm, stack; handle);
+        assert_eq!(find_close(&mut vm, stack), 1);
+        assert_eq!(find_close(&mut vm, stack), 0);
+    }
 }
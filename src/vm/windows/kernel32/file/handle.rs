@@ -12,7 +12,7 @@ pub(super) fn register(vm: &mut Vm) {
 
 fn close_handle(vm: &mut Vm, stack_ptr: u32) -> u32 {
     let (handle,) = vm_args!(vm, stack_ptr; u32);
-    if handle != 0 {
+    if handle != 0 && !vm.close_sync_handle(handle) {
         vm.file_close(handle);
     }
     1
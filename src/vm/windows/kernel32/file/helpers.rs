@@ -1,4 +1,16 @@
-use crate::vm::Vm;
+use std::io;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::vm::{FsMetadata, Vm, VmError};
+
+use super::constants::{
+    ERROR_ACCESS_DENIED, ERROR_DIR_NOT_EMPTY, ERROR_FILE_EXISTS, ERROR_FILE_NOT_FOUND,
+    ERROR_NOT_SAME_DEVICE, ERROR_PATH_NOT_FOUND, FILE_ATTRIBUTE_DIRECTORY, FILE_ATTRIBUTE_NORMAL,
+    FILE_ATTRIBUTE_READONLY,
+};
+
+// FILETIME ticks (100ns) between 1601-01-01 and the Unix epoch.
+const UNIX_EPOCH_TICKS: u64 = 116_444_736_000_000_000;
 
 pub(super) fn read_w_string(vm: &Vm, ptr: u32) -> String {
     let mut units = Vec::new();
@@ -13,3 +25,124 @@ pub(super) fn read_w_string(vm: &Vm, ptr: u32) -> String {
     }
     String::from_utf16_lossy(&units)
 }
+
+/// Copy a path into an ANSI buffer of `size` chars. Returns the length written,
+/// or the size needed including the NUL when the buffer is too small.
+pub(super) fn write_path_a(vm: &mut Vm, buffer: u32, size: u32, path: &str) -> u32 {
+    let bytes = path.as_bytes();
+    if buffer == 0 || bytes.len() >= size as usize {
+        return bytes.len() as u32 + 1;
+    }
+    let _ = vm.write_bytes(buffer, bytes);
+    let _ = vm.write_u8(buffer + bytes.len() as u32, 0);
+    bytes.len() as u32
+}
+
+/// Wide-char counterpart of `write_path_a`; `size` counts UTF-16 units.
+pub(super) fn write_path_w(vm: &mut Vm, buffer: u32, size: u32, path: &str) -> u32 {
+    let units: Vec<u16> = path.encode_utf16().collect();
+    if buffer == 0 || units.len() >= size as usize {
+        return units.len() as u32 + 1;
+    }
+    for (i, unit) in units.iter().enumerate() {
+        let _ = vm.write_u16(buffer + (i as u32) * 2, *unit);
+    }
+    let _ = vm.write_u16(buffer + (units.len() as u32) * 2, 0);
+    units.len() as u32
+}
+
+pub(super) fn io_error_code(err: &io::Error) -> u32 {
+    match err.kind() {
+        io::ErrorKind::NotFound => ERROR_FILE_NOT_FOUND,
+        io::ErrorKind::NotADirectory => ERROR_PATH_NOT_FOUND,
+        io::ErrorKind::PermissionDenied
+        | io::ErrorKind::ReadOnlyFilesystem
+        | io::ErrorKind::IsADirectory => ERROR_ACCESS_DENIED,
+        io::ErrorKind::AlreadyExists => ERROR_FILE_EXISTS,
+        io::ErrorKind::DirectoryNotEmpty => ERROR_DIR_NOT_EMPTY,
+        io::ErrorKind::CrossesDevices => ERROR_NOT_SAME_DEVICE,
+        _ => ERROR_ACCESS_DENIED,
+    }
+}
+
+pub(super) fn vm_error_code(err: &VmError) -> u32 {
+    match err {
+        VmError::Io(err) => io_error_code(err),
+        _ => ERROR_FILE_NOT_FOUND,
+    }
+}
+
+// Win32 tells a missing file apart from a missing (or sandboxed-away) directory.
+pub(super) fn path_error_code(vm: &Vm, path: &str, code: u32) -> u32 {
+    let parent = path.rsplit_once(['\\', '/']).map(|(parent, _)| parent);
+    if code == ERROR_FILE_NOT_FOUND && parent.is_some_and(|dir| !vm.file_exists(dir)) {
+        ERROR_PATH_NOT_FOUND
+    } else {
+        code
+    }
+}
+
+pub(super) fn file_attributes(metadata: &FsMetadata) -> u32 {
+    let mut attributes = if metadata.is_dir() {
+        FILE_ATTRIBUTE_DIRECTORY
+    } else {
+        0
+    };
+    if metadata.read_only_value() {
+        attributes |= FILE_ATTRIBUTE_READONLY;
+    }
+    // NORMAL is only valid on its own.
+    if attributes == 0 {
+        attributes = FILE_ATTRIBUTE_NORMAL;
+    }
+    attributes
+}
+
+pub(super) fn filetime_from_system(time: SystemTime) -> u64 {
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(since) => UNIX_EPOCH_TICKS.saturating_add((since.as_nanos() / 100) as u64),
+        Err(before) => UNIX_EPOCH_TICKS.saturating_sub((before.duration().as_nanos() / 100) as u64),
+    }
+}
+
+pub(super) fn system_from_filetime(ticks: u64) -> SystemTime {
+    let nanos = |ticks: u64| Duration::from_nanos(ticks.saturating_mul(100));
+    if ticks >= UNIX_EPOCH_TICKS {
+        UNIX_EPOCH + nanos(ticks - UNIX_EPOCH_TICKS)
+    } else {
+        UNIX_EPOCH - nanos(UNIX_EPOCH_TICKS - ticks)
+    }
+}
+
+/// Creation, last-access and last-write FILETIMEs. Times a backend does not
+/// track fall back to the last-write time.
+pub(super) fn file_times(metadata: &FsMetadata) -> [u64; 3] {
+    let times = metadata.times_value();
+    let modified = times.modified_value().map_or(0, filetime_from_system);
+    let created = times.created_value().map_or(modified, filetime_from_system);
+    let accessed = times
+        .accessed_value()
+        .map_or(modified, filetime_from_system);
+    [created, accessed, modified]
+}
+
+pub(super) fn write_filetime(vm: &mut Vm, ptr: u32, ticks: u64) {
+    let _ = vm.write_u32(ptr, ticks as u32);
+    let _ = vm.write_u32(ptr + 4, (ticks >> 32) as u32);
+}
+
+/// Write the three `file_times` back to back, as both `WIN32_FIND_DATA` and
+/// `WIN32_FILE_ATTRIBUTE_DATA` lay them out.
+pub(super) fn write_file_times(vm: &mut Vm, ptr: u32, metadata: &FsMetadata) {
+    for (index, ticks) in file_times(metadata).into_iter().enumerate() {
+        write_filetime(vm, ptr + index as u32 * 8, ticks);
+    }
+}
+
+/// Write a `WIN32_FILE_ATTRIBUTE_DATA`.
+pub(super) fn write_attribute_data(vm: &mut Vm, ptr: u32, metadata: &FsMetadata) {
+    let _ = vm.write_u32(ptr, file_attributes(metadata));
+    write_file_times(vm, ptr + 4, metadata);
+    let _ = vm.write_u32(ptr + 28, (metadata.size() >> 32) as u32);
+    let _ = vm.write_u32(ptr + 32, metadata.size() as u32);
+}
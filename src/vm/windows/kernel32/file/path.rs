@@ -1,7 +1,8 @@
 use crate::vm::Vm;
 use crate::vm_args;
 
-use super::constants::DRIVE_FIXED;
+use super::constants::{DRIVE_FIXED, DRIVE_NO_ROOT_DIR, DRIVE_REMOTE};
+use super::helpers::{io_error_code, write_path_a, write_path_w};
 
 pub(super) fn register(vm: &mut Vm) {
     vm.register_import_stdcall(
@@ -34,22 +35,213 @@ pub(super) fn register(vm: &mut Vm) {
         crate::vm::stdcall_args(6),
         search_path_a,
     );
+    vm.register_import_stdcall(
+        "KERNEL32.dll",
+        "GetCurrentDirectoryA",
+        crate::vm::stdcall_args(2),
+        get_current_directory_a,
+    );
+    vm.register_import_stdcall(
+        "KERNEL32.dll",
+        "GetCurrentDirectoryW",
+        crate::vm::stdcall_args(2),
+        get_current_directory_w,
+    );
+    vm.register_import_stdcall(
+        "KERNEL32.dll",
+        "SetCurrentDirectoryA",
+        crate::vm::stdcall_args(1),
+        set_current_directory_a,
+    );
+    vm.register_import_stdcall(
+        "KERNEL32.dll",
+        "SetCurrentDirectoryW",
+        crate::vm::stdcall_args(1),
+        set_current_directory_w,
+    );
+    vm.register_import_stdcall(
+        "KERNEL32.dll",
+        "GetFullPathNameA",
+        crate::vm::stdcall_args(4),
+        get_full_path_name_a,
+    );
+    vm.register_import_stdcall(
+        "KERNEL32.dll",
+        "GetFullPathNameW",
+        crate::vm::stdcall_args(4),
+        get_full_path_name_w,
+    );
+    vm.register_import_stdcall(
+        "KERNEL32.dll",
+        "GetShortPathNameA",
+        crate::vm::stdcall_args(3),
+        get_short_path_name_a,
+    );
+    vm.register_import_stdcall(
+        "KERNEL32.dll",
+        "GetShortPathNameW",
+        crate::vm::stdcall_args(3),
+        get_short_path_name_w,
+    );
+    vm.register_import_stdcall(
+        "KERNEL32.dll",
+        "GetLongPathNameA",
+        crate::vm::stdcall_args(3),
+        get_long_path_name_a,
+    );
+    vm.register_import_stdcall(
+        "KERNEL32.dll",
+        "GetLongPathNameW",
+        crate::vm::stdcall_args(3),
+        get_long_path_name_w,
+    );
+    vm.register_import_stdcall(
+        "KERNEL32.dll",
+        "GetLogicalDrives",
+        crate::vm::stdcall_args(0),
+        get_logical_drives,
+    );
 }
 
-fn get_drive_type_a(_vm: &mut Vm, _stack_ptr: u32) -> u32 {
-    DRIVE_FIXED
+fn get_drive_type_a(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (root,) = vm_args!(vm, stack_ptr; u32);
+    let root = if root == 0 {
+        vm.current_dir().to_string()
+    } else {
+        vm.read_c_string(root).unwrap_or_default()
+    };
+    if root.starts_with("\\\\") {
+        return DRIVE_REMOTE;
+    }
+    match root.as_bytes().first() {
+        Some(letter) if vm.drive_letters().contains(&letter.to_ascii_uppercase()) => DRIVE_FIXED,
+        _ => DRIVE_NO_ROOT_DIR,
+    }
 }
 
 fn get_logical_drive_strings_a(vm: &mut Vm, stack_ptr: u32) -> u32 {
-    let (_buffer_len, buffer) = vm_args!(vm, stack_ptr; u32, u32);
-    if buffer == 0 {
-        return 0;
+    let (buffer_len, buffer) = vm_args!(vm, stack_ptr; u32, u32);
+    let mut bytes = Vec::new();
+    for drive in vm.drive_letters() {
+        bytes.extend_from_slice(&[drive, b':', b'\\', 0]);
+    }
+    // The result length leaves out the final NUL; a short buffer gets the
+    // size it needs, which includes it.
+    if buffer == 0 || bytes.len() + 1 > buffer_len as usize {
+        return bytes.len() as u32 + 1;
     }
-    let bytes = [b'C', b':', b'\\', 0, 0];
+    bytes.push(0);
     let _ = vm.write_bytes(buffer, &bytes);
     bytes.len() as u32 - 1
 }
 
+fn get_logical_drives(vm: &mut Vm, _stack_ptr: u32) -> u32 {
+    vm.drive_letters()
+        .iter()
+        .fold(0, |mask, drive| mask | 1 << (drive - b'A'))
+}
+
+fn get_current_directory_a(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (size, buffer) = vm_args!(vm, stack_ptr; u32, u32);
+    let dir = vm.current_dir().to_string();
+    write_path_a(vm, buffer, size, &dir)
+}
+
+fn get_current_directory_w(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (size, buffer) = vm_args!(vm, stack_ptr; u32, u32);
+    let dir = vm.current_dir().to_string();
+    write_path_w(vm, buffer, size, &dir)
+}
+
+fn set_current_directory_a(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (path,) = vm_args!(vm, stack_ptr; str);
+    set_current_directory(vm, &path)
+}
+
+fn set_current_directory_w(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (path,) = vm_args!(vm, stack_ptr; wstr);
+    set_current_directory(vm, &path)
+}
+
+fn set_current_directory(vm: &mut Vm, path: &str) -> u32 {
+    match vm.set_current_dir(path) {
+        Ok(()) => 1,
+        Err(err) => {
+            vm.set_last_error(io_error_code(&err));
+            0
+        }
+    }
+}
+
+fn get_full_path_name_a(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (path, size, buffer, file_part) = vm_args!(vm, stack_ptr; str, u32, u32, u32);
+    let full = vm.full_path(&path);
+    let written = write_path_a(vm, buffer, size, &full);
+    if written < size && file_part != 0 {
+        let offset = file_part_offset(&full).map_or(0, |offset| buffer + offset as u32);
+        let _ = vm.write_u32(file_part, offset);
+    }
+    written
+}
+
+fn get_full_path_name_w(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (path, size, buffer, file_part) = vm_args!(vm, stack_ptr; wstr, u32, u32, u32);
+    let full = vm.full_path(&path);
+    let written = write_path_w(vm, buffer, size, &full);
+    if written < size && file_part != 0 {
+        let offset = file_part_offset(&full).map_or(0, |offset| {
+            let units = full[..offset].encode_utf16().count() as u32;
+            buffer + units * 2
+        });
+        let _ = vm.write_u32(file_part, offset);
+    }
+    written
+}
+
+// Byte offset of the final component, or `None` when the path ends in a
+// separator and has no file part.
+fn file_part_offset(path: &str) -> Option<usize> {
+    let offset = path.rfind('\\').map_or(0, |idx| idx + 1);
+    (offset < path.len()).then_some(offset)
+}
+
+fn get_short_path_name_a(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (path, buffer, size) = vm_args!(vm, stack_ptr; str, u32, u32);
+    match vm.short_path(&path) {
+        Ok(short) => write_path_a(vm, buffer, size, &short),
+        Err(err) => path_failure(vm, &err),
+    }
+}
+
+fn get_short_path_name_w(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (path, buffer, size) = vm_args!(vm, stack_ptr; wstr, u32, u32);
+    match vm.short_path(&path) {
+        Ok(short) => write_path_w(vm, buffer, size, &short),
+        Err(err) => path_failure(vm, &err),
+    }
+}
+
+fn get_long_path_name_a(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (path, buffer, size) = vm_args!(vm, stack_ptr; str, u32, u32);
+    match vm.long_path(&path) {
+        Ok(long) => write_path_a(vm, buffer, size, &long),
+        Err(err) => path_failure(vm, &err),
+    }
+}
+
+fn get_long_path_name_w(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (path, buffer, size) = vm_args!(vm, stack_ptr; wstr, u32, u32);
+    match vm.long_path(&path) {
+        Ok(long) => write_path_w(vm, buffer, size, &long),
+        Err(err) => path_failure(vm, &err),
+    }
+}
+
+fn path_failure(vm: &mut Vm, err: &std::io::Error) -> u32 {
+    vm.set_last_error(io_error_code(err));
+    0
+}
+
 fn get_temp_file_name_a(_vm: &mut Vm, _stack_ptr: u32) -> u32 {
     0
 }
@@ -1,7 +1,10 @@
-use crate::vm::Vm;
+use std::io;
+
+use crate::vm::{FsTimes, Vm};
 use crate::vm_args;
 
 use super::constants::{ERROR_INVALID_HANDLE, INVALID_HANDLE_VALUE};
+use super::helpers::{io_error_code, system_from_filetime};
 
 pub(super) fn register(vm: &mut Vm) {
     vm.register_import_stdcall(
@@ -30,8 +33,19 @@ pub(super) fn register(vm: &mut Vm) {
     );
 }
 
-fn set_end_of_file(_vm: &mut Vm, _stack_ptr: u32) -> u32 {
-    1
+fn set_end_of_file(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (handle,) = vm_args!(vm, stack_ptr; u32);
+    match vm.file_set_end(handle) {
+        Some(Ok(())) => 1,
+        Some(Err(err)) => {
+            vm.set_last_error(io_error_code(&err));
+            0
+        }
+        None => {
+            vm.set_last_error(ERROR_INVALID_HANDLE);
+            0
+        }
+    }
 }
 
 fn set_file_pointer(vm: &mut Vm, stack_ptr: u32) -> u32 {
@@ -73,6 +87,39 @@ fn set_file_pointer_ex(vm: &mut Vm, stack_ptr: u32) -> u32 {
     }
 }
 
-fn set_file_time(_vm: &mut Vm, _stack_ptr: u32) -> u32 {
-    1
+fn set_file_time(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (handle, creation, access, write) = vm_args!(vm, stack_ptr; u32, u32, u32, u32);
+    // A null pointer, zero or all-ones FILETIME leaves that time unchanged.
+    let read = |vm: &Vm, ptr: u32| {
+        if ptr == 0 {
+            return None;
+        }
+        let low = vm.read_u32(ptr).unwrap_or(0) as u64;
+        let high = vm.read_u32(ptr + 4).unwrap_or(0) as u64;
+        let ticks = (high << 32) | low;
+        (ticks != 0 && ticks != u64::MAX).then(|| system_from_filetime(ticks))
+    };
+    let mut times = FsTimes::new();
+    if let Some(time) = read(vm, creation) {
+        times = times.created(time);
+    }
+    if let Some(time) = read(vm, access) {
+        times = times.accessed(time);
+    }
+    if let Some(time) = read(vm, write) {
+        times = times.modified(time);
+    }
+    match vm.file_set_times(handle, times) {
+        // Backends without timestamps have nothing to update.
+        Some(Ok(())) => 1,
+        Some(Err(err)) if err.kind() == io::ErrorKind::Unsupported => 1,
+        Some(Err(err)) => {
+            vm.set_last_error(io_error_code(&err));
+            0
+        }
+        None => {
+            vm.set_last_error(ERROR_INVALID_HANDLE);
+            0
+        }
+    }
 }
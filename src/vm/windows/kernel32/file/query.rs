@@ -2,9 +2,17 @@ use crate::vm::Vm;
 use crate::vm_args;
 
 use super::constants::{
-    ERROR_FILE_NOT_FOUND, ERROR_INVALID_HANDLE, FILE_ATTRIBUTE_DIRECTORY, FILE_ATTRIBUTE_NORMAL,
+    DISK_BYTES_PER_SECTOR, DISK_FREE_BYTES, DISK_SECTORS_PER_CLUSTER, DISK_TOTAL_BYTES,
+    ERROR_FILE_NOT_FOUND, ERROR_INVALID_HANDLE, ERROR_INVALID_PARAMETER, ERROR_PATH_NOT_FOUND,
     FILE_TYPE_DISK, INVALID_FILE_ATTRIBUTES,
 };
+use super::helpers::{
+    file_attributes, file_times, io_error_code, path_error_code, read_w_string,
+    write_attribute_data, write_filetime,
+};
+
+// GetFileAttributesEx only defines GetFileExInfoStandard.
+const GET_FILE_EX_INFO_STANDARD: u32 = 0;
 
 pub(super) fn register(vm: &mut Vm) {
     vm.register_import_stdcall(
@@ -19,12 +27,36 @@ pub(super) fn register(vm: &mut Vm) {
         crate::vm::stdcall_args(1),
         get_file_attributes_a,
     );
+    vm.register_import_stdcall(
+        "KERNEL32.dll",
+        "GetFileAttributesW",
+        crate::vm::stdcall_args(1),
+        get_file_attributes_w,
+    );
+    vm.register_import_stdcall(
+        "KERNEL32.dll",
+        "GetFileAttributesExA",
+        crate::vm::stdcall_args(3),
+        get_file_attributes_ex_a,
+    );
+    vm.register_import_stdcall(
+        "KERNEL32.dll",
+        "GetFileAttributesExW",
+        crate::vm::stdcall_args(3),
+        get_file_attributes_ex_w,
+    );
     vm.register_import_stdcall(
         "KERNEL32.dll",
         "GetFileSize",
         crate::vm::stdcall_args(2),
         get_file_size,
     );
+    vm.register_import_stdcall(
+        "KERNEL32.dll",
+        "GetFileSizeEx",
+        crate::vm::stdcall_args(2),
+        get_file_size_ex,
+    );
     vm.register_import_stdcall(
         "KERNEL32.dll",
         "GetFileTime",
@@ -37,10 +69,42 @@ pub(super) fn register(vm: &mut Vm) {
         crate::vm::stdcall_args(1),
         get_file_type,
     );
+    vm.register_import_stdcall(
+        "KERNEL32.dll",
+        "GetDiskFreeSpaceA",
+        crate::vm::stdcall_args(5),
+        get_disk_free_space_a,
+    );
+    vm.register_import_stdcall(
+        "KERNEL32.dll",
+        "GetDiskFreeSpaceW",
+        crate::vm::stdcall_args(5),
+        get_disk_free_space_w,
+    );
+    vm.register_import_stdcall(
+        "KERNEL32.dll",
+        "GetDiskFreeSpaceExA",
+        crate::vm::stdcall_args(4),
+        get_disk_free_space_ex_a,
+    );
+    vm.register_import_stdcall(
+        "KERNEL32.dll",
+        "GetDiskFreeSpaceExW",
+        crate::vm::stdcall_args(4),
+        get_disk_free_space_ex_w,
+    );
 }
 
-fn flush_file_buffers(_vm: &mut Vm, _stack_ptr: u32) -> u32 {
-    1
+fn flush_file_buffers(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (handle,) = vm_args!(vm, stack_ptr; u32);
+    match vm.file_flush(handle) {
+        // Console and other non-file handles have nothing to flush.
+        None | Some(Ok(())) => 1,
+        Some(Err(err)) => {
+            vm.set_last_error(io_error_code(&err));
+            0
+        }
+    }
 }
 
 fn get_file_attributes_a(vm: &mut Vm, stack_ptr: u32) -> u32 {
@@ -53,16 +117,55 @@ fn get_file_attributes_a(vm: &mut Vm, stack_ptr: u32) -> u32 {
     if std::env::var("PE_VM_TRACE").is_ok() {
         eprintln!("[pe_vm] GetFileAttributesA: {path}");
     }
-    if vm.file_exists(&path) {
-        let host_path = vm.map_path(&path);
-        if std::path::Path::new(&host_path).is_dir() {
-            FILE_ATTRIBUTE_DIRECTORY
-        } else {
-            FILE_ATTRIBUTE_NORMAL
-        }
-    } else {
+    get_file_attributes(vm, &path)
+}
+
+fn get_file_attributes_w(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (path_ptr,) = vm_args!(vm, stack_ptr; u32);
+    if path_ptr == 0 {
         vm.set_last_error(ERROR_FILE_NOT_FOUND);
-        INVALID_FILE_ATTRIBUTES
+        return INVALID_FILE_ATTRIBUTES;
+    }
+    let path = read_w_string(vm, path_ptr);
+    get_file_attributes(vm, &path)
+}
+
+fn get_file_attributes(vm: &mut Vm, path: &str) -> u32 {
+    match vm.file_metadata(path) {
+        Ok(metadata) => file_attributes(&metadata),
+        Err(err) => {
+            let code = path_error_code(vm, path, io_error_code(&err));
+            vm.set_last_error(code);
+            INVALID_FILE_ATTRIBUTES
+        }
+    }
+}
+
+fn get_file_attributes_ex_a(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (path, level, data) = vm_args!(vm, stack_ptr; str, u32, u32);
+    get_file_attributes_ex(vm, &path, level, data)
+}
+
+fn get_file_attributes_ex_w(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (path, level, data) = vm_args!(vm, stack_ptr; wstr, u32, u32);
+    get_file_attributes_ex(vm, &path, level, data)
+}
+
+fn get_file_attributes_ex(vm: &mut Vm, path: &str, level: u32, data: u32) -> u32 {
+    if level != GET_FILE_EX_INFO_STANDARD || data == 0 {
+        vm.set_last_error(ERROR_INVALID_PARAMETER);
+        return 0;
+    }
+    match vm.file_metadata(path) {
+        Ok(metadata) => {
+            write_attribute_data(vm, data, &metadata);
+            1
+        }
+        Err(err) => {
+            let code = path_error_code(vm, path, io_error_code(&err));
+            vm.set_last_error(code);
+            0
+        }
     }
 }
 
@@ -71,9 +174,9 @@ fn get_file_size(vm: &mut Vm, stack_ptr: u32) -> u32 {
     match vm.file_size(handle) {
         Some(size) => {
             if high_ptr != 0 {
-                let _ = vm.write_u32(high_ptr, 0);
+                let _ = vm.write_u32(high_ptr, (size >> 32) as u32);
             }
-            size
+            size as u32
         }
         None => {
             vm.set_last_error(ERROR_INVALID_HANDLE);
@@ -82,19 +185,39 @@ fn get_file_size(vm: &mut Vm, stack_ptr: u32) -> u32 {
     }
 }
 
-fn get_file_time(vm: &mut Vm, stack_ptr: u32) -> u32 {
-    let (_handle, creation, access, write) = vm_args!(vm, stack_ptr; u32, u32, u32, u32);
-    if creation != 0 {
-        let _ = vm.write_u32(creation, 0);
-        let _ = vm.write_u32(creation + 4, 0);
-    }
-    if access != 0 {
-        let _ = vm.write_u32(access, 0);
-        let _ = vm.write_u32(access + 4, 0);
+fn get_file_size_ex(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (handle, size_ptr) = vm_args!(vm, stack_ptr; u32, u32);
+    match vm.file_size(handle) {
+        Some(size) => {
+            let _ = vm.write_u32(size_ptr, size as u32);
+            let _ = vm.write_u32(size_ptr + 4, (size >> 32) as u32);
+            1
+        }
+        None => {
+            vm.set_last_error(ERROR_INVALID_HANDLE);
+            0
+        }
     }
-    if write != 0 {
-        let _ = vm.write_u32(write, 0);
-        let _ = vm.write_u32(write + 4, 0);
+}
+
+fn get_file_time(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (handle, creation, access, write) = vm_args!(vm, stack_ptr; u32, u32, u32, u32);
+    let metadata = match vm.file_handle_metadata(handle) {
+        Some(Ok(metadata)) => metadata,
+        Some(Err(err)) => {
+            vm.set_last_error(io_error_code(&err));
+            return 0;
+        }
+        None => {
+            vm.set_last_error(ERROR_INVALID_HANDLE);
+            return 0;
+        }
+    };
+    let times = file_times(&metadata);
+    for (out, ticks) in [creation, access, write].into_iter().zip(times) {
+        if out != 0 {
+            write_filetime(vm, out, ticks);
+        }
     }
     1
 }
@@ -102,3 +225,76 @@ fn get_file_time(vm: &mut Vm, stack_ptr: u32) -> u32 {
 fn get_file_type(_vm: &mut Vm, _stack_ptr: u32) -> u32 {
     FILE_TYPE_DISK
 }
+
+fn get_disk_free_space_a(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (root, sectors, bytes, free, total) = vm_args!(vm, stack_ptr; u32, u32, u32, u32, u32);
+    let root = (root != 0).then(|| vm.read_c_string(root).unwrap_or_default());
+    get_disk_free_space(vm, root, [sectors, bytes, free, total])
+}
+
+fn get_disk_free_space_w(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (root, sectors, bytes, free, total) = vm_args!(vm, stack_ptr; u32, u32, u32, u32, u32);
+    let root = (root != 0).then(|| read_w_string(vm, root));
+    get_disk_free_space(vm, root, [sectors, bytes, free, total])
+}
+
+fn get_disk_free_space(vm: &mut Vm, root: Option<String>, out: [u32; 4]) -> u32 {
+    if !disk_exists(vm, root.as_deref()) {
+        vm.set_last_error(ERROR_PATH_NOT_FOUND);
+        return 0;
+    }
+    let cluster = (DISK_BYTES_PER_SECTOR * DISK_SECTORS_PER_CLUSTER) as u64;
+    let values = [
+        DISK_SECTORS_PER_CLUSTER,
+        DISK_BYTES_PER_SECTOR,
+        (DISK_FREE_BYTES / cluster) as u32,
+        (DISK_TOTAL_BYTES / cluster) as u32,
+    ];
+    for (ptr, value) in out.into_iter().zip(values) {
+        if ptr != 0 {
+            let _ = vm.write_u32(ptr, value);
+        }
+    }
+    1
+}
+
+fn get_disk_free_space_ex_a(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (dir, available, total, free) = vm_args!(vm, stack_ptr; u32, u32, u32, u32);
+    let dir = (dir != 0).then(|| vm.read_c_string(dir).unwrap_or_default());
+    get_disk_free_space_ex(vm, dir, [available, total, free])
+}
+
+fn get_disk_free_space_ex_w(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (dir, available, total, free) = vm_args!(vm, stack_ptr; u32, u32, u32, u32);
+    let dir = (dir != 0).then(|| read_w_string(vm, dir));
+    get_disk_free_space_ex(vm, dir, [available, total, free])
+}
+
+fn get_disk_free_space_ex(vm: &mut Vm, dir: Option<String>, out: [u32; 3]) -> u32 {
+    if !disk_exists(vm, dir.as_deref()) {
+        vm.set_last_error(ERROR_PATH_NOT_FOUND);
+        return 0;
+    }
+    let values = [DISK_FREE_BYTES, DISK_TOTAL_BYTES, DISK_FREE_BYTES];
+    for (ptr, value) in out.into_iter().zip(values) {
+        if ptr != 0 {
+            let _ = vm.write_u32(ptr, value as u32);
+            let _ = vm.write_u32(ptr + 4, (value >> 32) as u32);
+        }
+    }
+    1
+}
+
+// A known drive root, or a directory that exists. `None` means the current drive.
+fn disk_exists(vm: &Vm, dir: Option<&str>) -> bool {
+    let Some(dir) = dir else {
+        return true;
+    };
+    let full = vm.full_path(dir);
+    let bytes = full.as_bytes();
+    if bytes.len() <= 3 && bytes.get(1) == Some(&b':') {
+        return vm.drive_letters().contains(&bytes[0].to_ascii_uppercase());
+    }
+    vm.file_metadata(&full)
+        .is_ok_and(|metadata| metadata.is_dir())
+}
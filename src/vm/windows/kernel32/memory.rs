@@ -1,10 +1,19 @@
 //! Kernel32 heap/global memory stubs.
 
 use crate::vm::windows::kernel32::DLL_NAME;
-use crate::vm::Vm;
+use crate::vm::{HeapApi, Vm, VmError};
 use crate::vm_args;
 
-const HEAP_HANDLE: u32 = 0x1000;
+const HEAP_HANDLE: u32 = crate::vm::PROCESS_HEAP;
+const HEAP_REALLOC_IN_PLACE_ONLY: u32 = 0x10;
+const MEM_RELEASE: u32 = 0x8000;
+const ERROR_NOT_ENOUGH_MEMORY: u32 = 8;
+const ERROR_INVALID_HANDLE: u32 = 6;
+const ERROR_INVALID_PARAMETER: u32 = 87;
+const ERROR_BAD_LENGTH: u32 = 24;
+const ERROR_INVALID_ADDRESS: u32 = 487;
+const ERROR_NOACCESS: u32 = 998;
+const MEMORY_BASIC_INFORMATION_SIZE: usize = 28;
 
 pub fn register(vm: &mut Vm) {
     vm.register_import_stdcall(
@@ -111,52 +120,93 @@ fn get_process_heap(_vm: &mut Vm, _stack_ptr: u32) -> u32 {
     HEAP_HANDLE
 }
 
-fn heap_create(_vm: &mut Vm, _stack_ptr: u32) -> u32 {
-    HEAP_HANDLE
+fn heap_create(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (_options, initial, maximum) = vm_args!(vm, stack_ptr; u32, u32, u32);
+    match vm.heap_create(initial, maximum) {
+        Ok(handle) => handle,
+        Err(err) => {
+            vm.set_last_error(win32_error(&err));
+            0
+        }
+    }
 }
 
 fn heap_alloc(vm: &mut Vm, stack_ptr: u32) -> u32 {
-    let (_heap, _flags, size) = vm_args!(vm, stack_ptr; u32, u32, usize);
-    vm.heap_alloc(size)
+    let (heap, _flags, size) = vm_args!(vm, stack_ptr; u32, u32, u32);
+    match vm.heap_alloc_zeroed(heap, size) {
+        Ok(ptr) => {
+            vm.heap_debug_tag(ptr, HeapApi::HeapAlloc);
+            ptr
+        }
+        Err(err) => {
+            vm.set_last_error(win32_error(&err));
+            0
+        }
+    }
 }
 
 fn heap_realloc(vm: &mut Vm, stack_ptr: u32) -> u32 {
-    let (_heap, _flags, mem, size) = vm_args!(vm, stack_ptr; u32, u32, u32, usize);
-    vm.heap_realloc(mem, size)
+    let (heap, flags, mem, size) = vm_args!(vm, stack_ptr; u32, u32, u32, u32);
+    let result = if mem == 0 {
+        vm.heap_alloc_zeroed(heap, size).inspect(|&ptr| {
+            vm.heap_debug_tag(ptr, HeapApi::HeapAlloc);
+        })
+    } else {
+        vm.heap_realloc_in(heap, mem, size, flags & HEAP_REALLOC_IN_PLACE_ONLY != 0)
+    };
+    match result {
+        Ok(ptr) => ptr,
+        Err(err) => {
+            vm.set_last_error(win32_error(&err));
+            0
+        }
+    }
 }
 
 fn heap_free(vm: &mut Vm, stack_ptr: u32) -> u32 {
-    let (_, _, mem) = vm_args!(vm, stack_ptr; u32, u32, u32);
+    let (heap, _, mem) = vm_args!(vm, stack_ptr; u32, u32, u32);
     if mem == 0 {
         return 0;
     }
-    if vm.heap_free(mem) {
+    if vm.heap_free_in(heap, mem) {
         1
     } else {
+        vm.set_last_error(ERROR_INVALID_PARAMETER);
         0
     }
 }
 
 fn heap_size(vm: &mut Vm, stack_ptr: u32) -> u32 {
-    let (_, _, mem) = vm_args!(vm, stack_ptr; u32, u32, u32);
-    if let Some(size) = vm.heap_size(mem) {
-        size as u32
-    } else {
-        0xFFFF_FFFF
-    }
+    let (heap, _, mem) = vm_args!(vm, stack_ptr; u32, u32, u32);
+    vm.heap_size_in(heap, mem).unwrap_or(0xFFFF_FFFF)
 }
 
-fn heap_destroy(_vm: &mut Vm, _stack_ptr: u32) -> u32 {
-    1
+fn heap_destroy(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (heap,) = vm_args!(vm, stack_ptr; u32);
+    if vm.heap_destroy(heap) {
+        1
+    } else {
+        vm.set_last_error(ERROR_INVALID_HANDLE);
+        0
+    }
 }
 
 fn global_alloc(vm: &mut Vm, stack_ptr: u32) -> u32 {
     let (_flags, size) = vm_args!(vm, stack_ptr; u32, usize);
-    vm.heap_alloc(size)
+    let ptr = vm.heap_alloc(size);
+    vm.heap_debug_tag(ptr, HeapApi::GlobalAlloc);
+    ptr
 }
 
-fn global_free(_vm: &mut Vm, _stack_ptr: u32) -> u32 {
-    0
+// GlobalFree/LocalFree return NULL on success and the handle on failure.
+fn global_free(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (h_mem,) = vm_args!(vm, stack_ptr; u32);
+    if h_mem == 0 || vm.heap_free(h_mem) {
+        0
+    } else {
+        vm.set_last_error(ERROR_INVALID_HANDLE);
+        h_mem
+    }
 }
 
 fn global_lock(vm: &mut Vm, stack_ptr: u32) -> u32 {
@@ -173,41 +223,83 @@ fn global_handle(vm: &mut Vm, stack_ptr: u32) -> u32 {
     p_mem
 }
 
-fn local_free(_vm: &mut Vm, _stack_ptr: u32) -> u32 {
-    0
+fn local_free(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    global_free(vm, stack_ptr)
 }
 
 fn virtual_alloc(vm: &mut Vm, stack_ptr: u32) -> u32 {
-    let (_addr, size, _alloc_type, _protect) = vm_args!(vm, stack_ptr; u32, usize, u32, u32);
-    vm.alloc_bytes(&vec![0u8; size], 8).unwrap_or(0)
+    let (addr, size, alloc_type, protect) = vm_args!(vm, stack_ptr; u32, u32, u32, u32);
+    match vm.virtual_alloc(addr, size, alloc_type, protect) {
+        Ok(base) => base,
+        Err(err) => {
+            vm.set_last_error(win32_error(&err));
+            0
+        }
+    }
 }
 
-fn virtual_free(_vm: &mut Vm, _stack_ptr: u32) -> u32 {
-    1
+fn virtual_free(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (addr, size, free_type) = vm_args!(vm, stack_ptr; u32, u32, u32);
+    if vm.virtual_free(addr, size, free_type & MEM_RELEASE != 0) {
+        1
+    } else {
+        vm.set_last_error(ERROR_INVALID_PARAMETER);
+        0
+    }
 }
 
 fn virtual_protect(vm: &mut Vm, stack_ptr: u32) -> u32 {
-    let (_, _, _, old_protect_ptr) = vm_args!(vm, stack_ptr; u32, u32, u32, u32);
-    if old_protect_ptr != 0 {
-        let _ = vm.write_u32(old_protect_ptr, 0x04);
+    let (addr, size, protect, old_protect_ptr) = vm_args!(vm, stack_ptr; u32, u32, u32, u32);
+    if old_protect_ptr == 0 {
+        vm.set_last_error(ERROR_NOACCESS);
+        return 0;
+    }
+    match vm.protect_memory(addr, size, protect) {
+        Ok(old) => {
+            if vm.write_u32(old_protect_ptr, old).is_err() {
+                vm.set_last_error(ERROR_NOACCESS);
+                return 0;
+            }
+            1
+        }
+        Err(err) => {
+            vm.set_last_error(win32_error(&err));
+            0
+        }
     }
-    1
 }
 
 fn virtual_query(vm: &mut Vm, stack_ptr: u32) -> u32 {
-    let (_, info_ptr, len) = vm_args!(vm, stack_ptr; u32, u32, usize);
-    if info_ptr == 0 || len < 28 {
+    let (addr, info_ptr, len) = vm_args!(vm, stack_ptr; u32, u32, usize);
+    if info_ptr == 0 || len < MEMORY_BASIC_INFORMATION_SIZE {
+        vm.set_last_error(ERROR_BAD_LENGTH);
         return 0;
     }
-    let base = vm.base();
-    let _ = vm.write_u32(info_ptr, base);
-    let _ = vm.write_u32(info_ptr + 4, base);
-    let _ = vm.write_u32(info_ptr + 8, 0x04);
-    let _ = vm.write_u32(info_ptr + 12, 0x1000);
-    let _ = vm.write_u32(info_ptr + 16, 0x1000);
-    let _ = vm.write_u32(info_ptr + 20, 0x04);
-    let _ = vm.write_u32(info_ptr + 24, 0x20000);
-    28
+    let info = vm.query_memory(addr);
+    let fields = [
+        info.base_address,
+        info.allocation_base,
+        info.allocation_protect,
+        info.region_size,
+        info.state,
+        info.protect,
+        info.kind,
+    ];
+    for (index, value) in fields.into_iter().enumerate() {
+        if vm.write_u32(info_ptr + index as u32 * 4, value).is_err() {
+            vm.set_last_error(ERROR_NOACCESS);
+            return 0;
+        }
+    }
+    MEMORY_BASIC_INFORMATION_SIZE as u32
+}
+
+fn win32_error(err: &VmError) -> u32 {
+    match err {
+        VmError::OutOfMemory | VmError::NoImage => ERROR_NOT_ENOUGH_MEMORY,
+        VmError::InvalidConfig(_) => ERROR_INVALID_PARAMETER,
+        _ => ERROR_INVALID_ADDRESS,
+    }
 }
 
 fn flush_instruction_cache(_vm: &mut Vm, _stack_ptr: u32) -> u32 {
@@ -217,21 +309,43 @@ fn flush_instruction_cache(_vm: &mut Vm, _stack_ptr: u32) -> u32 {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::vm::{Architecture, VmConfig};
+    use crate::vm::{AccessKind, Architecture, HeapIssueKind, VmConfig};
     use crate::vm_set_args;
 
     fn create_test_vm() -> Vm {
         let mut vm = Vm::new(VmConfig::new().architecture(Architecture::X86)).expect("vm");
-        vm.memory = vec![0u8; 0x10000];
+        vm.memory.map(0x1000, 0x10000);
         vm.base = 0x1000;
         vm.stack_top = 0x1000 + 0x10000 - 4;
         vm.regs.esp = vm.stack_top;
         vm.heap_start = 0x2000;
         vm.heap_end = 0x8000;
-        vm.heap_cursor = vm.heap_start;
         vm
     }
 
+    fn create_debug_vm() -> Vm {
+        let mut vm = Vm::new(
+            VmConfig::new()
+                .architecture(Architecture::X86)
+                .heap_debug(true),
+        )
+        .expect("vm");
+        vm.memory.map(0x1000, 0x10000);
+        vm.base = 0x1000;
+        vm.stack_top = 0x1000 + 0x10000 - 4;
+        vm.regs.esp = vm.stack_top;
+        vm.heap_start = 0x2000;
+        vm.heap_end = 0x8000;
+        vm
+    }
+
+    // Call a stub the way call_host does: esp points at the caller's return address.
+    fn call_at(vm: &mut Vm, stub: fn(&mut Vm, u32) -> u32, stack: u32, ret: u32) -> u32 {
+        vm.write_u32(stack, ret).unwrap();
+        vm.regs.esp = stack;
+        stub(vm, stack)
+    }
+
     #[test]
     fn test_get_process_heap_returns_handle() {
         let mut vm = create_test_vm();
@@ -329,10 +443,34 @@ mod tests {
     }
 
     #[test]
-    fn test_virtual_free_returns_one() {
+    fn test_virtual_alloc_at_requested_address() {
         let mut vm = create_test_vm();
-        let result = virtual_free(&mut vm, 0);
-        assert_eq!(result, 1);
+        let stack = vm.stack_top - 20;
+        vm_set_args!(vm, stack; 0x5000_1234u32, 0x2000u32, 0x3000u32, 0x04u32);
+        let ptr = virtual_alloc(&mut vm, stack);
+        assert_eq!(ptr, 0x5000_0000);
+        vm.write_u32(0x5000_3FFC, 7).unwrap();
+        assert!(vm.read_u32(0x5000_4000).is_err());
+
+        // Committing inside the reservation succeeds, overlapping it elsewhere fails.
+        vm_set_args!(vm, stack; 0x5000_1000u32, 0x1000u32, 0x1000u32, 0x04u32);
+        assert_eq!(virtual_alloc(&mut vm, stack), 0x5000_1000);
+        vm_set_args!(vm, stack; 0x5000_3000u32, 0x2000u32, 0x1000u32, 0x04u32);
+        assert_eq!(virtual_alloc(&mut vm, stack), 0);
+    }
+
+    #[test]
+    fn test_virtual_free_releases_region() {
+        let mut vm = create_test_vm();
+        let stack = vm.stack_top - 20;
+        vm_set_args!(vm, stack; 0u32, 0x1000u32, 0x3000u32, 0x04u32);
+        let ptr = virtual_alloc(&mut vm, stack);
+        assert!(vm.read_u32(ptr).is_ok());
+
+        vm_set_args!(vm, stack; ptr, 0u32, MEM_RELEASE);
+        assert_eq!(virtual_free(&mut vm, stack), 1);
+        assert!(vm.read_u32(ptr).is_err());
+        assert_eq!(virtual_free(&mut vm, stack), 0);
     }
 
     #[test]
@@ -340,17 +478,119 @@ mod tests {
         let mut vm = create_test_vm();
         let stack = vm.stack_top - 20;
         let old_protect_ptr = vm.heap_start as u32;
-        vm.write_u32(stack + 16, old_protect_ptr).unwrap();
+        vm_set_args!(vm, stack; 0x8000u32, 0x1000u32, 0x02u32, old_protect_ptr);
         let result = virtual_protect(&mut vm, stack);
         assert_eq!(result, 1);
         assert_eq!(vm.read_u32(old_protect_ptr).unwrap(), 0x04);
+        assert!(matches!(
+            vm.write_u32(0x8000, 1),
+            Err(VmError::AccessViolation {
+                addr: 0x8000,
+                access: AccessKind::Write,
+                ..
+            })
+        ));
+        assert_eq!(vm.read_u32(0x8000).unwrap(), 0);
     }
 
     #[test]
-    fn test_heap_destroy_returns_one() {
+    fn test_virtual_query_reports_reservation() {
         let mut vm = create_test_vm();
-        let result = heap_destroy(&mut vm, 0);
-        assert_eq!(result, 1);
+        let stack = vm.stack_top - 20;
+        // Reserve 64 KiB and commit only its second page as read-only.
+        vm_set_args!(vm, stack; 0u32, 0x1_0000u32, 0x2000u32, 0x04u32);
+        let base = virtual_alloc(&mut vm, stack);
+        assert_ne!(base, 0);
+        vm_set_args!(vm, stack; base + 0x1000, 0x1000u32, 0x1000u32, 0x02u32);
+        assert_eq!(virtual_alloc(&mut vm, stack), base + 0x1000);
+
+        let info = vm.heap_start as u32;
+        vm_set_args!(vm, stack; base + 0x1234, info, 28u32);
+        assert_eq!(virtual_query(&mut vm, stack), 28);
+        let fields: Vec<u32> = (0..7).map(|i| vm.read_u32(info + i * 4).unwrap()).collect();
+        assert_eq!(
+            fields,
+            vec![base + 0x1000, base, 0x04, 0x1000, 0x1000, 0x02, 0x2_0000]
+        );
+
+        vm_set_args!(vm, stack; base + 0x2000, info, 28u32);
+        virtual_query(&mut vm, stack);
+        assert_eq!(vm.read_u32(info + 12).unwrap(), 0xE000);
+        assert_eq!(vm.read_u32(info + 16).unwrap(), 0x2000);
+        assert!(vm.read_u8(base).is_err());
+    }
+
+    #[test]
+    fn test_heap_free_reuses_memory() {
+        let mut vm = create_test_vm();
+        let stack = vm.stack_top - 16;
+        // Far more allocations than the 24 KiB test heap could hold without reuse.
+        for _ in 0..1000 {
+            vm_set_args!(vm, stack; HEAP_HANDLE, 0u32, 0x100u32);
+            let ptr = heap_alloc(&mut vm, stack);
+            assert!(ptr >= 0x3000 && ptr < 0x9000);
+            vm_set_args!(vm, stack; HEAP_HANDLE, 0u32, ptr);
+            assert_eq!(heap_free(&mut vm, stack), 1);
+        }
+    }
+
+    #[test]
+    fn test_heap_grows_past_initial_region() {
+        let mut vm = create_test_vm();
+        let stack = vm.stack_top - 16;
+        vm_set_args!(vm, stack; HEAP_HANDLE, 0u32, 0x8000u32);
+        let ptr = heap_alloc(&mut vm, stack);
+        assert_ne!(ptr, 0);
+        assert!(ptr >= 0x1_0000);
+        assert_eq!(vm.read_u32(ptr + 0x7FFC).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_heap_realloc_preserves_contents() {
+        let mut vm = create_test_vm();
+        let stack = vm.stack_top - 20;
+        vm_set_args!(vm, stack; HEAP_HANDLE, 0u32, 16u32);
+        let a = heap_alloc(&mut vm, stack);
+        vm_set_args!(vm, stack; HEAP_HANDLE, 0u32, 16u32);
+        let _b = heap_alloc(&mut vm, stack);
+        vm.write_u32(a, 0xCAFE_BABE).unwrap();
+
+        // The neighbour blocks in-place growth, so the block moves.
+        vm_set_args!(vm, stack; HEAP_HANDLE, HEAP_REALLOC_IN_PLACE_ONLY, a, 64u32);
+        assert_eq!(heap_realloc(&mut vm, stack), 0);
+        vm_set_args!(vm, stack; HEAP_HANDLE, 0u32, a, 64u32);
+        let moved = heap_realloc(&mut vm, stack);
+        assert_ne!(moved, a);
+        assert_eq!(vm.read_u32(moved).unwrap(), 0xCAFE_BABE);
+        assert_eq!(vm.heap_size_in(HEAP_HANDLE, moved), Some(64));
+        assert_eq!(vm.heap_size_in(HEAP_HANDLE, a), None);
+    }
+
+    #[test]
+    fn test_heap_create_and_destroy() {
+        let mut vm = create_test_vm();
+        let stack = vm.stack_top - 16;
+        vm_set_args!(vm, stack; 0u32, 0u32, 0x2000u32);
+        let heap = heap_create(&mut vm, stack);
+        assert_ne!(heap, 0);
+        assert_ne!(heap, HEAP_HANDLE);
+
+        vm_set_args!(vm, stack; heap, 0u32, 0x100u32);
+        let ptr = heap_alloc(&mut vm, stack);
+        assert!(ptr >= heap && ptr < heap + 0x2000);
+        // A fixed-size heap does not grow.
+        vm_set_args!(vm, stack; heap, 0u32, 0x2000u32);
+        assert_eq!(heap_alloc(&mut vm, stack), 0);
+        // Blocks belong to the heap that allocated them.
+        vm_set_args!(vm, stack; HEAP_HANDLE, 0u32, ptr);
+        assert_eq!(heap_free(&mut vm, stack), 0);
+
+        vm_set_args!(vm, stack; heap);
+        assert_eq!(heap_destroy(&mut vm, stack), 1);
+        assert!(!vm.is_mapped(ptr));
+        assert_eq!(heap_destroy(&mut vm, stack), 0);
+        vm_set_args!(vm, stack; HEAP_HANDLE);
+        assert_eq!(heap_destroy(&mut vm, stack), 0);
     }
 
     #[test]
@@ -359,4 +599,80 @@ mod tests {
         let result = flush_instruction_cache(&mut vm, 0);
         assert_eq!(result, 1);
     }
+
+    #[test]
+    fn test_heap_debug_reports_overrun_on_free() {
+        let mut vm = create_debug_vm();
+        let stack = vm.stack_top - 16;
+        vm_set_args!(vm, stack; HEAP_HANDLE, 0u32, 10u32);
+        let ptr = call_at(&mut vm, heap_alloc, stack, 0x40_1000);
+        vm.write_u8(ptr + 10, 0x41).unwrap();
+        vm_set_args!(vm, stack; HEAP_HANDLE, 0u32, ptr);
+        assert_eq!(call_at(&mut vm, heap_free, stack, 0x40_2000), 1);
+
+        let report = vm.heap_report();
+        assert!(report.leaks.is_empty());
+        assert_eq!(report.issues.len(), 1);
+        let issue = &report.issues[0];
+        assert_eq!(issue.kind, HeapIssueKind::BufferOverrun);
+        assert_eq!((issue.addr, issue.size), (ptr, 10));
+        assert_eq!(issue.site.eip, 0x40_2000);
+        assert_eq!(issue.allocated_at.as_ref().unwrap().eip, 0x40_1000);
+    }
+
+    #[test]
+    fn test_heap_debug_reports_double_and_unknown_free() {
+        let mut vm = create_debug_vm();
+        let stack = vm.stack_top - 16;
+        vm_set_args!(vm, stack; HEAP_HANDLE, 0u32, 32u32);
+        let ptr = call_at(&mut vm, heap_alloc, stack, 0x40_1000);
+        vm_set_args!(vm, stack; HEAP_HANDLE, 0u32, ptr);
+        assert_eq!(call_at(&mut vm, heap_free, stack, 0x40_2000), 1);
+        assert_eq!(call_at(&mut vm, heap_free, stack, 0x40_3000), 0);
+        vm_set_args!(vm, stack; HEAP_HANDLE, 0u32, ptr + 8);
+        assert_eq!(call_at(&mut vm, heap_free, stack, 0x40_4000), 0);
+
+        let kinds: Vec<_> = vm
+            .heap_report()
+            .issues
+            .iter()
+            .map(|issue| (issue.kind, issue.site.eip))
+            .collect();
+        assert_eq!(
+            kinds,
+            vec![
+                (HeapIssueKind::DoubleFree, 0x40_3000),
+                (HeapIssueKind::UnknownFree, 0x40_4000)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_heap_debug_reports_leaks_with_call_stack() {
+        let mut vm = create_debug_vm();
+        let stack = vm.stack_top - 0x40;
+        // Two guest frames: [ebp] -> saved ebp, [ebp + 4] -> return address.
+        let frame = vm.stack_top - 0x20;
+        vm.write_u32(frame, frame + 0x10).unwrap();
+        vm.write_u32(frame + 4, 0x40_5000).unwrap();
+        vm.write_u32(frame + 0x10, 0).unwrap();
+        vm.write_u32(frame + 0x14, 0x40_6000).unwrap();
+        vm.regs.ebp = frame;
+
+        vm_set_args!(vm, stack; 0u32, 24u32);
+        let leaked = call_at(&mut vm, global_alloc, stack, 0x40_1000);
+        // Host-internal allocations are checked but never reported as leaks.
+        vm.alloc_bytes(b"internal", 1).unwrap();
+
+        let report = vm.heap_report();
+        assert!(report.issues.is_empty());
+        assert_eq!(report.leaks.len(), 1);
+        let leak = &report.leaks[0];
+        assert_eq!(
+            (leak.addr, leak.size, leak.api),
+            (leaked, 24, HeapApi::GlobalAlloc)
+        );
+        assert_eq!(leak.allocated_at.eip, 0x40_1000);
+        assert_eq!(leak.allocated_at.call_stack, vec![0x40_5000, 0x40_6000]);
+    }
 }
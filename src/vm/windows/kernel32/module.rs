@@ -2,9 +2,17 @@
 
 use crate::pe::{ResourceData, ResourceId, ResourceNode};
 use crate::vm::windows::kernel32::DLL_NAME;
-use crate::vm::Vm;
+use crate::vm::{Vm, VmError};
 use crate::vm_args;
 
+const ERROR_INVALID_HANDLE: u32 = 6;
+const ERROR_BAD_EXE_FORMAT: u32 = 193;
+const ERROR_MOD_NOT_FOUND: u32 = 126;
+const ERROR_PROC_NOT_FOUND: u32 = 127;
+const ERROR_DLL_INIT_FAILED: u32 = 1114;
+const GET_MODULE_HANDLE_EX_FLAG_UNCHANGED_REFCOUNT: u32 = 0x2;
+const GET_MODULE_HANDLE_EX_FLAG_FROM_ADDRESS: u32 = 0x4;
+
 pub fn register(vm: &mut Vm) {
     vm.register_import_stdcall(
         DLL_NAME,
@@ -42,6 +50,12 @@ pub fn register(vm: &mut Vm) {
         crate::vm::stdcall_args(1),
         load_library_a,
     );
+    vm.register_import_stdcall(
+        DLL_NAME,
+        "LoadLibraryW",
+        crate::vm::stdcall_args(1),
+        load_library_w,
+    );
     vm.register_import_stdcall(
         DLL_NAME,
         "LoadLibraryExA",
@@ -121,7 +135,8 @@ fn get_module_handle_a(vm: &mut Vm, stack_ptr: u32) -> u32 {
     if name == 0 {
         return vm.base();
     }
-    vm.base()
+    let name = vm.read_c_string(name).unwrap_or_default();
+    module_handle_or_error(vm, &name)
 }
 
 fn get_module_handle_w(vm: &mut Vm, stack_ptr: u32) -> u32 {
@@ -129,24 +144,40 @@ fn get_module_handle_w(vm: &mut Vm, stack_ptr: u32) -> u32 {
     if name == 0 {
         return vm.base();
     }
-    vm.base()
+    let name = read_w_string(vm, name);
+    module_handle_or_error(vm, &name)
 }
 
 fn get_module_handle_ex_w(vm: &mut Vm, stack_ptr: u32) -> u32 {
-    let (_, _, out) = vm_args!(vm, stack_ptr; u32, u32, u32);
+    let (flags, name, out) = vm_args!(vm, stack_ptr; u32, u32, u32);
+    let handle = if name == 0 {
+        Some(vm.base())
+    } else if flags & GET_MODULE_HANDLE_EX_FLAG_FROM_ADDRESS != 0 {
+        vm.module_by_addr(name).map(|module| module.base())
+    } else {
+        let name = read_w_string(vm, name);
+        vm.module_handle(&name)
+    };
     if out != 0 {
-        let _ = vm.write_u32(out, vm.base());
+        let _ = vm.write_u32(out, handle.unwrap_or(0));
+    }
+    let Some(handle) = handle else {
+        vm.set_last_error(ERROR_MOD_NOT_FOUND);
+        return 0;
+    };
+    if flags & GET_MODULE_HANDLE_EX_FLAG_UNCHANGED_REFCOUNT == 0 && name != 0 {
+        let _ = vm.load_library(&module_path(vm, handle), 0);
     }
     1
 }
 
 fn get_module_file_name_a(vm: &mut Vm, stack_ptr: u32) -> u32 {
-    let (_, buffer, size) = vm_args!(vm, stack_ptr; u32, u32, u32);
+    let (module, buffer, size) = vm_args!(vm, stack_ptr; u32, u32, u32);
     let size = size as usize;
     if buffer == 0 || size == 0 {
         return 0;
     }
-    let path = vm.image_path().unwrap_or("C:\\pe_vm\\module.dll");
+    let path = module_path(vm, module);
     if std::env::var("PE_VM_TRACE").is_ok() {
         eprintln!("[pe_vm] GetModuleFileNameA: {path}");
     }
@@ -160,12 +191,12 @@ fn get_module_file_name_a(vm: &mut Vm, stack_ptr: u32) -> u32 {
 }
 
 fn get_module_file_name_w(vm: &mut Vm, stack_ptr: u32) -> u32 {
-    let (_, buffer, size) = vm_args!(vm, stack_ptr; u32, u32, u32);
+    let (module, buffer, size) = vm_args!(vm, stack_ptr; u32, u32, u32);
     let size = size as usize;
     if buffer == 0 || size == 0 {
         return 0;
     }
-    let path = vm.image_path().unwrap_or("C:\\pe_vm\\module.dll");
+    let path = module_path(vm, module);
     if std::env::var("PE_VM_TRACE").is_ok() {
         eprintln!("[pe_vm] GetModuleFileNameW: {path}");
     }
@@ -180,20 +211,34 @@ fn get_module_file_name_w(vm: &mut Vm, stack_ptr: u32) -> u32 {
     utf16.len() as u32
 }
 
-fn load_library_a(vm: &mut Vm, _stack_ptr: u32) -> u32 {
-    vm.base()
+fn load_library_a(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (name,) = vm_args!(vm, stack_ptr; str);
+    load_library(vm, &name, 0)
 }
 
-fn load_library_ex_a(vm: &mut Vm, _stack_ptr: u32) -> u32 {
-    vm.base()
+fn load_library_w(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (name,) = vm_args!(vm, stack_ptr; wstr);
+    load_library(vm, &name, 0)
 }
 
-fn load_library_ex_w(vm: &mut Vm, _stack_ptr: u32) -> u32 {
-    vm.base()
+fn load_library_ex_a(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (name, _, flags) = vm_args!(vm, stack_ptr; str, u32, u32);
+    load_library(vm, &name, flags)
 }
 
-fn free_library(_vm: &mut Vm, _stack_ptr: u32) -> u32 {
-    1
+fn load_library_ex_w(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (name, _, flags) = vm_args!(vm, stack_ptr; wstr, u32, u32);
+    load_library(vm, &name, flags)
+}
+
+fn free_library(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (module,) = vm_args!(vm, stack_ptr; u32);
+    if vm.free_module(module) {
+        1
+    } else {
+        vm.set_last_error(ERROR_INVALID_HANDLE);
+        0
+    }
 }
 
 fn get_proc_address(vm: &mut Vm, stack_ptr: u32) -> u32 {
@@ -206,7 +251,54 @@ fn get_proc_address(vm: &mut Vm, stack_ptr: u32) -> u32 {
     if std::env::var("PE_VM_TRACE_IMPORTS").is_ok() || std::env::var("PE_VM_TRACE").is_ok() {
         eprintln!("[pe_vm] GetProcAddress: module=0x{module:08X} name={name}");
     }
-    vm.resolve_dynamic_import(&name).unwrap_or(0)
+    let module = if module == 0 { vm.base() } else { module };
+    let resolved = match vm.module_by_handle(module) {
+        Some(_) => vm.module_proc_address(module, &name),
+        // Unknown handles keep the old behavior of searching every host stub.
+        None => vm.resolve_dynamic_import(&name),
+    };
+    match resolved {
+        Some(addr) => addr,
+        None => {
+            vm.set_last_error(ERROR_PROC_NOT_FOUND);
+            0
+        }
+    }
+}
+
+fn load_library(vm: &mut Vm, name: &str, flags: u32) -> u32 {
+    match vm.load_library(name, flags) {
+        Ok(handle) => handle,
+        Err(err) => {
+            let code = match err {
+                VmError::Io(_) => ERROR_MOD_NOT_FOUND,
+                VmError::MissingImports(_) => ERROR_PROC_NOT_FOUND,
+                VmError::InvalidConfig(_) => ERROR_DLL_INIT_FAILED,
+                _ => ERROR_BAD_EXE_FORMAT,
+            };
+            vm.set_last_error(code);
+            0
+        }
+    }
+}
+
+fn module_handle_or_error(vm: &mut Vm, name: &str) -> u32 {
+    match vm.module_handle(name) {
+        Some(handle) => handle,
+        None => {
+            vm.set_last_error(ERROR_MOD_NOT_FOUND);
+            0
+        }
+    }
+}
+
+fn module_path(vm: &Vm, module: u32) -> String {
+    if module == 0 {
+        return vm.main_module_path().to_string();
+    }
+    vm.module_by_handle(module)
+        .map(|module| module.path().to_string())
+        .unwrap_or_else(|| vm.main_module_path().to_string())
 }
 
 fn disable_thread_library_calls(_vm: &mut Vm, _stack_ptr: u32) -> u32 {
@@ -1,65 +1,8 @@
-//! Kernel32 synchronization stubs.
+//! Kernel32 synchronization objects: events, mutexes, semaphores, waits and user-mode locks.
 
-use crate::define_stub_fn;
 use crate::vm::windows::kernel32::DLL_NAME;
-use crate::vm::Vm;
-
-define_stub_fn!(DLL_NAME, create_event_a, 1);
-define_stub_fn!(DLL_NAME, initialize_critical_section_and_spin_count, 1);
-define_stub_fn!(DLL_NAME, enter_critical_section, 0);
-define_stub_fn!(DLL_NAME, leave_critical_section, 0);
-define_stub_fn!(DLL_NAME, delete_critical_section, 0);
-define_stub_fn!(DLL_NAME, set_event, 1);
-
-// Additional stubs
-define_stub_fn!(DLL_NAME, create_event_w, 1);
-define_stub_fn!(DLL_NAME, create_event_ex_a, 1);
-define_stub_fn!(DLL_NAME, create_mutex_a, 1);
-define_stub_fn!(DLL_NAME, create_mutex_w, 1);
-define_stub_fn!(DLL_NAME, create_mutex_ex_a, 1);
-define_stub_fn!(DLL_NAME, create_mutex_ex_w, 1);
-define_stub_fn!(DLL_NAME, open_mutex_a, 1);
-define_stub_fn!(DLL_NAME, open_mutex_w, 1);
-define_stub_fn!(DLL_NAME, release_mutex, 1);
-define_stub_fn!(DLL_NAME, create_semaphore_a, 1);
-define_stub_fn!(DLL_NAME, create_semaphore_w, 1);
-define_stub_fn!(DLL_NAME, create_semaphore_ex_a, 1);
-define_stub_fn!(DLL_NAME, create_semaphore_ex_w, 1);
-define_stub_fn!(DLL_NAME, open_semaphore_a, 1);
-define_stub_fn!(DLL_NAME, open_semaphore_w, 1);
-define_stub_fn!(DLL_NAME, release_semaphore, 1);
-define_stub_fn!(DLL_NAME, open_event_a, 1);
-define_stub_fn!(DLL_NAME, open_event_w, 1);
-define_stub_fn!(DLL_NAME, reset_event, 1);
-define_stub_fn!(DLL_NAME, pulse_event, 1);
-define_stub_fn!(DLL_NAME, initialize_critical_section, 0);
-define_stub_fn!(DLL_NAME, initialize_critical_section_ex, 1);
-define_stub_fn!(DLL_NAME, try_enter_critical_section, 1);
-define_stub_fn!(DLL_NAME, set_critical_section_spin_count, 0);
-define_stub_fn!(DLL_NAME, wait_for_multiple_objects, 0);
-define_stub_fn!(DLL_NAME, wait_for_multiple_objects_ex, 0);
-define_stub_fn!(DLL_NAME, wait_for_single_object_ex, 0);
-define_stub_fn!(DLL_NAME, signal_object_and_wait, 0);
-define_stub_fn!(DLL_NAME, sleep_ex, 0);
-
-// SRW locks and condition variables
-define_stub_fn!(DLL_NAME, initialize_srw_lock, 0);
-define_stub_fn!(DLL_NAME, acquire_srw_lock_exclusive, 0);
-define_stub_fn!(DLL_NAME, acquire_srw_lock_shared, 0);
-define_stub_fn!(DLL_NAME, release_srw_lock_exclusive, 0);
-define_stub_fn!(DLL_NAME, release_srw_lock_shared, 0);
-define_stub_fn!(DLL_NAME, try_acquire_srw_lock_exclusive, 1);
-define_stub_fn!(DLL_NAME, try_acquire_srw_lock_shared, 1);
-define_stub_fn!(DLL_NAME, initialize_condition_variable, 0);
-define_stub_fn!(DLL_NAME, wake_condition_variable, 0);
-define_stub_fn!(DLL_NAME, wake_all_condition_variable, 0);
-define_stub_fn!(DLL_NAME, sleep_condition_variable_cs, 1);
-define_stub_fn!(DLL_NAME, sleep_condition_variable_srw, 1);
-
-// Init once
-define_stub_fn!(DLL_NAME, init_once_begin_initialize, 1);
-define_stub_fn!(DLL_NAME, init_once_complete, 1);
-define_stub_fn!(DLL_NAME, init_once_execute_once, 1);
+use crate::vm::{SyncObject, Vm, WAIT_FAILED};
+use crate::vm_args;
 
 pub fn register(vm: &mut Vm) {
     vm.register_import_stdcall(
@@ -367,13 +310,874 @@ pub fn register(vm: &mut Vm) {
     );
 }
 
-// These have actual implementation (run_pending_threads)
-fn wait_for_single_object(vm: &mut Vm, _stack_ptr: u32) -> u32 {
-    let _ = vm.run_pending_threads();
+const CREATE_EVENT_MANUAL_RESET: u32 = 0x1;
+const CREATE_EVENT_INITIAL_SET: u32 = 0x2;
+const CREATE_MUTEX_INITIAL_OWNER: u32 = 0x1;
+const CONDITION_VARIABLE_LOCKMODE_SHARED: u32 = 0x1;
+const MAXIMUM_WAIT_OBJECTS: u32 = 64;
+const INIT_ONCE_CHECK_ONLY: u32 = 0x1;
+const INIT_ONCE_ASYNC: u32 = 0x2;
+const INIT_ONCE_INIT_FAILED: u32 = 0x4;
+// The low bits of an INIT_ONCE context are the object's own state.
+const INIT_ONCE_CTX_RESERVED_MASK: u32 = 0x3;
+const ERROR_INVALID_PARAMETER: u32 = 87;
+
+// An empty name creates an unnamed object.
+fn object_name(name: String) -> Option<String> {
+    (!name.is_empty()).then_some(name)
+}
+
+fn event(manual_reset: bool, initial_state: bool) -> SyncObject {
+    SyncObject::Event {
+        manual_reset,
+        signaled: initial_state,
+    }
+}
+
+fn semaphore(initial: u32, maximum: u32) -> Option<SyncObject> {
+    (maximum > 0 && (initial as i32) >= 0 && initial <= maximum).then_some(SyncObject::Semaphore {
+        count: initial,
+        maximum,
+    })
+}
+
+fn create_event(vm: &mut Vm, manual_reset: u32, initial_state: u32, name: String) -> u32 {
+    vm.create_sync_object(
+        event(manual_reset != 0, initial_state != 0),
+        object_name(name),
+    )
+}
+
+fn create_event_a(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (_attrs, manual_reset, initial_state, name) = vm_args!(vm, stack_ptr; u32, u32, u32, str);
+    create_event(vm, manual_reset, initial_state, name)
+}
+
+fn create_event_w(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (_attrs, manual_reset, initial_state, name) = vm_args!(vm, stack_ptr; u32, u32, u32, wstr);
+    create_event(vm, manual_reset, initial_state, name)
+}
+
+fn create_event_ex_a(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (_attrs, name, flags, _access) = vm_args!(vm, stack_ptr; u32, str, u32, u32);
+    create_event(
+        vm,
+        flags & CREATE_EVENT_MANUAL_RESET,
+        flags & CREATE_EVENT_INITIAL_SET,
+        name,
+    )
+}
+
+pub(super) fn create_event_ex_w(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (_attrs, name, flags, _access) = vm_args!(vm, stack_ptr; u32, wstr, u32, u32);
+    create_event(
+        vm,
+        flags & CREATE_EVENT_MANUAL_RESET,
+        flags & CREATE_EVENT_INITIAL_SET,
+        name,
+    )
+}
+
+fn open_event_a(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (_access, _inherit, name) = vm_args!(vm, stack_ptr; u32, u32, str);
+    vm.open_sync_object(&name, &event(false, false))
+}
+
+fn open_event_w(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (_access, _inherit, name) = vm_args!(vm, stack_ptr; u32, u32, wstr);
+    vm.open_sync_object(&name, &event(false, false))
+}
+
+fn set_event(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (handle,) = vm_args!(vm, stack_ptr; u32);
+    vm.set_event(handle, true) as u32
+}
+
+fn reset_event(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (handle,) = vm_args!(vm, stack_ptr; u32);
+    vm.set_event(handle, false) as u32
+}
+
+fn pulse_event(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (handle,) = vm_args!(vm, stack_ptr; u32);
+    vm.pulse_event(handle) as u32
+}
+
+fn create_mutex(vm: &mut Vm, initial_owner: bool, name: String) -> u32 {
+    let owner = initial_owner.then(|| vm.current_thread_id());
+    vm.create_sync_object(SyncObject::mutex(owner), object_name(name))
+}
+
+fn create_mutex_a(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (_attrs, initial_owner, name) = vm_args!(vm, stack_ptr; u32, u32, str);
+    create_mutex(vm, initial_owner != 0, name)
+}
+
+fn create_mutex_w(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (_attrs, initial_owner, name) = vm_args!(vm, stack_ptr; u32, u32, wstr);
+    create_mutex(vm, initial_owner != 0, name)
+}
+
+fn create_mutex_ex_a(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (_attrs, name, flags, _access) = vm_args!(vm, stack_ptr; u32, str, u32, u32);
+    create_mutex(vm, flags & CREATE_MUTEX_INITIAL_OWNER != 0, name)
+}
+
+fn create_mutex_ex_w(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (_attrs, name, flags, _access) = vm_args!(vm, stack_ptr; u32, wstr, u32, u32);
+    create_mutex(vm, flags & CREATE_MUTEX_INITIAL_OWNER != 0, name)
+}
+
+fn open_mutex_a(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (_access, _inherit, name) = vm_args!(vm, stack_ptr; u32, u32, str);
+    vm.open_sync_object(&name, &SyncObject::mutex(None))
+}
+
+fn open_mutex_w(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (_access, _inherit, name) = vm_args!(vm, stack_ptr; u32, u32, wstr);
+    vm.open_sync_object(&name, &SyncObject::mutex(None))
+}
+
+fn release_mutex(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (handle,) = vm_args!(vm, stack_ptr; u32);
+    vm.release_mutex(handle) as u32
+}
+
+fn create_semaphore(vm: &mut Vm, initial: u32, maximum: u32, name: String) -> u32 {
+    let Some(object) = semaphore(initial, maximum) else {
+        vm.set_last_error(ERROR_INVALID_PARAMETER);
+        return 0;
+    };
+    vm.create_sync_object(object, object_name(name))
+}
+
+fn create_semaphore_a(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (_attrs, initial, maximum, name) = vm_args!(vm, stack_ptr; u32, u32, u32, str);
+    create_semaphore(vm, initial, maximum, name)
+}
+
+fn create_semaphore_w(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (_attrs, initial, maximum, name) = vm_args!(vm, stack_ptr; u32, u32, u32, wstr);
+    create_semaphore(vm, initial, maximum, name)
+}
+
+fn create_semaphore_ex_a(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (_attrs, initial, maximum, name) = vm_args!(vm, stack_ptr; u32, u32, u32, str);
+    create_semaphore(vm, initial, maximum, name)
+}
+
+pub(super) fn create_semaphore_ex_w(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (_attrs, initial, maximum, name) = vm_args!(vm, stack_ptr; u32, u32, u32, wstr);
+    create_semaphore(vm, initial, maximum, name)
+}
+
+fn open_semaphore_a(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (_access, _inherit, name) = vm_args!(vm, stack_ptr; u32, u32, str);
+    vm.open_sync_object(
+        &name,
+        &SyncObject::Semaphore {
+            count: 0,
+            maximum: 0,
+        },
+    )
+}
+
+fn open_semaphore_w(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (_access, _inherit, name) = vm_args!(vm, stack_ptr; u32, u32, wstr);
+    vm.open_sync_object(
+        &name,
+        &SyncObject::Semaphore {
+            count: 0,
+            maximum: 0,
+        },
+    )
+}
+
+fn release_semaphore(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (handle, count, previous_ptr) = vm_args!(vm, stack_ptr; u32, u32, u32);
+    let Some(previous) = vm.release_semaphore(handle, count) else {
+        return 0;
+    };
+    if previous_ptr != 0 {
+        let _ = vm.write_u32(previous_ptr, previous);
+    }
+    1
+}
+
+fn wait_for_single_object(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (handle, timeout) = vm_args!(vm, stack_ptr; u32, u32);
+    vm.wait_for_objects(&[handle], false, timeout)
+}
+
+fn wait_for_single_object_ex(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (handle, timeout, _alertable) = vm_args!(vm, stack_ptr; u32, u32, u32);
+    vm.wait_for_objects(&[handle], false, timeout)
+}
+
+fn wait_for_multiple(
+    vm: &mut Vm,
+    count: u32,
+    handles_ptr: u32,
+    wait_all: u32,
+    timeout: u32,
+) -> u32 {
+    if count == 0 || count > MAXIMUM_WAIT_OBJECTS {
+        vm.set_last_error(ERROR_INVALID_PARAMETER);
+        return WAIT_FAILED;
+    }
+    let mut handles = Vec::with_capacity(count as usize);
+    for index in 0..count {
+        match vm.read_u32(handles_ptr.wrapping_add(index * 4)) {
+            Ok(handle) => handles.push(handle),
+            Err(_) => {
+                vm.set_last_error(ERROR_INVALID_PARAMETER);
+                return WAIT_FAILED;
+            }
+        }
+    }
+    vm.wait_for_objects(&handles, wait_all != 0, timeout)
+}
+
+fn wait_for_multiple_objects(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (count, handles_ptr, wait_all, timeout) = vm_args!(vm, stack_ptr; u32, u32, u32, u32);
+    wait_for_multiple(vm, count, handles_ptr, wait_all, timeout)
+}
+
+fn wait_for_multiple_objects_ex(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (count, handles_ptr, wait_all, timeout, _alertable) =
+        vm_args!(vm, stack_ptr; u32, u32, u32, u32, u32);
+    wait_for_multiple(vm, count, handles_ptr, wait_all, timeout)
+}
+
+fn signal_object_and_wait(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (signal, wait, timeout, _alertable) = vm_args!(vm, stack_ptr; u32, u32, u32, u32);
+    let signaled = match vm.sync_object(signal) {
+        Some(SyncObject::Event { .. }) => vm.set_event(signal, true),
+        Some(SyncObject::Mutex { .. }) => vm.release_mutex(signal),
+        Some(SyncObject::Semaphore { .. }) => vm.release_semaphore(signal, 1).is_some(),
+        _ => false,
+    };
+    if !signaled {
+        return WAIT_FAILED;
+    }
+    vm.wait_for_objects(&[wait], false, timeout)
+}
+
+fn sleep(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (millis,) = vm_args!(vm, stack_ptr; u32);
+    vm.sleep_current(millis);
     0
 }
 
-fn sleep(vm: &mut Vm, _stack_ptr: u32) -> u32 {
-    let _ = vm.run_pending_threads();
+fn sleep_ex(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (millis, _alertable) = vm_args!(vm, stack_ptr; u32, u32);
+    vm.sleep_current(millis);
     0
 }
+
+fn initialize_critical_section(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (cs,) = vm_args!(vm, stack_ptr; u32);
+    vm.initialize_critical_section(cs, 0);
+    0
+}
+
+fn initialize_critical_section_and_spin_count(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (cs, spin_count) = vm_args!(vm, stack_ptr; u32, u32);
+    vm.initialize_critical_section(cs, spin_count);
+    1
+}
+
+pub(super) fn initialize_critical_section_ex(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (cs, spin_count, _flags) = vm_args!(vm, stack_ptr; u32, u32, u32);
+    vm.initialize_critical_section(cs, spin_count);
+    1
+}
+
+fn enter_critical_section(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (cs,) = vm_args!(vm, stack_ptr; u32);
+    vm.enter_critical_section(cs);
+    0
+}
+
+fn try_enter_critical_section(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (cs,) = vm_args!(vm, stack_ptr; u32);
+    vm.try_enter_critical_section(cs) as u32
+}
+
+fn leave_critical_section(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (cs,) = vm_args!(vm, stack_ptr; u32);
+    vm.leave_critical_section(cs);
+    0
+}
+
+fn delete_critical_section(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (cs,) = vm_args!(vm, stack_ptr; u32);
+    vm.delete_critical_section(cs);
+    0
+}
+
+fn set_critical_section_spin_count(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (cs, spin_count) = vm_args!(vm, stack_ptr; u32, u32);
+    vm.set_critical_section_spin_count(cs, spin_count)
+}
+
+fn initialize_srw_lock(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (lock,) = vm_args!(vm, stack_ptr; u32);
+    vm.initialize_srw_lock(lock);
+    0
+}
+
+fn acquire_srw_lock_exclusive(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (lock,) = vm_args!(vm, stack_ptr; u32);
+    vm.acquire_srw_lock(lock, false);
+    0
+}
+
+fn acquire_srw_lock_shared(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (lock,) = vm_args!(vm, stack_ptr; u32);
+    vm.acquire_srw_lock(lock, true);
+    0
+}
+
+fn release_srw_lock_exclusive(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (lock,) = vm_args!(vm, stack_ptr; u32);
+    vm.release_srw_lock(lock, false);
+    0
+}
+
+fn release_srw_lock_shared(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (lock,) = vm_args!(vm, stack_ptr; u32);
+    vm.release_srw_lock(lock, true);
+    0
+}
+
+fn try_acquire_srw_lock_exclusive(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (lock,) = vm_args!(vm, stack_ptr; u32);
+    vm.try_acquire_srw_lock(lock, false) as u32
+}
+
+fn try_acquire_srw_lock_shared(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (lock,) = vm_args!(vm, stack_ptr; u32);
+    vm.try_acquire_srw_lock(lock, true) as u32
+}
+
+fn initialize_condition_variable(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (cv,) = vm_args!(vm, stack_ptr; u32);
+    vm.initialize_condition_variable(cv);
+    0
+}
+
+fn wake_condition_variable(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (cv,) = vm_args!(vm, stack_ptr; u32);
+    vm.wake_condition_variable(cv, false);
+    0
+}
+
+fn wake_all_condition_variable(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (cv,) = vm_args!(vm, stack_ptr; u32);
+    vm.wake_condition_variable(cv, true);
+    0
+}
+
+fn sleep_condition_variable_cs(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (cv, cs, timeout) = vm_args!(vm, stack_ptr; u32, u32, u32);
+    vm.sleep_condition_variable_cs(cv, cs, timeout)
+}
+
+fn sleep_condition_variable_srw(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (cv, lock, timeout, flags) = vm_args!(vm, stack_ptr; u32, u32, u32, u32);
+    let shared = flags & CONDITION_VARIABLE_LOCKMODE_SHARED != 0;
+    vm.sleep_condition_variable_srw(cv, lock, timeout, shared)
+}
+
+fn init_once_begin_initialize(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (init_once, flags, pending, context) = vm_args!(vm, stack_ptr; u32, u32, u32, u32);
+    if flags & !(INIT_ONCE_CHECK_ONLY | INIT_ONCE_ASYNC) != 0 || pending == 0 {
+        vm.set_last_error(ERROR_INVALID_PARAMETER);
+        return 0;
+    }
+    vm.begin_init_once(
+        init_once,
+        flags & INIT_ONCE_CHECK_ONLY != 0,
+        flags & INIT_ONCE_ASYNC != 0,
+        pending,
+        context,
+    )
+}
+
+fn init_once_complete(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (init_once, flags, context) = vm_args!(vm, stack_ptr; u32, u32, u32);
+    let failed = flags & INIT_ONCE_INIT_FAILED != 0;
+    let asynchronous = flags & INIT_ONCE_ASYNC != 0;
+    if flags & !(INIT_ONCE_ASYNC | INIT_ONCE_INIT_FAILED) != 0
+        || (failed && asynchronous)
+        || context & INIT_ONCE_CTX_RESERVED_MASK != 0
+    {
+        vm.set_last_error(ERROR_INVALID_PARAMETER);
+        return 0;
+    }
+    vm.complete_init_once(init_once, context, failed, asynchronous) as u32
+}
+
+fn init_once_execute_once(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (init_once, callback, param, context) = vm_args!(vm, stack_ptr; u32, u32, u32, u32);
+    vm.execute_init_once(init_once, callback, param, context)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::{
+        Architecture, VmConfig, VmError, INFINITE, MAIN_THREAD_ID, WAIT_ABANDONED_0, WAIT_OBJECT_0,
+        WAIT_TIMEOUT,
+    };
+
+    const MAIN: u32 = 0x1000;
+    const WORKER_A: u32 = 0x1100;
+    const WORKER_B: u32 = 0x1200;
+    const CALLBACK: u32 = 0x1300;
+    const DATA: u32 = 0x1800;
+    const CS: u32 = DATA + 0x100;
+    const CV: u32 = DATA + 0x120;
+    const SRW: u32 = DATA + 0x140;
+    const CV_SRW: u32 = DATA + 0x150;
+    const ERROR_INVALID_HANDLE: u32 = 6;
+    const ERROR_TIMEOUT: u32 = 1460;
+
+    fn create_test_vm() -> Vm {
+        let mut vm = Vm::new(VmConfig::new().architecture(Architecture::X86)).expect("vm");
+        vm.memory.map(0x1000, 0x10000);
+        vm.base = 0x1000;
+        vm.stack_top = 0x1000 + 0x10000 - 4;
+        vm.regs.esp = vm.stack_top;
+        vm.heap_start = 0x2000;
+        vm.heap_end = 0x8000;
+        vm
+    }
+
+    // push args right to left; mov eax, <stub>; call eax
+    fn call(vm: &mut Vm, code: &mut Vec<u8>, name: &str, args: &[u32]) {
+        for &arg in args.iter().rev() {
+            code.push(0x68);
+            code.extend_from_slice(&arg.to_le_bytes());
+        }
+        let addr = vm.resolve_host_proc(DLL_NAME, name).expect("stub");
+        code.push(0xB8);
+        code.extend_from_slice(&addr.to_le_bytes());
+        code.extend_from_slice(&[0xFF, 0xD0]);
+    }
+
+    // mov [addr], eax
+    fn store_eax(code: &mut Vec<u8>, addr: u32) {
+        code.push(0xA3);
+        code.extend_from_slice(&addr.to_le_bytes());
+    }
+
+    // mov eax, [from]; mov [to], eax
+    fn copy(code: &mut Vec<u8>, from: u32, to: u32) {
+        code.push(0xA1);
+        code.extend_from_slice(&from.to_le_bytes());
+        store_eax(code, to);
+    }
+
+    // mov dword [addr], value
+    fn set(code: &mut Vec<u8>, addr: u32, value: u32) {
+        code.extend_from_slice(&[0xC7, 0x05]);
+        code.extend_from_slice(&addr.to_le_bytes());
+        code.extend_from_slice(&value.to_le_bytes());
+    }
+
+    // inc dword [addr]
+    fn increment(code: &mut Vec<u8>, addr: u32) {
+        code.extend_from_slice(&[0xFF, 0x05]);
+        code.extend_from_slice(&addr.to_le_bytes());
+    }
+
+    fn load(vm: &mut Vm, addr: u32, code: &[u8]) {
+        vm.memory.write(addr, code).unwrap();
+    }
+
+    // Thread start routines end with `ret 4`, the main program with `ret`.
+    fn load_worker(vm: &mut Vm, addr: u32, mut code: Vec<u8>) -> u32 {
+        code.extend_from_slice(&[0xC2, 0x04, 0x00]);
+        load(vm, addr, &code);
+        let (handle, _) = vm.spawn_thread(addr, 0, 0, false).expect("thread");
+        handle
+    }
+
+    fn run_main(vm: &mut Vm, mut code: Vec<u8>) -> Result<(), VmError> {
+        code.push(0xC3);
+        load(vm, MAIN, &code);
+        vm.execute(MAIN)
+    }
+
+    fn new_event(vm: &mut Vm, manual_reset: bool, signaled: bool) -> u32 {
+        vm.create_sync_object(event(manual_reset, signaled), None)
+    }
+
+    #[test]
+    fn wait_any_reports_index_and_wait_all_needs_every_handle() {
+        let mut vm = create_test_vm();
+        let first = new_event(&mut vm, true, false);
+        let second = new_event(&mut vm, true, false);
+        let sem = vm.create_sync_object(semaphore(1, 1).unwrap(), None);
+        for (index, handle) in [first, second, first, sem].into_iter().enumerate() {
+            vm.write_u32(DATA + 0x40 + index as u32 * 4, handle)
+                .unwrap();
+        }
+        let mut worker = Vec::new();
+        call(&mut vm, &mut worker, "Sleep", &[10]);
+        call(&mut vm, &mut worker, "SetEvent", &[second]);
+        call(&mut vm, &mut worker, "Sleep", &[10]);
+        call(&mut vm, &mut worker, "SetEvent", &[first]);
+        load_worker(&mut vm, WORKER_A, worker);
+
+        let mut main = Vec::new();
+        call(
+            &mut vm,
+            &mut main,
+            "WaitForMultipleObjects",
+            &[2, DATA + 0x40, 0, INFINITE],
+        );
+        store_eax(&mut main, DATA);
+        // The semaphore is free all along, but must not be taken until `first` is set.
+        call(
+            &mut vm,
+            &mut main,
+            "WaitForMultipleObjects",
+            &[2, DATA + 0x48, 1, 0],
+        );
+        store_eax(&mut main, DATA + 4);
+        call(
+            &mut vm,
+            &mut main,
+            "WaitForMultipleObjects",
+            &[2, DATA + 0x48, 1, INFINITE],
+        );
+        store_eax(&mut main, DATA + 8);
+        run_main(&mut vm, main).expect("execute");
+
+        assert_eq!(vm.read_u32(DATA).unwrap(), WAIT_OBJECT_0 + 1);
+        assert_eq!(vm.read_u32(DATA + 4).unwrap(), WAIT_TIMEOUT);
+        assert_eq!(vm.read_u32(DATA + 8).unwrap(), WAIT_OBJECT_0);
+        assert!(matches!(
+            vm.sync_object(sem),
+            Some(SyncObject::Semaphore { count: 0, .. })
+        ));
+    }
+
+    #[test]
+    fn timeouts_follow_the_virtual_clock() {
+        let mut vm = create_test_vm();
+        let never = new_event(&mut vm, true, false);
+        let mut worker = Vec::new();
+        call(&mut vm, &mut worker, "Sleep", &[60]);
+        set(&mut worker, DATA + 0x20, 1);
+        load_worker(&mut vm, WORKER_A, worker);
+
+        let mut main = Vec::new();
+        call(&mut vm, &mut main, "WaitForSingleObject", &[never, 40]);
+        store_eax(&mut main, DATA);
+        copy(&mut main, DATA + 0x20, DATA + 4);
+        call(&mut vm, &mut main, "WaitForSingleObject", &[never, 40]);
+        store_eax(&mut main, DATA + 8);
+        copy(&mut main, DATA + 0x20, DATA + 0xC);
+        call(&mut vm, &mut main, "AcquireSRWLockShared", &[SRW]);
+        call(
+            &mut vm,
+            &mut main,
+            "SleepConditionVariableSRW",
+            &[CV_SRW, SRW, 30, CONDITION_VARIABLE_LOCKMODE_SHARED],
+        );
+        store_eax(&mut main, DATA + 0x10);
+        call(&mut vm, &mut main, "GetLastError", &[]);
+        store_eax(&mut main, DATA + 0x14);
+        call(&mut vm, &mut main, "ReleaseSRWLockShared", &[SRW]);
+        run_main(&mut vm, main).expect("execute");
+
+        // The worker wakes at 60, between the first timeout at 40 and the second at 80.
+        assert_eq!(vm.read_u32(DATA).unwrap(), WAIT_TIMEOUT);
+        assert_eq!(vm.read_u32(DATA + 4).unwrap(), 0);
+        assert_eq!(vm.read_u32(DATA + 8).unwrap(), WAIT_TIMEOUT);
+        assert_eq!(vm.read_u32(DATA + 0xC).unwrap(), 1);
+        assert_eq!(vm.read_u32(DATA + 0x10).unwrap(), 0);
+        assert_eq!(vm.read_u32(DATA + 0x14).unwrap(), ERROR_TIMEOUT);
+    }
+
+    // Two workers wait on one event; returns how many got through one SetEvent.
+    fn waiters_released_by_one_set(manual_reset: bool) -> u32 {
+        let mut vm = create_test_vm();
+        let event = new_event(&mut vm, manual_reset, false);
+        let mut worker = Vec::new();
+        call(
+            &mut vm,
+            &mut worker,
+            "WaitForSingleObject",
+            &[event, INFINITE],
+        );
+        increment(&mut worker, DATA);
+        let code = worker.clone();
+        let a = load_worker(&mut vm, WORKER_A, worker);
+        let b = load_worker(&mut vm, WORKER_B, code);
+        vm.write_u32(DATA + 0x40, a).unwrap();
+        vm.write_u32(DATA + 0x44, b).unwrap();
+
+        let mut main = Vec::new();
+        call(&mut vm, &mut main, "Sleep", &[10]);
+        call(&mut vm, &mut main, "SetEvent", &[event]);
+        call(&mut vm, &mut main, "Sleep", &[10]);
+        copy(&mut main, DATA, DATA + 4);
+        call(&mut vm, &mut main, "SetEvent", &[event]);
+        call(
+            &mut vm,
+            &mut main,
+            "WaitForMultipleObjects",
+            &[2, DATA + 0x40, 1, INFINITE],
+        );
+        run_main(&mut vm, main).expect("execute");
+        assert_eq!(vm.read_u32(DATA).unwrap(), 2);
+        vm.read_u32(DATA + 4).unwrap()
+    }
+
+    #[test]
+    fn auto_reset_wakes_one_waiter_and_manual_reset_wakes_all() {
+        assert_eq!(waiters_released_by_one_set(false), 1);
+        assert_eq!(waiters_released_by_one_set(true), 2);
+    }
+
+    #[test]
+    fn mutex_left_by_exited_thread_is_abandoned() {
+        let mut vm = create_test_vm();
+        let mutex = vm.create_sync_object(SyncObject::mutex(None), None);
+        let mut worker = Vec::new();
+        call(
+            &mut vm,
+            &mut worker,
+            "WaitForSingleObject",
+            &[mutex, INFINITE],
+        );
+        let thread = load_worker(&mut vm, WORKER_A, worker);
+
+        let mut main = Vec::new();
+        call(
+            &mut vm,
+            &mut main,
+            "WaitForSingleObject",
+            &[thread, INFINITE],
+        );
+        call(&mut vm, &mut main, "WaitForSingleObject", &[mutex, 0]);
+        store_eax(&mut main, DATA);
+        call(&mut vm, &mut main, "WaitForSingleObject", &[mutex, 0]);
+        store_eax(&mut main, DATA + 4);
+        run_main(&mut vm, main).expect("execute");
+
+        assert_eq!(vm.read_u32(DATA).unwrap(), WAIT_ABANDONED_0);
+        assert_eq!(vm.read_u32(DATA + 4).unwrap(), WAIT_OBJECT_0);
+    }
+
+    #[test]
+    fn critical_section_recursion_survives_a_condition_wait() {
+        let mut vm = create_test_vm();
+        // The section is free while main sleeps on the condition variable.
+        let mut worker = Vec::new();
+        call(&mut vm, &mut worker, "TryEnterCriticalSection", &[CS]);
+        store_eax(&mut worker, DATA + 0x20);
+        call(&mut vm, &mut worker, "LeaveCriticalSection", &[CS]);
+        load_worker(&mut vm, WORKER_A, worker);
+
+        let mut main = Vec::new();
+        call(&mut vm, &mut main, "InitializeCriticalSection", &[CS]);
+        call(&mut vm, &mut main, "EnterCriticalSection", &[CS]);
+        call(&mut vm, &mut main, "EnterCriticalSection", &[CS]);
+        copy(&mut main, CS + 8, DATA);
+        copy(&mut main, CS + 0xC, DATA + 4);
+        call(
+            &mut vm,
+            &mut main,
+            "SleepConditionVariableCS",
+            &[CV, CS, 50],
+        );
+        store_eax(&mut main, DATA + 8);
+        call(&mut vm, &mut main, "GetLastError", &[]);
+        store_eax(&mut main, DATA + 0xC);
+        copy(&mut main, CS + 8, DATA + 0x10);
+        copy(&mut main, CS + 0xC, DATA + 0x14);
+        call(&mut vm, &mut main, "LeaveCriticalSection", &[CS]);
+        copy(&mut main, CS + 8, DATA + 0x18);
+        call(&mut vm, &mut main, "LeaveCriticalSection", &[CS]);
+        run_main(&mut vm, main).expect("execute");
+
+        assert_eq!(vm.read_u32(DATA).unwrap(), 2);
+        assert_eq!(vm.read_u32(DATA + 4).unwrap(), MAIN_THREAD_ID);
+        assert_eq!(vm.read_u32(DATA + 8).unwrap(), 0);
+        assert_eq!(vm.read_u32(DATA + 0xC).unwrap(), ERROR_TIMEOUT);
+        assert_eq!(vm.read_u32(DATA + 0x10).unwrap(), 2);
+        assert_eq!(vm.read_u32(DATA + 0x14).unwrap(), MAIN_THREAD_ID);
+        assert_eq!(vm.read_u32(DATA + 0x18).unwrap(), 1);
+        assert_eq!(vm.read_u32(DATA + 0x20).unwrap(), 1);
+        assert_eq!(vm.read_u32(CS + 4).unwrap(), u32::MAX);
+        assert_eq!(vm.read_u32(CS + 8).unwrap(), 0);
+        assert_eq!(vm.read_u32(CS + 0xC).unwrap(), 0);
+    }
+
+    #[test]
+    fn condition_variables_wake_sleepers_under_both_locks() {
+        let mut vm = create_test_vm();
+        let mut srw_waker = Vec::new();
+        call(&mut vm, &mut srw_waker, "Sleep", &[10]);
+        call(&mut vm, &mut srw_waker, "AcquireSRWLockExclusive", &[SRW]);
+        set(&mut srw_waker, DATA + 0x20, 1);
+        call(&mut vm, &mut srw_waker, "WakeConditionVariable", &[CV_SRW]);
+        call(&mut vm, &mut srw_waker, "ReleaseSRWLockExclusive", &[SRW]);
+        load_worker(&mut vm, WORKER_A, srw_waker);
+        let mut cs_waker = Vec::new();
+        call(&mut vm, &mut cs_waker, "Sleep", &[50]);
+        call(&mut vm, &mut cs_waker, "EnterCriticalSection", &[CS]);
+        set(&mut cs_waker, DATA + 0x24, 1);
+        call(&mut vm, &mut cs_waker, "WakeAllConditionVariable", &[CV]);
+        call(&mut vm, &mut cs_waker, "LeaveCriticalSection", &[CS]);
+        load_worker(&mut vm, WORKER_B, cs_waker);
+
+        let mut main = Vec::new();
+        call(&mut vm, &mut main, "InitializeCriticalSection", &[CS]);
+        call(&mut vm, &mut main, "AcquireSRWLockExclusive", &[SRW]);
+        call(
+            &mut vm,
+            &mut main,
+            "SleepConditionVariableSRW",
+            &[CV_SRW, SRW, INFINITE, 0],
+        );
+        store_eax(&mut main, DATA);
+        copy(&mut main, DATA + 0x20, DATA + 4);
+        call(&mut vm, &mut main, "ReleaseSRWLockExclusive", &[SRW]);
+        call(&mut vm, &mut main, "EnterCriticalSection", &[CS]);
+        call(
+            &mut vm,
+            &mut main,
+            "SleepConditionVariableCS",
+            &[CV, CS, INFINITE],
+        );
+        store_eax(&mut main, DATA + 8);
+        copy(&mut main, DATA + 0x24, DATA + 0xC);
+        copy(&mut main, CS + 0xC, DATA + 0x10);
+        call(&mut vm, &mut main, "LeaveCriticalSection", &[CS]);
+        run_main(&mut vm, main).expect("execute");
+
+        assert_eq!(vm.read_u32(DATA).unwrap(), 1);
+        assert_eq!(vm.read_u32(DATA + 4).unwrap(), 1);
+        assert_eq!(vm.read_u32(DATA + 8).unwrap(), 1);
+        assert_eq!(vm.read_u32(DATA + 0xC).unwrap(), 1);
+        assert_eq!(vm.read_u32(DATA + 0x10).unwrap(), MAIN_THREAD_ID);
+    }
+
+    #[test]
+    fn every_thread_blocked_is_a_deadlock() {
+        let mut vm = create_test_vm();
+        let first = new_event(&mut vm, false, false);
+        let second = new_event(&mut vm, false, false);
+        let mut worker = Vec::new();
+        call(
+            &mut vm,
+            &mut worker,
+            "WaitForSingleObject",
+            &[second, INFINITE],
+        );
+        call(&mut vm, &mut worker, "SetEvent", &[first]);
+        load_worker(&mut vm, WORKER_A, worker);
+        let mut main = Vec::new();
+        call(
+            &mut vm,
+            &mut main,
+            "WaitForSingleObject",
+            &[first, INFINITE],
+        );
+        call(&mut vm, &mut main, "SetEvent", &[second]);
+
+        assert!(matches!(run_main(&mut vm, main), Err(VmError::Deadlock)));
+        assert_eq!(vm.current_thread_id(), MAIN_THREAD_ID);
+    }
+
+    #[test]
+    fn waits_on_unknown_handles_fail() {
+        let mut vm = create_test_vm();
+        let event = new_event(&mut vm, true, true);
+        assert_eq!(vm.wait_for_objects(&[event], false, 0), WAIT_OBJECT_0);
+        assert_eq!(vm.wait_for_objects(&[event, 0x1234], false, 0), WAIT_FAILED);
+        assert_eq!(vm.last_error(), ERROR_INVALID_HANDLE);
+        assert!(vm.close_sync_handle(event));
+        assert_eq!(vm.wait_for_objects(&[event], false, INFINITE), WAIT_FAILED);
+    }
+
+    #[test]
+    fn execute_once_runs_the_callback_once_for_concurrent_callers() {
+        let mut vm = create_test_vm();
+        let once = DATA + 0x60;
+        // Count the call, give the worker time to arrive, then store the context.
+        let mut callback = Vec::new();
+        increment(&mut callback, DATA + 0x10);
+        call(&mut vm, &mut callback, "Sleep", &[20]);
+        callback.extend_from_slice(&[0x8B, 0x44, 0x24, 0x0C]); // mov eax, [esp+12]
+        callback.extend_from_slice(&[0xC7, 0x00, 0x40, 0x44, 0x00, 0x00]); // mov [eax], 0x4440
+        callback.extend_from_slice(&[0xB8, 0x01, 0x00, 0x00, 0x00, 0xC2, 0x0C, 0x00]);
+        load(&mut vm, CALLBACK, &callback);
+
+        let mut worker = Vec::new();
+        call(
+            &mut vm,
+            &mut worker,
+            "InitOnceExecuteOnce",
+            &[once, CALLBACK, 0, DATA + 0x24],
+        );
+        store_eax(&mut worker, DATA + 0x28);
+        let thread = load_worker(&mut vm, WORKER_A, worker);
+
+        let mut main = Vec::new();
+        call(
+            &mut vm,
+            &mut main,
+            "InitOnceExecuteOnce",
+            &[once, CALLBACK, 0, DATA + 0x20],
+        );
+        store_eax(&mut main, DATA + 0x2C);
+        call(
+            &mut vm,
+            &mut main,
+            "WaitForSingleObject",
+            &[thread, INFINITE],
+        );
+        call(
+            &mut vm,
+            &mut main,
+            "InitOnceExecuteOnce",
+            &[once, CALLBACK, 0, DATA + 0x30],
+        );
+        store_eax(&mut main, DATA + 0x34);
+        run_main(&mut vm, main).expect("execute");
+
+        assert_eq!(vm.read_u32(DATA + 0x10).unwrap(), 1);
+        for offset in [0x20, 0x24, 0x30] {
+            assert_eq!(vm.read_u32(DATA + offset).unwrap(), 0x4440);
+        }
+        for offset in [0x28, 0x2C, 0x34] {
+            assert_eq!(vm.read_u32(DATA + offset).unwrap(), 1);
+        }
+    }
+
+    #[test]
+    fn begin_initialize_hands_over_after_a_failed_attempt() {
+        let mut vm = create_test_vm();
+        let (once, pending, context) = (DATA + 0x60, DATA + 0x64, DATA + 0x68);
+        assert_eq!(vm.begin_init_once(once, true, false, pending, context), 0);
+        assert_eq!(vm.begin_init_once(once, false, false, pending, context), 1);
+        assert_eq!(vm.read_u32(pending).unwrap(), 1);
+        assert!(vm.complete_init_once(once, 0, true, false));
+        assert_eq!(vm.begin_init_once(once, false, false, pending, context), 1);
+        assert_eq!(vm.read_u32(pending).unwrap(), 1);
+        assert!(vm.complete_init_once(once, 0x5550, false, false));
+
+        assert_eq!(vm.begin_init_once(once, true, false, pending, context), 1);
+        assert_eq!(vm.read_u32(pending).unwrap(), 0);
+        assert_eq!(vm.read_u32(context).unwrap(), 0x5550);
+        assert!(!vm.complete_init_once(once, 0x10, false, false));
+    }
+}
@@ -1,11 +1,11 @@
 //! Kernel32 thread-related stubs.
 
-use crate::define_stub_fn;
 use crate::vm::windows::kernel32::DLL_NAME;
 use crate::vm::Vm;
 use crate::vm_args;
 
-define_stub_fn!(DLL_NAME, exit_thread, 0);
+const CREATE_SUSPENDED: u32 = 0x4;
+const ERROR_INVALID_HANDLE: u32 = 6;
 
 pub fn register(vm: &mut Vm) {
     vm.register_import_stdcall(
@@ -26,23 +26,240 @@ pub fn register(vm: &mut Vm) {
         crate::vm::stdcall_args(1),
         exit_thread,
     );
+    vm.register_import_stdcall(
+        DLL_NAME,
+        "GetExitCodeThread",
+        crate::vm::stdcall_args(2),
+        get_exit_code_thread,
+    );
+    vm.register_import_stdcall(
+        DLL_NAME,
+        "ResumeThread",
+        crate::vm::stdcall_args(1),
+        resume_thread,
+    );
+    vm.register_import_stdcall(
+        DLL_NAME,
+        "SwitchToThread",
+        crate::vm::stdcall_args(0),
+        switch_to_thread,
+    );
 }
 
 pub(crate) fn create_thread(vm: &mut Vm, stack_ptr: u32) -> u32 {
-    let (_attrs, _stack_size, start, param, _flags, thread_id_ptr) =
+    let (_attrs, stack_size, start, param, flags, thread_id_ptr) =
         vm_args!(vm, stack_ptr; u32, u32, u32, u32, u32, u32);
 
     if start == 0 {
         return 0;
     }
 
-    let handle = vm.queue_thread(start, param);
+    let suspended = flags & CREATE_SUSPENDED != 0;
+    let Ok((handle, thread_id)) = vm.spawn_thread(start, param, stack_size, suspended) else {
+        return 0;
+    };
     if thread_id_ptr != 0 {
-        let _ = vm.write_u32(thread_id_ptr, handle);
+        let _ = vm.write_u32(thread_id_ptr, thread_id);
     }
     handle
 }
 
-fn get_current_thread_id(_vm: &mut Vm, _stack_ptr: u32) -> u32 {
+fn get_current_thread_id(vm: &mut Vm, _stack_ptr: u32) -> u32 {
+    vm.current_thread_id()
+}
+
+// Returning to address 0 ends a thread, so point the stub's return address there.
+fn exit_thread(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (exit_code,) = vm_args!(vm, stack_ptr; u32);
+    let _ = vm.write_u32(stack_ptr, 0);
+    exit_code
+}
+
+fn get_exit_code_thread(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (handle, exit_code_ptr) = vm_args!(vm, stack_ptr; u32, u32);
+    let Some(code) = vm.thread_exit_code(handle) else {
+        vm.set_last_error(ERROR_INVALID_HANDLE);
+        return 0;
+    };
+    if exit_code_ptr == 0 || vm.write_u32(exit_code_ptr, code).is_err() {
+        return 0;
+    }
     1
 }
+
+fn resume_thread(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (handle,) = vm_args!(vm, stack_ptr; u32);
+    vm.resume_thread(handle).unwrap_or_else(|| {
+        vm.set_last_error(ERROR_INVALID_HANDLE);
+        u32::MAX
+    })
+}
+
+fn switch_to_thread(vm: &mut Vm, _stack_ptr: u32) -> u32 {
+    vm.yield_thread();
+    1
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::{Architecture, VmConfig, VmError, MAIN_THREAD_ID, WAIT_OBJECT_0};
+
+    const MAIN: u32 = 0x1000;
+    const WORKER_A: u32 = 0x1100;
+    const WORKER_B: u32 = 0x1200;
+    const DATA: u32 = 0x1800;
+
+    fn create_test_vm(config: VmConfig) -> Vm {
+        let mut vm = Vm::new(config.architecture(Architecture::X86)).expect("vm");
+        vm.memory.map(0x1000, 0x10000);
+        vm.base = 0x1000;
+        vm.stack_top = 0x1000 + 0x10000 - 4;
+        vm.regs.esp = vm.stack_top;
+        vm.heap_start = 0x2000;
+        vm.heap_end = 0x8000;
+        vm
+    }
+
+    fn push(code: &mut Vec<u8>, value: u32) {
+        code.push(0x68);
+        code.extend_from_slice(&value.to_le_bytes());
+    }
+
+    // mov eax, <stub>; call eax
+    fn call(vm: &mut Vm, code: &mut Vec<u8>, name: &str) {
+        let addr = vm.resolve_host_proc(DLL_NAME, name).expect("stub");
+        code.push(0xB8);
+        code.extend_from_slice(&addr.to_le_bytes());
+        code.extend_from_slice(&[0xFF, 0xD0]);
+    }
+
+    // CreateThread(NULL, 0, start, param, flags, DATA + 4); the handle is left in EAX.
+    fn create(vm: &mut Vm, code: &mut Vec<u8>, start: u32, param: u32, flags: u32) {
+        push(code, DATA + 4);
+        push(code, flags);
+        push(code, param);
+        push(code, start);
+        push(code, 0);
+        push(code, 0);
+        call(vm, code, "CreateThread");
+    }
+
+    // WaitForSingleObject(esi, INFINITE)
+    fn join_esi(vm: &mut Vm, code: &mut Vec<u8>) {
+        push(code, 0xFFFF_FFFF);
+        code.push(0x56);
+        call(vm, code, "WaitForSingleObject");
+    }
+
+    fn load(vm: &mut Vm, addr: u32, code: &[u8]) {
+        vm.memory.write(addr, code).unwrap();
+    }
+
+    #[test]
+    fn join_thread_and_read_exit_code() {
+        let mut vm = create_test_vm(VmConfig::new());
+        // Worker: record its thread id, return param + 10.
+        let mut worker = Vec::new();
+        call(&mut vm, &mut worker, "GetCurrentThreadId");
+        worker.push(0xA3);
+        worker.extend_from_slice(&(DATA + 8).to_le_bytes());
+        worker.extend_from_slice(&[0x8B, 0x44, 0x24, 0x04, 0x83, 0xC0, 0x0A, 0xC2, 0x04, 0x00]);
+        load(&mut vm, WORKER_A, &worker);
+
+        let mut main = Vec::new();
+        create(&mut vm, &mut main, WORKER_A, 5, 0);
+        main.extend_from_slice(&[0x8B, 0xF0]); // mov esi, eax
+        join_esi(&mut vm, &mut main);
+        push(&mut main, DATA);
+        main.push(0x56);
+        call(&mut vm, &mut main, "GetExitCodeThread");
+        main.push(0xC3);
+        load(&mut vm, MAIN, &main);
+
+        vm.execute(MAIN).expect("execute");
+        assert_eq!(vm.regs.eax, 1);
+        assert_eq!(vm.read_u32(DATA).unwrap(), 15);
+        assert_eq!(vm.read_u32(DATA + 4).unwrap(), 2);
+        assert_eq!(vm.read_u32(DATA + 8).unwrap(), 2);
+        assert_eq!(vm.current_thread_id(), MAIN_THREAD_ID);
+    }
+
+    #[test]
+    fn quantum_expiry_preempts_spinning_thread() {
+        let mut vm = create_test_vm(VmConfig::new().thread_quantum(50));
+        // Worker A spins until worker B sets the flag.
+        load(
+            &mut vm,
+            WORKER_A,
+            &[
+                0x83, 0x3D, 0x10, 0x18, 0x00, 0x00, 0x00, // cmp dword [DATA+0x10], 0
+                0x74, 0xF7, // je -9
+                0xB8, 0x01, 0x00, 0x00, 0x00, // mov eax, 1
+                0xC2, 0x04, 0x00, // ret 4
+            ],
+        );
+        load(
+            &mut vm,
+            WORKER_B,
+            &[
+                0xC7, 0x05, 0x10, 0x18, 0x00, 0x00, 0x07, 0x00, 0x00,
+                0x00, // mov [DATA+0x10], 7
+                0x31, 0xC0, 0xC2, 0x04, 0x00, // xor eax, eax; ret 4
+            ],
+        );
+        let mut main = Vec::new();
+        create(&mut vm, &mut main, WORKER_A, 0, 0);
+        main.extend_from_slice(&[0x8B, 0xF0]);
+        create(&mut vm, &mut main, WORKER_B, 0, 0);
+        join_esi(&mut vm, &mut main);
+        main.push(0xC3);
+        load(&mut vm, MAIN, &main);
+
+        vm.execute(MAIN).expect("execute");
+        assert_eq!(vm.regs.eax, WAIT_OBJECT_0);
+        assert_eq!(vm.read_u32(DATA + 0x10).unwrap(), 7);
+    }
+
+    #[test]
+    fn sleeps_wake_in_virtual_time_order() {
+        let mut vm = create_test_vm(VmConfig::new());
+        // Worker: Sleep(100); mov [DATA+0x20], 1
+        let mut worker = Vec::new();
+        push(&mut worker, 100);
+        call(&mut vm, &mut worker, "Sleep");
+        worker.extend_from_slice(&[0xC7, 0x05, 0x20, 0x18, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00]);
+        worker.extend_from_slice(&[0xC2, 0x04, 0x00]);
+        load(&mut vm, WORKER_A, &worker);
+
+        // Main: Sleep(50); copy the flag to DATA+0x24; join.
+        let mut main = Vec::new();
+        create(&mut vm, &mut main, WORKER_A, 0, 0);
+        main.extend_from_slice(&[0x8B, 0xF0]);
+        push(&mut main, 50);
+        call(&mut vm, &mut main, "Sleep");
+        main.extend_from_slice(&[0xA1, 0x20, 0x18, 0x00, 0x00, 0xA3, 0x24, 0x18, 0x00, 0x00]);
+        join_esi(&mut vm, &mut main);
+        main.push(0xC3);
+        load(&mut vm, MAIN, &main);
+
+        vm.execute(MAIN).expect("execute");
+        assert_eq!(vm.read_u32(DATA + 0x24).unwrap(), 0);
+        assert_eq!(vm.read_u32(DATA + 0x20).unwrap(), 1);
+    }
+
+    #[test]
+    fn waiting_on_suspended_thread_deadlocks() {
+        let mut vm = create_test_vm(VmConfig::new());
+        load(&mut vm, WORKER_A, &[0xC2, 0x04, 0x00]);
+        let mut main = Vec::new();
+        create(&mut vm, &mut main, WORKER_A, 0, CREATE_SUSPENDED);
+        main.extend_from_slice(&[0x8B, 0xF0]);
+        join_esi(&mut vm, &mut main);
+        main.push(0xC3);
+        load(&mut vm, MAIN, &main);
+
+        assert!(matches!(vm.execute(MAIN), Err(VmError::Deadlock)));
+        assert_eq!(vm.current_thread_id(), MAIN_THREAD_ID);
+    }
+}
@@ -1,6 +1,8 @@
 //! CRT initialization and utility stubs for MSVCR100.dll.
 
-use crate::vm::Vm;
+use crate::vm::windows::core::except_handler4_common;
+use crate::vm::{Vm, DISPOSITION_CONTINUE_SEARCH};
+use crate::vm_args;
 
 const DLL: &str = "MSVCR100.dll";
 
@@ -15,12 +17,9 @@ define_stub_fn!(DLL, get_sbh_threshold, 0);
 define_stub_fn!(DLL, set_sbh_threshold, 0);
 define_stub_fn!(DLL, seh_longjmp_unwind, 0);
 define_stub_fn!(DLL, seh_longjmp_unwind4, 0);
-define_stub_fn!(DLL, local_unwind2, 0);
-define_stub_fn!(DLL, local_unwind4, 0);
-define_stub_fn!(DLL, global_unwind2, 0);
-define_stub_fn!(DLL, except_handler2, 0);
-define_stub_fn!(DLL, except_handler3, 0);
-define_stub_fn!(DLL, except_handler4, 0);
+// Frame handlers that are not implemented pass the exception on.
+define_stub_fn!(DLL, except_handler2, DISPOSITION_CONTINUE_SEARCH);
+define_stub_fn!(DLL, except_handler4, DISPOSITION_CONTINUE_SEARCH);
 define_stub_fn!(DLL, security_error_handler, 0);
 define_stub_fn!(DLL, security_init_cookie, 0);
 define_stub_fn!(DLL, crt_dbg_report_v, 0);
@@ -47,6 +46,7 @@ pub fn register(vm: &mut Vm) {
     vm.register_import(DLL, "_except_handler2", except_handler2);
     vm.register_import(DLL, "_except_handler3", except_handler3);
     vm.register_import(DLL, "_except_handler4", except_handler4);
+    vm.register_import(DLL, "_except_handler4_common", except_handler4_common);
     vm.register_import(DLL, "__security_error_handler", security_error_handler);
     vm.register_import(DLL, "__security_init_cookie", security_init_cookie);
     vm.register_import(DLL, "_CrtDbgReportV", crt_dbg_report_v);
@@ -55,3 +55,41 @@ pub fn register(vm: &mut Vm) {
     vm.register_import(DLL, "signal", signal_impl);
     vm.register_import(DLL, "raise", raise_impl);
 }
+
+fn except_handler3(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (record, frame, context, _dispatcher_context) = vm_args!(vm, stack_ptr; u32, u32, u32, u32);
+    let result = vm.scope_table_handler(None, record, frame, context);
+    frame_handler_result(vm, result)
+}
+
+fn local_unwind2(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (frame, stop_level) = vm_args!(vm, stack_ptr; u32, u32);
+    if let Err(err) = vm.local_unwind(None, frame, stop_level) {
+        vm.set_guest_fault(err);
+    }
+    0
+}
+
+fn local_unwind4(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (cookie_ptr, frame, stop_level) = vm_args!(vm, stack_ptr; u32, u32, u32);
+    let cookie = vm.read_u32(cookie_ptr).unwrap_or(0);
+    if let Err(err) = vm.local_unwind(Some(cookie), frame, stop_level) {
+        vm.set_guest_fault(err);
+    }
+    0
+}
+
+fn global_unwind2(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (frame,) = vm_args!(vm, stack_ptr; u32);
+    if let Err(err) = vm.unwind_frames(frame, 0) {
+        vm.set_guest_fault(err);
+    }
+    0
+}
+
+fn frame_handler_result(vm: &mut Vm, result: Result<u32, crate::vm::VmError>) -> u32 {
+    result.unwrap_or_else(|err| {
+        vm.set_guest_fault(err);
+        DISPOSITION_CONTINUE_SEARCH
+    })
+}
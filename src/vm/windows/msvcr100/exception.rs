@@ -1,15 +1,12 @@
 //! Exception handling stubs for MSVCR100.dll.
 #![allow(dead_code)]
 
+use crate::vm::windows::core::{cxx_frame_handler, cxx_throw_exception};
 use crate::vm::Vm;
 
 const DLL: &str = "MSVCR100.dll";
 
 // Exception handling
-define_stub_fn!(DLL, cxx_throw_exception, 0);
-define_stub_fn!(DLL, cxx_frame_handler, 0);
-define_stub_fn!(DLL, cxx_frame_handler2, 0);
-define_stub_fn!(DLL, cxx_frame_handler3, 0);
 define_stub_fn!(DLL, cxx_call_unwind_dtor, 0);
 define_stub_fn!(DLL, cxx_call_unwind_del_dtor, 0);
 define_stub_fn!(DLL, cxx_call_unwind_std_del_dtor, 0);
@@ -93,10 +90,15 @@ define_stub_fn!(DLL, unsupported_os_ctor, 0);
 
 pub fn register(vm: &mut Vm) {
     // C++ exception handling
-    vm.register_import(DLL, "_CxxThrowException", cxx_throw_exception);
+    vm.register_import_stdcall(
+        DLL,
+        "_CxxThrowException",
+        crate::vm::stdcall_args(2),
+        cxx_throw_exception,
+    );
     vm.register_import(DLL, "__CxxFrameHandler", cxx_frame_handler);
-    vm.register_import(DLL, "__CxxFrameHandler2", cxx_frame_handler2);
-    vm.register_import(DLL, "__CxxFrameHandler3", cxx_frame_handler3);
+    vm.register_import(DLL, "__CxxFrameHandler2", cxx_frame_handler);
+    vm.register_import(DLL, "__CxxFrameHandler3", cxx_frame_handler);
     vm.register_import(DLL, "__CxxCallUnwindDtor", cxx_call_unwind_dtor);
     vm.register_import(DLL, "__CxxCallUnwindDelDtor", cxx_call_unwind_del_dtor);
     vm.register_import(
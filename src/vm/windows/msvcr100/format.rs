@@ -0,0 +1,1145 @@
+//! The printf and scanf engine shared by every MSVCR100 formatting variant.
+//!
+//! Text is handled as UTF-16 code units. Narrow strings map each byte onto the
+//! unit of the same value, as the "C" locale does, so narrow text round-trips
+//! unchanged and wide text narrows to `?` past U+00FF.
+
+use crate::vm::Vm;
+
+/// A cursor over cdecl variadic arguments; a `va_list` is one too.
+#[derive(Debug, Clone, Copy)]
+pub(super) struct VaList {
+    ptr: u32,
+}
+
+impl VaList {
+    pub(super) fn new(ptr: u32) -> Self {
+        Self { ptr }
+    }
+
+    /// The variadic arguments of a cdecl call taking `fixed` named arguments.
+    pub(super) fn after(stack_ptr: u32, fixed: u32) -> Self {
+        Self::new(stack_ptr + 4 + fixed * 4)
+    }
+
+    fn next_u32(&mut self, vm: &Vm) -> u32 {
+        let value = vm.read_u32(self.ptr).unwrap_or(0);
+        self.ptr = self.ptr.wrapping_add(4);
+        value
+    }
+
+    fn next_u64(&mut self, vm: &Vm) -> u64 {
+        let value = vm.read_u64(self.ptr).unwrap_or(0);
+        self.ptr = self.ptr.wrapping_add(8);
+        value
+    }
+}
+
+/// Read a NUL-terminated narrow or wide string, at most `max` units of it.
+pub(super) fn read_units(vm: &Vm, ptr: u32, wide: bool, max: usize) -> Vec<u16> {
+    let mut units = Vec::new();
+    if ptr == 0 {
+        return units;
+    }
+    while units.len() < max {
+        let unit = if wide {
+            vm.read_u16(ptr.wrapping_add(units.len() as u32 * 2))
+        } else {
+            vm.read_u8(ptr.wrapping_add(units.len() as u32))
+                .map(u16::from)
+        };
+        match unit {
+            Ok(0) | Err(_) => break,
+            Ok(unit) => units.push(unit),
+        }
+    }
+    units
+}
+
+/// Store units as narrow or wide characters, without a terminator.
+pub(super) fn write_units(vm: &mut Vm, ptr: u32, units: &[u16], wide: bool) {
+    if wide {
+        for (index, unit) in units.iter().enumerate() {
+            let _ = vm.write_u16(ptr.wrapping_add(index as u32 * 2), *unit);
+        }
+    } else {
+        let _ = vm.write_bytes(ptr, &narrow(units));
+    }
+}
+
+pub(super) fn narrow(units: &[u16]) -> Vec<u8> {
+    units
+        .iter()
+        .map(|unit| u8::try_from(*unit).unwrap_or(b'?'))
+        .collect()
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+struct Flags {
+    left: bool,
+    plus: bool,
+    space: bool,
+    alt: bool,
+    zero: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Size {
+    Default,
+    Char,
+    Short,
+    Long,
+    LongLong,
+    LongDouble,
+    Wide,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Count {
+    None,
+    Fixed(usize),
+    Arg(usize),
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Spec {
+    flags: Flags,
+    width: Count,
+    precision: Count,
+    size: Size,
+    conv: u8,
+    arg: usize,
+}
+
+enum Piece {
+    Text(Vec<u16>),
+    Spec(Spec),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ArgKind {
+    Word,
+    Quad,
+}
+
+// Reads a format string, one unit at a time.
+struct Cursor<'a> {
+    fmt: &'a [u16],
+    pos: usize,
+}
+
+impl Cursor<'_> {
+    fn peek(&self) -> Option<u8> {
+        self.fmt
+            .get(self.pos)
+            .and_then(|unit| u8::try_from(*unit).ok())
+    }
+
+    fn eat(&mut self, ch: u8) -> bool {
+        let matched = self.peek() == Some(ch);
+        if matched {
+            self.pos += 1;
+        }
+        matched
+    }
+
+    fn eat_str(&mut self, text: &str) -> bool {
+        let end = self.pos + text.len();
+        let matched = self.fmt.len() >= end
+            && self.fmt[self.pos..end]
+                .iter()
+                .zip(text.bytes())
+                .all(|(unit, byte)| *unit == u16::from(byte));
+        if matched {
+            self.pos = end;
+        }
+        matched
+    }
+
+    fn number(&mut self) -> Option<usize> {
+        let start = self.pos;
+        let mut value = 0usize;
+        while let Some(digit @ b'0'..=b'9') = self.peek() {
+            value = value
+                .saturating_mul(10)
+                .saturating_add(usize::from(digit - b'0'));
+            self.pos += 1;
+        }
+        (self.pos > start).then_some(value)
+    }
+
+    // `n$`, the 1-based position of an argument in `_p` formats.
+    fn position(&mut self) -> Option<usize> {
+        let start = self.pos;
+        match self.number() {
+            Some(value) if value > 0 && self.eat(b'$') => Some(value - 1),
+            _ => {
+                self.pos = start;
+                None
+            }
+        }
+    }
+
+    fn size(&mut self) -> Size {
+        if self.eat_str("hh") {
+            Size::Char
+        } else if self.eat(b'h') {
+            Size::Short
+        } else if self.eat_str("ll") || self.eat_str("I64") || self.eat(b'j') {
+            Size::LongLong
+        } else if self.eat(b'l') {
+            Size::Long
+        } else if self.eat(b'L') {
+            Size::LongDouble
+        } else if self.eat_str("I32") || self.eat(b'I') || self.eat(b'z') || self.eat(b't') {
+            Size::Default
+        } else if self.eat(b'w') {
+            Size::Wide
+        } else {
+            Size::Default
+        }
+    }
+}
+
+// Arguments are numbered in order of use, or explicitly with `n$`.
+struct ArgTable {
+    kinds: Vec<Option<ArgKind>>,
+    next: usize,
+}
+
+impl ArgTable {
+    fn take(&mut self, position: Option<usize>, kind: ArgKind) -> usize {
+        let index = position.unwrap_or(self.next);
+        self.next = index + 1;
+        if self.kinds.len() <= index {
+            self.kinds.resize(index + 1, None);
+        }
+        self.kinds[index] = Some(kind);
+        index
+    }
+
+    fn count(&mut self, cursor: &mut Cursor) -> Count {
+        if cursor.eat(b'*') {
+            let position = cursor.position();
+            Count::Arg(self.take(position, ArgKind::Word))
+        } else {
+            cursor.number().map_or(Count::None, Count::Fixed)
+        }
+    }
+}
+
+fn parse(fmt: &[u16]) -> (Vec<Piece>, Vec<Option<ArgKind>>) {
+    let mut cursor = Cursor { fmt, pos: 0 };
+    let mut args = ArgTable {
+        kinds: Vec::new(),
+        next: 0,
+    };
+    let mut pieces = Vec::new();
+    let mut text = Vec::new();
+    while cursor.pos < fmt.len() {
+        let start = cursor.pos;
+        cursor.pos += 1;
+        if fmt[start] != u16::from(b'%') {
+            text.push(fmt[start]);
+            continue;
+        }
+        if cursor.eat(b'%') {
+            text.push(u16::from(b'%'));
+            continue;
+        }
+        let position = cursor.position();
+        let mut flags = Flags::default();
+        loop {
+            match cursor.peek() {
+                Some(b'-') => flags.left = true,
+                Some(b'+') => flags.plus = true,
+                Some(b' ') => flags.space = true,
+                Some(b'#') => flags.alt = true,
+                Some(b'0') => flags.zero = true,
+                _ => break,
+            }
+            cursor.pos += 1;
+        }
+        let width = args.count(&mut cursor);
+        let precision = if cursor.eat(b'.') {
+            match args.count(&mut cursor) {
+                Count::None => Count::Fixed(0),
+                count => count,
+            }
+        } else {
+            Count::None
+        };
+        let size = cursor.size();
+        let Some(conv) = cursor.peek() else {
+            break;
+        };
+        cursor.pos += 1;
+        let kind = match conv {
+            b'd' | b'i' | b'o' | b'u' | b'x' | b'X' if size == Size::LongLong => ArgKind::Quad,
+            b'd' | b'i' | b'o' | b'u' | b'x' | b'X' | b'c' | b'C' | b's' | b'S' | b'Z' | b'p'
+            | b'n' => ArgKind::Word,
+            b'e' | b'E' | b'f' | b'F' | b'g' | b'G' | b'a' | b'A' => ArgKind::Quad,
+            // Unknown conversions are copied through.
+            _ => {
+                text.extend_from_slice(&fmt[start..cursor.pos]);
+                continue;
+            }
+        };
+        let arg = args.take(position, kind);
+        if !text.is_empty() {
+            pieces.push(Piece::Text(std::mem::take(&mut text)));
+        }
+        pieces.push(Piece::Spec(Spec {
+            flags,
+            width,
+            precision,
+            size,
+            conv,
+            arg,
+        }));
+    }
+    if !text.is_empty() {
+        pieces.push(Piece::Text(text));
+    }
+    (pieces, args.kinds)
+}
+
+/// Format `fmt` with printf rules. `wide` selects the wprintf meaning of
+/// `%s`/`%c` (wide) versus `%S`/`%C` (narrow). Arguments may be numbered with
+/// `%n$` as the `_p` variants allow.
+pub(super) fn format(vm: &mut Vm, fmt: &[u16], args: VaList, wide: bool) -> Vec<u16> {
+    let (pieces, kinds) = parse(fmt);
+    let mut list = args;
+    let values: Vec<u64> = kinds
+        .iter()
+        .map(|kind| match kind {
+            Some(ArgKind::Quad) => list.next_u64(vm),
+            _ => u64::from(list.next_u32(vm)),
+        })
+        .collect();
+    let mut out = Vec::new();
+    for piece in pieces {
+        match piece {
+            Piece::Text(text) => out.extend(text),
+            Piece::Spec(spec) => render(vm, &spec, &values, wide, &mut out),
+        }
+    }
+    out
+}
+
+fn render(vm: &mut Vm, spec: &Spec, values: &[u64], wide: bool, out: &mut Vec<u16>) {
+    let value = |index: usize| values.get(index).copied().unwrap_or(0);
+    let mut flags = spec.flags;
+    let width = match spec.width {
+        Count::None => 0,
+        Count::Fixed(width) => width,
+        Count::Arg(index) => {
+            let width = value(index) as u32 as i32;
+            flags.left |= width < 0;
+            width.unsigned_abs() as usize
+        }
+    };
+    let precision = match spec.precision {
+        Count::None => None,
+        Count::Fixed(precision) => Some(precision),
+        Count::Arg(index) => usize::try_from(value(index) as u32 as i32).ok(),
+    };
+    let arg = value(spec.arg);
+    let (prefix, body) = match spec.conv {
+        b'd' | b'i' => {
+            let signed = match spec.size {
+                Size::Char => i64::from(arg as u8 as i8),
+                Size::Short => i64::from(arg as u16 as i16),
+                Size::LongLong => arg as i64,
+                _ => i64::from(arg as u32 as i32),
+            };
+            let sign = if signed < 0 {
+                "-"
+            } else if flags.plus {
+                "+"
+            } else if flags.space {
+                " "
+            } else {
+                ""
+            };
+            let digits = integer_digits(signed.unsigned_abs(), 10, false, precision);
+            flags.zero &= precision.is_none();
+            (sign.to_string(), digits)
+        }
+        b'u' | b'o' | b'x' | b'X' => {
+            let unsigned = match spec.size {
+                Size::Char => u64::from(arg as u8),
+                Size::Short => u64::from(arg as u16),
+                Size::LongLong => arg,
+                _ => u64::from(arg as u32),
+            };
+            let radix = match spec.conv {
+                b'u' => 10,
+                b'o' => 8,
+                _ => 16,
+            };
+            let mut digits = integer_digits(unsigned, radix, spec.conv == b'X', precision);
+            let mut prefix = String::new();
+            if flags.alt && radix == 8 && !digits.starts_with('0') {
+                digits.insert(0, '0');
+            } else if flags.alt && radix == 16 && unsigned != 0 {
+                prefix = if spec.conv == b'X' { "0X" } else { "0x" }.to_string();
+            }
+            flags.zero &= precision.is_none();
+            (prefix, digits)
+        }
+        b'p' => (String::new(), format!("{:08X}", arg as u32)),
+        b'e' | b'E' | b'f' | b'F' | b'g' | b'G' | b'a' | b'A' => {
+            float(f64::from_bits(arg), spec.conv, precision, flags)
+        }
+        b'n' => {
+            let ptr = arg as u32;
+            let count = out.len() as u64;
+            let _ = match spec.size {
+                Size::Short => vm.write_u16(ptr, count as u16),
+                Size::LongLong => vm.write_u64(ptr, count),
+                _ => vm.write_u32(ptr, count as u32),
+            };
+            return;
+        }
+        conv => {
+            // Characters and strings pad with spaces only.
+            let text = text_arg(vm, spec, conv, arg as u32, precision, wide);
+            pad(out, Vec::new(), text, width, flags.left, false);
+            return;
+        }
+    };
+    pad(
+        out,
+        ascii(&prefix),
+        ascii(&body),
+        width,
+        flags.left,
+        flags.zero,
+    );
+}
+
+fn text_arg(
+    vm: &Vm,
+    spec: &Spec,
+    conv: u8,
+    arg: u32,
+    precision: Option<usize>,
+    wide: bool,
+) -> Vec<u16> {
+    let wide_arg = match spec.size {
+        Size::Short => false,
+        Size::Long | Size::Wide => true,
+        _ if conv == b'Z' => false,
+        _ => conv.is_ascii_lowercase() == wide,
+    };
+    let limit = precision.unwrap_or(usize::MAX);
+    match conv {
+        b'c' | b'C' if wide_arg => vec![arg as u16],
+        b'c' | b'C' => vec![u16::from(arg as u8)],
+        // ANSI_STRING / UNICODE_STRING: byte length, capacity, buffer.
+        b'Z' => {
+            let length = vm.read_u16(arg).unwrap_or(0) as usize;
+            let buffer = vm.read_u32(arg.wrapping_add(4)).unwrap_or(0);
+            if arg == 0 || buffer == 0 {
+                return ascii("(null)");
+            }
+            let count = if wide_arg { length / 2 } else { length }.min(limit);
+            (0..count)
+                .map(|index| {
+                    if wide_arg {
+                        vm.read_u16(buffer.wrapping_add(index as u32 * 2))
+                            .unwrap_or(0)
+                    } else {
+                        vm.read_u8(buffer.wrapping_add(index as u32))
+                            .map_or(0, u16::from)
+                    }
+                })
+                .collect()
+        }
+        _ if arg == 0 => ascii("(null)").into_iter().take(limit).collect(),
+        _ => read_units(vm, arg, wide_arg, limit),
+    }
+}
+
+fn integer_digits(value: u64, radix: u32, upper: bool, precision: Option<usize>) -> String {
+    let mut digits = match (precision, value) {
+        (Some(0), 0) => String::new(),
+        _ => match (radix, upper) {
+            (8, _) => format!("{value:o}"),
+            (16, false) => format!("{value:x}"),
+            (16, true) => format!("{value:X}"),
+            _ => value.to_string(),
+        },
+    };
+    if let Some(precision) = precision {
+        while digits.len() < precision {
+            digits.insert(0, '0');
+        }
+    }
+    digits
+}
+
+fn float(value: f64, conv: u8, precision: Option<usize>, flags: Flags) -> (String, String) {
+    let sign = if value.is_sign_negative() {
+        "-"
+    } else if flags.plus {
+        "+"
+    } else if flags.space {
+        " "
+    } else {
+        ""
+    };
+    let abs = value.abs();
+    let upper = conv.is_ascii_uppercase();
+    if !abs.is_finite() {
+        // The VS2010 CRT spells these 1.#INF, 1.#QNAN and 1.#IND (the NaN
+        // that invalid operations produce), padded out to the precision.
+        let text = if abs.is_infinite() {
+            "1.#INF"
+        } else if value.to_bits() == 0xFFF8_0000_0000_0000 {
+            "1.#IND"
+        } else {
+            "1.#QNAN"
+        };
+        let mut text = text.to_string();
+        while text.len() < 2 + precision.unwrap_or(6) {
+            text.push('0');
+        }
+        return (sign.to_string(), text);
+    }
+    let body = match conv {
+        b'f' | b'F' => {
+            let precision = precision.unwrap_or(6);
+            let mut text = format!("{abs:.precision$}");
+            if flags.alt && precision == 0 {
+                text.push('.');
+            }
+            text
+        }
+        b'e' | b'E' => exponent_form(abs, precision.unwrap_or(6), upper, flags.alt),
+        b'g' | b'G' => {
+            let precision = precision.unwrap_or(6).max(1);
+            let exponent = decimal_exponent(abs, precision - 1);
+            let mut text = if exponent < -4 || exponent >= precision as i32 {
+                exponent_form(abs, precision - 1, upper, flags.alt)
+            } else {
+                let decimals = (precision as i32 - 1 - exponent) as usize;
+                let mut text = format!("{abs:.decimals$}");
+                if flags.alt && !text.contains('.') {
+                    text.push('.');
+                }
+                text
+            };
+            if !flags.alt {
+                text = strip_fraction_zeros(&text);
+            }
+            text
+        }
+        _ => {
+            let (prefix, body) = hex_float(abs, precision, flags.alt);
+            let prefix = format!("{sign}{prefix}");
+            return if upper {
+                (prefix.to_uppercase(), body.to_uppercase())
+            } else {
+                (prefix, body)
+            };
+        }
+    };
+    (sign.to_string(), body)
+}
+
+// MSVC prints at least three exponent digits: 1.500000e+003.
+fn exponent_form(value: f64, precision: usize, upper: bool, alt: bool) -> String {
+    let text = format!("{value:.precision$e}");
+    let (mantissa, exponent) = text.split_once('e').unwrap_or((&text, "0"));
+    let exponent: i32 = exponent.parse().unwrap_or(0);
+    let mut mantissa = mantissa.to_string();
+    if alt && precision == 0 {
+        mantissa.push('.');
+    }
+    let sign = if exponent < 0 { '-' } else { '+' };
+    let e = if upper { 'E' } else { 'e' };
+    format!("{mantissa}{e}{sign}{:03}", exponent.unsigned_abs())
+}
+
+fn decimal_exponent(value: f64, precision: usize) -> i32 {
+    let text = format!("{value:.precision$e}");
+    text.split_once('e')
+        .and_then(|(_, exponent)| exponent.parse().ok())
+        .unwrap_or(0)
+}
+
+fn strip_fraction_zeros(text: &str) -> String {
+    let (mantissa, exponent) = match text.find(['e', 'E']) {
+        Some(split) => text.split_at(split),
+        None => (text, ""),
+    };
+    let mantissa = if mantissa.contains('.') {
+        mantissa.trim_end_matches('0').trim_end_matches('.')
+    } else {
+        mantissa
+    };
+    format!("{mantissa}{exponent}")
+}
+
+// %a: 0x1.8000000000000p+1, thirteen hex digits unless a precision is given.
+fn hex_float(value: f64, precision: Option<usize>, alt: bool) -> (String, String) {
+    let bits = value.to_bits();
+    let exponent_bits = (bits >> 52) & 0x7FF;
+    let mut mantissa = bits & ((1 << 52) - 1);
+    let (mut lead, exponent) = match (exponent_bits, mantissa) {
+        (0, 0) => (0u64, 0i64),
+        (0, _) => (0, -1022),
+        _ => (1, exponent_bits as i64 - 1023),
+    };
+    let digits = precision.unwrap_or(13);
+    if digits < 13 {
+        let shift = (13 - digits) * 4;
+        mantissa = (mantissa + (1 << (shift - 1))) >> shift;
+        if mantissa >> (digits * 4) != 0 {
+            lead += 1;
+            mantissa &= (1u64 << (digits * 4)) - 1;
+        }
+    }
+    let mut fraction = match digits {
+        0 => String::new(),
+        1..=13 => format!("{mantissa:0width$x}", width = digits),
+        _ => format!("{mantissa:013x}"),
+    };
+    while fraction.len() < digits {
+        fraction.push('0');
+    }
+    let point = if digits > 0 || alt { "." } else { "" };
+    let sign = if exponent < 0 { '-' } else { '+' };
+    (
+        "0x".to_string(),
+        format!("{lead}{point}{fraction}p{sign}{}", exponent.unsigned_abs()),
+    )
+}
+
+fn ascii(text: &str) -> Vec<u16> {
+    text.bytes().map(u16::from).collect()
+}
+
+// Zero padding goes between the sign or radix prefix and the digits.
+fn pad(out: &mut Vec<u16>, prefix: Vec<u16>, body: Vec<u16>, width: usize, left: bool, zero: bool) {
+    let fill = width.saturating_sub(prefix.len() + body.len());
+    if left {
+        out.extend(prefix);
+        out.extend(body);
+        out.extend(std::iter::repeat_n(u16::from(b' '), fill));
+    } else if zero {
+        out.extend(prefix);
+        out.extend(std::iter::repeat_n(u16::from(b'0'), fill));
+        out.extend(body);
+    } else {
+        out.extend(std::iter::repeat_n(u16::from(b' '), fill));
+        out.extend(prefix);
+        out.extend(body);
+    }
+}
+
+/// Where scanf reads from, with one unit of lookahead.
+pub(super) trait ScanInput {
+    fn peek(&mut self, vm: &mut Vm) -> Option<u16>;
+    fn advance(&mut self);
+}
+
+/// Scanning input held in memory, as for `sscanf`.
+pub(super) struct TextInput {
+    units: Vec<u16>,
+    pos: usize,
+}
+
+impl TextInput {
+    pub(super) fn new(units: Vec<u16>) -> Self {
+        Self { units, pos: 0 }
+    }
+}
+
+impl ScanInput for TextInput {
+    fn peek(&mut self, _vm: &mut Vm) -> Option<u16> {
+        self.units.get(self.pos).copied()
+    }
+
+    fn advance(&mut self) {
+        self.pos += 1;
+    }
+}
+
+// Counts consumed units for %n.
+struct Scanner<'a> {
+    input: &'a mut dyn ScanInput,
+    consumed: usize,
+}
+
+impl Scanner<'_> {
+    fn peek(&mut self, vm: &mut Vm) -> Option<u16> {
+        self.input.peek(vm)
+    }
+
+    fn advance(&mut self) {
+        self.input.advance();
+        self.consumed += 1;
+    }
+
+    fn skip_space(&mut self, vm: &mut Vm) {
+        while self.peek(vm).is_some_and(is_space) {
+            self.advance();
+        }
+    }
+
+    // Take units while `accept` holds, up to `width` of them.
+    fn take_while(
+        &mut self,
+        vm: &mut Vm,
+        width: usize,
+        mut accept: impl FnMut(u16) -> bool,
+    ) -> Vec<u16> {
+        let mut units = Vec::new();
+        while units.len() < width {
+            match self.peek(vm) {
+                Some(unit) if accept(unit) => {
+                    units.push(unit);
+                    self.advance();
+                }
+                _ => break,
+            }
+        }
+        units
+    }
+
+    fn take_if(&mut self, vm: &mut Vm, taken: &mut usize, width: usize, options: &[u8]) -> bool {
+        if *taken >= width {
+            return false;
+        }
+        match self.peek(vm) {
+            Some(unit) if options.iter().any(|option| u16::from(*option) == unit) => {
+                self.advance();
+                *taken += 1;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    // An integer in `radix`, or any C radix when it is zero. Returns the
+    // two's-complement value.
+    fn integer(&mut self, vm: &mut Vm, width: usize, radix: u32) -> Option<u64> {
+        let mut taken = 0;
+        let negative = self.take_if(vm, &mut taken, width, b"-");
+        if !negative {
+            self.take_if(vm, &mut taken, width, b"+");
+        }
+        let mut radix = radix;
+        let mut seen = false;
+        if (radix == 0 || radix == 16) && self.take_if(vm, &mut taken, width, b"0") {
+            seen = true;
+            if self.take_if(vm, &mut taken, width, b"xX") {
+                radix = 16;
+            } else if radix == 0 {
+                radix = 8;
+            }
+        }
+        if radix == 0 {
+            radix = 10;
+        }
+        let mut value = 0u64;
+        let digits = self.take_while(vm, width - taken, |unit| {
+            char::from_u32(u32::from(unit)).is_some_and(|ch| ch.is_digit(radix))
+        });
+        for unit in &digits {
+            let digit = char::from_u32(u32::from(*unit))
+                .and_then(|ch| ch.to_digit(radix))
+                .unwrap_or(0);
+            value = value
+                .wrapping_mul(u64::from(radix))
+                .wrapping_add(u64::from(digit));
+        }
+        if !seen && digits.is_empty() {
+            return None;
+        }
+        Some(if negative {
+            value.wrapping_neg()
+        } else {
+            value
+        })
+    }
+
+    fn float(&mut self, vm: &mut Vm, width: usize) -> Option<f64> {
+        let mut text = String::new();
+        let mut taken = 0;
+        let mut take = |scanner: &mut Self, vm: &mut Vm, text: &mut String, options: &[u8]| {
+            let unit = scanner.peek(vm);
+            let matched = scanner.take_if(vm, &mut taken, width, options);
+            if matched {
+                text.push(char::from(unit.unwrap_or(0) as u8));
+            }
+            matched
+        };
+        take(self, vm, &mut text, b"+-");
+        while take(self, vm, &mut text, b"0123456789") {}
+        if take(self, vm, &mut text, b".") {
+            while take(self, vm, &mut text, b"0123456789") {}
+        }
+        if take(self, vm, &mut text, b"eE") {
+            take(self, vm, &mut text, b"+-");
+            while take(self, vm, &mut text, b"0123456789") {}
+        }
+        text.parse().ok()
+    }
+}
+
+fn is_space(unit: u16) -> bool {
+    matches!(unit, 0x09..=0x0D | 0x20)
+}
+
+// A %[...] set: listed units and ranges, possibly negated.
+struct ScanSet {
+    negated: bool,
+    ranges: Vec<(u16, u16)>,
+}
+
+impl ScanSet {
+    fn parse(cursor: &mut Cursor) -> Self {
+        let negated = cursor.eat(b'^');
+        let mut ranges = Vec::new();
+        let fmt = cursor.fmt;
+        let mut first = true;
+        while let Some(&unit) = fmt.get(cursor.pos) {
+            if unit == u16::from(b']') && !first {
+                cursor.pos += 1;
+                break;
+            }
+            first = false;
+            cursor.pos += 1;
+            let range_end = fmt.get(cursor.pos + 1).copied();
+            if fmt.get(cursor.pos) == Some(&u16::from(b'-'))
+                && range_end.is_some_and(|end| end != u16::from(b']'))
+            {
+                let end = range_end.unwrap_or(unit);
+                ranges.push((unit.min(end), unit.max(end)));
+                cursor.pos += 2;
+            } else {
+                ranges.push((unit, unit));
+            }
+        }
+        Self { negated, ranges }
+    }
+
+    fn contains(&self, unit: u16) -> bool {
+        let listed = self
+            .ranges
+            .iter()
+            .any(|(start, end)| (*start..=*end).contains(&unit));
+        listed != self.negated
+    }
+}
+
+/// Scan `input` with scanf rules, storing through the pointers in `args`.
+/// Returns the number of fields assigned, or -1 when the input ends before
+/// the first conversion. The `secure` (`_s`) variants follow every `%c`, `%s`
+/// and `%[` pointer with its buffer size in characters.
+pub(super) fn scan(
+    vm: &mut Vm,
+    input: &mut dyn ScanInput,
+    fmt: &[u16],
+    args: VaList,
+    wide: bool,
+    secure: bool,
+) -> i32 {
+    let mut scanner = Scanner { input, consumed: 0 };
+    let mut cursor = Cursor { fmt, pos: 0 };
+    let mut args = args;
+    let mut assigned = 0;
+    let mut conversions = 0;
+    let ended = |conversions: i32, assigned: i32| if conversions == 0 { -1 } else { assigned };
+    while let Some(&unit) = fmt.get(cursor.pos) {
+        cursor.pos += 1;
+        if is_space(unit) {
+            scanner.skip_space(vm);
+            continue;
+        }
+        if unit != u16::from(b'%') || cursor.eat(b'%') {
+            if unit == u16::from(b'%') {
+                scanner.skip_space(vm);
+            }
+            match scanner.peek(vm) {
+                Some(next) if next == unit => scanner.advance(),
+                Some(_) => return assigned,
+                None => return ended(conversions, assigned),
+            }
+            continue;
+        }
+        let suppress = cursor.eat(b'*');
+        let width = cursor.number().filter(|width| *width > 0);
+        let size = cursor.size();
+        let Some(conv) = cursor.peek() else {
+            break;
+        };
+        cursor.pos += 1;
+        let set = (conv == b'[').then(|| ScanSet::parse(&mut cursor));
+        if conv == b'n' {
+            if !suppress {
+                let ptr = args.next_u32(vm);
+                let _ = vm.write_u32(ptr, scanner.consumed as u32);
+            }
+            continue;
+        }
+        if !matches!(conv, b'c' | b'C' | b'[') {
+            scanner.skip_space(vm);
+        }
+        if scanner.peek(vm).is_none() {
+            return ended(conversions, assigned);
+        }
+        let stored = match conv {
+            b'c' | b'C' | b's' | b'S' | b'[' => {
+                let units = match (conv, &set) {
+                    (_, Some(set)) => scanner
+                        .take_while(vm, width.unwrap_or(usize::MAX), |unit| set.contains(unit)),
+                    (b'c' | b'C', _) => scanner.take_while(vm, width.unwrap_or(1), |_| true),
+                    _ => {
+                        scanner.take_while(vm, width.unwrap_or(usize::MAX), |unit| !is_space(unit))
+                    }
+                };
+                if units.is_empty() {
+                    return assigned;
+                }
+                if !suppress {
+                    let wide_dest = match size {
+                        Size::Short => false,
+                        Size::Long | Size::Wide => true,
+                        _ => (conv.is_ascii_lowercase() || conv == b'[') == wide,
+                    };
+                    let terminate = !matches!(conv, b'c' | b'C');
+                    let ptr = args.next_u32(vm);
+                    let capacity = if secure {
+                        args.next_u32(vm) as usize
+                    } else {
+                        usize::MAX
+                    };
+                    if units.len() + usize::from(terminate) > capacity {
+                        if capacity > 0 {
+                            write_units(vm, ptr, &[0], wide_dest);
+                        }
+                        return assigned;
+                    }
+                    write_units(vm, ptr, &units, wide_dest);
+                    if terminate {
+                        let end =
+                            ptr.wrapping_add(units.len() as u32 * if wide_dest { 2 } else { 1 });
+                        write_units(vm, end, &[0], wide_dest);
+                    }
+                }
+                true
+            }
+            b'd' | b'i' | b'u' | b'o' | b'x' | b'X' | b'p' => {
+                let radix = match conv {
+                    b'i' => 0,
+                    b'o' => 8,
+                    b'x' | b'X' | b'p' => 16,
+                    _ => 10,
+                };
+                let Some(value) = scanner.integer(vm, width.unwrap_or(usize::MAX), radix) else {
+                    return assigned;
+                };
+                if !suppress {
+                    let ptr = args.next_u32(vm);
+                    let _ = match size {
+                        Size::Char => vm.write_u8(ptr, value as u8),
+                        Size::Short => vm.write_u16(ptr, value as u16),
+                        Size::LongLong => vm.write_u64(ptr, value),
+                        _ => vm.write_u32(ptr, value as u32),
+                    };
+                }
+                true
+            }
+            b'e' | b'E' | b'f' | b'F' | b'g' | b'G' | b'a' | b'A' => {
+                let Some(value) = scanner.float(vm, width.unwrap_or(usize::MAX)) else {
+                    return assigned;
+                };
+                if !suppress {
+                    let ptr = args.next_u32(vm);
+                    let _ = match size {
+                        Size::Long | Size::LongLong | Size::LongDouble => {
+                            vm.write_u64(ptr, value.to_bits())
+                        }
+                        _ => vm.write_u32(ptr, (value as f32).to_bits()),
+                    };
+                }
+                true
+            }
+            _ => return assigned,
+        };
+        conversions += 1;
+        if stored && !suppress {
+            assigned += 1;
+        }
+    }
+    assigned
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::{Architecture, VmConfig};
+
+    fn create_test_vm() -> Vm {
+        let mut vm = Vm::new(VmConfig::new().architecture(Architecture::X86)).expect("vm");
+        vm.memory.map(0x1000, 0x10000);
+        vm
+    }
+
+    fn units(text: &str) -> Vec<u16> {
+        text.encode_utf16().collect()
+    }
+
+    // Lay out cdecl arguments from 0x2000 and format with them.
+    fn sprintf(vm: &mut Vm, fmt: &str, words: &[u32]) -> String {
+        for (index, word) in words.iter().enumerate() {
+            vm.write_u32(0x2000 + index as u32 * 4, *word).expect("arg");
+        }
+        let out = format(vm, &units(fmt), VaList::new(0x2000), false);
+        String::from_utf16_lossy(&out)
+    }
+
+    fn double(value: f64) -> [u32; 2] {
+        let bits = value.to_bits();
+        [bits as u32, (bits >> 32) as u32]
+    }
+
+    #[test]
+    fn printf_formats_integers_and_text() {
+        let mut vm = create_test_vm();
+        vm.write_bytes(0x3000, b"text\0").expect("str");
+        for (index, unit) in units("wide\0").iter().enumerate() {
+            vm.write_u16(0x3100 + index as u32 * 2, *unit)
+                .expect("wstr");
+        }
+        assert_eq!(
+            sprintf(
+                &mut vm,
+                "[%5d|%-5d|%05d|%+d|% d]",
+                &[42, 42, (-42i32) as u32, 7, 7]
+            ),
+            "[   42|42   |-0042|+7| 7]"
+        );
+        assert_eq!(
+            sprintf(
+                &mut vm,
+                "%x %X %#x %#o %o %.3u %.0d|",
+                &[255, 255, 255, 8, 0, 5, 0]
+            ),
+            "ff FF 0xff 010 0 005 |"
+        );
+        assert_eq!(
+            sprintf(
+                &mut vm,
+                "%I64d %lld %hd %hhu",
+                &[0, 1, 2, 0, 0x12345, 0x1FF]
+            ),
+            "4294967296 2 9029 255"
+        );
+        assert_eq!(
+            sprintf(
+                &mut vm,
+                "%s|%.2s|%6s|%S|%c%C",
+                &[0x3000, 0x3000, 0x3000, 0x3100, 0x41, 0x42]
+            ),
+            "text|te|  text|wide|AB"
+        );
+        assert_eq!(
+            sprintf(
+                &mut vm,
+                "%*d|%-*d|%.*s|%p|%s",
+                &[4, 1, 3, 2, 1, 0x3000, 0xBEEF, 0]
+            ),
+            "   1|2  |t|0000BEEF|(null)"
+        );
+        assert_eq!(sprintf(&mut vm, "%2$s %1$d%%", &[9, 0x3000]), "text 9%");
+        assert_eq!(sprintf(&mut vm, "ab%n!", &[0x3200]), "ab!");
+        assert_eq!(vm.read_u32(0x3200).expect("count"), 2);
+    }
+
+    #[test]
+    fn printf_formats_floats_like_msvc() {
+        let mut vm = create_test_vm();
+        let mut words = Vec::new();
+        for value in [3.14159, 1500.0, 0.0001, 123456789.0, 2.5, -0.5] {
+            words.extend(double(value));
+        }
+        assert_eq!(
+            sprintf(&mut vm, "%f %e %g %g %.2f %+.1E", &words),
+            "3.141590 1.500000e+003 0.0001 1.23457e+008 2.50 -5.0E-001"
+        );
+        let mut words = Vec::new();
+        for value in [1.0, 100.0, f64::INFINITY, 1.0, 10.0] {
+            words.extend(double(value));
+        }
+        assert_eq!(
+            sprintf(&mut vm, "%a %g %f %#.0f %08.2f", &words),
+            "0x1.0000000000000p+0 100 1.#INF00 1. 00010.00"
+        );
+    }
+
+    #[test]
+    fn scanf_assigns_fields_and_reports_eof() {
+        let mut vm = create_test_vm();
+        let scan_text = |vm: &mut Vm, text: &str, fmt: &str, secure: bool| {
+            let mut input = TextInput::new(units(text));
+            for index in 0..8 {
+                vm.write_u32(0x2000 + index * 4, 0x3000 + index * 0x40)
+                    .expect("arg");
+            }
+            scan(
+                vm,
+                &mut input,
+                &units(fmt),
+                VaList::new(0x2000),
+                false,
+                secure,
+            )
+        };
+
+        let count = scan_text(
+            &mut vm,
+            "  -12 0x1f 017 name rest",
+            "%d %x %i %s %3c",
+            false,
+        );
+        assert_eq!(count, 5);
+        assert_eq!(vm.read_u32(0x3000).expect("d") as i32, -12);
+        assert_eq!(vm.read_u32(0x3040).expect("x"), 0x1F);
+        assert_eq!(vm.read_u32(0x3080).expect("i"), 0o17);
+        assert_eq!(vm.read_c_string(0x30C0).expect("s"), "name");
+        assert_eq!(vm.read_bytes(0x3100, 3).expect("c"), b"res");
+
+        let count = scan_text(&mut vm, "key=value;2.5", "%[^=]=%[a-z];%lf%n", false);
+        assert_eq!(count, 3);
+        assert_eq!(vm.read_c_string(0x3000).expect("key"), "key");
+        assert_eq!(vm.read_c_string(0x3040).expect("value"), "value");
+        assert_eq!(f64::from_bits(vm.read_u64(0x3080).expect("lf")), 2.5);
+        assert_eq!(vm.read_u32(0x30C0).expect("n"), 13);
+
+        assert_eq!(scan_text(&mut vm, "12 abc", "%d %d", false), 1);
+        assert_eq!(scan_text(&mut vm, "   ", "%d", false), -1);
+        assert_eq!(scan_text(&mut vm, "7 x", "%*d %c", false), 1);
+        assert_eq!(vm.read_u8(0x3000).expect("c"), b'x');
+
+        // scanf_s: a buffer of four characters cannot hold "toolong".
+        vm.write_u32(0x2000, 0x3000).expect("buffer");
+        vm.write_u32(0x2004, 4).expect("size");
+        let mut input = TextInput::new(units("toolong"));
+        let count = scan(
+            &mut vm,
+            &mut input,
+            &units("%s"),
+            VaList::new(0x2000),
+            false,
+            true,
+        );
+        assert_eq!(count, 0);
+        assert_eq!(vm.read_u8(0x3000).expect("s"), 0);
+    }
+}
@@ -1,98 +1,1151 @@
-//! Low-level I/O function stubs for MSVCR100.dll.
+//! Low-level I/O for MSVCR100.dll: numbered file descriptors, directories,
+//! `_find*` searches and `_stat`.
 
-use crate::vm::Vm;
+use std::io;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::vm::{CrtFd, CrtTarget, FindEntry, FsMetadata, FsOpenOptions, Vm};
+
+use super::format::{read_units, write_units};
+use super::memory::crt_alloc;
+use super::stdlib::{io_errno, set_errno, EACCES, EBADF, EEXIST, EINVAL, ENOENT, ERANGE};
 
 const DLL: &str = "MSVCR100.dll";
 
+// `_open` flags.
+const O_WRONLY: u32 = 0x1;
+const O_RDWR: u32 = 0x2;
+const O_APPEND: u32 = 0x8;
+const O_TEMPORARY: u32 = 0x40;
+const O_CREAT: u32 = 0x100;
+const O_TRUNC: u32 = 0x200;
+const O_EXCL: u32 = 0x400;
+const O_TEXT: u32 = 0x4000;
+const O_BINARY: u32 = 0x8000;
+const O_WTEXT: u32 = 0x10000;
+const O_U16TEXT: u32 = 0x20000;
+const O_U8TEXT: u32 = 0x40000;
+
+// `st_mode` bits.
+const S_IFIFO: u32 = 0x1000;
+const S_IFCHR: u32 = 0x2000;
+const S_IFDIR: u32 = 0x4000;
+const S_IFREG: u32 = 0x8000;
+const S_IREAD: u32 = 0x100;
+const S_IWRITE: u32 = 0x80;
+const S_IEXEC: u32 = 0x40;
+
+// `_finddata_t` attributes.
+const A_RDONLY: u32 = 0x1;
+const A_SUBDIR: u32 = 0x10;
+const A_ARCH: u32 = 0x20;
+
+/// What `_isatty` reports for a character device.
+const FDEV: u32 = 0x40;
+const MAX_PATH: usize = 260;
+
+fn read_string(vm: &Vm, ptr: u32, wide: bool) -> Option<String> {
+    if ptr == 0 {
+        return None;
+    }
+    Some(if wide {
+        String::from_utf16_lossy(&read_units(vm, ptr, true, usize::MAX))
+    } else {
+        vm.read_c_string(ptr).unwrap_or_default()
+    })
+}
+
+fn store_string(vm: &mut Vm, ptr: u32, text: &str, wide: bool) {
+    let mut units: Vec<u16> = text.encode_utf16().collect();
+    units.push(0);
+    write_units(vm, ptr, &units, wide);
+}
+
+// -1 with errno set, the usual failure of this module.
+fn fail(vm: &mut Vm, errno: i32) -> u32 {
+    set_errno(vm, errno);
+    u32::MAX
+}
+
+fn io_fail(vm: &mut Vm, err: &io::Error) -> u32 {
+    fail(vm, io_errno(err))
+}
+
 // Low-level file I/O
-define_stub_fn!(DLL, open_impl, 0);
-define_stub_fn!(DLL, wopen_impl, 0);
-define_stub_fn!(DLL, sopen_impl, 0);
-define_stub_fn!(DLL, sopen_s_impl, 0);
-define_stub_fn!(DLL, wsopen_impl, 0);
-define_stub_fn!(DLL, wsopen_s_impl, 0);
-define_stub_fn!(DLL, creat_impl, 0);
-define_stub_fn!(DLL, wcreat_impl, 0);
-define_stub_fn!(DLL, close_impl, 0);
-define_stub_fn!(DLL, read_impl, 0);
-define_stub_fn!(DLL, write_impl, 0);
-define_stub_fn!(DLL, lseek_impl, 0);
-define_stub_fn!(DLL, lseeki64_impl, 0);
-define_stub_fn!(DLL, tell_impl, 0);
-define_stub_fn!(DLL, telli64_impl, 0);
-define_stub_fn!(DLL, dup_impl, 0);
-define_stub_fn!(DLL, dup2_impl, 0);
-define_stub_fn!(DLL, eof_impl, 0);
-define_stub_fn!(DLL, commit_impl, 0);
-define_stub_fn!(DLL, chsize_impl, 0);
-define_stub_fn!(DLL, chsize_s_impl, 0);
-define_stub_fn!(DLL, filelength_impl, 0);
-define_stub_fn!(DLL, filelengthi64_impl, 0);
-define_stub_fn!(DLL, locking_impl, 0);
-define_stub_fn!(DLL, isatty_impl, 0);
-define_stub_fn!(DLL, setmode_impl, 0);
-define_stub_fn!(DLL, umask_impl, 0);
-define_stub_fn!(DLL, umask_s_impl, 0);
-define_stub_fn!(DLL, unlink_impl, 0);
-define_stub_fn!(DLL, wunlink_impl, 0);
-define_stub_fn!(DLL, access_impl, 0);
-define_stub_fn!(DLL, access_s_impl, 0);
-define_stub_fn!(DLL, waccess_impl, 0);
-define_stub_fn!(DLL, waccess_s_impl, 0);
-define_stub_fn!(DLL, chmod_impl, 0);
-define_stub_fn!(DLL, wchmod_impl, 0);
-define_stub_fn!(DLL, mktemp_impl, 0);
-define_stub_fn!(DLL, mktemp_s_impl, 0);
-define_stub_fn!(DLL, wmktemp_impl, 0);
-define_stub_fn!(DLL, wmktemp_s_impl, 0);
-define_stub_fn!(DLL, pipe_impl, 0);
-define_stub_fn!(DLL, getcwd_impl, 0);
-define_stub_fn!(DLL, wgetcwd_impl, 0);
-define_stub_fn!(DLL, getdcwd_impl, 0);
-define_stub_fn!(DLL, wgetdcwd_impl, 0);
-define_stub_fn!(DLL, getdcwd_nolock_impl, 0);
-define_stub_fn!(DLL, wgetdcwd_nolock_impl, 0);
-define_stub_fn!(DLL, chdir_impl, 0);
-define_stub_fn!(DLL, wchdir_impl, 0);
-define_stub_fn!(DLL, mkdir_impl, 0);
-define_stub_fn!(DLL, wmkdir_impl, 0);
-define_stub_fn!(DLL, rmdir_impl, 0);
-define_stub_fn!(DLL, wrmdir_impl, 0);
-define_stub_fn!(DLL, getdrive_impl, 0);
-define_stub_fn!(DLL, chdrive_impl, 0);
+
+fn open_fd(vm: &mut Vm, path: &str, oflag: u32, pmode: u32) -> Result<i32, i32> {
+    let access = oflag & (O_WRONLY | O_RDWR);
+    if access == (O_WRONLY | O_RDWR) {
+        return Err(EINVAL);
+    }
+    let mut options = FsOpenOptions::new()
+        .read(access != O_WRONLY)
+        .write(access != 0)
+        .truncate(oflag & O_TRUNC != 0);
+    let create = oflag & O_CREAT != 0;
+    if create && oflag & O_EXCL != 0 {
+        options = options.create_new(true);
+    } else if create {
+        options = options.create(true);
+    }
+    let existed = vm.file_exists(path);
+    let text = oflag & O_BINARY == 0;
+    let fd = vm
+        .crt_open(
+            path,
+            options,
+            text,
+            oflag & O_APPEND != 0,
+            oflag & O_TEMPORARY != 0,
+        )
+        .map_err(|err| io_errno(&err))?;
+    // New files without write permission become read-only once created.
+    if create && !existed && pmode & !vm.crt_umask() & S_IWRITE == 0 {
+        let _ = vm.file_set_read_only(path, true);
+    }
+    Ok(fd)
+}
+
+fn open_with(vm: &mut Vm, path: u32, oflag: u32, pmode: u32, wide: bool) -> u32 {
+    let Some(path) = read_string(vm, path, wide) else {
+        return fail(vm, EINVAL);
+    };
+    match open_fd(vm, &path, oflag, pmode) {
+        Ok(fd) => fd as u32,
+        Err(errno) => fail(vm, errno),
+    }
+}
+
+fn open_impl(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (path, oflag, pmode) = vm_args!(vm, stack_ptr; u32, u32, u32);
+    open_with(vm, path, oflag, pmode, false)
+}
+
+fn wopen_impl(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (path, oflag, pmode) = vm_args!(vm, stack_ptr; u32, u32, u32);
+    open_with(vm, path, oflag, pmode, true)
+}
+
+// Sharing flags have no meaning for guest files.
+fn sopen_impl(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (path, oflag, _share, pmode) = vm_args!(vm, stack_ptr; u32, u32, u32, u32);
+    open_with(vm, path, oflag, pmode, false)
+}
+
+fn wsopen_impl(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (path, oflag, _share, pmode) = vm_args!(vm, stack_ptr; u32, u32, u32, u32);
+    open_with(vm, path, oflag, pmode, true)
+}
+
+fn sopen_s(vm: &mut Vm, stack_ptr: u32, wide: bool) -> u32 {
+    let (out, path, oflag, _share, pmode) = vm_args!(vm, stack_ptr; u32, u32, u32, u32, u32);
+    if out == 0 {
+        set_errno(vm, EINVAL);
+        return EINVAL as u32;
+    }
+    let _ = vm.write_u32(out, u32::MAX);
+    let Some(path) = read_string(vm, path, wide) else {
+        set_errno(vm, EINVAL);
+        return EINVAL as u32;
+    };
+    match open_fd(vm, &path, oflag, pmode) {
+        Ok(fd) => {
+            let _ = vm.write_u32(out, fd as u32);
+            0
+        }
+        Err(errno) => {
+            set_errno(vm, errno);
+            errno as u32
+        }
+    }
+}
+
+fn sopen_s_impl(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    sopen_s(vm, stack_ptr, false)
+}
+
+fn wsopen_s_impl(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    sopen_s(vm, stack_ptr, true)
+}
+
+fn creat_impl(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (path, pmode) = vm_args!(vm, stack_ptr; u32, u32);
+    open_with(vm, path, O_RDWR | O_CREAT | O_TRUNC, pmode, false)
+}
+
+fn wcreat_impl(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (path, pmode) = vm_args!(vm, stack_ptr; u32, u32);
+    open_with(vm, path, O_RDWR | O_CREAT | O_TRUNC, pmode, true)
+}
+
+fn close_impl(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (fd,) = vm_args!(vm, stack_ptr; i32);
+    if vm.crt_close(fd) {
+        0
+    } else {
+        fail(vm, EBADF)
+    }
+}
+
+fn read_impl(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (fd, buffer, count) = vm_args!(vm, stack_ptr; i32, u32, usize);
+    if buffer == 0 && count > 0 {
+        return fail(vm, EINVAL);
+    }
+    match vm.crt_read(fd, count) {
+        Some(Ok(bytes)) => {
+            let _ = vm.write_bytes(buffer, &bytes);
+            bytes.len() as u32
+        }
+        Some(Err(err)) => io_fail(vm, &err),
+        None => fail(vm, EBADF),
+    }
+}
+
+fn write_impl(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (fd, buffer, count) = vm_args!(vm, stack_ptr; i32, u32, usize);
+    if buffer == 0 && count > 0 {
+        return fail(vm, EINVAL);
+    }
+    let bytes = vm.read_bytes(buffer, count).unwrap_or_default();
+    match vm.crt_write(fd, &bytes) {
+        Some(Ok(written)) => written as u32,
+        Some(Err(err)) => io_fail(vm, &err),
+        None => fail(vm, EBADF),
+    }
+}
+
+fn seek_fd(vm: &mut Vm, fd: i32, offset: i64, origin: u32) -> Option<u64> {
+    if origin > 2 {
+        fail(vm, EINVAL);
+        return None;
+    }
+    match vm.crt_seek(fd, offset, origin) {
+        Some(Ok(position)) => Some(position),
+        Some(Err(err)) => {
+            io_fail(vm, &err);
+            None
+        }
+        None => {
+            fail(vm, EBADF);
+            None
+        }
+    }
+}
+
+// The 32-bit forms cannot report positions past 2 GB.
+fn narrow_position(vm: &mut Vm, position: Option<u64>) -> u32 {
+    match position {
+        Some(position) if position <= i32::MAX as u64 => position as u32,
+        Some(_) => fail(vm, EINVAL),
+        None => u32::MAX,
+    }
+}
+
+fn wide_position(vm: &mut Vm, position: Option<u64>) -> u32 {
+    let position = position.unwrap_or(u64::MAX);
+    vm.regs.edx = (position >> 32) as u32;
+    position as u32
+}
+
+fn lseek_impl(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (fd, offset, origin) = vm_args!(vm, stack_ptr; i32, i32, u32);
+    let position = seek_fd(vm, fd, i64::from(offset), origin);
+    narrow_position(vm, position)
+}
+
+fn lseeki64_impl(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (fd, low, high, origin) = vm_args!(vm, stack_ptr; i32, u32, u32, u32);
+    let offset = (u64::from(high) << 32 | u64::from(low)) as i64;
+    let position = seek_fd(vm, fd, offset, origin);
+    wide_position(vm, position)
+}
+
+fn tell_impl(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (fd,) = vm_args!(vm, stack_ptr; i32);
+    let position = seek_fd(vm, fd, 0, 1);
+    narrow_position(vm, position)
+}
+
+fn telli64_impl(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (fd,) = vm_args!(vm, stack_ptr; i32);
+    let position = seek_fd(vm, fd, 0, 1);
+    wide_position(vm, position)
+}
+
+fn dup_impl(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (fd,) = vm_args!(vm, stack_ptr; i32);
+    match vm.crt_dup(fd) {
+        Some(copy) => copy as u32,
+        None => fail(vm, EBADF),
+    }
+}
+
+fn dup2_impl(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (fd, target) = vm_args!(vm, stack_ptr; i32, i32);
+    if target < 0 {
+        return fail(vm, EBADF);
+    }
+    if vm.crt_dup2(fd, target) {
+        0
+    } else {
+        fail(vm, EBADF)
+    }
+}
+
+// 1 at end of file, 0 before it; devices and pipes never are.
+fn eof_impl(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (fd,) = vm_args!(vm, stack_ptr; i32);
+    let Some(entry) = vm.crt_fd(fd) else {
+        return fail(vm, EBADF);
+    };
+    if entry.eof {
+        return 1;
+    }
+    if !matches!(entry.target, CrtTarget::File(_)) {
+        return 0;
+    }
+    match (vm.crt_seek(fd, 0, 1), vm.crt_fd_size(fd)) {
+        (Some(Ok(position)), Some(Ok(size))) => u32::from(position >= size),
+        _ => u32::MAX,
+    }
+}
+
+fn commit_impl(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (fd,) = vm_args!(vm, stack_ptr; i32);
+    match vm.crt_commit(fd) {
+        Some(Ok(())) => 0,
+        Some(Err(err)) => io_fail(vm, &err),
+        None => fail(vm, EBADF),
+    }
+}
+
+fn resize(vm: &mut Vm, fd: i32, size: i64) -> i32 {
+    if size < 0 {
+        return EINVAL;
+    }
+    match vm.crt_set_size(fd, size as u64) {
+        Some(Ok(())) => 0,
+        Some(Err(err)) => io_errno(&err),
+        None => EBADF,
+    }
+}
+
+fn chsize_impl(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (fd, size) = vm_args!(vm, stack_ptr; i32, i32);
+    match resize(vm, fd, i64::from(size)) {
+        0 => 0,
+        errno => fail(vm, errno),
+    }
+}
+
+fn chsize_s_impl(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (fd, low, high) = vm_args!(vm, stack_ptr; i32, u32, u32);
+    let size = (u64::from(high) << 32 | u64::from(low)) as i64;
+    let errno = resize(vm, fd, size);
+    if errno != 0 {
+        set_errno(vm, errno);
+    }
+    errno as u32
+}
+
+fn file_length(vm: &mut Vm, fd: i32) -> Option<u64> {
+    match vm.crt_fd_size(fd) {
+        Some(Ok(size)) => Some(size),
+        Some(Err(err)) => {
+            io_fail(vm, &err);
+            None
+        }
+        None => {
+            fail(vm, EBADF);
+            None
+        }
+    }
+}
+
+fn filelength_impl(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (fd,) = vm_args!(vm, stack_ptr; i32);
+    let size = file_length(vm, fd);
+    narrow_position(vm, size)
+}
+
+fn filelengthi64_impl(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (fd,) = vm_args!(vm, stack_ptr; i32);
+    let size = file_length(vm, fd);
+    wide_position(vm, size)
+}
+
+// Nothing else can touch guest files, so byte-range locks always succeed.
+fn locking_impl(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (fd,) = vm_args!(vm, stack_ptr; i32);
+    if vm.crt_fd(fd).is_none() {
+        return fail(vm, EBADF);
+    }
+    0
+}
+
+fn isatty_impl(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (fd,) = vm_args!(vm, stack_ptr; i32);
+    match vm.crt_fd(fd) {
+        Some(entry) if matches!(entry.target, CrtTarget::Console) => FDEV,
+        Some(_) => 0,
+        None => {
+            set_errno(vm, EBADF);
+            0
+        }
+    }
+}
+
+// Every text flavour translates line ends the same way here.
+fn setmode_impl(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (fd, mode) = vm_args!(vm, stack_ptr; i32, u32);
+    if !matches!(mode, O_TEXT | O_BINARY | O_WTEXT | O_U16TEXT | O_U8TEXT) {
+        return fail(vm, EINVAL);
+    }
+    let Some(entry) = vm.crt_fd_mut(fd) else {
+        return fail(vm, EBADF);
+    };
+    let old = if entry.text { O_TEXT } else { O_BINARY };
+    entry.text = mode != O_BINARY;
+    old
+}
+
+fn umask_impl(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (mask,) = vm_args!(vm, stack_ptr; u32);
+    vm.crt_set_umask(mask & (S_IREAD | S_IWRITE))
+}
+
+fn umask_s_impl(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (mask, old) = vm_args!(vm, stack_ptr; u32, u32);
+    if old == 0 || mask & !(S_IREAD | S_IWRITE) != 0 {
+        set_errno(vm, EINVAL);
+        return EINVAL as u32;
+    }
+    let previous = vm.crt_set_umask(mask);
+    let _ = vm.write_u32(old, previous);
+    0
+}
+
+fn unlink(vm: &mut Vm, stack_ptr: u32, wide: bool) -> u32 {
+    let (path,) = vm_args!(vm, stack_ptr; u32);
+    let Some(path) = read_string(vm, path, wide) else {
+        return fail(vm, EINVAL);
+    };
+    match vm.file_metadata(&path) {
+        Ok(metadata) if metadata.is_dir() || metadata.read_only_value() => fail(vm, EACCES),
+        _ => match vm.file_delete(&path) {
+            Ok(()) => 0,
+            Err(err) => io_fail(vm, &err),
+        },
+    }
+}
+
+fn unlink_impl(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    unlink(vm, stack_ptr, false)
+}
+
+fn wunlink_impl(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    unlink(vm, stack_ptr, true)
+}
+
+// `_access` modes: 0 existence, 2 write, 4 read, 6 both. Returns the errno.
+fn check_access(vm: &mut Vm, path: u32, mode: u32, wide: bool) -> i32 {
+    let Some(path) = read_string(vm, path, wide) else {
+        return EINVAL;
+    };
+    if mode & !6 != 0 {
+        return EINVAL;
+    }
+    match vm.file_metadata(&path) {
+        Ok(metadata) if mode & 2 != 0 && metadata.read_only_value() => EACCES,
+        Ok(_) => 0,
+        Err(_) => ENOENT,
+    }
+}
+
+fn access(vm: &mut Vm, stack_ptr: u32, wide: bool) -> u32 {
+    let (path, mode) = vm_args!(vm, stack_ptr; u32, u32);
+    match check_access(vm, path, mode, wide) {
+        0 => 0,
+        errno => fail(vm, errno),
+    }
+}
+
+fn access_s(vm: &mut Vm, stack_ptr: u32, wide: bool) -> u32 {
+    let (path, mode) = vm_args!(vm, stack_ptr; u32, u32);
+    let errno = check_access(vm, path, mode, wide);
+    if errno != 0 {
+        set_errno(vm, errno);
+    }
+    errno as u32
+}
+
+fn access_impl(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    access(vm, stack_ptr, false)
+}
+
+fn access_s_impl(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    access_s(vm, stack_ptr, false)
+}
+
+fn waccess_impl(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    access(vm, stack_ptr, true)
+}
+
+fn waccess_s_impl(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    access_s(vm, stack_ptr, true)
+}
+
+// Only the write bit means anything: without it the file is read-only.
+fn chmod(vm: &mut Vm, stack_ptr: u32, wide: bool) -> u32 {
+    let (path, mode) = vm_args!(vm, stack_ptr; u32, u32);
+    let Some(path) = read_string(vm, path, wide) else {
+        return fail(vm, EINVAL);
+    };
+    match vm.file_set_read_only(&path, mode & S_IWRITE == 0) {
+        Ok(()) => 0,
+        Err(err) => io_fail(vm, &err),
+    }
+}
+
+fn chmod_impl(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    chmod(vm, stack_ptr, false)
+}
+
+fn wchmod_impl(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    chmod(vm, stack_ptr, true)
+}
+
+// `_mktemp`: the trailing XXXXXX become a letter and the five-digit process
+// id, trying letters from `a` until the name is unused. Returns the errno.
+fn make_temp(vm: &mut Vm, template: u32, wide: bool) -> i32 {
+    let Some(pattern) = read_string(vm, template, wide) else {
+        return EINVAL;
+    };
+    let Some(stem) = pattern.strip_suffix("XXXXXX") else {
+        return EINVAL;
+    };
+    let pid = format!("{:05}", 1);
+    for letter in 'a'..='z' {
+        let name = format!("{stem}{letter}{pid}");
+        if !vm.file_exists(&name) {
+            store_string(vm, template, &name, wide);
+            return 0;
+        }
+    }
+    EEXIST
+}
+
+fn mktemp(vm: &mut Vm, stack_ptr: u32, wide: bool) -> u32 {
+    let (template,) = vm_args!(vm, stack_ptr; u32);
+    match make_temp(vm, template, wide) {
+        0 => template,
+        errno => {
+            set_errno(vm, errno);
+            0
+        }
+    }
+}
+
+fn mktemp_s(vm: &mut Vm, stack_ptr: u32, wide: bool) -> u32 {
+    let (template, size) = vm_args!(vm, stack_ptr; u32, usize);
+    let length = read_string(vm, template, wide).map_or(0, |text| text.encode_utf16().count());
+    let errno = if template == 0 || size <= length {
+        EINVAL
+    } else {
+        make_temp(vm, template, wide)
+    };
+    if errno != 0 {
+        set_errno(vm, errno);
+    }
+    errno as u32
+}
+
+fn mktemp_impl(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    mktemp(vm, stack_ptr, false)
+}
+
+fn mktemp_s_impl(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    mktemp_s(vm, stack_ptr, false)
+}
+
+fn wmktemp_impl(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    mktemp(vm, stack_ptr, true)
+}
+
+fn wmktemp_s_impl(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    mktemp_s(vm, stack_ptr, true)
+}
+
+// Pipes are unbounded, so the requested size is ignored.
+fn pipe_impl(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (fds, _size, mode) = vm_args!(vm, stack_ptr; u32, u32, u32);
+    if fds == 0 {
+        return fail(vm, EINVAL);
+    }
+    let (read, write) = vm.crt_pipe(mode & O_BINARY == 0);
+    let _ = vm.write_u32(fds, read as u32);
+    let _ = vm.write_u32(fds + 4, write as u32);
+    0
+}
+
+// `_getcwd` and `_getdcwd` fill `buffer`, or a malloc'd buffer of at least
+// `max` characters when it is null.
+fn store_dir(vm: &mut Vm, dir: &str, buffer: u32, max: i32, wide: bool) -> u32 {
+    let length = dir.encode_utf16().count() + 1;
+    let width = if wide { 2 } else { 1 };
+    let buffer = if buffer == 0 {
+        crt_alloc(vm, length.max(max.max(0) as usize) * width)
+    } else if max <= 0 {
+        set_errno(vm, EINVAL);
+        return 0;
+    } else if length > max as usize {
+        set_errno(vm, ERANGE);
+        return 0;
+    } else {
+        buffer
+    };
+    if buffer != 0 {
+        store_string(vm, buffer, dir, wide);
+    }
+    buffer
+}
+
+fn getcwd(vm: &mut Vm, stack_ptr: u32, wide: bool) -> u32 {
+    let (buffer, max) = vm_args!(vm, stack_ptr; u32, i32);
+    let dir = vm.current_dir().to_string();
+    store_dir(vm, &dir, buffer, max, wide)
+}
+
+// Drive 0 is the current drive, 1 is A: and so on.
+fn drive_dir(vm: &Vm, drive: u32) -> Option<String> {
+    if drive == 0 {
+        return Some(vm.current_dir().to_string());
+    }
+    let letter = u8::try_from(drive + u32::from(b'A') - 1).ok()?;
+    vm.drive_letters()
+        .contains(&letter)
+        .then(|| vm.full_path(&format!("{}:", char::from(letter))))
+}
+
+fn getdcwd(vm: &mut Vm, stack_ptr: u32, wide: bool) -> u32 {
+    let (drive, buffer, max) = vm_args!(vm, stack_ptr; u32, u32, i32);
+    let Some(dir) = drive_dir(vm, drive) else {
+        set_errno(vm, EACCES);
+        return 0;
+    };
+    store_dir(vm, &dir, buffer, max, wide)
+}
+
+fn getcwd_impl(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    getcwd(vm, stack_ptr, false)
+}
+
+fn wgetcwd_impl(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    getcwd(vm, stack_ptr, true)
+}
+
+fn getdcwd_impl(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    getdcwd(vm, stack_ptr, false)
+}
+
+fn wgetdcwd_impl(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    getdcwd(vm, stack_ptr, true)
+}
+
+// The guest runs one thread at a time, so the unlocked forms are the same.
+fn getdcwd_nolock_impl(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    getdcwd(vm, stack_ptr, false)
+}
+
+fn wgetdcwd_nolock_impl(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    getdcwd(vm, stack_ptr, true)
+}
+
+fn chdir(vm: &mut Vm, stack_ptr: u32, wide: bool) -> u32 {
+    let (path,) = vm_args!(vm, stack_ptr; u32);
+    let Some(path) = read_string(vm, path, wide) else {
+        return fail(vm, EINVAL);
+    };
+    match vm.set_current_dir(&path) {
+        Ok(()) => 0,
+        Err(err) => io_fail(vm, &err),
+    }
+}
+
+fn chdir_impl(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    chdir(vm, stack_ptr, false)
+}
+
+fn wchdir_impl(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    chdir(vm, stack_ptr, true)
+}
+
+fn mkdir(vm: &mut Vm, stack_ptr: u32, wide: bool) -> u32 {
+    let (path,) = vm_args!(vm, stack_ptr; u32);
+    let Some(path) = read_string(vm, path, wide) else {
+        return fail(vm, EINVAL);
+    };
+    if vm.file_exists(&path) {
+        return fail(vm, EEXIST);
+    }
+    match vm.file_create_dir(&path) {
+        Ok(()) => 0,
+        Err(err) => io_fail(vm, &err),
+    }
+}
+
+fn mkdir_impl(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    mkdir(vm, stack_ptr, false)
+}
+
+fn wmkdir_impl(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    mkdir(vm, stack_ptr, true)
+}
+
+fn rmdir(vm: &mut Vm, stack_ptr: u32, wide: bool) -> u32 {
+    let (path,) = vm_args!(vm, stack_ptr; u32);
+    let Some(path) = read_string(vm, path, wide) else {
+        return fail(vm, EINVAL);
+    };
+    match vm.file_remove_dir(&path) {
+        Ok(()) => 0,
+        Err(err) => io_fail(vm, &err),
+    }
+}
+
+fn rmdir_impl(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    rmdir(vm, stack_ptr, false)
+}
+
+fn wrmdir_impl(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    rmdir(vm, stack_ptr, true)
+}
+
+fn getdrive_impl(vm: &mut Vm, _stack_ptr: u32) -> u32 {
+    match vm.current_dir().as_bytes() {
+        [letter, b':', ..] => u32::from(letter.to_ascii_uppercase() - b'A' + 1),
+        _ => 0,
+    }
+}
+
+fn chdrive_impl(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (drive,) = vm_args!(vm, stack_ptr; u32);
+    if drive == 0 {
+        return fail(vm, EACCES);
+    }
+    let Some(dir) = drive_dir(vm, drive) else {
+        return fail(vm, EACCES);
+    };
+    match vm.set_current_dir(&dir) {
+        Ok(()) => 0,
+        Err(_) => fail(vm, EACCES),
+    }
+}
+
+fn get_osfhandle_impl(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (fd,) = vm_args!(vm, stack_ptr; i32);
+    match vm.crt_fd(fd).map(|entry| &entry.target) {
+        Some(CrtTarget::File(handle)) => *handle,
+        // STD_INPUT_HANDLE, STD_OUTPUT_HANDLE and STD_ERROR_HANDLE.
+        Some(CrtTarget::Console) if (0..3).contains(&fd) => (-10 - fd) as u32,
+        _ => fail(vm, EBADF),
+    }
+}
+
+fn open_osfhandle_impl(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (handle, flags) = vm_args!(vm, stack_ptr; u32, u32);
+    let target = if vm.file_handle_metadata(handle).is_some() {
+        CrtTarget::File(handle)
+    } else if (0xFFFF_FFF4..=0xFFFF_FFF6).contains(&handle) {
+        CrtTarget::Console
+    } else {
+        return fail(vm, EBADF);
+    };
+    let mut entry = CrtFd::new(target, flags & O_BINARY == 0);
+    entry.append = flags & O_APPEND != 0;
+    vm.crt_insert_fd(entry) as u32
+}
+
+fn unix_time(time: Option<SystemTime>) -> i64 {
+    time.and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |elapsed| elapsed.as_secs() as i64)
+}
+
+// Creation, access and write times; missing ones fall back to the write time.
+fn unix_times(metadata: &FsMetadata) -> [i64; 3] {
+    let times = metadata.times_value();
+    let modified = unix_time(times.modified_value());
+    let or_modified = |time: Option<SystemTime>| match unix_time(time) {
+        0 => modified,
+        time => time,
+    };
+    [
+        or_modified(times.created_value()),
+        or_modified(times.accessed_value()),
+        modified,
+    ]
+}
+
+fn write_value(vm: &mut Vm, ptr: u32, value: i64, wide: bool) {
+    let _ = if wide {
+        vm.write_u64(ptr, value as u64)
+    } else {
+        vm.write_u32(ptr, value as u32)
+    };
+}
 
 // File find
-define_stub_fn!(DLL, findfirst32_impl, 0);
-define_stub_fn!(DLL, findfirst32i64_impl, 0);
-define_stub_fn!(DLL, findfirst64_impl, 0);
-define_stub_fn!(DLL, findfirst64i32_impl, 0);
-define_stub_fn!(DLL, findnext32_impl, 0);
-define_stub_fn!(DLL, findnext32i64_impl, 0);
-define_stub_fn!(DLL, findnext64_impl, 0);
-define_stub_fn!(DLL, findnext64i32_impl, 0);
-define_stub_fn!(DLL, findclose_impl, 0);
-define_stub_fn!(DLL, wfindfirst32_impl, 0);
-define_stub_fn!(DLL, wfindfirst32i64_impl, 0);
-define_stub_fn!(DLL, wfindfirst64_impl, 0);
-define_stub_fn!(DLL, wfindfirst64i32_impl, 0);
-define_stub_fn!(DLL, wfindnext32_impl, 0);
-define_stub_fn!(DLL, wfindnext32i64_impl, 0);
-define_stub_fn!(DLL, wfindnext64_impl, 0);
-define_stub_fn!(DLL, wfindnext64i32_impl, 0);
+
+/// Where the times, size and name sit in one of the `_finddata` structures.
+struct FindLayout {
+    times: u32,
+    time64: bool,
+    size: u32,
+    size64: bool,
+    name: u32,
+}
+
+const FIND32: FindLayout = FindLayout {
+    times: 4,
+    time64: false,
+    size: 16,
+    size64: false,
+    name: 20,
+};
+const FIND32I64: FindLayout = FindLayout {
+    times: 4,
+    time64: false,
+    size: 16,
+    size64: true,
+    name: 24,
+};
+const FIND64I32: FindLayout = FindLayout {
+    times: 8,
+    time64: true,
+    size: 32,
+    size64: false,
+    name: 36,
+};
+const FIND64: FindLayout = FindLayout {
+    times: 8,
+    time64: true,
+    size: 32,
+    size64: true,
+    name: 40,
+};
+
+fn write_find_data(vm: &mut Vm, ptr: u32, entry: &FindEntry, layout: &FindLayout, wide: bool) {
+    let metadata = &entry.metadata;
+    let mut attrib = if metadata.is_dir() { A_SUBDIR } else { A_ARCH };
+    if metadata.read_only_value() {
+        attrib |= A_RDONLY;
+    }
+    let _ = vm.write_u32(ptr, attrib);
+    let step = if layout.time64 { 8 } else { 4 };
+    for (index, time) in unix_times(metadata).into_iter().enumerate() {
+        write_value(
+            vm,
+            ptr + layout.times + index as u32 * step,
+            time,
+            layout.time64,
+        );
+    }
+    write_value(vm, ptr + layout.size, metadata.size() as i64, layout.size64);
+    let name: String = entry.name.chars().take(MAX_PATH - 1).collect();
+    store_string(vm, ptr + layout.name, &name, wide);
+}
+
+fn find_failure(vm: &mut Vm, err: &io::Error) -> u32 {
+    match err.kind() {
+        io::ErrorKind::NotFound | io::ErrorKind::NotADirectory => fail(vm, ENOENT),
+        _ => io_fail(vm, err),
+    }
+}
+
+fn findfirst(vm: &mut Vm, stack_ptr: u32, layout: &FindLayout, wide: bool) -> u32 {
+    let (pattern, data) = vm_args!(vm, stack_ptr; u32, u32);
+    let (Some(pattern), true) = (read_string(vm, pattern, wide), data != 0) else {
+        return fail(vm, EINVAL);
+    };
+    match vm.find_first(&pattern, false) {
+        Ok((handle, entry)) => {
+            write_find_data(vm, data, &entry, layout, wide);
+            handle
+        }
+        Err(err) => find_failure(vm, &err),
+    }
+}
+
+fn findnext(vm: &mut Vm, stack_ptr: u32, layout: &FindLayout, wide: bool) -> u32 {
+    let (handle, data) = vm_args!(vm, stack_ptr; u32, u32);
+    if data == 0 {
+        return fail(vm, EINVAL);
+    }
+    match vm.find_next(handle) {
+        Some(Some(entry)) => {
+            write_find_data(vm, data, &entry, layout, wide);
+            0
+        }
+        Some(None) => fail(vm, ENOENT),
+        None => fail(vm, EINVAL),
+    }
+}
+
+fn findfirst32_impl(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    findfirst(vm, stack_ptr, &FIND32, false)
+}
+
+fn findfirst32i64_impl(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    findfirst(vm, stack_ptr, &FIND32I64, false)
+}
+
+fn findfirst64_impl(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    findfirst(vm, stack_ptr, &FIND64, false)
+}
+
+fn findfirst64i32_impl(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    findfirst(vm, stack_ptr, &FIND64I32, false)
+}
+
+fn findnext32_impl(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    findnext(vm, stack_ptr, &FIND32, false)
+}
+
+fn findnext32i64_impl(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    findnext(vm, stack_ptr, &FIND32I64, false)
+}
+
+fn findnext64_impl(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    findnext(vm, stack_ptr, &FIND64, false)
+}
+
+fn findnext64i32_impl(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    findnext(vm, stack_ptr, &FIND64I32, false)
+}
+
+fn findclose_impl(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (handle,) = vm_args!(vm, stack_ptr; u32);
+    if vm.find_close(handle) {
+        0
+    } else {
+        fail(vm, ENOENT)
+    }
+}
+
+fn wfindfirst32_impl(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    findfirst(vm, stack_ptr, &FIND32, true)
+}
+
+fn wfindfirst32i64_impl(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    findfirst(vm, stack_ptr, &FIND32I64, true)
+}
+
+fn wfindfirst64_impl(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    findfirst(vm, stack_ptr, &FIND64, true)
+}
+
+fn wfindfirst64i32_impl(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    findfirst(vm, stack_ptr, &FIND64I32, true)
+}
+
+fn wfindnext32_impl(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    findnext(vm, stack_ptr, &FIND32, true)
+}
+
+fn wfindnext32i64_impl(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    findnext(vm, stack_ptr, &FIND32I64, true)
+}
+
+fn wfindnext64_impl(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    findnext(vm, stack_ptr, &FIND64, true)
+}
+
+fn wfindnext64i32_impl(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    findnext(vm, stack_ptr, &FIND64I32, true)
+}
 
 // File stat
-define_stub_fn!(DLL, stat32_impl, 0);
-define_stub_fn!(DLL, stat32i64_impl, 0);
-define_stub_fn!(DLL, stat64_impl, 0);
-define_stub_fn!(DLL, stat64i32_impl, 0);
-define_stub_fn!(DLL, wstat32_impl, 0);
-define_stub_fn!(DLL, wstat32i64_impl, 0);
-define_stub_fn!(DLL, wstat64_impl, 0);
-define_stub_fn!(DLL, wstat64i32_impl, 0);
-define_stub_fn!(DLL, fstat32_impl, 0);
-define_stub_fn!(DLL, fstat32i64_impl, 0);
-define_stub_fn!(DLL, fstat64_impl, 0);
-define_stub_fn!(DLL, fstat64i32_impl, 0);
+
+/// The size and layout of one of the `_stat` structures.
+struct StatLayout {
+    len: u32,
+    size: u32,
+    size64: bool,
+    times: u32,
+    time64: bool,
+}
+
+const STAT32: StatLayout = StatLayout {
+    len: 36,
+    size: 20,
+    size64: false,
+    times: 24,
+    time64: false,
+};
+const STAT32I64: StatLayout = StatLayout {
+    len: 48,
+    size: 24,
+    size64: true,
+    times: 32,
+    time64: false,
+};
+const STAT64I32: StatLayout = StatLayout {
+    len: 48,
+    size: 20,
+    size64: false,
+    times: 24,
+    time64: true,
+};
+const STAT64: StatLayout = StatLayout {
+    len: 56,
+    size: 24,
+    size64: true,
+    times: 32,
+    time64: true,
+};
+
+// Permission bits are copied from the owner to group and others.
+fn file_mode(metadata: &FsMetadata, path: &str) -> u32 {
+    let executable = [".exe", ".com", ".bat", ".cmd"]
+        .iter()
+        .any(|ext| path.to_ascii_lowercase().ends_with(ext));
+    let mut mode = if metadata.is_dir() {
+        S_IFDIR | S_IEXEC
+    } else {
+        S_IFREG
+    };
+    mode |= S_IREAD;
+    if !metadata.read_only_value() {
+        mode |= S_IWRITE;
+    }
+    if executable {
+        mode |= S_IEXEC;
+    }
+    mode | (mode & 0x1C0) >> 3 | (mode & 0x1C0) >> 6
+}
+
+fn write_stat(
+    vm: &mut Vm,
+    ptr: u32,
+    layout: &StatLayout,
+    device: u32,
+    mode: u32,
+    metadata: Option<&FsMetadata>,
+) {
+    let _ = vm.memset(ptr, 0, layout.len as usize);
+    let _ = vm.write_u32(ptr, device);
+    let _ = vm.write_u16(ptr + 6, mode as u16);
+    let _ = vm.write_u16(ptr + 8, 1);
+    let _ = vm.write_u32(ptr + 16, device);
+    let Some(metadata) = metadata else {
+        return;
+    };
+    write_value(vm, ptr + layout.size, metadata.size() as i64, layout.size64);
+    let [created, accessed, modified] = unix_times(metadata);
+    let step = if layout.time64 { 8 } else { 4 };
+    for (index, time) in [accessed, modified, created].into_iter().enumerate() {
+        write_value(
+            vm,
+            ptr + layout.times + index as u32 * step,
+            time,
+            layout.time64,
+        );
+    }
+}
+
+fn stat(vm: &mut Vm, stack_ptr: u32, layout: &StatLayout, wide: bool) -> u32 {
+    let (path, buffer) = vm_args!(vm, stack_ptr; u32, u32);
+    let (Some(path), true) = (read_string(vm, path, wide), buffer != 0) else {
+        return fail(vm, EINVAL);
+    };
+    let full = vm.full_path(&path);
+    let metadata = match vm.file_metadata(&full) {
+        Ok(metadata) => metadata,
+        Err(_) => return fail(vm, ENOENT),
+    };
+    // st_dev is the drive number, 0 for A:.
+    let device = match full.as_bytes() {
+        [letter, b':', ..] => u32::from(letter.to_ascii_uppercase() - b'A'),
+        _ => 0,
+    };
+    let mode = file_mode(&metadata, &full);
+    write_stat(vm, buffer, layout, device, mode, Some(&metadata));
+    0
+}
+
+fn fstat(vm: &mut Vm, stack_ptr: u32, layout: &StatLayout) -> u32 {
+    let (fd, buffer) = vm_args!(vm, stack_ptr; i32, u32);
+    if buffer == 0 {
+        return fail(vm, EINVAL);
+    }
+    let Some(target) = vm.crt_fd(fd).map(|entry| entry.target.clone()) else {
+        return fail(vm, EBADF);
+    };
+    let read_write = S_IREAD | S_IWRITE;
+    match target {
+        CrtTarget::Console => write_stat(vm, buffer, layout, fd as u32, S_IFCHR | read_write, None),
+        CrtTarget::Pipe(_) => write_stat(vm, buffer, layout, fd as u32, S_IFIFO | read_write, None),
+        CrtTarget::File(_) => {
+            let metadata = match vm.crt_fd_metadata(fd) {
+                Some(Ok(metadata)) => metadata,
+                Some(Err(err)) => return io_fail(vm, &err),
+                None => return fail(vm, EBADF),
+            };
+            let mode = file_mode(&metadata, "");
+            write_stat(vm, buffer, layout, 0, mode, Some(&metadata));
+        }
+    }
+    0
+}
+
+fn stat32_impl(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    stat(vm, stack_ptr, &STAT32, false)
+}
+
+fn stat32i64_impl(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    stat(vm, stack_ptr, &STAT32I64, false)
+}
+
+fn stat64_impl(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    stat(vm, stack_ptr, &STAT64, false)
+}
+
+fn stat64i32_impl(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    stat(vm, stack_ptr, &STAT64I32, false)
+}
+
+fn wstat32_impl(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    stat(vm, stack_ptr, &STAT32, true)
+}
+
+fn wstat32i64_impl(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    stat(vm, stack_ptr, &STAT32I64, true)
+}
+
+fn wstat64_impl(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    stat(vm, stack_ptr, &STAT64, true)
+}
+
+fn wstat64i32_impl(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    stat(vm, stack_ptr, &STAT64I32, true)
+}
+
+fn fstat32_impl(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    fstat(vm, stack_ptr, &STAT32)
+}
+
+fn fstat32i64_impl(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    fstat(vm, stack_ptr, &STAT32I64)
+}
+
+fn fstat64_impl(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    fstat(vm, stack_ptr, &STAT64)
+}
+
+fn fstat64i32_impl(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    fstat(vm, stack_ptr, &STAT64I32)
+}
 
 // Pioinfo
 define_stub_fn!(DLL, pioinfo, 0);
@@ -155,6 +1208,8 @@ pub fn register(vm: &mut Vm) {
     vm.register_import(DLL, "_wrmdir", wrmdir_impl);
     vm.register_import(DLL, "_getdrive", getdrive_impl);
     vm.register_import(DLL, "_chdrive", chdrive_impl);
+    vm.register_import(DLL, "_get_osfhandle", get_osfhandle_impl);
+    vm.register_import(DLL, "_open_osfhandle", open_osfhandle_impl);
 
     // File find
     vm.register_import(DLL, "_findfirst32", findfirst32_impl);
@@ -193,3 +1248,87 @@ pub fn register(vm: &mut Vm) {
     vm.register_import(DLL, "__pioinfo", pioinfo);
     vm.register_import(DLL, "__badioinfo", badioinfo);
 }
+
+#[cfg(test)]
+mod tests {
+    use super::super::stdlib::errno;
+    use super::*;
+    use crate::vm::{Architecture, MemoryFs, VmConfig};
+    use crate::vm_set_args;
+
+    const PATH: u32 = 0x4000;
+    const BUFFER: u32 = 0x5000;
+
+    fn create_test_vm() -> Vm {
+        let config = VmConfig::new()
+            .architecture(Architecture::X86)
+            .mount("C:\\data", MemoryFs::new());
+        let mut vm = Vm::new(config).expect("vm");
+        vm.memory.map(0x1000, 0x10000);
+        vm.base = 0x1000;
+        vm.stack_top = 0x1000 + 0x10000 - 4;
+        vm.regs.esp = vm.stack_top;
+        vm.heap_start = 0x6000;
+        vm.heap_end = 0x8000;
+        vm
+    }
+
+    #[test]
+    fn descriptors_write_seek_and_read_back() {
+        let mut vm = create_test_vm();
+        let stack = vm.stack_top - 32;
+        vm.write_bytes(PATH, b"C:\\data\\log.txt\0").expect("path");
+        vm_set_args!(vm, stack; PATH, O_RDWR | O_CREAT | O_BINARY, S_IREAD | S_IWRITE);
+        let fd = open_impl(&mut vm, stack);
+        assert_eq!(fd, 3);
+
+        vm.write_bytes(BUFFER, b"hello").expect("data");
+        vm_set_args!(vm, stack; fd, BUFFER, 5u32);
+        assert_eq!(write_impl(&mut vm, stack), 5);
+        vm_set_args!(vm, stack; fd, 1u32, 0u32);
+        assert_eq!(lseek_impl(&mut vm, stack), 1);
+        vm_set_args!(vm, stack; fd, BUFFER + 0x10, 16u32);
+        assert_eq!(read_impl(&mut vm, stack), 4);
+        assert_eq!(vm.read_bytes(BUFFER + 0x10, 4).expect("read"), b"ello");
+        vm_set_args!(vm, stack; fd);
+        assert_eq!(eof_impl(&mut vm, stack), 1);
+        assert_eq!(close_impl(&mut vm, stack), 0);
+        assert_eq!(close_impl(&mut vm, stack), u32::MAX);
+        assert_eq!(errno(&mut vm), EBADF);
+
+        // O_EXCL refuses a file that already exists.
+        vm_set_args!(vm, stack; PATH, O_WRONLY | O_CREAT | O_EXCL, S_IWRITE);
+        assert_eq!(open_impl(&mut vm, stack), u32::MAX);
+        assert_eq!(errno(&mut vm), EEXIST);
+    }
+
+    #[test]
+    fn find_and_stat_describe_files() {
+        let mut vm = create_test_vm();
+        let stack = vm.stack_top - 32;
+        let options = FsOpenOptions::new().write(true).create(true);
+        let fd = vm
+            .crt_open("C:\\data\\a.exe", options, false, false, false)
+            .expect("create");
+        vm.crt_write(fd, b"1234").expect("fd").expect("write");
+        vm.crt_close(fd);
+        vm.write_bytes(PATH, b"C:\\data\\*.exe\0").expect("pattern");
+        vm_set_args!(vm, stack; PATH, BUFFER);
+        let handle = findfirst64i32_impl(&mut vm, stack);
+        assert_ne!(handle, u32::MAX);
+        assert_eq!(vm.read_u32(BUFFER).expect("attrib"), A_ARCH);
+        assert_eq!(vm.read_u32(BUFFER + 32).expect("size"), 4);
+        assert_eq!(vm.read_c_string(BUFFER + 36).expect("name"), "a.exe");
+        vm_set_args!(vm, stack; handle, BUFFER);
+        assert_eq!(findnext64i32_impl(&mut vm, stack), u32::MAX);
+        vm_set_args!(vm, stack; handle);
+        assert_eq!(findclose_impl(&mut vm, stack), 0);
+
+        vm.write_bytes(PATH, b"C:\\data\\a.exe\0").expect("path");
+        vm_set_args!(vm, stack; PATH, BUFFER);
+        assert_eq!(stat32_impl(&mut vm, stack), 0);
+        assert_eq!(vm.read_u32(BUFFER).expect("dev"), 2);
+        assert_eq!(vm.read_u16(BUFFER + 6).expect("mode"), 0x81FF);
+        assert_eq!(vm.read_u32(BUFFER + 20).expect("size"), 4);
+    }
+}
@@ -1,14 +1,9 @@
 //! Memory management stubs for MSVCR100.dll.
 
-use crate::vm::Vm;
+use crate::vm::{HeapApi, Vm, PROCESS_HEAP};
 
 const DLL: &str = "MSVCR100.dll";
 
-// Memory allocation
-define_stub_fn!(DLL, malloc_impl, 0);
-define_stub_fn!(DLL, calloc_impl, 0);
-define_stub_fn!(DLL, realloc_impl, 0);
-define_stub_fn!(DLL, free_impl, 0);
 define_stub_fn!(DLL, aligned_malloc, 0);
 define_stub_fn!(DLL, aligned_realloc, 0);
 define_stub_fn!(DLL, aligned_recalloc, 0);
@@ -17,7 +12,6 @@ define_stub_fn!(DLL, aligned_msize, 0);
 define_stub_fn!(DLL, aligned_offset_malloc, 0);
 define_stub_fn!(DLL, aligned_offset_realloc, 0);
 define_stub_fn!(DLL, aligned_offset_recalloc, 0);
-define_stub_fn!(DLL, msize, 0);
 define_stub_fn!(DLL, expand, 0);
 define_stub_fn!(DLL, heapchk, 0);
 define_stub_fn!(DLL, heapmin, 0);
@@ -51,12 +45,6 @@ define_stub_fn!(DLL, crt_check_memory, 0);
 define_stub_fn!(DLL, crt_dump_memory_leaks, 0);
 define_stub_fn!(DLL, get_heap_handle, 0);
 define_stub_fn!(DLL, sbh_heap_init, 0);
-define_stub_fn!(DLL, new_operator, 0);
-define_stub_fn!(DLL, new_operator_debug, 0);
-define_stub_fn!(DLL, delete_operator, 0);
-define_stub_fn!(DLL, new_array_operator, 0);
-define_stub_fn!(DLL, new_array_operator_debug, 0);
-define_stub_fn!(DLL, delete_array_operator, 0);
 define_stub_fn!(DLL, nh_malloc, 0);
 define_stub_fn!(DLL, nh_malloc_dbg, 0);
 define_stub_fn!(DLL, heap_alloc, 0);
@@ -69,6 +57,77 @@ define_stub_fn!(DLL, onexit, 0);
 define_stub_fn!(DLL, initterm, 0);
 define_stub_fn!(DLL, initterm_e, 0);
 
+// The CRT heap is the process heap, as with the DLL CRT on Windows.
+fn malloc_impl(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (size,) = vm_args!(vm, stack_ptr; usize);
+    crt_alloc(vm, size)
+}
+
+fn calloc_impl(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (count, size) = vm_args!(vm, stack_ptr; usize, usize);
+    match count.checked_mul(size) {
+        Some(total) => crt_alloc(vm, total),
+        None => 0,
+    }
+}
+
+fn realloc_impl(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (ptr, size) = vm_args!(vm, stack_ptr; u32, u32);
+    if ptr == 0 {
+        return crt_alloc(vm, size as usize);
+    }
+    if size == 0 {
+        vm.heap_free(ptr);
+        return 0;
+    }
+    vm.heap_realloc_in(PROCESS_HEAP, ptr, size, false)
+        .unwrap_or(0)
+}
+
+fn free_impl(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (ptr,) = vm_args!(vm, stack_ptr; u32);
+    if ptr != 0 {
+        vm.heap_free(ptr);
+    }
+    0
+}
+
+fn msize(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (ptr,) = vm_args!(vm, stack_ptr; u32);
+    vm.heap_size_in(PROCESS_HEAP, ptr).unwrap_or(u32::MAX)
+}
+
+// operator new/new[] (including the debug overloads, whose extra arguments are ignored).
+fn new_operator(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    malloc_impl(vm, stack_ptr)
+}
+
+fn new_operator_debug(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    malloc_impl(vm, stack_ptr)
+}
+
+fn new_array_operator(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    malloc_impl(vm, stack_ptr)
+}
+
+fn new_array_operator_debug(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    malloc_impl(vm, stack_ptr)
+}
+
+fn delete_operator(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    free_impl(vm, stack_ptr)
+}
+
+fn delete_array_operator(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    free_impl(vm, stack_ptr)
+}
+
+pub(super) fn crt_alloc(vm: &mut Vm, size: usize) -> u32 {
+    let ptr = vm.heap_alloc(size);
+    vm.heap_debug_tag(ptr, HeapApi::Malloc);
+    ptr
+}
+
 pub fn register(vm: &mut Vm) {
     // Standard C memory functions
     vm.register_import(DLL, "malloc", malloc_impl);
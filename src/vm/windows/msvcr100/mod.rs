@@ -8,6 +8,7 @@ pub const DLL_NAME: &str = "MSVCR100.dll";
 mod concurrency;
 mod crt;
 mod exception;
+mod format;
 mod io;
 mod locale;
 mod math;
@@ -15,6 +16,7 @@ mod memory;
 mod process;
 mod stdio;
 mod stdlib;
+mod stream;
 mod string;
 mod time;
 
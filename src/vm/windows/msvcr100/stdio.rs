@@ -1,300 +1,1369 @@
-//! Standard I/O function stubs for MSVCR100.dll.
+//! Standard I/O for MSVCR100.dll: `FILE` streams, the printf and scanf
+//! families, and console I/O.
 
 use crate::vm::Vm;
 
+use super::format::{self, narrow, read_units, write_units, TextInput, VaList};
+use super::memory::crt_alloc;
+use super::stdlib::{errno, errno_message, io_errno, set_errno, EBADF, EINVAL, ERANGE};
+use super::stream::{self, StreamInput, EOF, IOEOF, IOERR, IOFBF, IONBF};
+
 const DLL: &str = "MSVCR100.dll";
 
+const WEOF: u32 = 0xFFFF;
+/// `_TRUNCATE`: the `_snprintf_s` count asking for truncation.
+const TRUNCATE: u32 = u32::MAX;
+/// `BUFSIZ`, the buffer size `setbuf` buffers are assumed to have.
+const BUFSIZ: u32 = 512;
+const MAX_STDIO: u32 = 2048;
+
+fn arg(vm: &Vm, stack_ptr: u32, index: u32) -> u32 {
+    vm.read_u32(stack_ptr + 4 + index * 4).unwrap_or(0)
+}
+
+fn read_string(vm: &Vm, ptr: u32, wide: bool) -> String {
+    if wide {
+        String::from_utf16_lossy(&read_units(vm, ptr, true, usize::MAX))
+    } else {
+        vm.read_c_string(ptr).unwrap_or_default()
+    }
+}
+
+fn invalid(vm: &mut Vm, value: u32) -> u32 {
+    set_errno(vm, EINVAL);
+    value
+}
+
+// Store units followed by a terminator.
+fn store_string(vm: &mut Vm, ptr: u32, units: &[u16], wide: bool) {
+    write_units(vm, ptr, units, wide);
+    let end = ptr.wrapping_add(units.len() as u32 * if wide { 2 } else { 1 });
+    write_units(vm, end, &[0], wide);
+}
+
 // File I/O
-define_stub_fn!(DLL, fopen_impl, 0);
-define_stub_fn!(DLL, fopen_s_impl, 0);
-define_stub_fn!(DLL, freopen_impl, 0);
-define_stub_fn!(DLL, freopen_s_impl, 0);
-define_stub_fn!(DLL, fclose_impl, 0);
-define_stub_fn!(DLL, fflush_impl, 0);
-define_stub_fn!(DLL, fread_impl, 0);
-define_stub_fn!(DLL, fread_s_impl, 0);
-define_stub_fn!(DLL, fwrite_impl, 0);
-define_stub_fn!(DLL, fgetc_impl, 0);
-define_stub_fn!(DLL, fgets_impl, 0);
-define_stub_fn!(DLL, fputc_impl, 0);
-define_stub_fn!(DLL, fputs_impl, 0);
-define_stub_fn!(DLL, fgetwc_impl, 0);
-define_stub_fn!(DLL, fgetws_impl, 0);
-define_stub_fn!(DLL, fputwc_impl, 0);
-define_stub_fn!(DLL, fputws_impl, 0);
-define_stub_fn!(DLL, ungetc_impl, 0);
-define_stub_fn!(DLL, ungetwc_impl, 0);
-define_stub_fn!(DLL, fseek_impl, 0);
-define_stub_fn!(DLL, fseeki64_impl, 0);
-define_stub_fn!(DLL, ftell_impl, 0);
-define_stub_fn!(DLL, ftelli64_impl, 0);
-define_stub_fn!(DLL, fsetpos_impl, 0);
-define_stub_fn!(DLL, fgetpos_impl, 0);
-define_stub_fn!(DLL, rewind_impl, 0);
-define_stub_fn!(DLL, feof_impl, 0);
-define_stub_fn!(DLL, ferror_impl, 0);
-define_stub_fn!(DLL, clearerr_impl, 0);
-define_stub_fn!(DLL, clearerr_s_impl, 0);
-define_stub_fn!(DLL, setbuf_impl, 0);
-define_stub_fn!(DLL, setvbuf_impl, 0);
-define_stub_fn!(DLL, tmpfile_impl, 0);
-define_stub_fn!(DLL, tmpfile_s_impl, 0);
-define_stub_fn!(DLL, tmpnam_impl, 0);
-define_stub_fn!(DLL, tmpnam_s_impl, 0);
-define_stub_fn!(DLL, tempnam_impl, 0);
-define_stub_fn!(DLL, wtmpnam_impl, 0);
-define_stub_fn!(DLL, wtmpnam_s_impl, 0);
-define_stub_fn!(DLL, wtempnam_impl, 0);
-define_stub_fn!(DLL, remove_impl, 0);
-define_stub_fn!(DLL, rename_impl, 0);
-define_stub_fn!(DLL, wremove_impl, 0);
-define_stub_fn!(DLL, wrename_impl, 0);
-define_stub_fn!(DLL, perror_impl, 0);
-define_stub_fn!(DLL, wperror_impl, 0);
+
+fn open_stream(vm: &mut Vm, path: u32, mode: u32, wide: bool) -> Result<u32, i32> {
+    if path == 0 || mode == 0 {
+        return Err(EINVAL);
+    }
+    let path = read_string(vm, path, wide);
+    let mode = read_string(vm, mode, wide);
+    stream::open(vm, &path, &mode)
+}
+
+fn fopen(vm: &mut Vm, stack_ptr: u32, wide: bool) -> u32 {
+    let (path, mode) = vm_args!(vm, stack_ptr; u32, u32);
+    open_stream(vm, path, mode, wide).unwrap_or_else(|errno| {
+        set_errno(vm, errno);
+        0
+    })
+}
+
+fn fopen_s(vm: &mut Vm, stack_ptr: u32, wide: bool) -> u32 {
+    let (out, path, mode) = vm_args!(vm, stack_ptr; u32, u32, u32);
+    if out == 0 {
+        return invalid(vm, EINVAL as u32);
+    }
+    let (stream, errno) = match open_stream(vm, path, mode, wide) {
+        Ok(stream) => (stream, 0),
+        Err(errno) => (0, errno),
+    };
+    let _ = vm.write_u32(out, stream);
+    if errno != 0 {
+        set_errno(vm, errno);
+    }
+    errno as u32
+}
+
+fn fopen_impl(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    fopen(vm, stack_ptr, false)
+}
+
+fn wfopen_impl(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    fopen(vm, stack_ptr, true)
+}
+
+// Sharing modes have no meaning for guest files.
+fn fsopen_impl(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    fopen(vm, stack_ptr, false)
+}
+
+fn wfsopen_impl(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    fopen(vm, stack_ptr, true)
+}
+
+fn fopen_s_impl(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    fopen_s(vm, stack_ptr, false)
+}
+
+fn wfopen_s_impl(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    fopen_s(vm, stack_ptr, true)
+}
+
+fn reopen_stream(vm: &mut Vm, path: u32, mode: u32, stream: u32) -> Result<u32, i32> {
+    if path == 0 || mode == 0 || stream == 0 {
+        return Err(EINVAL);
+    }
+    let path = read_string(vm, path, false);
+    let mode = read_string(vm, mode, false);
+    stream::reopen(vm, stream, &path, &mode)
+}
+
+fn freopen_impl(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (path, mode, stream) = vm_args!(vm, stack_ptr; u32, u32, u32);
+    reopen_stream(vm, path, mode, stream).unwrap_or_else(|errno| {
+        set_errno(vm, errno);
+        0
+    })
+}
+
+fn freopen_s_impl(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (out, path, mode, stream) = vm_args!(vm, stack_ptr; u32, u32, u32, u32);
+    if out == 0 {
+        return invalid(vm, EINVAL as u32);
+    }
+    let (stream, errno) = match reopen_stream(vm, path, mode, stream) {
+        Ok(stream) => (stream, 0),
+        Err(errno) => (0, errno),
+    };
+    let _ = vm.write_u32(out, stream);
+    if errno != 0 {
+        set_errno(vm, errno);
+    }
+    errno as u32
+}
+
+fn fclose_impl(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (stream,) = vm_args!(vm, stack_ptr; u32);
+    if !stream::is_open(vm, stream) {
+        return invalid(vm, EOF);
+    }
+    if stream::close(vm, stream) {
+        0
+    } else {
+        EOF
+    }
+}
+
+// Closes every stream but stdin, stdout and stderr.
+fn fcloseall_impl(vm: &mut Vm, _stack_ptr: u32) -> u32 {
+    let standard: Vec<u32> = (0..3).map(|index| stream::std_stream(vm, index)).collect();
+    let mut closed = 0;
+    for stream in stream::open_streams(vm) {
+        if !standard.contains(&stream) && stream::close(vm, stream) {
+            closed += 1;
+        }
+    }
+    closed
+}
+
+fn fflush_impl(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (stream,) = vm_args!(vm, stack_ptr; u32);
+    if stream == 0 {
+        let failed = stream::open_streams(vm)
+            .into_iter()
+            .filter(|stream| !stream::flush(vm, *stream))
+            .count();
+        return if failed == 0 { 0 } else { EOF };
+    }
+    if !stream::is_open(vm, stream) || stream::flush(vm, stream) {
+        0
+    } else {
+        EOF
+    }
+}
+
+fn flushall_impl(vm: &mut Vm, _stack_ptr: u32) -> u32 {
+    let streams = stream::open_streams(vm);
+    for stream in &streams {
+        stream::flush(vm, *stream);
+    }
+    streams.len() as u32
+}
+
+fn read_items(
+    vm: &mut Vm,
+    buffer: u32,
+    capacity: Option<usize>,
+    size: u32,
+    count: u32,
+    stream: u32,
+) -> u32 {
+    let total = (size as usize).saturating_mul(count as usize);
+    if total == 0 {
+        return 0;
+    }
+    if buffer == 0 || !stream::is_open(vm, stream) {
+        return invalid(vm, 0);
+    }
+    let wanted = capacity.map_or(total, |capacity| capacity.min(total));
+    let data = stream::read(vm, stream, wanted);
+    let _ = vm.write_bytes(buffer, &data);
+    if wanted < total && data.len() == wanted {
+        set_errno(vm, ERANGE);
+    }
+    (data.len() / size as usize) as u32
+}
+
+fn fread_impl(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (buffer, size, count, stream) = vm_args!(vm, stack_ptr; u32, u32, u32, u32);
+    read_items(vm, buffer, None, size, count, stream)
+}
+
+fn fread_s_impl(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (buffer, capacity, size, count, stream) =
+        vm_args!(vm, stack_ptr; u32, usize, u32, u32, u32);
+    read_items(vm, buffer, Some(capacity), size, count, stream)
+}
+
+fn fwrite_impl(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (buffer, size, count, stream) = vm_args!(vm, stack_ptr; u32, u32, u32, u32);
+    let total = (size as usize).saturating_mul(count as usize);
+    if total == 0 {
+        return 0;
+    }
+    if buffer == 0 || !stream::is_open(vm, stream) {
+        return invalid(vm, 0);
+    }
+    let data = vm.read_bytes(buffer, total).unwrap_or_default();
+    (stream::write(vm, stream, &data) / size as usize) as u32
+}
+
+fn getc_impl(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (stream,) = vm_args!(vm, stack_ptr; u32);
+    if !stream::is_open(vm, stream) {
+        return invalid(vm, EOF);
+    }
+    stream::getc(vm, stream)
+}
+
+fn putc_impl(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (ch, stream) = vm_args!(vm, stack_ptr; u32, u32);
+    if !stream::is_open(vm, stream) {
+        return invalid(vm, EOF);
+    }
+    stream::putc(vm, ch, stream)
+}
+
+fn getwc_impl(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (stream,) = vm_args!(vm, stack_ptr; u32);
+    if !stream::is_open(vm, stream) {
+        return invalid(vm, WEOF);
+    }
+    stream::getwc(vm, stream)
+}
+
+fn putwc_impl(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (ch, stream) = vm_args!(vm, stack_ptr; u32, u32);
+    if !stream::is_open(vm, stream) {
+        return invalid(vm, WEOF);
+    }
+    stream::putwc(vm, ch as u16, stream)
+}
+
+fn ungetc_impl(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (ch, stream) = vm_args!(vm, stack_ptr; u32, u32);
+    if !stream::is_open(vm, stream) {
+        return invalid(vm, EOF);
+    }
+    stream::ungetc(vm, ch, stream)
+}
+
+fn ungetwc_impl(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (ch, stream) = vm_args!(vm, stack_ptr; u32, u32);
+    if !stream::is_open(vm, stream) {
+        return invalid(vm, WEOF);
+    }
+    stream::ungetwc(vm, ch & 0xFFFF, stream)
+}
+
+// Read a line, keeping the newline, into at most `size - 1` units.
+fn read_line(vm: &mut Vm, buffer: u32, size: u32, stream: u32, wide: bool) -> u32 {
+    if buffer == 0 || (size as i32) <= 0 || !stream::is_open(vm, stream) {
+        return invalid(vm, 0);
+    }
+    let mut units = Vec::new();
+    while units.len() + 1 < size as usize {
+        let unit = if wide {
+            Some(stream::getwc(vm, stream)).filter(|unit| *unit != WEOF)
+        } else {
+            Some(stream::getc(vm, stream)).filter(|byte| *byte != EOF)
+        };
+        let Some(unit) = unit else {
+            break;
+        };
+        units.push(unit as u16);
+        if unit == u32::from(b'\n') {
+            break;
+        }
+    }
+    if units.is_empty() && size > 1 {
+        return 0;
+    }
+    store_string(vm, buffer, &units, wide);
+    buffer
+}
+
+fn fgets_impl(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (buffer, size, stream) = vm_args!(vm, stack_ptr; u32, u32, u32);
+    read_line(vm, buffer, size, stream, false)
+}
+
+fn fgetws_impl(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (buffer, size, stream) = vm_args!(vm, stack_ptr; u32, u32, u32);
+    read_line(vm, buffer, size, stream, true)
+}
+
+fn write_text(vm: &mut Vm, stream: u32, units: &[u16], wide: bool) -> bool {
+    let written = if wide {
+        stream::write_wide(vm, stream, units)
+    } else {
+        stream::write(vm, stream, &narrow(units))
+    };
+    written == units.len()
+}
+
+fn fputs_impl(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (text, stream) = vm_args!(vm, stack_ptr; u32, u32);
+    if text == 0 || !stream::is_open(vm, stream) {
+        return invalid(vm, EOF);
+    }
+    let units = read_units(vm, text, false, usize::MAX);
+    if write_text(vm, stream, &units, false) {
+        0
+    } else {
+        EOF
+    }
+}
+
+fn fputws_impl(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (text, stream) = vm_args!(vm, stack_ptr; u32, u32);
+    if text == 0 || !stream::is_open(vm, stream) {
+        return invalid(vm, WEOF);
+    }
+    let units = read_units(vm, text, true, usize::MAX);
+    if write_text(vm, stream, &units, true) {
+        0
+    } else {
+        WEOF
+    }
+}
+
+fn seek_stream(vm: &mut Vm, stream: u32, offset: i64, origin: u32) -> u32 {
+    match stream::seek(vm, stream, offset, origin) {
+        Ok(()) => 0,
+        Err(errno) => {
+            set_errno(vm, errno);
+            u32::MAX
+        }
+    }
+}
+
+fn fseek_impl(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (stream, offset, origin) = vm_args!(vm, stack_ptr; u32, i32, u32);
+    seek_stream(vm, stream, i64::from(offset), origin)
+}
+
+fn fseeki64_impl(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (stream, low, high, origin) = vm_args!(vm, stack_ptr; u32, u32, u32, u32);
+    let offset = (u64::from(high) << 32 | u64::from(low)) as i64;
+    seek_stream(vm, stream, offset, origin)
+}
+
+fn tell_stream(vm: &mut Vm, stream: u32) -> Option<u64> {
+    if !stream::is_open(vm, stream) {
+        set_errno(vm, EINVAL);
+        return None;
+    }
+    stream::tell(vm, stream)
+        .map_err(|errno| set_errno(vm, errno))
+        .ok()
+}
+
+fn ftell_impl(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (stream,) = vm_args!(vm, stack_ptr; u32);
+    match tell_stream(vm, stream) {
+        Some(position) if position <= i32::MAX as u64 => position as u32,
+        Some(_) => invalid(vm, u32::MAX),
+        None => u32::MAX,
+    }
+}
+
+fn ftelli64_impl(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (stream,) = vm_args!(vm, stack_ptr; u32);
+    let position = tell_stream(vm, stream).unwrap_or(u64::MAX);
+    vm.regs.edx = (position >> 32) as u32;
+    position as u32
+}
+
+fn fsetpos_impl(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (stream, pos) = vm_args!(vm, stack_ptr; u32, u32);
+    if pos == 0 {
+        return invalid(vm, EINVAL as u32);
+    }
+    let position = vm.read_u64(pos).unwrap_or(0);
+    seek_stream(vm, stream, position as i64, 0)
+}
+
+fn fgetpos_impl(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (stream, pos) = vm_args!(vm, stack_ptr; u32, u32);
+    if pos == 0 {
+        return invalid(vm, EINVAL as u32);
+    }
+    match tell_stream(vm, stream) {
+        Some(position) => {
+            let _ = vm.write_u64(pos, position);
+            0
+        }
+        None => u32::MAX,
+    }
+}
+
+fn rewind_impl(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (stream,) = vm_args!(vm, stack_ptr; u32);
+    if stream::is_open(vm, stream) {
+        let _ = stream::seek(vm, stream, 0, 0);
+        stream::clear_error(vm, stream);
+    }
+    0
+}
+
+fn feof_impl(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (stream,) = vm_args!(vm, stack_ptr; u32);
+    if stream == 0 {
+        return invalid(vm, 0);
+    }
+    stream::flag(vm, stream) & IOEOF
+}
+
+fn ferror_impl(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (stream,) = vm_args!(vm, stack_ptr; u32);
+    if stream == 0 {
+        return invalid(vm, 0);
+    }
+    stream::flag(vm, stream) & IOERR
+}
+
+fn clearerr_impl(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (stream,) = vm_args!(vm, stack_ptr; u32);
+    if stream != 0 {
+        stream::clear_error(vm, stream);
+    }
+    0
+}
+
+fn clearerr_s_impl(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (stream,) = vm_args!(vm, stack_ptr; u32);
+    if stream == 0 {
+        return invalid(vm, EINVAL as u32);
+    }
+    stream::clear_error(vm, stream);
+    0
+}
+
+fn setbuf_impl(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (stream, buffer) = vm_args!(vm, stack_ptr; u32, u32);
+    if buffer == 0 {
+        stream::set_buffer(vm, stream, 0, IONBF, 0);
+    } else {
+        stream::set_buffer(vm, stream, buffer, IOFBF, BUFSIZ);
+    }
+    0
+}
+
+fn setvbuf_impl(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (stream, buffer, mode, size) = vm_args!(vm, stack_ptr; u32, u32, u32, u32);
+    if stream::set_buffer(vm, stream, buffer, mode, size) {
+        0
+    } else {
+        invalid(vm, u32::MAX)
+    }
+}
+
+// `tmpnam` names sit in the root of the current drive, numbered from one.
+fn temp_name(vm: &mut Vm) -> String {
+    loop {
+        let name = format!("\\s1.{}", vm.crt_next_tmp());
+        if !vm.file_exists(&name) {
+            return name;
+        }
+    }
+}
+
+fn open_temp(vm: &mut Vm) -> Result<u32, i32> {
+    let name = temp_name(vm);
+    stream::open(vm, &name, "w+bD")
+}
+
+fn tmpfile_impl(vm: &mut Vm, _stack_ptr: u32) -> u32 {
+    open_temp(vm).unwrap_or_else(|errno| {
+        set_errno(vm, errno);
+        0
+    })
+}
+
+fn tmpfile_s_impl(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (out,) = vm_args!(vm, stack_ptr; u32);
+    if out == 0 {
+        return invalid(vm, EINVAL as u32);
+    }
+    match open_temp(vm) {
+        Ok(stream) => {
+            let _ = vm.write_u32(out, stream);
+            0
+        }
+        Err(errno) => {
+            let _ = vm.write_u32(out, 0);
+            set_errno(vm, errno);
+            errno as u32
+        }
+    }
+}
+
+fn tmpnam(vm: &mut Vm, stack_ptr: u32, wide: bool) -> u32 {
+    let (buffer,) = vm_args!(vm, stack_ptr; u32);
+    let name: Vec<u16> = temp_name(vm).encode_utf16().collect();
+    let buffer = if buffer == 0 {
+        vm.crt_tmpnam_buffer()
+    } else {
+        buffer
+    };
+    store_string(vm, buffer, &name, wide);
+    buffer
+}
+
+fn tmpnam_s(vm: &mut Vm, stack_ptr: u32, wide: bool) -> u32 {
+    let (buffer, size) = vm_args!(vm, stack_ptr; u32, usize);
+    if buffer == 0 {
+        return invalid(vm, EINVAL as u32);
+    }
+    let name: Vec<u16> = temp_name(vm).encode_utf16().collect();
+    if name.len() >= size {
+        if size > 0 {
+            store_string(vm, buffer, &[], wide);
+        }
+        set_errno(vm, ERANGE);
+        return ERANGE as u32;
+    }
+    store_string(vm, buffer, &name, wide);
+    0
+}
+
+fn tmpnam_impl(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    tmpnam(vm, stack_ptr, false)
+}
+
+fn tmpnam_s_impl(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    tmpnam_s(vm, stack_ptr, false)
+}
+
+fn wtmpnam_impl(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    tmpnam(vm, stack_ptr, true)
+}
+
+fn wtmpnam_s_impl(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    tmpnam_s(vm, stack_ptr, true)
+}
+
+// `_tempnam`: a fresh name in `dir` (or the drive root when it is not a
+// directory), returned in a malloc'd buffer.
+fn tempnam(vm: &mut Vm, stack_ptr: u32, wide: bool) -> u32 {
+    let (dir, prefix) = vm_args!(vm, stack_ptr; u32, u32);
+    let dir = read_string(vm, dir, wide);
+    let prefix = read_string(vm, prefix, wide);
+    let dir = if !dir.is_empty() && vm.file_metadata(&dir).is_ok_and(|meta| meta.is_dir()) {
+        dir.trim_end_matches(['\\', '/']).to_string()
+    } else {
+        String::new()
+    };
+    let name = loop {
+        let name = format!("{dir}\\{prefix}{}", vm.crt_next_tmp());
+        if !vm.file_exists(&name) {
+            break name;
+        }
+    };
+    let units: Vec<u16> = name.encode_utf16().collect();
+    let width = if wide { 2 } else { 1 };
+    let buffer = crt_alloc(vm, (units.len() + 1) * width);
+    if buffer != 0 {
+        store_string(vm, buffer, &units, wide);
+    }
+    buffer
+}
+
+fn tempnam_impl(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    tempnam(vm, stack_ptr, false)
+}
+
+fn wtempnam_impl(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    tempnam(vm, stack_ptr, true)
+}
+
+fn remove(vm: &mut Vm, stack_ptr: u32, wide: bool) -> u32 {
+    let (path,) = vm_args!(vm, stack_ptr; u32);
+    if path == 0 {
+        return invalid(vm, u32::MAX);
+    }
+    let path = read_string(vm, path, wide);
+    match vm.file_delete(&path) {
+        Ok(()) => 0,
+        Err(err) => {
+            set_errno(vm, io_errno(&err));
+            u32::MAX
+        }
+    }
+}
+
+fn rename(vm: &mut Vm, stack_ptr: u32, wide: bool) -> u32 {
+    let (from, to) = vm_args!(vm, stack_ptr; u32, u32);
+    if from == 0 || to == 0 {
+        return invalid(vm, u32::MAX);
+    }
+    let from = read_string(vm, from, wide);
+    let to = read_string(vm, to, wide);
+    match vm.file_move(&from, &to, false) {
+        Ok(()) => 0,
+        Err(err) => {
+            set_errno(vm, io_errno(&err));
+            u32::MAX
+        }
+    }
+}
+
+fn remove_impl(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    remove(vm, stack_ptr, false)
+}
+
+fn wremove_impl(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    remove(vm, stack_ptr, true)
+}
+
+fn rename_impl(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    rename(vm, stack_ptr, false)
+}
+
+fn wrename_impl(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    rename(vm, stack_ptr, true)
+}
+
+// perror: "prefix: message" on stderr, or just the message.
+fn perror(vm: &mut Vm, stack_ptr: u32, wide: bool) -> u32 {
+    let (prefix,) = vm_args!(vm, stack_ptr; u32);
+    let prefix = read_string(vm, prefix, wide);
+    let message = errno_message(errno(vm));
+    let text = if prefix.is_empty() {
+        format!("{message}\n")
+    } else {
+        format!("{prefix}: {message}\n")
+    };
+    let stderr = stream::std_stream(vm, 2);
+    stream::write(vm, stderr, text.as_bytes());
+    0
+}
+
+fn perror_impl(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    perror(vm, stack_ptr, false)
+}
+
+fn wperror_impl(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    perror(vm, stack_ptr, true)
+}
 
 // Console I/O
-define_stub_fn!(DLL, getc_impl, 0);
-define_stub_fn!(DLL, getchar_impl, 0);
-define_stub_fn!(DLL, gets_impl, 0);
-define_stub_fn!(DLL, gets_s_impl, 0);
-define_stub_fn!(DLL, putc_impl, 0);
-define_stub_fn!(DLL, putchar_impl, 0);
-define_stub_fn!(DLL, puts_impl, 0);
-define_stub_fn!(DLL, getwc_impl, 0);
-define_stub_fn!(DLL, getwchar_impl, 0);
-define_stub_fn!(DLL, putwc_impl, 0);
-define_stub_fn!(DLL, putwchar_impl, 0);
-define_stub_fn!(DLL, getch_impl, 0);
-define_stub_fn!(DLL, getche_impl, 0);
-define_stub_fn!(DLL, ungetch_impl, 0);
-define_stub_fn!(DLL, getwch_impl, 0);
-define_stub_fn!(DLL, getwche_impl, 0);
-define_stub_fn!(DLL, ungetwch_impl, 0);
-define_stub_fn!(DLL, kbhit_impl, 0);
-define_stub_fn!(DLL, cgets_impl, 0);
-define_stub_fn!(DLL, cgets_s_impl, 0);
-define_stub_fn!(DLL, cgetws_impl, 0);
-define_stub_fn!(DLL, cgetws_s_impl, 0);
-define_stub_fn!(DLL, cputs_impl, 0);
-define_stub_fn!(DLL, cputws_impl, 0);
-define_stub_fn!(DLL, getch_nolock_impl, 0);
-define_stub_fn!(DLL, getche_nolock_impl, 0);
-define_stub_fn!(DLL, ungetch_nolock_impl, 0);
-define_stub_fn!(DLL, getwch_nolock_impl, 0);
-define_stub_fn!(DLL, getwche_nolock_impl, 0);
-define_stub_fn!(DLL, ungetwch_nolock_impl, 0);
-define_stub_fn!(DLL, getc_nolock_impl, 0);
-define_stub_fn!(DLL, putc_nolock_impl, 0);
-define_stub_fn!(DLL, ungetc_nolock_impl, 0);
-define_stub_fn!(DLL, getwc_nolock_impl, 0);
-define_stub_fn!(DLL, putwc_nolock_impl, 0);
-define_stub_fn!(DLL, ungetwc_nolock_impl, 0);
+
+fn getchar_impl(vm: &mut Vm, _stack_ptr: u32) -> u32 {
+    let stdin = stream::std_stream(vm, 0);
+    stream::getc(vm, stdin)
+}
+
+fn putchar_impl(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (ch,) = vm_args!(vm, stack_ptr; u32);
+    let stdout = stream::std_stream(vm, 1);
+    stream::putc(vm, ch, stdout)
+}
+
+fn getwchar_impl(vm: &mut Vm, _stack_ptr: u32) -> u32 {
+    let stdin = stream::std_stream(vm, 0);
+    stream::getwc(vm, stdin)
+}
+
+fn putwchar_impl(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (ch,) = vm_args!(vm, stack_ptr; u32);
+    let stdout = stream::std_stream(vm, 1);
+    stream::putwc(vm, ch as u16, stdout)
+}
+
+// A line from stdin without its newline; `None` at end of input.
+fn stdin_line(vm: &mut Vm) -> Option<Vec<u8>> {
+    let stdin = stream::std_stream(vm, 0);
+    let mut line = Vec::new();
+    loop {
+        match stream::getc(vm, stdin) {
+            EOF if line.is_empty() => return None,
+            EOF => return Some(line),
+            byte if byte == u32::from(b'\n') => return Some(line),
+            byte => line.push(byte as u8),
+        }
+    }
+}
+
+fn gets_impl(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (buffer,) = vm_args!(vm, stack_ptr; u32);
+    if buffer == 0 {
+        return invalid(vm, 0);
+    }
+    match stdin_line(vm) {
+        Some(line) => {
+            let _ = vm.write_bytes(buffer, &line);
+            let _ = vm.write_u8(buffer + line.len() as u32, 0);
+            buffer
+        }
+        None => 0,
+    }
+}
+
+fn gets_s_impl(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (buffer, size) = vm_args!(vm, stack_ptr; u32, usize);
+    if buffer == 0 || size == 0 {
+        return invalid(vm, 0);
+    }
+    let Some(line) = stdin_line(vm) else {
+        return 0;
+    };
+    if line.len() >= size {
+        let _ = vm.write_u8(buffer, 0);
+        set_errno(vm, ERANGE);
+        return 0;
+    }
+    let _ = vm.write_bytes(buffer, &line);
+    let _ = vm.write_u8(buffer + line.len() as u32, 0);
+    buffer
+}
+
+fn puts_impl(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (text,) = vm_args!(vm, stack_ptr; u32);
+    if text == 0 {
+        return invalid(vm, EOF);
+    }
+    let mut units = read_units(vm, text, false, usize::MAX);
+    units.push(u16::from(b'\n'));
+    let stdout = stream::std_stream(vm, 1);
+    if write_text(vm, stdout, &units, false) {
+        0
+    } else {
+        EOF
+    }
+}
+
+// The console has no keyboard: only a key pushed back with `_ungetch` is
+// ever there to read.
+fn getch(vm: &mut Vm, echo: bool, eof: u32) -> u32 {
+    let Some(key) = vm.crt_take_ungetch() else {
+        return eof;
+    };
+    if echo {
+        let text = char::from_u32(key).map(String::from).unwrap_or_default();
+        vm.write_stdout(&text);
+    }
+    key
+}
+
+fn getch_impl(vm: &mut Vm, _stack_ptr: u32) -> u32 {
+    getch(vm, false, EOF)
+}
+
+fn getche_impl(vm: &mut Vm, _stack_ptr: u32) -> u32 {
+    getch(vm, true, EOF)
+}
+
+fn getwch_impl(vm: &mut Vm, _stack_ptr: u32) -> u32 {
+    getch(vm, false, WEOF)
+}
+
+fn getwche_impl(vm: &mut Vm, _stack_ptr: u32) -> u32 {
+    getch(vm, true, WEOF)
+}
+
+fn ungetch(vm: &mut Vm, stack_ptr: u32, eof: u32) -> u32 {
+    let (key,) = vm_args!(vm, stack_ptr; u32);
+    if key == eof || !vm.crt_set_ungetch(key) {
+        return eof;
+    }
+    key
+}
+
+fn ungetch_impl(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    ungetch(vm, stack_ptr, EOF)
+}
+
+fn ungetwch_impl(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    ungetch(vm, stack_ptr, WEOF)
+}
+
+fn kbhit_impl(vm: &mut Vm, _stack_ptr: u32) -> u32 {
+    u32::from(vm.crt_peek_ungetch().is_some())
+}
+
+// `_cgets` buffers hold the capacity and length ahead of the text, which
+// stays empty.
+fn cgets_impl(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (buffer,) = vm_args!(vm, stack_ptr; u32);
+    if buffer == 0 {
+        return invalid(vm, 0);
+    }
+    let _ = vm.write_u8(buffer + 1, 0);
+    let _ = vm.write_u8(buffer + 2, 0);
+    buffer + 2
+}
+
+fn cgetws_impl(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (buffer,) = vm_args!(vm, stack_ptr; u32);
+    if buffer == 0 {
+        return invalid(vm, 0);
+    }
+    let _ = vm.write_u16(buffer + 2, 0);
+    let _ = vm.write_u16(buffer + 4, 0);
+    buffer + 4
+}
+
+fn cgets_s(vm: &mut Vm, stack_ptr: u32, wide: bool) -> u32 {
+    let (buffer, size, read) = vm_args!(vm, stack_ptr; u32, u32, u32);
+    if buffer == 0 || size == 0 || read == 0 {
+        return invalid(vm, EINVAL as u32);
+    }
+    store_string(vm, buffer, &[], wide);
+    let _ = vm.write_u32(read, 0);
+    0
+}
+
+fn cgets_s_impl(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    cgets_s(vm, stack_ptr, false)
+}
+
+fn cgetws_s_impl(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    cgets_s(vm, stack_ptr, true)
+}
+
+fn cputs_impl(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (text,) = vm_args!(vm, stack_ptr; u32);
+    if text == 0 {
+        return invalid(vm, u32::MAX);
+    }
+    console_write(vm, &read_units(vm, text, false, usize::MAX), false);
+    0
+}
+
+fn cputws_impl(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (text,) = vm_args!(vm, stack_ptr; u32);
+    if text == 0 {
+        return invalid(vm, u32::MAX);
+    }
+    console_write(vm, &read_units(vm, text, true, usize::MAX), true);
+    0
+}
+
+fn console_write(vm: &mut Vm, units: &[u16], wide: bool) {
+    let text = if wide {
+        String::from_utf16_lossy(units)
+    } else {
+        String::from_utf8_lossy(&narrow(units)).into_owned()
+    };
+    vm.write_stdout(&text);
+}
 
 // Printf family
-define_stub_fn!(DLL, printf_impl, 0);
-define_stub_fn!(DLL, printf_s_impl, 0);
-define_stub_fn!(DLL, printf_l_impl, 0);
-define_stub_fn!(DLL, printf_s_l_impl, 0);
-define_stub_fn!(DLL, printf_p_impl, 0);
-define_stub_fn!(DLL, printf_p_l_impl, 0);
-define_stub_fn!(DLL, wprintf_impl, 0);
-define_stub_fn!(DLL, wprintf_s_impl, 0);
-define_stub_fn!(DLL, wprintf_l_impl, 0);
-define_stub_fn!(DLL, wprintf_s_l_impl, 0);
-define_stub_fn!(DLL, wprintf_p_impl, 0);
-define_stub_fn!(DLL, wprintf_p_l_impl, 0);
-define_stub_fn!(DLL, fprintf_impl, 0);
-define_stub_fn!(DLL, fprintf_s_impl, 0);
-define_stub_fn!(DLL, fprintf_l_impl, 0);
-define_stub_fn!(DLL, fprintf_s_l_impl, 0);
-define_stub_fn!(DLL, fprintf_p_impl, 0);
-define_stub_fn!(DLL, fprintf_p_l_impl, 0);
-define_stub_fn!(DLL, fwprintf_impl, 0);
-define_stub_fn!(DLL, fwprintf_s_impl, 0);
-define_stub_fn!(DLL, fwprintf_l_impl, 0);
-define_stub_fn!(DLL, fwprintf_s_l_impl, 0);
-define_stub_fn!(DLL, fwprintf_p_impl, 0);
-define_stub_fn!(DLL, fwprintf_p_l_impl, 0);
-define_stub_fn!(DLL, sprintf_impl, 0);
-define_stub_fn!(DLL, sprintf_s_impl, 0);
-define_stub_fn!(DLL, sprintf_l_impl, 0);
-define_stub_fn!(DLL, sprintf_s_l_impl, 0);
-define_stub_fn!(DLL, sprintf_p_impl, 0);
-define_stub_fn!(DLL, sprintf_p_l_impl, 0);
-define_stub_fn!(DLL, swprintf_impl, 0);
-define_stub_fn!(DLL, swprintf_s_impl, 0);
-define_stub_fn!(DLL, swprintf_c_impl, 0);
-define_stub_fn!(DLL, swprintf_c_l_impl, 0);
-define_stub_fn!(DLL, swprintf_l_impl, 0);
-define_stub_fn!(DLL, swprintf_s_l_impl, 0);
-define_stub_fn!(DLL, swprintf_p_impl, 0);
-define_stub_fn!(DLL, swprintf_p_l_impl, 0);
-define_stub_fn!(DLL, snprintf_impl, 0);
-define_stub_fn!(DLL, snprintf_s_impl, 0);
-define_stub_fn!(DLL, snprintf_l_impl, 0);
-define_stub_fn!(DLL, snprintf_s_l_impl, 0);
-define_stub_fn!(DLL, snprintf_c_impl, 0);
-define_stub_fn!(DLL, snprintf_c_l_impl, 0);
-define_stub_fn!(DLL, snwprintf_impl, 0);
-define_stub_fn!(DLL, snwprintf_s_impl, 0);
-define_stub_fn!(DLL, snwprintf_l_impl, 0);
-define_stub_fn!(DLL, snwprintf_s_l_impl, 0);
-define_stub_fn!(DLL, scprintf_impl, 0);
-define_stub_fn!(DLL, scprintf_l_impl, 0);
-define_stub_fn!(DLL, scprintf_p_impl, 0);
-define_stub_fn!(DLL, scprintf_p_l_impl, 0);
-define_stub_fn!(DLL, scwprintf_impl, 0);
-define_stub_fn!(DLL, scwprintf_l_impl, 0);
-define_stub_fn!(DLL, scwprintf_p_impl, 0);
-define_stub_fn!(DLL, scwprintf_p_l_impl, 0);
-define_stub_fn!(DLL, cprintf_impl, 0);
-define_stub_fn!(DLL, cprintf_l_impl, 0);
-define_stub_fn!(DLL, cprintf_s_impl, 0);
-define_stub_fn!(DLL, cprintf_s_l_impl, 0);
-define_stub_fn!(DLL, cprintf_p_impl, 0);
-define_stub_fn!(DLL, cprintf_p_l_impl, 0);
-define_stub_fn!(DLL, cwprintf_impl, 0);
-define_stub_fn!(DLL, cwprintf_l_impl, 0);
-define_stub_fn!(DLL, cwprintf_s_impl, 0);
-define_stub_fn!(DLL, cwprintf_s_l_impl, 0);
-define_stub_fn!(DLL, cwprintf_p_impl, 0);
-define_stub_fn!(DLL, cwprintf_p_l_impl, 0);
+
+// The formatted text, or `None` (with EINVAL) for a null format string.
+fn printf_text(
+    vm: &mut Vm,
+    stack_ptr: u32,
+    fmt: u32,
+    args: u32,
+    va: bool,
+    wide: bool,
+) -> Option<Vec<u16>> {
+    let fmt = arg(vm, stack_ptr, fmt);
+    if fmt == 0 {
+        set_errno(vm, EINVAL);
+        return None;
+    }
+    let fmt = read_units(vm, fmt, wide, usize::MAX);
+    let args = if va {
+        VaList::new(arg(vm, stack_ptr, args))
+    } else {
+        VaList::after(stack_ptr, args)
+    };
+    Some(format::format(vm, &fmt, args, wide))
+}
+
+// Where formatted text goes. Each returns the printf result.
+
+fn to_file(vm: &mut Vm, stream: u32, text: Vec<u16>, wide: bool) -> u32 {
+    if !stream::is_open(vm, stream) {
+        return invalid(vm, u32::MAX);
+    }
+    if write_text(vm, stream, &text, wide) {
+        text.len() as u32
+    } else {
+        u32::MAX
+    }
+}
+
+fn to_stdout(vm: &mut Vm, _stack_ptr: u32, text: Vec<u16>, wide: bool) -> u32 {
+    let stdout = stream::std_stream(vm, 1);
+    to_file(vm, stdout, text, wide)
+}
+
+fn to_stream(vm: &mut Vm, stack_ptr: u32, text: Vec<u16>, wide: bool) -> u32 {
+    let stream = arg(vm, stack_ptr, 0);
+    to_file(vm, stream, text, wide)
+}
+
+fn to_console(vm: &mut Vm, _stack_ptr: u32, text: Vec<u16>, wide: bool) -> u32 {
+    console_write(vm, &text, wide);
+    text.len() as u32
+}
+
+fn to_count(_vm: &mut Vm, _stack_ptr: u32, text: Vec<u16>, _wide: bool) -> u32 {
+    text.len() as u32
+}
+
+// sprintf: no bound at all.
+fn to_buffer(vm: &mut Vm, stack_ptr: u32, text: Vec<u16>, wide: bool) -> u32 {
+    let buffer = arg(vm, stack_ptr, 0);
+    if buffer == 0 {
+        return invalid(vm, u32::MAX);
+    }
+    store_string(vm, buffer, &text, wide);
+    text.len() as u32
+}
+
+// sprintf_s: text that does not fit empties the buffer.
+fn to_sized(vm: &mut Vm, stack_ptr: u32, text: Vec<u16>, wide: bool) -> u32 {
+    let (buffer, size) = (arg(vm, stack_ptr, 0), arg(vm, stack_ptr, 1) as usize);
+    if buffer == 0 || size == 0 {
+        return invalid(vm, u32::MAX);
+    }
+    if text.len() >= size {
+        store_string(vm, buffer, &[], wide);
+        set_errno(vm, ERANGE);
+        return u32::MAX;
+    }
+    store_string(vm, buffer, &text, wide);
+    text.len() as u32
+}
+
+// _snprintf: at most `count` units, terminated only when there is room;
+// -1 when the text was cut.
+fn to_counted(vm: &mut Vm, stack_ptr: u32, text: Vec<u16>, wide: bool) -> u32 {
+    let (buffer, count) = (arg(vm, stack_ptr, 0), arg(vm, stack_ptr, 1) as usize);
+    if buffer == 0 && count > 0 {
+        return invalid(vm, u32::MAX);
+    }
+    let take = text.len().min(count);
+    if take < count {
+        store_string(vm, buffer, &text, wide);
+    } else {
+        write_units(vm, buffer, &text[..take], wide);
+    }
+    if text.len() > count {
+        u32::MAX
+    } else {
+        text.len() as u32
+    }
+}
+
+// _snprintf_s: `count` units at most, always terminated. `_TRUNCATE` cuts
+// the text to the buffer; otherwise text too long for it empties it.
+fn to_truncated(vm: &mut Vm, stack_ptr: u32, text: Vec<u16>, wide: bool) -> u32 {
+    let buffer = arg(vm, stack_ptr, 0);
+    let size = arg(vm, stack_ptr, 1) as usize;
+    let count = arg(vm, stack_ptr, 2);
+    if buffer == 0 || size == 0 {
+        return invalid(vm, u32::MAX);
+    }
+    let limit = if count == TRUNCATE {
+        size - 1
+    } else {
+        count as usize
+    };
+    let take = text.len().min(limit);
+    if take >= size {
+        store_string(vm, buffer, &[], wide);
+        set_errno(vm, ERANGE);
+        return u32::MAX;
+    }
+    store_string(vm, buffer, &text[..take], wide);
+    if take < text.len() {
+        u32::MAX
+    } else {
+        text.len() as u32
+    }
+}
+
+// _snprintf_c and _swprintf_c: terminated within `count`; -1 when cut.
+fn to_bounded(vm: &mut Vm, stack_ptr: u32, text: Vec<u16>, wide: bool) -> u32 {
+    let (buffer, count) = (arg(vm, stack_ptr, 0), arg(vm, stack_ptr, 1) as usize);
+    if buffer == 0 || count == 0 {
+        return invalid(vm, u32::MAX);
+    }
+    let take = text.len().min(count - 1);
+    store_string(vm, buffer, &text[..take], wide);
+    if take < text.len() {
+        u32::MAX
+    } else {
+        text.len() as u32
+    }
+}
+
+// A printf variant: wide or narrow, the argument slots of its format and of
+// its first variadic argument (or its `va_list`), and where the text goes.
+macro_rules! printf_fn {
+    ($name:ident, $wide:expr, $fmt:expr, $args:expr, $va:expr, $sink:ident) => {
+        fn $name(vm: &mut Vm, stack_ptr: u32) -> u32 {
+            match printf_text(vm, stack_ptr, $fmt, $args, $va, $wide) {
+                Some(text) => $sink(vm, stack_ptr, text, $wide),
+                None => u32::MAX,
+            }
+        }
+    };
+}
+
+printf_fn!(printf_impl, false, 0, 1, false, to_stdout);
+printf_fn!(printf_s_impl, false, 0, 1, false, to_stdout);
+printf_fn!(printf_l_impl, false, 0, 2, false, to_stdout);
+printf_fn!(printf_s_l_impl, false, 0, 2, false, to_stdout);
+printf_fn!(printf_p_impl, false, 0, 1, false, to_stdout);
+printf_fn!(printf_p_l_impl, false, 0, 2, false, to_stdout);
+printf_fn!(wprintf_impl, true, 0, 1, false, to_stdout);
+printf_fn!(wprintf_s_impl, true, 0, 1, false, to_stdout);
+printf_fn!(wprintf_l_impl, true, 0, 2, false, to_stdout);
+printf_fn!(wprintf_s_l_impl, true, 0, 2, false, to_stdout);
+printf_fn!(wprintf_p_impl, true, 0, 1, false, to_stdout);
+printf_fn!(wprintf_p_l_impl, true, 0, 2, false, to_stdout);
+printf_fn!(fprintf_impl, false, 1, 2, false, to_stream);
+printf_fn!(fprintf_s_impl, false, 1, 2, false, to_stream);
+printf_fn!(fprintf_l_impl, false, 1, 3, false, to_stream);
+printf_fn!(fprintf_s_l_impl, false, 1, 3, false, to_stream);
+printf_fn!(fprintf_p_impl, false, 1, 2, false, to_stream);
+printf_fn!(fprintf_p_l_impl, false, 1, 3, false, to_stream);
+printf_fn!(fwprintf_impl, true, 1, 2, false, to_stream);
+printf_fn!(fwprintf_s_impl, true, 1, 2, false, to_stream);
+printf_fn!(fwprintf_l_impl, true, 1, 3, false, to_stream);
+printf_fn!(fwprintf_s_l_impl, true, 1, 3, false, to_stream);
+printf_fn!(fwprintf_p_impl, true, 1, 2, false, to_stream);
+printf_fn!(fwprintf_p_l_impl, true, 1, 3, false, to_stream);
+printf_fn!(sprintf_impl, false, 1, 2, false, to_buffer);
+printf_fn!(sprintf_s_impl, false, 2, 3, false, to_sized);
+printf_fn!(sprintf_l_impl, false, 1, 3, false, to_buffer);
+printf_fn!(sprintf_s_l_impl, false, 2, 4, false, to_sized);
+printf_fn!(sprintf_p_impl, false, 2, 3, false, to_sized);
+printf_fn!(sprintf_p_l_impl, false, 2, 4, false, to_sized);
+// `_swprintf` and `__swprintf_l` are the old forms without a count.
+printf_fn!(swprintf_impl, true, 1, 2, false, to_buffer);
+printf_fn!(legacy_swprintf_l_impl, true, 1, 3, false, to_buffer);
+printf_fn!(swprintf_s_impl, true, 2, 3, false, to_sized);
+printf_fn!(swprintf_c_impl, true, 2, 3, false, to_bounded);
+printf_fn!(swprintf_c_l_impl, true, 2, 4, false, to_bounded);
+printf_fn!(swprintf_l_impl, true, 2, 4, false, to_bounded);
+printf_fn!(swprintf_s_l_impl, true, 2, 4, false, to_sized);
+printf_fn!(swprintf_p_impl, true, 2, 3, false, to_sized);
+printf_fn!(swprintf_p_l_impl, true, 2, 4, false, to_sized);
+printf_fn!(snprintf_impl, false, 2, 3, false, to_counted);
+printf_fn!(snprintf_s_impl, false, 3, 4, false, to_truncated);
+printf_fn!(snprintf_l_impl, false, 2, 4, false, to_counted);
+printf_fn!(snprintf_s_l_impl, false, 3, 5, false, to_truncated);
+printf_fn!(snprintf_c_impl, false, 2, 3, false, to_bounded);
+printf_fn!(snprintf_c_l_impl, false, 2, 4, false, to_bounded);
+printf_fn!(snwprintf_impl, true, 2, 3, false, to_counted);
+printf_fn!(snwprintf_s_impl, true, 3, 4, false, to_truncated);
+printf_fn!(snwprintf_l_impl, true, 2, 4, false, to_counted);
+printf_fn!(snwprintf_s_l_impl, true, 3, 5, false, to_truncated);
+printf_fn!(scprintf_impl, false, 0, 1, false, to_count);
+printf_fn!(scprintf_l_impl, false, 0, 2, false, to_count);
+printf_fn!(scprintf_p_impl, false, 0, 1, false, to_count);
+printf_fn!(scprintf_p_l_impl, false, 0, 2, false, to_count);
+printf_fn!(scwprintf_impl, true, 0, 1, false, to_count);
+printf_fn!(scwprintf_l_impl, true, 0, 2, false, to_count);
+printf_fn!(scwprintf_p_impl, true, 0, 1, false, to_count);
+printf_fn!(scwprintf_p_l_impl, true, 0, 2, false, to_count);
+printf_fn!(cprintf_impl, false, 0, 1, false, to_console);
+printf_fn!(cprintf_l_impl, false, 0, 2, false, to_console);
+printf_fn!(cprintf_s_impl, false, 0, 1, false, to_console);
+printf_fn!(cprintf_s_l_impl, false, 0, 2, false, to_console);
+printf_fn!(cprintf_p_impl, false, 0, 1, false, to_console);
+printf_fn!(cprintf_p_l_impl, false, 0, 2, false, to_console);
+printf_fn!(cwprintf_impl, true, 0, 1, false, to_console);
+printf_fn!(cwprintf_l_impl, true, 0, 2, false, to_console);
+printf_fn!(cwprintf_s_impl, true, 0, 1, false, to_console);
+printf_fn!(cwprintf_s_l_impl, true, 0, 2, false, to_console);
+printf_fn!(cwprintf_p_impl, true, 0, 1, false, to_console);
+printf_fn!(cwprintf_p_l_impl, true, 0, 2, false, to_console);
 
 // Vprintf family
-define_stub_fn!(DLL, vprintf_impl, 0);
-define_stub_fn!(DLL, vprintf_s_impl, 0);
-define_stub_fn!(DLL, vprintf_l_impl, 0);
-define_stub_fn!(DLL, vprintf_s_l_impl, 0);
-define_stub_fn!(DLL, vprintf_p_impl, 0);
-define_stub_fn!(DLL, vprintf_p_l_impl, 0);
-define_stub_fn!(DLL, vwprintf_impl, 0);
-define_stub_fn!(DLL, vwprintf_s_impl, 0);
-define_stub_fn!(DLL, vwprintf_l_impl, 0);
-define_stub_fn!(DLL, vwprintf_s_l_impl, 0);
-define_stub_fn!(DLL, vwprintf_p_impl, 0);
-define_stub_fn!(DLL, vwprintf_p_l_impl, 0);
-define_stub_fn!(DLL, vfprintf_impl, 0);
-define_stub_fn!(DLL, vfprintf_s_impl, 0);
-define_stub_fn!(DLL, vfprintf_l_impl, 0);
-define_stub_fn!(DLL, vfprintf_s_l_impl, 0);
-define_stub_fn!(DLL, vfprintf_p_impl, 0);
-define_stub_fn!(DLL, vfprintf_p_l_impl, 0);
-define_stub_fn!(DLL, vfwprintf_impl, 0);
-define_stub_fn!(DLL, vfwprintf_s_impl, 0);
-define_stub_fn!(DLL, vfwprintf_l_impl, 0);
-define_stub_fn!(DLL, vfwprintf_s_l_impl, 0);
-define_stub_fn!(DLL, vfwprintf_p_impl, 0);
-define_stub_fn!(DLL, vfwprintf_p_l_impl, 0);
-define_stub_fn!(DLL, vsprintf_impl, 0);
-define_stub_fn!(DLL, vsprintf_s_impl, 0);
-define_stub_fn!(DLL, vsprintf_l_impl, 0);
-define_stub_fn!(DLL, vsprintf_s_l_impl, 0);
-define_stub_fn!(DLL, vsprintf_p_impl, 0);
-define_stub_fn!(DLL, vsprintf_p_l_impl, 0);
-define_stub_fn!(DLL, vswprintf_impl, 0);
-define_stub_fn!(DLL, vswprintf_s_impl, 0);
-define_stub_fn!(DLL, vswprintf_c_impl, 0);
-define_stub_fn!(DLL, vswprintf_c_l_impl, 0);
-define_stub_fn!(DLL, vswprintf_l_impl, 0);
-define_stub_fn!(DLL, vswprintf_s_l_impl, 0);
-define_stub_fn!(DLL, vswprintf_p_impl, 0);
-define_stub_fn!(DLL, vswprintf_p_l_impl, 0);
-define_stub_fn!(DLL, vsnprintf_impl, 0);
-define_stub_fn!(DLL, vsnprintf_s_impl, 0);
-define_stub_fn!(DLL, vsnprintf_l_impl, 0);
-define_stub_fn!(DLL, vsnprintf_s_l_impl, 0);
-define_stub_fn!(DLL, vsnprintf_c_impl, 0);
-define_stub_fn!(DLL, vsnprintf_c_l_impl, 0);
-define_stub_fn!(DLL, vsnwprintf_impl, 0);
-define_stub_fn!(DLL, vsnwprintf_s_impl, 0);
-define_stub_fn!(DLL, vsnwprintf_l_impl, 0);
-define_stub_fn!(DLL, vsnwprintf_s_l_impl, 0);
-define_stub_fn!(DLL, vscprintf_impl, 0);
-define_stub_fn!(DLL, vscprintf_l_impl, 0);
-define_stub_fn!(DLL, vscprintf_p_impl, 0);
-define_stub_fn!(DLL, vscprintf_p_l_impl, 0);
-define_stub_fn!(DLL, vscwprintf_impl, 0);
-define_stub_fn!(DLL, vscwprintf_l_impl, 0);
-define_stub_fn!(DLL, vscwprintf_p_impl, 0);
-define_stub_fn!(DLL, vscwprintf_p_l_impl, 0);
-define_stub_fn!(DLL, vcprintf_impl, 0);
-define_stub_fn!(DLL, vcprintf_l_impl, 0);
-define_stub_fn!(DLL, vcprintf_s_impl, 0);
-define_stub_fn!(DLL, vcprintf_s_l_impl, 0);
-define_stub_fn!(DLL, vcprintf_p_impl, 0);
-define_stub_fn!(DLL, vcprintf_p_l_impl, 0);
-define_stub_fn!(DLL, vcwprintf_impl, 0);
-define_stub_fn!(DLL, vcwprintf_l_impl, 0);
-define_stub_fn!(DLL, vcwprintf_s_impl, 0);
-define_stub_fn!(DLL, vcwprintf_s_l_impl, 0);
-define_stub_fn!(DLL, vcwprintf_p_impl, 0);
-define_stub_fn!(DLL, vcwprintf_p_l_impl, 0);
+
+printf_fn!(vprintf_impl, false, 0, 1, true, to_stdout);
+printf_fn!(vprintf_s_impl, false, 0, 1, true, to_stdout);
+printf_fn!(vprintf_l_impl, false, 0, 2, true, to_stdout);
+printf_fn!(vprintf_s_l_impl, false, 0, 2, true, to_stdout);
+printf_fn!(vprintf_p_impl, false, 0, 1, true, to_stdout);
+printf_fn!(vprintf_p_l_impl, false, 0, 2, true, to_stdout);
+printf_fn!(vwprintf_impl, true, 0, 1, true, to_stdout);
+printf_fn!(vwprintf_s_impl, true, 0, 1, true, to_stdout);
+printf_fn!(vwprintf_l_impl, true, 0, 2, true, to_stdout);
+printf_fn!(vwprintf_s_l_impl, true, 0, 2, true, to_stdout);
+printf_fn!(vwprintf_p_impl, true, 0, 1, true, to_stdout);
+printf_fn!(vwprintf_p_l_impl, true, 0, 2, true, to_stdout);
+printf_fn!(vfprintf_impl, false, 1, 2, true, to_stream);
+printf_fn!(vfprintf_s_impl, false, 1, 2, true, to_stream);
+printf_fn!(vfprintf_l_impl, false, 1, 3, true, to_stream);
+printf_fn!(vfprintf_s_l_impl, false, 1, 3, true, to_stream);
+printf_fn!(vfprintf_p_impl, false, 1, 2, true, to_stream);
+printf_fn!(vfprintf_p_l_impl, false, 1, 3, true, to_stream);
+printf_fn!(vfwprintf_impl, true, 1, 2, true, to_stream);
+printf_fn!(vfwprintf_s_impl, true, 1, 2, true, to_stream);
+printf_fn!(vfwprintf_l_impl, true, 1, 3, true, to_stream);
+printf_fn!(vfwprintf_s_l_impl, true, 1, 3, true, to_stream);
+printf_fn!(vfwprintf_p_impl, true, 1, 2, true, to_stream);
+printf_fn!(vfwprintf_p_l_impl, true, 1, 3, true, to_stream);
+printf_fn!(vsprintf_impl, false, 1, 2, true, to_buffer);
+printf_fn!(vsprintf_s_impl, false, 2, 3, true, to_sized);
+printf_fn!(vsprintf_l_impl, false, 1, 3, true, to_buffer);
+printf_fn!(vsprintf_s_l_impl, false, 2, 4, true, to_sized);
+printf_fn!(vsprintf_p_impl, false, 2, 3, true, to_sized);
+printf_fn!(vsprintf_p_l_impl, false, 2, 4, true, to_sized);
+printf_fn!(vswprintf_impl, true, 1, 2, true, to_buffer);
+printf_fn!(legacy_vswprintf_l_impl, true, 1, 3, true, to_buffer);
+printf_fn!(vswprintf_s_impl, true, 2, 3, true, to_sized);
+printf_fn!(vswprintf_c_impl, true, 2, 3, true, to_bounded);
+printf_fn!(vswprintf_c_l_impl, true, 2, 4, true, to_bounded);
+printf_fn!(vswprintf_l_impl, true, 2, 4, true, to_bounded);
+printf_fn!(vswprintf_s_l_impl, true, 2, 4, true, to_sized);
+printf_fn!(vswprintf_p_impl, true, 2, 3, true, to_sized);
+printf_fn!(vswprintf_p_l_impl, true, 2, 4, true, to_sized);
+printf_fn!(vsnprintf_impl, false, 2, 3, true, to_counted);
+printf_fn!(vsnprintf_s_impl, false, 3, 4, true, to_truncated);
+printf_fn!(vsnprintf_l_impl, false, 2, 4, true, to_counted);
+printf_fn!(vsnprintf_s_l_impl, false, 3, 5, true, to_truncated);
+printf_fn!(vsnprintf_c_impl, false, 2, 3, true, to_bounded);
+printf_fn!(vsnprintf_c_l_impl, false, 2, 4, true, to_bounded);
+printf_fn!(vsnwprintf_impl, true, 2, 3, true, to_counted);
+printf_fn!(vsnwprintf_s_impl, true, 3, 4, true, to_truncated);
+printf_fn!(vsnwprintf_l_impl, true, 2, 4, true, to_counted);
+printf_fn!(vsnwprintf_s_l_impl, true, 3, 5, true, to_truncated);
+printf_fn!(vscprintf_impl, false, 0, 1, true, to_count);
+printf_fn!(vscprintf_l_impl, false, 0, 2, true, to_count);
+printf_fn!(vscprintf_p_impl, false, 0, 1, true, to_count);
+printf_fn!(vscprintf_p_l_impl, false, 0, 2, true, to_count);
+printf_fn!(vscwprintf_impl, true, 0, 1, true, to_count);
+printf_fn!(vscwprintf_l_impl, true, 0, 2, true, to_count);
+printf_fn!(vscwprintf_p_impl, true, 0, 1, true, to_count);
+printf_fn!(vscwprintf_p_l_impl, true, 0, 2, true, to_count);
+printf_fn!(vcprintf_impl, false, 0, 1, true, to_console);
+printf_fn!(vcprintf_l_impl, false, 0, 2, true, to_console);
+printf_fn!(vcprintf_s_impl, false, 0, 1, true, to_console);
+printf_fn!(vcprintf_s_l_impl, false, 0, 2, true, to_console);
+printf_fn!(vcprintf_p_impl, false, 0, 1, true, to_console);
+printf_fn!(vcprintf_p_l_impl, false, 0, 2, true, to_console);
+printf_fn!(vcwprintf_impl, true, 0, 1, true, to_console);
+printf_fn!(vcwprintf_l_impl, true, 0, 2, true, to_console);
+printf_fn!(vcwprintf_s_impl, true, 0, 1, true, to_console);
+printf_fn!(vcwprintf_s_l_impl, true, 0, 2, true, to_console);
+printf_fn!(vcwprintf_p_impl, true, 0, 1, true, to_console);
+printf_fn!(vcwprintf_p_l_impl, true, 0, 2, true, to_console);
 
 // Scanf family
-define_stub_fn!(DLL, scanf_impl, 0);
-define_stub_fn!(DLL, scanf_s_impl, 0);
-define_stub_fn!(DLL, scanf_l_impl, 0);
-define_stub_fn!(DLL, scanf_s_l_impl, 0);
-define_stub_fn!(DLL, wscanf_impl, 0);
-define_stub_fn!(DLL, wscanf_s_impl, 0);
-define_stub_fn!(DLL, wscanf_l_impl, 0);
-define_stub_fn!(DLL, wscanf_s_l_impl, 0);
-define_stub_fn!(DLL, fscanf_impl, 0);
-define_stub_fn!(DLL, fscanf_s_impl, 0);
-define_stub_fn!(DLL, fscanf_l_impl, 0);
-define_stub_fn!(DLL, fscanf_s_l_impl, 0);
-define_stub_fn!(DLL, fwscanf_impl, 0);
-define_stub_fn!(DLL, fwscanf_s_impl, 0);
-define_stub_fn!(DLL, fwscanf_l_impl, 0);
-define_stub_fn!(DLL, fwscanf_s_l_impl, 0);
-define_stub_fn!(DLL, sscanf_impl, 0);
-define_stub_fn!(DLL, sscanf_s_impl, 0);
-define_stub_fn!(DLL, sscanf_l_impl, 0);
-define_stub_fn!(DLL, sscanf_s_l_impl, 0);
-define_stub_fn!(DLL, swscanf_impl, 0);
-define_stub_fn!(DLL, swscanf_s_impl, 0);
-define_stub_fn!(DLL, swscanf_l_impl, 0);
-define_stub_fn!(DLL, swscanf_s_l_impl, 0);
-define_stub_fn!(DLL, cscanf_impl, 0);
-define_stub_fn!(DLL, cscanf_s_impl, 0);
-define_stub_fn!(DLL, cscanf_l_impl, 0);
-define_stub_fn!(DLL, cscanf_s_l_impl, 0);
-define_stub_fn!(DLL, cwscanf_impl, 0);
-define_stub_fn!(DLL, cwscanf_s_impl, 0);
-define_stub_fn!(DLL, cwscanf_l_impl, 0);
-define_stub_fn!(DLL, cwscanf_s_l_impl, 0);
+
+#[derive(Clone, Copy)]
+enum ScanFrom {
+    Stdin,
+    Stream,
+    Text,
+    Console,
+}
+
+fn scan_from(
+    vm: &mut Vm,
+    stack_ptr: u32,
+    from: ScanFrom,
+    fmt: u32,
+    args: u32,
+    wide: bool,
+    secure: bool,
+) -> i32 {
+    let fmt = arg(vm, stack_ptr, fmt);
+    if fmt == 0 {
+        set_errno(vm, EINVAL);
+        return -1;
+    }
+    let fmt = read_units(vm, fmt, wide, usize::MAX);
+    let args = VaList::after(stack_ptr, args);
+    let stream = match from {
+        ScanFrom::Stdin => stream::std_stream(vm, 0),
+        ScanFrom::Stream => arg(vm, stack_ptr, 0),
+        ScanFrom::Text => {
+            let text = arg(vm, stack_ptr, 0);
+            if text == 0 {
+                set_errno(vm, EINVAL);
+                return -1;
+            }
+            let mut input = TextInput::new(read_units(vm, text, wide, usize::MAX));
+            return format::scan(vm, &mut input, &fmt, args, wide, secure);
+        }
+        ScanFrom::Console => {
+            let key = vm.crt_take_ungetch().map(|key| key as u16);
+            let mut input = TextInput::new(key.into_iter().collect());
+            return format::scan(vm, &mut input, &fmt, args, wide, secure);
+        }
+    };
+    if !stream::is_open(vm, stream) {
+        set_errno(vm, EINVAL);
+        return -1;
+    }
+    let mut input = StreamInput::new(stream, wide);
+    let count = format::scan(vm, &mut input, &fmt, args, wide, secure);
+    input.finish(vm);
+    count
+}
+
+// A scanf variant: wide or narrow, its input, the argument slots of its
+// format and first pointer, and whether buffer sizes follow pointers.
+macro_rules! scanf_fn {
+    ($name:ident, $wide:expr, $from:ident, $fmt:expr, $args:expr, $secure:expr) => {
+        fn $name(vm: &mut Vm, stack_ptr: u32) -> u32 {
+            scan_from(vm, stack_ptr, ScanFrom::$from, $fmt, $args, $wide, $secure) as u32
+        }
+    };
+}
+
+scanf_fn!(scanf_impl, false, Stdin, 0, 1, false);
+scanf_fn!(scanf_s_impl, false, Stdin, 0, 1, true);
+scanf_fn!(scanf_l_impl, false, Stdin, 0, 2, false);
+scanf_fn!(scanf_s_l_impl, false, Stdin, 0, 2, true);
+scanf_fn!(wscanf_impl, true, Stdin, 0, 1, false);
+scanf_fn!(wscanf_s_impl, true, Stdin, 0, 1, true);
+scanf_fn!(wscanf_l_impl, true, Stdin, 0, 2, false);
+scanf_fn!(wscanf_s_l_impl, true, Stdin, 0, 2, true);
+scanf_fn!(fscanf_impl, false, Stream, 1, 2, false);
+scanf_fn!(fscanf_s_impl, false, Stream, 1, 2, true);
+scanf_fn!(fscanf_l_impl, false, Stream, 1, 3, false);
+scanf_fn!(fscanf_s_l_impl, false, Stream, 1, 3, true);
+scanf_fn!(fwscanf_impl, true, Stream, 1, 2, false);
+scanf_fn!(fwscanf_s_impl, true, Stream, 1, 2, true);
+scanf_fn!(fwscanf_l_impl, true, Stream, 1, 3, false);
+scanf_fn!(fwscanf_s_l_impl, true, Stream, 1, 3, true);
+scanf_fn!(sscanf_impl, false, Text, 1, 2, false);
+scanf_fn!(sscanf_s_impl, false, Text, 1, 2, true);
+scanf_fn!(sscanf_l_impl, false, Text, 1, 3, false);
+scanf_fn!(sscanf_s_l_impl, false, Text, 1, 3, true);
+scanf_fn!(swscanf_impl, true, Text, 1, 2, false);
+scanf_fn!(swscanf_s_impl, true, Text, 1, 2, true);
+scanf_fn!(swscanf_l_impl, true, Text, 1, 3, false);
+scanf_fn!(swscanf_s_l_impl, true, Text, 1, 3, true);
+scanf_fn!(cscanf_impl, false, Console, 0, 1, false);
+scanf_fn!(cscanf_s_impl, false, Console, 0, 1, true);
+scanf_fn!(cscanf_l_impl, false, Console, 0, 2, false);
+scanf_fn!(cscanf_s_l_impl, false, Console, 0, 2, true);
+scanf_fn!(cwscanf_impl, true, Console, 0, 1, false);
+scanf_fn!(cwscanf_s_impl, true, Console, 0, 1, true);
+scanf_fn!(cwscanf_l_impl, true, Console, 0, 2, false);
+scanf_fn!(cwscanf_s_l_impl, true, Console, 0, 2, true);
 
 // IOB functions
-define_stub_fn!(DLL, iob_func, 0);
-define_stub_fn!(DLL, lock_file, 0);
-define_stub_fn!(DLL, unlock_file, 0);
-define_stub_fn!(DLL, fileno, 0);
-define_stub_fn!(DLL, fdopen, 0);
-define_stub_fn!(DLL, wfdopen, 0);
-define_stub_fn!(DLL, setmode, 0);
-define_stub_fn!(DLL, filbuf, 0);
-define_stub_fn!(DLL, flsbuf, 0);
-define_stub_fn!(DLL, getmaxstdio, 0);
-define_stub_fn!(DLL, setmaxstdio, 0);
+
+fn iob_func(vm: &mut Vm, _stack_ptr: u32) -> u32 {
+    stream::iob(vm)
+}
+
+// Guest code runs on one thread at a time, so stream locks are no-ops.
+fn lock_file(_vm: &mut Vm, _stack_ptr: u32) -> u32 {
+    0
+}
+
+fn unlock_file(_vm: &mut Vm, _stack_ptr: u32) -> u32 {
+    0
+}
+
+fn fileno(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (stream,) = vm_args!(vm, stack_ptr; u32);
+    if stream == 0 {
+        return invalid(vm, u32::MAX);
+    }
+    stream::fd(vm, stream) as u32
+}
+
+fn fdopen_stream(vm: &mut Vm, stack_ptr: u32, wide: bool) -> u32 {
+    let (fd, mode) = vm_args!(vm, stack_ptr; i32, u32);
+    if vm.crt_fd(fd).is_none() {
+        set_errno(vm, EBADF);
+        return 0;
+    }
+    if mode == 0 {
+        return invalid(vm, 0);
+    }
+    let mode = read_string(vm, mode, wide);
+    match stream::parse_mode(&mode) {
+        Some(mode) => stream::attach(vm, fd, mode.flag),
+        None => invalid(vm, 0),
+    }
+}
+
+fn fdopen(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    fdopen_stream(vm, stack_ptr, false)
+}
+
+fn wfdopen(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    fdopen_stream(vm, stack_ptr, true)
+}
+
+fn filbuf(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (stream,) = vm_args!(vm, stack_ptr; u32);
+    stream::filbuf(vm, stream)
+}
+
+fn flsbuf(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (ch, stream) = vm_args!(vm, stack_ptr; u32, u32);
+    stream::flsbuf(vm, ch, stream)
+}
+
+fn getmaxstdio(_vm: &mut Vm, _stack_ptr: u32) -> u32 {
+    512
+}
+
+fn setmaxstdio(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (max,) = vm_args!(vm, stack_ptr; u32);
+    if (20..=MAX_STDIO).contains(&max) {
+        max
+    } else {
+        invalid(vm, u32::MAX)
+    }
+}
 
 pub fn register(vm: &mut Vm) {
     // File I/O
     vm.register_import(DLL, "fopen", fopen_impl);
     vm.register_import(DLL, "fopen_s", fopen_s_impl);
+    vm.register_import(DLL, "_wfopen", wfopen_impl);
+    vm.register_import(DLL, "_wfopen_s", wfopen_s_impl);
+    vm.register_import(DLL, "_fsopen", fsopen_impl);
+    vm.register_import(DLL, "_wfsopen", wfsopen_impl);
     vm.register_import(DLL, "freopen", freopen_impl);
     vm.register_import(DLL, "freopen_s", freopen_s_impl);
     vm.register_import(DLL, "fclose", fclose_impl);
     vm.register_import(DLL, "fflush", fflush_impl);
+    vm.register_import(DLL, "_fcloseall", fcloseall_impl);
+    vm.register_import(DLL, "_flushall", flushall_impl);
     vm.register_import(DLL, "fread", fread_impl);
     vm.register_import(DLL, "fread_s", fread_s_impl);
     vm.register_import(DLL, "fwrite", fwrite_impl);
-    vm.register_import(DLL, "fgetc", fgetc_impl);
+    vm.register_import(DLL, "fgetc", getc_impl);
     vm.register_import(DLL, "fgets", fgets_impl);
-    vm.register_import(DLL, "fputc", fputc_impl);
+    vm.register_import(DLL, "fputc", putc_impl);
     vm.register_import(DLL, "fputs", fputs_impl);
-    vm.register_import(DLL, "fgetwc", fgetwc_impl);
+    vm.register_import(DLL, "fgetwc", getwc_impl);
     vm.register_import(DLL, "fgetws", fgetws_impl);
-    vm.register_import(DLL, "fputwc", fputwc_impl);
+    vm.register_import(DLL, "fputwc", putwc_impl);
     vm.register_import(DLL, "fputws", fputws_impl);
     vm.register_import(DLL, "ungetc", ungetc_impl);
     vm.register_import(DLL, "ungetwc", ungetwc_impl);
@@ -351,18 +1420,18 @@ pub fn register(vm: &mut Vm) {
     vm.register_import(DLL, "_cgetws_s", cgetws_s_impl);
     vm.register_import(DLL, "_cputs", cputs_impl);
     vm.register_import(DLL, "_cputws", cputws_impl);
-    vm.register_import(DLL, "_getch_nolock", getch_nolock_impl);
-    vm.register_import(DLL, "_getche_nolock", getche_nolock_impl);
-    vm.register_import(DLL, "_ungetch_nolock", ungetch_nolock_impl);
-    vm.register_import(DLL, "_getwch_nolock", getwch_nolock_impl);
-    vm.register_import(DLL, "_getwche_nolock", getwche_nolock_impl);
-    vm.register_import(DLL, "_ungetwch_nolock", ungetwch_nolock_impl);
-    vm.register_import(DLL, "_getc_nolock", getc_nolock_impl);
-    vm.register_import(DLL, "_putc_nolock", putc_nolock_impl);
-    vm.register_import(DLL, "_ungetc_nolock", ungetc_nolock_impl);
-    vm.register_import(DLL, "_getwc_nolock", getwc_nolock_impl);
-    vm.register_import(DLL, "_putwc_nolock", putwc_nolock_impl);
-    vm.register_import(DLL, "_ungetwc_nolock", ungetwc_nolock_impl);
+    vm.register_import(DLL, "_getch_nolock", getch_impl);
+    vm.register_import(DLL, "_getche_nolock", getche_impl);
+    vm.register_import(DLL, "_ungetch_nolock", ungetch_impl);
+    vm.register_import(DLL, "_getwch_nolock", getwch_impl);
+    vm.register_import(DLL, "_getwche_nolock", getwche_impl);
+    vm.register_import(DLL, "_ungetwch_nolock", ungetwch_impl);
+    vm.register_import(DLL, "_getc_nolock", getc_impl);
+    vm.register_import(DLL, "_putc_nolock", putc_impl);
+    vm.register_import(DLL, "_ungetc_nolock", ungetc_impl);
+    vm.register_import(DLL, "_getwc_nolock", getwc_impl);
+    vm.register_import(DLL, "_putwc_nolock", putwc_impl);
+    vm.register_import(DLL, "_ungetwc_nolock", ungetwc_impl);
 
     // Printf family
     vm.register_import(DLL, "printf", printf_impl);
@@ -402,6 +1471,7 @@ pub fn register(vm: &mut Vm) {
     vm.register_import(DLL, "_swprintf_l", swprintf_l_impl);
     vm.register_import(DLL, "_swprintf_s_l", swprintf_s_l_impl);
     vm.register_import(DLL, "_swprintf_p", swprintf_p_impl);
+    vm.register_import(DLL, "__swprintf_l", legacy_swprintf_l_impl);
     vm.register_import(DLL, "_swprintf_p_l", swprintf_p_l_impl);
     vm.register_import(DLL, "_snprintf", snprintf_impl);
     vm.register_import(DLL, "_snprintf_s", snprintf_s_impl);
@@ -473,7 +1543,7 @@ pub fn register(vm: &mut Vm) {
     vm.register_import(DLL, "_vswprintf_s_l", vswprintf_s_l_impl);
     vm.register_import(DLL, "_vswprintf_p", vswprintf_p_impl);
     vm.register_import(DLL, "_vswprintf_p_l", vswprintf_p_l_impl);
-    vm.register_import(DLL, "__vswprintf_l", vswprintf_l_impl);
+    vm.register_import(DLL, "__vswprintf_l", legacy_vswprintf_l_impl);
     vm.register_import(DLL, "_vsnprintf", vsnprintf_impl);
     vm.register_import(DLL, "_vsnprintf_s", vsnprintf_s_impl);
     vm.register_import(DLL, "_vsnprintf_l", vsnprintf_l_impl);
@@ -547,7 +1617,6 @@ pub fn register(vm: &mut Vm) {
     vm.register_import(DLL, "_fileno", fileno);
     vm.register_import(DLL, "_fdopen", fdopen);
     vm.register_import(DLL, "_wfdopen", wfdopen);
-    vm.register_import(DLL, "_setmode", setmode);
     vm.register_import(DLL, "_filbuf", filbuf);
     vm.register_import(DLL, "_flsbuf", flsbuf);
     vm.register_import(DLL, "_getmaxstdio", getmaxstdio);
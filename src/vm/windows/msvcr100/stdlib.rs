@@ -1,6 +1,8 @@
 //! Standard library function stubs for MSVCR100.dll.
 #![allow(dead_code)]
 
+use std::io;
+
 use crate::vm::Vm;
 
 const DLL: &str = "MSVCR100.dll";
@@ -148,16 +150,134 @@ define_stub_fn!(DLL, invalid_parameter, 0);
 define_stub_fn!(DLL, invalid_parameter_noinfo, 0);
 define_stub_fn!(DLL, invalid_parameter_noinfo_noreturn, 0);
 define_stub_fn!(DLL, invoke_watson, 0);
-define_stub_fn!(DLL, errno_impl, 0);
-define_stub_fn!(DLL, doserrno_impl, 0);
-define_stub_fn!(DLL, set_errno_impl, 0);
-define_stub_fn!(DLL, get_errno_impl, 0);
-define_stub_fn!(DLL, set_doserrno_impl, 0);
-define_stub_fn!(DLL, get_doserrno_impl, 0);
 define_stub_fn!(DLL, strerror_impl, 0);
 define_stub_fn!(DLL, sys_errlist, 0);
 define_stub_fn!(DLL, sys_nerr, 0);
 
+// errno.h values.
+pub(super) const ENOENT: i32 = 2;
+pub(super) const EBADF: i32 = 9;
+pub(super) const EACCES: i32 = 13;
+pub(super) const EEXIST: i32 = 17;
+pub(super) const EXDEV: i32 = 18;
+pub(super) const EINVAL: i32 = 22;
+pub(super) const EMFILE: i32 = 24;
+pub(super) const ESPIPE: i32 = 29;
+pub(super) const ERANGE: i32 = 34;
+pub(super) const ENOTEMPTY: i32 = 41;
+pub(super) const EILSEQ: i32 = 42;
+
+// errno and _doserrno live in one guest cell pair shared by every thread.
+fn errno_impl(vm: &mut Vm, _stack_ptr: u32) -> u32 {
+    vm.crt_errno_cell()
+}
+
+fn doserrno_impl(vm: &mut Vm, _stack_ptr: u32) -> u32 {
+    vm.crt_errno_cell() + 4
+}
+
+fn set_errno_impl(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (value,) = vm_args!(vm, stack_ptr; i32);
+    set_errno(vm, value);
+    0
+}
+
+fn get_errno_impl(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (out,) = vm_args!(vm, stack_ptr; u32);
+    if out == 0 {
+        return EINVAL as u32;
+    }
+    let value = errno(vm);
+    let _ = vm.write_u32(out, value as u32);
+    0
+}
+
+fn set_doserrno_impl(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (value,) = vm_args!(vm, stack_ptr; u32);
+    let cell = vm.crt_errno_cell();
+    let _ = vm.write_u32(cell + 4, value);
+    0
+}
+
+fn get_doserrno_impl(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (out,) = vm_args!(vm, stack_ptr; u32);
+    if out == 0 {
+        return EINVAL as u32;
+    }
+    let cell = vm.crt_errno_cell();
+    let value = vm.read_u32(cell + 4).unwrap_or(0);
+    let _ = vm.write_u32(out, value);
+    0
+}
+
+pub(super) fn set_errno(vm: &mut Vm, value: i32) {
+    let cell = vm.crt_errno_cell();
+    let _ = vm.write_u32(cell, value as u32);
+}
+
+pub(super) fn errno(vm: &mut Vm) -> i32 {
+    let cell = vm.crt_errno_cell();
+    vm.read_u32(cell).unwrap_or(0) as i32
+}
+
+pub(super) fn io_errno(err: &io::Error) -> i32 {
+    match err.kind() {
+        io::ErrorKind::NotFound | io::ErrorKind::NotADirectory => ENOENT,
+        io::ErrorKind::AlreadyExists => EEXIST,
+        io::ErrorKind::DirectoryNotEmpty => ENOTEMPTY,
+        io::ErrorKind::CrossesDevices => EXDEV,
+        io::ErrorKind::NotSeekable => ESPIPE,
+        io::ErrorKind::InvalidInput | io::ErrorKind::InvalidFilename => EINVAL,
+        _ => EACCES,
+    }
+}
+
+// The CRT's `_sys_errlist` wording.
+pub(super) fn errno_message(value: i32) -> &'static str {
+    match value {
+        0 => "No error",
+        1 => "Operation not permitted",
+        2 => "No such file or directory",
+        3 => "No such process",
+        4 => "Interrupted function call",
+        5 => "Input/output error",
+        6 => "No such device or address",
+        7 => "Arg list too long",
+        8 => "Exec format error",
+        9 => "Bad file descriptor",
+        10 => "No child processes",
+        11 => "Resource temporarily unavailable",
+        12 => "Not enough space",
+        13 => "Permission denied",
+        14 => "Bad address",
+        16 => "Resource device",
+        17 => "File exists",
+        18 => "Improper link",
+        19 => "No such device",
+        20 => "Not a directory",
+        21 => "Is a directory",
+        22 => "Invalid argument",
+        23 => "Too many open files in system",
+        24 => "Too many open files",
+        25 => "Inappropriate I/O control operation",
+        27 => "File too large",
+        28 => "No space left on device",
+        29 => "Invalid seek",
+        30 => "Read-only file system",
+        31 => "Too many links",
+        32 => "Broken pipe",
+        33 => "Domain error",
+        34 => "Result too large",
+        36 => "Resource deadlock avoided",
+        38 => "Filename too long",
+        39 => "No locks available",
+        40 => "Function not implemented",
+        41 => "Directory not empty",
+        42 => "Illegal byte sequence",
+        _ => "Unknown error",
+    }
+}
+
 pub fn register(vm: &mut Vm) {
     // Integer conversion
     vm.register_import(DLL, "atoi", atoi_impl);
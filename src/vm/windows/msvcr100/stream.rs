@@ -0,0 +1,710 @@
+//! Guest `FILE` streams for MSVCR100.dll.
+//!
+//! Streams use the VS2010 `_iobuf` layout so that code inlining the `getc` and
+//! `putc` macros, or indexing `__iob_func()`, sees the same fields the real CRT
+//! maintains. Data moves through the descriptor layer (`_read`/`_write`), which
+//! owns text-mode translation.
+
+use crate::vm::{CrtTarget, FsOpenOptions, Vm};
+
+use super::format::ScanInput;
+use super::stdlib::{io_errno, EBADF, EINVAL};
+
+pub(super) const EOF: u32 = u32::MAX;
+
+const FILE_SIZE: u32 = 32;
+const PTR: u32 = 0;
+const CNT: u32 = 4;
+const BASE: u32 = 8;
+const FLAG: u32 = 12;
+const FILE: u32 = 16;
+const CHARBUF: u32 = 20;
+const BUFSIZ: u32 = 24;
+const TMPFNAME: u32 = 28;
+
+/// Slots in the static `_iob` array; stdin, stdout and stderr come first.
+const IOB_COUNT: u32 = 20;
+const BUFFER_SIZE: u32 = 4096;
+
+pub(super) const IOREAD: u32 = 0x1;
+pub(super) const IOWRT: u32 = 0x2;
+pub(super) const IONBF: u32 = 0x4;
+const IOMYBUF: u32 = 0x8;
+pub(super) const IOEOF: u32 = 0x10;
+pub(super) const IOERR: u32 = 0x20;
+const IOSTRG: u32 = 0x40;
+pub(super) const IORW: u32 = 0x80;
+const IOYOURBUF: u32 = 0x100;
+
+// setvbuf modes.
+pub(super) const IOFBF: u32 = 0x0;
+pub(super) const IOLBF: u32 = 0x40;
+
+fn get(vm: &Vm, stream: u32, field: u32) -> u32 {
+    vm.read_u32(stream + field).unwrap_or(0)
+}
+
+fn set(vm: &mut Vm, stream: u32, field: u32, value: u32) {
+    let _ = vm.write_u32(stream + field, value);
+}
+
+fn set_flag(vm: &mut Vm, stream: u32, add: u32, remove: u32) {
+    let flag = get(vm, stream, FLAG);
+    set(vm, stream, FLAG, (flag & !remove) | add);
+}
+
+pub(super) fn flag(vm: &Vm, stream: u32) -> u32 {
+    get(vm, stream, FLAG)
+}
+
+pub(super) fn fd(vm: &Vm, stream: u32) -> i32 {
+    get(vm, stream, FILE) as i32
+}
+
+/// The `_iob` array, created with the standard streams on first use.
+pub(super) fn iob(vm: &mut Vm) -> u32 {
+    if vm.crt_iob() == 0 {
+        let iob = vm.heap_alloc((IOB_COUNT * FILE_SIZE) as usize);
+        for (index, flag) in [IOREAD, IOWRT, IOWRT].into_iter().enumerate() {
+            let stream = iob + index as u32 * FILE_SIZE;
+            set(vm, stream, FLAG, flag);
+            set(vm, stream, FILE, index as u32);
+        }
+        vm.set_crt_iob(iob);
+    }
+    vm.crt_iob()
+}
+
+pub(super) fn std_stream(vm: &mut Vm, index: u32) -> u32 {
+    iob(vm) + index * FILE_SIZE
+}
+
+/// Whether `stream` is open; null and closed streams are not.
+pub(super) fn is_open(vm: &Vm, stream: u32) -> bool {
+    stream != 0 && flag(vm, stream) & (IOREAD | IOWRT | IORW) != 0
+}
+
+fn is_text(vm: &Vm, stream: u32) -> bool {
+    vm.crt_fd(fd(vm, stream)).is_some_and(|entry| entry.text)
+}
+
+// A free `_iob` slot, then a free heap stream, then a new heap stream.
+fn alloc_stream(vm: &mut Vm) -> u32 {
+    let iob = iob(vm);
+    let free = (3..IOB_COUNT)
+        .map(|index| iob + index * FILE_SIZE)
+        .chain(vm.crt_streams().to_vec())
+        .find(|stream| !is_open(vm, *stream));
+    let stream = free.unwrap_or_else(|| {
+        let stream = vm.heap_alloc(FILE_SIZE as usize);
+        vm.crt_add_stream(stream);
+        stream
+    });
+    let _ = vm.memset(stream, 0, FILE_SIZE as usize);
+    stream
+}
+
+/// Wrap descriptor `fd` in a new stream with the given `_flag` bits.
+pub(super) fn attach(vm: &mut Vm, fd: i32, flag: u32) -> u32 {
+    let stream = alloc_stream(vm);
+    set(vm, stream, FLAG, flag);
+    set(vm, stream, FILE, fd as u32);
+    stream
+}
+
+/// A parsed `fopen` mode string.
+pub(super) struct OpenMode {
+    pub(super) options: FsOpenOptions,
+    pub(super) flag: u32,
+    pub(super) text: bool,
+    pub(super) append: bool,
+    pub(super) temporary: bool,
+}
+
+/// Parse an `fopen` mode such as `"r+b"` or `"w, ccs=UTF-8"`. Encoded
+/// (`ccs=`) streams are opened in text mode.
+pub(super) fn parse_mode(mode: &str) -> Option<OpenMode> {
+    let (mode, _encoding) = mode.split_once(',').unwrap_or((mode, ""));
+    let mut chars = mode.trim_start().chars();
+    let (options, flag, append) = match chars.next()? {
+        'r' => (FsOpenOptions::new().read(true), IOREAD, false),
+        'w' => (
+            FsOpenOptions::new().write(true).create(true).truncate(true),
+            IOWRT,
+            false,
+        ),
+        'a' => (FsOpenOptions::new().write(true).create(true), IOWRT, true),
+        _ => return None,
+    };
+    let mut mode = OpenMode {
+        options,
+        flag,
+        text: true,
+        append,
+        temporary: false,
+    };
+    for ch in chars {
+        match ch {
+            '+' => {
+                mode.options = mode.options.read(true).write(true);
+                mode.flag = IORW;
+            }
+            't' => mode.text = true,
+            'b' => mode.text = false,
+            'D' => mode.temporary = true,
+            'c' | 'n' | 'S' | 'R' | 'T' | 'N' | ' ' => {}
+            _ => return None,
+        }
+    }
+    Some(mode)
+}
+
+/// `fopen`: returns the stream, or the errno value on failure.
+pub(super) fn open(vm: &mut Vm, path: &str, mode: &str) -> Result<u32, i32> {
+    let mode = parse_mode(mode).ok_or(EINVAL)?;
+    let fd = vm
+        .crt_open(path, mode.options, mode.text, mode.append, mode.temporary)
+        .map_err(|err| io_errno(&err))?;
+    Ok(attach(vm, fd, mode.flag))
+}
+
+/// `freopen`: close `stream` and open `path` in its place.
+pub(super) fn reopen(vm: &mut Vm, stream: u32, path: &str, mode: &str) -> Result<u32, i32> {
+    close(vm, stream);
+    let mode = parse_mode(mode).ok_or(EINVAL)?;
+    let fd = vm
+        .crt_open(path, mode.options, mode.text, mode.append, mode.temporary)
+        .map_err(|err| io_errno(&err))?;
+    let _ = vm.memset(stream, 0, FILE_SIZE as usize);
+    set(vm, stream, FLAG, mode.flag);
+    set(vm, stream, FILE, fd as u32);
+    Ok(stream)
+}
+
+/// `fclose`: flush, release the buffer and close the descriptor.
+pub(super) fn close(vm: &mut Vm, stream: u32) -> bool {
+    if !is_open(vm, stream) {
+        return false;
+    }
+    let flushed = flush(vm, stream);
+    release_buffer(vm, stream);
+    let closed = vm.crt_close(fd(vm, stream));
+    let tmpfname = get(vm, stream, TMPFNAME);
+    if tmpfname != 0 {
+        vm.heap_free(tmpfname);
+    }
+    let _ = vm.memset(stream, 0, FILE_SIZE as usize);
+    flushed && closed
+}
+
+fn release_buffer(vm: &mut Vm, stream: u32) {
+    if flag(vm, stream) & IOMYBUF != 0 {
+        vm.heap_free(get(vm, stream, BASE));
+    }
+    set_flag(vm, stream, 0, IOMYBUF | IOYOURBUF | IONBF);
+    for field in [PTR, CNT, BASE, BUFSIZ] {
+        set(vm, stream, field, 0);
+    }
+}
+
+// Give the stream its buffer on first transfer. Console streams stay
+// unbuffered so output interleaves with everything else written to it.
+fn ensure_buffer(vm: &mut Vm, stream: u32) {
+    if get(vm, stream, BASE) != 0 {
+        return;
+    }
+    let console = matches!(
+        vm.crt_fd(fd(vm, stream)).map(|entry| &entry.target),
+        Some(CrtTarget::Console)
+    );
+    let (base, size) = if console || flag(vm, stream) & IONBF != 0 {
+        set_flag(vm, stream, IONBF, 0);
+        (stream + CHARBUF, 1)
+    } else {
+        set_flag(vm, stream, IOMYBUF, 0);
+        (vm.heap_alloc(BUFFER_SIZE as usize), BUFFER_SIZE)
+    };
+    set(vm, stream, BASE, base);
+    set(vm, stream, PTR, base);
+    set(vm, stream, BUFSIZ, size);
+    set(vm, stream, CNT, 0);
+}
+
+/// `_filbuf`: refill the read buffer and return its first byte, or EOF.
+pub(super) fn filbuf(vm: &mut Vm, stream: u32) -> u32 {
+    let current = flag(vm, stream);
+    if current & (IOREAD | IORW) == 0 || current & IOSTRG != 0 {
+        set_flag(vm, stream, IOERR, 0);
+        set(vm, stream, CNT, 0);
+        return EOF;
+    }
+    if current & IOWRT != 0 {
+        if current & IORW == 0 || !flush(vm, stream) {
+            set_flag(vm, stream, IOERR, 0);
+            return EOF;
+        }
+        set_flag(vm, stream, 0, IOWRT);
+    }
+    set_flag(vm, stream, IOREAD, 0);
+    ensure_buffer(vm, stream);
+    let base = get(vm, stream, BASE);
+    let size = get(vm, stream, BUFSIZ) as usize;
+    let bytes = match vm.crt_read(fd(vm, stream), size) {
+        Some(Ok(bytes)) => bytes,
+        _ => {
+            set_flag(vm, stream, IOERR, 0);
+            set(vm, stream, CNT, 0);
+            return EOF;
+        }
+    };
+    if bytes.is_empty() {
+        set_flag(vm, stream, IOEOF, 0);
+        set(vm, stream, CNT, 0);
+        return EOF;
+    }
+    let _ = vm.write_bytes(base, &bytes);
+    set(vm, stream, PTR, base + 1);
+    set(vm, stream, CNT, bytes.len() as u32 - 1);
+    u32::from(bytes[0])
+}
+
+/// `_flsbuf`: write out the buffer and start a new one with `ch`.
+pub(super) fn flsbuf(vm: &mut Vm, ch: u32, stream: u32) -> u32 {
+    let current = flag(vm, stream);
+    if current & (IOWRT | IORW) == 0 || current & IOSTRG != 0 {
+        set_flag(vm, stream, IOERR, 0);
+        set(vm, stream, CNT, 0);
+        return EOF;
+    }
+    if current & IOREAD != 0 {
+        if current & IORW == 0 {
+            set_flag(vm, stream, IOERR, 0);
+            return EOF;
+        }
+        // Switching from reading: drop what was read ahead.
+        let base = get(vm, stream, BASE);
+        set(vm, stream, PTR, base);
+        set(vm, stream, CNT, 0);
+        set_flag(vm, stream, 0, IOREAD);
+    }
+    set_flag(vm, stream, IOWRT, IOEOF);
+    ensure_buffer(vm, stream);
+    let byte = ch as u8;
+    if flag(vm, stream) & IONBF != 0 {
+        set(vm, stream, CNT, 0);
+        return match vm.crt_write(fd(vm, stream), &[byte]) {
+            Some(Ok(_)) => u32::from(byte),
+            _ => {
+                set_flag(vm, stream, IOERR, 0);
+                EOF
+            }
+        };
+    }
+    if !write_pending(vm, stream) {
+        return EOF;
+    }
+    let base = get(vm, stream, BASE);
+    let _ = vm.write_u8(base, byte);
+    set(vm, stream, PTR, base + 1);
+    set(vm, stream, CNT, get(vm, stream, BUFSIZ) - 1);
+    u32::from(byte)
+}
+
+// Write buffered output and empty the buffer.
+fn write_pending(vm: &mut Vm, stream: u32) -> bool {
+    let base = get(vm, stream, BASE);
+    let pending = get(vm, stream, PTR).saturating_sub(base);
+    set(vm, stream, PTR, base);
+    set(vm, stream, CNT, 0);
+    if pending == 0 {
+        return true;
+    }
+    let bytes = vm.read_bytes(base, pending as usize).unwrap_or_default();
+    match vm.crt_write(fd(vm, stream), &bytes) {
+        Some(Ok(_)) => true,
+        _ => {
+            set_flag(vm, stream, IOERR, 0);
+            false
+        }
+    }
+}
+
+/// `fflush`: write pending output and drop read-ahead input.
+pub(super) fn flush(vm: &mut Vm, stream: u32) -> bool {
+    let current = flag(vm, stream);
+    let mut ok = true;
+    if current & IOWRT != 0 && current & IONBF == 0 {
+        ok = write_pending(vm, stream);
+    } else if current & IOREAD != 0 {
+        let base = get(vm, stream, BASE);
+        set(vm, stream, PTR, base);
+        set(vm, stream, CNT, 0);
+    }
+    if current & IORW != 0 {
+        set_flag(vm, stream, 0, IOREAD | IOWRT);
+    }
+    ok
+}
+
+/// `getc`, as the CRT macro does it.
+pub(super) fn getc(vm: &mut Vm, stream: u32) -> u32 {
+    let cnt = get(vm, stream, CNT) as i32 - 1;
+    set(vm, stream, CNT, cnt as u32);
+    if cnt < 0 {
+        return filbuf(vm, stream);
+    }
+    let ptr = get(vm, stream, PTR);
+    set(vm, stream, PTR, ptr + 1);
+    u32::from(vm.read_u8(ptr).unwrap_or(0))
+}
+
+/// `putc`, as the CRT macro does it.
+pub(super) fn putc(vm: &mut Vm, ch: u32, stream: u32) -> u32 {
+    let cnt = get(vm, stream, CNT) as i32 - 1;
+    set(vm, stream, CNT, cnt as u32);
+    if cnt < 0 {
+        return flsbuf(vm, ch, stream);
+    }
+    let ptr = get(vm, stream, PTR);
+    let _ = vm.write_u8(ptr, ch as u8);
+    set(vm, stream, PTR, ptr + 1);
+    ch & 0xFF
+}
+
+/// Read up to `len` bytes, stopping early at end of file or on error.
+pub(super) fn read(vm: &mut Vm, stream: u32, len: usize) -> Vec<u8> {
+    let mut data = Vec::with_capacity(len.min(BUFFER_SIZE as usize));
+    while data.len() < len {
+        let cnt = get(vm, stream, CNT) as i32;
+        if cnt > 0 {
+            let take = (cnt as usize).min(len - data.len());
+            let ptr = get(vm, stream, PTR);
+            data.extend(vm.read_bytes(ptr, take).unwrap_or_default());
+            set(vm, stream, PTR, ptr + take as u32);
+            set(vm, stream, CNT, (cnt - take as i32) as u32);
+            continue;
+        }
+        match filbuf(vm, stream) {
+            EOF => break,
+            byte => data.push(byte as u8),
+        }
+    }
+    data
+}
+
+/// Write `data`, returning how many bytes were accepted.
+pub(super) fn write(vm: &mut Vm, stream: u32, data: &[u8]) -> usize {
+    let mut written = 0;
+    while written < data.len() {
+        let cnt = get(vm, stream, CNT) as i32;
+        if cnt > 0 {
+            let take = (cnt as usize).min(data.len() - written);
+            let ptr = get(vm, stream, PTR);
+            let _ = vm.write_bytes(ptr, &data[written..written + take]);
+            set(vm, stream, PTR, ptr + take as u32);
+            set(vm, stream, CNT, (cnt - take as i32) as u32);
+            written += take;
+            continue;
+        }
+        if flsbuf(vm, u32::from(data[written]), stream) == EOF {
+            break;
+        }
+        written += 1;
+        // Unbuffered streams take the rest in one write.
+        if flag(vm, stream) & IONBF != 0 && written < data.len() {
+            match vm.crt_write(fd(vm, stream), &data[written..]) {
+                Some(Ok(_)) => written = data.len(),
+                _ => set_flag(vm, stream, IOERR, 0),
+            }
+            break;
+        }
+    }
+    written
+}
+
+/// `ungetc`: push `ch` back in front of the read position.
+pub(super) fn ungetc(vm: &mut Vm, ch: u32, stream: u32) -> u32 {
+    let current = flag(vm, stream);
+    let readable = current & IOREAD != 0 || (current & IORW != 0 && current & IOWRT == 0);
+    if ch == EOF || !readable {
+        return EOF;
+    }
+    set_flag(vm, stream, IOREAD, IOEOF);
+    ensure_buffer(vm, stream);
+    let base = get(vm, stream, BASE);
+    let mut ptr = get(vm, stream, PTR);
+    let cnt = get(vm, stream, CNT) as i32;
+    if ptr == base {
+        if cnt > 0 {
+            return EOF;
+        }
+        ptr += 1;
+    }
+    ptr -= 1;
+    let _ = vm.write_u8(ptr, ch as u8);
+    set(vm, stream, PTR, ptr);
+    set(vm, stream, CNT, (cnt.max(0) + 1) as u32);
+    ch & 0xFF
+}
+
+/// Read one character from a wide stream: a byte in text mode, a UTF-16
+/// unit in binary mode.
+pub(super) fn getwc(vm: &mut Vm, stream: u32) -> u32 {
+    if is_text(vm, stream) {
+        return match getc(vm, stream) {
+            EOF => 0xFFFF,
+            byte => byte,
+        };
+    }
+    let bytes = read(vm, stream, 2);
+    match bytes[..] {
+        [low, high] => u32::from(u16::from_le_bytes([low, high])),
+        _ => 0xFFFF,
+    }
+}
+
+/// Write one wide character; text-mode streams store it narrowed.
+pub(super) fn putwc(vm: &mut Vm, unit: u16, stream: u32) -> u32 {
+    if is_text(vm, stream) {
+        let byte = u8::try_from(unit).unwrap_or(b'?');
+        return match putc(vm, u32::from(byte), stream) {
+            EOF => 0xFFFF,
+            _ => u32::from(unit),
+        };
+    }
+    match write(vm, stream, &unit.to_le_bytes()) {
+        2 => u32::from(unit),
+        _ => 0xFFFF,
+    }
+}
+
+/// Write wide text, narrowing it for text-mode streams.
+pub(super) fn write_wide(vm: &mut Vm, stream: u32, units: &[u16]) -> usize {
+    if is_text(vm, stream) {
+        return write(vm, stream, &super::format::narrow(units));
+    }
+    let bytes: Vec<u8> = units.iter().flat_map(|unit| unit.to_le_bytes()).collect();
+    write(vm, stream, &bytes) / 2
+}
+
+/// `ungetwc` counterpart of [`ungetc`].
+pub(super) fn ungetwc(vm: &mut Vm, unit: u32, stream: u32) -> u32 {
+    if unit == 0xFFFF {
+        return 0xFFFF;
+    }
+    if is_text(vm, stream) {
+        return match ungetc(vm, unit & 0xFF, stream) {
+            EOF => 0xFFFF,
+            _ => unit,
+        };
+    }
+    let [low, high] = (unit as u16).to_le_bytes();
+    if ungetc(vm, u32::from(high), stream) == EOF || ungetc(vm, u32::from(low), stream) == EOF {
+        return 0xFFFF;
+    }
+    unit
+}
+
+/// The logical file position, accounting for buffered data. Text-mode
+/// buffers count each LF as the CRLF it stands for on disk.
+pub(super) fn tell(vm: &mut Vm, stream: u32) -> Result<u64, i32> {
+    let fd = fd(vm, stream);
+    let position = match vm.crt_seek(fd, 0, 1) {
+        Some(Ok(position)) => position,
+        Some(Err(err)) => return Err(io_errno(&err)),
+        None => return Err(EBADF),
+    };
+    let current = flag(vm, stream);
+    let base = get(vm, stream, BASE);
+    let ptr = get(vm, stream, PTR);
+    let (start, len, reading) = if current & IOREAD != 0 {
+        (ptr, (get(vm, stream, CNT) as i32).max(0) as u32, true)
+    } else if current & IOWRT != 0 && current & IONBF == 0 {
+        (base, ptr.saturating_sub(base), false)
+    } else {
+        return Ok(position);
+    };
+    let buffered = vm.read_bytes(start, len as usize).unwrap_or_default();
+    let mut span = buffered.len() as u64;
+    if is_text(vm, stream) {
+        span += buffered.iter().filter(|byte| **byte == b'\n').count() as u64;
+    }
+    Ok(if reading {
+        position.saturating_sub(span)
+    } else {
+        position + span
+    })
+}
+
+/// `fseek`: flush, then reposition the descriptor.
+pub(super) fn seek(vm: &mut Vm, stream: u32, offset: i64, origin: u32) -> Result<(), i32> {
+    if !is_open(vm, stream) || origin > 2 {
+        return Err(EINVAL);
+    }
+    let (offset, origin) = match origin {
+        1 => (tell(vm, stream)? as i64 + offset, 0),
+        _ => (offset, origin),
+    };
+    if !flush(vm, stream) {
+        return Err(EBADF);
+    }
+    set_flag(vm, stream, 0, IOEOF);
+    match vm.crt_seek(fd(vm, stream), offset, origin) {
+        Some(Ok(_)) => Ok(()),
+        Some(Err(err)) => Err(io_errno(&err)),
+        None => Err(EBADF),
+    }
+}
+
+/// `setvbuf`: choose full, line or no buffering, optionally over a
+/// caller-supplied buffer.
+pub(super) fn set_buffer(vm: &mut Vm, stream: u32, buffer: u32, mode: u32, size: u32) -> bool {
+    if !is_open(vm, stream) || !matches!(mode, IOFBF | IOLBF | IONBF) {
+        return false;
+    }
+    if mode != IONBF && !(2..=i32::MAX as u32).contains(&size) {
+        return false;
+    }
+    flush(vm, stream);
+    release_buffer(vm, stream);
+    let (base, size) = if mode == IONBF {
+        set_flag(vm, stream, IONBF, 0);
+        (stream + CHARBUF, 1)
+    } else if buffer == 0 {
+        set_flag(vm, stream, IOMYBUF, 0);
+        (vm.heap_alloc(size as usize), size)
+    } else {
+        set_flag(vm, stream, IOYOURBUF, 0);
+        (buffer, size)
+    };
+    set(vm, stream, BASE, base);
+    set(vm, stream, PTR, base);
+    set(vm, stream, BUFSIZ, size);
+    true
+}
+
+pub(super) fn clear_error(vm: &mut Vm, stream: u32) {
+    set_flag(vm, stream, 0, IOERR | IOEOF);
+    if let Some(entry) = vm.crt_fd_mut(fd(vm, stream)) {
+        entry.eof = false;
+    }
+}
+
+/// Every open stream, standard ones first.
+pub(super) fn open_streams(vm: &mut Vm) -> Vec<u32> {
+    let iob = iob(vm);
+    (0..IOB_COUNT)
+        .map(|index| iob + index * FILE_SIZE)
+        .chain(vm.crt_streams().to_vec())
+        .filter(|stream| is_open(vm, *stream))
+        .collect()
+}
+
+/// scanf input drawn from a stream. The lookahead character goes back with
+/// `ungetc` once scanning finishes.
+pub(super) struct StreamInput {
+    stream: u32,
+    wide: bool,
+    pending: Option<u16>,
+}
+
+impl StreamInput {
+    pub(super) fn new(stream: u32, wide: bool) -> Self {
+        Self {
+            stream,
+            wide,
+            pending: None,
+        }
+    }
+
+    pub(super) fn finish(self, vm: &mut Vm) {
+        if let Some(unit) = self.pending {
+            if self.wide {
+                ungetwc(vm, u32::from(unit), self.stream);
+            } else {
+                ungetc(vm, u32::from(unit), self.stream);
+            }
+        }
+    }
+}
+
+impl ScanInput for StreamInput {
+    fn peek(&mut self, vm: &mut Vm) -> Option<u16> {
+        if self.pending.is_none() {
+            let unit = if self.wide {
+                Some(getwc(vm, self.stream)).filter(|unit| *unit != 0xFFFF)
+            } else {
+                Some(getc(vm, self.stream)).filter(|byte| *byte != EOF)
+            };
+            self.pending = unit.map(|unit| unit as u16);
+        }
+        self.pending
+    }
+
+    fn advance(&mut self) {
+        self.pending = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::{Architecture, MemoryFs, VmConfig};
+
+    fn create_test_vm() -> Vm {
+        let config = VmConfig::new()
+            .architecture(Architecture::X86)
+            .mount("C:\\data", MemoryFs::new());
+        let mut vm = Vm::new(config).expect("vm");
+        vm.memory.map(0x1000, 0x10000);
+        vm.base = 0x1000;
+        vm.heap_start = 0x2000;
+        vm.heap_end = 0x10000;
+        vm
+    }
+
+    #[test]
+    fn streams_buffer_translate_and_reposition() {
+        let mut vm = create_test_vm();
+        let stream = open(&mut vm, "C:\\data\\notes.txt", "w+").expect("fopen");
+        assert!(stream >= iob(&mut vm) + 3 * FILE_SIZE);
+        assert_eq!(write(&mut vm, stream, b"one\ntwo\n"), 8);
+        // Nothing reaches the file until the buffer is flushed.
+        assert_eq!(vm.file_read_all("C:\\data\\notes.txt").expect("read"), b"");
+        assert_eq!(tell(&mut vm, stream), Ok(10));
+        assert!(flush(&mut vm, stream));
+        assert_eq!(
+            vm.file_read_all("C:\\data\\notes.txt").expect("read"),
+            b"one\r\ntwo\r\n"
+        );
+
+        seek(&mut vm, stream, 0, 0).expect("rewind");
+        assert_eq!(getc(&mut vm, stream), u32::from(b'o'));
+        assert_eq!(read(&mut vm, stream, 4), b"ne\nt");
+        assert_eq!(tell(&mut vm, stream), Ok(6));
+        assert_eq!(ungetc(&mut vm, u32::from(b'W'), stream), u32::from(b'W'));
+        assert_eq!(read(&mut vm, stream, 10), b"Wwo\n");
+        assert_eq!(getc(&mut vm, stream), EOF);
+        assert_ne!(flag(&vm, stream) & IOEOF, 0);
+
+        let reader = open(&mut vm, "C:\\data\\notes.txt", "rb").expect("fopen");
+        assert_eq!(read(&mut vm, reader, 5), b"one\r\n");
+        assert!(open(&mut vm, "C:\\data\\missing.txt", "r").is_err());
+        assert!(parse_mode("x").is_none());
+        assert!(close(&mut vm, reader));
+        assert!(close(&mut vm, stream));
+        assert!(!close(&mut vm, stream));
+    }
+
+    #[test]
+    fn console_streams_write_through() {
+        let mut vm = create_test_vm();
+        let stdout = std_stream(&mut vm, 1);
+        assert_eq!(write(&mut vm, stdout, b"hi\n"), 3);
+        assert_eq!(putc(&mut vm, u32::from(b'!'), stdout), u32::from(b'!'));
+        let output = vm.stdout_buffer();
+        assert_eq!(output.lock().unwrap().as_slice(), b"hi\n!");
+        let stdin = std_stream(&mut vm, 0);
+        assert_eq!(getc(&mut vm, stdin), EOF);
+    }
+}
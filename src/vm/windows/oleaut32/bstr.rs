@@ -1,6 +1,6 @@
 //! BSTR helpers.
 
-use crate::vm::{Vm, VmError};
+use crate::vm::{HeapApi, Vm, VmError};
 use crate::vm_args;
 
 // Allocate a BSTR from UTF-16 units.
@@ -16,6 +16,15 @@ pub(crate) fn alloc_bstr_from_utf16(vm: &mut Vm, utf16: &[u16]) -> Result<u32, V
     Ok(base + 4)
 }
 
+// Allocate a BSTR on behalf of a guest SysAllocString* call.
+fn alloc_guest_bstr(vm: &mut Vm, utf16: &[u16]) -> u32 {
+    let Ok(ptr) = alloc_bstr_from_utf16(vm, utf16) else {
+        return 0;
+    };
+    vm.heap_debug_tag(ptr - 4, HeapApi::SysAllocString);
+    ptr
+}
+
 // Allocate a BSTR from a UTF-8 string.
 pub(crate) fn alloc_bstr(vm: &mut Vm, text: &str) -> Result<u32, VmError> {
     let utf16: Vec<u16> = text.encode_utf16().collect();
@@ -73,7 +82,7 @@ pub(super) fn sys_alloc_string(vm: &mut Vm, stack_ptr: u32) -> u32 {
         Ok(value) => value,
         Err(_) => return 0,
     };
-    alloc_bstr_from_utf16(vm, &utf16).unwrap_or(0)
+    alloc_guest_bstr(vm, &utf16)
 }
 
 // SysAllocStringLen(wstr, len)
@@ -88,7 +97,7 @@ pub(super) fn sys_alloc_string_len(vm: &mut Vm, stack_ptr: u32) -> u32 {
             Err(_) => return 0,
         }
     };
-    alloc_bstr_from_utf16(vm, &utf16).unwrap_or(0)
+    alloc_guest_bstr(vm, &utf16)
 }
 
 // SysAllocStringByteLen(ptr, len)
@@ -96,18 +105,22 @@ pub(super) fn sys_alloc_string_byte_len(vm: &mut Vm, stack_ptr: u32) -> u32 {
     let (src, len) = vm_args!(vm, stack_ptr; u32, u32);
     let len = len as usize;
     if src == 0 {
-        return alloc_bstr_from_utf16(vm, &[]).unwrap_or(0);
+        return alloc_guest_bstr(vm, &[]);
     }
     let mut bytes = Vec::with_capacity(len);
     for i in 0..len {
         bytes.push(vm.read_u8(src + i as u32).unwrap_or(0));
     }
     let utf16: Vec<u16> = bytes.iter().map(|value| *value as u16).collect();
-    alloc_bstr_from_utf16(vm, &utf16).unwrap_or(0)
+    alloc_guest_bstr(vm, &utf16)
 }
 
 // SysFreeString(wstr)
-pub(super) fn sys_free_string(_vm: &mut Vm, _stack_ptr: u32) -> u32 {
+pub(super) fn sys_free_string(vm: &mut Vm, stack_ptr: u32) -> u32 {
+    let (ptr,) = vm_args!(vm, stack_ptr; u32);
+    if ptr >= 4 {
+        vm.heap_free(ptr - 4);
+    }
     0
 }
 
@@ -136,13 +149,12 @@ mod tests {
 
     fn create_test_vm() -> Vm {
         let mut vm = Vm::new(VmConfig::new().architecture(Architecture::X86)).expect("vm");
-        vm.memory = vec![0u8; 0x10000];
+        vm.memory.map(0x1000, 0x10000);
         vm.base = 0x1000;
         vm.stack_top = 0x1000 + 0x10000 - 4;
         vm.regs.esp = vm.stack_top;
         vm.heap_start = 0x2000;
         vm.heap_end = 0x8000;
-        vm.heap_cursor = vm.heap_start;
         vm
     }
 
@@ -180,6 +192,30 @@ mod tests {
         assert!(result.is_err());
     }
 
+    #[test]
+    fn test_sys_free_string_releases_block() {
+        let mut vm = Vm::new(
+            VmConfig::new()
+                .architecture(Architecture::X86)
+                .heap_debug(true),
+        )
+        .expect("vm");
+        vm.memory.map(0x1000, 0x10000);
+        vm.base = 0x1000;
+        vm.heap_start = 0x2000;
+        vm.heap_end = 0x8000;
+        let stack = 0x1000 + 0x10000 - 16;
+        let src = vm.alloc_bytes(&[b'H', 0, b'i', 0, 0, 0], 2).unwrap();
+        vm.write_u32(stack + 4, src).unwrap();
+        let bstr = sys_alloc_string(&mut vm, stack);
+        assert_eq!(read_bstr(&vm, bstr).unwrap(), "Hi");
+        assert_eq!(vm.heap_report().leaks.len(), 1);
+
+        vm.write_u32(stack + 4, bstr).unwrap();
+        sys_free_string(&mut vm, stack);
+        assert!(vm.heap_report().is_clean());
+    }
+
     #[test]
     fn test_alloc_bstr_from_utf16() {
         let mut vm = create_test_vm();
@@ -11,8 +11,7 @@ pub(super) fn load_typelib_from_path(
     path: &str,
     expected_guid: Option<[u8; 16]>,
 ) -> Result<u32, VmError> {
-    let host_path = vm.map_path(path);
-    let bytes = std::fs::read(host_path)?;
+    let bytes = vm.file_read_all(path)?;
     let lib = match typelib::load_from_bytes(&bytes) {
         Ok(lib) => lib,
         Err(err) => {
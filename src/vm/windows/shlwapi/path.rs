@@ -20,8 +20,7 @@ fn path_file_exists_a(vm: &mut Vm, stack_ptr: u32) -> u32 {
         return 0;
     }
     let path = vm.read_c_string(ptr).unwrap_or_default();
-    let host_path = vm.map_path(&path);
-    let exists = std::path::Path::new(&host_path).exists();
+    let exists = vm.file_exists(&path);
     if std::env::var("PE_VM_TRACE").is_ok() {
         eprintln!("[pe_vm] PathFileExistsA: {path} -> {exists}");
     }
@@ -39,13 +38,12 @@ mod tests {
 
     fn create_test_vm() -> Vm {
         let mut vm = Vm::new(VmConfig::new().architecture(Architecture::X86)).expect("vm");
-        vm.memory = vec![0u8; 0x10000];
+        vm.memory.map(0x1000, 0x10000);
         vm.base = 0x1000;
         vm.stack_top = 0x1000 + 0x10000 - 4;
         vm.regs.esp = vm.stack_top;
         vm.heap_start = 0x2000;
         vm.heap_end = 0x8000;
-        vm.heap_cursor = vm.heap_start;
         vm
     }
 
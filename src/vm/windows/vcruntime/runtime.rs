@@ -1,5 +1,6 @@
 //! VCRUNTIME runtime stubs.
 
+use crate::vm::windows::core::{cxx_frame_handler, cxx_throw_exception, except_handler4_common};
 use crate::vm::Vm;
 
 pub fn register(vm: &mut Vm) {
@@ -13,12 +14,15 @@ pub fn register(vm: &mut Vm) {
         "_except_handler4_common",
         except_handler4_common,
     );
+    vm.register_import_stdcall(
+        "VCRUNTIME140.dll",
+        "_CxxThrowException",
+        crate::vm::stdcall_args(2),
+        cxx_throw_exception,
+    );
+    vm.register_import("VCRUNTIME140.dll", "__CxxFrameHandler3", cxx_frame_handler);
 }
 
 fn std_type_info_destroy(_vm: &mut Vm, _stack_ptr: u32) -> u32 {
     0
 }
-
-fn except_handler4_common(_vm: &mut Vm, _stack_ptr: u32) -> u32 {
-    0
-}
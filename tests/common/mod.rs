@@ -21,7 +21,6 @@ impl TestVm {
         vm.stack_top = vm.regs.esp;
         vm.heap_start = 0x2000;
         vm.heap_end = 0x8000;
-        vm.heap_cursor = vm.heap_start;
         vm.fs_base = TEST_BASE + 0x100;
         vm.gs_base = 0;
         Self { vm }
@@ -0,0 +1,182 @@
+// Instructions-per-second benchmark for the x86 executor.
+//
+// Runs a tight arithmetic loop with and without the decoded-instruction cache and
+// prints the throughput of each. Scale the run with PE_VM_BENCH_ITERATIONS and use
+// a release build for numbers worth comparing:
+//
+//     PE_VM_BENCH_ITERATIONS=5000000 cargo test --release --test instruction_throughput -- --nocapture
+
+use std::time::{Duration, Instant};
+
+use pe_vm::{ExecuteOptions, PeFile, Value, Vm, VmConfig};
+
+const IMAGE_BASE: u32 = 0x0040_0000;
+const FILE_ALIGNMENT: u32 = 0x200;
+const SECTION_ALIGNMENT: u32 = 0x1000;
+const TEXT_RVA: u32 = 0x1000;
+const EXPORT_RVA: u32 = 0x2000;
+const SECTION_RAW_SIZE: u32 = 0x200;
+const SIZE_OF_HEADERS: u32 = 0x200;
+const SIZE_OF_IMAGE: u32 = 0x3000;
+
+const DEFAULT_ITERATIONS: u32 = 50_000;
+// Instructions outside the loop, and per iteration.
+const SETUP_INSTRUCTIONS: u64 = 3;
+const LOOP_INSTRUCTIONS: u64 = 6;
+
+// spin(count): mix count into eax with add/shift/xor until it reaches zero.
+fn spin_code() -> Vec<u8> {
+    vec![
+        0x8B, 0x4C, 0x24, 0x04, // mov ecx, [esp+4]
+        0x31, 0xC0, // xor eax, eax
+        0x01, 0xC8, // add eax, ecx
+        0x89, 0xC2, // mov edx, eax
+        0xD1, 0xE2, // shl edx, 1
+        0x31, 0xD0, // xor eax, edx
+        0x49, // dec ecx
+        0x75, 0xF5, // jnz -11
+        0xC3, // ret
+    ]
+}
+
+fn spin_expected(count: u32) -> u32 {
+    let mut eax = 0u32;
+    for ecx in (1..=count).rev() {
+        eax = eax.wrapping_add(ecx);
+        eax ^= eax << 1;
+    }
+    eax
+}
+
+fn build_dll() -> Vec<u8> {
+    let raw = |rva: u32| {
+        (SIZE_OF_HEADERS + (rva - TEXT_RVA) / SECTION_ALIGNMENT * SECTION_RAW_SIZE) as usize
+            + (rva % SECTION_ALIGNMENT) as usize
+    };
+    let mut image = vec![0u8; (SIZE_OF_HEADERS + 2 * SECTION_RAW_SIZE) as usize];
+
+    // DOS header and PE signature.
+    image[0] = b'M';
+    image[1] = b'Z';
+    write_u32(&mut image, 0x3C, 0x80);
+    let pe_off = 0x80;
+    image[pe_off..pe_off + 4].copy_from_slice(b"PE\0\0");
+
+    // File header.
+    let file_off = pe_off + 4;
+    write_u16(&mut image, file_off, 0x14C); // Machine x86
+    write_u16(&mut image, file_off + 2, 2); // NumberOfSections
+    write_u16(&mut image, file_off + 16, 0xE0); // SizeOfOptionalHeader
+    write_u16(&mut image, file_off + 18, 0x210E); // Characteristics (DLL)
+
+    // Optional header (PE32).
+    let opt_off = file_off + 20;
+    write_u16(&mut image, opt_off, 0x10B);
+    write_u32(&mut image, opt_off + 0x14, TEXT_RVA);
+    write_u32(&mut image, opt_off + 0x1C, IMAGE_BASE);
+    write_u32(&mut image, opt_off + 0x20, SECTION_ALIGNMENT);
+    write_u32(&mut image, opt_off + 0x24, FILE_ALIGNMENT);
+    write_u32(&mut image, opt_off + 0x38, SIZE_OF_IMAGE);
+    write_u32(&mut image, opt_off + 0x3C, SIZE_OF_HEADERS);
+    write_u32(&mut image, opt_off + 0x5C, 16); // NumberOfRvaAndSizes
+
+    // Section headers: .text and .rdata (exports).
+    let sect_off = opt_off + 0xE0;
+    let sections: [(&[u8; 8], u32, u32); 2] = [
+        (b".text\0\0\0", TEXT_RVA, 0x6000_0020),
+        (b".rdata\0\0", EXPORT_RVA, 0x4000_0040),
+    ];
+    for (index, (name, rva, characteristics)) in sections.iter().enumerate() {
+        let off = sect_off + index * 40;
+        image[off..off + 8].copy_from_slice(*name);
+        write_u32(&mut image, off + 8, SECTION_RAW_SIZE);
+        write_u32(&mut image, off + 12, *rva);
+        write_u32(&mut image, off + 16, SECTION_RAW_SIZE);
+        write_u32(&mut image, off + 20, raw(*rva) as u32);
+        write_u32(&mut image, off + 36, *characteristics);
+    }
+
+    let code = spin_code();
+    let text = raw(TEXT_RVA);
+    image[text..text + code.len()].copy_from_slice(&code);
+
+    // Export directory with the single `spin` export.
+    let dir = raw(EXPORT_RVA);
+    write_u32(&mut image, dir + 0x0C, EXPORT_RVA + 0x40); // Name
+    write_u32(&mut image, dir + 0x10, 1); // Base
+    write_u32(&mut image, dir + 0x14, 1); // NumberOfFunctions
+    write_u32(&mut image, dir + 0x18, 1); // NumberOfNames
+    write_u32(&mut image, dir + 0x1C, EXPORT_RVA + 0x28); // AddressOfFunctions
+    write_u32(&mut image, dir + 0x20, EXPORT_RVA + 0x2C); // AddressOfNames
+    write_u32(&mut image, dir + 0x24, EXPORT_RVA + 0x30); // AddressOfNameOrdinals
+    write_u32(&mut image, dir + 0x28, TEXT_RVA);
+    write_u32(&mut image, dir + 0x2C, EXPORT_RVA + 0x34);
+    write_u16(&mut image, dir + 0x30, 0);
+    write_bytes(&mut image, dir + 0x34, b"spin\0");
+    write_bytes(&mut image, dir + 0x40, b"bench.dll\0");
+    let data_dir_off = opt_off + 0x60;
+    write_u32(&mut image, data_dir_off, EXPORT_RVA);
+    write_u32(&mut image, data_dir_off + 4, 0x50);
+
+    image
+}
+
+fn iterations() -> u32 {
+    std::env::var("PE_VM_BENCH_ITERATIONS")
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(DEFAULT_ITERATIONS)
+}
+
+// Run spin(count) once and return the elapsed time.
+fn run(decode_cache: bool, count: u32) -> Duration {
+    let image = build_dll();
+    let file = PeFile::parse(&image).expect("parse");
+    let instructions = SETUP_INSTRUCTIONS + LOOP_INSTRUCTIONS * count as u64;
+    let config = VmConfig::new()
+        .execution_limit(instructions * 2)
+        .decode_cache(decode_cache);
+    let mut vm = Vm::new(config).expect("vm");
+    vm.load_image(&file, &image).expect("load");
+
+    let start = Instant::now();
+    let result = vm
+        .execute_export_with_values(
+            &file,
+            "spin",
+            &[Value::U32(count)],
+            ExecuteOptions::default(),
+        )
+        .expect("spin");
+    let elapsed = start.elapsed();
+    assert_eq!(result, spin_expected(count));
+    elapsed
+}
+
+// Report instructions per second with and without the decoded-instruction cache.
+#[test]
+fn instructions_per_second() {
+    let count = iterations();
+    let instructions = SETUP_INSTRUCTIONS + LOOP_INSTRUCTIONS * count as u64;
+    for decode_cache in [false, true] {
+        let elapsed = run(decode_cache, count);
+        let rate = instructions as f64 / elapsed.as_secs_f64().max(f64::EPSILON);
+        eprintln!(
+            "[bench] decode_cache={decode_cache}: {instructions} instructions in {:.3}s, {:.2} M instructions/s",
+            elapsed.as_secs_f64(),
+            rate / 1e6
+        );
+    }
+}
+
+fn write_u16(image: &mut [u8], offset: usize, value: u16) {
+    image[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+}
+
+fn write_u32(image: &mut [u8], offset: usize, value: u32) {
+    image[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+fn write_bytes(image: &mut [u8], offset: usize, bytes: &[u8]) {
+    image[offset..offset + bytes.len()].copy_from_slice(bytes);
+}
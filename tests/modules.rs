@@ -0,0 +1,463 @@
+// Tests loading several PE modules into one VM and binding imports between them.
+
+use std::path::{Path, PathBuf};
+
+use pe_vm::{
+    AccessKind, ExecuteOptions, Pe, TraceBuffer, TraceOptions, TraceRecord, Value, Vm, VmConfig,
+    VmError,
+};
+
+const HELPER_BASE: u32 = 0x1000_0000;
+const MAIN_BASE: u32 = 0x0040_0000;
+const FILE_ALIGNMENT: u32 = 0x200;
+const SECTION_ALIGNMENT: u32 = 0x1000;
+const TEXT_RVA: u32 = 0x1000;
+const RDATA_RVA: u32 = 0x2000;
+const DATA_RVA: u32 = 0x3000;
+const RELOC_RVA: u32 = 0x4000;
+const SECTION_RAW_SIZE: u32 = 0x400;
+const SIZE_OF_HEADERS: u32 = 0x400;
+const SIZE_OF_IMAGE: u32 = 0x5000;
+
+const EXPORT_DIR_RVA: u32 = RDATA_RVA;
+const IMPORT_DIR_RVA: u32 = RDATA_RVA + 0x100;
+const ILT_RVA: u32 = RDATA_RVA + 0x140;
+const IAT_RVA: u32 = RDATA_RVA + 0x180;
+const HINT_NAME_RVA: u32 = RDATA_RVA + 0x1C0;
+
+struct DllSpec<'a> {
+    name: &'a str,
+    image_base: u32,
+    entry_rva: u32,
+    text: Vec<u8>,
+    exports: &'a [(&'a str, u32)],
+    import_dll: Option<&'a str>,
+    imports: &'a [&'a str],
+    relocs: &'a [u32],
+}
+
+fn iat_va(base: u32, index: usize) -> u32 {
+    base + IAT_RVA + (index as u32) * 4
+}
+
+fn build_dll(spec: &DllSpec<'_>) -> Vec<u8> {
+    let raw = |rva: u32| {
+        (SIZE_OF_HEADERS + (rva - TEXT_RVA) / SECTION_ALIGNMENT * SECTION_RAW_SIZE) as usize
+            + (rva % SECTION_ALIGNMENT) as usize
+    };
+    let total_size = (SIZE_OF_HEADERS + 4 * SECTION_RAW_SIZE) as usize;
+    let mut image = vec![0u8; total_size];
+
+    // DOS header and PE signature.
+    image[0] = b'M';
+    image[1] = b'Z';
+    write_u32(&mut image, 0x3C, 0x80);
+    let pe_off = 0x80;
+    image[pe_off..pe_off + 4].copy_from_slice(b"PE\0\0");
+
+    // File header.
+    let file_off = pe_off + 4;
+    write_u16(&mut image, file_off, 0x14C); // Machine x86
+    write_u16(&mut image, file_off + 2, 4); // NumberOfSections
+    write_u16(&mut image, file_off + 16, 0xE0); // SizeOfOptionalHeader
+    write_u16(&mut image, file_off + 18, 0x210E); // Characteristics (DLL)
+
+    // Optional header (PE32).
+    let opt_off = file_off + 20;
+    write_u16(&mut image, opt_off, 0x10B);
+    write_u32(&mut image, opt_off + 0x10, spec.entry_rva);
+    write_u32(&mut image, opt_off + 0x14, TEXT_RVA);
+    write_u32(&mut image, opt_off + 0x18, RDATA_RVA);
+    write_u32(&mut image, opt_off + 0x1C, spec.image_base);
+    write_u32(&mut image, opt_off + 0x20, SECTION_ALIGNMENT);
+    write_u32(&mut image, opt_off + 0x24, FILE_ALIGNMENT);
+    write_u32(&mut image, opt_off + 0x38, SIZE_OF_IMAGE);
+    write_u32(&mut image, opt_off + 0x3C, SIZE_OF_HEADERS);
+    write_u32(&mut image, opt_off + 0x5C, 16); // NumberOfRvaAndSizes
+
+    // Section headers.
+    let sect_off = opt_off + 0xE0;
+    let sections: [(&[u8; 8], u32, u32); 4] = [
+        (b".text\0\0\0", TEXT_RVA, 0x6000_0020),
+        (b".rdata\0\0", RDATA_RVA, 0x4000_0040),
+        (b".data\0\0\0", DATA_RVA, 0xC000_0040),
+        (b".reloc\0\0", RELOC_RVA, 0x4200_0040),
+    ];
+    for (index, (name, rva, characteristics)) in sections.iter().enumerate() {
+        let off = sect_off + index * 40;
+        image[off..off + 8].copy_from_slice(*name);
+        write_u32(&mut image, off + 8, SECTION_RAW_SIZE);
+        write_u32(&mut image, off + 12, *rva);
+        write_u32(&mut image, off + 16, SECTION_RAW_SIZE);
+        write_u32(&mut image, off + 20, raw(*rva) as u32);
+        write_u32(&mut image, off + 36, *characteristics);
+    }
+
+    let text_off = raw(TEXT_RVA);
+    image[text_off..text_off + spec.text.len()].copy_from_slice(&spec.text);
+
+    let data_dir_off = opt_off + 0x60;
+    if !spec.exports.is_empty() {
+        let count = spec.exports.len() as u32;
+        let functions = EXPORT_DIR_RVA + 0x28;
+        let names = functions + count * 4;
+        let ordinals = names + count * 4;
+        let mut strings = ordinals + count * 2;
+        let dir = raw(EXPORT_DIR_RVA);
+        write_u32(&mut image, dir + 0x0C, strings);
+        write_bytes(&mut image, raw(strings), spec.name.as_bytes());
+        strings += spec.name.len() as u32 + 1;
+        write_u32(&mut image, dir + 0x10, 1); // Base
+        write_u32(&mut image, dir + 0x14, count);
+        write_u32(&mut image, dir + 0x18, count);
+        write_u32(&mut image, dir + 0x1C, functions);
+        write_u32(&mut image, dir + 0x20, names);
+        write_u32(&mut image, dir + 0x24, ordinals);
+        for (index, (name, rva)) in spec.exports.iter().enumerate() {
+            let index = index as u32;
+            write_u32(&mut image, raw(functions + index * 4), *rva);
+            write_u32(&mut image, raw(names + index * 4), strings);
+            write_u16(&mut image, raw(ordinals + index * 2), index as u16);
+            write_bytes(&mut image, raw(strings), name.as_bytes());
+            strings += name.len() as u32 + 1;
+        }
+        write_u32(&mut image, data_dir_off, EXPORT_DIR_RVA);
+        write_u32(&mut image, data_dir_off + 4, strings - EXPORT_DIR_RVA);
+    }
+
+    if let Some(dll) = spec.import_dll {
+        let desc = raw(IMPORT_DIR_RVA);
+        let mut hint_name = HINT_NAME_RVA;
+        for (index, name) in spec.imports.iter().enumerate() {
+            let slot = (index as u32) * 4;
+            write_u32(&mut image, raw(ILT_RVA + slot), hint_name);
+            write_u32(&mut image, raw(IAT_RVA + slot), hint_name);
+            write_bytes(&mut image, raw(hint_name + 2), name.as_bytes());
+            hint_name += (name.len() as u32 + 4) & !1;
+        }
+        write_bytes(&mut image, raw(hint_name), dll.as_bytes());
+        write_u32(&mut image, desc, ILT_RVA); // OriginalFirstThunk
+        write_u32(&mut image, desc + 12, hint_name); // Name
+        write_u32(&mut image, desc + 16, IAT_RVA); // FirstThunk
+        write_u32(&mut image, data_dir_off + 8, IMPORT_DIR_RVA);
+        write_u32(&mut image, data_dir_off + 12, 0x28);
+    }
+
+    if !spec.relocs.is_empty() {
+        let block = raw(RELOC_RVA);
+        let size = (8 + spec.relocs.len() as u32 * 2 + 3) & !3;
+        write_u32(&mut image, block, TEXT_RVA);
+        write_u32(&mut image, block + 4, size);
+        for (index, rva) in spec.relocs.iter().enumerate() {
+            let entry = 0x3000 | (rva - TEXT_RVA) as u16;
+            write_u16(&mut image, block + 8 + index * 2, entry);
+        }
+        write_u32(&mut image, data_dir_off + 5 * 8, RELOC_RVA);
+        write_u32(&mut image, data_dir_off + 5 * 8 + 4, size);
+    }
+
+    image
+}
+
+// helper.dll: DllMain stores a marker in .data, `add_one` increments its argument and
+// `get_marker` returns the stored marker. Absolute .data references need relocation.
+fn build_helper_dll() -> Vec<u8> {
+    let marker = HELPER_BASE + DATA_RVA;
+    let mut text = Vec::new();
+    // DllMain at +0x00: mov dword [marker], 0x1234; mov eax, 1; ret 12
+    text.extend_from_slice(&[0xC7, 0x05]);
+    text.extend_from_slice(&marker.to_le_bytes());
+    text.extend_from_slice(&0x1234u32.to_le_bytes());
+    text.extend_from_slice(&[0xB8, 0x01, 0x00, 0x00, 0x00, 0xC2, 0x0C, 0x00]);
+    text.resize(0x20, 0xCC);
+    // add_one at +0x20: mov eax, [esp+4]; inc eax; ret
+    text.extend_from_slice(&[0x8B, 0x44, 0x24, 0x04, 0x40, 0xC3]);
+    text.resize(0x30, 0xCC);
+    // get_marker at +0x30: mov eax, [marker]; ret
+    text.push(0xA1);
+    text.extend_from_slice(&marker.to_le_bytes());
+    text.push(0xC3);
+
+    build_dll(&DllSpec {
+        name: "helper.dll",
+        image_base: HELPER_BASE,
+        entry_rva: TEXT_RVA,
+        text,
+        exports: &[
+            ("add_one", TEXT_RVA + 0x20),
+            ("get_marker", TEXT_RVA + 0x30),
+        ],
+        import_dll: None,
+        imports: &[],
+        relocs: &[TEXT_RVA + 0x02, TEXT_RVA + 0x31],
+    })
+}
+
+// main.dll: `run` returns add_one(41) and `marker` returns get_marker(), both via the IAT.
+fn build_main_dll() -> Vec<u8> {
+    let mut text = Vec::new();
+    // run at +0x00: push 41; call [iat0]; add esp, 4; ret
+    text.extend_from_slice(&[0x6A, 0x29, 0xFF, 0x15]);
+    text.extend_from_slice(&iat_va(MAIN_BASE, 0).to_le_bytes());
+    text.extend_from_slice(&[0x83, 0xC4, 0x04, 0xC3]);
+    text.resize(0x20, 0xCC);
+    // marker at +0x20: call [iat1]; ret
+    text.extend_from_slice(&[0xFF, 0x15]);
+    text.extend_from_slice(&iat_va(MAIN_BASE, 1).to_le_bytes());
+    text.push(0xC3);
+    text.resize(0x30, 0xCC);
+    // poke at +0x30: mov dword [.text], 1; ret
+    text.extend_from_slice(&[0xC7, 0x05]);
+    text.extend_from_slice(&(MAIN_BASE + TEXT_RVA).to_le_bytes());
+    text.extend_from_slice(&1u32.to_le_bytes());
+    text.push(0xC3);
+
+    build_dll(&DllSpec {
+        name: "main.dll",
+        image_base: MAIN_BASE,
+        entry_rva: 0,
+        text,
+        exports: &[
+            ("run", TEXT_RVA),
+            ("marker", TEXT_RVA + 0x20),
+            ("poke", TEXT_RVA + 0x30),
+        ],
+        import_dll: Some("helper.dll"),
+        imports: &["add_one", "get_marker"],
+        relocs: &[],
+    })
+}
+
+fn fixture_dir(label: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("pe_vm_modules_{label}_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).expect("fixture dir");
+    std::fs::write(dir.join("helper.dll"), build_helper_dll()).expect("helper");
+    std::fs::write(dir.join("main.dll"), build_main_dll()).expect("main");
+    dir
+}
+
+fn create_vm(dir: &Path) -> Vm {
+    let mut paths = pe_vm::PathMapping::new();
+    paths.insert("C:\\app".to_string(), dir.to_string_lossy().to_string());
+    Vm::new(VmConfig::new().paths(paths)).expect("vm")
+}
+
+// Static imports of a sibling DLL map it at its preferred base and run DllMain.
+#[test]
+fn static_import_loads_sibling_module() {
+    let dir = fixture_dir("static");
+    let mut vm = create_vm(&dir);
+    let pe = Pe::load(&mut vm, "C:\\app\\main.dll").expect("load");
+
+    let helper = vm.module("helper.dll").expect("helper loaded").base();
+    assert_eq!(helper, HELPER_BASE);
+    assert_eq!(vm.module("main.dll").expect("main").base(), MAIN_BASE);
+
+    let result = vm
+        .execute_export_with_values(pe.file(), "run", &[], ExecuteOptions::default())
+        .expect("run");
+    assert_eq!(result, 42);
+    let marker = vm
+        .execute_export_with_values(pe.file(), "marker", &[], ExecuteOptions::default())
+        .expect("marker");
+    assert_eq!(marker, 0x1234);
+
+    let _ = std::fs::remove_dir_all(dir);
+}
+
+// Mapping a sibling DLL is recorded in the trace even without instruction records.
+#[test]
+fn module_mappings_are_traced() {
+    let dir = fixture_dir("trace");
+    let mut vm = create_vm(&dir);
+    let buffer = TraceBuffer::new();
+    vm.set_trace_sink(Box::new(buffer.clone()), TraceOptions::new())
+        .expect("sink");
+    let _pe = Pe::load(&mut vm, "C:\\app\\main.dll").expect("load");
+
+    let records = buffer.records();
+    assert!(
+        records.iter().any(|record| matches!(
+            record,
+            TraceRecord::ModuleMapped { name, base: HELPER_BASE, size: SIZE_OF_IMAGE }
+                if name.to_ascii_lowercase().ends_with("helper.dll")
+        )),
+        "{records:?}"
+    );
+
+    let _ = std::fs::remove_dir_all(dir);
+}
+
+// Dynamic loads share the module table with static imports and respect load counts.
+#[test]
+fn load_module_and_proc_address() {
+    let dir = fixture_dir("dynamic");
+    let mut vm = create_vm(&dir);
+    let _pe = Pe::load(&mut vm, "C:\\app\\main.dll").expect("load");
+
+    let helper = vm.load_module("HELPER").expect("helper");
+    assert_eq!(Some(helper), vm.module("helper.dll").map(|m| m.base()));
+    let add_one = vm.module_proc_address(helper, "add_one").expect("add_one");
+    assert_eq!(add_one, helper + TEXT_RVA + 0x20);
+    assert_eq!(
+        vm.module_proc_address(helper, "#2"),
+        Some(helper + TEXT_RVA + 0x30)
+    );
+    assert_eq!(vm.module_proc_address(helper, "missing"), None);
+
+    let kernel32 = vm.load_module("kernel32.dll").expect("kernel32");
+    assert!(vm.module_by_handle(kernel32).expect("host").is_host());
+    assert!(vm.module_proc_address(kernel32, "GetProcAddress").is_some());
+
+    // Statically imported modules stay pinned after FreeLibrary.
+    assert!(vm.free_module(helper));
+    assert!(vm.module("helper.dll").is_some());
+    assert!(vm.load_module("missing.dll").is_err());
+
+    let _ = std::fs::remove_dir_all(dir);
+}
+
+// A module whose DllMain fails is unmapped again, so a later load gets its preferred base.
+#[test]
+fn failed_attach_unmaps_the_image() {
+    let dir = fixture_dir("failed");
+    let mut vm = create_vm(&dir);
+    let main = build_main_dll();
+    let file = pe_vm::PeFile::parse(&main).expect("parse");
+    vm.load_image(&file, &main).expect("image");
+
+    let mut text = vec![0x31, 0xC0, 0xC2, 0x0C, 0x00]; // xor eax, eax; ret 12
+    text.resize(0x10, 0xCC);
+    let failing_image = build_dll(&DllSpec {
+        name: "failing.dll",
+        image_base: HELPER_BASE,
+        entry_rva: TEXT_RVA,
+        text,
+        exports: &[],
+        import_dll: None,
+        imports: &[],
+        relocs: &[],
+    });
+    let failing_file = pe_vm::PeFile::parse(&failing_image).expect("parse failing");
+    assert!(vm
+        .load_module_image("C:\\plugins\\failing.dll", &failing_file, &failing_image)
+        .is_err());
+    assert!(vm.module("failing.dll").is_none());
+    assert!(!vm.is_mapped(HELPER_BASE));
+
+    let helper_image = build_helper_dll();
+    let helper_file = pe_vm::PeFile::parse(&helper_image).expect("parse helper");
+    let helper = vm
+        .load_module_image("C:\\plugins\\helper.dll", &helper_file, &helper_image)
+        .expect("helper");
+    assert_eq!(helper, HELPER_BASE);
+
+    let _ = std::fs::remove_dir_all(dir);
+}
+
+// Modules whose preferred base is taken are relocated into the next free range.
+#[test]
+fn load_module_image_and_free() {
+    let dir = fixture_dir("image");
+    let mut vm = create_vm(&dir);
+    let main = build_main_dll();
+    let file = pe_vm::PeFile::parse(&main).expect("parse");
+    vm.load_image(&file, &main).expect("image");
+    vm.map_memory(HELPER_BASE, 0x1000)
+        .expect("occupy preferred base");
+
+    let helper_image = build_helper_dll();
+    let helper_file = pe_vm::PeFile::parse(&helper_image).expect("parse helper");
+    let helper = vm
+        .load_module_image("C:\\plugins\\helper.dll", &helper_file, &helper_image)
+        .expect("helper");
+    assert_ne!(helper, HELPER_BASE);
+    assert_eq!(helper % 0x1_0000, 0);
+    vm.resolve_imports(&file).expect("imports");
+    let result = vm
+        .execute_export_with_values(&file, "run", &[Value::U32(0)], ExecuteOptions::default())
+        .expect("run");
+    assert_eq!(result, 42);
+    let marker = vm
+        .execute_export_with_values(&file, "marker", &[], ExecuteOptions::default())
+        .expect("marker");
+    assert_eq!(marker, 0x1234);
+
+    assert_eq!(vm.modules().count(), 2);
+    assert!(vm.free_module(helper));
+    assert!(vm.module("helper.dll").is_none());
+    assert!(!vm.is_mapped(helper));
+
+    let _ = std::fs::remove_dir_all(dir);
+}
+
+// Section characteristics become page protections and guest writes to code fault.
+#[test]
+fn section_protections_are_enforced() {
+    let dir = fixture_dir("protect");
+    let mut vm = create_vm(&dir);
+    let pe = Pe::load(&mut vm, "C:\\app\\main.dll").expect("load");
+
+    assert_eq!(vm.memory_protection(MAIN_BASE), Some(0x02));
+    assert_eq!(vm.memory_protection(MAIN_BASE + TEXT_RVA), Some(0x20));
+    assert_eq!(vm.memory_protection(MAIN_BASE + RDATA_RVA), Some(0x02));
+    assert_eq!(vm.memory_protection(HELPER_BASE + DATA_RVA), Some(0x04));
+
+    let err = vm
+        .execute_export_with_values(pe.file(), "poke", &[], ExecuteOptions::default())
+        .expect_err("write to .text");
+    match err {
+        VmError::AccessViolation { addr, access, eip } => {
+            assert_eq!(addr, MAIN_BASE + TEXT_RVA);
+            assert_eq!(access, AccessKind::Write);
+            assert_eq!(eip, MAIN_BASE + TEXT_RVA + 0x30);
+        }
+        other => panic!("unexpected error: {other}"),
+    }
+
+    // After VirtualProtect-style unprotecting, the same write succeeds.
+    assert_eq!(
+        vm.protect_memory(MAIN_BASE + TEXT_RVA, 1, 0x40)
+            .expect("protect"),
+        0x20
+    );
+    vm.execute_export_with_values(pe.file(), "poke", &[], ExecuteOptions::default())
+        .expect("poke");
+    assert_eq!(vm.read_u32(MAIN_BASE + TEXT_RVA).expect("read"), 1);
+
+    let _ = std::fs::remove_dir_all(dir);
+}
+
+fn write_u16(image: &mut [u8], offset: usize, value: u16) {
+    image[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+}
+
+fn write_u32(image: &mut [u8], offset: usize, value: u32) {
+    image[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+fn write_bytes(image: &mut [u8], offset: usize, bytes: &[u8]) {
+    image[offset..offset + bytes.len()].copy_from_slice(bytes);
+}
+
+// Exports disassemble from the file bytes and from guest memory alike.
+#[test]
+fn disassembles_exports_without_running_them() {
+    let dir = fixture_dir("disasm");
+    let mut vm = create_vm(&dir);
+    let pe = Pe::load(&mut vm, "C:\\app\\main.dll").expect("load");
+
+    let listing = pe.disassemble_export("run", 4).expect("run");
+    let text: Vec<String> = listing.iter().map(ToString::to_string).collect();
+    let call = format!("call dword ptr [0x{:08X}]", iat_va(MAIN_BASE, 0));
+    assert_eq!(text, ["push 0x29", call.as_str(), "add esp, 0x4", "ret"]);
+    assert_eq!(listing[0].address, MAIN_BASE + TEXT_RVA);
+    assert_eq!(listing[3].flow, pe_vm::disasm::Flow::Return);
+
+    let poke = pe.disassemble_rva(TEXT_RVA + 0x30, 1).expect("poke");
+    assert_eq!(poke[0].length(), 10);
+    assert_eq!(vm.disassemble(MAIN_BASE + TEXT_RVA, 4), listing);
+    assert!(pe.disassemble_export("missing", 4).is_none());
+
+    let _ = std::fs::remove_dir_all(dir);
+}
@@ -1,5 +1,5 @@
-// Tests sandbox network allow/deny rules in the VM.
-use pe_vm::{SandboxConfig, Vm, VmConfig};
+// Tests sandbox network and filesystem rules in the VM.
+use pe_vm::{PathAccess, SandboxConfig, UnmappedPaths, Vm, VmConfig};
 
 #[test]
 fn sandbox_network_policy() {
@@ -13,3 +13,26 @@ fn sandbox_network_policy() {
     let vm_disabled = Vm::new(VmConfig::new().sandbox(disabled)).expect("vm");
     assert!(!vm_disabled.network_allowed("example.com"));
 }
+
+#[test]
+fn sandbox_filesystem_policy_from_settings() {
+    let dir = std::env::temp_dir().join(format!("pe_vm_sandbox_settings_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).expect("dir");
+    let path = dir.join("settings.yml");
+    std::fs::write(
+        &path,
+        "sandbox:\n  filesystem:\n    unmapped: deny\n    read_only:\n      - \"C:\\\\Program Files\"\n",
+    )
+    .expect("settings");
+
+    let config = VmConfig::from_settings(&path).expect("config");
+    let sandbox = config.sandbox_config().expect("sandbox");
+    assert_eq!(sandbox.unmapped_paths_value(), &UnmappedPaths::Deny);
+    assert_eq!(
+        sandbox.path_access_rules().get("C:\\Program Files"),
+        Some(&PathAccess::ReadOnly)
+    );
+    assert!(!sandbox.network_enabled());
+
+    let _ = std::fs::remove_dir_all(dir);
+}